@@ -0,0 +1,37 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Plaintext framing as seen after decryption. Ciphertext is attacker chosen,
+// so the decrypted frame can be any bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proto::framing::{frame, payload_range, LENGTH_PREFIX_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(range) = payload_range(data) {
+        assert!(range.end <= data.len());
+    }
+    if let Some(framed) = frame(data) {
+        assert_eq!(framed.len() % proto::key_manager::AES_BLOCK_SIZE, 0);
+        assert_eq!(
+            payload_range(&framed),
+            Some(LENGTH_PREFIX_SIZE..LENGTH_PREFIX_SIZE + data.len())
+        );
+    }
+});
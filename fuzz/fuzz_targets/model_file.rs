@@ -0,0 +1,42 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Encrypted model files (`encrypt-model` JSON, single or chunked), read by
+// the host from users and the network before anything reaches the TA.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proto::inference::MAX_MODEL_SIGNATURE_SIZE;
+use proto::key_manager::AES_BLOCK_SIZE;
+use proto::model_file::{self, ALGORITHM, MAX_CIPHERTEXT_SIZE};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(model) = model_file::parse(data) {
+        let len = model.ciphertext.len();
+        assert_eq!(model.algorithm, ALGORITHM);
+        assert!(len <= MAX_CIPHERTEXT_SIZE);
+        assert!(len >= 2 * AES_BLOCK_SIZE && len % AES_BLOCK_SIZE == 0);
+        if let Some((total_chunks, original_size)) = model.chunks {
+            assert!(total_chunks <= model_file::MAX_CHUNKS);
+            assert_eq!(original_size, len);
+        }
+        if let Some(signature) = &model.signature {
+            assert!(!signature.is_empty() && signature.len() <= MAX_MODEL_SIGNATURE_SIZE);
+        }
+    }
+});
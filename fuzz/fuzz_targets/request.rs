@@ -0,0 +1,64 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Inference request header and the image buffer behind it, straight from the
+// normal world, plus the packing of the predictions sent back.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proto::inference::{
+    correlation_ids, output_window, reject_threshold, split_request, Prediction,
+    MAX_REJECT_THRESHOLD, REJECT_LABEL,
+};
+use proto::{wire, Image};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((header, images)) = split_request(data) {
+        // The TA views the image bytes as records without further checks
+        let images: &[Image] = wire::records(images).unwrap();
+        if let Some(header) = header {
+            assert_eq!(images.len(), header.batch_len() as usize);
+            assert!(header.temperature() > 0.0);
+            if let Some(ids) = correlation_ids(data, &header) {
+                assert_eq!(ids.len(), images.len());
+            }
+            if let Some(threshold) = reject_threshold(data, &header) {
+                assert!(threshold <= MAX_REJECT_THRESHOLD);
+            }
+            // The TA slices its outputs with the window
+            if let Some(window) = output_window(data, &header) {
+                assert!(window.count > 0 && window.range().end <= images.len());
+            }
+        }
+    }
+    if let [label, a, b, c, d, ..] = *data {
+        let confidence = f32::from_le_bytes([a, b, c, d]);
+        if let Some(prediction) = Prediction::new(label, confidence) {
+            assert_eq!(prediction.label, label);
+            assert!((prediction.confidence() - confidence).abs() <= 0.0005 + f32::EPSILON);
+            let threshold = u32::from(d) * 4;
+            let checked = prediction.with_threshold(threshold);
+            assert_eq!(checked.candidate, label);
+            if u32::from(prediction.confidence_milli()) < threshold {
+                assert_eq!(checked.label, REJECT_LABEL);
+            } else {
+                assert_eq!(checked, prediction);
+            }
+        }
+    }
+});
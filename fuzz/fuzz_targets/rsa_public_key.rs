@@ -0,0 +1,32 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Model verify keys (DER) as passed to `set-verify-key`; the TA parses them
+// again before storing them and before every signature check.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use proto::key_manager::{parse_rsa_public_key, MAX_RSA_BITS, MIN_RSA_BITS};
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(key) = parse_rsa_public_key(data) {
+        assert!((MIN_RSA_BITS..=MAX_RSA_BITS).contains(&key.bits()));
+        assert!(key.modulus.first().is_some_and(|&top| top != 0));
+        assert!(!key.exponent.is_empty());
+    }
+});
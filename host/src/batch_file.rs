@@ -0,0 +1,339 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Packed batches of images in one file, so large test sets don't have to be
+// shipped to a device as thousands of 784-byte files. Layout, integers
+// little-endian:
+//
+//   magic "EMNB" | flags u32 | count u32 | count images | count labels
+//
+// The labels section is only there with `FLAG_LABELED`. Images are read as
+// they are needed so the whole file never has to be in memory.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use proto::{Image, IMAGE_SIZE};
+
+use crate::source::ImageSource;
+
+const MAGIC: &[u8; 4] = b"EMNB";
+const HEADER_SIZE: usize = 12;
+/// One label byte per image follows the images.
+const FLAG_LABELED: u32 = 1 << 0;
+const KNOWN_FLAGS: u32 = FLAG_LABELED;
+
+/// Conventional extension of batch files.
+pub const EXTENSION: &str = "emnb";
+
+/// A batch file as an image source, after checking its header against its
+/// length. Images are read from the file as they are asked for.
+pub struct BatchFile {
+    path: PathBuf,
+    name: String,
+    file: File,
+    labels: Option<Vec<u8>>,
+    count: usize,
+}
+
+impl BatchFile {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("cannot open {}", path.display()))?;
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header)
+            .with_context(|| format!("{}: truncated batch file header", path.display()))?;
+        anyhow::ensure!(
+            &header[..4] == MAGIC,
+            "{} is not a batch file",
+            path.display()
+        );
+        let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let count = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        anyhow::ensure!(
+            flags & !KNOWN_FLAGS == 0,
+            "{}: unknown batch file flags {:#x}",
+            path.display(),
+            flags & !KNOWN_FLAGS
+        );
+        let labeled = flags & FLAG_LABELED != 0;
+        let expected = file_size(count, labeled);
+        let actual = file.metadata()?.len();
+        anyhow::ensure!(
+            actual >= expected,
+            "{}: truncated, {} images{} need {} bytes but the file has {}",
+            path.display(),
+            count,
+            if labeled { " with labels" } else { "" },
+            expected,
+            actual
+        );
+        anyhow::ensure!(
+            actual == expected,
+            "{}: count mismatch, the header says {} images{} ({} bytes) but the file has {}",
+            path.display(),
+            count,
+            if labeled { " with labels" } else { "" },
+            expected,
+            actual
+        );
+        let labels = if labeled {
+            let mut labels = vec![0u8; count];
+            file.read_exact_at(&mut labels, file_size(count, false))
+                .with_context(|| format!("{}: read failed", path.display()))?;
+            Some(labels)
+        } else {
+            None
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            name: path.display().to_string(),
+            file,
+            labels,
+            count,
+        })
+    }
+
+    pub fn is_labeled(&self) -> bool {
+        self.labels.is_some()
+    }
+}
+
+impl ImageSource for BatchFile {
+    fn len(&self) -> usize {
+        self.count
+    }
+
+    fn read_into(&self, index: usize, image: &mut Image) -> anyhow::Result<()> {
+        let offset = (HEADER_SIZE + index * IMAGE_SIZE) as u64;
+        self.file
+            .read_exact_at(image.as_bytes_mut(), offset)
+            .with_context(|| format!("{}: read failed", self.path.display()))
+    }
+
+    fn label(&self, index: usize) -> Option<u8> {
+        self.labels.as_ref().map(|labels| labels[index])
+    }
+
+    fn name(&self, index: usize) -> Cow<'_, str> {
+        Cow::Owned(format!("{}#{}", self.name, index))
+    }
+}
+
+/// Writes `images` to a batch file at `path`, with `labels` (one per image)
+/// when given.
+pub fn write(path: &Path, images: &[Image], labels: Option<&[u8]>) -> anyhow::Result<()> {
+    if let Some(labels) = labels {
+        anyhow::ensure!(
+            labels.len() == images.len(),
+            "{} labels for {} images",
+            labels.len(),
+            images.len()
+        );
+    }
+    let count = u32::try_from(images.len()).context("too many images for a batch file")?;
+    let flags = if labels.is_some() { FLAG_LABELED } else { 0 };
+    let file = File::create(path).with_context(|| format!("cannot create {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    out.write_all(MAGIC)?;
+    out.write_all(&flags.to_le_bytes())?;
+    out.write_all(&count.to_le_bytes())?;
+    for image in images {
+        out.write_all(image.as_bytes())?;
+    }
+    if let Some(labels) = labels {
+        out.write_all(labels)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Bytes of a well-formed file of `count` images
+fn file_size(count: usize, labeled: bool) -> u64 {
+    let per_image = IMAGE_SIZE as u64 + labeled as u64;
+    HEADER_SIZE as u64 + count as u64 * per_image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Batches;
+
+    // A file under the temp directory for one test
+    fn temp_path(test: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("{}-{}", test, std::process::id()))
+            .with_extension(EXTENSION)
+    }
+
+    fn images(count: usize) -> Vec<Image> {
+        (0..count)
+            .map(|i| {
+                let bytes: Vec<u8> = (0..IMAGE_SIZE).map(|p| (p * 7 + i * 31) as u8).collect();
+                Image::from_bytes(&bytes).unwrap()
+            })
+            .collect()
+    }
+
+    // Opens `bytes` written as a batch file
+    fn open_bytes(test: &str, bytes: &[u8]) -> anyhow::Result<BatchFile> {
+        let path = temp_path(test);
+        std::fs::write(&path, bytes).unwrap();
+        let file = BatchFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    fn read_all(file: &BatchFile) -> Vec<Image> {
+        (0..file.len())
+            .map(|index| {
+                let mut image = Image::from_bytes(&[0; IMAGE_SIZE]).unwrap();
+                file.read_into(index, &mut image).unwrap();
+                image
+            })
+            .collect()
+    }
+
+    #[test]
+    fn batch_files_round_trip() {
+        let path = temp_path("batch-round-trip");
+        let images = images(5);
+        let labels = [3, 1, 4, 1, 5];
+
+        write(&path, &images, None).unwrap();
+        let file = BatchFile::open(&path).unwrap();
+        assert_eq!(file.len(), 5);
+        assert!(!file.is_labeled());
+        assert_eq!(file.label(0), None);
+        assert_eq!(read_all(&file), images);
+
+        write(&path, &images, Some(&labels)).unwrap();
+        let file = BatchFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(file.is_labeled());
+        assert_eq!(read_all(&file), images);
+        let read: Vec<u8> = (0..5).map(|index| file.label(index).unwrap()).collect();
+        assert_eq!(read, labels);
+        assert!(file.name(2).ends_with("#2"));
+    }
+
+    #[test]
+    fn empty_batch_files_round_trip() {
+        let path = temp_path("batch-empty");
+        write(&path, &[], Some(&[])).unwrap();
+        let file = BatchFile::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let file = file.unwrap();
+        assert_eq!(file.len(), 0);
+        assert!(file.is_labeled());
+    }
+
+    #[test]
+    fn writing_needs_a_label_per_image() {
+        let path = temp_path("batch-labels");
+        assert!(write(&path, &images(2), Some(&[1])).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn malformed_batch_files_are_refused() {
+        let path = temp_path("batch-malformed");
+        write(&path, &images(3), Some(&[0, 1, 2])).unwrap();
+        let good = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let error = |test: &str, bytes: &[u8]| {
+            open_bytes(test, bytes)
+                .err()
+                .map(|err| format!("{:#}", err))
+                .unwrap_or_default()
+        };
+
+        assert!(open_bytes("batch-good", &good).is_ok());
+        assert!(
+            error("batch-header", &good[..HEADER_SIZE - 1]).contains("truncated batch file header")
+        );
+        let mut magic = good.clone();
+        magic[0] = b'X';
+        assert!(error("batch-magic", &magic).contains("is not a batch file"));
+        let mut flags = good.clone();
+        flags[4] |= 0x2;
+        assert!(error("batch-flags", &flags).contains("unknown batch file flags 0x2"));
+        // Cut inside the images and inside the labels
+        for cut in [HEADER_SIZE + IMAGE_SIZE, good.len() - 1] {
+            assert!(error("batch-truncated", &good[..cut]).contains("truncated"));
+        }
+        // A count past the data, and trailing bytes past the count
+        let mut count = good.clone();
+        count[8] = 4;
+        assert!(error("batch-count", &count).contains("truncated"));
+        let mut trailing = good.clone();
+        trailing.push(0);
+        assert!(error("batch-trailing", &trailing).contains("count mismatch"));
+        // Without the labeled flag the labels are trailing bytes
+        let mut unlabeled = good.clone();
+        unlabeled[4] = 0;
+        assert!(error("batch-unlabeled", &unlabeled).contains("count mismatch"));
+    }
+
+    #[cfg(feature = "encrypt-model")]
+    #[test]
+    fn inference_over_a_batch_file_matches_the_model() {
+        use crate::tee::InferenceTa;
+        use burn::backend::NdArray;
+        use proto::inference::Normalization;
+
+        let dir = std::env::temp_dir();
+        let record = dir.join(format!("batch-model-{}.bin", std::process::id()));
+        let encrypted = dir.join(format!("batch-model-{}.json", std::process::id()));
+        let batch = temp_path("batch-inference");
+        let key = [0x5a; 32];
+        let device = Default::default();
+        let model = common::Model::<NdArray>::new_with_seed(&device, 7, 10);
+        std::fs::write(&record, model.export().unwrap()).unwrap();
+        crate::commands::encrypt::encrypt_model(&record, &encrypted, &key, &Default::default())
+            .unwrap();
+        let images = images(10);
+        let labels: Vec<u8> = (0..10).collect();
+        write(&batch, &images, Some(&labels)).unwrap();
+
+        // What `infer --dry-run --batch-file` does: load the model into the
+        // dry-run TA and run the file through it a window at a time
+        let mut ta = crate::sim::SimulatedTa::new(key);
+        let loaded = crate::commands::infer::load_model(&mut ta, encrypted.to_str().unwrap(), 0);
+        let sources: Vec<Box<dyn ImageSource>> = vec![Box::new(BatchFile::open(&batch).unwrap())];
+        for path in [&record, &encrypted, &batch] {
+            std::fs::remove_file(path).unwrap();
+        }
+        loaded.unwrap();
+        let mut batches = Batches::new(&sources, Default::default());
+        let (mut predicted, mut read_labels) = (Vec::new(), Vec::new());
+        while let Some(batch) = batches.next_batch(4).unwrap() {
+            assert!(batch.images.len() <= 4);
+            predicted.extend(ta.infer_batch(&batch.images, 0).unwrap());
+            read_labels.extend(batch.labels.unwrap());
+        }
+        assert_eq!(read_labels, labels);
+
+        let input =
+            common::Model::<NdArray>::images_to_tensors(&device, &images, &Normalization::MNIST);
+        assert_eq!(Some(predicted), model.predict_labels(input));
+    }
+}
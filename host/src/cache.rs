@@ -0,0 +1,306 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// On-disk cache of preprocessed input images. Entries are named after the
+// SHA-256 of the source file, its format and `PREPROCESS_VERSION`, hold the 28x28 image
+// and its warnings, and carry a checksum of both. Modification times double
+// as LRU order: hits touch their entry and the oldest entries are evicted
+// once the directory outgrows its limit.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use proto::{Image, IMAGE_SIZE};
+use sha2::{Digest, Sha256};
+
+use crate::input::{Format, Input};
+
+// Part of every key; bump when the decode/resize pipeline changes
+const PREPROCESS_VERSION: &[u8] = b"luma8 28x28 triangle v2";
+const CHECKSUM_SIZE: usize = 32;
+const ENTRY_EXTENSION: &str = "img";
+
+pub struct PreprocessCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl PreprocessCache {
+    pub fn open(dir: &Path, max_bytes: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+        })
+    }
+
+    /// Cache key of an image file with contents `source`, decoded as `format`.
+    pub fn key(source: &[u8], format: Format) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(source);
+        hasher.update(PREPROCESS_VERSION);
+        hasher.update(format!("{:?}", format));
+        hex(&hasher.finalize())
+    }
+
+    /// Entry for `key`, unless missing or failing its checksum; a corrupt
+    /// entry is removed.
+    pub fn get(&self, key: &str) -> Option<Input> {
+        let path = self.entry_path(key);
+        let data = fs::read(&path).ok()?;
+        match decode_entry(&data) {
+            Some(input) => {
+                // Refresh the LRU position; failing to do so only skews eviction
+                if let Ok(file) = fs::File::options().append(true).open(&path) {
+                    let _ = file.set_modified(SystemTime::now());
+                }
+                Some(input)
+            }
+            None => {
+                println!("warning: dropping corrupt cache entry {}", path.display());
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, input: &Input) -> anyhow::Result<()> {
+        fs::write(self.entry_path(key), encode_entry(input))?;
+        self.evict()
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(key).with_extension(ENTRY_EXTENSION)
+    }
+
+    // Removes the least recently used entries until the cache fits its limit
+    fn evict(&self) -> anyhow::Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some(ENTRY_EXTENSION) {
+                continue;
+            }
+            let metadata = fs::metadata(&path)?;
+            let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            entries.push((used, metadata.len(), path));
+        }
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort();
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+// [image][checksum][warnings, one per line]
+fn encode_entry(input: &Input) -> Vec<u8> {
+    let warnings = input.warnings.join("\n");
+    let mut data = Vec::with_capacity(IMAGE_SIZE + CHECKSUM_SIZE + warnings.len());
+    data.extend_from_slice(input.image.as_bytes());
+    data.extend_from_slice(&checksum(&input.image, warnings.as_bytes()));
+    data.extend_from_slice(warnings.as_bytes());
+    data
+}
+
+fn decode_entry(data: &[u8]) -> Option<Input> {
+    let image = Image::from_bytes(data.get(..IMAGE_SIZE)?).ok()?;
+    let stored = data.get(IMAGE_SIZE..IMAGE_SIZE + CHECKSUM_SIZE)?;
+    let warnings = &data[IMAGE_SIZE + CHECKSUM_SIZE..];
+    if checksum(&image, warnings) != stored {
+        return None;
+    }
+    let warnings = std::str::from_utf8(warnings).ok()?;
+    Some(Input {
+        image,
+        warnings: warnings
+            .split('\n')
+            .filter(|w| !w.is_empty())
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+fn checksum(image: &Image, warnings: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(image.as_bytes());
+    hasher.update(warnings);
+    hasher.finalize().into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::load_image;
+    use burn::backend::NdArray;
+    use image::DynamicImage;
+    use proto::inference::Normalization;
+
+    // A fresh cache directory named after `test`
+    fn cache_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // PNGs of `side` x `side` RGB digits, which need converting and resizing
+    fn pngs(dir: &Path, count: usize, side: u32) -> Vec<String> {
+        (0..count)
+            .map(|i| {
+                let image = image::RgbImage::from_fn(side, side, |x, y| {
+                    let on = (x as usize + i) % 9 < 3 || (y as usize * i) % 11 == 0;
+                    image::Rgb(if on { [250, 240, 230] } else { [0, 10, 5] })
+                });
+                let path = dir.join(format!("{}.png", i));
+                DynamicImage::ImageRgb8(image).save(&path).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    fn input(image: Image, warnings: &[&str]) -> Input {
+        Input {
+            image,
+            warnings: warnings.iter().map(|w| w.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn cached_runs_predict_the_same() {
+        let dir = cache_dir("predict");
+        fs::create_dir_all(&dir).unwrap();
+        let paths = pngs(&dir, 6, 40);
+        let cache = PreprocessCache::open(&dir.join("cache"), 1 << 20).unwrap();
+        let load = |cache| -> Vec<Input> {
+            paths
+                .iter()
+                .map(|path| load_image(path, Format::Auto, cache).unwrap())
+                .collect()
+        };
+        let uncached = load(None);
+        let filled = load(Some(&cache));
+        let hits = load(Some(&cache));
+        let _ = fs::remove_dir_all(&dir);
+
+        for inputs in [&filled, &hits] {
+            for (input, expected) in inputs.iter().zip(&uncached) {
+                assert!(input.image == expected.image);
+                assert_eq!(input.warnings, expected.warnings);
+            }
+        }
+        let model = common::Model::<NdArray>::new_with_seed(&Default::default(), 3, 10);
+        let predict = |inputs: &[Input]| {
+            let images: Vec<Image> = inputs.iter().map(|input| input.image).collect();
+            let tensor = common::Model::images_to_tensors(
+                &Default::default(),
+                &images,
+                &Normalization::MNIST,
+            );
+            model.predict(tensor).unwrap()
+        };
+        assert_eq!(predict(&hits), predict(&uncached));
+    }
+
+    // Photo-sized inputs, where decoding and resizing dominate; the second
+    // run only reads and hashes the files
+    #[test]
+    #[ignore = "timing, run with --ignored on an idle machine"]
+    fn cached_runs_are_faster() {
+        let dir = cache_dir("timing");
+        fs::create_dir_all(&dir).unwrap();
+        let paths = pngs(&dir, 20, 1024);
+        let cache = PreprocessCache::open(&dir.join("cache"), 1 << 20).unwrap();
+        let run = || {
+            let start = std::time::Instant::now();
+            for path in &paths {
+                load_image(path, Format::Auto, Some(&cache)).unwrap();
+            }
+            start.elapsed()
+        };
+        let (cold, warm) = (run(), run());
+        let _ = fs::remove_dir_all(&dir);
+        assert!(warm * 2 < cold, "cold {:?}, cached {:?}", cold, warm);
+    }
+
+    #[test]
+    fn keys_cover_the_contents_and_format() {
+        let key = PreprocessCache::key(b"file", Format::Auto);
+        assert_eq!(key.len(), 64);
+        assert_eq!(key, PreprocessCache::key(b"file", Format::Auto));
+        assert_ne!(key, PreprocessCache::key(b"file!", Format::Auto));
+        assert_ne!(key, PreprocessCache::key(b"file", Format::Png));
+    }
+
+    #[test]
+    fn corrupt_entries_are_dropped() {
+        let dir = cache_dir("corrupt");
+        let cache = PreprocessCache::open(&dir, 1 << 20).unwrap();
+        let entry = input(Image::BLANK, &["resized 40x40 to 28x28", "other"]);
+        cache.put("k", &entry).unwrap();
+        let cached = cache.get("k").unwrap();
+        assert!(cached.image == entry.image);
+        assert_eq!(cached.warnings, entry.warnings);
+
+        let path = cache.entry_path("k");
+        let mut data = fs::read(&path).unwrap();
+        data[3] ^= 1;
+        fs::write(&path, data).unwrap();
+        assert!(cache.get("k").is_none());
+        assert!(!path.exists());
+        fs::write(&path, [0; 10]).unwrap();
+        assert!(cache.get("k").is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let dir = cache_dir("lru");
+        let entry = input(Image::BLANK, &[]);
+        let size = encode_entry(&entry).len() as u64;
+        let cache = PreprocessCache::open(&dir, 2 * size).unwrap();
+        // Use times an hour apart, older first, so the order doesn't depend
+        // on the file system's timestamp resolution
+        let age = |key: &str, hours: u64| {
+            let file = fs::File::options()
+                .append(true)
+                .open(cache.entry_path(key))
+                .unwrap();
+            let time = SystemTime::now() - std::time::Duration::from_secs(hours * 3600);
+            file.set_modified(time).unwrap();
+        };
+        cache.put("a", &entry).unwrap();
+        age("a", 3);
+        cache.put("b", &entry).unwrap();
+        age("b", 2);
+        // A hit makes "a" the most recently used
+        assert!(cache.get("a").is_some());
+        cache.put("c", &entry).unwrap();
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,38 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+
+#[derive(Parser, Debug)]
+pub struct Args {}
+
+pub fn execute(_args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    caller.commit_model()?;
+    let status = caller.model_status(0)?;
+    match &status.name {
+        Some(name) => println!("Committed \"{}\"", name),
+        None => println!("Committed the staged model"),
+    }
+    if let Some(stored) = &status.stored {
+        println!("Active model sha256: {}", stored.model_hash);
+    }
+    Ok(())
+}
@@ -0,0 +1,293 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+use proto::{Image, IMAGE_HEIGHT, IMAGE_WIDTH};
+
+use crate::commands::infer;
+use crate::diff::{self, ModelDiff, Outputs};
+use crate::input;
+use crate::registry;
+use crate::source::{self, Batches, IdxDataset, ImageSource, Manifest};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Plaintext model records to compare, old then new; run host-side on
+    /// NdArray
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"], required_unless_present = "slots")]
+    records: Vec<String>,
+    /// TA slots holding the old and new model, compared on the device
+    #[arg(long, value_delimiter = ',', conflicts_with = "records")]
+    slots: Vec<u32>,
+    /// Encrypted models (.json) loaded into --slots first, in order
+    #[arg(short, long, requires = "slots")]
+    model: Vec<String>,
+    /// Registered models (see `model add`) loaded instead of --model, by
+    /// name or tag
+    #[arg(long, requires = "slots", conflicts_with = "model")]
+    model_name: Vec<String>,
+    /// IDX images file (e.g. t10k-images-idx3-ubyte) to run through both
+    /// models
+    #[arg(long, required_unless_present = "manifest")]
+    idx: Option<String>,
+    /// Records of --idx to use, `N` or `N..M` (M exclusive); all by default
+    #[arg(long, requires = "idx", value_parser = infer::parse_index_range)]
+    index: Option<std::ops::Range<usize>>,
+    /// JSON list of `{"image": <path>}` entries, paths relative to the
+    /// manifest (canary manifests work as they are)
+    #[arg(long, conflicts_with = "idx")]
+    manifest: Option<String>,
+    /// Number of inputs with the largest probability shift to report
+    #[arg(long, default_value_t = 10)]
+    top: usize,
+    /// Write the reported inputs to this directory as PNGs
+    #[arg(long)]
+    dump: Option<PathBuf>,
+    /// Also write the report to this .json file
+    #[arg(long)]
+    report: Option<PathBuf>,
+    /// Number of images sent to the TA per invocation
+    #[arg(long, default_value_t = 64)]
+    batch_size: usize,
+}
+
+/// `--report` contents.
+#[derive(serde::Serialize)]
+struct Report<'a> {
+    old: &'a str,
+    new: &'a str,
+    disagreement_rate: f64,
+    #[serde(flatten)]
+    diff: &'a ModelDiff,
+    /// Names of the `largest_shifts` inputs, in the same order.
+    shifted_inputs: Vec<&'a str>,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    anyhow::ensure!(args.batch_size > 0, "batch size must be positive");
+    let sources = [open_inputs(args)?];
+    source::print_warnings(&sources);
+    let total = source::total(&sources);
+    anyhow::ensure!(total != 0, "the dataset holds no images");
+    // Compared as they are, without alignment
+    let images = Batches::new(&sources, input::Alignment::default())
+        .next_batch(total)?
+        .map(|batch| batch.images)
+        .unwrap_or_default();
+    let inputs = source::names(&sources);
+    println!("Comparing on {} images", images.len());
+
+    let (names, old, new) = if args.records.is_empty() {
+        anyhow::ensure!(
+            args.slots.len() == 2,
+            "--slots takes the old and the new slot"
+        );
+        let (old, new) = run_on_device(args, &images)?;
+        let names = args.slots.iter().map(|slot| format!("slot {}", slot));
+        (names.collect::<Vec<_>>(), old, new)
+    } else {
+        let old = run_on_host(&args.records[0], &images)?;
+        let new = run_on_host(&args.records[1], &images)?;
+        (args.records.clone(), old, new)
+    };
+    let diff = diff::compare(&old, &new, args.top)?;
+
+    println!("Old: {}", names[0]);
+    println!("New: {}", names[1]);
+    println!(
+        "Disagreements: {}/{} ({:.2}%)",
+        diff.disagreements,
+        diff.inputs,
+        diff.disagreement_rate() * 100.0
+    );
+    if diff.tied_disagreements > 0 {
+        println!(
+            "  {} of them on tied top classes, decided by the tie-break rule",
+            diff.tied_disagreements
+        );
+    }
+    print_confusion(&diff.confusion);
+    if !diff.largest_shifts.is_empty() {
+        println!("Largest probability shifts:");
+    }
+    for shift in &diff.largest_shifts {
+        println!(
+            "  {}: {} -> {} (shift {:.3}{})",
+            inputs[shift.index],
+            shift.old_label,
+            shift.new_label,
+            shift.shift,
+            if shift.tied { ", tie" } else { "" }
+        );
+    }
+
+    if let Some(dir) = &args.dump {
+        dump_inputs(dir, &diff, &images)?;
+        println!("Inputs written to {}", dir.display());
+    }
+    if let Some(path) = &args.report {
+        anyhow::ensure!(
+            path.extension().and_then(|s| s.to_str()) == Some("json"),
+            "--report must end in .json"
+        );
+        let report = Report {
+            old: &names[0],
+            new: &names[1],
+            disagreement_rate: diff.disagreement_rate(),
+            diff: &diff,
+            shifted_inputs: diff
+                .largest_shifts
+                .iter()
+                .map(|shift| inputs[shift.index].as_str())
+                .collect(),
+        };
+        std::fs::write(path, serde_json::to_vec_pretty(&report)?)?;
+        println!("Report written to {}", path.display());
+    }
+    Ok(())
+}
+
+fn open_inputs(args: &Args) -> anyhow::Result<Box<dyn ImageSource>> {
+    Ok(match (&args.idx, &args.manifest) {
+        (Some(path), _) => Box::new(IdxDataset::open(path, args.index.clone())?),
+        (None, Some(path)) => Box::new(Manifest::open(path)?),
+        (None, None) => anyhow::bail!("--idx or --manifest is required"),
+    })
+}
+
+// Outputs of the models in the two slots, loading the given files first
+fn run_on_device(args: &Args, images: &[Image]) -> anyhow::Result<(Outputs, Outputs)> {
+    let models = registry::model_paths(&args.model, &args.model_name)?;
+    anyhow::ensure!(
+        models.is_empty() || models.len() == args.slots.len(),
+        "got {} models but {} slots",
+        models.len(),
+        args.slots.len()
+    );
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    for (path, &slot) in models.iter().zip(&args.slots) {
+        infer::load_model(&mut caller, path, slot)?;
+    }
+    let mut outputs = Vec::with_capacity(args.slots.len());
+    for &slot in &args.slots {
+        let num_classes = caller.model_status(slot)?.num_classes;
+        let mut labels = Vec::with_capacity(images.len());
+        let mut probs = Vec::with_capacity(images.len() * num_classes);
+        for batch in images.chunks(args.batch_size) {
+            let (batch_labels, batch_probs) =
+                caller.infer_batch_with_probabilities(batch, 1.0, slot, num_classes)?;
+            labels.extend(batch_labels);
+            probs.extend(batch_probs);
+        }
+        outputs.push(Outputs {
+            labels,
+            probs,
+            num_classes,
+        });
+    }
+    let new = outputs.pop().unwrap();
+    let old = outputs.pop().unwrap();
+    Ok((old, new))
+}
+
+// Outputs of the plaintext record at `path`, computed like the TA does
+#[cfg(feature = "encrypt-model")]
+fn run_on_host(path: &str, images: &[Image]) -> anyhow::Result<Outputs> {
+    use burn::{backend::NdArray, prelude::*};
+    type Model = common::Model<NdArray>;
+    // Keeps the tensors of a large dataset bounded
+    const HOST_BATCH: usize = 256;
+
+    let device: <NdArray as Backend>::Device = Default::default();
+    let (metadata, format, record) = common::split_container(std::fs::read(path)?)?;
+    let model = Model::import_as(&device, record, format)?;
+    let normalization = metadata
+        .as_ref()
+        .map(common::ModelMetadata::normalization)
+        .unwrap_or_default();
+    let num_classes = model.num_classes();
+    let mut labels = Vec::with_capacity(images.len());
+    let mut probs = Vec::with_capacity(images.len() * num_classes);
+    for batch in images.chunks(HOST_BATCH) {
+        let input = Model::images_to_tensors(&device, batch, &normalization);
+        let (batch_labels, batch_probs) = model
+            .predict_with_temperature(input, 1.0)
+            .ok_or_else(|| anyhow::anyhow!("{}: class index doesn't fit in a byte", path))?;
+        labels.extend(batch_labels);
+        probs.extend(batch_probs);
+    }
+    Ok(Outputs {
+        labels,
+        probs,
+        num_classes,
+    })
+}
+
+#[cfg(not(feature = "encrypt-model"))]
+fn run_on_host(_path: &str, _images: &[Image]) -> anyhow::Result<Outputs> {
+    anyhow::bail!("--records needs a host built with the encrypt-model feature")
+}
+
+// Rows are the old model's classes, columns the new one's
+fn print_confusion(confusion: &[Vec<usize>]) {
+    let width = confusion
+        .iter()
+        .flatten()
+        .map(|count| count.to_string().len())
+        .max()
+        .unwrap_or(1)
+        .max(3);
+    println!("Confusion (rows: old, columns: new):");
+    let header: String = (0..confusion.len())
+        .map(|class| format!(" {:>width$}", class, width = width))
+        .collect();
+    println!("  {:>width$}{}", "", header, width = width);
+    for (class, row) in confusion.iter().enumerate() {
+        let cells: String = row
+            .iter()
+            .map(|count| format!(" {:>width$}", count, width = width))
+            .collect();
+        println!("  {:>width$}{}", class, cells, width = width);
+    }
+}
+
+// One PNG per reported input, named after its rank, index and labels
+fn dump_inputs(dir: &Path, diff: &ModelDiff, images: &[Image]) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    for (rank, shift) in diff.largest_shifts.iter().enumerate() {
+        let image = image::GrayImage::from_raw(
+            IMAGE_WIDTH as u32,
+            IMAGE_HEIGHT as u32,
+            images[shift.index].as_bytes().to_vec(),
+        )
+        .ok_or_else(|| anyhow::anyhow!("image size mismatch"))?;
+        let name = format!(
+            "{:02}_{}_{}-{}.png",
+            rank + 1,
+            shift.index,
+            shift.old_label,
+            shift.new_label
+        );
+        image.save(dir.join(name))?;
+    }
+    Ok(())
+}
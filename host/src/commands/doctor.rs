@@ -0,0 +1,130 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::Path;
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+
+use crate::doctor::{self, Check, Outcome, RealSystem};
+
+/// Checks the TEE device, tee-supplicant, the TA binary and the provisioned
+/// model, and tells how to fix what fails
+#[derive(Parser, Debug)]
+pub struct Args {}
+
+pub fn execute(_args: &Args) -> anyhow::Result<()> {
+    let device = doctor::check_device(&RealSystem, Path::new(doctor::TEE_DEVICE));
+    let reachable = device.outcome == Outcome::Pass;
+    let mut checks = vec![
+        device,
+        doctor::check_supplicant(&RealSystem),
+        doctor::check_ta_binary(&RealSystem, crate::tee::configured_ta_uuid()),
+    ];
+    checks.push(if reachable {
+        check_build()
+    } else {
+        Check::skip("TA build", "the TEE device can't be opened".to_string())
+    });
+    checks.push(if reachable {
+        check_provisioned()
+    } else {
+        Check::skip(
+            "provisioned model",
+            "the TEE device can't be opened".to_string(),
+        )
+    });
+
+    for check in &checks {
+        let label = match check.outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "SKIP",
+        };
+        println!("[{}] {}: {}", label, check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            for line in hint.lines() {
+                println!("       {}", line);
+            }
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|check| check.outcome == Outcome::Fail)
+        .count();
+    anyhow::ensure!(failed == 0, "{} of {} checks failed", failed, checks.len());
+    Ok(())
+}
+
+// Which features and limits the TA was built with
+fn check_build() -> Check {
+    const NAME: &str = "TA build";
+    let manifest = Context::new().and_then(|mut ctx| {
+        let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Infer)?;
+        caller.build_manifest()
+    });
+    match manifest {
+        Ok(Some(manifest)) => Check::pass(
+            NAME,
+            format!(
+                "features: {}; heap {} bytes, model memory budget {} bytes",
+                super::status::features(&manifest),
+                manifest.heap_size,
+                manifest.model_memory_budget
+            ),
+        ),
+        Ok(None) => Check::skip(NAME, "the TA predates build manifests".to_string()),
+        Err(err) => Check::fail(
+            NAME,
+            format!("cannot query the TA: {}", err),
+            "check the tee-supplicant and TA binary results above, and the TA log".to_string(),
+        ),
+    }
+}
+
+// Whether the TA answers and keeps a model in secure storage
+fn check_provisioned() -> Check {
+    const NAME: &str = "provisioned model";
+    let status = Context::new().and_then(|mut ctx| {
+        let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Infer)?;
+        caller.model_status(0)
+    });
+    match status {
+        Ok(status) => match status.stored {
+            Some(stored) => Check::pass(
+                NAME,
+                format!(
+                    "{} ({} bytes, sha256 {})",
+                    stored.name.as_deref().unwrap_or("unnamed"),
+                    stored.encrypted_size,
+                    stored.model_hash
+                ),
+            ),
+            None => Check::fail(
+                NAME,
+                "no model in secure storage".to_string(),
+                "provision one with `store-key` and `provision --model <file>`".to_string(),
+            ),
+        },
+        Err(err) => Check::fail(
+            NAME,
+            format!("cannot query the TA: {}", err),
+            "check the tee-supplicant and TA binary results above, and the TA log".to_string(),
+        ),
+    }
+}
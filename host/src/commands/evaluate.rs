@@ -0,0 +1,488 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{SessionRole, FLAG_FIXED_POINT_PROBS, REJECT_LABEL};
+use proto::{Image, IMAGE_HEIGHT, IMAGE_WIDTH};
+
+use crate::batch_file::{self, BatchFile};
+use crate::source::{self, Batches, ImageSource, MnistTestSet};
+use crate::tee::InferenceTa;
+
+// Temperatures swept by `--calibrate`: 0.05, 0.10, ..., 5.00
+const CALIBRATION_STEP: f64 = 0.05;
+const CALIBRATION_STEPS: usize = 100;
+/// Manifest `--dump-errors` writes next to the images.
+pub const ERROR_MANIFEST: &str = "errors.csv";
+const ERROR_MANIFEST_HEADER: &str = "binary,png,true,predicted,confidence";
+/// Batch file of the same images, with their true labels, named with
+/// `batch_file::EXTENSION`.
+const ERROR_BATCH: &str = "errors";
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// The path of the model.
+    #[arg(short, long, required_unless_present = "encrypted")]
+    model: Option<String>,
+    /// Evaluate this encrypted model (.json) on the host instead of a TA,
+    /// decrypting it with --key (needs the dev-tools feature)
+    #[arg(long, conflicts_with = "model", requires = "key")]
+    encrypted: Option<String>,
+    /// 32-byte AES key in hex (64 hex chars) decrypting --encrypted
+    #[arg(long, requires = "encrypted")]
+    key: Option<String>,
+    /// Directory holding the MNIST test set (t10k-images-idx3-ubyte, t10k-labels-idx1-ubyte)
+    #[arg(short, long, required_unless_present = "batch_file")]
+    data: Option<String>,
+    /// Labeled batch file to evaluate on instead of --data, read one batch
+    /// at a time
+    #[arg(long, conflicts_with = "data")]
+    batch_file: Option<PathBuf>,
+    /// Number of images sent to the TA per invocation; defaults to what the
+    /// TA recommends for the model and is capped at its maximum
+    #[arg(long)]
+    batch_size: Option<usize>,
+    /// Only evaluate the first N test images
+    #[arg(long)]
+    limit: Option<usize>,
+    /// Softmax temperature used for the reported confidences, must be positive
+    #[arg(long, value_parser = super::infer::parse_temperature)]
+    temperature: Option<f32>,
+    /// Sweep temperatures and report the one minimizing negative log-likelihood
+    #[arg(long, conflicts_with = "temperature")]
+    calibrate: bool,
+    /// Reject images whose confidence is below this probability and report
+    /// coverage and the accuracy on the accepted ones
+    #[arg(long, value_parser = super::infer::parse_reject_threshold)]
+    reject_below: Option<f32>,
+    /// Have the TA return probabilities as 16-bit fixed point, halving its
+    /// output; each image's row still sums to exactly 1 (needs a TA that
+    /// knows the flag)
+    #[arg(long, conflicts_with = "calibrate")]
+    fixed_point_probs: bool,
+    /// Print class numbers even when the model names its classes
+    #[arg(long)]
+    numeric_labels: bool,
+    /// Scale each digit to fit 20x20 and center it by mass, as in `infer`
+    #[arg(long)]
+    center: bool,
+    /// Straighten slanted digits using their image moments, as in `infer`
+    #[arg(long)]
+    deskew: bool,
+    /// Write the misclassified images to this directory, one subdirectory
+    /// per true class, as raw binaries (for `infer -b` and `train --extra`)
+    /// and PNGs, listed in errors.csv, and all of them in errors.emnb (for
+    /// `--batch-file`)
+    #[arg(long)]
+    dump_errors: Option<PathBuf>,
+    /// Dump at most this many misclassified images, the most confident first
+    #[arg(long, requires = "dump_errors")]
+    max_errors: Option<usize>,
+}
+
+// Labeled test images: the batch file, or the MNIST test set in --data
+fn test_set(args: &Args) -> anyhow::Result<Box<dyn ImageSource>> {
+    if let Some(path) = &args.batch_file {
+        let file = BatchFile::open(path)?;
+        anyhow::ensure!(
+            file.is_labeled(),
+            "{} has no labels to evaluate against",
+            path.display()
+        );
+        return Ok(Box::new(file));
+    }
+    let dir = args.data.as_deref().unwrap_or_default();
+    Ok(Box::new(MnistTestSet::open(dir)?))
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let sources = [test_set(args)?];
+    let total = source::total(&sources).min(args.limit.unwrap_or(usize::MAX));
+    anyhow::ensure!(total != 0, "no test images to evaluate");
+    let alignment = crate::input::Alignment {
+        center: args.center,
+        deskew: args.deskew,
+    };
+
+    // Outlives the connector's session
+    let mut ctx = None;
+    let (mut caller, model): (Box<dyn InferenceTa>, _) = match &args.encrypted {
+        Some(path) => (
+            super::infer::host_side_ta(args.key.as_deref().unwrap_or_default())?,
+            path,
+        ),
+        None => {
+            let ctx = ctx.insert(Context::new()?);
+            let connector = crate::tee::InferenceTaConnector::new(ctx, SessionRole::Manage)?;
+            (Box::new(connector), args.model.as_ref().unwrap())
+        }
+    };
+    super::infer::load_model(caller.as_mut(), model, 0)?;
+    let status = caller.model_status(0)?;
+    let num_classes = status.num_classes;
+    // Applied by the TA to the raw pixels sent below
+    println!("Input normalization: {}", status.normalization);
+    let batch_size = super::infer::batch_size(args.batch_size, status.batch_hint.as_ref())?;
+    if args.reject_below.is_some() {
+        caller.set_reject_threshold(args.reject_below)?;
+    }
+    if args.fixed_point_probs {
+        caller.set_input_flags(FLAG_FIXED_POINT_PROBS)?;
+    }
+
+    // Calibration rescales the T = 1 probabilities host-side, so one pass is enough
+    let temperature = args.temperature.unwrap_or(1.0);
+    let mut predictions = Vec::with_capacity(total);
+    let mut probabilities = Vec::with_capacity(total * num_classes);
+    let mut labels = Vec::with_capacity(total);
+    // Only kept for --dump-errors
+    let mut images = Vec::new();
+    let mut progress = crate::progress::start("Evaluating", "images", Some(total as u64));
+    let mut batches = Batches::new(&sources, alignment);
+    while labels.len() < total {
+        let max = batch_size.min(total - labels.len());
+        let Some(batch) = batches.next_batch(max)? else {
+            break;
+        };
+        let (batch_predictions, probs) =
+            caller.infer_batch_with_probabilities(&batch.images, temperature, 0, num_classes)?;
+        anyhow::ensure!(batch_predictions.len() == batch.images.len());
+        predictions.extend(batch_predictions);
+        probabilities.extend(probs);
+        // Both test sets are labeled throughout
+        labels.extend(batch.labels.unwrap_or_default());
+        progress.tick(batch.images.len() as u64);
+        if args.dump_errors.is_some() {
+            images.extend(batch.images);
+        }
+    }
+    progress.finish();
+
+    // Rejected images count as misses here and in the per-class figures
+    let correct = predictions
+        .iter()
+        .zip(&labels)
+        .filter(|(predicted, expected)| predicted == expected)
+        .count();
+    println!(
+        "Accuracy: {}/{} ({:.2}%)",
+        correct,
+        labels.len(),
+        correct as f64 * 100.0 / labels.len() as f64
+    );
+    if let Some(threshold) = args.reject_below {
+        let accepted = predictions
+            .iter()
+            .filter(|&&predicted| predicted != REJECT_LABEL)
+            .count();
+        println!(
+            "Coverage (confidence >= {}): {}/{} ({:.2}%)",
+            threshold,
+            accepted,
+            labels.len(),
+            accepted as f64 * 100.0 / labels.len() as f64
+        );
+        if accepted == 0 {
+            println!("Accuracy on accepted: n/a, every image was rejected");
+        } else {
+            println!(
+                "Accuracy on accepted: {}/{} ({:.2}%)",
+                correct,
+                accepted,
+                correct as f64 * 100.0 / accepted as f64
+            );
+        }
+    }
+    let class_labels = if args.numeric_labels {
+        Vec::new()
+    } else {
+        caller.class_labels(0)?
+    };
+    for class in 0..num_classes {
+        let total = labels
+            .iter()
+            .filter(|&&label| label as usize == class)
+            .count();
+        if total == 0 {
+            continue;
+        }
+        let correct = predictions
+            .iter()
+            .zip(&labels)
+            .filter(|&(&predicted, &label)| predicted == label && label as usize == class)
+            .count();
+        println!(
+            "  {}: {}/{} ({:.2}%)",
+            super::infer::label_name(&class_labels, class as u8),
+            correct,
+            total,
+            correct as f64 * 100.0 / total as f64
+        );
+    }
+    println!(
+        "Negative log-likelihood (T = {}): {:.4}",
+        temperature,
+        negative_log_likelihood(&probabilities, &labels, num_classes, 1.0)
+    );
+
+    if args.calibrate {
+        let (best_temperature, best_nll) = calibrate(&probabilities, &labels, num_classes);
+        println!(
+            "Calibrated temperature: {:.2} (negative log-likelihood {:.4})",
+            best_temperature, best_nll
+        );
+    }
+    if let Some(dir) = &args.dump_errors {
+        let errors = misclassified(&predictions, &labels, &probabilities, num_classes);
+        let count = errors.len().min(args.max_errors.unwrap_or(usize::MAX));
+        dump_errors(dir, &errors[..count], &images, &labels, &predictions)?;
+        println!(
+            "Wrote {} of {} misclassified images to {}",
+            count,
+            errors.len(),
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+// Indices of the images given a wrong class (rejected ones aren't), with the
+// confidence in it, the most confident first
+fn misclassified(
+    predictions: &[u8],
+    labels: &[u8],
+    probabilities: &[f32],
+    num_classes: usize,
+) -> Vec<(usize, f32)> {
+    let mut errors: Vec<(usize, f32)> = predictions
+        .iter()
+        .zip(labels)
+        .enumerate()
+        .filter(|&(_, (&predicted, &label))| predicted != label && predicted != REJECT_LABEL)
+        .map(|(index, (&predicted, _))| {
+            let confidence = probabilities[index * num_classes + predicted as usize];
+            (index, confidence)
+        })
+        .collect();
+    errors.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    errors
+}
+
+// `<true>/<true>_<predicted>_<index>.bin` and `.png` under `dir` for every
+// error, plus the manifest with their paths relative to `dir` and a batch
+// file of them all
+fn dump_errors(
+    dir: &Path,
+    errors: &[(usize, f32)],
+    images: &[Image],
+    labels: &[u8],
+    predictions: &[u8],
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    let mut manifest = format!("{}\n", ERROR_MANIFEST_HEADER);
+    for &(index, confidence) in errors {
+        let (label, predicted) = (labels[index], predictions[index]);
+        std::fs::create_dir_all(dir.join(label.to_string()))?;
+        let stem = format!("{}/{}_{}_{}", label, label, predicted, index);
+        let binary = format!("{}.bin", stem);
+        let png = format!("{}.png", stem);
+        std::fs::write(dir.join(&binary), images[index].as_bytes())?;
+        image::GrayImage::from_raw(
+            IMAGE_WIDTH as u32,
+            IMAGE_HEIGHT as u32,
+            images[index].as_bytes().to_vec(),
+        )
+        .ok_or_else(|| anyhow::anyhow!("image size mismatch"))?
+        .save(dir.join(&png))?;
+        manifest.push_str(&format!(
+            "{},{},{},{},{:.4}\n",
+            binary, png, label, predicted, confidence
+        ));
+    }
+    std::fs::write(dir.join(ERROR_MANIFEST), manifest)?;
+    let dumped: Vec<Image> = errors.iter().map(|&(index, _)| images[index]).collect();
+    let dumped_labels: Vec<u8> = errors.iter().map(|&(index, _)| labels[index]).collect();
+    let batch = dir.join(ERROR_BATCH).with_extension(batch_file::EXTENSION);
+    batch_file::write(&batch, &dumped, Some(&dumped_labels))?;
+    Ok(())
+}
+
+/// Images listed in a `--dump-errors` manifest, with their true labels. The
+/// binaries are read the way `infer -b` reads them.
+#[cfg(feature = "encrypt-model")]
+pub fn read_error_manifest(path: &Path) -> anyhow::Result<Vec<(Image, u8)>> {
+    let manifest =
+        std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let mut lines = manifest.lines();
+    anyhow::ensure!(
+        lines.next() == Some(ERROR_MANIFEST_HEADER),
+        "{} is not an evaluate --dump-errors manifest",
+        path.display()
+    );
+    let dir = path.parent().unwrap_or(Path::new(""));
+    lines
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            anyhow::ensure!(
+                fields.len() == 5,
+                "{}: malformed line {:?}",
+                path.display(),
+                line
+            );
+            let label = fields[2]
+                .parse()
+                .with_context(|| format!("{}: invalid label in {:?}", path.display(), line))?;
+            let input = crate::input::load_binary(&dir.join(fields[0]).to_string_lossy())?;
+            Ok((input.image, label))
+        })
+        .collect()
+}
+
+/// rust_mnist joins file names onto the directory without a separator.
+pub fn mnist_data_path(dir: &str) -> String {
+    let mut path = dir.to_string();
+    if !path.ends_with('/') {
+        path.push('/');
+    }
+    path
+}
+
+/// Sweeps temperatures over `probs` (softmax outputs at T = 1, `num_classes`
+/// per image) and returns the temperature minimizing the negative
+/// log-likelihood of `labels`, together with that likelihood.
+pub fn calibrate(probs: &[f32], labels: &[u8], num_classes: usize) -> (f64, f64) {
+    (1..=CALIBRATION_STEPS)
+        .map(|step| {
+            let temperature = step as f64 * CALIBRATION_STEP;
+            (
+                temperature,
+                negative_log_likelihood(probs, labels, num_classes, temperature),
+            )
+        })
+        .fold((1.0, f64::INFINITY), |best, candidate| {
+            if candidate.1 < best.1 {
+                candidate
+            } else {
+                best
+            }
+        })
+}
+
+/// Mean negative log-likelihood of `labels` after rescaling `probs` to the
+/// given temperature: softmax(z / t) is proportional to softmax(z)^(1 / t).
+fn negative_log_likelihood(
+    probs: &[f32],
+    labels: &[u8],
+    num_classes: usize,
+    temperature: f64,
+) -> f64 {
+    let total: f64 = probs
+        .chunks_exact(num_classes)
+        .zip(labels)
+        .map(|(row, &label)| {
+            let scaled: Vec<f64> = row
+                .iter()
+                .map(|&p| (p.max(f32::MIN_POSITIVE) as f64).ln() / temperature)
+                .collect();
+            let max = scaled.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let log_sum = max + scaled.iter().map(|v| (v - max).exp()).sum::<f64>().ln();
+            log_sum - scaled[label as usize]
+        })
+        .sum();
+    total / labels.len() as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Four images, the second rejected and the third correct
+    fn evaluated() -> (Vec<Image>, Vec<u8>, Vec<u8>, Vec<f32>) {
+        let images = (0..4)
+            .map(|i| {
+                let pixels: Vec<u8> = (0..IMAGE_WIDTH * IMAGE_HEIGHT)
+                    .map(|j| (j * 3 + i * 50) as u8)
+                    .collect();
+                Image::from_luma28(pixels.as_slice().try_into().unwrap())
+            })
+            .collect();
+        let labels = vec![3, 7, 1, 7];
+        let predictions = vec![8, REJECT_LABEL, 1, 2];
+        let mut probabilities = vec![0.0; 4 * 10];
+        probabilities[8] = 0.6;
+        probabilities[10 + 7] = 0.3;
+        probabilities[20 + 1] = 0.9;
+        probabilities[30 + 2] = 0.8;
+        (images, labels, predictions, probabilities)
+    }
+
+    #[test]
+    fn only_wrong_classes_are_mined_the_most_confident_first() {
+        let (_, labels, predictions, probabilities) = evaluated();
+        assert_eq!(
+            misclassified(&predictions, &labels, &probabilities, 10),
+            [(3, 0.8), (0, 0.6)]
+        );
+    }
+
+    #[test]
+    fn dumped_errors_read_back_as_inputs_and_training_samples() {
+        let (images, labels, predictions, probabilities) = evaluated();
+        let dir = std::env::temp_dir().join(format!("evaluate-dump-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let errors = misclassified(&predictions, &labels, &probabilities, 10);
+        dump_errors(&dir, &errors, &images, &labels, &predictions).unwrap();
+
+        // One subdirectory per true class, named <true>_<pred>_<index>
+        let bin = dir.join("7/7_2_3.bin");
+        assert!(dir.join("3/3_8_0.bin").is_file());
+        assert!(!dir.join("1").exists());
+        // What `infer -b` reads
+        let input = crate::input::load_binary(bin.to_str().unwrap()).unwrap();
+        assert_eq!(input.image, images[3]);
+        let png = image::open(dir.join("7/7_2_3.png")).unwrap().into_luma8();
+        assert_eq!(png.as_raw().as_slice(), images[3].as_bytes());
+        // What `--batch-file` reads
+        let sources: [Box<dyn ImageSource>; 1] =
+            [Box::new(BatchFile::open(&dir.join("errors.emnb")).unwrap())];
+        let batch = Batches::new(&sources, Default::default())
+            .next_batch(10)
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.images, [images[3], images[0]]);
+        assert_eq!(batch.labels, Some(vec![7, 3]));
+        // What `train --extra` reads, with the true labels
+        #[cfg(feature = "encrypt-model")]
+        assert_eq!(
+            read_error_manifest(&dir.join(ERROR_MANIFEST)).unwrap(),
+            [(images[3], 7), (images[0], 3)]
+        );
+        let manifest = std::fs::read_to_string(dir.join(ERROR_MANIFEST)).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(
+            manifest,
+            "binary,png,true,predicted,confidence\n\
+             7/7_2_3.bin,7/7_2_3.png,7,2,0.8000\n\
+             3/3_8_0.bin,3/3_8_0.png,3,8,0.6000\n"
+        );
+    }
+}
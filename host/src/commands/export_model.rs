@@ -0,0 +1,84 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{export_chunks, SessionRole};
+use sha2::{Digest, Sha256};
+
+use crate::tee::InferenceTaConnector;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// File to write the encrypted model to, in the format of `encrypt-model`
+    #[arg(long)]
+    output: PathBuf,
+}
+
+#[derive(serde::Serialize)]
+struct EncryptedModelFile<'a> {
+    algorithm: &'a str,
+    encrypted_data: &'a [u8],
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let mut caller = InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    let (size, expected) = caller.begin_model_export()?;
+    println!("Exporting {} encrypted bytes", size);
+    let read = read_export(&mut caller, size);
+    // The TA keeps its copy until told otherwise, also after a failed read
+    caller.end_model_export()?;
+    let ciphertext = read?;
+
+    let hash: [u8; 32] = Sha256::digest(&ciphertext).into();
+    anyhow::ensure!(
+        hash == expected,
+        "export is corrupted: SHA-256 {}, the TA reported {}",
+        hex(&hash),
+        hex(&expected)
+    );
+    println!("SHA-256 verified: {}", hex(&hash));
+    let file = EncryptedModelFile {
+        algorithm: "AES-256-CBC",
+        encrypted_data: &ciphertext,
+    };
+    std::fs::write(&args.output, serde_json::to_vec_pretty(&file)?)?;
+    println!("Encrypted model saved to: {}", args.output.display());
+    Ok(())
+}
+
+// Reads the export in the ranges of `export_chunks`, so every byte is read
+// exactly once
+fn read_export(caller: &mut InferenceTaConnector, size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut ciphertext = vec![0_u8; size];
+    let mut progress =
+        crate::progress::start("Receiving encrypted model", "bytes", Some(size as u64));
+    for chunk in export_chunks(size) {
+        let len = chunk.len();
+        caller.read_encrypted_chunk(chunk.start, &mut ciphertext[chunk])?;
+        progress.tick(len as u64);
+    }
+    progress.finish();
+    Ok(ciphertext)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
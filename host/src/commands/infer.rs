@@ -16,87 +16,721 @@
 // under the License.
 
 use clap::Parser;
-use image::EncodableLayout;
 use optee_teec::Context;
-use proto::{Image, IMAGE_SIZE};
+use proto::inference::{
+    self, BatchHint, Normalization, Prediction, SessionRole, MODEL_SLOTS, REJECT_LABEL,
+};
+use proto::model_file::{self, EncryptedModel, ModelFileError};
+use proto::Image;
 use serde_json;
 
-#[derive(serde::Deserialize)]
-struct EncryptedModelFile {
-    algorithm: String,
-    encrypted_data: Vec<u8>,
-}
-
-#[derive(serde::Deserialize)]
-struct ChunkedEncryptedModelFile {
-    algorithm: String,
-    chunk_size: usize,
-    total_chunks: usize,
-    original_size: usize,
-    chunks: Vec<EncryptedChunk>,
-}
-
-#[derive(serde::Deserialize)]
-struct EncryptedChunk {
-    id: usize,
-    size: usize,
-    data: Vec<u8>,
-}
+use crate::batch_file::BatchFile;
+use crate::cache::PreprocessCache;
+use crate::input;
+use crate::recorded_run::{RecordedRun, RunSettings};
+use crate::registry;
+use crate::source::{self, Batches, BinaryFile, DecodedImage, IdxDataset, ImageSource};
+use crate::tee::{BudgetOutcome, InferenceTa, RecordedBatch};
+use crate::transcript::{self, Redacted, Step};
+use crate::upload;
 
 #[derive(Parser, Debug)]
 pub struct Args {
-    /// The path of the model.
+    /// The path of the model, can be multiple (one per slot); without one the
+    /// model kept in the TA's secure storage is used
     #[arg(short, long)]
-    model: String,
+    model: Vec<String>,
+    /// Registered models (see `model add`) to load instead of --model, by
+    /// name or tag
+    #[arg(long, conflicts_with = "model")]
+    model_name: Vec<String>,
+    /// Slots the models are loaded into, in order (defaults to 0, 1, ...)
+    #[arg(long, value_delimiter = ',')]
+    slots: Vec<u32>,
+    /// Average the softmax outputs of all loaded models
+    #[arg(long)]
+    ensemble: bool,
     /// The path of the input binary, must be IMAGE_SIZE byte binary, can be multiple
     #[arg(short, long)]
     binary: Vec<String>,
     /// The path of the input image, must be dimension of 28x28x1 (MNIST), can be multiple
     #[arg(short, long)]
     image: Vec<String>,
+    /// Format of the --image inputs: one for all of them or one per image,
+    /// in order
+    #[arg(long, value_enum, value_delimiter = ',')]
+    format: Vec<input::Format>,
+    /// IDX images file (e.g. t10k-images-idx3-ubyte) to take inputs from
+    #[arg(long)]
+    idx: Option<String>,
+    /// Records of --idx to infer, `N` or `N..M` (M exclusive); all by default
+    #[arg(long, requires = "idx", value_parser = parse_index_range)]
+    index: Option<std::ops::Range<usize>>,
+    /// Batch file of images (`evaluate --dump-errors` writes one) to take
+    /// inputs from, read one batch at a time; its labels, if any, are
+    /// checked against the predictions
+    #[arg(long, conflicts_with_all = ["binary", "image", "idx", "record_run"])]
+    batch_file: Option<std::path::PathBuf>,
+    /// Softmax temperature used for the reported confidences, must be positive
+    #[arg(long, value_parser = parse_temperature)]
+    temperature: Option<f32>,
+    /// Images sent to the TA per invocation; defaults to what the TA
+    /// recommends for the loaded model and is capped at its maximum
+    #[arg(long)]
+    batch_size: Option<usize>,
+    /// Copy label-only batches into one buffer allocated up front and reused
+    /// for every call, SHARED_MEM_BATCH images per call
+    #[arg(long)]
+    shared_mem: bool,
+    /// Load and run the models in the simulated TA instead of a TEE
+    #[arg(long, requires = "key")]
+    dry_run: bool,
+    /// 32-byte AES key in hex the simulated TA decrypts models with
+    #[arg(long)]
+    key: Option<String>,
+    /// Tag every image with an id the TA echoes, and fail unless each result
+    /// comes back with the id of its image (reports confidences)
+    #[arg(long, conflicts_with = "ensemble")]
+    correlate: bool,
+    /// Inputs are already standardized: only scale them to [0, 1], skipping
+    /// the mean/std of the model's normalization profile
+    #[arg(long, conflicts_with = "ensemble")]
+    raw_scale: bool,
+    /// Feed the pixel values to the model as they are, without any scaling
+    #[arg(long, conflicts_with_all = ["ensemble", "raw_scale"])]
+    no_normalize: bool,
+    /// Report images whose confidence is below this probability as rejected
+    /// instead of guessing a class (reports confidences)
+    #[arg(long, value_parser = parse_reject_threshold, conflicts_with = "ensemble")]
+    reject_below: Option<f32>,
+    /// Don't run the shadow model (see `shadow enable`) for these images,
+    /// when the answer is needed fast
+    #[arg(long, conflicts_with = "ensemble")]
+    skip_shadow: bool,
+    /// Configure the TA's result cache, which answers images it classified
+    /// before without running the model: on, off or size=N entries. The
+    /// setting stays with the TA for later sessions
+    #[arg(long, value_name = "on|off|size=N", value_parser = parse_ta_cache)]
+    ta_cache: Option<u32>,
+    /// Run the model on these images even if the TA's result cache has them
+    #[arg(long, conflicts_with = "ensemble")]
+    bypass_ta_cache: bool,
+    /// Milliseconds the TA may spend on one call: it returns the images it
+    /// got through once they're spent, and the rest are sent again in the
+    /// next call (reports confidences)
+    #[arg(long, conflicts_with_all = ["ensemble", "correlate", "record_run"])]
+    budget_ms: Option<u64>,
+    /// Scale each digit to fit 20x20 and center it by mass, as the MNIST
+    /// digits are
+    #[arg(long)]
+    center: bool,
+    /// Straighten slanted digits using their image moments
+    #[arg(long)]
+    deskew: bool,
+    /// Print each input as ASCII art, as sent to the TA (after --center and
+    /// --deskew)
+    #[arg(long)]
+    show_input: bool,
+    /// Fail on input warnings (stripped newline, colour or size conversion,
+    /// inverted-looking image) instead of continuing
+    #[arg(long)]
+    strict: bool,
+    /// Keep decoded and resized images in this directory, keyed by the
+    /// SHA-256 of the file, so later runs skip decoding
+    #[arg(long, env = "ENC_MNIST_CACHE_DIR")]
+    cache_dir: Option<String>,
+    /// Size limit of the cache directory in MiB; least recently used entries
+    /// are evicted beyond it
+    #[arg(long, default_value_t = 64)]
+    cache_size: u64,
+    /// Decode every image even when a cache directory is configured
+    #[arg(long)]
+    no_cache: bool,
+    /// Print class numbers even when the model names its classes
+    #[arg(long)]
+    numeric_labels: bool,
+    /// Also write the results to this .json or .csv file
+    #[arg(long)]
+    results: Option<std::path::PathBuf>,
+    /// Save the inputs, the TA's hash of each input tensor and its answers
+    /// to this directory, to be run again with `replay` (reports
+    /// confidences)
+    #[arg(long, conflicts_with_all = ["ensemble", "correlate"])]
+    record_run: Option<std::path::PathBuf>,
+}
+
+/// One line of `--results`.
+#[derive(serde::Serialize)]
+struct InferenceResult<'a> {
+    input: &'a str,
+    class: u8,
+    label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<f32>,
+    /// Most probable class of a rejected image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidate: Option<String>,
+}
+
+/// Images per call when inferring through registered shared memory
+const SHARED_MEM_BATCH: usize = 64;
+/// Images per call when the TA doesn't suggest a batch size.
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+
+/// Images per TA call: `requested` when given, capped at the TA's maximum
+/// for the loaded model with a warning, otherwise the TA's recommendation,
+/// or `DEFAULT_BATCH_SIZE` without a hint (empty slot, older TA).
+pub fn batch_size(requested: Option<usize>, hint: Option<&BatchHint>) -> anyhow::Result<usize> {
+    match (requested, hint) {
+        (Some(0), _) => anyhow::bail!("batch size must be positive"),
+        (Some(requested), Some(hint)) if requested > hint.max => {
+            println!(
+                "warning: batch size {} exceeds the TA's maximum of {} for this model, using {}",
+                requested, hint.max, hint.max
+            );
+            Ok(hint.max)
+        }
+        (Some(requested), _) => Ok(requested),
+        (None, Some(hint)) => Ok(hint.recommended),
+        (None, None) => Ok(DEFAULT_BATCH_SIZE),
+    }
+}
+
+// Points out input flags that override a step the model's profile relies on,
+// or that change nothing
+fn normalization_override(flags: u32, profile: &Normalization) -> Option<String> {
+    let applied = inference::input_normalization(flags, profile);
+    if applied == *profile {
+        Some(format!(
+            "note: the model already normalizes as {}, the flag changes nothing",
+            profile
+        ))
+    } else if profile.mean != 0.0 || profile.std != 1.0 {
+        Some(format!(
+            "warning: the model was trained on {} but inputs get {}; only correct if they \
+             were standardized upstream",
+            profile, applied
+        ))
+    } else if applied.scale != profile.scale {
+        Some(format!(
+            "warning: the model scales inputs by 1/{} but they get 1/{}",
+            profile.scale, applied.scale
+        ))
+    } else {
+        None
+    }
+}
+
+pub fn parse_temperature(s: &str) -> Result<f32, String> {
+    let t: f32 = s
+        .parse()
+        .map_err(|_| format!("invalid temperature: {}", s))?;
+    if !t.is_finite() || t <= 0.0 {
+        return Err("temperature must be positive".to_string());
+    }
+    Ok(t)
+}
+
+/// Parses `--ta-cache` into the number of entries to keep.
+pub fn parse_ta_cache(s: &str) -> Result<u32, String> {
+    match s {
+        "on" => Ok(inference::DEFAULT_RESULT_CACHE_ENTRIES),
+        "off" => Ok(0),
+        _ => {
+            let size = s
+                .strip_prefix("size=")
+                .ok_or_else(|| format!("expected on, off or size=N, got {}", s))?;
+            let entries: u32 = size
+                .parse()
+                .map_err(|_| format!("invalid cache size: {}", size))?;
+            if entries > inference::MAX_RESULT_CACHE_ENTRIES {
+                return Err(format!(
+                    "the TA caches at most {} results",
+                    inference::MAX_RESULT_CACHE_ENTRIES
+                ));
+            }
+            Ok(entries)
+        }
+    }
+}
+
+pub fn parse_reject_threshold(s: &str) -> Result<f32, String> {
+    let threshold: f32 = s.parse().map_err(|_| format!("invalid threshold: {}", s))?;
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err("threshold must be between 0 and 1".to_string());
+    }
+    Ok(threshold)
+}
+
+pub fn parse_index_range(s: &str) -> Result<std::ops::Range<usize>, String> {
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("invalid index: {}", n))
+    };
+    let range = match s.split_once("..") {
+        Some((start, end)) => parse(start)?..parse(end)?,
+        None => {
+            let index = parse(s)?;
+            index..index + 1
+        }
+    };
+    if range.is_empty() {
+        return Err(format!("empty index range: {}", s));
+    }
+    Ok(range)
 }
 
 pub fn execute(args: &Args) -> anyhow::Result<()> {
-    let model_path = std::path::absolute(&args.model)?;
-    println!("Load model from \"{}\"", model_path.display());
-    
-    let mut ctx = Context::new()?;
-    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx)?;
+    anyhow::ensure!(
+        !args.binary.is_empty()
+            || !args.image.is_empty()
+            || args.idx.is_some()
+            || args.batch_file.is_some(),
+        "nothing to infer: pass images with -i/--image, raw {}-byte inputs with \
+         -b/--binary, an IDX file with --idx or a batch file with --batch-file \
+         (see `infer --help`)",
+        proto::IMAGE_SIZE
+    );
+    let models = registry::model_paths(&args.model, &args.model_name)?;
+    let slots: Vec<u32> = if models.is_empty() {
+        // The TA loads its stored model into slot 0 on the first inference
+        vec![0]
+    } else if args.slots.is_empty() {
+        (0..models.len() as u32).collect()
+    } else {
+        args.slots.clone()
+    };
+    anyhow::ensure!(
+        models.is_empty() || slots.len() == models.len(),
+        "got {} models but {} slots",
+        models.len(),
+        slots.len()
+    );
+    anyhow::ensure!(
+        slots.iter().all(|&slot| (slot as usize) < MODEL_SLOTS),
+        "slots must be below {}",
+        MODEL_SLOTS
+    );
+    anyhow::ensure!(
+        args.ensemble || slots.len() == 1,
+        "multiple models need --ensemble"
+    );
+    anyhow::ensure!(
+        !args.dry_run || !models.is_empty(),
+        "--dry-run needs --model, the simulated TA has no stored model"
+    );
 
-    let record = if model_path.extension().and_then(|s| s.to_str()) == Some("json") {
-        println!("Detected encrypted model file");
-        let encrypted_data = std::fs::read(&model_path)?;
-        
-        // Try to parse as chunked model first
-        if let Ok(chunked_model) = serde_json::from_slice::<ChunkedEncryptedModelFile>(&encrypted_data) {
-            println!("Model algorithm: {} (chunked)", chunked_model.algorithm);
-            println!("Reconstructing model from {} chunks ({} bytes)", 
-                     chunked_model.total_chunks, chunked_model.original_size);
-            // Stream encrypted chunks to TA
-            caller.begin_model_load()?;
-            let mut sorted_chunks = chunked_model.chunks;
-            sorted_chunks.sort_by_key(|c| c.id);
-            for chunk in sorted_chunks {
-                println!("Sending encrypted chunk {}/{} ({} bytes)", chunk.id + 1, chunked_model.total_chunks, chunk.data.len());
-                caller.push_encrypted_chunk(&chunk.data)?;
+    // Inputs are checked before anything is sent to the TA
+    let cache = match &args.cache_dir {
+        Some(dir) if !args.no_cache => Some(PreprocessCache::open(
+            std::path::Path::new(dir),
+            args.cache_size * 1024 * 1024,
+        )?),
+        _ => None,
+    };
+    anyhow::ensure!(
+        args.format.len() <= 1 || args.format.len() == args.image.len(),
+        "got {} formats for {} images",
+        args.format.len(),
+        args.image.len()
+    );
+    let mut sources: Vec<Box<dyn ImageSource>> = Vec::new();
+    for path in &args.binary {
+        sources.push(Box::new(BinaryFile::open(path)?));
+    }
+    for (i, path) in args.image.iter().enumerate() {
+        let format = match args.format.as_slice() {
+            [] => input::Format::Auto,
+            [format] => *format,
+            formats => formats[i],
+        };
+        sources.push(Box::new(DecodedImage::open(path, format, cache.as_ref())?));
+    }
+    if let Some(path) = &args.idx {
+        sources.push(Box::new(IdxDataset::open(path, args.index.clone())?));
+    }
+    let warnings = source::print_warnings(&sources);
+    if warnings > 0 {
+        anyhow::ensure!(
+            !args.strict,
+            "{} input warning(s), refusing to continue with --strict",
+            warnings
+        );
+        println!("{} input warning(s), continuing", warnings);
+    }
+    if let Some(path) = &args.batch_file {
+        sources.push(Box::new(BatchFile::open(path)?));
+    }
+    let count = source::total(&sources);
+    anyhow::ensure!(count != 0, "the inputs hold no images");
+    let alignment = input::Alignment {
+        center: args.center,
+        deskew: args.deskew,
+    };
+    let names = source::names(&sources);
+
+    // Outlives the connector's session
+    let mut ctx = None;
+    let mut caller: Box<dyn InferenceTa> = if args.dry_run {
+        simulated_ta(args.key.as_deref().unwrap_or_default())?
+    } else {
+        let ctx = ctx.insert(Context::new()?);
+        // Loading models and configuring the result cache need a manage session
+        let role = if models.is_empty() && args.ta_cache.is_none() {
+            SessionRole::Infer
+        } else {
+            SessionRole::Manage
+        };
+        let mut connector = crate::tee::InferenceTaConnector::new(ctx, role)?;
+        if args.shared_mem && !connector.use_shared_memory(SHARED_MEM_BATCH) {
+            println!("Falling back to per-batch buffers");
+        }
+        Box::new(connector)
+    };
+    for (path, &slot) in models.iter().zip(&slots) {
+        load_model(caller.as_mut(), path, slot)?;
+    }
+    // Labels are taken from the first model; ensemble members should share them
+    let status = caller.model_status(slots[0])?;
+    if let Some(name) = &status.name {
+        println!("Model name: {}", name);
+    }
+    let input_flags = match (args.raw_scale, args.no_normalize) {
+        (true, _) => inference::FLAG_RAW_SCALE,
+        (_, true) => inference::FLAG_NO_NORMALIZE,
+        _ => 0,
+    };
+    if input_flags != 0 {
+        if let Some(message) = normalization_override(input_flags, &status.normalization) {
+            println!("{}", message);
+        }
+    }
+    let input_flags = if args.skip_shadow {
+        input_flags | inference::FLAG_SKIP_SHADOW
+    } else {
+        input_flags
+    };
+    let input_flags = if args.bypass_ta_cache {
+        input_flags | inference::FLAG_NO_CACHE
+    } else {
+        input_flags
+    };
+    if input_flags != 0 {
+        caller.set_input_flags(input_flags)?;
+    }
+    if args.reject_below.is_some() {
+        caller.set_reject_threshold(args.reject_below)?;
+    }
+    if let Some(entries) = args.ta_cache {
+        caller.set_result_cache(entries)?;
+    }
+
+    let batch_size = batch_size(args.batch_size, status.batch_hint.as_ref())?;
+    let mut result = Vec::with_capacity(count);
+    let mut confidences: Option<Vec<f32>> = None;
+    let mut candidates: Option<Vec<u8>> = None;
+    let mut recorded: Option<RecordedBatch> = None;
+    // Calls that ran out of the --budget-ms before the end of their batch
+    let mut cut_short = 0;
+    let mut run_batch = |batch: &[Image]| -> anyhow::Result<()> {
+        let (labels, batch_confidences, batch_candidates) = if args.ensemble {
+            let mask = slots.iter().fold(0, |mask, &slot| mask | 1 << slot);
+            let temperature = args.temperature.unwrap_or(1.0);
+            let (labels, probs) =
+                caller.infer_ensemble(batch, mask, temperature, status.num_classes)?;
+            let confidences = labels
+                .iter()
+                .enumerate()
+                .map(|(i, &label)| probs[i * status.num_classes + label as usize])
+                .collect();
+            (labels, Some(confidences), None)
+        } else if args.correlate {
+            let temperature = args.temperature.unwrap_or(1.0);
+            split_predictions(caller.infer_correlated(batch, temperature, slots[0])?)
+        } else if args.record_run.is_some() {
+            let temperature = args.temperature.unwrap_or(1.0);
+            let answer = caller.infer_recorded(batch, temperature, slots[0], status.num_classes)?;
+            let split = split_predictions(answer.predictions.clone());
+            match &mut recorded {
+                Some(recorded) => {
+                    recorded.predictions.extend(answer.predictions);
+                    recorded.probabilities.extend(answer.probabilities);
+                    recorded.input_hashes.extend(answer.input_hashes);
+                }
+                None => recorded = Some(answer),
             }
-            caller.finalize_model_load()?;
-            Vec::new() // no local record
+            split
+        } else if let Some(budget) = args.budget_ms {
+            let temperature = args.temperature.unwrap_or(1.0);
+            let budget = std::time::Duration::from_millis(budget);
+            let (predictions, exceeded) =
+                infer_in_budget(&mut *caller, batch, temperature, slots[0], budget)?;
+            cut_short += exceeded;
+            split_predictions(predictions)
+        } else if let Some(temperature) = args.temperature.or(args.reject_below.map(|_| 1.0)) {
+            split_predictions(caller.infer_predictions(batch, temperature, slots[0])?)
         } else {
-            // Fall back to single encrypted model
-            let encrypted_model: EncryptedModelFile = serde_json::from_slice(&encrypted_data)?;
-            println!("Model algorithm: {}", encrypted_model.algorithm);
-            caller.begin_model_load()?;
-            // Send in chunks to avoid large shared buffers
-            let data = encrypted_model.encrypted_data;
-            const CHUNK: usize = 64 * 1024;
-            for (i, part) in data.chunks(CHUNK).enumerate() {
-                println!("Sending encrypted part {} ({} bytes)", i + 1, part.len());
-                caller.push_encrypted_chunk(part)?;
+            (caller.infer_batch(batch, slots[0])?, None, None)
+        };
+        result.extend(labels);
+        if let Some(batch_confidences) = batch_confidences {
+            confidences
+                .get_or_insert_with(Vec::new)
+                .extend(batch_confidences);
+        }
+        if let Some(batch_candidates) = batch_candidates {
+            candidates
+                .get_or_insert_with(Vec::new)
+                .extend(batch_candidates);
+        }
+        Ok(())
+    };
+    // Labels of a labeled batch file
+    let mut expected: Option<Vec<u8>> = None;
+    // Only kept for --record-run
+    let mut binaries = Vec::new();
+    let mut batches = Batches::new(&sources, alignment);
+    let mut shown = 0;
+    while let Some(batch) = batches.next_batch(batch_size)? {
+        if args.show_input {
+            for (name, image) in names[shown..].iter().zip(&batch.images) {
+                println!("{}:\n{}", name, image);
+            }
+            shown += batch.images.len();
+        }
+        run_batch(&batch.images)?;
+        if let Some(labels) = batch.labels {
+            expected.get_or_insert_with(Vec::new).extend(labels);
+        }
+        if args.record_run.is_some() {
+            binaries.extend(batch.images);
+        }
+    }
+    anyhow::ensure!(count == result.len());
+    if cut_short > 0 {
+        println!(
+            "{} call(s) ran out of the time budget, their remaining images were sent again",
+            cut_short
+        );
+    }
+    let recording = match recorded {
+        Some(batch) => {
+            let settings = RunSettings {
+                slot: slots[0],
+                input_flags,
+                temperature: args.temperature.unwrap_or(1.0),
+                reject_below: args.reject_below,
+            };
+            Some(RecordedRun::new(
+                &status, settings, &names, &binaries, batch,
+            )?)
+        }
+        None => None,
+    };
+
+    // Asked after inferring, which loads the stored model if none was given
+    let labels = if args.numeric_labels {
+        Vec::new()
+    } else {
+        caller.class_labels(slots[0])?
+    };
+    let results: Vec<InferenceResult> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let rejected = args.reject_below.is_some() && result[i] == REJECT_LABEL;
+            InferenceResult {
+                input: name,
+                class: result[i],
+                label: if rejected {
+                    "rejected".to_string()
+                } else {
+                    label_name(&labels, result[i])
+                },
+                confidence: confidences.as_ref().map(|c: &Vec<f32>| c[i]),
+                candidate: candidates
+                    .as_ref()
+                    .filter(|_| rejected)
+                    .map(|c: &Vec<u8>| label_name(&labels, c[i])),
+            }
+        })
+        .collect();
+    for (i, result) in results.iter().enumerate() {
+        match (&result.candidate, result.confidence) {
+            (Some(candidate), Some(confidence)) => println!(
+                "{}. {}: rejected (best guess {}, confidence {:.2}%)",
+                i + 1,
+                result.input,
+                candidate,
+                confidence * 100.0
+            ),
+            (_, Some(confidence)) => println!(
+                "{}. {}: {} (confidence {:.2}%)",
+                i + 1,
+                result.input,
+                result.label,
+                confidence * 100.0
+            ),
+            (_, None) => println!("{}. {}: {}", i + 1, result.input, result.label),
+        }
+    }
+    if let Some(threshold) = args.reject_below {
+        let rejected = results.iter().filter(|r| r.candidate.is_some()).count();
+        println!(
+            "{} of {} image(s) rejected below confidence {}",
+            rejected,
+            results.len(),
+            threshold
+        );
+    }
+    if let Some(expected) = &expected {
+        let correct = result
+            .iter()
+            .zip(expected)
+            .filter(|(predicted, label)| predicted == label)
+            .count();
+        println!(
+            "{} of {} image(s) match the batch file's labels",
+            correct,
+            expected.len()
+        );
+    }
+    if let Some(path) = &args.results {
+        write_results(path, &results)?;
+        println!("Results written to {}", path.display());
+    }
+    if let (Some(dir), Some(run)) = (&args.record_run, &recording) {
+        for entry in run.drifted() {
+            println!(
+                "warning: {}: the TA ran another input tensor than the host computes",
+                entry.input
+            );
+        }
+        run.write(dir, &binaries)?;
+        println!("Run recorded to {}", dir.display());
+    }
+    println!("Infer Success");
+    if args.dry_run {
+        println!("{}", crate::tee::DRY_RUN_BANNER);
+    }
+
+    Ok(())
+}
+
+// Predictions of `images` through `infer_within_budget`, the images a call
+// didn't get to sent again in the next one, and the number of calls that
+// ran out of `budget`
+fn infer_in_budget(
+    caller: &mut dyn InferenceTa,
+    images: &[Image],
+    temperature: f32,
+    slot: u32,
+    budget: std::time::Duration,
+) -> optee_teec::Result<(Vec<Prediction>, usize)> {
+    let mut predictions = Vec::with_capacity(images.len());
+    let mut exceeded = 0;
+    while predictions.len() < images.len() {
+        let rest = &images[predictions.len()..];
+        match caller.infer_within_budget(rest, temperature, slot, budget)? {
+            BudgetOutcome::Complete(done) => predictions.extend(done),
+            BudgetOutcome::Exceeded(done) => {
+                exceeded += 1;
+                predictions.extend(done);
+            }
+        }
+    }
+    Ok((predictions, exceeded))
+}
+
+// Labels, confidences and most probable classes of `predictions`
+fn split_predictions(predictions: Vec<Prediction>) -> (Vec<u8>, Option<Vec<f32>>, Option<Vec<u8>>) {
+    let labels = predictions.iter().map(|p| p.label).collect();
+    let confidences = predictions.iter().map(Prediction::confidence).collect();
+    let candidates = predictions.iter().map(|p| p.candidate).collect();
+    (labels, Some(confidences), Some(candidates))
+}
+
+/// Name of `class` in `labels`, falling back to the class number.
+pub fn label_name(labels: &[String], class: u8) -> String {
+    match labels.get(class as usize) {
+        Some(label) => label.clone(),
+        None => class.to_string(),
+    }
+}
+
+fn write_results(path: &std::path::Path, results: &[InferenceResult]) -> anyhow::Result<()> {
+    let data = match path.extension().and_then(|s| s.to_str()) {
+        Some("json") => serde_json::to_vec_pretty(results)?,
+        Some("csv") => {
+            let mut csv = String::from("input,class,label,confidence\n");
+            for result in results {
+                let confidence = result.confidence.map(|c| c.to_string());
+                csv.push_str(&format!(
+                    "{},{},{},{}\n",
+                    csv_field(result.input),
+                    result.class,
+                    csv_field(&result.label),
+                    confidence.unwrap_or_default()
+                ));
             }
-            caller.finalize_model_load()?;
-            Vec::new()
+            csv.into_bytes()
         }
+        _ => anyhow::bail!("--results must end in .json or .csv"),
+    };
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+// Quotes fields with separators, quotes or line breaks (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(feature = "encrypt-model")]
+pub fn simulated_ta(key: &str) -> anyhow::Result<Box<dyn InferenceTa>> {
+    let key = super::encrypt::parse_hex_key_32(key)?;
+    println!("{}", crate::tee::DRY_RUN_BANNER);
+    Ok(Box::new(crate::sim::SimulatedTa::new(key)))
+}
+
+#[cfg(not(feature = "encrypt-model"))]
+pub fn simulated_ta(_key: &str) -> anyhow::Result<Box<dyn InferenceTa>> {
+    anyhow::bail!("--dry-run needs a host built with the encrypt-model feature")
+}
+
+/// The simulated TA as the backend of `--encrypted`: the same decryption
+/// and NdArray model, labelled as host-side rather than a dry run.
+#[cfg(feature = "dev-tools")]
+pub fn host_side_ta(key: &str) -> anyhow::Result<Box<dyn InferenceTa>> {
+    let key = super::encrypt::parse_hex_key_32(key)?;
+    println!("{}", crate::tee::HOST_SIDE_BANNER);
+    Ok(Box::new(crate::sim::SimulatedTa::new(key)))
+}
+
+#[cfg(not(feature = "dev-tools"))]
+pub fn host_side_ta(_key: &str) -> anyhow::Result<Box<dyn InferenceTa>> {
+    anyhow::bail!("--encrypted needs a host built with the dev-tools feature")
+}
+
+/// Streams the model at `path` into the given TA slot. Encrypted (`.json`)
+/// models are pushed chunk by chunk and decrypted inside the TA on finalize.
+pub fn load_model(caller: &mut dyn InferenceTa, path: &str, slot: u32) -> anyhow::Result<()> {
+    let model_path = std::path::absolute(path)?;
+    println!("Load model from \"{}\"", model_path.display());
+
+    let record = if model_path.extension().and_then(|s| s.to_str()) == Some("json") {
+        let model = read_encrypted_model(&model_path)?;
+        preflight_storage(caller, slot, model.ciphertext.len())?;
+        caller.begin_model_load(slot, model.ciphertext.len())?;
+        // Send in chunks to avoid large shared buffers
+        upload::push_payload(caller, &model.ciphertext)?;
+        caller.finalize_model_load(model.signature.as_deref())?;
+        Vec::new() // no local record
     } else {
         println!("Loading plaintext model (legacy mode)");
         let data = std::fs::read(&model_path)?;
@@ -112,51 +746,392 @@ pub fn execute(args: &Args) -> anyhow::Result<()> {
         println!("Model sent on open_session (legacy mode)");
     }
 
-    let mut binaries: Vec<Image> = args
-        .binary
-        .iter()
-        .map(|v| {
-            let data = std::fs::read(v)?;
-            anyhow::ensure!(data.len() == IMAGE_SIZE);
+    Ok(())
+}
 
-            TryInto::<Image>::try_into(data)
-                .map_err(|err| anyhow::Error::msg(format!("cannot convert {:?} into Image", err)))
-        })
-        .collect::<Result<Vec<_>, anyhow::Error>>()?;
-    let images: Vec<Image> = args
-        .image
-        .iter()
-        .map(|v| {
-            let img = image::open(v)
-                .unwrap()
-                .resize_exact(28, 28, image::imageops::FilterType::Triangle)
-                .to_luma8();
-            let bytes = img.as_bytes();
-            anyhow::ensure!(bytes.len() == IMAGE_SIZE);
-            TryInto::<Image>::try_into(bytes)
-                .map_err(|err| anyhow::Error::msg(format!("cannot convert {:?} into Image", err)))
-        })
-        .collect::<Result<Vec<_>, anyhow::Error>>()?;
-    binaries.extend(images);
+/// Reads an encrypted model file, single or chunked, into the ciphertext the
+/// TA is sent and the signature, if any.
+pub fn read_encrypted_model(model_path: &std::path::Path) -> anyhow::Result<EncryptedModel> {
+    println!("Detected encrypted model file");
+    let encrypted_data = read_model_file(model_path)?;
+    transcript::record(Step::ModelFile {
+        path: &model_path.display().to_string(),
+        bytes: encrypted_data.len(),
+        fingerprint: Redacted::new(&encrypted_data[..]),
+    });
+    let model = model_file::parse(&encrypted_data)?;
+    match model.chunks {
+        Some((total_chunks, original_size)) => {
+            println!("Model algorithm: {} (chunked)", model.algorithm);
+            println!(
+                "Reconstructing model from {} chunks ({} bytes)",
+                total_chunks, original_size
+            );
+        }
+        None => println!("Model algorithm: {}", model.algorithm),
+    }
+    if model.signature.is_some() {
+        println!("Model is signed");
+    }
+    Ok(model)
+}
 
-    let result = caller.infer_batch(&binaries)?;
-    anyhow::ensure!(binaries.len() == result.len());
+/// Reads an encrypted model file, refusing one over `MAX_MODEL_FILE_SIZE`
+/// before reading it and never reading past the limit if it grows meanwhile.
+pub fn read_model_file(path: &std::path::Path) -> anyhow::Result<Vec<u8>> {
+    use std::io::Read;
 
-    for (i, binary) in args.binary.iter().enumerate() {
-        println!("{}. {}: {}", i + 1, binary, result[i]);
+    let file = std::fs::File::open(path)?;
+    let size = file.metadata()?.len();
+    if size > model_file::MAX_MODEL_FILE_SIZE {
+        return Err(ModelFileError::FileTooLarge(size).into());
     }
-
-    for (i, image) in args.image.iter().enumerate() {
-        println!(
-            "{}. {}: {}",
-            i + args.binary.len() + 1,
-            image,
-            result[args.binary.len()]
-        );
+    let mut data = Vec::with_capacity(size as usize);
+    file.take(model_file::MAX_MODEL_FILE_SIZE + 1)
+        .read_to_end(&mut data)?;
+    if data.len() as u64 > model_file::MAX_MODEL_FILE_SIZE {
+        return Err(ModelFileError::FileTooLarge(data.len() as u64).into());
     }
-    println!("Infer Success");
+    Ok(data)
+}
+
+/// Reads and parses an encrypted model file, single or chunked, without
+/// printing anything.
+pub fn decode_encrypted_model(path: &std::path::Path) -> anyhow::Result<EncryptedModel> {
+    let data = read_model_file(path)?;
+    Ok(model_file::parse(&data)?)
+}
 
+/// Models loaded into slot 0 are kept in the TA's secure storage; refuse up
+/// front instead of after pushing every chunk when they won't fit.
+pub fn preflight_storage(
+    caller: &mut dyn InferenceTa,
+    slot: u32,
+    encrypted_size: usize,
+) -> anyhow::Result<()> {
+    if slot != 0 || !caller.supports(proto::inference::CAP_STORAGE) {
+        return Ok(());
+    }
+    let preflight = caller.storage_preflight(encrypted_size)?;
+    anyhow::ensure!(
+        preflight.fits,
+        "insufficient secure storage (need {}, used {} of {})",
+        encrypted_size,
+        preflight.used,
+        preflight.quota.unwrap_or_default()
+    );
     Ok(())
 }
 
 // reconstruct_chunked_model removed: we never return plaintext model to host.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::encrypt::encrypt_model;
+    use crate::sim::SimulatedTa;
+    use crate::tee::StoragePreflight;
+    use burn::backend::NdArray;
+    use proto::inference::ModelStatus;
+    use std::time::Duration;
+
+    const KEY: [u8; 32] = [0x5a; 32];
+
+    // The simulated TA behind the storage preflight of a real one, with
+    // `used` bytes stored under `quota`, logging the load commands it sees
+    struct Storage {
+        ta: SimulatedTa,
+        used: usize,
+        quota: Option<usize>,
+        commands: Vec<&'static str>,
+    }
+
+    impl Storage {
+        fn new(used: usize, quota: Option<usize>) -> Self {
+            Self {
+                ta: SimulatedTa::new(KEY),
+                used,
+                quota,
+                commands: Vec::new(),
+            }
+        }
+    }
+
+    impl InferenceTa for Storage {
+        fn supports(&self, capability: u32) -> bool {
+            capability == inference::CAP_STORAGE || self.ta.supports(capability)
+        }
+
+        fn begin_model_load(&mut self, slot: u32, size: usize) -> optee_teec::Result<()> {
+            self.commands.push("begin");
+            self.ta.begin_model_load(slot, size)
+        }
+
+        fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()> {
+            if self.commands.last() != Some(&"push") {
+                self.commands.push("push");
+            }
+            self.ta.push_encrypted_chunk(chunk)
+        }
+
+        fn finalize_model_load(&mut self, signature: Option<&[u8]>) -> optee_teec::Result<()> {
+            self.commands.push("finalize");
+            self.ta.finalize_model_load(signature)
+        }
+
+        fn storage_preflight(&mut self, size: usize) -> optee_teec::Result<StoragePreflight> {
+            self.commands.push("preflight");
+            Ok(StoragePreflight {
+                used: self.used,
+                quota: self.quota,
+                fits: common::storage_fits(self.used, size, self.quota),
+            })
+        }
+
+        fn model_status(&mut self, slot: u32) -> optee_teec::Result<ModelStatus> {
+            self.ta.model_status(slot)
+        }
+
+        fn class_labels(&mut self, slot: u32) -> optee_teec::Result<Vec<String>> {
+            self.ta.class_labels(slot)
+        }
+
+        fn set_input_flags(&mut self, flags: u32) -> optee_teec::Result<()> {
+            self.ta.set_input_flags(flags)
+        }
+
+        fn set_reject_threshold(&mut self, threshold: Option<f32>) -> optee_teec::Result<()> {
+            self.ta.set_reject_threshold(threshold)
+        }
+
+        fn infer_batch(&mut self, images: &[Image], slot: u32) -> optee_teec::Result<Vec<u8>> {
+            self.ta.infer_batch(images, slot)
+        }
+
+        fn infer_predictions(
+            &mut self,
+            images: &[Image],
+            temperature: f32,
+            slot: u32,
+        ) -> optee_teec::Result<Vec<Prediction>> {
+            self.ta.infer_predictions(images, temperature, slot)
+        }
+
+        fn infer_batch_with_probabilities(
+            &mut self,
+            images: &[Image],
+            temperature: f32,
+            slot: u32,
+            num_classes: usize,
+        ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+            self.ta
+                .infer_batch_with_probabilities(images, temperature, slot, num_classes)
+        }
+
+        fn infer_correlated(
+            &mut self,
+            images: &[Image],
+            temperature: f32,
+            slot: u32,
+        ) -> optee_teec::Result<Vec<Prediction>> {
+            self.ta.infer_correlated(images, temperature, slot)
+        }
+
+        fn infer_ensemble(
+            &mut self,
+            images: &[Image],
+            slot_mask: u32,
+            temperature: f32,
+            num_classes: usize,
+        ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+            self.ta
+                .infer_ensemble(images, slot_mask, temperature, num_classes)
+        }
+
+        fn infer_recorded(
+            &mut self,
+            images: &[Image],
+            temperature: f32,
+            slot: u32,
+            num_classes: usize,
+        ) -> optee_teec::Result<RecordedBatch> {
+            self.ta
+                .infer_recorded(images, temperature, slot, num_classes)
+        }
+
+        fn infer_within_budget(
+            &mut self,
+            images: &[Image],
+            temperature: f32,
+            slot: u32,
+            budget: Duration,
+        ) -> optee_teec::Result<BudgetOutcome> {
+            self.ta
+                .infer_within_budget(images, temperature, slot, budget)
+        }
+    }
+
+    // An encrypted model file under `test`, and its ciphertext size
+    fn encrypted_model(test: &str) -> (std::path::PathBuf, usize) {
+        let dir = std::env::temp_dir();
+        let record = dir.join(format!("infer-{}-{}.bin", test, std::process::id()));
+        let encrypted = record.with_extension("json");
+        let model = common::Model::<NdArray>::new(&Default::default());
+        std::fs::write(&record, model.export().unwrap()).unwrap();
+        encrypt_model(&record, &encrypted, &KEY, &Default::default()).unwrap();
+        let _ = std::fs::remove_file(&record);
+        let size = decode_encrypted_model(&encrypted).unwrap().ciphertext.len();
+        (encrypted, size)
+    }
+
+    #[test]
+    fn loads_that_dont_fit_stop_before_streaming() {
+        let (path, size) = encrypted_model("full");
+        let path_str = path.to_str().unwrap();
+        let mut ta = Storage::new(1000, Some(1000 + size - 1));
+        let err = load_model(&mut ta, path_str, 0).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "insufficient secure storage (need {}, used 1000 of {})",
+                size,
+                1000 + size - 1
+            )
+        );
+        assert_eq!(ta.commands, ["preflight"]);
+        assert!(!ta.model_status(0).unwrap().loaded);
+
+        // Only slot 0 is stored
+        load_model(&mut ta, path_str, 1).unwrap();
+        assert_eq!(ta.commands, ["preflight", "begin", "push", "finalize"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unknown_quotas_are_optimistic() {
+        let (path, size) = encrypted_model("unknown");
+        let path_str = path.to_str().unwrap();
+        for quota in [None, Some(1000 + size)] {
+            let mut ta = Storage::new(1000, quota);
+            load_model(&mut ta, path_str, 0).unwrap();
+            assert_eq!(ta.commands, ["preflight", "begin", "push", "finalize"]);
+            assert!(ta.model_status(0).unwrap().loaded);
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn index_ranges() {
+        assert_eq!(parse_index_range("3"), Ok(3..4));
+        assert_eq!(parse_index_range("0..10"), Ok(0..10));
+        assert_eq!(parse_index_range(" 2 .. 5"), Ok(2..5));
+        for range in ["5..5", "6..2", "a", "1..", "-1"] {
+            assert!(parse_index_range(range).is_err(), "{}", range);
+        }
+    }
+
+    #[test]
+    fn requests_without_inputs_are_refused_before_the_ta() {
+        // No context is opened, so this fails the same way without a TEE
+        let err = execute(&Args::try_parse_from(["infer"]).unwrap()).unwrap_err();
+        assert!(err.to_string().starts_with("nothing to infer: pass images"));
+        // Inputs that load to no images
+        let path = std::env::temp_dir().join(format!("infer-empty-{}.idx", std::process::id()));
+        let header: Vec<u8> = [0x803u32, 0, 28, 28]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect();
+        std::fs::write(&path, header).unwrap();
+        let args = ["infer", "--idx", path.to_str().unwrap()];
+        let err = execute(&Args::try_parse_from(args).unwrap()).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(err.to_string(), "the inputs hold no images");
+    }
+
+    #[test]
+    fn strict_refuses_inputs_with_warnings() {
+        let path = std::env::temp_dir().join(format!("infer-strict-{}.bin", std::process::id()));
+        let mut data = vec![0; proto::IMAGE_SIZE];
+        data.push(b'\n');
+        std::fs::write(&path, data).unwrap();
+        let args = ["infer", "--strict", "--binary", path.to_str().unwrap()];
+        // Refused before any TA is opened
+        let err = execute(&Args::try_parse_from(args).unwrap()).unwrap_err();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            err.to_string(),
+            "1 input warning(s), refusing to continue with --strict"
+        );
+    }
+
+    #[test]
+    fn conflicting_input_flags_are_pointed_out() {
+        use inference::{FLAG_NO_NORMALIZE, FLAG_RAW_SCALE};
+        let message = |flags, profile| normalization_override(flags, &profile).unwrap();
+        // Skipping the standardization an MNIST model was trained with
+        for flags in [FLAG_RAW_SCALE, FLAG_NO_NORMALIZE] {
+            assert!(message(flags, Normalization::MNIST)
+                .starts_with("warning: the model was trained on"));
+        }
+        // Skipping the scaling of a [0, 1] model
+        assert_eq!(
+            message(FLAG_NO_NORMALIZE, Normalization::UNIT),
+            "warning: the model scales inputs by 1/255 but they get 1/1"
+        );
+        // Flags asking for what the model does anyway
+        assert!(message(FLAG_RAW_SCALE, Normalization::UNIT).starts_with("note:"));
+        assert!(message(FLAG_NO_NORMALIZE, Normalization::IDENTITY).starts_with("note:"));
+        // A model that already takes raw pixels, scaled after all
+        assert_eq!(
+            message(FLAG_RAW_SCALE, Normalization::IDENTITY),
+            "warning: the model scales inputs by 1/1 but they get 1/255"
+        );
+    }
+
+    #[test]
+    fn batch_sizes_follow_the_ta_hint() {
+        let hint = BatchHint {
+            recommended: 32,
+            max: 100,
+        };
+        // The recommendation unless overridden, the override within the
+        // maximum, and capped at it beyond
+        assert_eq!(batch_size(None, Some(&hint)).unwrap(), 32);
+        assert_eq!(batch_size(Some(8), Some(&hint)).unwrap(), 8);
+        assert_eq!(batch_size(Some(100), Some(&hint)).unwrap(), 100);
+        assert_eq!(batch_size(Some(500), Some(&hint)).unwrap(), 100);
+        // Without a hint (empty slot, older TA), the override or the default
+        assert_eq!(batch_size(None, None).unwrap(), DEFAULT_BATCH_SIZE);
+        assert_eq!(batch_size(Some(500), None).unwrap(), 500);
+        assert!(batch_size(Some(0), Some(&hint)).is_err());
+        assert!(batch_size(Some(0), None).is_err());
+    }
+
+    #[test]
+    fn budgeted_batches_send_the_rest_again() {
+        let (path, _) = encrypted_model("budget");
+        let mut ta = SimulatedTa::new(KEY);
+        load_model(&mut ta, path.to_str().unwrap(), 0).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let images: Vec<Image> = (0..40)
+            .map(|i| {
+                let mut pixels = [0; proto::IMAGE_SIZE];
+                for (j, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = (i * 31 + j * 7) as u8;
+                }
+                Image::from_luma28(&pixels)
+            })
+            .collect();
+        let expected = ta.infer_predictions(&images, 1.0, 0).unwrap();
+        // Without any budget each call gets through its first slice only:
+        // 16 and 16 images cut short, then the last 8
+        let (predictions, exceeded) =
+            infer_in_budget(&mut ta, &images, 1.0, 0, Duration::ZERO).unwrap();
+        assert_eq!(exceeded, 2);
+        assert_eq!(predictions, expected);
+        let (predictions, exceeded) =
+            infer_in_budget(&mut ta, &images, 1.0, 0, Duration::from_secs(3600)).unwrap();
+        assert_eq!(exceeded, 0);
+        assert_eq!(predictions, expected);
+    }
+}
@@ -0,0 +1,224 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use anyhow::Result;
+use burn::{backend::NdArray, tensor::TensorData};
+use clap::Args as ClapArgs;
+use serde::Serialize;
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// Plaintext Burn record, or an encrypted model (.json) with --key
+    #[arg(long, required_unless_present = "encrypted")]
+    input: Option<String>,
+    /// Inspect this encrypted model (.json) decrypted on the host, like
+    /// `evaluate --encrypted` (needs the dev-tools feature)
+    #[arg(long, conflicts_with = "input", requires = "key")]
+    encrypted: Option<String>,
+    /// 32-byte AES key in hex (64 hex chars) decrypting an encrypted --input
+    /// or --encrypted
+    #[arg(long)]
+    key: Option<String>,
+    /// Print the report as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Serialize)]
+struct Report {
+    format: String,
+    metadata_name: Option<String>,
+    /// `UnifiedModel` (what the TA loads) or the plain `MnistModel` fallback
+    loaded_as: &'static str,
+    ta_compatible: bool,
+    total_params: usize,
+    parameters: Vec<ParamStats>,
+    warnings: Vec<String>,
+}
+
+/// Statistics of one parameter tensor. Min/max/mean/std only cover the
+/// finite values and are `None` when there are none.
+#[derive(Serialize)]
+struct ParamStats {
+    name: String,
+    shape: Vec<usize>,
+    dtype: String,
+    min: Option<f32>,
+    max: Option<f32>,
+    mean: Option<f64>,
+    std: Option<f64>,
+    nan: usize,
+    infinite: usize,
+    all_zero: bool,
+}
+
+pub fn execute(args: &Args) -> Result<()> {
+    let input = args.input.as_deref().unwrap_or_default();
+    let bytes = match (&args.encrypted, &args.key) {
+        (Some(path), key) => {
+            super::encrypt::decrypt_on_host(path, key.as_deref().unwrap_or_default())?
+        }
+        (None, Some(key)) => super::encrypt::read_decrypted_model(input, key)?,
+        (None, None) => std::fs::read(input)?,
+    };
+    let (metadata, format, record) = common::split_container(bytes)?;
+
+    // The TA only imports `UnifiedModel`; a plain `MnistModel` record is
+    // tried second so the report can say why the TA would refuse it
+    let device = Default::default();
+    let (params, loaded_as, ta_compatible) =
+        match common::Model::<NdArray>::import_as(&device, record.clone(), format) {
+            Ok(model) => (model.parameters(), "UnifiedModel", true),
+            Err(unified) => match common::MnistModel::<NdArray>::import_as(&device, record, format)
+            {
+                Ok(model) => (model.parameters(), "MnistModel", false),
+                // The TA's error comes first, `report_model_error` explains it
+                Err(plain) => {
+                    return Err(anyhow::Error::new(unified).context(format!(
+                        "{:?} record loads neither as UnifiedModel nor as MnistModel ({})",
+                        format, plain
+                    )))
+                }
+            },
+        };
+
+    let parameters: Vec<ParamStats> = params
+        .into_iter()
+        .map(|(name, data)| param_stats(name, &data))
+        .collect();
+    let mut warnings = Vec::new();
+    for param in &parameters {
+        if param.nan > 0 {
+            warnings.push(format!("{} has {} NaN values", param.name, param.nan));
+        }
+        if param.infinite > 0 {
+            warnings.push(format!(
+                "{} has {} infinite values",
+                param.name, param.infinite
+            ));
+        }
+        if param.all_zero {
+            warnings.push(format!("{} is all zeros", param.name));
+        }
+    }
+    let report = Report {
+        format: format!("{:?}", format),
+        metadata_name: metadata.map(|m| m.name),
+        loaded_as,
+        ta_compatible,
+        total_params: parameters
+            .iter()
+            .map(|p| p.shape.iter().product::<usize>())
+            .sum(),
+        parameters,
+        warnings,
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+    Ok(())
+}
+
+fn param_stats(name: String, data: &TensorData) -> ParamStats {
+    let mut finite = 0_usize;
+    let (mut nan, mut infinite) = (0, 0);
+    let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+    let (mut sum, mut sum_sq) = (0.0_f64, 0.0_f64);
+    let mut all_zero = true;
+    for value in data.iter::<f32>() {
+        if value.is_nan() {
+            nan += 1;
+        } else if value.is_infinite() {
+            infinite += 1;
+        } else {
+            finite += 1;
+            min = min.min(value);
+            max = max.max(value);
+            sum += value as f64;
+            sum_sq += value as f64 * value as f64;
+        }
+        all_zero &= value == 0.0;
+    }
+    let mean = (finite > 0).then(|| sum / finite as f64);
+    ParamStats {
+        name,
+        shape: data.shape.clone(),
+        dtype: format!("{:?}", data.dtype).to_lowercase(),
+        min: (finite > 0).then_some(min),
+        max: (finite > 0).then_some(max),
+        mean,
+        // Population variance, clamped against rounding below zero
+        std: mean.map(|mean| (sum_sq / finite as f64 - mean * mean).max(0.0).sqrt()),
+        nan,
+        infinite,
+        all_zero,
+    }
+}
+
+fn print_report(report: &Report) {
+    println!("Record format: {}", report.format);
+    match &report.metadata_name {
+        Some(name) => println!("Model metadata: {}", name),
+        None => println!("No model metadata block"),
+    }
+    if report.ta_compatible {
+        println!("Loaded as {} (the TA's loader)", report.loaded_as);
+    } else {
+        println!(
+            "Loaded as {} only; the TA imports UnifiedModel and would refuse this record",
+            report.loaded_as
+        );
+    }
+    let width = report
+        .parameters
+        .iter()
+        .map(|p| p.name.len())
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<width$}  {:<12} {:<6} {:>11} {:>11} {:>11} {:>11}",
+        "parameter",
+        "shape",
+        "dtype",
+        "min",
+        "max",
+        "mean",
+        "std",
+        width = width
+    );
+    let cell = |value: Option<f64>| value.map_or("-".to_string(), |v| format!("{:.5}", v));
+    for param in &report.parameters {
+        println!(
+            "{:<width$}  {:<12} {:<6} {:>11} {:>11} {:>11} {:>11}",
+            param.name,
+            format!("{:?}", param.shape),
+            param.dtype,
+            cell(param.min.map(f64::from)),
+            cell(param.max.map(f64::from)),
+            cell(param.mean),
+            cell(param.std),
+            width = width
+        );
+    }
+    println!("Total parameters: {}", report.total_params);
+    for warning in &report.warnings {
+        println!("WARNING: {}", warning);
+    }
+}
@@ -0,0 +1,85 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::io::Write;
+
+use clap::{Parser, Subcommand};
+
+use crate::keystore::{self, SecretKey};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: KeystoreCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeystoreCommand {
+    /// Store a key in the OS secret service, replacing one of the same name
+    Put {
+        /// Name to fetch it by with --key-from-keystore
+        name: String,
+        /// 32-byte AES key in hex (64 hex chars); read from stdin when
+        /// omitted, so it stays out of the shell history
+        #[arg(long)]
+        key: Option<String>,
+    },
+    /// Delete a stored key
+    Delete { name: String },
+    /// List the names of the stored keys
+    List,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    match &args.command {
+        KeystoreCommand::Put { name, key } => {
+            let key = match key {
+                Some(hex) => SecretKey::new(super::store_key::parse_hex_key_32(hex)?),
+                None => read_key()?,
+            };
+            if keystore::put_key(name, &key)? {
+                println!("Replaced key \"{}\" in the keystore", name);
+            } else {
+                println!("Stored key \"{}\" in the keystore", name);
+            }
+        }
+        KeystoreCommand::Delete { name } => {
+            keystore::delete_key(name)?;
+            println!("Deleted key \"{}\" from the keystore", name);
+        }
+        KeystoreCommand::List => {
+            let names = keystore::list()?;
+            if names.is_empty() {
+                println!("No keys in the keystore");
+            }
+            for name in names {
+                println!("{}", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_key() -> anyhow::Result<SecretKey> {
+    print!("Key (64 hex chars): ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let key = super::store_key::parse_hex_key_32(&line);
+    keystore::wipe(&mut line.into_bytes());
+    Ok(SecretKey::new(key?))
+}
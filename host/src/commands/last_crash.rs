@@ -0,0 +1,72 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Delete the report once shown
+    #[arg(long)]
+    clear: bool,
+    /// Make the TA panic first (TAs built with the `debug-panic` feature only)
+    #[arg(long)]
+    trigger: bool,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    if args.trigger {
+        let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+        match caller.debug_panic() {
+            Err(err) if matches!(err.kind(), optee_teec::ErrorKind::TargetDead) => {
+                println!("TA panicked as requested")
+            }
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    optee_teec::ErrorKind::BadParameters | optee_teec::ErrorKind::NotSupported
+                ) =>
+            {
+                anyhow::bail!("the TA was not built with the debug-panic feature")
+            }
+            Err(err) => return Err(err.into()),
+            Ok(()) => anyhow::bail!("the TA did not panic"),
+        }
+    }
+    // The session of a dead TA is unusable; the report is read from a new one
+    let role = if args.clear {
+        SessionRole::Manage
+    } else {
+        SessionRole::Infer
+    };
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, role)?;
+    match caller.last_crash(args.clear)? {
+        Some(report) => {
+            println!("Last crash: {}", report);
+            if report.time != 0 {
+                println!("  at: {}", crate::date::format_date(report.time));
+            }
+            if args.clear {
+                println!("Report cleared");
+            }
+        }
+        None => println!("No crash recorded"),
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{ManageClient, ManagePolicy, SessionRole};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Client allowed to open manage sessions, as LOGIN:UUID with the
+    /// TEEC_LOGIN_* method in hex (e.g. 0:00000000-0000-0000-0000-000000000000
+    /// for public logins); repeat for more clients. The list must include
+    /// this client.
+    #[arg(long, value_parser = parse_client, required_unless_present = "clear")]
+    allow: Vec<ManageClient>,
+    /// Remove the policy, so that any client may manage the TA again
+    #[arg(long, conflicts_with = "allow")]
+    clear: bool,
+}
+
+fn parse_client(s: &str) -> Result<ManageClient, String> {
+    let (login, uuid) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected LOGIN:UUID, got {:?}", s))?;
+    let login = u32::from_str_radix(login.trim_start_matches("0x"), 16)
+        .map_err(|err| format!("bad login method {:?}: {}", login, err))?;
+    let uuid = proto::parse_uuid(uuid).map_err(|err| format!("bad client UUID: {}", err))?;
+    Ok(ManageClient {
+        login,
+        uuid: uuid.to_string(),
+    })
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let policy = ManagePolicy {
+        clients: args.allow.clone(),
+    };
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    caller.set_manage_policy(&policy)?;
+    if args.clear {
+        println!("Manage policy removed; any client may manage the TA");
+    } else {
+        println!(
+            "Manage sessions restricted to {} client(s)",
+            policy.clients.len()
+        );
+    }
+    Ok(())
+}
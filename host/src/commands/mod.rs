@@ -16,7 +16,39 @@
 // under the License.
 
 pub mod infer;
+#[cfg(feature = "encrypt-model")]
+pub mod inspect;
+pub mod model_history;
+#[cfg(feature = "encrypt-model")]
 pub mod encrypt;
+pub mod commit;
+pub mod diff_models;
+pub mod discard_staged;
+pub mod doctor;
+pub mod evaluate;
+pub mod export_model;
+pub mod keystore;
+pub mod last_crash;
+pub mod manage_policy;
+pub mod model;
+#[cfg(feature = "encrypt-model")]
+pub mod patch;
+pub mod provision;
+pub mod provision_line;
+pub mod replay;
+pub mod residency;
+pub mod rollback;
+pub mod rotate_key;
+pub mod selftest;
+pub mod set_verify_key;
+pub mod shadow;
+pub mod stats;
+pub mod status;
+pub mod storage;
 pub mod store_key;
+pub mod support_bundle;
+#[cfg(feature = "encrypt-model")]
+pub mod train;
 #[cfg(feature = "encrypt-model")]
 pub mod verify_model;
+pub mod watch;
@@ -0,0 +1,113 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::{Parser, Subcommand};
+
+use crate::date;
+use crate::registry::{Entry, Registry};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: ModelCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ModelCommand {
+    /// Register an encrypted model (.json) under a name for --model-name
+    Add {
+        name: String,
+        /// The encrypted model file
+        path: std::path::PathBuf,
+        /// Further names to find it by, e.g. `prod` or `shadow`
+        #[arg(long, value_delimiter = ',')]
+        tag: Vec<String>,
+        /// Replace a model registered under the same name
+        #[arg(long)]
+        force: bool,
+    },
+    /// List the registered models
+    List,
+    /// Forget a registered model; its file is left alone
+    Remove { name: String },
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    match &args.command {
+        ModelCommand::Add {
+            name,
+            path,
+            tag,
+            force,
+        } => {
+            let entry = Entry::new(name, path, tag.clone())?;
+            let replaced = Registry::edit(|registry| registry.add(entry, *force))?;
+            if replaced {
+                println!("Replaced model \"{}\" in the registry", name);
+            } else {
+                println!("Added model \"{}\" to the registry", name);
+            }
+        }
+        ModelCommand::List => {
+            let registry = Registry::load()?;
+            if registry.models.is_empty() {
+                println!("No models in the registry");
+            }
+            for entry in &registry.models {
+                print_entry(entry);
+            }
+        }
+        ModelCommand::Remove { name } => {
+            Registry::edit(|registry| registry.remove(name))?;
+            println!("Removed model \"{}\" from the registry", name);
+        }
+    }
+    Ok(())
+}
+
+fn print_entry(entry: &Entry) {
+    println!("{}:", entry.name);
+    println!("  path: {}", entry.path.display());
+    if let Err(err) = entry.check() {
+        println!("  warning: {}", err);
+    }
+    if !entry.tags.is_empty() {
+        println!("  tags: {}", entry.tags.join(", "));
+    }
+    println!("  encrypted sha256: {}", entry.encrypted_sha256);
+    if let Some(hash) = &entry.plaintext_sha256 {
+        println!("  model sha256: {}", hash);
+    }
+    if let Some(arch) = &entry.arch {
+        println!("  architecture: {}", arch);
+    }
+    if !entry.class_labels.is_empty() {
+        println!("  class labels: {}", entry.class_labels.join(", "));
+    }
+    if let Some(fingerprint) = &entry.key_fingerprint {
+        println!("  key fingerprint: {}", fingerprint);
+    }
+    for deployment in &entry.deployments {
+        println!(
+            "  provisioned to {} (TA {}) slot {} on {}",
+            deployment.device,
+            deployment.ta_uuid,
+            deployment.slot,
+            date::format_date(deployment.provisioned)
+        );
+    }
+}
@@ -0,0 +1,47 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+
+#[derive(Parser, Debug)]
+pub struct Args {}
+
+pub fn execute(_args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Infer)?;
+    let history = caller.model_history()?;
+    if history.is_empty() {
+        println!("No stored models");
+    }
+    for (i, version) in history.iter().enumerate() {
+        let provisioned = match version.provisioned {
+            0 => "unknown".to_string(),
+            secs => format!("{} (seconds since epoch)", secs),
+        };
+        println!(
+            "{}{} {} bytes, provisioned {}, sha256 {}",
+            i,
+            if version.active { "*" } else { " " },
+            version.size,
+            provisioned,
+            version.hash
+        );
+    }
+    Ok(())
+}
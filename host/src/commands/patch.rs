@@ -0,0 +1,72 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use burn::backend::NdArray;
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Encrypted base model (.json) loaded into the slot before patching
+    #[arg(short, long)]
+    model: String,
+    /// Plaintext model record holding the fine-tuned layer
+    #[arg(long)]
+    source: String,
+    /// Layer taken from `--source`: linear1, linear2, linear3 or output
+    #[arg(long, default_value = "output")]
+    layer: String,
+    /// 32-byte AES key in hex (64 hex chars), same as for encrypt-model
+    #[arg(long)]
+    key: String,
+    #[arg(long, default_value_t = 0)]
+    slot: u32,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        common::LAYER_NAMES.contains(&args.layer.as_str()),
+        "unknown layer {}, expected one of {}",
+        args.layer,
+        common::LAYER_NAMES.join(", ")
+    );
+    let device = Default::default();
+    let (_, format, record) = common::split_container(std::fs::read(&args.source)?)?;
+    let source = common::Model::<NdArray>::import_as(&device, record, format)?;
+    let partial = source
+        .export_layer(&args.layer)
+        .map_err(|err| anyhow::anyhow!("cannot export {}: {}", args.layer, err))?;
+    let patch = common::encode_patch(&args.layer, &partial)?;
+    let key = super::encrypt::parse_hex_key_32(&args.key)?;
+    let encrypted = super::encrypt::encrypt_with_key_host(&key, &patch)?;
+    println!(
+        "Patch for layer {}: {} bytes ({} encrypted)",
+        args.layer,
+        patch.len(),
+        encrypted.len()
+    );
+
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    super::infer::load_model(&mut caller, &args.model, args.slot)?;
+    caller.begin_model_load(args.slot, encrypted.len())?;
+    crate::upload::push_payload(&mut caller, &encrypted)?;
+    caller.patch_model()?;
+    println!("Patched layer {} in slot {}", args.layer, args.slot);
+    Ok(())
+}
@@ -0,0 +1,254 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{SessionRole, StoredModelInfo};
+
+use crate::commands::infer;
+use crate::registry::{self, Deployment, Entry, Registry};
+use crate::tee::InferenceTa;
+use crate::upload;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// The encrypted model (.json) to provision into slot 0
+    #[arg(short, long, required_unless_present_any = ["url", "plain", "model_name"])]
+    model: Option<String>,
+    /// Provision the registered model (see `model add`) with this name or
+    /// tag, recording the device and slot back into the registry
+    #[arg(long, conflicts_with_all = ["model", "url", "plain"])]
+    model_name: Option<String>,
+    /// Fetch the encrypted model (.json) from this http(s) URL instead,
+    /// streaming it to the TA as it downloads (needs the `net` feature)
+    #[arg(long, conflicts_with = "model", requires = "sha256")]
+    url: Option<String>,
+    /// Expected SHA-256 of the file at --url, in hex; the model is only
+    /// installed or staged when the download matches it
+    #[arg(long, requires = "url", value_parser = parse_sha256)]
+    sha256: Option<[u8; 32]>,
+    /// Don't verify the TLS certificate of the --url server
+    #[arg(long, requires = "url")]
+    insecure: bool,
+    /// Encrypt this plaintext Burn record with --key here and push it as it
+    /// is encrypted, like `encrypt-model` followed by `provision --model`
+    /// without the round trip through a file (needs the encrypt-model feature)
+    #[arg(long, conflicts_with_all = ["model", "url"], requires = "key")]
+    plain: Option<String>,
+    /// 32-byte AES key in hex (64 hex chars) encrypting --plain, the one
+    /// stored in the TA
+    #[arg(long, requires = "plain")]
+    key: Option<String>,
+    /// Validate and keep the model as the staged one without installing it;
+    /// `commit` makes it active, `discard-staged` drops it
+    #[arg(long)]
+    stage_only: bool,
+    /// Replace a model that is already staged
+    #[arg(long, requires = "stage_only")]
+    force: bool,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    let registered = match &args.model_name {
+        Some(name) => Some(registry::resolve(name)?),
+        None => None,
+    };
+    let model = match &registered {
+        Some(entry) => Some(entry.path.display().to_string()),
+        None => args.model.clone(),
+    };
+    // Only models read from a file carry a signature
+    let mut signature = None;
+    match (&args.url, &model) {
+        _ if args.plain.is_some() => {
+            let path = args.plain.as_deref().unwrap_or_default();
+            push_plain(&mut caller, path, args.key.as_deref().unwrap_or_default())?;
+            if !args.stage_only {
+                caller.finalize_model_load(None)?;
+            }
+        }
+        (Some(url), _) => {
+            let sha256 = args
+                .sha256
+                .ok_or_else(|| anyhow::anyhow!("--url needs --sha256"))?;
+            caller.begin_model_load(0, 0)?;
+            fetch_model(&mut caller, url, &sha256, args.insecure)?;
+            if !args.stage_only {
+                caller.finalize_model_load(None)?;
+            }
+        }
+        (None, Some(model)) if !args.stage_only => infer::load_model(&mut caller, model, 0)?,
+        (None, Some(model)) => {
+            let model_path = std::path::absolute(model)?;
+            println!("Stage model from \"{}\"", model_path.display());
+            let model = infer::read_encrypted_model(&model_path)?;
+            infer::preflight_storage(&mut caller, 0, model.ciphertext.len())?;
+            caller.begin_model_load(0, model.ciphertext.len())?;
+            upload::push_payload(&mut caller, &model.ciphertext)?;
+            signature = model.signature;
+        }
+        (None, None) => anyhow::bail!("--model, --url or --plain is required"),
+    }
+    if !args.stage_only {
+        let status = caller.model_status(0)?;
+        report_canaries(status.canaries);
+        println!("Model provisioned");
+        if let Some(entry) = &registered {
+            record_deployment(entry, status.stored.as_ref())?;
+        }
+        return Ok(());
+    }
+    report_canaries(caller.stage_model(args.force, signature.as_deref())?);
+    if let Some(staged) = caller.model_status(0)?.staged {
+        println!("Staged model sha256: {}", staged.model_hash);
+    }
+    println!("Run `commit` to make it the active model");
+    Ok(())
+}
+
+// Notes the installation in the registry entry, with what the TA reports
+// about the model it now stores
+fn record_deployment(entry: &Entry, stored: Option<&StoredModelInfo>) -> anyhow::Result<()> {
+    let deployment = Deployment {
+        device: registry::device_name(),
+        ta_uuid: crate::tee::configured_ta_uuid().to_string(),
+        slot: 0,
+        provisioned: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+    };
+    Registry::edit(|registry| {
+        let registered = registry.find_mut(&entry.name)?;
+        // Only trusted when the TA stores the very ciphertext registered
+        if let Some(stored) = stored.filter(|s| s.encrypted_hash == registered.encrypted_sha256) {
+            registered.plaintext_sha256 = Some(stored.model_hash.clone());
+            registered.arch = Some(stored.arch.clone());
+            registered.class_labels = stored.class_labels.clone();
+            registered.key_fingerprint = stored.key_fingerprint.clone();
+        }
+        registered.deployments.retain(|d| {
+            d.device != deployment.device
+                || d.ta_uuid != deployment.ta_uuid
+                || d.slot != deployment.slot
+        });
+        registered.deployments.push(deployment);
+        Ok(())
+    })?;
+    println!("Recorded the deployment in the registry");
+    Ok(())
+}
+
+// A failing canary fails the load itself, reported by the connector
+fn report_canaries(passed: usize) {
+    match passed {
+        0 => println!("Canary check: the model carries no canary images"),
+        n => println!("Canary check: passed ({} images)", n),
+    }
+}
+
+fn parse_sha256(s: &str) -> Result<[u8; 32], String> {
+    let s = s.trim();
+    if s.len() != 64 || !s.is_ascii() {
+        return Err("expected 64 hex characters".to_string());
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("invalid hex digest: {}", s))?;
+    }
+    Ok(digest)
+}
+
+/// Starts a load of slot 0 and encrypts the plaintext record at `path` into
+/// it, overlapping encryption and pushing.
+#[cfg(feature = "encrypt-model")]
+fn push_plain(caller: &mut dyn InferenceTa, path: &str, key_hex: &str) -> anyhow::Result<()> {
+    let key = super::encrypt::parse_hex_key_32(key_hex)?;
+    let model_path = std::path::absolute(path)?;
+    println!("Encrypt and push model from \"{}\"", model_path.display());
+    let payload = std::fs::read(&model_path)?;
+    if let Err(common::ModelError::Metadata(common::ContainerError::Compressed)) =
+        common::parse_container(&payload)
+    {
+        caller.require_feature(proto::inference::FEATURE_DEFLATE)?;
+    }
+    let encrypted_len = crate::pipeline::encrypted_len(payload.len())?;
+    infer::preflight_storage(caller, 0, encrypted_len)?;
+    caller.begin_model_load(0, encrypted_len)?;
+    let stats = crate::pipeline::encrypt_and_push(caller, &key, &payload)?;
+    println!(
+        "Encrypted in {:.2?}, pushed in {:.2?}, {:.2?} in all ({:.2?} saved by overlapping)",
+        stats.encrypt,
+        stats.push,
+        stats.wall,
+        stats.saved()
+    );
+    Ok(())
+}
+
+#[cfg(not(feature = "encrypt-model"))]
+fn push_plain(_caller: &mut dyn InferenceTa, _path: &str, _key_hex: &str) -> anyhow::Result<()> {
+    anyhow::bail!("--plain needs a host built with the encrypt-model feature")
+}
+
+/// Streams the model file at `url` into the load started with
+/// `begin_model_load`, without keeping more than a chunk of it. Fails, with
+/// nothing finalized, unless the file has the SHA-256 `sha256`; the TA drops
+/// the pushed chunks when its last session closes.
+#[cfg(feature = "net")]
+fn fetch_model(
+    caller: &mut dyn InferenceTa,
+    url: &str,
+    sha256: &[u8; 32],
+    insecure: bool,
+) -> anyhow::Result<()> {
+    use crate::download::{Download, EncryptedDataDecoder};
+
+    println!("Fetch model from {}", url);
+    let mut download = Download::start(url, insecure)?;
+    // The ciphertext size is only known once the file is decoded, so there
+    // is no storage preflight; the TA still refuses what it can't store
+    let mut decoder = EncryptedDataDecoder::default();
+    let mut pusher = upload::Pusher::new(caller, None);
+    let mut buffer = vec![0; upload::DEFAULT_CHUNK_SIZE];
+    let mut pending = Vec::new();
+    loop {
+        let read = download.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        decoder.feed(&buffer[..read], &mut pending)?;
+        let pushed = pusher.push(&pending, false)?;
+        pending.drain(..pushed);
+    }
+    decoder.finish()?;
+    download.finish(sha256)?;
+    pusher.push(&pending, true)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "net"))]
+fn fetch_model(
+    _caller: &mut dyn InferenceTa,
+    _url: &str,
+    _sha256: &[u8; 32],
+    _insecure: bool,
+) -> anyhow::Result<()> {
+    anyhow::bail!("--url needs a host built with the net feature")
+}
@@ -0,0 +1,718 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Provisioning line: gives each device that turns up the next unused key of
+// a batch and appends the outcome to a ledger. Devices are reached through
+// `LineDevice`, so the line can be run against a made-up one.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use optee_teec::{Context, ErrorKind};
+use proto::inference::KEY_FINGERPRINT_SIZE;
+use sha2::{Digest, Sha256};
+
+use super::store_key::parse_hex_key_32;
+use crate::keystore::SecretKey;
+use crate::tee::KeyProvisionTaConnector;
+use crate::transcript::{self, Redacted, Step};
+
+/// First line of a ledger.
+pub const LEDGER_HEADER: &str = "serial,fingerprint,timestamp,status";
+
+type Fingerprint = [u8; KEY_FINGERPRINT_SIZE];
+
+/// Provisions a batch of keys, one device after the other: waits for a
+/// device's TA to answer, stores the next unused key unless the device
+/// already holds one of the batch, checks the fingerprint the TA reports
+/// and appends the outcome to the ledger; runs until interrupted
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// CSV of `serial,key` lines with the keys as 64 hex chars; a first
+    /// line starting with "serial" is skipped
+    #[arg(long)]
+    keys: PathBuf,
+    /// CSV the outcomes are appended to (`serial,fingerprint,timestamp,
+    /// status`), created when missing. Keys it lists are never handed out
+    /// again, so a restarted line picks up where it stopped
+    #[arg(long)]
+    ledger: PathBuf,
+    /// Seconds between probes while waiting for a device to be attached or
+    /// removed
+    #[arg(long, default_value_t = 2)]
+    poll_secs: u64,
+    /// Stop after this many devices, e.g. 1 when the host runs on the
+    /// device itself
+    #[arg(long)]
+    count: Option<usize>,
+}
+
+/// What the line needs of a device.
+pub trait LineDevice {
+    /// Opens a session to the TA of the attached device; fails while none
+    /// is attached.
+    fn connect(&mut self) -> Result<()>;
+    /// Closes the session, if any.
+    fn disconnect(&mut self);
+    /// Fingerprint of the key the device holds, `None` without one.
+    fn key_fingerprint(&mut self) -> optee_teec::Result<Option<Fingerprint>>;
+    /// Stores `key`, leaving a different key already stored in place.
+    fn store_key(&mut self, key: &[u8; 32]) -> optee_teec::Result<()>;
+}
+
+/// The device behind the TEE driver of this host.
+#[derive(Default)]
+pub struct TeeDevice {
+    // Declared before the context, so that it is dropped first
+    provisioner: Option<KeyProvisionTaConnector>,
+    ctx: Option<Context>,
+}
+
+impl TeeDevice {
+    fn provisioner(&mut self) -> optee_teec::Result<&mut KeyProvisionTaConnector> {
+        self.provisioner
+            .as_mut()
+            .ok_or_else(|| ErrorKind::BadState.into())
+    }
+}
+
+impl LineDevice for TeeDevice {
+    fn connect(&mut self) -> Result<()> {
+        self.disconnect();
+        let mut ctx = Context::new()?;
+        self.provisioner = Some(KeyProvisionTaConnector::new(&mut ctx)?);
+        self.ctx = Some(ctx);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.provisioner = None;
+        self.ctx = None;
+    }
+
+    fn key_fingerprint(&mut self) -> optee_teec::Result<Option<Fingerprint>> {
+        self.provisioner()?.key_fingerprint()
+    }
+
+    fn store_key(&mut self, key: &[u8; 32]) -> optee_teec::Result<()> {
+        self.provisioner()?.store_key(key, false)
+    }
+}
+
+/// One line of the keys CSV.
+pub struct BatchKey {
+    pub serial: String,
+    pub key: SecretKey,
+    pub fingerprint: Fingerprint,
+}
+
+/// Reads the keys CSV. Serials and keys must be unique, so that a
+/// fingerprint names one serial.
+pub fn read_batch(path: &Path) -> Result<Vec<BatchKey>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let mut batch: Vec<BatchKey> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (number == 0 && line.starts_with("serial")) {
+            continue;
+        }
+        let at = || format!("{}:{}", path.display(), number + 1);
+        let (serial, key) = line
+            .split_once(',')
+            .with_context(|| format!("{}: expected serial,key", at()))?;
+        let serial = serial.trim();
+        anyhow::ensure!(!serial.is_empty(), "{}: empty serial", at());
+        // Wrapped keys would need a key to unwrap them with, which neither
+        // the host nor the TA has
+        let key = SecretKey::new(parse_hex_key_32(key).with_context(|| {
+            format!(
+                "{}: not a hex key; wrapped keys must be unwrapped first",
+                at()
+            )
+        })?);
+        let fingerprint = fingerprint(key.bytes());
+        if let Some(other) = batch
+            .iter()
+            .find(|entry| entry.serial == serial || entry.fingerprint == fingerprint)
+        {
+            anyhow::bail!("{}: serial or key repeats those of {}", at(), other.serial);
+        }
+        batch.push(BatchKey {
+            serial: serial.to_string(),
+            key,
+            fingerprint,
+        });
+    }
+    anyhow::ensure!(!batch.is_empty(), "{} lists no keys", path.display());
+    Ok(batch)
+}
+
+/// What happened to a device, as written to the ledger.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The key was stored and the device reports its fingerprint.
+    Provisioned,
+    /// The device already held this key of the batch.
+    AlreadyProvisioned,
+    /// The device holds a key from outside the batch, which was left alone.
+    ForeignKey,
+    /// The key was stored but the device reports another fingerprint.
+    Mismatch,
+    /// The key was stored but its fingerprint couldn't be read back.
+    Unverified,
+    /// Storing the key failed; the device may hold it anyway.
+    Failed,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Status::Provisioned => "provisioned",
+            Status::AlreadyProvisioned => "already-provisioned",
+            Status::ForeignKey => "foreign-key",
+            Status::Mismatch => "mismatch",
+            Status::Unverified => "unverified",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+/// The ledger CSV, opened for appending, and the serials it lists.
+pub struct Ledger {
+    file: File,
+    used: HashSet<String>,
+}
+
+impl Ledger {
+    pub fn open(path: &Path) -> Result<Self> {
+        let existing = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err).with_context(|| format!("cannot read {}", path.display())),
+        };
+        let mut lines = existing.lines();
+        let header = lines.next();
+        anyhow::ensure!(
+            header.is_none_or(|header| header == LEDGER_HEADER),
+            "{} is not a provision-line ledger",
+            path.display()
+        );
+        let used = lines
+            .filter_map(|line| line.split(',').next())
+            .filter(|serial| !serial.is_empty())
+            .map(str::to_string)
+            .collect();
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open {}", path.display()))?;
+        if header.is_none() {
+            writeln!(file, "{}", LEDGER_HEADER)?;
+        }
+        Ok(Self { file, used })
+    }
+
+    /// Whether the ledger lists `serial`, whatever its status.
+    pub fn lists(&self, serial: &str) -> bool {
+        self.used.contains(serial)
+    }
+
+    // Each line is synced before the next device is taken, so a crash can't
+    // lose the record of a stored key
+    fn append(&mut self, serial: &str, fingerprint: &Fingerprint, status: Status) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        writeln!(
+            self.file,
+            "{},{},{},{}",
+            serial,
+            hex(fingerprint),
+            crate::date::format_date(now),
+            status.as_str()
+        )?;
+        self.file.sync_data()?;
+        if !serial.is_empty() {
+            self.used.insert(serial.to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn execute(args: &Args) -> Result<()> {
+    let batch = read_batch(&args.keys)?;
+    let mut ledger = Ledger::open(&args.ledger)?;
+    let unused = batch
+        .iter()
+        .filter(|entry| !ledger.lists(&entry.serial))
+        .count();
+    println!("{} of {} keys unused", unused, batch.len());
+    let poll = Duration::from_secs(args.poll_secs);
+    run(
+        &mut TeeDevice::default(),
+        &batch,
+        &mut ledger,
+        poll,
+        args.count,
+    )
+}
+
+/// Provisions devices until `count` of them are done, if given. Returns
+/// when a device can't be provisioned at all (batch used up, TA without key
+/// fingerprints, ledger not writable); other failures are recorded in the
+/// ledger and the line moves on.
+pub fn run(
+    device: &mut dyn LineDevice,
+    batch: &[BatchKey],
+    ledger: &mut Ledger,
+    poll: Duration,
+    count: Option<usize>,
+) -> Result<()> {
+    let mut done = 0;
+    while count.is_none_or(|count| done < count) {
+        println!("Waiting for a device");
+        wait_for(device, poll, true);
+        let outcome = provision_device(device, batch, ledger);
+        device.disconnect();
+        outcome?;
+        done += 1;
+        if count.is_none_or(|count| done < count) {
+            println!("Remove the device");
+            wait_for(device, poll, false);
+        }
+    }
+    Ok(())
+}
+
+// Probes until a device is attached (or removed), like `doctor` printing
+// why the TA can't be reached whenever the reason changes
+fn wait_for(device: &mut dyn LineDevice, poll: Duration, attached: bool) {
+    let mut last_error = None;
+    loop {
+        match device.connect() {
+            Ok(()) if attached => return,
+            Ok(()) => device.disconnect(),
+            Err(_) if !attached => return,
+            Err(err) => {
+                let reason = format!("{:#}", err);
+                if last_error.as_ref() != Some(&reason) {
+                    println!("no device yet: {}", reason);
+                    last_error = Some(reason);
+                }
+            }
+        }
+        std::thread::sleep(poll);
+    }
+}
+
+// Gives the attached device the next unused key unless it holds one already
+fn provision_device(
+    device: &mut dyn LineDevice,
+    batch: &[BatchKey],
+    ledger: &mut Ledger,
+) -> Result<()> {
+    let held = match device.key_fingerprint() {
+        Err(err) if matches!(err.kind(), ErrorKind::NotSupported) => {
+            anyhow::bail!("the device's TA can't report key fingerprints, update it first")
+        }
+        Err(err) => {
+            println!(
+                "Cannot read the device's key fingerprint ({}), skipping it",
+                err
+            );
+            return Ok(());
+        }
+        Ok(held) => held,
+    };
+    if let Some(held) = held {
+        return match batch.iter().find(|entry| entry.fingerprint == held) {
+            Some(entry) => {
+                println!("Device already holds the key of {}", entry.serial);
+                ledger.append(&entry.serial, &held, Status::AlreadyProvisioned)
+            }
+            None => {
+                println!(
+                    "Device holds key {} from outside the batch, leaving it alone",
+                    hex(&held)
+                );
+                ledger.append("", &held, Status::ForeignKey)
+            }
+        };
+    }
+    let entry = batch
+        .iter()
+        .find(|entry| !ledger.lists(&entry.serial))
+        .with_context(|| format!("all {} keys of the batch are used", batch.len()))?;
+    println!("Storing the key of {}", entry.serial);
+    transcript::record(Step::Key {
+        key: Redacted::new(&entry.key.bytes()[..]),
+    });
+    let status = match device.store_key(entry.key.bytes()) {
+        Err(err) => {
+            println!("Storing the key failed: {}", err);
+            Status::Failed
+        }
+        Ok(()) => match device.key_fingerprint() {
+            Ok(Some(reported)) if reported == entry.fingerprint => Status::Provisioned,
+            Ok(reported) => {
+                println!(
+                    "Device reports key {} instead of {}",
+                    reported.map_or("none".to_string(), |reported| hex(&reported)),
+                    hex(&entry.fingerprint)
+                );
+                Status::Mismatch
+            }
+            Err(err) => {
+                println!("Cannot read the key fingerprint back: {}", err);
+                Status::Unverified
+            }
+        },
+    };
+    println!("{}: {}", entry.serial, status.as_str());
+    ledger.append(&entry.serial, &entry.fingerprint, status)
+}
+
+fn fingerprint(key: &[u8; 32]) -> Fingerprint {
+    let hash = Sha256::digest(key);
+    let mut fingerprint = [0_u8; KEY_FINGERPRINT_SIZE];
+    fingerprint.copy_from_slice(&hash[..KEY_FINGERPRINT_SIZE]);
+    fingerprint
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A device on the line; flags left unset answer as a working TA would
+    #[derive(Default)]
+    struct MockDevice {
+        attached: bool,
+        connected: bool,
+        key: Option<[u8; 32]>,
+        // The TA predates key fingerprints
+        no_fingerprints: bool,
+        // Storing fails, as on a full or damaged secure storage
+        store_fails: bool,
+        // The device is pulled right after the key was stored
+        pulled_after_store: bool,
+        // The TA reports a fingerprint other than the key's
+        misreports: bool,
+        stores: usize,
+    }
+
+    impl LineDevice for MockDevice {
+        fn connect(&mut self) -> Result<()> {
+            anyhow::ensure!(self.attached, "no device");
+            self.connected = true;
+            Ok(())
+        }
+
+        fn disconnect(&mut self) {
+            self.connected = false;
+        }
+
+        fn key_fingerprint(&mut self) -> optee_teec::Result<Option<Fingerprint>> {
+            if !self.connected {
+                return Err(ErrorKind::Communication.into());
+            }
+            if self.no_fingerprints {
+                return Err(ErrorKind::NotSupported.into());
+            }
+            Ok(self.key.map(|key| {
+                let mut reported = fingerprint(&key);
+                if self.misreports {
+                    reported[0] ^= 1;
+                }
+                reported
+            }))
+        }
+
+        fn store_key(&mut self, key: &[u8; 32]) -> optee_teec::Result<()> {
+            if !self.connected {
+                return Err(ErrorKind::Communication.into());
+            }
+            self.stores += 1;
+            if self.store_fails {
+                return Err(ErrorKind::Generic.into());
+            }
+            if self.key.is_none() {
+                self.key = Some(*key);
+            }
+            if self.pulled_after_store {
+                self.attached = false;
+                self.connected = false;
+            }
+            Ok(())
+        }
+    }
+
+    fn attached() -> MockDevice {
+        MockDevice {
+            attached: true,
+            connected: true,
+            ..Default::default()
+        }
+    }
+
+    // A scratch file unique to the test, removed when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "provision-line-{}-{}.csv",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn write(self, text: &str) -> Self {
+            std::fs::write(&self.0, text).unwrap();
+            self
+        }
+
+        fn lines(&self) -> Vec<String> {
+            std::fs::read_to_string(&self.0)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn key(byte: u8) -> [u8; 32] {
+        [byte; 32]
+    }
+
+    fn batch(serials: &[(&str, u8)]) -> Vec<BatchKey> {
+        serials
+            .iter()
+            .map(|&(serial, byte)| BatchKey {
+                serial: serial.to_string(),
+                key: SecretKey::new(key(byte)),
+                fingerprint: fingerprint(&key(byte)),
+            })
+            .collect()
+    }
+
+    // Serial and status of each ledger line after the header
+    fn outcomes(ledger: &Scratch) -> Vec<(String, String)> {
+        let lines = ledger.lines();
+        assert_eq!(lines[0], LEDGER_HEADER);
+        lines[1..]
+            .iter()
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                assert_eq!(fields.len(), 4, "{line}");
+                (fields[0].to_string(), fields[3].to_string())
+            })
+            .collect()
+    }
+
+    fn outcome(serial: &str, status: Status) -> (String, String) {
+        (serial.to_string(), status.as_str().to_string())
+    }
+
+    #[test]
+    fn provisions_the_next_unused_key() {
+        let file = Scratch::new("next");
+        let batch = batch(&[("A", 1), ("B", 2)]);
+        let mut ledger = Ledger::open(&file.0).unwrap();
+        let mut device = attached();
+        provision_device(&mut device, &batch, &mut ledger).unwrap();
+        assert_eq!(device.key, Some(key(1)));
+        let mut device = attached();
+        provision_device(&mut device, &batch, &mut ledger).unwrap();
+        assert_eq!(device.key, Some(key(2)));
+        assert_eq!(
+            outcomes(&file),
+            [
+                outcome("A", Status::Provisioned),
+                outcome("B", Status::Provisioned)
+            ]
+        );
+        assert!(ledger.lists("A") && ledger.lists("B"));
+        let mut device = attached();
+        assert!(provision_device(&mut device, &batch, &mut ledger).is_err());
+        assert_eq!(device.stores, 0);
+    }
+
+    #[test]
+    fn devices_holding_a_batch_key_are_only_recorded() {
+        let file = Scratch::new("held");
+        let batch = batch(&[("A", 1), ("B", 2)]);
+        let mut ledger = Ledger::open(&file.0).unwrap();
+        let mut device = MockDevice {
+            key: Some(key(2)),
+            ..attached()
+        };
+        provision_device(&mut device, &batch, &mut ledger).unwrap();
+        let mut device = MockDevice {
+            key: Some(key(9)),
+            ..attached()
+        };
+        provision_device(&mut device, &batch, &mut ledger).unwrap();
+        assert_eq!(device.stores, 0);
+        assert_eq!(
+            outcomes(&file),
+            [
+                outcome("B", Status::AlreadyProvisioned),
+                outcome("", Status::ForeignKey)
+            ]
+        );
+        // A's key is still free, B's isn't
+        assert!(!ledger.lists("A") && ledger.lists("B"));
+    }
+
+    #[test]
+    fn failures_are_recorded_and_burn_the_key() {
+        let file = Scratch::new("failures");
+        let batch = batch(&[("A", 1), ("B", 2), ("C", 3)]);
+        let mut ledger = Ledger::open(&file.0).unwrap();
+        for mut device in [
+            MockDevice {
+                misreports: true,
+                ..attached()
+            },
+            MockDevice {
+                pulled_after_store: true,
+                ..attached()
+            },
+            MockDevice {
+                store_fails: true,
+                ..attached()
+            },
+        ] {
+            provision_device(&mut device, &batch, &mut ledger).unwrap();
+            assert_eq!(device.stores, 1);
+        }
+        assert_eq!(
+            outcomes(&file),
+            [
+                outcome("A", Status::Mismatch),
+                outcome("B", Status::Unverified),
+                outcome("C", Status::Failed)
+            ]
+        );
+    }
+
+    #[test]
+    fn unreadable_and_outdated_devices() {
+        let file = Scratch::new("unreadable");
+        let batch = batch(&[("A", 1)]);
+        let mut ledger = Ledger::open(&file.0).unwrap();
+        // Pulled before the fingerprint was read: skipped, nothing recorded
+        let mut device = MockDevice {
+            connected: false,
+            ..attached()
+        };
+        provision_device(&mut device, &batch, &mut ledger).unwrap();
+        // A TA without fingerprints stops the line
+        let mut device = MockDevice {
+            no_fingerprints: true,
+            ..attached()
+        };
+        assert!(provision_device(&mut device, &batch, &mut ledger).is_err());
+        assert_eq!(device.stores, 0);
+        assert!(outcomes(&file).is_empty());
+    }
+
+    #[test]
+    fn run_handles_one_device_at_a_time() {
+        let file = Scratch::new("run");
+        let batch = batch(&[("A", 1), ("B", 2)]);
+        let mut ledger = Ledger::open(&file.0).unwrap();
+        let mut device = MockDevice {
+            attached: true,
+            ..Default::default()
+        };
+        run(&mut device, &batch, &mut ledger, Duration::ZERO, Some(1)).unwrap();
+        assert!(!device.connected);
+        assert_eq!(device.key, Some(key(1)));
+        assert_eq!(outcomes(&file), [outcome("A", Status::Provisioned)]);
+    }
+
+    #[test]
+    fn restarted_line_skips_listed_serials() {
+        let file = Scratch::new("restart");
+        let batch = batch(&[("A", 1), ("B", 2)]);
+        {
+            let mut ledger = Ledger::open(&file.0).unwrap();
+            provision_device(&mut attached(), &batch, &mut ledger).unwrap();
+        }
+        let mut ledger = Ledger::open(&file.0).unwrap();
+        assert!(ledger.lists("A") && !ledger.lists("B"));
+        let mut device = attached();
+        provision_device(&mut device, &batch, &mut ledger).unwrap();
+        assert_eq!(device.key, Some(key(2)));
+        assert_eq!(file.lines().len(), 3);
+    }
+
+    #[test]
+    fn foreign_ledgers_are_refused() {
+        let file = Scratch::new("foreign").write("name,value\n");
+        assert!(Ledger::open(&file.0).is_err());
+    }
+
+    #[test]
+    fn batch_files() {
+        let hex_key = |byte: u8| hex(&key(byte));
+        let file = Scratch::new("batch").write(&format!(
+            "serial,key\nA,{}\n\n B , {} \n",
+            hex_key(1),
+            hex_key(2)
+        ));
+        let batch = read_batch(&file.0).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[1].serial, "B");
+        assert_eq!(batch[1].key.bytes(), &key(2));
+        assert_eq!(batch[1].fingerprint, fingerprint(&key(2)));
+
+        for text in [
+            String::new(),
+            "serial,key\n".to_string(),
+            format!("A,{}\nA,{}\n", hex_key(1), hex_key(2)),
+            format!("A,{}\nB,{}\n", hex_key(1), hex_key(1)),
+            format!(",{}\n", hex_key(1)),
+            "A,not-hex\n".to_string(),
+            format!("A {}\n", hex_key(1)),
+        ] {
+            let file = Scratch::new("bad-batch").write(&text);
+            assert!(read_batch(&file.0).is_err(), "{text:?}");
+        }
+    }
+}
@@ -0,0 +1,268 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{self, SessionRole, MODEL_SLOTS};
+
+use crate::recorded_run::{self, RecordedRun};
+use crate::tee::InferenceTa;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Directory written by `infer --record-run`
+    dir: PathBuf,
+    /// Model to load first; the model already in the recorded slot (or the
+    /// stored one) is used otherwise
+    #[arg(short, long)]
+    model: Option<String>,
+    /// Slot to run the inputs in instead of the recorded one
+    #[arg(long)]
+    slot: Option<u32>,
+    /// Largest change of a probability that still counts as the same answer
+    #[arg(long, default_value_t = 0.0)]
+    tolerance: f32,
+    /// Load and run the model in the simulated TA instead of a TEE
+    #[arg(long, requires_all = ["key", "model"])]
+    dry_run: bool,
+    /// 32-byte AES key in hex the simulated TA decrypts the model with
+    #[arg(long)]
+    key: Option<String>,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let (run, images) = RecordedRun::read(&args.dir)?;
+    let slot = args.slot.unwrap_or(run.settings.slot);
+    anyhow::ensure!(
+        (slot as usize) < MODEL_SLOTS,
+        "slots must be below {}",
+        MODEL_SLOTS
+    );
+    println!(
+        "Replaying {} input(s) recorded from {}",
+        run.entries.len(),
+        run.model_name.as_deref().unwrap_or("an unnamed model")
+    );
+
+    // Outlives the connector's session
+    let mut ctx = None;
+    let mut caller: Box<dyn InferenceTa> = if args.dry_run {
+        super::infer::simulated_ta(args.key.as_deref().unwrap_or_default())?
+    } else {
+        let ctx = ctx.insert(Context::new()?);
+        let role = if args.model.is_some() {
+            SessionRole::Manage
+        } else {
+            SessionRole::Infer
+        };
+        Box::new(crate::tee::InferenceTaConnector::new(ctx, role)?)
+    };
+    if let Some(path) = &args.model {
+        super::infer::load_model(caller.as_mut(), path, slot)?;
+    }
+    let status = caller.model_status(slot)?;
+    anyhow::ensure!(
+        status.num_classes == run.num_classes,
+        "the model has {} classes, the recording {}",
+        status.num_classes,
+        run.num_classes
+    );
+    if status.name != run.model_name {
+        println!(
+            "warning: replaying with {} instead of {}",
+            status.name.as_deref().unwrap_or("an unnamed model"),
+            run.model_name.as_deref().unwrap_or("an unnamed model")
+        );
+    }
+    if status.normalization != run.normalization {
+        println!(
+            "warning: the model normalizes with {}, the recording with {}",
+            status.normalization, run.normalization
+        );
+    }
+    if run.settings.input_flags != 0 {
+        caller.set_input_flags(run.settings.input_flags)?;
+    }
+    if run.settings.reject_below.is_some() {
+        caller.set_reject_threshold(run.settings.reject_below)?;
+    }
+
+    let batch =
+        caller.infer_recorded(&images, run.settings.temperature, slot, status.num_classes)?;
+    let normalization =
+        inference::input_normalization(run.settings.input_flags, &status.normalization);
+    let mut differing = 0;
+    for (i, entry) in run.entries.iter().enumerate() {
+        let prediction = &batch.predictions[i];
+        let mut changes = Vec::new();
+        if prediction.label != entry.label || prediction.candidate != entry.candidate {
+            changes.push(format!(
+                "class {} (best guess {}{}) was {} (best guess {})",
+                prediction.label,
+                prediction.candidate,
+                if prediction.is_tied() { ", a tie" } else { "" },
+                entry.label,
+                entry.candidate
+            ));
+        }
+        if recorded_run::input_hash(&batch.input_hashes[i]) != entry.input_hash {
+            changes.push("the TA ran another input tensor".to_string());
+        }
+        if recorded_run::expected_hash(&images[i], &normalization) != entry.expected_hash {
+            changes.push("the host computes another input tensor".to_string());
+        }
+        let probabilities =
+            &batch.probabilities[i * status.num_classes..(i + 1) * status.num_classes];
+        let moved = probabilities
+            .iter()
+            .zip(&entry.probabilities)
+            .map(|(now, then)| (now - then).abs())
+            .fold(0.0, f32::max);
+        if moved > args.tolerance {
+            changes.push(format!("probabilities moved by up to {}", moved));
+        }
+        if !changes.is_empty() {
+            differing += 1;
+            println!("{}. {}: {}", i + 1, entry.input, changes.join(", "));
+        }
+    }
+    if args.dry_run {
+        println!("{}", crate::tee::DRY_RUN_BANNER);
+    }
+    anyhow::ensure!(
+        differing == 0,
+        "{} of {} input(s) differ from the recording",
+        differing,
+        run.entries.len()
+    );
+    println!("All {} input(s) match the recording", run.entries.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+    use clap::Parser;
+    use proto::{Image, DEFAULT_NUM_CLASSES, IMAGE_SIZE};
+
+    type Model = common::Model<NdArray>;
+
+    fn run(command: &[&str]) -> anyhow::Result<()> {
+        let args = ["enc_mnist-rs"].iter().chain(command);
+        crate::run(crate::Cli::try_parse_from(args).unwrap().command)
+    }
+
+    #[test]
+    fn recorded_runs_replay_through_the_simulated_ta() {
+        let dir = std::env::temp_dir().join(format!("record-replay-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let (plain, encrypted, bundle) = (path("model.bin"), path("model.json"), path("bundle"));
+        let model = Model::new_with_seed(&Default::default(), 3, DEFAULT_NUM_CLASSES);
+        std::fs::write(&plain, model.export().unwrap()).unwrap();
+        let images: Vec<Image> = (0..3)
+            .map(|i| {
+                let mut pixels = [0; IMAGE_SIZE];
+                for (j, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = (j * (i + 3) % 256) as u8;
+                }
+                Image::from_luma28(&pixels)
+            })
+            .collect();
+        let inputs: Vec<String> = images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| {
+                let input = path(&format!("{}.bin", i));
+                std::fs::write(&input, image.as_bytes()).unwrap();
+                input
+            })
+            .collect();
+        let key = "5a".repeat(32);
+        let replay = || {
+            run(&[
+                "replay",
+                &bundle,
+                "--dry-run",
+                "--key",
+                &key,
+                "--model",
+                &encrypted,
+            ])
+        };
+
+        let result = run(&[
+            "encrypt-model",
+            "--input",
+            &plain,
+            "--output",
+            &encrypted,
+            "--key",
+            &key,
+        ])
+        .and_then(|()| {
+            let mut command = vec!["infer", "--dry-run", "--key", &key, "--model", &encrypted];
+            for input in &inputs {
+                command.extend(["--binary", input]);
+            }
+            command.extend(["--record-run", &bundle]);
+            run(&command)
+        })
+        .and_then(|()| {
+            let recorded = RecordedRun::read(bundle.as_ref())?;
+            let matched = replay();
+            // The recording no longer matches once its answers are changed
+            let manifest = std::path::Path::new(&bundle).join(recorded_run::MANIFEST);
+            let (mut run, _) = RecordedRun::read(bundle.as_ref())?;
+            let label = run.entries[1].label;
+            run.entries[1].label = (label + 1) % DEFAULT_NUM_CLASSES as u8;
+            std::fs::write(&manifest, serde_json::to_vec(&run)?)?;
+            let relabelled = replay();
+            run.entries[1].label = label;
+            run.entries[2].probabilities[0] += 0.25;
+            std::fs::write(&manifest, serde_json::to_vec(&run)?)?;
+            let shifted = replay();
+            Ok((recorded, matched, relabelled, shifted))
+        });
+        let _ = std::fs::remove_dir_all(&dir);
+        let ((recorded, images_read), matched, relabelled, shifted) = result.unwrap();
+
+        assert_eq!(images_read, images);
+        assert_eq!(recorded.entries.len(), 3);
+        assert_eq!(recorded.drifted().count(), 0);
+        let input = Model::images_to_tensors(&Default::default(), &images, &recorded.normalization);
+        let (labels, probs) = model.predict(input).unwrap();
+        for (i, entry) in recorded.entries.iter().enumerate() {
+            assert_eq!(entry.input, inputs[i]);
+            assert_eq!(entry.label, labels[i]);
+            assert_eq!(
+                entry.probabilities,
+                probs[i * DEFAULT_NUM_CLASSES..][..DEFAULT_NUM_CLASSES]
+            );
+            assert_eq!(
+                entry.input_hash,
+                recorded_run::expected_hash(&images[i], &recorded.normalization)
+            );
+        }
+        matched.unwrap();
+        assert!(relabelled.is_err());
+        assert!(shifted.is_err());
+    }
+}
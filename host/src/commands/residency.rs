@@ -0,0 +1,46 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+use clap::{Parser, ValueEnum};
+use optee_teec::Context;
+use proto::inference::{ResidencyPolicy, SessionRole};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Policy {
+    /// Keep models loaded after the last session closes
+    KeepResident,
+    /// Drop models when the last session closes; the stored model is
+    /// reloaded by the next inference
+    DropOnIdle,
+}
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[arg(value_enum)]
+    policy: Policy,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let policy = match args.policy {
+        Policy::KeepResident => ResidencyPolicy::KeepResident,
+        Policy::DropOnIdle => ResidencyPolicy::DropOnIdle,
+    };
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    caller.set_residency_policy(policy)?;
+    println!("Residency policy set to {:?}", policy);
+    Ok(())
+}
@@ -0,0 +1,62 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use anyhow::Result;
+use clap::Args as ClapArgs;
+
+use crate::keystore::{self, SecretKey};
+use crate::transcript::{self, Redacted, Step};
+
+#[derive(ClapArgs, Debug)]
+pub struct Args {
+    /// The new 32-byte AES key in hex (64 hex chars)
+    #[arg(long, required_unless_present = "key_from_keystore")]
+    key: Option<String>,
+    /// Fetch the new key stored under this name with `keystore put` from the
+    /// OS secret service instead (needs the keystore feature)
+    #[arg(long, conflicts_with = "key")]
+    key_from_keystore: Option<String>,
+}
+
+pub fn execute(args: &Args) -> Result<()> {
+    let key = match &args.key_from_keystore {
+        Some(name) => keystore::fetch_key(name)?,
+        None => SecretKey::new(super::store_key::parse_hex_key_32(
+            args.key.as_deref().unwrap_or_default(),
+        )?),
+    };
+    let mut ctx = optee_teec::Context::new()?;
+    let mut provisioner = crate::tee::KeyProvisionTaConnector::new(&mut ctx)?;
+    transcript::record(Step::Key {
+        key: Redacted::new(&key.bytes()[..]),
+    });
+    match provisioner.rotate_key(key.bytes()) {
+        Err(err) if matches!(err.kind(), optee_teec::ErrorKind::ItemNotFound) => {
+            anyhow::bail!("the TA has no key to rotate; use store-key")
+        }
+        Err(err) if matches!(err.kind(), optee_teec::ErrorKind::BadState) => {
+            anyhow::bail!("a model is staged; commit or discard it before rotating the key")
+        }
+        Err(err) if matches!(err.kind(), optee_teec::ErrorKind::NotSupported) => {
+            anyhow::bail!("this TA can't rotate its key")
+        }
+        result => result?,
+    }
+    println!("Key rotated; the stored model is encrypted under the new key.");
+    println!("Earlier model versions were dropped, rollback starts from this one.");
+    Ok(())
+}
@@ -0,0 +1,61 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+use proto::test_vectors::VECTORS;
+
+#[derive(Parser, Debug)]
+pub struct Args {}
+
+/// Checks the known-answer vectors of `proto::test_vectors` against the host
+/// encryptor and the TA.
+pub fn execute(_args: &Args) -> anyhow::Result<()> {
+    let mut failures = 0;
+    #[cfg(feature = "encrypt-model")]
+    for vector in VECTORS {
+        let encrypted =
+            crate::commands::encrypt::encrypt_with_iv(&vector.key, &vector.iv, vector.payload)?;
+        let pass = encrypted[vector.iv.len()..] == *vector.ciphertext;
+        report("host", vector.name, pass);
+        failures += usize::from(!pass);
+    }
+
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Infer)?;
+    let results = caller.run_self_test()?;
+    for (vector, &pass) in VECTORS.iter().zip(&results) {
+        report("TA", vector.name, pass);
+        failures += usize::from(!pass);
+    }
+    if results.len() < VECTORS.len() {
+        println!(
+            "TA only knows {} of {} vectors; update it",
+            results.len(),
+            VECTORS.len()
+        );
+    }
+
+    anyhow::ensure!(failures == 0, "{} self test(s) failed", failures);
+    println!("Self test passed");
+    Ok(())
+}
+
+fn report(side: &str, name: &str, pass: bool) {
+    println!("{} {}: {}", side, name, if pass { "ok" } else { "FAILED" });
+}
@@ -0,0 +1,64 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::SessionRole;
+use proto::key_manager::{self, MAX_RSA_PUBLIC_KEY_SIZE};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// DER RSA public key (SubjectPublicKeyInfo or PKCS#1, 2048 to 4096
+    /// bits), e.g. from `openssl rsa -pubout -outform DER`. Once set, the TA
+    /// only installs models signed with `encrypt-model --sign-key`; the key
+    /// can't be replaced.
+    public_key: PathBuf,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let der = std::fs::read(&args.public_key)?;
+    anyhow::ensure!(
+        der.len() <= MAX_RSA_PUBLIC_KEY_SIZE,
+        "{} is larger than a {}-bit RSA public key",
+        args.public_key.display(),
+        key_manager::MAX_RSA_BITS
+    );
+    let key = key_manager::parse_rsa_public_key(&der).ok_or_else(|| {
+        anyhow::anyhow!(
+            "{} is not a DER RSA public key of {} to {} bits",
+            args.public_key.display(),
+            key_manager::MIN_RSA_BITS,
+            key_manager::MAX_RSA_BITS
+        )
+    })?;
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    caller.set_model_verify_key(&der)?;
+    println!(
+        "Model verify key set: {}-bit RSA, sha256 {}",
+        key.bits(),
+        hex(&Sha256::digest(&der))
+    );
+    println!("The TA now only installs models signed with this key");
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,100 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::{Parser, Subcommand};
+use optee_teec::Context;
+use proto::inference::{SessionRole, ShadowReport};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: ShadowCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ShadowCommand {
+    /// Run the model in --shadow on every inference of --active as well,
+    /// without changing the responses, and count where they disagree
+    Enable {
+        /// Slot whose responses are returned
+        #[arg(long, default_value_t = 0)]
+        active: u32,
+        /// Slot holding the candidate model
+        #[arg(long)]
+        shadow: u32,
+    },
+    /// Stop running the shadow model
+    Disable,
+    /// Show how the shadow model compared so far
+    Report {
+        /// Start counting anew once read
+        #[arg(long)]
+        reset: bool,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Manage)?;
+    match &args.command {
+        ShadowCommand::Enable { active, shadow } => {
+            anyhow::ensure!(active != shadow, "a slot can't shadow itself");
+            caller.set_shadow(Some((*active, *shadow)))?;
+            println!("Slot {} now shadows slot {}", shadow, active);
+        }
+        ShadowCommand::Disable => {
+            caller.set_shadow(None)?;
+            println!("Shadowing disabled");
+        }
+        ShadowCommand::Report { reset, json } => {
+            let report = caller.shadow_report(*reset)?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_report(&report);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_report(report: &ShadowReport) {
+    match report.slots {
+        Some((active, shadow)) => println!("Slot {} shadows slot {}", shadow, active),
+        None => println!("Shadowing is disabled"),
+    }
+    println!(
+        "Compared:      {} images, {} disagreements ({:.2}%)",
+        report.compared,
+        report.disagreements,
+        report.disagreement_rate() * 100.0
+    );
+    println!("Skipped:       {} images", report.skipped);
+    println!("Failed:        {} images", report.failed);
+    if !report.examples.is_empty() {
+        println!("Disagreements (input SHA-256, active label, shadow label):");
+        for example in &report.examples {
+            println!(
+                "  {}  {} -> {}",
+                example.input_hash, example.active_label, example.shadow_label
+            );
+        }
+    }
+}
@@ -0,0 +1,101 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{DurationMicros, SessionRole, UsageCounters};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Model to load first; defaults to the model kept in secure storage
+    #[arg(short, long)]
+    model: Option<String>,
+    /// Slot to report on
+    #[arg(long, default_value_t = 0)]
+    slot: u32,
+    /// Report the lifetime counters kept across reboots
+    #[arg(long)]
+    persistent: bool,
+    /// Zero the counters, including the persisted ones
+    #[arg(long)]
+    reset: bool,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let role = if args.model.is_some() || args.reset {
+        SessionRole::Manage
+    } else {
+        SessionRole::Infer
+    };
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, role)?;
+    match &args.model {
+        Some(path) => super::infer::load_model(&mut caller, path, args.slot)?,
+        None => caller.load_stored_model(args.slot)?,
+    }
+    if args.reset {
+        caller.reset_model_usage(args.slot)?;
+        println!("Usage counters of slot {} reset", args.slot);
+        return Ok(());
+    }
+    let status = caller.model_status(args.slot)?;
+    let usage = caller.model_usage(args.slot)?;
+    if let Some(name) = &status.name {
+        println!("Model name: {}", name);
+    }
+    let (title, counters) = if args.persistent {
+        ("Lifetime", &usage.lifetime)
+    } else {
+        ("Since load", &usage.since_load)
+    };
+    println!("{} usage of slot {}:", title, args.slot);
+    print_counters(counters, |class| status.label_name(class));
+    if let Some(heap) = &usage.heap {
+        println!(
+            "TA heap: {} bytes in use, {} bytes peak, {} bytes total",
+            heap.current, heap.peak, heap.size
+        );
+    }
+    Ok(())
+}
+
+fn print_counters(counters: &UsageCounters, label_name: impl Fn(u8) -> String) {
+    println!("  invocations: {}", counters.invocations);
+    println!("  images: {}", counters.images);
+    if counters.cache_hits + counters.cache_misses != 0 {
+        println!(
+            "  result cache: {} hits, {} misses",
+            counters.cache_hits, counters.cache_misses
+        );
+    }
+    if counters.inference_time != DurationMicros::ZERO {
+        println!("  inference time: {}", counters.inference_time);
+        if let Some(per_image) = counters
+            .inference_time
+            .as_micros()
+            .checked_div(counters.images)
+        {
+            println!("  per image: {}", DurationMicros::from_micros(per_image));
+        }
+    }
+    if counters.last_used != 0 {
+        println!("  last used: {} (seconds since epoch)", counters.last_used);
+    }
+    for (class, count) in counters.class_histogram.iter().enumerate() {
+        println!("  {}: {}", label_name(class as u8), count);
+    }
+}
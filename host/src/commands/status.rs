@@ -0,0 +1,129 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use clap::Parser;
+use optee_teec::Context;
+use proto::inference::{self, BuildManifest, SessionRole};
+
+use crate::date;
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Slot to report on
+    #[arg(long, default_value_t = 0)]
+    slot: u32,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, SessionRole::Infer)?;
+    let status = caller.model_status(args.slot)?;
+    println!(
+        "Slot {}: {}",
+        args.slot,
+        if status.loaded { "loaded" } else { "empty" }
+    );
+    if let Some(name) = &status.name {
+        println!("  name: {}", name);
+    }
+    println!("  classes: {}", status.num_classes);
+    println!("  input normalization: {}", status.normalization);
+    if !status.class_labels.is_empty() {
+        println!("  class labels: {}", status.class_labels.join(", "));
+    }
+    if status.canaries > 0 {
+        println!("  canary images passed at install: {}", status.canaries);
+    }
+    if let Some(hint) = &status.batch_hint {
+        println!(
+            "  images per call: {} recommended, at most {}",
+            hint.recommended, hint.max
+        );
+    }
+    if let Some(license) = &status.license {
+        print!(
+            "  license: \"{}\" until {}",
+            license.license.licensee,
+            date::format_date(license.license.expires)
+        );
+        match license.remaining() {
+            0 => println!(" (expired)"),
+            left => println!(" ({} left)", date::format_duration(left)),
+        }
+    }
+    if let Some(stored) = &status.stored {
+        println!("Stored model:");
+        if let Some(name) = &stored.name {
+            println!("  name: {}", name);
+        }
+        println!("  architecture: {}", stored.arch);
+        println!("  model sha256: {}", stored.model_hash);
+        println!("  encrypted sha256: {}", stored.encrypted_hash);
+        println!("  plaintext size: {} bytes", stored.plaintext_size);
+        println!("  encrypted size: {} bytes", stored.encrypted_size);
+        if let Some(fingerprint) = &stored.key_fingerprint {
+            println!("  key fingerprint: {}", fingerprint);
+        }
+        if stored.provisioned != 0 {
+            println!(
+                "  provisioned: {} (seconds since epoch)",
+                stored.provisioned
+            );
+        }
+    }
+    if let Some(staged) = &status.staged {
+        println!("Staged model (not yet committed):");
+        println!("  model sha256: {}", staged.model_hash);
+        println!("  encrypted sha256: {}", staged.encrypted_hash);
+        println!("  encrypted size: {} bytes", staged.encrypted_size);
+        if staged.staged != 0 {
+            println!("  staged: {} (seconds since epoch)", staged.staged);
+        }
+    }
+    if caller.supports(inference::CAP_STORAGE_SCHEMA) {
+        let schema = caller.storage_schema()?;
+        print!("Storage schema: version {}", schema.version);
+        match (schema.migrated_from, schema.migrated_at) {
+            (Some(from), Some(at)) if at != 0 => {
+                println!(" (migrated from {} on {})", from, date::format_date(at))
+            }
+            (Some(from), _) => println!(" (migrated from {})", from),
+            _ => println!(),
+        }
+    }
+    if let Some(manifest) = caller.build_manifest()? {
+        println!("TA build:");
+        println!("  features: {}", features(&manifest));
+        println!("  heap: {} bytes", manifest.heap_size);
+        println!("  memory budget: {} bytes", manifest.model_memory_budget);
+        if manifest.storage_quota != 0 {
+            println!("  storage quota: {} bytes", manifest.storage_quota);
+        }
+        println!("  model history: {} versions", manifest.model_history);
+        println!("  largest chunk: {} bytes", manifest.max_push_chunk_size);
+    }
+    Ok(())
+}
+
+/// Enabled features of a TA build, comma-separated.
+pub fn features(manifest: &BuildManifest) -> String {
+    if manifest.features.is_empty() {
+        "none".to_string()
+    } else {
+        manifest.features.join(", ")
+    }
+}
@@ -0,0 +1,88 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::io::Write;
+
+use clap::{Parser, Subcommand};
+use optee_teec::Context;
+use proto::inference::{is_deletable_storage_id, SessionRole, DELETABLE_STORAGE_PREFIXES};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    #[command(subcommand)]
+    command: StorageCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum StorageCommand {
+    /// List the secure storage objects owned by the TA
+    List,
+    /// Delete a secure storage object (model pieces only)
+    Delete {
+        /// Id of the object, as printed by `storage list`
+        id: String,
+        /// Don't ask for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut ctx = Context::new()?;
+    let role = match args.command {
+        StorageCommand::List => SessionRole::Infer,
+        StorageCommand::Delete { .. } => SessionRole::Manage,
+    };
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, role)?;
+    match &args.command {
+        StorageCommand::List => {
+            let objects = caller.list_storage()?;
+            if objects.is_empty() {
+                println!("No storage objects");
+            }
+            for object in objects {
+                println!(
+                    "{:<32} {:>10} bytes  flags {:#010x}",
+                    object.id, object.data_size, object.flags
+                );
+            }
+        }
+        StorageCommand::Delete { id, yes } => {
+            anyhow::ensure!(
+                is_deletable_storage_id(id.as_bytes()),
+                "only objects under {} can be deleted",
+                DELETABLE_STORAGE_PREFIXES.join(", ")
+            );
+            let prompt = format!("Delete \"{}\"? The model in slot 0 will be unloaded", id);
+            if !yes && !confirm(&prompt)? {
+                println!("Aborted");
+                return Ok(());
+            }
+            caller.delete_storage_object(id)?;
+            println!("Deleted \"{}\"", id);
+        }
+    }
+    Ok(())
+}
+
+fn confirm(prompt: &str) -> anyhow::Result<bool> {
+    print!("{} [y/N] ", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -1,23 +1,83 @@
 use anyhow::Result;
 use clap::Args as ClapArgs;
 
+use crate::keystore::{self, SecretKey};
+use crate::transcript::{self, Redacted, Step};
+
 #[derive(ClapArgs, Debug)]
 pub struct Args {
     /// 32-byte AES key in hex (64 hex chars)
+    #[arg(long, required_unless_present = "key_from_keystore")]
+    key: Option<String>,
+    /// Fetch the key stored under this name with `keystore put` from the OS
+    /// secret service instead (needs the keystore feature)
+    #[arg(long, conflicts_with = "key")]
+    key_from_keystore: Option<String>,
+    /// Only check the key and, with --model, that the simulated TA can
+    /// decrypt and load the model with it; nothing is stored
+    #[arg(long)]
+    dry_run: bool,
+    /// Replace a different key already stored in the TA. Models encrypted
+    /// with the old key no longer load
     #[arg(long)]
-    key: String,
+    force: bool,
+    /// Encrypted model (.json) to provision into the simulated TA
+    #[arg(long, requires = "dry_run")]
+    model: Option<String>,
 }
 
 pub fn execute(args: &Args) -> Result<()> {
-    let key = parse_hex_key_32(&args.key)?;
+    let key = match &args.key_from_keystore {
+        Some(name) => keystore::fetch_key(name)?,
+        None => SecretKey::new(parse_hex_key_32(args.key.as_deref().unwrap_or_default())?),
+    };
+    if args.dry_run {
+        return dry_run(key.bytes(), args.model.as_deref());
+    }
     let mut ctx = optee_teec::Context::new()?;
     let mut provisioner = crate::tee::KeyProvisionTaConnector::new(&mut ctx)?;
-    provisioner.store_key(&key)?;
+    transcript::record(Step::Key {
+        key: Redacted::new(&key.bytes()[..]),
+    });
+    match provisioner.store_key(key.bytes(), args.force) {
+        Err(err) if matches!(err.kind(), optee_teec::ErrorKind::AccessConflict) => {
+            anyhow::bail!("a different key is already stored in the TA; pass --force to replace it")
+        }
+        Err(err) if matches!(err.kind(), optee_teec::ErrorKind::NotSupported) => {
+            anyhow::bail!("pass --force to store the key in this TA anyway")
+        }
+        result => result?,
+    }
     println!("Secret key stored in TA secure storage.");
     Ok(())
 }
 
-fn parse_hex_key_32(hex_str: &str) -> Result<[u8; 32]> {
+#[cfg(feature = "encrypt-model")]
+fn dry_run(key: &[u8; 32], model: Option<&str>) -> Result<()> {
+    use crate::tee::{InferenceTa, DRY_RUN_BANNER};
+
+    println!("{}", DRY_RUN_BANNER);
+    println!("Key is valid, not sent to the key manager");
+    if let Some(path) = model {
+        let mut ta = crate::sim::SimulatedTa::new(*key);
+        super::infer::load_model(&mut ta, path, 0)?;
+        let status = ta.model_status(0)?;
+        println!(
+            "Model provisioned: {} ({} classes)",
+            status.name.as_deref().unwrap_or("unnamed"),
+            status.num_classes
+        );
+    }
+    println!("{}", DRY_RUN_BANNER);
+    Ok(())
+}
+
+#[cfg(not(feature = "encrypt-model"))]
+fn dry_run(_key: &[u8; 32], _model: Option<&str>) -> Result<()> {
+    anyhow::bail!("--dry-run needs a host built with the encrypt-model feature")
+}
+
+pub fn parse_hex_key_32(hex_str: &str) -> Result<[u8; 32]> {
     let s = hex_str.trim();
     if s.len() != 64 {
         anyhow::bail!("Key must be 64 hex chars (32 bytes)");
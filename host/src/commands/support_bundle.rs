@@ -0,0 +1,197 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use clap::Parser;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use optee_teec::Context;
+use proto::inference::{self, SessionRole, MODEL_SLOTS};
+
+use crate::tee::InferenceTaConnector;
+
+// Environment variables the host reads; none of them carry secrets
+const HOST_ENV_VARS: &[&str] = &["ENC_MNIST_TA_UUID", "ENC_MNIST_CACHE_DIR"];
+const TEE_DEVICES: &[&str] = &["/dev/tee0", "/dev/teepriv0"];
+const BLOCK_SIZE: usize = 512;
+
+/// Collects the transcript, TA status and host details into a tar.gz to
+/// attach to a bug report
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Archive to write
+    #[arg(short, long, default_value = "support-bundle.tar.gz")]
+    output: PathBuf,
+    /// Further transcripts to include, besides the one given with
+    /// --transcript
+    #[arg(long = "include")]
+    include: Vec<PathBuf>,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut files = vec![
+        ("host.txt".to_string(), host_report().into_bytes()),
+        ("ta.txt".to_string(), ta_report().into_bytes()),
+    ];
+    for path in crate::transcript::path()
+        .iter()
+        .copied()
+        .chain(args.include.iter().map(PathBuf::as_path))
+    {
+        let data = std::fs::read(path)
+            .map_err(|err| anyhow::anyhow!("cannot read {}: {}", path.display(), err))?;
+        files.push((format!("transcripts/{}", file_name(path)), data));
+    }
+
+    let mtime = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut archive = GzEncoder::new(File::create(&args.output)?, Compression::default());
+    for (name, data) in &files {
+        append(&mut archive, name, data, mtime)?;
+        println!("Added {} ({} bytes)", name, data.len());
+    }
+    archive.write_all(&[0; 2 * BLOCK_SIZE])?;
+    archive.finish()?;
+    println!("Support bundle written to {}", args.output.display());
+    Ok(())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "transcript.jsonl".to_string())
+}
+
+fn host_report() -> String {
+    let mut report = String::new();
+    let _ = writeln!(report, "host version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "protocol version: {}", inference::PROTOCOL_VERSION);
+    let _ = writeln!(
+        report,
+        "os: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    if let Ok(release) = std::fs::read_to_string("/proc/sys/kernel/osrelease") {
+        let _ = writeln!(report, "kernel: {}", release.trim());
+    }
+    let _ = writeln!(report, "ta uuid: {}", crate::tee::configured_ta_uuid());
+    for device in TEE_DEVICES {
+        let present = Path::new(device).exists();
+        let _ = writeln!(
+            report,
+            "{}: {}",
+            device,
+            if present { "present" } else { "missing" }
+        );
+    }
+    for var in HOST_ENV_VARS {
+        if let Ok(value) = std::env::var(var) {
+            let _ = writeln!(report, "{}={}", var, value);
+        }
+    }
+    report
+}
+
+// What `status` and `storage list` would print, or why the TA couldn't be
+// reached
+fn ta_report() -> String {
+    let mut report = String::new();
+    let mut ctx = match Context::new() {
+        Ok(ctx) => ctx,
+        Err(err) => return format!("cannot open TEE context: {}\n", err),
+    };
+    let mut caller = match InferenceTaConnector::new(&mut ctx, SessionRole::Infer) {
+        Ok(caller) => caller,
+        Err(err) => return format!("cannot open TA session: {}\n", err),
+    };
+    let _ = writeln!(report, "protocol version: {}", caller.protocol_version());
+    let _ = writeln!(report, "capabilities: {:#x}", caller.capabilities());
+    match caller.build_manifest() {
+        Ok(Some(manifest)) => {
+            let _ = writeln!(report, "build manifest: {:#?}", manifest);
+        }
+        Ok(None) => {
+            let _ = writeln!(report, "build manifest: not reported");
+        }
+        Err(err) => {
+            let _ = writeln!(report, "build manifest: {}", err);
+        }
+    }
+    let slots = if caller.supports(inference::CAP_SLOTS) {
+        MODEL_SLOTS as u32
+    } else {
+        1
+    };
+    for slot in 0..slots {
+        match caller.model_status(slot) {
+            Ok(status) => {
+                let _ = writeln!(report, "slot {}: {:#?}", slot, status);
+            }
+            Err(err) => {
+                let _ = writeln!(report, "slot {}: {}", slot, err);
+            }
+        }
+    }
+    if caller.supports(inference::CAP_STORAGE) {
+        match caller.list_storage() {
+            Ok(objects) => {
+                for object in objects {
+                    let _ = writeln!(
+                        report,
+                        "storage object {}: {} bytes, flags {:#x}",
+                        object.id, object.data_size, object.flags
+                    );
+                }
+            }
+            Err(err) => {
+                let _ = writeln!(report, "storage list: {}", err);
+            }
+        }
+    }
+    report
+}
+
+// Writes one regular file entry of a ustar archive
+fn append(archive: &mut impl Write, name: &str, data: &[u8], mtime: u64) -> anyhow::Result<()> {
+    anyhow::ensure!(name.len() < 100, "archive member name too long: {}", name);
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(b"0000644\0");
+    header[108..116].copy_from_slice(b"0000000\0");
+    header[116..124].copy_from_slice(b"0000000\0");
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(format!("{:011o}\0", mtime).as_bytes());
+    header[148..156].fill(b' ');
+    header[156] = b'0';
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    archive.write_all(&header)?;
+    archive.write_all(data)?;
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    archive.write_all(&[0; BLOCK_SIZE][..padding])?;
+    Ok(())
+}
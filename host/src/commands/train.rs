@@ -0,0 +1,413 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use burn::{
+    backend::{Autodiff, NdArray},
+    nn::loss::CrossEntropyLossConfig,
+    optim::{AdamConfig, GradientsParams, Optimizer},
+    prelude::*,
+    tensor::backend::AutodiffBackend,
+};
+use clap::Parser;
+use common::{Model, ModelMetadata, RecordFormat};
+use proto::inference::Normalization;
+use proto::Image;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::backend::BackendKind;
+use crate::training::{Augmentation, LrSchedule};
+
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Directory holding the MNIST training set (train-images-idx3-ubyte, train-labels-idx1-ubyte)
+    #[arg(short, long)]
+    data: String,
+    /// Output path of the plaintext Burn record (pass it to encrypt-model)
+    #[arg(short, long)]
+    output: String,
+    /// Also train on the images listed in this errors.csv of `evaluate
+    /// --dump-errors`, with their true labels; can be repeated
+    #[arg(long)]
+    extra: Vec<std::path::PathBuf>,
+    #[arg(long, default_value_t = 3)]
+    epochs: usize,
+    #[arg(long, default_value_t = 64)]
+    batch_size: usize,
+    #[arg(long, default_value_t = 1e-3)]
+    learning_rate: f64,
+    /// Seed for parameter initialization and shuffling; a random one is printed when omitted
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Width of the output layer; must exceed every label in the dataset
+    #[arg(long, default_value_t = proto::DEFAULT_NUM_CLASSES)]
+    num_classes: usize,
+    /// Model name stored in the metadata block
+    #[arg(long, default_value = "mnist-mlp")]
+    name: String,
+    /// Recorder used for the exported record: bin or mpk (named MessagePack)
+    #[arg(long, default_value = "bin", value_parser = super::encrypt::parse_record_format)]
+    record_format: RecordFormat,
+    /// Input normalization used for training and recorded in the metadata:
+    /// mnist, unit ([0, 1] scaling) or mean,std[,scale]
+    #[arg(long, default_value = "mnist", value_parser = super::encrypt::parse_normalization)]
+    normalization: Normalization,
+    /// Backend used for training; the exported record loads on any backend
+    #[arg(long, value_enum, default_value_t = BackendKind::Ndarray)]
+    backend: BackendKind,
+    /// Label smoothing epsilon of the cross-entropy loss; 0 disables it
+    #[arg(long, default_value_t = 0.1)]
+    label_smoothing: f32,
+    /// Largest random shift of training images, in pixels per direction
+    #[arg(long, default_value_t = 2)]
+    max_shift: usize,
+    /// Largest random rotation of training images, in degrees either way
+    #[arg(long, default_value_t = 10.0)]
+    max_rotation: f32,
+    /// Learning rate schedule
+    #[arg(long, value_enum, default_value_t = LrSchedule::Cosine)]
+    lr_schedule: LrSchedule,
+    /// Epochs between halvings of the learning rate with `--lr-schedule step`
+    #[arg(long, default_value_t = 1)]
+    lr_step_epochs: usize,
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    anyhow::ensure!(args.batch_size > 0, "batch size must be positive");
+    anyhow::ensure!(
+        (0.0..1.0).contains(&args.label_smoothing),
+        "label smoothing must be in [0, 1)"
+    );
+    anyhow::ensure!(
+        args.max_shift < proto::IMAGE_WIDTH / 2,
+        "max shift must be below {}",
+        proto::IMAGE_WIDTH / 2
+    );
+    anyhow::ensure!(
+        (0.0..=180.0).contains(&args.max_rotation),
+        "max rotation must be between 0 and 180 degrees"
+    );
+    anyhow::ensure!(
+        args.lr_step_epochs > 0,
+        "learning rate step must be at least one epoch"
+    );
+    let seed = args.seed.unwrap_or_else(|| rand::rng().random());
+    println!("Training seed: {}", seed);
+
+    let dataset = rust_mnist::Mnist::new(&super::evaluate::mnist_data_path(&args.data));
+    let mut images: Vec<Image> = dataset.train_data.iter().map(Image::from_luma28).collect();
+    let mut labels = dataset.train_labels;
+    anyhow::ensure!(
+        !images.is_empty(),
+        "no training images found in {}",
+        args.data
+    );
+    println!("Loaded {} training images", images.len());
+    for path in &args.extra {
+        let samples = super::evaluate::read_error_manifest(path)?;
+        println!("Added {} images from {}", samples.len(), path.display());
+        for (image, label) in samples {
+            images.push(image);
+            labels.push(label);
+        }
+    }
+    anyhow::ensure!(
+        (1..=proto::MAX_NUM_CLASSES).contains(&args.num_classes)
+            && labels.iter().all(|&l| (l as usize) < args.num_classes),
+        "labels don't fit into {} classes",
+        args.num_classes
+    );
+
+    let record = match args.backend {
+        BackendKind::Ndarray => {
+            train::<Autodiff<NdArray>>(args, seed, &Default::default(), &images, &labels)?
+        }
+        #[cfg(feature = "wgpu")]
+        BackendKind::Wgpu => train::<Autodiff<burn::backend::Wgpu>>(
+            args,
+            seed,
+            &Default::default(),
+            &images,
+            &labels,
+        )?,
+    };
+
+    let mut metadata = ModelMetadata {
+        name: args.name.clone(),
+        dataset: Some("MNIST".to_string()),
+        num_classes: Some(args.num_classes),
+        normalization: Some(args.normalization),
+        ..Default::default()
+    };
+    metadata.extra.insert("seed".to_string(), seed.to_string());
+    metadata
+        .extra
+        .insert("epochs".to_string(), args.epochs.to_string());
+    metadata
+        .extra
+        .insert("batch_size".to_string(), args.batch_size.to_string());
+    metadata
+        .extra
+        .insert("learning_rate".to_string(), args.learning_rate.to_string());
+    metadata.extra.insert(
+        "lr_schedule".to_string(),
+        args.lr_schedule.name().to_string(),
+    );
+    if args.lr_schedule == LrSchedule::Step {
+        metadata.extra.insert(
+            "lr_step_epochs".to_string(),
+            args.lr_step_epochs.to_string(),
+        );
+    }
+    metadata.extra.insert(
+        "label_smoothing".to_string(),
+        args.label_smoothing.to_string(),
+    );
+    metadata
+        .extra
+        .insert("max_shift".to_string(), args.max_shift.to_string());
+    metadata
+        .extra
+        .insert("max_rotation".to_string(), args.max_rotation.to_string());
+
+    let container = common::encode_container(&metadata, args.record_format, &record)?;
+    std::fs::write(&args.output, container)?;
+    println!("Model record saved to: {}", args.output);
+    Ok(())
+}
+
+/// Runs the training loop on backend `B` and returns the exported record.
+fn train<B: AutodiffBackend>(
+    args: &Args,
+    seed: u64,
+    device: &B::Device,
+    images: &[Image],
+    labels: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let mut model = Model::<B>::new_with_seed(device, seed, args.num_classes);
+    let mut optim = AdamConfig::new().init();
+    let smoothing = (args.label_smoothing > 0.0).then_some(args.label_smoothing);
+    let loss_fn = CrossEntropyLossConfig::new()
+        .with_smoothing(smoothing)
+        .init(device);
+    let augmentation = Augmentation {
+        max_shift: args.max_shift,
+        max_rotation: args.max_rotation,
+    };
+    let mut rng = StdRng::seed_from_u64(seed);
+    // Separate stream so the shuffle order doesn't depend on augmentation
+    let mut augment_rng = StdRng::seed_from_u64(seed.wrapping_add(1));
+    let mut order: Vec<usize> = (0..images.len()).collect();
+    let total_steps = args.epochs * images.len().div_ceil(args.batch_size);
+    let mut step = 0;
+
+    let total = (images.len() * args.epochs) as u64;
+    let mut progress = crate::progress::start("Training", "images", Some(total));
+    for epoch in 1..=args.epochs {
+        order.shuffle(&mut rng);
+        let mut total_loss = 0.0;
+        let mut correct = 0;
+        let mut batches = 0;
+        for indices in order.chunks(args.batch_size) {
+            let batch_images: Vec<Image> = indices.iter().map(|&i| images[i]).collect();
+            let batch_labels: Vec<u8> = indices.iter().map(|&i| labels[i]).collect();
+            let input = augmentation.apply(
+                Model::<B>::images_to_tensors(device, &batch_images, &args.normalization),
+                &mut augment_rng,
+            );
+            let targets = Model::<B>::labels_to_tensors(device, &batch_labels);
+
+            let output = model.forward(input);
+            let loss = loss_fn.forward(output.clone(), targets.clone());
+            correct += output
+                .argmax(1)
+                .squeeze::<1>(1)
+                .equal(targets)
+                .int()
+                .sum()
+                .into_scalar()
+                .elem::<i64>() as usize;
+            total_loss += loss.clone().into_scalar().elem::<f64>();
+            batches += 1;
+            progress.tick(indices.len() as u64);
+
+            let grads = GradientsParams::from_grads(loss.backward(), &model);
+            let rate = args.lr_schedule.rate(
+                args.learning_rate,
+                epoch - 1,
+                args.lr_step_epochs,
+                step,
+                total_steps,
+            );
+            model = optim.step(rate, model, grads);
+            step += 1;
+        }
+        progress.message(&format!(
+            "Epoch {}/{}: loss {:.4}, accuracy {:.2}%",
+            epoch,
+            args.epochs,
+            total_loss / batches as f64,
+            correct as f64 * 100.0 / images.len() as f64
+        ));
+    }
+    progress.finish();
+
+    Ok(model.export_as(args.record_format)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds a seeded model on `B` and checks that its record imports into
+    // the TA's NdArray model with the same weights. Outputs can't be
+    // compared directly: autodiff backends run the dropout layers.
+    fn round_trips_to_ndarray<B: Backend>(device: &B::Device) {
+        let model = Model::<B>::new_with_seed(device, 11, 10);
+        let imported = Model::<NdArray>::import(&Default::default(), model.export().unwrap());
+        assert!(imported.unwrap().parameters() == model.parameters());
+    }
+
+    // Four classes of bands across the image, with some speckle
+    fn bands() -> (Vec<Image>, Vec<u8>) {
+        let labels: Vec<u8> = (0..32).map(|i| i % 4).collect();
+        let images = labels
+            .iter()
+            .enumerate()
+            .map(|(i, &label)| {
+                let mut pixels = [0; proto::IMAGE_SIZE];
+                for (j, pixel) in pixels.iter_mut().enumerate() {
+                    let row = j / proto::IMAGE_WIDTH;
+                    if row / 7 == label as usize || (i * 13 + j) % 29 == 0 {
+                        *pixel = 255;
+                    }
+                }
+                Image::from_luma28(&pixels)
+            })
+            .collect();
+        (images, labels)
+    }
+
+    #[test]
+    fn training_reduces_the_loss() {
+        let args =
+            Args::parse_from("train --data - --output - --epochs 3 --batch-size 8".split(' '));
+        let (images, labels) = bands();
+        let device = Default::default();
+        let loss = |model: Model<NdArray>| {
+            let input = Model::images_to_tensors(&device, &images, &args.normalization);
+            let targets = Model::<NdArray>::labels_to_tensors(&device, &labels);
+            CrossEntropyLossConfig::new()
+                .init(&device)
+                .forward(model.forward(input), targets)
+                .into_scalar()
+        };
+
+        let before = loss(Model::new_with_seed(&device, 5, 10));
+        let record = train::<Autodiff<NdArray>>(&args, 5, &device, &images, &labels).unwrap();
+        let after = loss(Model::import(&device, record).unwrap());
+        assert!(
+            after < before / 2.0,
+            "loss went from {} to {}",
+            before,
+            after
+        );
+    }
+
+    // What CI runs without a TEE: a trained model through encrypt-model,
+    // `store-key --dry-run` and `infer --dry-run`, as on the command line
+    #[test]
+    fn trained_models_run_through_the_dry_run_commands() {
+        let args =
+            Args::parse_from("train --data - --output - --epochs 3 --batch-size 8".split(' '));
+        let (images, labels) = bands();
+        let device = Default::default();
+        let record = train::<Autodiff<NdArray>>(&args, 5, &device, &images, &labels).unwrap();
+        let model = Model::<NdArray>::import(&device, record.clone()).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("dry-run-cycle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let (plain, encrypted, results) =
+            (path("model.bin"), path("model.json"), path("results.json"));
+        std::fs::write(&plain, &record).unwrap();
+        // One image of every class
+        let inputs: Vec<String> = (0..4)
+            .map(|i| {
+                let input = path(&format!("{}.bin", i));
+                std::fs::write(&input, images[i].as_bytes()).unwrap();
+                input
+            })
+            .collect();
+        let key = "5a".repeat(32);
+        let run = |command: &[&str]| {
+            let args = ["enc_mnist-rs"].iter().chain(command);
+            crate::run(crate::Cli::try_parse_from(args).unwrap().command)
+        };
+
+        let result = run(&[
+            "encrypt-model",
+            "--input",
+            &plain,
+            "--output",
+            &encrypted,
+            "--key",
+            &key,
+        ])
+        .and_then(|()| {
+            run(&[
+                "store-key",
+                "--key",
+                &key,
+                "--dry-run",
+                "--model",
+                &encrypted,
+            ])
+        })
+        .and_then(|()| {
+            let mut command = vec!["infer", "--dry-run", "--key", &key];
+            command.extend(["--model", &encrypted, "--results", &results]);
+            for input in &inputs {
+                command.extend(["--binary", input]);
+            }
+            run(&command)
+        });
+        let results = std::fs::read(&results);
+        let _ = std::fs::remove_dir_all(&dir);
+        result.unwrap();
+
+        let results: Vec<serde_json::Value> = serde_json::from_slice(&results.unwrap()).unwrap();
+        let classes: Vec<u8> = results
+            .iter()
+            .map(|result| result["class"].as_u64().unwrap() as u8)
+            .collect();
+        let input = Model::images_to_tensors(&device, &images[..4], &args.normalization);
+        assert_eq!(Some(classes.clone()), model.predict_labels(input));
+        assert_eq!(classes, labels[..4]);
+    }
+
+    #[test]
+    fn training_backend_records_load_in_the_ta() {
+        round_trips_to_ndarray::<Autodiff<NdArray>>(&Default::default());
+    }
+
+    #[cfg(feature = "wgpu")]
+    #[test]
+    #[ignore = "needs a GPU adapter"]
+    fn wgpu_records_load_in_the_ta() {
+        round_trips_to_ndarray::<Autodiff<burn::backend::Wgpu>>(&Default::default());
+    }
+}
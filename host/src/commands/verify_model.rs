@@ -1,20 +1,126 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Args as ClapArgs;
+use proto::Image;
+
+use crate::backend::BackendKind;
 
 #[derive(ClapArgs, Debug)]
 pub struct Args {
     /// Path to plaintext Burn record (.bin) to verify with burn 0.17 loader
-    #[arg(long)]
-    input: String,
+    #[arg(long, required_unless_present = "encrypted")]
+    input: Option<String>,
+    /// Verify this encrypted model (.json) decrypted on the host instead
+    /// (needs the dev-tools feature)
+    #[arg(long, conflicts_with = "input", requires = "key")]
+    encrypted: Option<String>,
+    /// 32-byte AES key in hex (64 hex chars) decrypting --encrypted
+    #[arg(long, requires = "encrypted")]
+    key: Option<String>,
+    /// Batch size assumed for the memory estimate
+    #[arg(long, default_value_t = 64)]
+    batch_size: usize,
+    /// Backend to cross-check predictions against NdArray (the TA backend)
+    #[arg(long, value_enum, default_value_t = BackendKind::Ndarray)]
+    backend: BackendKind,
 }
 
 pub fn execute(args: &Args) -> Result<()> {
     use burn::{backend::NdArray, prelude::*};
     let device: <NdArray as Backend>::Device = Default::default();
-    let bytes = std::fs::read(&args.input)?;
-    println!("Verifying Burn record with burn 0.17 loader: {} bytes", bytes.len());
-    let _model = common::Model::<NdArray>::import(&device, bytes)?;
+    let bytes = match &args.encrypted {
+        Some(path) => {
+            super::encrypt::decrypt_on_host(path, args.key.as_deref().unwrap_or_default())?
+        }
+        None => std::fs::read(args.input.as_ref().unwrap())?,
+    };
+    let (metadata, format, record) = common::split_container(bytes)?;
+    match &metadata {
+        Some(metadata) => {
+            println!("Model metadata: {}", metadata.name);
+            if let Some(dataset) = &metadata.dataset {
+                println!("  dataset: {}", dataset);
+            }
+            if !metadata.class_labels.is_empty() {
+                println!("  class labels: {}", metadata.class_labels.join(", "));
+            }
+        }
+        None => println!("No model metadata block"),
+    }
+    println!(
+        "Verifying {:?} Burn record with burn 0.17 loader: {} bytes",
+        format,
+        record.len()
+    );
+    let model = common::Model::<NdArray>::import_as(&device, record.clone(), format)?;
     println!("Model record is compatible with burn 0.17 (TA loader)");
+    println!("Classes: {}", model.num_classes());
+    if let Some(declared) = metadata.as_ref().and_then(|m| m.num_classes) {
+        anyhow::ensure!(
+            declared == model.num_classes(),
+            "metadata declares {} classes but the model outputs {}",
+            declared,
+            model.num_classes()
+        );
+    }
+    println!("Parameters: {}", model.num_params());
+    println!(
+        "Estimated memory (batch {}): {} bytes",
+        args.batch_size,
+        model.memory_estimate(args.batch_size)
+    );
+
+    let images = probe_images(args.batch_size.max(1));
+    let normalization = metadata
+        .as_ref()
+        .map(common::ModelMetadata::normalization)
+        .unwrap_or_default();
+    let input = common::Model::<NdArray>::images_to_tensors(&device, &images, &normalization);
+    let expected = model
+        .predict_labels(input)
+        .context("predicted class index doesn't fit in a byte")?;
+    let labels = match args.backend {
+        BackendKind::Ndarray => expected.clone(),
+        #[cfg(feature = "wgpu")]
+        BackendKind::Wgpu => predict_on::<burn::backend::Wgpu>(
+            &Default::default(),
+            record,
+            format,
+            &images,
+            &normalization,
+        )?,
+    };
+    anyhow::ensure!(
+        labels == expected,
+        "{:?} predictions differ from NdArray",
+        args.backend
+    );
+    println!(
+        "Forward pass on {:?} matches NdArray ({} images)",
+        args.backend,
+        images.len()
+    );
     Ok(())
 }
 
+/// Imports the record on backend `B` and predicts labels for `images`.
+#[cfg(feature = "wgpu")]
+fn predict_on<B: burn::prelude::Backend>(
+    device: &B::Device,
+    record: Vec<u8>,
+    format: common::RecordFormat,
+    images: &[Image],
+    normalization: &proto::inference::Normalization,
+) -> Result<Vec<u8>> {
+    let model = common::Model::<B>::import_as(device, record, format)?;
+    let input = common::Model::<B>::images_to_tensors(device, images, normalization);
+    model
+        .predict_labels(input)
+        .context("predicted class index doesn't fit in a byte")
+}
+
+/// Deterministic, non-trivial inputs for comparing backends.
+fn probe_images(count: usize) -> Vec<Image> {
+    (0..count)
+        .map(|i| Image::from_luma28(&core::array::from_fn(|j| ((i * 31 + j * 7) % 256) as u8)))
+        .collect()
+}
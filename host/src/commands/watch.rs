@@ -0,0 +1,421 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use optee_teec::Context;
+use proto::inference::SessionRole;
+use proto::Image;
+
+use crate::commands::infer;
+use crate::input::{self, Alignment};
+use crate::registry;
+use crate::source::{self, Batches, DecodedImage, ImageSource};
+use crate::spool::{FileResult, Spool};
+use crate::tee::InferenceTa;
+
+/// Classifies image files as they are dropped into a spool directory,
+/// appending one JSON line per file to the output and moving the file to the
+/// done directory; runs until interrupted
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// Directory new images arrive in; files are taken once closed after
+    /// writing or renamed into it, and hidden ones are left alone
+    #[arg(long)]
+    dir: PathBuf,
+    /// Directory processed files are moved to, on the same file system
+    #[arg(long)]
+    done_dir: PathBuf,
+    /// JSONL file the results are appended to
+    #[arg(short, long)]
+    output: PathBuf,
+    /// Encrypted model to load first; without it the model kept in the TA's
+    /// secure storage is used
+    #[arg(long)]
+    model: Option<String>,
+    /// Registered model (see `model add`) to load instead of --model
+    #[arg(long, conflicts_with = "model")]
+    model_name: Option<String>,
+    /// Most images sent to the TA per call; defaults to what the TA
+    /// recommends for the loaded model
+    #[arg(long)]
+    batch_size: Option<usize>,
+    /// Milliseconds a file waits for others to fill its batch
+    #[arg(long, default_value_t = 500)]
+    max_wait_ms: u64,
+    /// Scale each digit to fit 20x20 and center it by mass
+    #[arg(long)]
+    center: bool,
+    /// Straighten slanted digits using their image moments
+    #[arg(long)]
+    deskew: bool,
+}
+
+// Files taken from the spool and not yet committed, in arrival order
+#[derive(Default)]
+struct Queue {
+    files: Vec<PathBuf>,
+    // When the oldest of them arrived
+    since: Option<Instant>,
+}
+
+impl Queue {
+    fn push(&mut self, path: PathBuf) {
+        if !self.files.contains(&path) {
+            self.files.push(path);
+            self.since.get_or_insert_with(Instant::now);
+        }
+    }
+
+    // How long to wait for more files before the queue is due
+    fn timeout(&self, max_wait: Duration) -> Option<Duration> {
+        self.since
+            .map(|since| max_wait.saturating_sub(since.elapsed()))
+    }
+
+    fn due(&self, batch_size: usize, max_wait: Duration) -> bool {
+        self.files.len() >= batch_size || self.timeout(max_wait) == Some(Duration::ZERO)
+    }
+
+    fn take(&mut self, batch_size: usize) -> Vec<PathBuf> {
+        let rest = self.files.split_off(batch_size.min(self.files.len()));
+        let batch = std::mem::replace(&mut self.files, rest);
+        // What is left has waited as long, and is due with the next check
+        if self.files.is_empty() {
+            self.since = None;
+        }
+        batch
+    }
+}
+
+pub fn execute(args: &Args) -> anyhow::Result<()> {
+    let mut spool = Spool::open(&args.dir, &args.done_dir, &args.output)?;
+    let model = match &args.model_name {
+        Some(name) => Some(registry::resolve(name)?.path.display().to_string()),
+        None => args.model.clone(),
+    };
+
+    let mut ctx = Context::new()?;
+    // Only loading a model needs a manage session
+    let role = if model.is_some() {
+        SessionRole::Manage
+    } else {
+        SessionRole::Infer
+    };
+    let mut caller = crate::tee::InferenceTaConnector::new(&mut ctx, role)?;
+    if let Some(path) = &model {
+        infer::load_model(&mut caller, path, 0)?;
+    }
+    let status = caller.model_status(0)?;
+    let batching = Batching {
+        size: infer::batch_size(args.batch_size, status.batch_hint.as_ref())?,
+        max_wait: Duration::from_millis(args.max_wait_ms),
+        alignment: Alignment {
+            center: args.center,
+            deskew: args.deskew,
+        },
+    };
+
+    // Watching starts before the listing, so no file falls between the two
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(spool.dir(), RecursiveMode::NonRecursive)?;
+    let mut queue = Queue::default();
+    for path in spool.waiting()? {
+        queue.push(path);
+    }
+    println!(
+        "Watching {} ({} file(s) waiting), batches of up to {}",
+        spool.dir().display(),
+        queue.files.len(),
+        batching.size
+    );
+    loop {
+        let event = match queue.timeout(batching.max_wait) {
+            Some(timeout) => events.recv_timeout(timeout),
+            None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(Ok(event)) if is_finished_file(&event.kind) => {
+                for path in event.paths {
+                    if spool.is_candidate(&path) {
+                        queue.push(path);
+                    }
+                }
+            }
+            Ok(Ok(_)) | Err(RecvTimeoutError::Timeout) => {}
+            Ok(Err(err)) => println!("warning: watching {}: {}", spool.dir().display(), err),
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("the file watcher stopped"),
+        }
+        process_due(&mut queue, &batching, &mut spool, &mut caller)?;
+    }
+}
+
+// How files are batched and preprocessed
+struct Batching {
+    size: usize,
+    max_wait: Duration,
+    alignment: Alignment,
+}
+
+// Classifies and commits the batches of `queue` that are due
+fn process_due(
+    queue: &mut Queue,
+    batching: &Batching,
+    spool: &mut Spool,
+    caller: &mut dyn InferenceTa,
+) -> anyhow::Result<()> {
+    while queue.due(batching.size, batching.max_wait) {
+        let files = queue.take(batching.size);
+        let results = classify(caller, &files, batching.alignment)?;
+        let count = results.len();
+        spool.commit(results)?;
+        if count > 0 {
+            println!("Processed {} file(s)", count);
+        }
+    }
+    Ok(())
+}
+
+// Closed after writing, or renamed into the spool
+fn is_finished_file(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+    )
+}
+
+// Results of the `files` still in the spool; those that don't decode get an
+// error instead of a class, so they aren't picked up again
+fn classify(
+    caller: &mut dyn InferenceTa,
+    files: &[PathBuf],
+    alignment: Alignment,
+) -> anyhow::Result<Vec<FileResult>> {
+    let mut results = Vec::with_capacity(files.len());
+    let mut names = Vec::new();
+    let mut sources: Vec<Box<dyn ImageSource>> = Vec::new();
+    for path in files {
+        // Already committed, e.g. listed and then reported by the watcher
+        if !path.is_file() {
+            continue;
+        }
+        let name = file_name(path);
+        match DecodedImage::open(&path.display().to_string(), input::Format::Auto, None) {
+            Ok(image) => {
+                names.push(name);
+                sources.push(Box::new(image));
+            }
+            Err(err) => {
+                println!("warning: {}: {:#}", name, err);
+                results.push(FileResult::failed(&name, &err));
+            }
+        }
+    }
+    if sources.is_empty() {
+        return Ok(results);
+    }
+    source::print_warnings(&sources);
+    let mut images: Vec<Image> = Vec::with_capacity(sources.len());
+    let mut batches = Batches::new(&sources, alignment);
+    while let Some(batch) = batches.next_batch(sources.len())? {
+        images.extend(batch.images);
+    }
+    let predictions = caller.infer_predictions(&images, 1.0, 0)?;
+    anyhow::ensure!(
+        predictions.len() == images.len(),
+        "the TA answered {} of {} images",
+        predictions.len(),
+        images.len()
+    );
+    let labels = caller.class_labels(0)?;
+    for (name, prediction) in names.iter().zip(&predictions) {
+        results.push(FileResult::classified(
+            name,
+            prediction.label,
+            infer::label_name(&labels, prediction.label),
+            prediction.confidence(),
+        ));
+    }
+    Ok(results)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::encrypt::encrypt_model;
+    use crate::sim::SimulatedTa;
+    use burn::backend::NdArray;
+    use proto::IMAGE_SIZE;
+
+    const KEY: [u8; 32] = [0x5a; 32];
+
+    // A simulated TA with a fresh model in slot 0
+    fn simulated_ta(test: &str) -> SimulatedTa {
+        let mut ta = SimulatedTa::new(KEY);
+        let dir = std::env::temp_dir();
+        let record = dir.join(format!("watch-{}-{}.bin", test, std::process::id()));
+        let encrypted = record.with_extension("json");
+        let model = common::Model::<NdArray>::new(&Default::default());
+        std::fs::write(&record, model.export().unwrap()).unwrap();
+        encrypt_model(&record, &encrypted, &KEY, &Default::default()).unwrap();
+        infer::load_model(&mut ta, encrypted.to_str().unwrap(), 0).unwrap();
+        let _ = std::fs::remove_file(&record);
+        let _ = std::fs::remove_file(&encrypted);
+        ta
+    }
+
+    // Dim enough not to look inverted, and different from each other
+    fn image(seed: usize) -> Image {
+        let mut pixels = [0; IMAGE_SIZE];
+        for (p, pixel) in pixels.iter_mut().enumerate() {
+            *pixel = ((p * 7 + seed * 31) % 100) as u8;
+        }
+        Image::from_luma28(&pixels)
+    }
+
+    // Writes `image` into the spool as `name` and queues it, as the event
+    // of it being closed would
+    fn arrive(queue: &mut Queue, spool: &Spool, name: &str, image: &Image) {
+        let path = spool.dir().join(name);
+        image::GrayImage::from_raw(28, 28, image.as_bytes().to_vec())
+            .unwrap()
+            .save(&path)
+            .unwrap();
+        queue.push(path);
+    }
+
+    fn emitted(output: &Path) -> Vec<FileResult> {
+        std::fs::read_to_string(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn arriving_files_are_emitted_exactly_once() {
+        let root = std::env::temp_dir().join(format!("watch-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let (dir, done, output) = (root.join("in"), root.join("done"), root.join("out.jsonl"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut ta = simulated_ta("spool");
+        let mut spool = Spool::open(&dir, &done, &output).unwrap();
+        let mut queue = Queue::default();
+        let mut batching = Batching {
+            size: 2,
+            max_wait: Duration::from_secs(3600),
+            alignment: Alignment::default(),
+        };
+        let images: Vec<Image> = (0..4).map(image).collect();
+        let expected = ta.infer_predictions(&images, 1.0, 0).unwrap();
+
+        // Full batches go at once, the rest waits for company
+        for (name, image) in ["a.png", "b.png", "c.png"].iter().zip(&images) {
+            arrive(&mut queue, &spool, name, image);
+        }
+        process_due(&mut queue, &batching, &mut spool, &mut ta).unwrap();
+        assert_eq!(emitted(&output).len(), 2);
+        assert_eq!(queue.files, [spool.dir().join("c.png")]);
+        arrive(&mut queue, &spool, "d.png", &images[3]);
+        process_due(&mut queue, &batching, &mut spool, &mut ta).unwrap();
+        assert!(queue.files.is_empty());
+
+        // A file that isn't an image goes alone once the wait is over
+        std::fs::write(dir.join("e.png"), b"not a png").unwrap();
+        queue.push(spool.dir().join("e.png"));
+        process_due(&mut queue, &batching, &mut spool, &mut ta).unwrap();
+        assert_eq!(queue.files.len(), 1);
+        batching.max_wait = Duration::ZERO;
+        process_due(&mut queue, &batching, &mut spool, &mut ta).unwrap();
+        // And a second event for a processed file emits nothing
+        queue.push(spool.dir().join("a.png"));
+        process_due(&mut queue, &batching, &mut spool, &mut ta).unwrap();
+        assert!(queue.files.is_empty());
+
+        let results = emitted(&output);
+        let files: Vec<&str> = results.iter().map(|r| r.file.as_str()).collect();
+        assert_eq!(files, ["a.png", "b.png", "c.png", "d.png", "e.png"]);
+        for (result, prediction) in results.iter().zip(&expected) {
+            assert_eq!(result.class, Some(prediction.label));
+            assert_eq!(result.error, None);
+        }
+        assert_eq!(results[4].class, None);
+        assert!(results[4].error.is_some());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Restarting finds nothing left to do
+        drop(spool);
+        let spool = Spool::open(&dir, &done, &output).unwrap();
+        assert!(spool.waiting().unwrap().is_empty());
+        assert_eq!(emitted(&output), results);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn queues_are_due_when_full_or_waited_on() {
+        let wait = Duration::from_secs(3600);
+        let mut queue = Queue::default();
+        assert!(!queue.due(2, Duration::ZERO));
+        assert_eq!(queue.timeout(wait), None);
+        queue.push("a".into());
+        queue.push("a".into());
+        assert!(!queue.due(2, wait));
+        assert!(queue.timeout(wait).unwrap() > Duration::ZERO);
+        assert!(queue.due(2, Duration::ZERO));
+        queue.push("b".into());
+        queue.push("c".into());
+        assert!(queue.due(2, wait));
+        assert_eq!(queue.take(2), [PathBuf::from("a"), PathBuf::from("b")]);
+        // The rest has waited as long as the batch taken
+        assert!(queue.since.is_some());
+        assert_eq!(queue.take(2), [PathBuf::from("c")]);
+        assert_eq!(queue.since, None);
+    }
+
+    #[test]
+    fn only_finished_files_are_taken() {
+        use notify::event::{CreateKind, DataChange};
+        assert!(is_finished_file(&EventKind::Access(AccessKind::Close(
+            AccessMode::Write
+        ))));
+        assert!(is_finished_file(&EventKind::Modify(ModifyKind::Name(
+            RenameMode::To
+        ))));
+        // Still being written, or read
+        for kind in [
+            EventKind::Create(CreateKind::File),
+            EventKind::Modify(ModifyKind::Data(DataChange::Content)),
+            EventKind::Access(AccessKind::Close(AccessMode::Read)),
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+        ] {
+            assert!(!is_finished_file(&kind));
+        }
+    }
+}
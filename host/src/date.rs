@@ -0,0 +1,95 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// UTC calendar dates for model licenses, without pulling in a date crate.
+// Conversions follow Howard Hinnant's days_from_civil / civil_from_days.
+
+const SECS_PER_DAY: u64 = 86_400;
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Parses `YYYY-MM-DD` (midnight UTC) or seconds since the Unix epoch.
+pub fn parse_date(s: &str) -> Result<u64, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    let invalid = || format!("invalid date {}, expected YYYY-MM-DD", s);
+    let mut parts = s.splitn(3, '-');
+    let mut next = || parts.next().and_then(|part| part.parse::<u32>().ok());
+    let (year, month, day) = match (next(), next(), next()) {
+        (Some(year), Some(month), Some(day)) => (year, month, day),
+        _ => return Err(invalid()),
+    };
+    let days_in_month = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid()),
+    };
+    if year < 1970 || day == 0 || day > days_in_month {
+        return Err(invalid());
+    }
+    Ok(days_from_civil(year as i64, month, day) as u64 * SECS_PER_DAY)
+}
+
+/// `YYYY-MM-DD HH:MM:SS UTC` of seconds since the Unix epoch.
+pub fn format_date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+    let time = secs % SECS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Rough human readable length of `secs`, e.g. "12 days" or "5 hours".
+pub fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=119 => format!("{} seconds", secs),
+        120..=7199 => format!("{} minutes", secs / 60),
+        7200..=172_799 => format!("{} hours", secs / 3600),
+        _ => format!("{} days", secs / SECS_PER_DAY),
+    }
+}
@@ -0,0 +1,260 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Comparison of two models' outputs over the same inputs, behind
+// `diff-models`: how often they disagree, how the old predictions map onto
+// the new ones, and which inputs moved the most. Where the outputs were
+// computed (host-side or in the TA) doesn't matter here.
+
+use proto::inference;
+use serde::Serialize;
+
+/// Labels and softmax probabilities (row-major, `num_classes` per input) of
+/// one model over a dataset.
+pub struct Outputs {
+    pub labels: Vec<u8>,
+    pub probs: Vec<f32>,
+    pub num_classes: usize,
+}
+
+/// How far the probabilities of one input moved between the models.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Shift {
+    /// Position of the input in the dataset.
+    pub index: usize,
+    pub old_label: u8,
+    pub new_label: u8,
+    /// Total variation distance between the two probability vectors: 0
+    /// for identical outputs, 1 for disjoint ones.
+    pub shift: f32,
+    /// Either model had tied top classes for the input, so its label comes
+    /// from the tie-break rule (`inference::top_class`).
+    pub tied: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ModelDiff {
+    pub inputs: usize,
+    pub disagreements: usize,
+    /// Disagreements on inputs where either model had tied top classes.
+    pub tied_disagreements: usize,
+    /// `confusion[old][new]` counts the inputs the old model put in class
+    /// `old` and the new one in class `new`.
+    pub confusion: Vec<Vec<usize>>,
+    /// The inputs that shifted the most, largest first.
+    pub largest_shifts: Vec<Shift>,
+}
+
+impl ModelDiff {
+    pub fn disagreement_rate(&self) -> f64 {
+        if self.inputs == 0 {
+            return 0.0;
+        }
+        self.disagreements as f64 / self.inputs as f64
+    }
+}
+
+/// Compares the outputs of two models over the same inputs, keeping the
+/// `top` inputs whose probabilities shifted the most (ties in input order).
+pub fn compare(old: &Outputs, new: &Outputs, top: usize) -> anyhow::Result<ModelDiff> {
+    anyhow::ensure!(
+        old.labels.len() == new.labels.len(),
+        "the models answered {} and {} inputs",
+        old.labels.len(),
+        new.labels.len()
+    );
+    anyhow::ensure!(
+        old.num_classes == new.num_classes,
+        "the models have {} and {} classes",
+        old.num_classes,
+        new.num_classes
+    );
+    let (inputs, num_classes) = (old.labels.len(), old.num_classes);
+    for outputs in [old, new] {
+        anyhow::ensure!(
+            outputs.probs.len() == inputs * num_classes,
+            "got {} probabilities for {} inputs of {} classes",
+            outputs.probs.len(),
+            inputs,
+            num_classes
+        );
+        anyhow::ensure!(
+            outputs
+                .labels
+                .iter()
+                .all(|&label| (label as usize) < num_classes),
+            "a label is out of the {} classes",
+            num_classes
+        );
+    }
+
+    let mut confusion = vec![vec![0; num_classes]; num_classes];
+    let mut disagreements = 0;
+    let mut tied_disagreements = 0;
+    let mut shifts = Vec::with_capacity(inputs);
+    let rows = old
+        .probs
+        .chunks_exact(num_classes)
+        .zip(new.probs.chunks_exact(num_classes));
+    for (index, (old_probs, new_probs)) in rows.enumerate() {
+        let (old_label, new_label) = (old.labels[index], new.labels[index]);
+        confusion[old_label as usize][new_label as usize] += 1;
+        let tied = [old_probs, new_probs]
+            .iter()
+            .any(|probs| inference::top_class(probs).is_some_and(|(_, tied)| tied));
+        if old_label != new_label {
+            disagreements += 1;
+            if tied {
+                tied_disagreements += 1;
+            }
+        }
+        let distance: f32 = old_probs
+            .iter()
+            .zip(new_probs)
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        shifts.push(Shift {
+            index,
+            old_label,
+            new_label,
+            shift: distance / 2.0,
+            tied,
+        });
+    }
+    // Stable, so equal shifts stay in input order
+    shifts.sort_by(|a, b| b.shift.total_cmp(&a.shift));
+    shifts.truncate(top);
+    Ok(ModelDiff {
+        inputs,
+        disagreements,
+        tied_disagreements,
+        confusion,
+        largest_shifts: shifts,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASSES: usize = 3;
+
+    // A synthetic model over inputs 0..count: `classify` gives the class
+    // of an input and the probability it gets, the rest split evenly
+    fn run(count: usize, classify: impl Fn(usize) -> (u8, f32)) -> Outputs {
+        let mut labels = Vec::new();
+        let mut probs = Vec::new();
+        for input in 0..count {
+            let (label, top) = classify(input);
+            labels.push(label);
+            probs.extend((0..CLASSES).map(|class| match class == label as usize {
+                true => top,
+                false => (1.0 - top) / (CLASSES - 1) as f32,
+            }));
+        }
+        Outputs {
+            labels,
+            probs,
+            num_classes: CLASSES,
+        }
+    }
+
+    fn old(input: usize) -> (u8, f32) {
+        ((input % CLASSES) as u8, 0.8)
+    }
+
+    // Agrees with `old` except on the inputs it moves to the next class
+    fn new(moved: &'static [usize]) -> impl Fn(usize) -> (u8, f32) {
+        move |input| match moved.contains(&input) {
+            true => (((input + 1) % CLASSES) as u8, 0.6),
+            false => old(input),
+        }
+    }
+
+    #[test]
+    fn disagreements_are_found_on_the_inputs_that_changed() {
+        let diff = compare(&run(20, old), &run(20, new(&[5, 11, 17])), 10).unwrap();
+        assert_eq!(diff.inputs, 20);
+        assert_eq!(diff.disagreements, 3);
+        assert_eq!(diff.tied_disagreements, 0);
+        assert!((diff.disagreement_rate() - 0.15).abs() < 1e-9);
+        // 5, 11 and 17 are all class 2 for the old model and class 0 for the
+        // new one
+        assert_eq!(diff.confusion, [[7, 0, 0], [0, 7, 0], [3, 0, 3]]);
+        let moved: Vec<(usize, u8, u8)> = diff.largest_shifts[..3]
+            .iter()
+            .map(|shift| (shift.index, shift.old_label, shift.new_label))
+            .collect();
+        assert_eq!(moved, [(5, 2, 0), (11, 2, 0), (17, 2, 0)]);
+        // |0.1 - 0.6| + |0.1 - 0.2| + |0.8 - 0.2|, halved
+        assert!((diff.largest_shifts[0].shift - 0.6).abs() < 1e-6);
+        assert!(diff.largest_shifts[3..]
+            .iter()
+            .all(|shift| shift.shift == 0.0));
+        // Equal shifts stay in input order
+        assert_eq!(diff.largest_shifts[3].index, 0);
+
+        let top = compare(&run(20, old), &run(20, new(&[5, 11, 17])), 2).unwrap();
+        assert_eq!(top.largest_shifts, diff.largest_shifts[..2]);
+    }
+
+    #[test]
+    fn identical_models_agree_everywhere() {
+        let diff = compare(&run(9, old), &run(9, old), 100).unwrap();
+        assert_eq!((diff.disagreements, diff.disagreement_rate()), (0, 0.0));
+        assert_eq!(diff.confusion, [[3, 0, 0], [0, 3, 0], [0, 0, 3]]);
+        assert_eq!(diff.largest_shifts.len(), 9);
+        assert!(diff.largest_shifts.iter().all(|shift| shift.shift == 0.0));
+
+        let empty = compare(&run(0, old), &run(0, old), 5).unwrap();
+        assert_eq!((empty.inputs, empty.disagreement_rate()), (0, 0.0));
+    }
+
+    #[test]
+    fn tie_broken_disagreements_are_counted_apart() {
+        let old = run(4, old);
+        let mut new = run(4, new(&[1]));
+        // Input 2 tied between classes 1 and 2 in the new model, which picks
+        // the lower index
+        new.probs[6..9].copy_from_slice(&[0.2, 0.4, 0.4]);
+        new.labels[2] = 1;
+        let diff = compare(&old, &new, 4).unwrap();
+        assert_eq!((diff.disagreements, diff.tied_disagreements), (2, 1));
+        let tied: Vec<usize> = diff
+            .largest_shifts
+            .iter()
+            .filter(|shift| shift.tied)
+            .map(|shift| shift.index)
+            .collect();
+        assert_eq!(tied, [2]);
+    }
+
+    #[test]
+    fn mismatched_outputs_are_refused() {
+        assert!(compare(&run(4, old), &run(5, old), 1).is_err());
+        let mut wide = run(4, old);
+        wide.num_classes = 4;
+        wide.probs = vec![0.25; 16];
+        assert!(compare(&run(4, old), &wide, 1).is_err());
+        let mut short = run(4, old);
+        short.probs.pop();
+        assert!(compare(&short, &run(4, old), 1).is_err());
+        let mut out_of_range = run(4, old);
+        out_of_range.labels[0] = CLASSES as u8;
+        assert!(compare(&run(4, old), &out_of_range, 1).is_err());
+    }
+}
@@ -0,0 +1,304 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Checks of what the host needs before it can reach the TA: the TEE device
+// node, tee-supplicant and the TA binary. They see the system only through
+// `SystemView`, so they can be run against a made-up one.
+
+use std::io;
+use std::path::Path;
+
+/// Device node libteec opens for client applications.
+pub const TEE_DEVICE: &str = "/dev/tee0";
+/// Directories tee-supplicant loads TAs from, depending on how it was built.
+pub const TA_LOAD_PATHS: &[&str] = &["/lib/optee_armtz", "/usr/lib/optee_armtz"];
+const SUPPLICANT: &str = "tee-supplicant";
+
+/// Ownership and permissions of a file.
+pub struct Node {
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits, e.g. 0o600.
+    pub mode: u32,
+    pub char_device: bool,
+}
+
+/// What the checks need to know about the system.
+pub trait SystemView {
+    fn node(&self, path: &Path) -> io::Result<Node>;
+    /// Opens `path` for reading and writing, as libteec does, and closes it.
+    fn open_rw(&self, path: &Path) -> io::Result<()>;
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+    /// Command names of the running processes.
+    fn processes(&self) -> io::Result<Vec<String>>;
+}
+
+/// The system the host runs on.
+pub struct RealSystem;
+
+impl SystemView for RealSystem {
+    fn node(&self, path: &Path) -> io::Result<Node> {
+        use std::os::unix::fs::{FileTypeExt, MetadataExt};
+        let metadata = std::fs::metadata(path)?;
+        Ok(Node {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            mode: metadata.mode() & 0o7777,
+            char_device: metadata.file_type().is_char_device(),
+        })
+    }
+
+    fn open_rw(&self, path: &Path) -> io::Result<()> {
+        std::fs::File::options()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map(drop)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn processes(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir("/proc")? {
+            let entry = entry?;
+            let pid = entry.file_name();
+            if !pid.to_string_lossy().bytes().all(|b| b.is_ascii_digit()) {
+                continue;
+            }
+            // Processes may exit while the list is read
+            if let Ok(comm) = std::fs::read_to_string(entry.path().join("comm")) {
+                names.push(comm.trim_end().to_string());
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail,
+    /// Not run because an earlier check failed.
+    Skip,
+}
+
+/// Result of one check.
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    /// How to fix a failure.
+    pub hint: Option<String>,
+}
+
+impl Check {
+    pub fn pass(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    pub fn fail(name: &'static str, detail: String, hint: String) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Fail,
+            detail,
+            hint: Some(hint),
+        }
+    }
+
+    pub fn skip(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            outcome: Outcome::Skip,
+            detail,
+            hint: None,
+        }
+    }
+}
+
+/// Whether `path` exists, is a character device and can be opened.
+pub fn check_device(system: &dyn SystemView, path: &Path) -> Check {
+    const NAME: &str = "TEE device";
+    let node = match system.node(path) {
+        Ok(node) => node,
+        Err(err) => {
+            return Check::fail(
+                NAME,
+                format!("{}: {}", path.display(), err),
+                "the OP-TEE driver isn't loaded: check that the kernel is built with \
+                 CONFIG_OPTEE and that the secure world firmware runs OP-TEE"
+                    .to_string(),
+            )
+        }
+    };
+    let description = format!(
+        "{} ({} {}:{})",
+        path.display(),
+        mode_string(&node),
+        user_name(system, node.uid),
+        group_name(system, node.gid)
+    );
+    if !node.char_device {
+        return Check::fail(
+            NAME,
+            format!("{} is not a character device", description),
+            "something else took the device's name; remove it and reload the OP-TEE driver"
+                .to_string(),
+        );
+    }
+    match system.open_rw(path) {
+        Ok(()) => Check::pass(NAME, description),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Check::fail(
+            NAME,
+            format!("{}: permission denied", description),
+            permission_hint(system, path, &node),
+        ),
+        Err(err) => Check::fail(
+            NAME,
+            format!("{}: {}", description, err),
+            "the driver refused the device; check the kernel log (dmesg) for OP-TEE errors"
+                .to_string(),
+        ),
+    }
+}
+
+/// Whether tee-supplicant, which loads TAs and backs secure storage, runs.
+pub fn check_supplicant(system: &dyn SystemView) -> Check {
+    const NAME: &str = "tee-supplicant";
+    match system.processes() {
+        Ok(names) if names.iter().any(|name| name == SUPPLICANT) => {
+            Check::pass(NAME, "running".to_string())
+        }
+        Ok(_) => Check::fail(
+            NAME,
+            "not running".to_string(),
+            "start it (e.g. `systemctl start tee-supplicant` or `tee-supplicant -d`); \
+             without it the TA can't be loaded and secure storage is unavailable"
+                .to_string(),
+        ),
+        Err(err) => Check::fail(
+            NAME,
+            format!("cannot list processes: {}", err),
+            "mount /proc".to_string(),
+        ),
+    }
+}
+
+/// Whether the binary of the TA with `uuid` is in one of `TA_LOAD_PATHS`.
+pub fn check_ta_binary(system: &dyn SystemView, uuid: &str) -> Check {
+    const NAME: &str = "TA binary";
+    let file = format!("{}.ta", uuid);
+    let found = TA_LOAD_PATHS
+        .iter()
+        .map(|dir| Path::new(dir).join(&file))
+        .find(|path| system.node(path).is_ok());
+    match found {
+        Some(path) => Check::pass(NAME, path.display().to_string()),
+        None => Check::fail(
+            NAME,
+            format!("{} not found in {}", file, TA_LOAD_PATHS.join(" or ")),
+            format!(
+                "copy ta/inference's {} into the directory tee-supplicant loads TAs from",
+                file
+            ),
+        ),
+    }
+}
+
+/// Explains why the TEE can't be used when the device node exists but can't
+/// be opened. Called for failed commands, whose TEEC errors don't say.
+pub fn report_device_access(err: &anyhow::Error) {
+    let path = Path::new(TEE_DEVICE);
+    if RealSystem.node(path).is_err()
+        || !err
+            .chain()
+            .any(|cause| cause.downcast_ref::<optee_teec::Error>().is_some())
+    {
+        return;
+    }
+    let check = check_device(&RealSystem, path);
+    if let Some(hint) = check.hint {
+        println!("cannot use {}", check.detail);
+        println!("{}", hint);
+        println!("`doctor` checks the rest of the setup");
+    }
+}
+
+fn permission_hint(system: &dyn SystemView, path: &Path, node: &Node) -> String {
+    let group = group_name(system, node.gid);
+    if node.gid != 0 && node.mode & 0o060 == 0o060 {
+        return format!(
+            "{} is open to the group {}: add your user to it \
+             (`sudo usermod -aG {} $USER`) and log in again",
+            path.display(),
+            group,
+            group
+        );
+    }
+    format!(
+        "{} is only open to {}. Run as root, or let a group open it with a udev rule, \
+         e.g. in /etc/udev/rules.d/99-tee.rules:\n  \
+         KERNEL==\"tee[0-9]*\", MODE=\"0660\", GROUP=\"tee\"\n\
+         then `sudo groupadd -f tee && sudo usermod -aG tee $USER`, \
+         `sudo udevadm control --reload && sudo udevadm trigger`, and log in again",
+        path.display(),
+        user_name(system, node.uid)
+    )
+}
+
+// `ls -l` style type and permission bits
+fn mode_string(node: &Node) -> String {
+    let mut mode = String::from(if node.char_device { "c" } else { "-" });
+    for shift in [6, 3, 0] {
+        let bits = node.mode >> shift;
+        mode.push(if bits & 4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 1 != 0 { 'x' } else { '-' });
+    }
+    mode
+}
+
+fn user_name(system: &dyn SystemView, uid: u32) -> String {
+    lookup_name(system, "/etc/passwd", uid)
+}
+
+fn group_name(system: &dyn SystemView, gid: u32) -> String {
+    lookup_name(system, "/etc/group", gid)
+}
+
+// Name of `id` in a passwd/group style file, the number when not listed
+fn lookup_name(system: &dyn SystemView, file: &str, id: u32) -> String {
+    system
+        .read_to_string(Path::new(file))
+        .ok()
+        .and_then(|entries| {
+            entries.lines().find_map(|line| {
+                let mut fields = line.split(':');
+                let name = fields.next()?;
+                let listed = fields.nth(1)?.parse::<u32>().ok()?;
+                (listed == id).then(|| name.to_string())
+            })
+        })
+        .unwrap_or_else(|| id.to_string())
+}
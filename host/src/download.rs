@@ -0,0 +1,483 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Fetching of encrypted model files over HTTP(S) for `provision --url`. The
+// body is hashed and decoded as it arrives and handed on in pieces, so the
+// file never has to fit in memory or on the device's flash. A dropped
+// connection is resumed with a range request from the last byte received.
+
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use ureq::tls::TlsConfig;
+use ureq::Agent;
+
+use crate::progress::{self, Progress};
+use crate::transcript::{self, Step};
+
+// Range requests after dropped connections before the download fails
+const MAX_RESUMES: usize = 5;
+const RESUME_DELAY: Duration = Duration::from_secs(1);
+// Key of the ciphertext in the model file written by `encrypt-model`
+const DATA_KEY: &[u8] = b"\"encrypted_data\"";
+
+/// A download whose body is read with `read`; every byte read is hashed.
+pub struct Download {
+    agent: Agent,
+    url: String,
+    body: Box<dyn Read>,
+    received: u64,
+    // Size of the whole body, when the server tells
+    length: Option<u64>,
+    hasher: Sha256,
+    resumes: usize,
+    progress: Box<dyn Progress>,
+}
+
+impl Download {
+    /// Sends the request. TLS certificates are verified unless `insecure`.
+    pub fn start(url: &str, insecure: bool) -> anyhow::Result<Self> {
+        if insecure {
+            println!("warning: TLS certificate verification is disabled");
+        }
+        let agent: Agent = Agent::config_builder()
+            .tls_config(TlsConfig::builder().disable_verification(insecure).build())
+            .build()
+            .into();
+        let (body, length) = request(&agent, url, 0)?;
+        Ok(Self {
+            agent,
+            url: url.to_string(),
+            body,
+            received: 0,
+            length,
+            hasher: Sha256::new(),
+            resumes: 0,
+            progress: progress::start("Downloading", "bytes", length),
+        })
+    }
+
+    /// Reads the next part of the body into `buf`, 0 once it is complete.
+    pub fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        loop {
+            let reason = match self.body.read(buf) {
+                // A body cut short by the connection looks like its end
+                Ok(0) if self.length.is_some_and(|length| self.received < length) => {
+                    "connection closed".to_string()
+                }
+                Ok(n) => {
+                    self.hasher.update(&buf[..n]);
+                    self.received += n as u64;
+                    self.progress.tick(n as u64);
+                    return Ok(n);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => err.to_string(),
+            };
+            self.resume(reason)?;
+        }
+    }
+
+    // Asks for the rest of the body after the connection dropped
+    fn resume(&mut self, mut reason: String) -> anyhow::Result<()> {
+        loop {
+            anyhow::ensure!(
+                self.resumes < MAX_RESUMES,
+                "download failed at byte {}: {}",
+                self.received,
+                reason
+            );
+            self.resumes += 1;
+            self.progress.message(&format!(
+                "Download interrupted at byte {} ({}), resuming ({}/{})",
+                self.received, reason, self.resumes, MAX_RESUMES
+            ));
+            transcript::record(Step::Retry {
+                command: "Download".to_string(),
+                reason: format!("{} at byte {}", reason, self.received),
+            });
+            thread::sleep(RESUME_DELAY);
+            match request(&self.agent, &self.url, self.received) {
+                Ok((body, length)) => {
+                    anyhow::ensure!(
+                        length.is_none() || self.length.is_none() || length == self.length,
+                        "{} changed size during the download",
+                        self.url
+                    );
+                    self.body = body;
+                    return Ok(());
+                }
+                Err(err) => reason = err.to_string(),
+            }
+        }
+    }
+
+    /// Fails unless the body read so far, which must be all of it, has the
+    /// SHA-256 `expected`.
+    pub fn finish(mut self, expected: &[u8; 32]) -> anyhow::Result<()> {
+        self.progress.finish();
+        let digest = self.hasher.finalize();
+        let verified = digest.as_slice() == expected;
+        transcript::record(Step::Download {
+            url: &self.url,
+            bytes: self.received,
+            resumes: self.resumes,
+            verified,
+        });
+        anyhow::ensure!(
+            verified,
+            "checksum mismatch: downloaded {} bytes with sha256 {}, expected {}",
+            self.received,
+            hex(&digest),
+            hex(expected)
+        );
+        println!("Checksum verified ({} bytes)", self.received);
+        Ok(())
+    }
+}
+
+// GET of the body from byte `offset` on; returns it and the size of the whole
+// body, if known. Servers that ignore the range send everything, and the part
+// already received is skipped.
+fn request(agent: &Agent, url: &str, offset: u64) -> anyhow::Result<(Box<dyn Read>, Option<u64>)> {
+    // Compressed transfers would make the offsets meaningless
+    let mut request = agent.get(url).header("Accept-Encoding", "identity");
+    if offset > 0 {
+        request = request.header("Range", format!("bytes={}-", offset));
+    }
+    let response = request.call()?;
+    let status = response.status().as_u16();
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let content_length = header("content-length").and_then(|value| value.parse::<u64>().ok());
+    let content_range = header("content-range");
+    let mut body: Box<dyn Read> = Box::new(response.into_body().into_reader());
+    let length = match status {
+        206 if offset > 0 => {
+            // "bytes <first>-<last>/<total>"
+            let range = content_range.unwrap_or_default();
+            let (first, total) = range
+                .strip_prefix("bytes ")
+                .and_then(|range| range.split_once('-'))
+                .map(|(first, rest)| (first, rest.split_once('/').map(|(_, total)| total)))
+                .unwrap_or_default();
+            anyhow::ensure!(
+                first.parse::<u64>().ok() == Some(offset),
+                "asked for the body from byte {}, got range \"{}\"",
+                offset,
+                range
+            );
+            total.and_then(|total| total.parse().ok())
+        }
+        200 => {
+            if offset > 0 {
+                println!(
+                    "Server ignored the range request, skipping {} bytes",
+                    offset
+                );
+                let skipped = std::io::copy(&mut body.by_ref().take(offset), &mut std::io::sink())?;
+                anyhow::ensure!(skipped == offset, "{} got shorter on the retry", url);
+            }
+            content_length
+        }
+        status => anyhow::bail!("unexpected HTTP status {} from {}", status, url),
+    };
+    Ok((body, length))
+}
+
+/// Pulls the ciphertext out of a model file written by `encrypt-model`
+/// (`{"algorithm": ..., "encrypted_data": [<byte>, ...]}`) as it streams
+/// past. Chunked model files are not supported.
+#[derive(Default)]
+pub struct EncryptedDataDecoder {
+    state: DecoderState,
+    // Input searched for the key so far, trimmed to what a split key needs
+    window: Vec<u8>,
+    // Byte being parsed
+    value: Option<u16>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum DecoderState {
+    #[default]
+    Key,
+    Colon,
+    Open,
+    Value,
+    // Whitespace after a value, before its separator
+    Separator,
+    Done,
+}
+
+impl EncryptedDataDecoder {
+    /// Decodes the next part of the file, appending the ciphertext bytes it
+    /// completes to `out`.
+    pub fn feed(&mut self, input: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.state != DecoderState::Key {
+            return self.parse(input, out);
+        }
+        self.window.extend_from_slice(input);
+        match self
+            .window
+            .windows(DATA_KEY.len())
+            .position(|window| window == DATA_KEY)
+        {
+            Some(position) => {
+                let rest = self.window.split_off(position + DATA_KEY.len());
+                self.window = Vec::new();
+                self.state = DecoderState::Colon;
+                self.parse(&rest, out)
+            }
+            None => {
+                let keep = self.window.len().min(DATA_KEY.len() - 1);
+                self.window.drain(..self.window.len() - keep);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails unless the whole array was decoded.
+    pub fn finish(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.state == DecoderState::Done,
+            "no complete encrypted_data array in the model file (chunked files can't be \
+             fetched)"
+        );
+        Ok(())
+    }
+
+    fn parse(&mut self, input: &[u8], out: &mut Vec<u8>) -> anyhow::Result<()> {
+        use DecoderState::*;
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (Done, _) => return Ok(()),
+                (Colon | Open | Separator, byte) if byte.is_ascii_whitespace() => self.state,
+                (Colon, b':') => Open,
+                (Open, b'[') => Value,
+                (Value, b'0'..=b'9') => {
+                    let value = self.value.unwrap_or(0) * 10 + (byte - b'0') as u16;
+                    anyhow::ensure!(value <= u8::MAX as u16, "encrypted_data holds a non-byte");
+                    self.value = Some(value);
+                    Value
+                }
+                (Value, byte) if byte.is_ascii_whitespace() => match self.value {
+                    Some(_) => Separator,
+                    None => Value,
+                },
+                (Value | Separator, b',' | b']') => {
+                    match self.value.take() {
+                        Some(value) => out.push(value as u8),
+                        // Only an empty array may close without a value
+                        None if byte == b']' && self.state == Value => {}
+                        None => anyhow::bail!("malformed encrypted_data array"),
+                    }
+                    if byte == b']' {
+                        Done
+                    } else {
+                        Value
+                    }
+                }
+                _ => anyhow::bail!("malformed encrypted_data array"),
+            };
+        }
+        Ok(())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    type Response = Box<dyn FnOnce(&str) -> Vec<u8> + Send>;
+
+    const CIPHERTEXT: &[u8] = &[0, 1, 9, 10, 99, 100, 255, 128, 7, 42, 200, 16];
+
+    // A model file like the ones `encrypt-model` writes
+    fn fixture() -> Vec<u8> {
+        let data: Vec<String> = CIPHERTEXT.iter().map(u8::to_string).collect();
+        format!(
+            "{{\n  \"algorithm\": \"AES-256-CBC\",\n  \"encrypted_data\": [\n    {}\n  ]\n}}",
+            data.join(",\n    ")
+        )
+        .into_bytes()
+    }
+
+    // Serves one connection per response; each response is built from the
+    // request head and written raw, so it can lie about its length or stop
+    // early. Returns the URL to fetch.
+    fn serve(responses: Vec<Response>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/model.json", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for respond in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                let mut stream = reader.into_inner();
+                stream.write_all(&respond(&head)).unwrap();
+            }
+        });
+        url
+    }
+
+    fn ok(body: Vec<u8>) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend(body);
+        response
+    }
+
+    fn sha256(bytes: &[u8]) -> [u8; 32] {
+        Sha256::digest(bytes).into()
+    }
+
+    // Reads the whole body in small pieces and decodes it on the way
+    fn fetch(download: &mut Download) -> anyhow::Result<Vec<u8>> {
+        let mut decoder = EncryptedDataDecoder::default();
+        let mut ciphertext = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            let n = download.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            decoder.feed(&buf[..n], &mut ciphertext)?;
+        }
+        decoder.finish()?;
+        Ok(ciphertext)
+    }
+
+    #[test]
+    fn downloads_are_checked_and_decoded() {
+        let url = serve(vec![Box::new(|_: &str| ok(fixture()))]);
+        let mut download = Download::start(&url, false).unwrap();
+        assert_eq!(fetch(&mut download).unwrap(), CIPHERTEXT);
+        assert_eq!(download.received, fixture().len() as u64);
+        download.finish(&sha256(&fixture())).unwrap();
+    }
+
+    #[test]
+    fn corrupted_downloads_are_refused() {
+        let mut corrupted = fixture();
+        let last = corrupted.iter().rposition(u8::is_ascii_digit).unwrap();
+        corrupted[last] = if corrupted[last] == b'1' { b'2' } else { b'1' };
+        let url = serve(vec![Box::new(move |_: &str| ok(corrupted))]);
+        let mut download = Download::start(&url, false).unwrap();
+        // Still a well-formed file, so only the checksum tells
+        assert_ne!(fetch(&mut download).unwrap(), CIPHERTEXT);
+        let err = download.finish(&sha256(&fixture())).unwrap_err();
+        assert!(err.to_string().starts_with("checksum mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn dropped_connections_are_resumed_with_a_range_request() {
+        let file = fixture();
+        let cut = file.len() / 2;
+        let tail = file.len() - cut;
+        let total = file.len();
+        let url = serve(vec![
+            // Promises the whole file but hangs up halfway
+            Box::new(move |_: &str| {
+                let mut response = ok(file);
+                response.truncate(response.len() - tail);
+                response
+            }),
+            Box::new(move |request: &str| {
+                let range = format!("range: bytes={}-\r\n", cut);
+                assert!(request.to_lowercase().contains(&range), "{}", request);
+                let body = &fixture()[cut..];
+                let mut response = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\n\
+                     Content-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                    body.len(),
+                    cut,
+                    total - 1,
+                    total
+                )
+                .into_bytes();
+                response.extend(body);
+                response
+            }),
+        ]);
+        let mut download = Download::start(&url, false).unwrap();
+        assert_eq!(fetch(&mut download).unwrap(), CIPHERTEXT);
+        assert_eq!(download.resumes, 1);
+        download.finish(&sha256(&fixture())).unwrap();
+    }
+
+    #[test]
+    fn decoding_doesnt_depend_on_how_the_file_is_split() {
+        let file = fixture();
+        for size in [1, 2, 3, DATA_KEY.len() - 1, DATA_KEY.len(), file.len()] {
+            let mut decoder = EncryptedDataDecoder::default();
+            let mut ciphertext = Vec::new();
+            for piece in file.chunks(size) {
+                decoder.feed(piece, &mut ciphertext).unwrap();
+            }
+            decoder.finish().unwrap();
+            assert_eq!(ciphertext, CIPHERTEXT, "pieces of {} bytes", size);
+        }
+    }
+
+    #[test]
+    fn malformed_model_files_are_refused() {
+        for file in [
+            &br#"{"encrypted_data": [1, 256]}"#[..],
+            br#"{"encrypted_data": [1,, 2]}"#,
+            br#"{"encrypted_data": [1 2]}"#,
+            br#"{"encrypted_data": "0102"}"#,
+        ] {
+            let mut decoder = EncryptedDataDecoder::default();
+            let result = decoder.feed(file, &mut Vec::new());
+            assert!(result.is_err(), "{}", String::from_utf8_lossy(file));
+        }
+        for file in [&br#"{"chunks": []}"#[..], br#"{"encrypted_data": [1, 2"#] {
+            let mut decoder = EncryptedDataDecoder::default();
+            decoder.feed(file, &mut Vec::new()).unwrap();
+            assert!(
+                decoder.finish().is_err(),
+                "{}",
+                String::from_utf8_lossy(file)
+            );
+        }
+        let mut decoder = EncryptedDataDecoder::default();
+        let mut ciphertext = Vec::new();
+        decoder
+            .feed(br#"{"encrypted_data": [ ]}"#, &mut ciphertext)
+            .unwrap();
+        decoder.finish().unwrap();
+        assert!(ciphertext.is_empty());
+    }
+}
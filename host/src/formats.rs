@@ -0,0 +1,291 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Parsers for the input formats the image crate doesn't cover the way we
+// need: PGM/PBM (ASCII and binary, with maxval scaling) and IDX image files
+// as found in the MNIST distribution. Both produce 8-bit grayscale images
+// that go through the same resizing as decoded PNGs.
+
+use anyhow::Context;
+use image::GrayImage;
+
+const IDX_UBYTE_3D: [u8; 4] = [0, 0, 0x08, 0x03];
+const IDX_HEADER_SIZE: usize = 16;
+
+/// Whether `source` starts like a PBM (P1/P4) or PGM (P2/P5) file.
+pub fn is_pnm(source: &[u8]) -> bool {
+    matches!(source, [b'P', b'1' | b'2' | b'4' | b'5', ..])
+}
+
+/// Whether `source` starts like an IDX file of unsigned byte images.
+pub fn is_idx(source: &[u8]) -> bool {
+    source.starts_with(&IDX_UBYTE_3D)
+}
+
+/// Decodes a PBM or PGM file. PGM samples are scaled from `0..=maxval` to
+/// `0..=255`; PBM's 1 (black) becomes 0 and 0 (white) becomes 255.
+pub fn decode_pnm(source: &[u8]) -> anyhow::Result<GrayImage> {
+    let mut reader = PnmReader {
+        data: source,
+        pos: 2,
+    };
+    let kind = source.get(1).copied().unwrap_or_default();
+    let width = reader.number().context("missing width")?;
+    let height = reader.number().context("missing height")?;
+    let maxval = match kind {
+        b'1' | b'4' => 1,
+        _ => reader.number().context("missing maxval")?,
+    };
+    anyhow::ensure!(width > 0 && height > 0, "empty {}x{} image", width, height);
+    anyhow::ensure!((1..=65535).contains(&maxval), "invalid maxval {}", maxval);
+    let pixels = width as usize * height as usize;
+    let scale = |v: u32| -> anyhow::Result<u8> {
+        anyhow::ensure!(v <= maxval, "sample {} above maxval {}", v, maxval);
+        Ok((v * 255 / maxval) as u8)
+    };
+
+    let data = match kind {
+        b'1' => (0..pixels)
+            .map(|_| reader.bit().map(|bit| if bit { 0 } else { 255 }))
+            .collect::<Option<Vec<u8>>>()
+            .context("truncated PBM data")?,
+        b'2' => (0..pixels)
+            .map(|_| {
+                reader
+                    .number()
+                    .context("truncated PGM data")
+                    .and_then(scale)
+            })
+            .collect::<anyhow::Result<Vec<u8>>>()?,
+        b'4' => {
+            let row_bytes = (width as usize).div_ceil(8);
+            let raster = reader
+                .raster(row_bytes * height as usize)
+                .context("truncated PBM data")?;
+            raster
+                .chunks(row_bytes)
+                .flat_map(|row| {
+                    (0..width as usize).map(move |x| row[x / 8] & (0x80 >> (x % 8)) != 0)
+                })
+                .map(|bit| if bit { 0 } else { 255 })
+                .collect()
+        }
+        b'5' => {
+            let sample_size = if maxval < 256 { 1 } else { 2 };
+            let raster = reader
+                .raster(pixels * sample_size)
+                .context("truncated PGM data")?;
+            raster
+                .chunks(sample_size)
+                .map(|sample| scale(sample.iter().fold(0, |v, &b| v << 8 | b as u32)))
+                .collect::<anyhow::Result<Vec<u8>>>()?
+        }
+        _ => anyhow::bail!("not a PBM or PGM file"),
+    };
+    Ok(GrayImage::from_raw(width, height, data).expect("sized above"))
+}
+
+struct PnmReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl PnmReader<'_> {
+    // Skips whitespace and `#` comments up to the next token
+    fn skip_separators(&mut self) {
+        while let Some(&byte) = self.data.get(self.pos) {
+            if byte == b'#' {
+                while self.data.get(self.pos).is_some_and(|&b| b != b'\n') {
+                    self.pos += 1;
+                }
+            } else if byte.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        self.skip_separators();
+        let start = self.pos;
+        while self.data.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.pos])
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    // ASCII PBM samples need no separators between them
+    fn bit(&mut self) -> Option<bool> {
+        self.skip_separators();
+        let bit = match self.data.get(self.pos)? {
+            b'0' => false,
+            b'1' => true,
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(bit)
+    }
+
+    // Binary data starts after exactly one whitespace byte
+    fn raster(&mut self, size: usize) -> Option<&[u8]> {
+        let start = self.pos + 1;
+        self.data.get(start..start + size)
+    }
+}
+
+/// An IDX file of unsigned byte images (`idx3-ubyte`).
+pub struct IdxImages<'a> {
+    pub count: usize,
+    width: u32,
+    height: u32,
+    data: &'a [u8],
+}
+
+impl<'a> IdxImages<'a> {
+    pub fn parse(source: &'a [u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(is_idx(source), "not an IDX file of byte images");
+        let header = source
+            .get(..IDX_HEADER_SIZE)
+            .context("truncated IDX header")?;
+        let dim = |i: usize| u32::from_be_bytes(header[4 + 4 * i..8 + 4 * i].try_into().unwrap());
+        let (count, height, width) = (dim(0) as usize, dim(1), dim(2));
+        let size = count
+            .checked_mul(height as usize * width as usize)
+            .context("IDX dimensions overflow")?;
+        let data = source
+            .get(IDX_HEADER_SIZE..)
+            .and_then(|data| data.get(..size))
+            .with_context(|| format!("truncated IDX data, header says {} images", count))?;
+        Ok(Self {
+            count,
+            width,
+            height,
+            data,
+        })
+    }
+
+    pub fn record(&self, index: usize) -> anyhow::Result<GrayImage> {
+        anyhow::ensure!(
+            index < self.count,
+            "record {} out of range, the file holds {}",
+            index,
+            self.count
+        );
+        let size = self.width as usize * self.height as usize;
+        let data = self.data[index * size..(index + 1) * size].to_vec();
+        Ok(GrayImage::from_raw(self.width, self.height, data).expect("sized above"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IDX file of `count` 2x3 images, image i filled with i
+    fn idx(count: u32) -> Vec<u8> {
+        let mut data = IDX_UBYTE_3D.to_vec();
+        for dim in [count, 2, 3] {
+            data.extend_from_slice(&dim.to_be_bytes());
+        }
+        for i in 0..count {
+            data.extend_from_slice(&[i as u8; 6]);
+        }
+        data
+    }
+
+    #[test]
+    fn ascii_and_binary_pgm_scale_to_bytes() {
+        let ascii = decode_pnm(b"P2\n# from the camera\n3 2\n15\n0 15 7\n 1 2\n3\n").unwrap();
+        assert_eq!(ascii.dimensions(), (3, 2));
+        assert_eq!(ascii.as_raw(), &[0, 255, 119, 17, 34, 51]);
+
+        let binary = decode_pnm(b"P5 2 2 255\n\x00\x80\xfe\xff").unwrap();
+        assert_eq!(binary.as_raw(), &[0, 128, 254, 255]);
+        // Two bytes per sample, big-endian, past a maxval of 255
+        let wide = decode_pnm(b"P5\n2 1\n65535\n\x00\x00\xff\xff").unwrap();
+        assert_eq!(wide.as_raw(), &[0, 255]);
+        let wide = decode_pnm(b"P5\n1 1\n1000\n\x01\xf4").unwrap();
+        assert_eq!(wide.as_raw(), &[127]);
+    }
+
+    #[test]
+    fn ascii_and_binary_pbm_are_black_on_white() {
+        // ASCII bits need no separators
+        let ascii = decode_pnm(b"P1\n3 2\n1 0 1\n011\n").unwrap();
+        assert_eq!(ascii.as_raw(), &[0, 255, 0, 255, 0, 0]);
+        // Rows are padded to whole bytes
+        let binary = decode_pnm(b"P4\n10 2\n\xa0\x40\x00\xc0").unwrap();
+        assert_eq!(
+            binary.as_raw(),
+            &[
+                0, 255, 0, 255, 255, 255, 255, 255, 255, 0, //
+                255, 255, 255, 255, 255, 255, 255, 255, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_pnm_files_are_refused() {
+        for source in [
+            &b"P2\n3 2\n15\n0 15 7 1 2\n"[..],
+            b"P2\n1 1\n15\n16\n",
+            b"P5\n2 2\n255\n\x00\x01\x02",
+            b"P4\n9 1\n\xff",
+            b"P1\n2 1\n1 2\n",
+            b"P2\n0 4\n255\n",
+            b"P2\n1 1\n0\n0\n",
+            b"P2\n1 1\n70000\n0\n",
+            b"P5\n",
+            b"P6\n1 1\n255\n\x00\x00\x00",
+        ] {
+            assert!(
+                decode_pnm(source).is_err(),
+                "{:?}",
+                String::from_utf8_lossy(source)
+            );
+        }
+        assert!(is_pnm(b"P5\n") && is_pnm(b"P1") && !is_pnm(b"P6") && !is_pnm(b"\x89PNG"));
+    }
+
+    #[test]
+    fn idx_records_up_to_the_last() {
+        let source = idx(4);
+        assert!(is_idx(&source));
+        let images = IdxImages::parse(&source).unwrap();
+        assert_eq!(images.count, 4);
+        let first = images.record(0).unwrap();
+        assert_eq!(first.dimensions(), (3, 2));
+        assert_eq!(first.as_raw(), &[0; 6]);
+        assert_eq!(images.record(3).unwrap().as_raw(), &[3; 6]);
+        assert!(images.record(4).is_err());
+
+        // Trailing bytes are ignored, missing ones refused
+        let mut longer = source.clone();
+        longer.push(9);
+        assert_eq!(IdxImages::parse(&longer).unwrap().count, 4);
+        assert!(IdxImages::parse(&source[..source.len() - 1]).is_err());
+        assert!(IdxImages::parse(&source[..10]).is_err());
+        let mut labels = source.clone();
+        labels[3] = 1;
+        assert!(IdxImages::parse(&labels).is_err());
+        assert_eq!(IdxImages::parse(&idx(0)).unwrap().count, 0);
+    }
+}
@@ -0,0 +1,626 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Loading of inference inputs. Files that only need fixing up (a trailing
+// newline, colour, size) are accepted with a warning so the user learns
+// about it before the TA produces a nonsense prediction. Every format ends
+// up in `preprocess`, so they are all resized and checked the same way.
+//
+// `align_digit` optionally brings a loaded image to the MNIST conventions:
+// the training digits were scaled to fit a 20x20 box, keeping their aspect
+// ratio, and placed in the 28x28 image with their center of mass in the
+// middle. Photographed digits rarely are, and the model does much worse on
+// them.
+
+use std::ops::Range;
+
+use anyhow::Context;
+use image::imageops::FilterType;
+use image::DynamicImage;
+use proto::{Image, IMAGE_HEIGHT, IMAGE_SIZE, IMAGE_WIDTH};
+
+use crate::cache::PreprocessCache;
+use crate::formats::{self, IdxImages};
+
+// Cache hits that are decoded again to check the cached result
+const VERIFY_ONE_IN: u32 = 64;
+
+// MNIST digits are light strokes on a black background, with a mean
+// intensity around 33; a much brighter image is most likely inverted
+const INVERTED_MEAN_INTENSITY: f32 = 128.0;
+
+// Side of the box MNIST digits were scaled to fit
+const GLYPH_BOX: usize = 20;
+// Pixels dimmer than this fraction of the brightest one are background to
+// `align_digit`, cleared before the digit is measured
+const GLYPH_THRESHOLD: f32 = 0.25;
+
+/// An input image and what was off about the file it came from.
+pub struct Input {
+    pub image: Image,
+    pub warnings: Vec<String>,
+}
+
+/// What `align_digit` does to an image (`--center`, `--deskew`).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Alignment {
+    /// Scale the digit to fit `GLYPH_BOX` and center it by mass
+    pub center: bool,
+    /// Shear the digit upright, using its second-order moments
+    pub deskew: bool,
+}
+
+/// How an `--image` file is decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Pick one of the others by the file's magic bytes and size
+    Auto,
+    /// Binary or ASCII PGM, or PBM
+    Pgm,
+    /// PNG or anything else the image crate decodes
+    Png,
+    /// IDX images file holding a single record
+    IdxRecord,
+    /// IMAGE_SIZE bytes, as with --binary
+    Raw,
+}
+
+impl Format {
+    fn detect(source: &[u8]) -> Self {
+        if formats::is_pnm(source) {
+            Format::Pgm
+        } else if formats::is_idx(source) {
+            Format::IdxRecord
+        } else if image::guess_format(source).is_err()
+            && (source.len() == IMAGE_SIZE || source.len() == IMAGE_SIZE + 1)
+        {
+            Format::Raw
+        } else {
+            Format::Png
+        }
+    }
+}
+
+/// Reads a raw IMAGE_SIZE byte image. A single trailing newline, as left
+/// by text editors, is stripped with a warning.
+pub fn load_binary(path: &str) -> anyhow::Result<Input> {
+    let data = std::fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    decode_raw(path, data)
+}
+
+fn decode_raw(path: &str, mut data: Vec<u8>) -> anyhow::Result<Input> {
+    let mut warnings = Vec::new();
+    if data.len() == IMAGE_SIZE + 1 && data.last() == Some(&b'\n') {
+        data.pop();
+        warnings.push("stripped a trailing newline".to_string());
+    }
+    let image = Image::from_bytes(&data).map_err(|err| anyhow::anyhow!("{}: {}", path, err))?;
+    check_polarity(&image, &mut warnings);
+    Ok(Input { image, warnings })
+}
+
+/// Decodes an image file and converts it to a 28x28 grayscale image,
+/// warning about every conversion applied. With a cache, a file seen before
+/// skips decoding; one hit in `VERIFY_ONE_IN` is decoded anyway and checked
+/// against the cached result.
+pub fn load_image(
+    path: &str,
+    format: Format,
+    cache: Option<&PreprocessCache>,
+) -> anyhow::Result<Input> {
+    let source = std::fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    let format = match format {
+        Format::Auto => Format::detect(&source),
+        format => format,
+    };
+    let Some(cache) = cache else {
+        return decode(path, &source, format);
+    };
+    let key = PreprocessCache::key(&source, format);
+    let cached = match cache.get(&key) {
+        Some(cached) if !rand::random_ratio(1, VERIFY_ONE_IN) => return Ok(cached),
+        cached => cached,
+    };
+    let input = decode(path, &source, format)?;
+    match cached {
+        Some(cached) if cached.image == input.image && cached.warnings == input.warnings => {
+            return Ok(input)
+        }
+        Some(_) => println!(
+            "warning: {}: cached preprocessing was stale, replaced",
+            path
+        ),
+        None => {}
+    }
+    if let Err(err) = cache.put(&key, &input) {
+        println!("warning: cannot cache {}: {}", path, err);
+    }
+    Ok(input)
+}
+
+/// Loads the `records` of an IDX images file, named `path[index]`.
+pub fn load_idx(path: &str, records: Option<Range<usize>>) -> anyhow::Result<Vec<(String, Input)>> {
+    let source = std::fs::read(path).with_context(|| format!("cannot read {}", path))?;
+    let idx = IdxImages::parse(&source).with_context(|| format!("cannot parse {}", path))?;
+    let records = records.unwrap_or(0..idx.count);
+    anyhow::ensure!(
+        records.end <= idx.count,
+        "{}: records {}..{} requested, the file holds {}",
+        path,
+        records.start,
+        records.end,
+        idx.count
+    );
+    records
+        .map(|index| {
+            let name = format!("{}[{}]", path, index);
+            let input = preprocess(&name, DynamicImage::ImageLuma8(idx.record(index)?))?;
+            Ok((name, input))
+        })
+        .collect()
+}
+
+fn decode(path: &str, source: &[u8], format: Format) -> anyhow::Result<Input> {
+    let img = match format {
+        Format::Raw => return decode_raw(path, source.to_vec()),
+        Format::Pgm => DynamicImage::ImageLuma8(
+            formats::decode_pnm(source).with_context(|| format!("cannot decode {}", path))?,
+        ),
+        Format::IdxRecord => {
+            let idx = IdxImages::parse(source).with_context(|| format!("cannot parse {}", path))?;
+            anyhow::ensure!(
+                idx.count == 1,
+                "{} holds {} records, select them with --idx and --index",
+                path,
+                idx.count
+            );
+            DynamicImage::ImageLuma8(idx.record(0)?)
+        }
+        Format::Png | Format::Auto => {
+            image::load_from_memory(source).with_context(|| format!("cannot decode {}", path))?
+        }
+    };
+    preprocess(path, img)
+}
+
+fn preprocess(path: &str, img: DynamicImage) -> anyhow::Result<Input> {
+    let mut warnings = Vec::new();
+    let color = img.color();
+    if color.channel_count() > 1 {
+        warnings.push(format!("converted {:?} to grayscale", color));
+    }
+    let (width, height) = (img.width(), img.height());
+    if (width as usize, height as usize) != (IMAGE_WIDTH, IMAGE_HEIGHT) {
+        warnings.push(format!(
+            "resized {}x{} to {}x{}",
+            width, height, IMAGE_WIDTH, IMAGE_HEIGHT
+        ));
+    }
+    let luma = img
+        .resize_exact(
+            IMAGE_WIDTH as u32,
+            IMAGE_HEIGHT as u32,
+            FilterType::Triangle,
+        )
+        .to_luma8();
+    let image =
+        Image::from_bytes(luma.as_raw()).map_err(|err| anyhow::anyhow!("{}: {}", path, err))?;
+    check_polarity(&image, &mut warnings);
+    Ok(Input { image, warnings })
+}
+
+fn check_polarity(image: &Image, warnings: &mut Vec<String>) {
+    let mean = image.as_bytes().iter().map(|&v| v as f32).sum::<f32>() / IMAGE_SIZE as f32;
+    if mean >= INVERTED_MEAN_INTENSITY {
+        warnings.push(format!(
+            "mean intensity {:.0} looks like a dark digit on a light background, \
+             MNIST expects light on dark",
+            mean
+        ));
+    }
+}
+
+/// Thresholds `image` and deskews and/or centers the digit in it as the
+/// MNIST digits are. Images with nothing but background are returned as
+/// they are.
+pub fn align_digit(image: &Image, alignment: Alignment) -> Image {
+    let max = image.as_bytes().iter().copied().max().unwrap_or(0);
+    if alignment == Alignment::default() || max == 0 {
+        return *image;
+    }
+    let cutoff = max as f32 * GLYPH_THRESHOLD;
+    let mut pixels: Vec<f32> = image
+        .as_bytes()
+        .iter()
+        .map(|&value| {
+            if (value as f32) < cutoff {
+                0.0
+            } else {
+                value as f32
+            }
+        })
+        .collect();
+    if alignment.deskew {
+        pixels = deskew(&pixels);
+    }
+    if alignment.center {
+        pixels = center(&pixels);
+    }
+    let mut aligned = Image::BLANK;
+    for (out, value) in aligned.as_bytes_mut().iter_mut().zip(pixels) {
+        *out = value.round().clamp(0.0, 255.0) as u8;
+    }
+    aligned
+}
+
+// Shears the rows of the image horizontally so that the digit's principal
+// axis is vertical, around its center of mass
+fn deskew(pixels: &[f32]) -> Vec<f32> {
+    let (mx, my) = match center_of_mass(pixels, IMAGE_WIDTH) {
+        Some(center) => center,
+        None => return pixels.to_vec(),
+    };
+    let (mut mu11, mut mu02) = (0.0, 0.0);
+    for (i, &value) in pixels.iter().enumerate() {
+        let (dx, dy) = ((i % IMAGE_WIDTH) as f32 - mx, (i / IMAGE_WIDTH) as f32 - my);
+        mu11 += dx * dy * value;
+        mu02 += dy * dy * value;
+    }
+    if mu02 < f32::EPSILON {
+        return pixels.to_vec();
+    }
+    let skew = mu11 / mu02;
+    (0..IMAGE_SIZE)
+        .map(|i| {
+            let (x, y) = ((i % IMAGE_WIDTH) as f32, (i / IMAGE_WIDTH) as f32);
+            sample(pixels, IMAGE_WIDTH, IMAGE_HEIGHT, x + skew * (y - my), y)
+        })
+        .collect()
+}
+
+// Crops the image to the digit's bounding box, scales that to fit
+// `GLYPH_BOX` and pastes it into an empty image with its center of mass as
+// near the middle as the box allows
+fn center(pixels: &[f32]) -> Vec<f32> {
+    let lit: Vec<usize> = (0..IMAGE_SIZE).filter(|&i| pixels[i] > 0.0).collect();
+    if lit.is_empty() {
+        return pixels.to_vec();
+    }
+    let left = lit.iter().map(|i| i % IMAGE_WIDTH).min().unwrap_or(0);
+    let right = lit.iter().map(|i| i % IMAGE_WIDTH).max().unwrap_or(0);
+    let (top, bottom) = (lit[0] / IMAGE_WIDTH, lit[lit.len() - 1] / IMAGE_WIDTH);
+    let (width, height) = (right - left + 1, bottom - top + 1);
+    let scale = GLYPH_BOX as f32 / width.max(height) as f32;
+    let glyph_width = ((width as f32 * scale).round() as usize).clamp(1, GLYPH_BOX);
+    let glyph_height = ((height as f32 * scale).round() as usize).clamp(1, GLYPH_BOX);
+    // Pixel centers of the glyph mapped back into the bounding box
+    let glyph: Vec<f32> = (0..glyph_width * glyph_height)
+        .map(|i| {
+            let (x, y) = ((i % glyph_width) as f32, (i / glyph_width) as f32);
+            let source_x = left as f32 + (x + 0.5) * width as f32 / glyph_width as f32 - 0.5;
+            let source_y = top as f32 + (y + 0.5) * height as f32 / glyph_height as f32 - 0.5;
+            sample(pixels, IMAGE_WIDTH, IMAGE_HEIGHT, source_x, source_y)
+        })
+        .collect();
+    let (mx, my) = center_of_mass(&glyph, glyph_width)
+        .unwrap_or((glyph_width as f32 / 2.0, glyph_height as f32 / 2.0));
+    let offset = |middle: usize, mass: f32, size: usize| {
+        ((middle as f32 - 1.0) / 2.0 - mass)
+            .round()
+            .clamp(0.0, (middle - size) as f32) as usize
+    };
+    let offset_x = offset(IMAGE_WIDTH, mx, glyph_width);
+    let offset_y = offset(IMAGE_HEIGHT, my, glyph_height);
+    let mut centered = vec![0.0; IMAGE_SIZE];
+    for (i, &value) in glyph.iter().enumerate() {
+        let (x, y) = (offset_x + i % glyph_width, offset_y + i / glyph_width);
+        centered[y * IMAGE_WIDTH + x] = value;
+    }
+    centered
+}
+
+// Intensity-weighted mean position of a `width` wide image, in pixel
+// indices; `None` for an empty one
+fn center_of_mass(pixels: &[f32], width: usize) -> Option<(f32, f32)> {
+    let total: f32 = pixels.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let (mut x, mut y) = (0.0, 0.0);
+    for (i, &value) in pixels.iter().enumerate() {
+        x += (i % width) as f32 * value;
+        y += (i / width) as f32 * value;
+    }
+    Some((x / total, y / total))
+}
+
+// Bilinear interpolation at (x, y), in pixel indices, of a `width` x
+// `height` image that is black outside its bounds
+fn sample(pixels: &[f32], width: usize, height: usize, x: f32, y: f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let at = |x: f32, y: f32| {
+        if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+            0.0
+        } else {
+            pixels[y as usize * width + x as usize]
+        }
+    };
+    at(x0, y0) * (1.0 - fx) * (1.0 - fy)
+        + at(x0 + 1.0, y0) * fx * (1.0 - fy)
+        + at(x0, y0 + 1.0) * (1.0 - fx) * fy
+        + at(x0 + 1.0, y0 + 1.0) * fx * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes `data` to a temporary file named after `test` and loads it with
+    // `load`
+    fn load<T>(test: &str, data: &[u8], load: impl FnOnce(&str) -> T) -> T {
+        let path = std::env::temp_dir().join(format!("input-{}-{}", test, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let result = load(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    // A light stroke on black, as MNIST digits are
+    fn digit() -> Vec<u8> {
+        (0..IMAGE_SIZE)
+            .map(|i| if i % IMAGE_WIDTH == 14 { 255 } else { 0 })
+            .collect()
+    }
+
+    fn png(image: DynamicImage) -> Vec<u8> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut data, image::ImageFormat::Png).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn binaries_must_be_exactly_one_image() {
+        let input = load("exact", &digit(), load_binary).unwrap();
+        assert_eq!(input.image.as_bytes()[..], digit()[..]);
+        assert!(input.warnings.is_empty());
+        assert!(load("short", &digit()[1..], load_binary).is_err());
+        let mut long = digit();
+        long.push(0);
+        assert!(load("long", &long, load_binary).is_err());
+    }
+
+    #[test]
+    fn a_trailing_newline_is_stripped() {
+        let mut data = digit();
+        data.push(b'\n');
+        let input = load("newline", &data, load_binary).unwrap();
+        assert_eq!(input.image.as_bytes()[..], digit()[..]);
+        assert_eq!(input.warnings, ["stripped a trailing newline"]);
+        // Only a single one
+        data.push(b'\n');
+        assert!(load("newlines", &data, load_binary).is_err());
+    }
+
+    #[test]
+    fn colour_images_are_converted_with_a_warning() {
+        let gray = image::GrayImage::from_raw(28, 28, digit()).unwrap();
+        let rgb = DynamicImage::ImageLuma8(gray).to_rgb8();
+        let data = png(DynamicImage::ImageRgb8(rgb));
+        let input = load("rgb", &data, |path| load_image(path, Format::Auto, None)).unwrap();
+        assert_eq!(input.image.as_bytes()[..], digit()[..]);
+        assert_eq!(input.warnings, ["converted Rgb8 to grayscale"]);
+
+        let large = DynamicImage::ImageLuma8(image::GrayImage::new(56, 30));
+        let input = load("large", &png(large), |path| {
+            load_image(path, Format::Auto, None)
+        });
+        assert_eq!(input.unwrap().warnings, ["resized 56x30 to 28x28"]);
+    }
+
+    #[test]
+    fn inverted_images_are_pointed_out() {
+        let inverted: Vec<u8> = digit().iter().map(|v| 255 - v).collect();
+        let input = load("inverted", &inverted, load_binary).unwrap();
+        assert_eq!(input.warnings.len(), 1);
+        assert!(input.warnings[0].contains("light background"));
+        // Every format is checked the same way
+        let gray = image::GrayImage::from_raw(28, 28, inverted).unwrap();
+        let data = png(DynamicImage::ImageLuma8(gray));
+        let input = load("inverted-png", &data, |path| {
+            load_image(path, Format::Png, None)
+        });
+        assert_eq!(input.unwrap().warnings.len(), 1);
+    }
+
+    // IDX file of `count` 28x28 images, image i filled with i + 1
+    fn idx(count: u32) -> Vec<u8> {
+        let mut data = vec![0, 0, 0x08, 0x03];
+        for dim in [count, 28, 28] {
+            data.extend_from_slice(&dim.to_be_bytes());
+        }
+        for i in 0..count {
+            data.extend_from_slice(&[i as u8 + 1; IMAGE_SIZE]);
+        }
+        data
+    }
+
+    #[test]
+    fn formats_are_told_apart_by_their_contents() {
+        let auto =
+            |test, data: &[u8]| load(test, data, |path| load_image(path, Format::Auto, None));
+        // Binary PGM, as the camera pipeline writes it
+        let mut pgm = b"P5\n28 28\n255\n".to_vec();
+        pgm.extend_from_slice(&digit());
+        let input = auto("pgm", &pgm).unwrap();
+        assert_eq!(input.image.as_bytes()[..], digit()[..]);
+        assert!(input.warnings.is_empty());
+        // A single record cut out of an IDX file
+        let input = auto("idx-record", &idx(1)).unwrap();
+        assert_eq!(input.image.as_bytes(), &[1; IMAGE_SIZE]);
+        assert!(auto("idx-records", &idx(2)).is_err());
+        // Raw bytes that happen not to look like anything else
+        let input = auto("raw", &digit()).unwrap();
+        assert_eq!(input.image.as_bytes()[..], digit()[..]);
+
+        // An explicit format wins over the guess
+        let forced = load("forced", &digit(), |path| {
+            load_image(path, Format::Pgm, None)
+        });
+        assert!(forced.is_err());
+    }
+
+    #[test]
+    fn idx_ranges_up_to_the_last_record() {
+        let records = |range| load("idx", &idx(5), |path| load_idx(path, range));
+        let all = records(None).unwrap();
+        assert_eq!(all.len(), 5);
+        assert!(all[0].0.ends_with("[0]") && all[4].0.ends_with("[4]"));
+        assert_eq!(all[4].1.image.as_bytes(), &[5; IMAGE_SIZE]);
+
+        let last = records(Some(4..5)).unwrap();
+        assert_eq!(last.len(), 1);
+        assert_eq!(last[0].1.image.as_bytes(), &[5; IMAGE_SIZE]);
+        assert_eq!(records(Some(1..3)).unwrap().len(), 2);
+        assert!(records(Some(4..6)).is_err());
+        assert!(records(Some(5..6)).is_err());
+    }
+
+    // An image with `lit` pixels, as (x, y), at full intensity
+    fn glyph(lit: impl IntoIterator<Item = (usize, usize)>) -> Image {
+        let mut image = Image::BLANK;
+        for (x, y) in lit {
+            image.as_bytes_mut()[y * IMAGE_WIDTH + x] = 255;
+        }
+        image
+    }
+
+    fn square(left: usize, top: usize, size: usize) -> Image {
+        glyph((0..size * size).map(|i| (left + i % size, top + i / size)))
+    }
+
+    const CENTER: Alignment = Alignment {
+        center: true,
+        deskew: false,
+    };
+    const DESKEW: Alignment = Alignment {
+        center: false,
+        deskew: true,
+    };
+
+    #[test]
+    fn digits_are_centered_by_mass() {
+        // A glyph that already fills the box is only moved
+        assert_eq!(align_digit(&square(0, 0, 20), CENTER), square(4, 4, 20));
+        assert_eq!(align_digit(&square(8, 3, 20), CENTER), square(4, 4, 20));
+        // A smaller one is scaled up to it first, its edges interpolated
+        // with the background
+        let edge = [0.625, 0.875];
+        let profile: Vec<f32> = edge
+            .into_iter()
+            .chain([1.0; 16])
+            .chain(edge.into_iter().rev())
+            .collect();
+        let mut scaled = Image::BLANK;
+        for (i, &row) in profile.iter().enumerate() {
+            for (j, &column) in profile.iter().enumerate() {
+                scaled.as_bytes_mut()[(4 + i) * IMAGE_WIDTH + 4 + j] =
+                    (255.0 * row * column).round() as u8;
+            }
+        }
+        assert_eq!(align_digit(&square(20, 22, 5), CENTER), scaled);
+        // Faint noise below the threshold is dropped with the background
+        let mut noisy = square(1, 1, 20);
+        noisy.as_bytes_mut()[IMAGE_SIZE - 1] = 40;
+        assert_eq!(align_digit(&noisy, CENTER), square(4, 4, 20));
+        // Nothing is done without a step or a glyph
+        assert_eq!(align_digit(&noisy, Alignment::default()), noisy);
+        assert_eq!(align_digit(&Image::BLANK, CENTER), Image::BLANK);
+    }
+
+    #[test]
+    fn slanted_strokes_are_made_upright() {
+        // A 45 degree stroke through the middle becomes a vertical one
+        let slanted = glyph((4..25).map(|y| (y, y)));
+        let upright = glyph((4..25).map(|y| (14, y)));
+        assert_eq!(align_digit(&slanted, DESKEW), upright);
+        // Upright ones stay as they are
+        assert_eq!(align_digit(&upright, DESKEW), upright);
+        // Deskewed, then centered: the stroke fills the box's height
+        let both = align_digit(
+            &slanted,
+            Alignment {
+                center: true,
+                deskew: true,
+            },
+        );
+        let rows: Vec<usize> = (0..IMAGE_SIZE)
+            .filter(|&i| both.as_bytes()[i] != 0)
+            .map(|i| i / IMAGE_WIDTH)
+            .collect();
+        assert_eq!((rows[0], rows[rows.len() - 1]), (4, 23));
+    }
+
+    #[test]
+    fn centering_recovers_off_center_digits() {
+        // Three glyphs, one for each class, small and in a corner of the image
+        let glyphs: [fn(usize, usize) -> Image; 3] = [
+            // 0: a ring
+            |left, top| {
+                glyph((0..64).filter_map(|i| {
+                    let (x, y) = (i % 8, i / 8);
+                    (x == 0 || x == 7 || y == 0 || y == 7).then_some((left + x, top + y))
+                }))
+            },
+            // 1: a bar
+            |left, top| glyph((0..16).map(|i| (left + 3 + i % 2, top + i / 2))),
+            // 4: a cross
+            |left, top| glyph((0..8).flat_map(|i| [(left + i, top + 3), (left + 3, top + i)])),
+        ];
+        // The model: the nearest of the glyphs as MNIST would show them
+        let templates: Vec<Image> = glyphs
+            .iter()
+            .map(|g| align_digit(&g(10, 10), CENTER))
+            .collect();
+        let classify = |image: &Image| {
+            let distance = |template: &Image| -> u64 {
+                template
+                    .as_bytes()
+                    .iter()
+                    .zip(image.as_bytes())
+                    .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+                    .sum()
+            };
+            (0..templates.len())
+                .min_by_key(|&class| distance(&templates[class]))
+                .unwrap()
+        };
+        let accuracy = |alignment| {
+            let mut correct = 0;
+            for (class, glyph) in glyphs.iter().enumerate() {
+                for (left, top) in [(0, 0), (20, 0), (0, 20), (20, 20), (2, 14)] {
+                    let image = align_digit(&glyph(left, top), alignment);
+                    correct += (classify(&image) == class) as usize;
+                }
+            }
+            correct
+        };
+        let (raw, centered) = (accuracy(Alignment::default()), accuracy(CENTER));
+        assert_eq!(centered, 15);
+        assert!(raw < 10, "{raw}/15 without centering");
+    }
+}
@@ -0,0 +1,359 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Provisioning keys kept in the OS secret service (GNOME Keyring, macOS
+// Keychain) instead of hex in files, behind the `keystore` feature. Entries
+// live under the `enc-mnist` service, one per name, holding the 32 raw key
+// bytes. The secret service can't enumerate a service's entries portably, so
+// the names are also kept in an index entry for `keystore list`.
+//
+// Errors from the keystore itself (locked, missing entry) are worded apart
+// from key-format errors, so a locked keyring doesn't read as a bad key.
+
+use anyhow::Result;
+
+#[cfg(feature = "keystore")]
+const SERVICE: &str = "enc-mnist";
+
+// Entry holding the newline-separated names; entry names can't start with a dot
+#[cfg(feature = "keystore")]
+const INDEX_ENTRY: &str = ".index";
+
+/// A 32-byte AES key, overwritten with zeros when dropped.
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    pub fn bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        wipe(&mut self.0);
+    }
+}
+
+/// Overwrites `bytes` with zeros in a way the compiler can't elide.
+pub fn wipe(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        // SAFETY: `byte` is a valid, exclusive reference
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+#[cfg(feature = "keystore")]
+mod backend {
+    use super::{wipe, Result, SecretKey, INDEX_ENTRY, SERVICE};
+    use keyring::{Entry, Error};
+
+    fn validate_name(name: &str) -> Result<()> {
+        anyhow::ensure!(
+            !name.is_empty() && !name.starts_with('.') && !name.contains('\n'),
+            "invalid keystore entry name \"{}\": must be non-empty, one line and not start with '.'",
+            name
+        );
+        Ok(())
+    }
+
+    fn entry(name: &str) -> Result<Entry> {
+        Entry::new(SERVICE, name).map_err(|err| describe(name, err))
+    }
+
+    // Keystore failures, worded apart from the key-format errors of --key
+    fn describe(name: &str, err: Error) -> anyhow::Error {
+        match err {
+            Error::NoEntry => anyhow::anyhow!(
+                "no keystore entry \"{}\" (add it with `keystore put {}`)",
+                name,
+                name
+            ),
+            Error::NoStorageAccess(err) => anyhow::anyhow!(
+                "keystore refused access to \"{}\", is it locked? Unlock it and retry ({})",
+                name,
+                err
+            ),
+            Error::PlatformFailure(err) => {
+                anyhow::anyhow!("keystore unavailable: {}", err)
+            }
+            err => anyhow::anyhow!("keystore entry \"{}\": {}", name, err),
+        }
+    }
+
+    /// Fetches the key stored as `name`.
+    pub fn fetch_key(name: &str) -> Result<SecretKey> {
+        validate_name(name)?;
+        let mut secret = entry(name)?
+            .get_secret()
+            .map_err(|err| describe(name, err))?;
+        let key = <[u8; 32]>::try_from(secret.as_slice()).ok();
+        let len = secret.len();
+        wipe(&mut secret);
+        key.map(SecretKey::new).ok_or_else(|| {
+            anyhow::anyhow!(
+                "keystore entry \"{}\" holds {} bytes, not a 32-byte AES key",
+                name,
+                len
+            )
+        })
+    }
+
+    /// Stores `key` as `name`, replacing any key of that name. Returns whether
+    /// one was replaced.
+    pub fn put_key(name: &str, key: &SecretKey) -> Result<bool> {
+        validate_name(name)?;
+        let entry = entry(name)?;
+        let replaced = match entry.get_secret() {
+            Ok(mut secret) => {
+                wipe(&mut secret);
+                true
+            }
+            Err(Error::NoEntry) => false,
+            Err(err) => return Err(describe(name, err)),
+        };
+        entry
+            .set_secret(key.bytes())
+            .map_err(|err| describe(name, err))?;
+        let mut names = list()?;
+        if !names.iter().any(|n| n == name) {
+            names.push(name.to_string());
+            write_index(&names)?;
+        }
+        Ok(replaced)
+    }
+
+    /// Deletes the key stored as `name`.
+    pub fn delete_key(name: &str) -> Result<()> {
+        validate_name(name)?;
+        let deleted = entry(name)?
+            .delete_credential()
+            .map_err(|err| describe(name, err));
+        // Drop the name from the index even when the entry was already gone
+        let mut names = list()?;
+        let count = names.len();
+        names.retain(|n| n != name);
+        if names.len() != count {
+            write_index(&names)?;
+        }
+        deleted
+    }
+
+    /// Names of the stored keys, in the order they were added.
+    pub fn list() -> Result<Vec<String>> {
+        match entry(INDEX_ENTRY)?.get_password() {
+            Ok(index) => Ok(index.lines().map(str::to_string).collect()),
+            Err(Error::NoEntry) => Ok(Vec::new()),
+            Err(err) => Err(describe(INDEX_ENTRY, err)),
+        }
+    }
+
+    fn write_index(names: &[String]) -> Result<()> {
+        let index = entry(INDEX_ENTRY)?;
+        let result = if names.is_empty() {
+            match index.delete_credential() {
+                Err(Error::NoEntry) => Ok(()),
+                result => result,
+            }
+        } else {
+            index.set_password(&names.join("\n"))
+        };
+        result.map_err(|err| describe(INDEX_ENTRY, err))
+    }
+}
+
+#[cfg(feature = "keystore")]
+pub use backend::{delete_key, fetch_key, list, put_key};
+
+#[cfg(not(feature = "keystore"))]
+fn unsupported() -> anyhow::Error {
+    anyhow::anyhow!("the keystore needs a host built with the keystore feature")
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn fetch_key(_name: &str) -> Result<SecretKey> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn put_key(_name: &str, _key: &SecretKey) -> Result<bool> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn delete_key(_name: &str) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "keystore"))]
+pub fn list() -> Result<Vec<String>> {
+    Err(unsupported())
+}
+
+#[cfg(all(test, feature = "keystore"))]
+mod tests {
+    use super::*;
+    use keyring::credential::{Credential, CredentialApi, CredentialBuilderApi};
+    use keyring::Error;
+    use std::collections::HashMap;
+    use std::sync::{Mutex, MutexGuard, Once};
+
+    // In-memory keystore shared by every entry of the same name, which the
+    // keyring crate's mock store isn't. `locked` makes every call fail as a
+    // locked keyring does.
+    #[derive(Default)]
+    struct Store {
+        secrets: HashMap<String, Vec<u8>>,
+        locked: bool,
+    }
+
+    static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+    struct Builder;
+
+    impl CredentialBuilderApi for Builder {
+        fn build(
+            &self,
+            _target: Option<&str>,
+            service: &str,
+            user: &str,
+        ) -> keyring::Result<Box<Credential>> {
+            assert_eq!(service, SERVICE);
+            Ok(Box::new(Entry(user.to_string())))
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+    }
+
+    struct Entry(String);
+
+    impl Entry {
+        fn with<R>(
+            &self,
+            f: impl FnOnce(&mut HashMap<String, Vec<u8>>) -> R,
+        ) -> keyring::Result<R> {
+            let mut store = STORE.lock().unwrap_or_else(|err| err.into_inner());
+            let store = store.get_or_insert_with(Default::default);
+            if store.locked {
+                return Err(Error::NoStorageAccess("the keyring is locked".into()));
+            }
+            Ok(f(&mut store.secrets))
+        }
+    }
+
+    impl CredentialApi for Entry {
+        fn set_secret(&self, secret: &[u8]) -> keyring::Result<()> {
+            self.with(|secrets| {
+                secrets.insert(self.0.clone(), secret.to_vec());
+            })
+        }
+
+        fn get_secret(&self) -> keyring::Result<Vec<u8>> {
+            self.with(|secrets| secrets.get(&self.0).cloned())?
+                .ok_or(Error::NoEntry)
+        }
+
+        fn delete_credential(&self) -> keyring::Result<()> {
+            self.with(|secrets| secrets.remove(&self.0))?
+                .map(drop)
+                .ok_or(Error::NoEntry)
+        }
+
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+    }
+
+    // Tests share the store and its index entry, so they run one at a time
+    // on an empty store
+    fn empty_store() -> MutexGuard<'static, ()> {
+        static INSTALL: Once = Once::new();
+        static SERIAL: Mutex<()> = Mutex::new(());
+        INSTALL.call_once(|| keyring::set_default_credential_builder(Box::new(Builder)));
+        let guard = SERIAL.lock().unwrap_or_else(|err| err.into_inner());
+        *STORE.lock().unwrap() = Some(Store::default());
+        guard
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let _store = empty_store();
+        let key = SecretKey::new([7; 32]);
+        assert!(!put_key("lab", &key).unwrap());
+        assert!(put_key("lab", &SecretKey::new([8; 32])).unwrap());
+        assert!(!put_key("field", &key).unwrap());
+        assert_eq!(fetch_key("lab").unwrap().bytes(), &[8; 32]);
+        assert_eq!(list().unwrap(), ["lab", "field"]);
+
+        delete_key("lab").unwrap();
+        assert_eq!(list().unwrap(), ["field"]);
+        let err = fetch_key("lab").err().unwrap().to_string();
+        assert!(err.contains("no keystore entry \"lab\""), "{}", err);
+        assert!(delete_key("lab").is_err());
+        delete_key("field").unwrap();
+        assert!(list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn entries_that_arent_keys_are_refused() {
+        let _store = empty_store();
+        keyring::Entry::new(SERVICE, "short")
+            .unwrap()
+            .set_secret(&[1; 16])
+            .unwrap();
+        let err = fetch_key("short").err().unwrap().to_string();
+        assert!(
+            err.contains("holds 16 bytes, not a 32-byte AES key"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn locked_keyrings_read_apart_from_missing_keys() {
+        let _store = empty_store();
+        put_key("lab", &SecretKey::new([7; 32])).unwrap();
+        STORE.lock().unwrap().as_mut().unwrap().locked = true;
+        let err = fetch_key("lab").err().unwrap().to_string();
+        assert!(err.contains("is it locked?"), "{}", err);
+        assert!(!err.contains("no keystore entry"), "{}", err);
+        assert!(list().is_err());
+    }
+
+    #[test]
+    fn invalid_names_are_refused() {
+        let _store = empty_store();
+        for name in ["", ".index", "two\nlines"] {
+            let err = fetch_key(name).err().unwrap().to_string();
+            assert!(err.contains("invalid keystore entry name"), "{}", err);
+            assert!(put_key(name, &SecretKey::new([7; 32])).is_err());
+        }
+    }
+
+    #[test]
+    fn secret_keys_are_wiped() {
+        let mut bytes = [0xa5; 32];
+        wipe(&mut bytes);
+        assert_eq!(bytes, [0; 32]);
+    }
+}
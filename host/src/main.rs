@@ -15,14 +15,70 @@
 // specific language governing permissions and limitations
 // under the License.
 
+#[cfg(feature = "encrypt-model")]
+mod backend;
+mod batch_file;
+mod cache;
 mod commands;
+mod date;
+mod diff;
+mod doctor;
+#[cfg(feature = "net")]
+mod download;
+mod formats;
+mod input;
+mod keystore;
+mod metrics;
+#[cfg(feature = "encrypt-model")]
+mod pipeline;
+mod progress;
+mod recorded_run;
+mod registry;
+#[cfg(feature = "encrypt-model")]
+mod sim;
+mod source;
+mod spool;
+mod ta_call;
 mod tee;
+#[cfg(feature = "encrypt-model")]
+mod training;
+mod transcript;
+mod upload;
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use transcript::Step;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    /// UUID of the inference TA instance, overriding the compiled-in one
+    #[arg(long, global = true, env = "ENC_MNIST_TA_UUID")]
+    ta_uuid: Option<String>,
+    /// Model registry of `model` and --model-name, instead of
+    /// ~/.enc-mnist/registry.json
+    #[arg(long, global = true, env = "ENC_MNIST_REGISTRY")]
+    registry: Option<std::path::PathBuf>,
+    /// Append a JSONL log of every TA command and provisioning step to this
+    /// file; keys and model data appear as fingerprints only
+    #[arg(long, global = true)]
+    transcript: Option<std::path::PathBuf>,
+    /// Id tagging this invocation's transcript lines, errors and TA log lines,
+    /// in hex; a random one is used when not given, 0 for none
+    #[arg(long, global = true, value_parser = transcript::parse_trace_id)]
+    trace_id: Option<u64>,
+    /// Serve Prometheus metrics of the inference requests on this address
+    /// while the command runs
+    #[cfg(feature = "metrics")]
+    #[arg(long, global = true)]
+    metrics_addr: Option<std::net::SocketAddr>,
+    /// Size of the encrypted chunks pushed to the TA, a multiple of 16; by
+    /// default the size the TA asks for. Capped at the TA's maximum and
+    /// halved while the transport reports Communication/Busy errors
+    #[arg(long, global = true, value_parser = upload::parse_chunk_size)]
+    chunk_size: Option<usize>,
+    /// Pause between encrypted chunks, for transports that can't keep up
+    #[arg(long, global = true, default_value_t = 0)]
+    throttle_ms: u64,
     #[command(subcommand)]
     command: Commands,
 }
@@ -30,22 +86,147 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     Infer(commands::infer::Args),
+    Evaluate(commands::evaluate::Args),
+    DiffModels(commands::diff_models::Args),
     #[cfg(feature = "encrypt-model")]
     EncryptModel(commands::encrypt::Args),
     StoreKey(commands::store_key::Args),
+    RotateKey(commands::rotate_key::Args),
+    SetVerifyKey(commands::set_verify_key::Args),
+    ManagePolicy(commands::manage_policy::Args),
+    ProvisionLine(commands::provision_line::Args),
+    Keystore(commands::keystore::Args),
+    Storage(commands::storage::Args),
+    Stats(commands::stats::Args),
+    Status(commands::status::Args),
+    Rollback(commands::rollback::Args),
+    Provision(commands::provision::Args),
+    ExportModel(commands::export_model::Args),
+    Commit(commands::commit::Args),
+    DiscardStaged(commands::discard_staged::Args),
+    LastCrash(commands::last_crash::Args),
+    Residency(commands::residency::Args),
+    Shadow(commands::shadow::Args),
+    ModelHistory(commands::model_history::Args),
+    Model(commands::model::Args),
+    Selftest(commands::selftest::Args),
+    SupportBundle(commands::support_bundle::Args),
+    Doctor(commands::doctor::Args),
+    Replay(commands::replay::Args),
+    Watch(commands::watch::Args),
     #[cfg(feature = "encrypt-model")]
     VerifyModel(commands::verify_model::Args),
+    #[cfg(feature = "encrypt-model")]
+    Inspect(commands::inspect::Args),
+    #[cfg(feature = "encrypt-model")]
+    Train(commands::train::Args),
+    #[cfg(feature = "encrypt-model")]
+    Patch(commands::patch::Args),
 }
 
 fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    if let Some(uuid) = &cli.ta_uuid {
+        tee::set_inference_ta_uuid(uuid)?;
+    }
+    if let Some(path) = &cli.registry {
+        registry::set_path(path)?;
+    }
+    upload::set_options(upload::UploadOptions {
+        chunk_size: cli.chunk_size,
+        throttle: std::time::Duration::from_millis(cli.throttle_ms),
+    })?;
+    transcript::set_trace_id(cli.trace_id.unwrap_or_else(rand::random))?;
+    if let Some(path) = &cli.transcript {
+        transcript::open(path)?;
+        transcript::record(Step::Started {
+            command: matches.subcommand_name().unwrap_or_default(),
+            version: env!("CARGO_PKG_VERSION"),
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(addr) = cli.metrics_addr {
+        metrics::serve(addr)?;
+    }
+
+    let result = run(cli.command).map_err(tee::attach_error_detail);
+    if let Err(err) = &result {
+        tee::report_last_crash(err);
+        tee::report_model_error(err);
+        doctor::report_device_access(err);
+        if let Some(id) = transcript::trace_id() {
+            println!("trace id: {}", transcript::format_trace_id(id));
+        }
+    }
+    transcript::record(Step::Finished {
+        error: result.as_ref().err().map(|err| format!("{:#}", err)),
+    });
+    result
+}
 
-    match cli.command {
+fn run(command: Commands) -> anyhow::Result<()> {
+    match command {
         Commands::Infer(args) => commands::infer::execute(&args),
+        Commands::Evaluate(args) => commands::evaluate::execute(&args),
+        Commands::DiffModels(args) => commands::diff_models::execute(&args),
         #[cfg(feature = "encrypt-model")]
         Commands::EncryptModel(args) => commands::encrypt::execute(&args),
         Commands::StoreKey(args) => commands::store_key::execute(&args),
+        Commands::RotateKey(args) => commands::rotate_key::execute(&args),
+        Commands::SetVerifyKey(args) => commands::set_verify_key::execute(&args),
+        Commands::ManagePolicy(args) => commands::manage_policy::execute(&args),
+        Commands::ProvisionLine(args) => commands::provision_line::execute(&args),
+        Commands::Keystore(args) => commands::keystore::execute(&args),
+        Commands::Storage(args) => commands::storage::execute(&args),
+        Commands::Stats(args) => commands::stats::execute(&args),
+        Commands::Status(args) => commands::status::execute(&args),
+        Commands::Rollback(args) => commands::rollback::execute(&args),
+        Commands::Provision(args) => commands::provision::execute(&args),
+        Commands::ExportModel(args) => commands::export_model::execute(&args),
+        Commands::Commit(args) => commands::commit::execute(&args),
+        Commands::DiscardStaged(args) => commands::discard_staged::execute(&args),
+        Commands::LastCrash(args) => commands::last_crash::execute(&args),
+        Commands::Residency(args) => commands::residency::execute(&args),
+        Commands::Shadow(args) => commands::shadow::execute(&args),
+        Commands::ModelHistory(args) => commands::model_history::execute(&args),
+        Commands::Model(args) => commands::model::execute(&args),
+        Commands::Selftest(args) => commands::selftest::execute(&args),
+        Commands::SupportBundle(args) => commands::support_bundle::execute(&args),
+        Commands::Doctor(args) => commands::doctor::execute(&args),
+        Commands::Replay(args) => commands::replay::execute(&args),
+        Commands::Watch(args) => commands::watch::execute(&args),
         #[cfg(feature = "encrypt-model")]
         Commands::VerifyModel(args) => commands::verify_model::execute(&args),
+        #[cfg(feature = "encrypt-model")]
+        Commands::Inspect(args) => commands::inspect::execute(&args),
+        #[cfg(feature = "encrypt-model")]
+        Commands::Train(args) => commands::train::execute(&args),
+        #[cfg(feature = "encrypt-model")]
+        Commands::Patch(args) => commands::patch::execute(&args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The only test touching ENC_MNIST_TA_UUID
+    #[test]
+    fn ta_uuid_flag_beats_the_environment() {
+        let uuid = |args: &[&str]| {
+            let args = ["enc_mnist-rs"].iter().chain(args).chain(&["status"]);
+            Cli::try_parse_from(args).unwrap().ta_uuid
+        };
+        std::env::remove_var("ENC_MNIST_TA_UUID");
+        assert_eq!(uuid(&[]), None);
+        std::env::set_var("ENC_MNIST_TA_UUID", "from-env");
+        assert_eq!(uuid(&[]).as_deref(), Some("from-env"));
+        assert_eq!(
+            uuid(&["--ta-uuid", "from-flag"]).as_deref(),
+            Some("from-flag")
+        );
+        std::env::remove_var("ENC_MNIST_TA_UUID");
     }
 }
@@ -0,0 +1,344 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Inference counters and latency histograms, rendered in the Prometheus text
+// format. Recording is always on (a few atomic adds per request); the HTTP
+// listener that exposes them is behind the `metrics` feature.
+//
+// Metric names are part of the interface, keep them stable:
+//   enc_mnist_requests_total                 inference requests made by the host
+//   enc_mnist_images_total                   images in those requests
+//   enc_mnist_errors_total{kind}             failed requests by `ErrorKind`
+//   enc_mnist_request_duration_seconds       end-to-end request latency
+//   enc_mnist_ta_invoke_duration_seconds     latency of single TA invocations
+
+use std::collections::BTreeMap;
+#[cfg(feature = "metrics")]
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use proto::inference::DurationMicros;
+
+// Upper bounds in seconds; TA calls range from sub-millisecond single
+// images to seconds for large batches on slow boards
+const BUCKETS: [f64; 11] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static IMAGES: AtomicU64 = AtomicU64::new(0);
+static ERRORS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static REQUEST_DURATION: Histogram = Histogram::new();
+static TA_INVOKE_DURATION: Histogram = Histogram::new();
+
+struct Histogram {
+    // Per bucket, not cumulative; the last one counts values above BUCKETS
+    counts: [AtomicU64; BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            counts: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let elapsed = DurationMicros::from(elapsed);
+        let seconds = elapsed.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros(), Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "metrics")]
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = match BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let sum =
+            DurationMicros::from_micros(self.sum_micros.load(Ordering::Relaxed)).as_secs_f64();
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+/// Runs one inference request of `images` images, counting and timing it.
+pub fn measure_request<T>(
+    images: usize,
+    request: impl FnOnce() -> optee_teec::Result<T>,
+) -> optee_teec::Result<T> {
+    let start = Instant::now();
+    let result = request();
+    REQUEST_DURATION.observe(start.elapsed());
+    REQUESTS.fetch_add(1, Ordering::Relaxed);
+    IMAGES.fetch_add(images as u64, Ordering::Relaxed);
+    if let Err(err) = &result {
+        let mut errors = ERRORS.lock().unwrap_or_else(|err| err.into_inner());
+        *errors.entry(format!("{:?}", err.kind())).or_default() += 1;
+    }
+    result
+}
+
+/// Records the latency of one TA invocation.
+pub fn observe_ta_invoke(elapsed: Duration) {
+    TA_INVOKE_DURATION.observe(elapsed);
+}
+
+/// Current values in the Prometheus text exposition format.
+#[cfg(feature = "metrics")]
+pub fn render() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP enc_mnist_requests_total Inference requests.");
+    let _ = writeln!(out, "# TYPE enc_mnist_requests_total counter");
+    let _ = writeln!(
+        out,
+        "enc_mnist_requests_total {}",
+        REQUESTS.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP enc_mnist_images_total Images in inference requests."
+    );
+    let _ = writeln!(out, "# TYPE enc_mnist_images_total counter");
+    let _ = writeln!(
+        out,
+        "enc_mnist_images_total {}",
+        IMAGES.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "# HELP enc_mnist_errors_total Failed inference requests by error kind."
+    );
+    let _ = writeln!(out, "# TYPE enc_mnist_errors_total counter");
+    for (kind, count) in ERRORS.lock().unwrap_or_else(|err| err.into_inner()).iter() {
+        let _ = writeln!(out, "enc_mnist_errors_total{{kind=\"{}\"}} {}", kind, count);
+    }
+    REQUEST_DURATION.render(
+        &mut out,
+        "enc_mnist_request_duration_seconds",
+        "End-to-end latency of inference requests.",
+    );
+    TA_INVOKE_DURATION.render(
+        &mut out,
+        "enc_mnist_ta_invoke_duration_seconds",
+        "Latency of single TA invocations.",
+    );
+    out
+}
+
+/// Serves `render()` over HTTP on `addr` from a background thread for the
+/// rest of the process, so it can be scraped while requests are running.
+/// Returns the address listened on, which tells the port when `addr` has 0.
+#[cfg(feature = "metrics")]
+pub fn serve(addr: std::net::SocketAddr) -> anyhow::Result<std::net::SocketAddr> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind(addr)
+        .map_err(|err| anyhow::anyhow!("cannot listen on {}: {}", addr, err))?;
+    let addr = listener.local_addr()?;
+    println!("Serving metrics on http://{}/metrics", addr);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                continue;
+            };
+            // Any path gets the metrics. The request is read up to the blank
+            // line ending its headers, so closing doesn't cut the client off
+            // while it is still sending them.
+            let mut request = BufReader::new(&stream);
+            let mut line = String::new();
+            while request.read_line(&mut line).is_ok_and(|read| read > 0) && line != "\r\n" {
+                line.clear();
+            }
+            let body = render();
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        }
+    });
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "metrics")]
+    use std::io::{Read, Write};
+    #[cfg(feature = "metrics")]
+    use std::net::TcpStream;
+
+    #[cfg(feature = "metrics")]
+    use optee_teec::ErrorKind;
+
+    fn counts(histogram: &Histogram) -> Vec<u64> {
+        histogram
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    #[test]
+    fn latencies_land_in_the_bucket_bounding_them() {
+        let histogram = Histogram::new();
+        // Bounds are inclusive, and microseconds are whole before comparing
+        histogram.observe(Duration::from_micros(1000));
+        histogram.observe(Duration::from_nanos(1_000_900));
+        histogram.observe(Duration::from_micros(1001));
+        histogram.observe(Duration::from_millis(100));
+        histogram.observe(Duration::from_millis(2500));
+        histogram.observe(Duration::from_secs(3));
+        let mut expected = vec![0; BUCKETS.len() + 1];
+        expected[0] = 2;
+        expected[1] = 1;
+        expected[6] = 1;
+        expected[10] = 1;
+        expected[11] = 1;
+        assert_eq!(counts(&histogram), expected);
+        assert_eq!(
+            histogram.sum_micros.load(Ordering::Relaxed),
+            1000 + 1000 + 1001 + 100_000 + 2_500_000 + 3_000_000
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn histograms_render_cumulative_buckets_in_seconds() {
+        let histogram = Histogram::new();
+        for ms in [2, 2, 40, 5000] {
+            histogram.observe(Duration::from_millis(ms));
+        }
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "Test latency.");
+        let rendered: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            rendered[..2],
+            ["# HELP latency Test latency.", "# TYPE latency histogram"]
+        );
+        assert_eq!(
+            rendered[2..],
+            [
+                "latency_bucket{le=\"0.001\"} 0",
+                "latency_bucket{le=\"0.0025\"} 2",
+                "latency_bucket{le=\"0.005\"} 2",
+                "latency_bucket{le=\"0.01\"} 2",
+                "latency_bucket{le=\"0.025\"} 2",
+                "latency_bucket{le=\"0.05\"} 3",
+                "latency_bucket{le=\"0.1\"} 3",
+                "latency_bucket{le=\"0.25\"} 3",
+                "latency_bucket{le=\"0.5\"} 3",
+                "latency_bucket{le=\"1\"} 3",
+                "latency_bucket{le=\"2.5\"} 3",
+                "latency_bucket{le=\"+Inf\"} 4",
+                "latency_sum 5.044",
+                "latency_count 4",
+            ]
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    fn scrape(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let request = format!("GET /metrics HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+        assert!(
+            head.contains(&format!("Content-Length: {}", body.len())),
+            "{}",
+            head
+        );
+        body.to_string()
+    }
+
+    // Value of the sample `name` (with its labels), 0 if it isn't there yet
+    #[cfg(feature = "metrics")]
+    fn sample(metrics: &str, name: &str) -> f64 {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .map_or(0.0, |value| value.parse().unwrap())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn scrapes_show_the_requests_made() {
+        let addr = serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let before = scrape(addr);
+
+        measure_request(3, || Ok(())).unwrap();
+        measure_request(5, || Ok(())).unwrap();
+        measure_request(1, || Err::<(), _>(optee_teec::Error::new(ErrorKind::Busy))).unwrap_err();
+        observe_ta_invoke(Duration::from_millis(2));
+
+        let after = scrape(addr);
+        // Other tests may count requests too, so only a lower bound holds
+        let moved = |name: &str| sample(&after, name) - sample(&before, name);
+        assert!(moved("enc_mnist_requests_total") >= 3.0, "{}", after);
+        assert!(moved("enc_mnist_images_total") >= 9.0, "{}", after);
+        assert!(
+            moved("enc_mnist_errors_total{kind=\"Busy\"}") >= 1.0,
+            "{}",
+            after
+        );
+        assert!(
+            moved("enc_mnist_request_duration_seconds_count") >= 3.0,
+            "{}",
+            after
+        );
+        assert!(
+            moved("enc_mnist_ta_invoke_duration_seconds_count") >= 1.0,
+            "{}",
+            after
+        );
+        // 2ms is past the 1ms bucket but within the 2.5ms one
+        assert!(
+            moved("enc_mnist_ta_invoke_duration_seconds_bucket{le=\"0.0025\"}") >= 1.0,
+            "{}",
+            after
+        );
+        assert!(
+            moved("enc_mnist_ta_invoke_duration_seconds_sum") >= 0.001,
+            "{}",
+            after
+        );
+    }
+}
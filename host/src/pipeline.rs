@@ -0,0 +1,220 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Encryption of a plaintext model overlapped with pushing it, for
+// `provision --plain`. A producer thread frames and CBC-encrypts the model a
+// batch at a time while the caller's thread pushes the previous batches;
+// the channel between them holds at most PIPELINE_DEPTH batches, so memory
+// stays flat whatever the model size. The ciphertext is the one
+// `encrypt-model` writes (IV || CBC(frame)), pushed in order.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aes::Aes256;
+use cbc::cipher::generic_array::GenericArray;
+use cbc::cipher::{BlockEncryptMut, KeyIvInit};
+use rand::RngCore;
+
+use crate::tee::InferenceTa;
+use crate::upload::Pusher;
+
+type Aes256CbcEnc = cbc::Encryptor<Aes256>;
+
+const IV_SIZE: usize = 16;
+// Plaintext encrypted per batch; a multiple of the AES block size
+const BATCH_SIZE: usize = 256 * 1024;
+// Encrypted batches waiting to be pushed
+const PIPELINE_DEPTH: usize = 2;
+
+/// Time spent encrypting, pushing, and in total; the first two add up to
+/// more than the last by what the pipeline overlapped.
+pub struct PipelineStats {
+    pub encrypt: Duration,
+    pub push: Duration,
+    pub wall: Duration,
+}
+
+impl PipelineStats {
+    /// Time the serial encrypt-then-push would have taken on top.
+    pub fn saved(&self) -> Duration {
+        (self.encrypt + self.push).saturating_sub(self.wall)
+    }
+}
+
+/// Size of the ciphertext of a `len` byte model.
+pub fn encrypted_len(len: usize) -> anyhow::Result<usize> {
+    proto::framing::framed_len(len)
+        .map(|framed| IV_SIZE + framed)
+        .ok_or_else(|| anyhow::anyhow!("model too large to encrypt ({} bytes)", len))
+}
+
+/// Encrypts `payload` with `key` and pushes it into the load started with
+/// `begin_model_load`. When either side fails the other stops at its next
+/// batch; nothing is finalized, and the TA drops the pushed chunks when its
+/// last session closes.
+pub fn encrypt_and_push(
+    caller: &mut dyn InferenceTa,
+    key: &[u8; 32],
+    payload: &[u8],
+) -> anyhow::Result<PipelineStats> {
+    let total = encrypted_len(payload.len())?;
+    let chunks = proto::framing::frame_chunks(payload, BATCH_SIZE)
+        .ok_or_else(|| anyhow::anyhow!("model too large to encrypt ({} bytes)", payload.len()))?;
+    let mut iv = [0u8; IV_SIZE];
+    rand::rng().fill_bytes(&mut iv);
+    let cancelled = AtomicBool::new(false);
+    let started = Instant::now();
+
+    let (encrypt, pushed) = thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let cancelled = &cancelled;
+        let producer = scope.spawn(move || {
+            let mut encryptor = Aes256CbcEnc::new(key.into(), (&iv).into());
+            let mut busy = Duration::ZERO;
+            // The IV leads the ciphertext, as in `encrypt_with_key_host`
+            let mut batch = iv.to_vec();
+            for mut chunk in chunks {
+                if cancelled.load(Ordering::Relaxed) {
+                    break;
+                }
+                let start = Instant::now();
+                for block in chunk.chunks_exact_mut(IV_SIZE) {
+                    encryptor.encrypt_block_mut(GenericArray::from_mut_slice(block));
+                }
+                batch.extend_from_slice(&chunk);
+                busy += start.elapsed();
+                // Fails once the pushing side gave up and dropped the receiver
+                if sender.send(std::mem::take(&mut batch)).is_err() {
+                    break;
+                }
+            }
+            busy
+        });
+        let pushed = push_batches(caller, receiver, total);
+        if pushed.is_err() {
+            cancelled.store(true, Ordering::Relaxed);
+        }
+        let encrypt = producer
+            .join()
+            .map_err(|_| anyhow::anyhow!("encryption thread panicked"));
+        (encrypt, pushed)
+    });
+    let push = pushed?;
+    Ok(PipelineStats {
+        encrypt: encrypt?,
+        push,
+        wall: started.elapsed(),
+    })
+}
+
+// Pushes the batches in the order they arrive and returns the time spent
+// pushing; fails unless `total` bytes arrive
+fn push_batches(
+    caller: &mut dyn InferenceTa,
+    receiver: Receiver<Vec<u8>>,
+    total: usize,
+) -> anyhow::Result<Duration> {
+    let mut pusher = Pusher::new(caller, Some(total));
+    let mut pending = Vec::new();
+    let mut received = 0;
+    let mut busy = Duration::ZERO;
+    for batch in receiver {
+        received += batch.len();
+        pending.extend_from_slice(&batch);
+        let start = Instant::now();
+        let pushed = pusher.push(&pending, received == total)?;
+        busy += start.elapsed();
+        pending.drain(..pushed);
+    }
+    anyhow::ensure!(
+        received == total,
+        "encryption stopped after {} of {} bytes",
+        received,
+        total
+    );
+    Ok(busy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::encrypt::decrypt_with_key_host;
+    use crate::upload::MockTransport;
+    use optee_teec::ErrorKind;
+
+    const KEY: [u8; 32] = [0x5a; 32];
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 + i / 509) as u8).collect()
+    }
+
+    #[test]
+    fn pushed_ciphertext_decrypts_to_the_model() {
+        // Batches of whole chunks, a last short one, and a model smaller than
+        // one batch
+        for len in [3 * BATCH_SIZE + 1000, 5000, 0] {
+            let payload = payload(len);
+            let mut transport = MockTransport::new(usize::MAX, ErrorKind::Communication);
+            encrypt_and_push(&mut transport, &KEY, &payload).unwrap();
+            assert_eq!(transport.received.len(), encrypted_len(len).unwrap());
+            assert_eq!(
+                decrypt_with_key_host(&KEY, &transport.received).unwrap(),
+                payload
+            );
+        }
+    }
+
+    #[test]
+    fn encryption_overlaps_slow_pushes() {
+        let payload = payload(8 * BATCH_SIZE);
+        let mut transport = MockTransport::new(usize::MAX, ErrorKind::Communication);
+        transport.latency = Duration::from_millis(5);
+        let stats = encrypt_and_push(&mut transport, &KEY, &payload).unwrap();
+        assert!(stats.push >= transport.latency * transport.pushes.len() as u32);
+        // Serially the wall clock would be the sum of the two
+        assert!(stats.wall < stats.encrypt + stats.push);
+        assert!(stats.saved() > Duration::ZERO);
+        assert_eq!(
+            decrypt_with_key_host(&KEY, &transport.received).unwrap(),
+            payload
+        );
+    }
+
+    #[test]
+    fn push_failures_stop_the_encryption() {
+        let payload = payload(64 * BATCH_SIZE);
+        let mut transport = MockTransport::new(0, ErrorKind::BadParameters);
+        let started = Instant::now();
+        assert!(encrypt_and_push(&mut transport, &KEY, &payload).is_err());
+        let failed = started.elapsed();
+        assert_eq!(transport.pushes.len(), 1);
+        assert!(transport.received.is_empty());
+        // The producer gave up within a batch or two instead of encrypting
+        // all 64
+        let mut transport = MockTransport::new(usize::MAX, ErrorKind::BadParameters);
+        let stats = encrypt_and_push(&mut transport, &KEY, &payload).unwrap();
+        assert!(
+            failed < stats.encrypt / 4,
+            "{:?} {:?}",
+            failed,
+            stats.encrypt
+        );
+    }
+}
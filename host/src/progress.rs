@@ -0,0 +1,278 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Progress of long operations: pushing and downloading models, evaluating,
+// training. On a terminal it is one line redrawn in place; when stdout is
+// not a terminal (or TERM is dumb) it is a plain line every LOG_STEP percent,
+// or every LOG_INTERVAL without a known total, with no control characters.
+
+use std::io::{IsTerminal, Stdout, Write};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+// Least time between two redraws of the bar
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const LOG_STEP: u64 = 10;
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+pub trait Progress {
+    /// Adds `n` units (bytes, images) to what is done.
+    fn tick(&mut self, n: u64);
+    /// Prints `message` on a line of its own.
+    fn message(&mut self, message: &str);
+    /// Prints the final state; later ticks are not shown.
+    fn finish(&mut self);
+}
+
+/// Starts reporting `label`, counted in `unit`, out of `total` when known.
+pub fn start(label: &str, unit: &'static str, total: Option<u64>) -> Box<dyn Progress> {
+    let counter = Counter {
+        label: label.to_string(),
+        unit,
+        done: 0,
+        total,
+    };
+    let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+    if std::io::stdout().is_terminal() && !dumb {
+        Box::new(Bar {
+            counter,
+            drawn: None,
+            finished: false,
+        })
+    } else {
+        Box::new(Log::new(counter, std::io::stdout()))
+    }
+}
+
+struct Counter {
+    label: String,
+    unit: &'static str,
+    done: u64,
+    total: Option<u64>,
+}
+
+impl Counter {
+    fn percent(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(100.0),
+            Some(total) => Some((self.done.min(total) as f64 * 100.0) / total as f64),
+            None => None,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match (self.total, self.percent()) {
+            (Some(total), Some(percent)) => format!(
+                "{}: {}/{} {} ({:.0}%)",
+                self.label, self.done, total, self.unit, percent
+            ),
+            _ => format!("{}: {} {}", self.label, self.done, self.unit),
+        }
+    }
+}
+
+// Redraws one line with `\r`; only used on terminals
+struct Bar {
+    counter: Counter,
+    drawn: Option<Instant>,
+    finished: bool,
+}
+
+impl Bar {
+    fn draw(&mut self) {
+        let bar = match self.counter.percent() {
+            Some(percent) => {
+                let filled = (percent / 100.0 * BAR_WIDTH as f64) as usize;
+                let empty = BAR_WIDTH - filled;
+                format!("[{}{}] ", "#".repeat(filled), "-".repeat(empty))
+            }
+            None => String::new(),
+        };
+        // Clears what a longer previous line left behind
+        print!("\r\x1b[K{}{}", bar, self.counter.describe());
+        let _ = std::io::stdout().flush();
+        self.drawn = Some(Instant::now());
+    }
+}
+
+impl Progress for Bar {
+    fn tick(&mut self, n: u64) {
+        self.counter.done += n;
+        let complete = self.counter.total == Some(self.counter.done);
+        let due = self.drawn.is_none_or(|at| at.elapsed() >= REDRAW_INTERVAL);
+        if !self.finished && (complete || due) {
+            self.draw();
+        }
+    }
+
+    fn message(&mut self, message: &str) {
+        if self.finished || self.drawn.is_none() {
+            println!("{}", message);
+            return;
+        }
+        print!("\r\x1b[K");
+        println!("{}", message);
+        self.draw();
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            self.draw();
+            println!();
+            self.finished = true;
+        }
+    }
+}
+
+// Plain lines into `out`, stdout outside the tests
+struct Log<W: Write = Stdout> {
+    counter: Counter,
+    // Percentage at which the next line is printed
+    next_step: u64,
+    logged: Instant,
+    logged_done: u64,
+    finished: bool,
+    out: W,
+}
+
+impl<W: Write> Log<W> {
+    fn new(counter: Counter, out: W) -> Self {
+        Self {
+            counter,
+            next_step: LOG_STEP,
+            logged: Instant::now(),
+            logged_done: 0,
+            finished: false,
+            out,
+        }
+    }
+
+    fn log(&mut self) {
+        let _ = writeln!(self.out, "{}", self.counter.describe());
+        self.logged = Instant::now();
+        self.logged_done = self.counter.done;
+    }
+}
+
+impl<W: Write> Progress for Log<W> {
+    fn tick(&mut self, n: u64) {
+        self.counter.done += n;
+        if self.finished {
+            return;
+        }
+        let due = match self.counter.percent() {
+            Some(percent) if percent >= self.next_step as f64 => {
+                while self.next_step as f64 <= percent {
+                    self.next_step += LOG_STEP;
+                }
+                true
+            }
+            Some(_) => false,
+            None => self.logged.elapsed() >= LOG_INTERVAL,
+        };
+        if due {
+            self.log();
+        }
+    }
+
+    fn message(&mut self, message: &str) {
+        let _ = writeln!(self.out, "{}", message);
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            if self.logged_done != self.counter.done || self.counter.done == 0 {
+                self.log();
+            }
+            self.finished = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(total: Option<u64>) -> Log<Vec<u8>> {
+        let counter = Counter {
+            label: "Pushing encrypted model".to_string(),
+            unit: "bytes",
+            done: 0,
+            total,
+        };
+        Log::new(counter, Vec::new())
+    }
+
+    fn lines(log: &Log<Vec<u8>>) -> Vec<&str> {
+        let text = std::str::from_utf8(&log.out).unwrap();
+        // Plain lines only: no carriage returns, escapes or other controls
+        assert!(
+            text.chars().all(|c| c == '\n' || !c.is_control()),
+            "{:?}",
+            text
+        );
+        text.lines().collect()
+    }
+
+    #[test]
+    fn logs_are_plain_lines_every_step() {
+        let mut progress = log(Some(1000));
+        for _ in 0..40 {
+            progress.tick(25);
+        }
+        progress.finish();
+        let lines = lines(&progress);
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "Pushing encrypted model: 100/1000 bytes (10%)");
+        assert_eq!(lines[9], "Pushing encrypted model: 1000/1000 bytes (100%)");
+    }
+
+    #[test]
+    fn large_ticks_print_one_line() {
+        let mut progress = log(Some(1000));
+        progress.tick(450);
+        progress.message("Push at offset 450 failed, retrying");
+        progress.tick(550);
+        progress.finish();
+        // Ticks past `finish` are counted but not shown
+        progress.tick(10);
+        progress.finish();
+        assert_eq!(
+            lines(&progress),
+            [
+                "Pushing encrypted model: 450/1000 bytes (45%)",
+                "Push at offset 450 failed, retrying",
+                "Pushing encrypted model: 1000/1000 bytes (100%)",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_and_empty_totals() {
+        let mut progress = log(None);
+        progress.tick(300);
+        progress.finish();
+        assert_eq!(lines(&progress), ["Pushing encrypted model: 300 bytes"]);
+
+        let mut progress = log(Some(0));
+        progress.finish();
+        assert_eq!(
+            lines(&progress),
+            ["Pushing encrypted model: 0/0 bytes (100%)"]
+        );
+    }
+}
@@ -0,0 +1,188 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Bundles written by `infer --record-run` and re-run by `replay`: the image
+// bytes sent to the TA, one `.bin` per input, and `run.json` with the
+// settings of the run and what the TA answered for every input, including
+// its hash of the input tensor it ran.
+
+use std::path::Path;
+
+use proto::inference::{self, ModelStatus, Normalization, INPUT_HASH_SIZE};
+use proto::Image;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::tee::RecordedBatch;
+
+/// Name of the manifest in a bundle directory.
+pub const MANIFEST: &str = "run.json";
+const BUNDLE_VERSION: u32 = 1;
+
+/// How the inputs of a run were sent; `replay` sends them the same way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct RunSettings {
+    pub slot: u32,
+    /// `FLAG_RAW_SCALE` / `FLAG_NO_NORMALIZE`, 0 for the model's profile
+    pub input_flags: u32,
+    pub temperature: f32,
+    pub reject_below: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecordedRun {
+    pub version: u32,
+    pub model_name: Option<String>,
+    pub num_classes: usize,
+    /// Profile of the model; the inputs got it unless `input_flags` say
+    /// otherwise
+    pub normalization: Normalization,
+    #[serde(flatten)]
+    pub settings: RunSettings,
+    pub entries: Vec<RecordedInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordedInput {
+    /// Where the input was loaded from
+    pub input: String,
+    /// File in the bundle holding the image bytes sent to the TA
+    pub file: String,
+    /// SHA-256 of the input tensor the host's preprocessing gives
+    /// (`inference::input_tensor_bytes`), hex
+    pub expected_hash: String,
+    /// SHA-256 the TA reported for the input tensor it ran, hex
+    pub input_hash: String,
+    pub label: u8,
+    pub confidence: f32,
+    /// Most probable class, differs from `label` for rejected images
+    pub candidate: u8,
+    pub probabilities: Vec<f32>,
+}
+
+impl RecordedRun {
+    /// Pairs the result of `InferenceTa::infer_recorded` with the inputs it
+    /// answers, named by `names`.
+    pub fn new(
+        status: &ModelStatus,
+        settings: RunSettings,
+        names: &[String],
+        images: &[Image],
+        batch: RecordedBatch,
+    ) -> anyhow::Result<Self> {
+        let count = images.len();
+        anyhow::ensure!(
+            names.len() == count
+                && batch.predictions.len() == count
+                && batch.input_hashes.len() == count
+                && batch.probabilities.len() == count * status.num_classes,
+            "the TA's answer doesn't cover the {} input(s)",
+            count
+        );
+        let normalization =
+            inference::input_normalization(settings.input_flags, &status.normalization);
+        let entries = (0..count)
+            .map(|i| {
+                let prediction = &batch.predictions[i];
+                RecordedInput {
+                    input: names[i].clone(),
+                    file: format!("{:05}.bin", i),
+                    expected_hash: expected_hash(&images[i], &normalization),
+                    input_hash: hex(&batch.input_hashes[i]),
+                    label: prediction.label,
+                    confidence: prediction.confidence(),
+                    candidate: prediction.candidate,
+                    probabilities: batch.probabilities
+                        [i * status.num_classes..(i + 1) * status.num_classes]
+                        .to_vec(),
+                }
+            })
+            .collect();
+        Ok(Self {
+            version: BUNDLE_VERSION,
+            model_name: status.name.clone(),
+            num_classes: status.num_classes,
+            normalization: status.normalization,
+            settings,
+            entries,
+        })
+    }
+
+    /// Inputs the TA ran with a different tensor than the host's
+    /// preprocessing gives, a sign that the two normalize differently.
+    pub fn drifted(&self) -> impl Iterator<Item = &RecordedInput> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.expected_hash != entry.input_hash)
+    }
+
+    /// Writes the bundle into `dir`, which must be new or empty.
+    pub fn write(&self, dir: &Path, images: &[Image]) -> anyhow::Result<()> {
+        std::fs::create_dir_all(dir)?;
+        anyhow::ensure!(
+            std::fs::read_dir(dir)?.next().is_none(),
+            "{} is not empty",
+            dir.display()
+        );
+        for (entry, image) in self.entries.iter().zip(images) {
+            std::fs::write(dir.join(&entry.file), image.as_bytes())?;
+        }
+        std::fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Reads a bundle written by [`Self::write`] and the images it holds.
+    pub fn read(dir: &Path) -> anyhow::Result<(Self, Vec<Image>)> {
+        let run: Self = serde_json::from_slice(&std::fs::read(dir.join(MANIFEST))?)?;
+        anyhow::ensure!(
+            run.version == BUNDLE_VERSION,
+            "unsupported bundle version {}",
+            run.version
+        );
+        let mut images = Vec::with_capacity(run.entries.len());
+        for entry in &run.entries {
+            // Only files inside the bundle
+            anyhow::ensure!(
+                Path::new(&entry.file).file_name() == Some(entry.file.as_ref()),
+                "invalid file name in bundle: {}",
+                entry.file
+            );
+            let bytes = std::fs::read(dir.join(&entry.file))?;
+            let image = Image::from_bytes(&bytes)
+                .map_err(|err| anyhow::anyhow!("{}: {}", entry.file, err))?;
+            images.push(image);
+        }
+        Ok((run, images))
+    }
+}
+
+/// Hex SHA-256 of the input tensor `image` becomes under `normalization`.
+pub fn expected_hash(image: &Image, normalization: &Normalization) -> String {
+    hex(&Sha256::digest(inference::input_tensor_bytes(
+        image,
+        normalization,
+    )))
+}
+
+/// Hex form of a TA-reported input hash.
+pub fn input_hash(hash: &[u8; INPUT_HASH_SIZE]) -> String {
+    hex(hash)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,489 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Host-side registry of encrypted model artifacts, so workflows juggling
+// slots, shadow models and ensembles can name models instead of passing
+// paths around. One JSON file, by default ~/.enc-mnist/registry.json
+// (`--registry` / ENC_MNIST_REGISTRY), holds an entry per model:
+//
+//   { "models": [ { "name", "path", "encrypted_sha256", "tags", ... } ] }
+//
+// What only the TA can tell (plaintext hash, architecture, class labels, key
+// fingerprint) is filled in by `provision --model-name`, which also records
+// where the model went. Edits hold a lock file next to the registry and
+// replace it by renaming, so concurrent ones neither interleave nor leave a
+// half-written file behind.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::commands::infer;
+
+static PATH_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+// How long an edit waits for another one to finish
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+const LOCK_POLL: Duration = Duration::from_millis(50);
+
+/// An encrypted model known by name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    /// Absolute path of the encrypted model (.json).
+    pub path: PathBuf,
+    /// Hex SHA-256 of the ciphertext, as the TA reports it; a file that no
+    /// longer matches was replaced since it was registered.
+    pub encrypted_sha256: String,
+    /// Hex SHA-256 of the decrypted model container, once provisioned.
+    #[serde(default)]
+    pub plaintext_sha256: Option<String>,
+    /// Layer widths, once provisioned.
+    #[serde(default)]
+    pub arch: Option<String>,
+    #[serde(default)]
+    pub class_labels: Vec<String>,
+    /// First 8 bytes of the SHA-256 of the AES key, hex, once provisioned.
+    #[serde(default)]
+    pub key_fingerprint: Option<String>,
+    /// Further names `--model-name` finds the model by.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Where `provision --model-name` installed it, latest last.
+    #[serde(default)]
+    pub deployments: Vec<Deployment>,
+}
+
+/// One installation of a registered model.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Deployment {
+    /// Host name of the device.
+    pub device: String,
+    /// Inference TA instance on the device.
+    pub ta_uuid: String,
+    pub slot: u32,
+    /// Seconds since the Unix epoch.
+    pub provisioned: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Registry {
+    pub models: Vec<Entry>,
+}
+
+/// Makes the registry commands use the file at `path` instead of the one
+/// in the home directory.
+pub fn set_path(path: &Path) -> anyhow::Result<()> {
+    PATH_OVERRIDE
+        .set(path.to_path_buf())
+        .map_err(|_| anyhow::anyhow!("registry path already set"))
+}
+
+/// The registry file, as configured.
+pub fn path() -> anyhow::Result<PathBuf> {
+    if let Some(path) = PATH_OVERRIDE.get() {
+        return Ok(path.clone());
+    }
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| anyhow::anyhow!("HOME is not set, pass --registry"))?;
+    Ok(Path::new(&home).join(".enc-mnist").join("registry.json"))
+}
+
+impl Registry {
+    /// Reads the registry; a missing file is an empty one.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::load_from(&path()?)
+    }
+
+    fn load_from(path: &Path) -> anyhow::Result<Self> {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("{}: malformed registry", path.display())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).with_context(|| format!("cannot read {}", path.display())),
+        }
+    }
+
+    /// Runs `edit` on the registry as it is on disk and writes the result
+    /// back, holding the lock throughout. Nothing is written when `edit`
+    /// fails.
+    pub fn edit<T>(edit: impl FnOnce(&mut Registry) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        Self::edit_at(&path()?, edit)
+    }
+
+    fn edit_at<T>(
+        path: &Path,
+        edit: impl FnOnce(&mut Registry) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        let _lock = Lock::acquire(path)?;
+        let mut registry = Self::load_from(path)?;
+        let result = edit(&mut registry)?;
+        let temp = path.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(&registry)?)
+            .with_context(|| format!("cannot write {}", temp.display()))?;
+        fs::rename(&temp, path).with_context(|| format!("cannot replace {}", path.display()))?;
+        Ok(result)
+    }
+
+    /// Registers `entry`, replacing a model of the same name only when
+    /// `force` is set. Returns whether one was replaced.
+    pub fn add(&mut self, entry: Entry, force: bool) -> anyhow::Result<bool> {
+        let existing = self.models.iter().position(|e| e.name == entry.name);
+        anyhow::ensure!(
+            existing.is_none() || force,
+            "a model named \"{}\" is already registered, pass --force to replace it",
+            entry.name
+        );
+        if let Some(index) = existing {
+            self.models.remove(index);
+        }
+        self.models.push(entry);
+        Ok(existing.is_some())
+    }
+
+    /// Forgets the model named `name`; tags don't count here.
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<()> {
+        let index = self
+            .models
+            .iter()
+            .position(|e| e.name == name)
+            .ok_or_else(|| anyhow::anyhow!("no model named \"{}\" in the registry", name))?;
+        self.models.remove(index);
+        Ok(())
+    }
+
+    /// The model named `name`, or else the one tagged `name`.
+    pub fn find(&self, name: &str) -> anyhow::Result<&Entry> {
+        if let Some(entry) = self.models.iter().find(|entry| entry.name == name) {
+            return Ok(entry);
+        }
+        let tagged: Vec<&Entry> = self
+            .models
+            .iter()
+            .filter(|entry| entry.tags.iter().any(|tag| tag == name))
+            .collect();
+        match tagged.as_slice() {
+            [entry] => Ok(entry),
+            [] => anyhow::bail!(
+                "no model named or tagged \"{}\" in the registry (see `model list`)",
+                name
+            ),
+            entries => anyhow::bail!(
+                "\"{}\" tags {} models ({}), use one of their names",
+                name,
+                entries.len(),
+                entries
+                    .iter()
+                    .map(|entry| entry.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// `find`, mutably.
+    pub fn find_mut(&mut self, name: &str) -> anyhow::Result<&mut Entry> {
+        let name = self.find(name)?.name.clone();
+        Ok(self
+            .models
+            .iter_mut()
+            .find(|entry| entry.name == name)
+            .unwrap())
+    }
+}
+
+impl Entry {
+    /// An entry for the encrypted model at `path`, hashing its ciphertext.
+    pub fn new(name: &str, path: &Path, tags: Vec<String>) -> anyhow::Result<Self> {
+        let path = std::path::absolute(path)?;
+        Ok(Self {
+            name: name.to_string(),
+            encrypted_sha256: ciphertext_sha256(&path)?,
+            path,
+            plaintext_sha256: None,
+            arch: None,
+            class_labels: Vec::new(),
+            key_fingerprint: None,
+            tags,
+            deployments: Vec::new(),
+        })
+    }
+
+    /// Fails unless the file is still where it was registered, unchanged.
+    pub fn check(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.path.exists(),
+            "model \"{}\": {} is gone (moved or deleted?); `model remove {}` and add it again",
+            self.name,
+            self.path.display(),
+            self.name
+        );
+        anyhow::ensure!(
+            ciphertext_sha256(&self.path)? == self.encrypted_sha256,
+            "model \"{}\": {} was replaced since it was registered; `model remove {}` and \
+             add it again",
+            self.name,
+            self.path.display(),
+            self.name
+        );
+        Ok(())
+    }
+}
+
+/// The registered model `name` (or the one tagged `name`), checked against
+/// its file.
+pub fn resolve(name: &str) -> anyhow::Result<Entry> {
+    let entry = Registry::load()?.find(name)?.clone();
+    entry.check()?;
+    println!("Model \"{}\": {}", entry.name, entry.path.display());
+    Ok(entry)
+}
+
+/// The model paths of a command taking either paths (`--model`) or
+/// registered names (`--model-name`).
+pub fn model_paths(paths: &[String], names: &[String]) -> anyhow::Result<Vec<String>> {
+    if names.is_empty() {
+        return Ok(paths.to_vec());
+    }
+    names
+        .iter()
+        .map(|name| Ok(resolve(name)?.path.display().to_string()))
+        .collect()
+}
+
+/// Host name of this device, for `Deployment::device`.
+pub fn device_name() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn ciphertext_sha256(path: &Path) -> anyhow::Result<String> {
+    let model = infer::decode_encrypted_model(path)
+        .with_context(|| format!("cannot read {}", path.display()))?;
+    Ok(hex(&Sha256::digest(&model.ciphertext)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Exclusive right to edit the registry at a path: a lock file created next
+// to it, removed on drop
+struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(registry: &Path) -> anyhow::Result<Self> {
+        let path = registry.with_extension("json.lock");
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    // Only for whoever finds a stale lock
+                    let _ = writeln!(file, "{}", std::process::id());
+                    return Ok(Self { path });
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                    anyhow::ensure!(
+                        start.elapsed() < LOCK_TIMEOUT,
+                        "{} is locked by another edit (process {}); remove the lock file if \
+                         that process is gone",
+                        registry.display(),
+                        lock_holder(&path)
+                    );
+                    std::thread::sleep(LOCK_POLL);
+                }
+                Err(err) => {
+                    return Err(err).with_context(|| format!("cannot create {}", path.display()))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn lock_holder(path: &Path) -> String {
+    File::open(path)
+        .and_then(|mut file| {
+            let mut pid = String::new();
+            std::io::Read::read_to_string(&mut file, &mut pid)?;
+            Ok(pid.trim().to_string())
+        })
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("registry-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // An encrypted model file whose ciphertext is `fill` repeated
+    fn model_file(dir: &Path, name: &str, fill: u8) -> PathBuf {
+        let path = dir.join(name);
+        let json = serde_json::json!({
+            "algorithm": proto::model_file::ALGORITHM,
+            "encrypted_data": vec![fill; 32],
+        });
+        fs::write(&path, json.to_string()).unwrap();
+        path
+    }
+
+    fn entry(dir: &Path, name: &str, tags: &[&str]) -> Entry {
+        let path = model_file(dir, &format!("{}.json", name), name.len() as u8);
+        Entry::new(name, &path, tags.iter().map(|t| t.to_string()).collect()).unwrap()
+    }
+
+    fn names(registry: &Registry) -> Vec<&str> {
+        registry.models.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn models_are_added_replaced_and_removed() {
+        let dir = temp_dir("crud");
+        let path = dir.join("registry.json");
+        assert!(Registry::load_from(&path).unwrap().models.is_empty());
+
+        let first = entry(&dir, "mnist", &["prod"]);
+        let added = Registry::edit_at(&path, |r| r.add(first.clone(), false)).unwrap();
+        assert!(!added);
+        Registry::edit_at(&path, |r| r.add(entry(&dir, "candidate", &[]), false)).unwrap();
+        let registry = Registry::load_from(&path).unwrap();
+        assert_eq!(names(&registry), ["mnist", "candidate"]);
+        assert_eq!(registry.models[0].path, first.path);
+        assert_eq!(registry.models[0].encrypted_sha256, first.encrypted_sha256);
+        assert_eq!(registry.models[0].tags, ["prod"]);
+
+        // The same name again only with --force, and a refused edit writes
+        // nothing
+        let retagged = entry(&dir, "mnist", &["old"]);
+        assert!(Registry::edit_at(&path, |r| r.add(retagged.clone(), false)).is_err());
+        assert_eq!(Registry::load_from(&path).unwrap().models[0].tags, ["prod"]);
+        assert!(Registry::edit_at(&path, |r| r.add(retagged, true)).unwrap());
+        let registry = Registry::load_from(&path).unwrap();
+        assert_eq!(names(&registry), ["candidate", "mnist"]);
+        assert_eq!(registry.models[1].tags, ["old"]);
+
+        Registry::edit_at(&path, |r| r.remove("candidate")).unwrap();
+        assert_eq!(names(&Registry::load_from(&path).unwrap()), ["mnist"]);
+        // Tags don't name a model to remove
+        assert!(Registry::edit_at(&path, |r| r.remove("old")).is_err());
+        assert!(Registry::edit_at(&path, |r| r.remove("candidate")).is_err());
+        // No lock or temporary file is left behind
+        assert!(!path.with_extension("json.lock").exists());
+        assert!(!path.with_extension("json.tmp").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn names_resolve_before_unique_tags() {
+        let dir = temp_dir("resolve");
+        let mut registry = Registry::default();
+        registry
+            .add(entry(&dir, "a", &["prod", "x"]), false)
+            .unwrap();
+        registry
+            .add(entry(&dir, "b", &["shadow", "x"]), false)
+            .unwrap();
+        registry.add(entry(&dir, "c", &["a"]), false).unwrap();
+
+        assert_eq!(registry.find("b").unwrap().name, "b");
+        assert_eq!(registry.find("prod").unwrap().name, "a");
+        assert_eq!(registry.find("shadow").unwrap().name, "b");
+        // A name wins over another model's tag
+        assert_eq!(registry.find("a").unwrap().name, "a");
+        let err = registry.find("x").unwrap_err().to_string();
+        assert!(err.contains("tags 2 models (a, b)"), "{}", err);
+        assert!(registry.find("staging").is_err());
+
+        registry.find_mut("shadow").unwrap().arch = Some("784-10".into());
+        assert_eq!(registry.models[1].arch.as_deref(), Some("784-10"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn moved_and_replaced_files_are_caught_on_use() {
+        let dir = temp_dir("stale");
+        let entry = entry(&dir, "mnist", &[]);
+        entry.check().unwrap();
+        // Rewritten with another ciphertext
+        model_file(&dir, "mnist.json", 0xee);
+        let err = entry.check().unwrap_err().to_string();
+        assert!(err.contains("was replaced"), "{}", err);
+        // Moved away
+        fs::rename(&entry.path, dir.join("moved.json")).unwrap();
+        let err = entry.check().unwrap_err().to_string();
+        assert!(err.contains("is gone"), "{}", err);
+        assert!(err.contains("\"mnist\""), "{}", err);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn concurrent_edits_all_land() {
+        let dir = temp_dir("concurrent");
+        let path = dir.join("registry.json");
+        let entries: Vec<Entry> = (0..8)
+            .map(|i| entry(&dir, &format!("model{}", i), &[]))
+            .collect();
+        std::thread::scope(|scope| {
+            for entry in &entries {
+                let path = &path;
+                scope.spawn(move || {
+                    Registry::edit_at(path, |registry| {
+                        // Widen the window between reading and writing
+                        std::thread::sleep(Duration::from_millis(5));
+                        registry.add(entry.clone(), false)
+                    })
+                    .unwrap();
+                });
+            }
+        });
+        let mut names: Vec<String> = Registry::load_from(&path)
+            .unwrap()
+            .models
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            (0..8).map(|i| format!("model{}", i)).collect::<Vec<_>>()
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
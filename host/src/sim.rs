@@ -0,0 +1,939 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// In-process stand-in for the inference TA used by `--dry-run`. It runs the
+// same framing, container and model code as the TA and refuses what the TA
+// refuses, so an encrypted artifact and the host pipeline can be checked on
+// machines without OP-TEE. Nothing here touches secure storage.
+
+use burn::backend::NdArray;
+use common::{LoadError, LoadState};
+use optee_teec::ErrorKind;
+use proto::inference::{
+    self, CorrelatedPrediction, LicenseStatus, ModelLicense, ModelStatus, Normalization,
+    Prediction, FLAG_FIXED_POINT_PROBS, FLAG_INPUT_HASHES, FLAG_MARK_TIES, FLAG_PREDICTIONS,
+    INPUT_HASH_SIZE, MAX_MODEL_STATUS_SIZE, MODEL_SLOTS, REJECT_LABEL, TEMPERATURE_SCALE,
+    TIME_BUDGET_SLICE,
+};
+use proto::{fixed_point, wire, Image, DEFAULT_NUM_CLASSES, IMAGE_SIZE, MAX_NUM_CLASSES};
+use std::time::{Duration, Instant};
+
+use crate::tee::{BudgetOutcome, InferenceTa, RecordedBatch, StoragePreflight};
+
+type Model = common::Model<NdArray>;
+// What `SimulatedTa::outputs` computes for one request
+type Outputs = (Vec<Prediction>, Vec<f32>, Vec<[u8; INPUT_HASH_SIZE]>);
+
+// Default of `TA_MODEL_MEMORY_BUDGET` in ta/inference/build.rs
+const MODEL_MEMORY_BUDGET: usize = 12 * 1024 * 1024;
+// Batch size the TA checks the memory estimate with
+const ESTIMATE_BATCH_SIZE: usize = 64;
+
+#[derive(Default)]
+struct ModelInfo {
+    name: Option<String>,
+    class_labels: Vec<String>,
+    num_classes: usize,
+    license: Option<ModelLicense>,
+    canaries: usize,
+    normalization: Normalization,
+}
+
+// The TA's answer to a load command its load state refused: BadState out of
+// order, BadParameters for a chunk past the announced size
+fn bad_load_state(cmd: &str, err: LoadError) -> optee_teec::Error {
+    println!("[!] {} {}", cmd, err);
+    match err {
+        LoadError::OutOfOrder { .. } => ErrorKind::BadState.into(),
+        LoadError::Overrun { .. } => ErrorKind::BadParameters.into(),
+    }
+}
+
+pub struct SimulatedTa {
+    key: [u8; 32],
+    device: <NdArray as burn::prelude::Backend>::Device,
+    models: [Option<Model>; MODEL_SLOTS],
+    info: [ModelInfo; MODEL_SLOTS],
+    load: LoadState,
+    next_id: u32,
+    input_flags: u32,
+    reject_below: Option<u32>,
+}
+
+impl SimulatedTa {
+    /// `key` plays the part of the key provisioned in the key manager TA.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            device: Default::default(),
+            models: Default::default(),
+            info: Default::default(),
+            load: LoadState::Idle,
+            next_id: 0,
+            input_flags: 0,
+            reject_below: None,
+        }
+    }
+
+    fn model(&self, slot: usize) -> optee_teec::Result<&Model> {
+        self.check_license(slot)?;
+        self.models[slot]
+            .as_ref()
+            .ok_or_else(|| ErrorKind::ItemNotFound.into())
+    }
+
+    // Against the host clock; the TA also keeps it from running backwards
+    fn check_license(&self, slot: usize) -> optee_teec::Result<()> {
+        match &self.info[slot].license {
+            Some(license) if now() >= license.expires => {
+                println!(
+                    "[!] License of slot {} expired at {}",
+                    slot, license.expires
+                );
+                Err(optee_teec::Error::from_raw_error(
+                    inference::ERROR_LICENSE_EXPIRED,
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The host-side decryption `inspect --key` and `--encrypted` use, which
+    // follows the TA's
+    fn decrypt(&self, data: &[u8]) -> optee_teec::Result<Vec<u8>> {
+        crate::commands::encrypt::decrypt_with_key_host(&self.key, data).map_err(|err| {
+            println!("[!] {}", err);
+            ErrorKind::BadParameters.into()
+        })
+    }
+
+    // Input tensor of a parsed request to the model in `slot`
+    fn request_input(&self, slot: usize, request: &Request) -> burn::prelude::Tensor<NdArray, 2> {
+        let normalization =
+            inference::input_normalization(request.flags, &self.info[slot].normalization);
+        Model::images_to_tensors(&self.device, request.images, &normalization)
+    }
+
+    fn predictions(&self, slot: usize, request: &Request) -> optee_teec::Result<Vec<Prediction>> {
+        self.outputs(slot, request)
+            .map(|(predictions, _, _)| predictions)
+    }
+
+    // Predictions, probabilities and input hashes (`FLAG_INPUT_HASHES`)
+    fn outputs(&self, slot: usize, request: &Request) -> optee_teec::Result<Outputs> {
+        let model = self.model(slot)?;
+        let input = self.request_input(slot, request);
+        let hashes = if request.flags & FLAG_INPUT_HASHES != 0 {
+            input_hashes(&input)
+        } else {
+            Vec::new()
+        };
+        let (_, probs) = model
+            .predict_with_temperature(input, request.temperature)
+            .ok_or(ErrorKind::Generic)?;
+        let num_classes = model.num_classes();
+        if request.threshold.is_some() && num_classes > REJECT_LABEL as usize {
+            println!(
+                "[!] Reject threshold on a model with {} classes",
+                num_classes
+            );
+            return Err(ErrorKind::BadParameters.into());
+        }
+        let mark_ties = request.flags & FLAG_MARK_TIES != 0;
+        let predictions = probs
+            .chunks_exact(num_classes)
+            .map(|row| {
+                let prediction =
+                    Prediction::from_probabilities(row, mark_ties).ok_or(ErrorKind::Generic)?;
+                Ok(request
+                    .threshold
+                    .map_or(prediction, |threshold| prediction.with_threshold(threshold)))
+            })
+            .collect::<optee_teec::Result<_>>()?;
+        // The TA encodes the rows after picking the predictions from them
+        let probs = if request.flags & FLAG_FIXED_POINT_PROBS != 0 {
+            probs
+                .chunks_exact(num_classes)
+                .flat_map(|row| fixed_point::decode_probs(&fixed_point::encode_probs(row)))
+                .collect()
+        } else {
+            probs
+        };
+        Ok((predictions, probs, hashes))
+    }
+
+    fn install_model(&mut self, slot: usize, plain: Vec<u8>) -> optee_teec::Result<()> {
+        let (metadata, format, record) = common::split_container(plain).map_err(|err| {
+            println!("[!] Invalid model container: {}", err);
+            optee_teec::Error::from_raw_error(err.code())
+        })?;
+        if !format.is_supported() {
+            println!("[!] Record format {:?} not supported", format);
+            return Err(ErrorKind::NotSupported.into());
+        }
+        let model = Model::import_as(&self.device, record, format).map_err(|err| {
+            println!("[!] Model import failed: {}", err);
+            optee_teec::Error::from_raw_error(err.code())
+        })?;
+        let num_classes = model.num_classes();
+        let declared = metadata.as_ref().map_or(num_classes, |m| m.num_classes());
+        if num_classes == 0 || num_classes > MAX_NUM_CLASSES || num_classes != declared {
+            println!(
+                "[!] Unsupported class count: model {}, metadata {}",
+                num_classes, declared
+            );
+            return Err(ErrorKind::BadFormat.into());
+        }
+        let estimate = model.memory_estimate(ESTIMATE_BATCH_SIZE);
+        if estimate > MODEL_MEMORY_BUDGET {
+            println!(
+                "model needs ~{} bytes, more than the TA memory budget",
+                estimate
+            );
+            return Err(ErrorKind::OutOfMemory.into());
+        }
+        let canaries = metadata.as_ref().map_or(&[][..], |m| &m.canary[..]);
+        if canaries.len() > common::MAX_CANARIES {
+            println!(
+                "[!] {} canary images, at most {}",
+                canaries.len(),
+                common::MAX_CANARIES
+            );
+            return Err(ErrorKind::BadFormat.into());
+        }
+        let normalization = metadata
+            .as_ref()
+            .map(common::ModelMetadata::normalization)
+            .unwrap_or_default();
+        if let Some(index) = model.failing_canary(&self.device, canaries, &normalization) {
+            println!("[!] Canary {} misclassified, wrong key?", index);
+            return Err(optee_teec::Error::from_raw_error(
+                inference::ERROR_CANARY_MISMATCH,
+            ));
+        }
+        self.models[slot] = Some(model);
+        self.info[slot] = match metadata {
+            Some(metadata) => ModelInfo {
+                normalization,
+                name: Some(metadata.name),
+                class_labels: metadata.class_labels,
+                num_classes,
+                license: metadata.license,
+                canaries: metadata.canary.len(),
+            },
+            None => ModelInfo {
+                num_classes,
+                ..Default::default()
+            },
+        };
+        Ok(())
+    }
+}
+
+impl InferenceTa for SimulatedTa {
+    // Storage, patches, statistics and history need the real TA
+    fn supports(&self, capability: u32) -> bool {
+        const CAPABILITIES: u32 = inference::CAP_PROBABILITIES
+            | inference::CAP_SLOTS
+            | inference::CAP_ENSEMBLE
+            | inference::CAP_CORRELATION
+            | inference::CAP_CLASS_LABELS
+            | inference::CAP_LICENSE
+            | inference::CAP_CANARY
+            | inference::CAP_INPUT_SCALING
+            | inference::CAP_REJECT_THRESHOLD
+            | inference::CAP_INPUT_HASHES
+            | inference::CAP_LOAD_STATE
+            | inference::CAP_TIE_MARKS
+            | inference::CAP_TIME_BUDGET;
+        CAPABILITIES & capability == capability
+    }
+
+    fn begin_model_load(&mut self, slot: u32, size: usize) -> optee_teec::Result<()> {
+        let slot = slot_index(slot)?;
+        // The dry run doesn't log loads, so they all go by id 0
+        self.load
+            .begin(slot, size, 0)
+            .map_err(|err| bad_load_state("begin", err))
+    }
+
+    fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()> {
+        self.load
+            .push(chunk)
+            .map_err(|err| bad_load_state("push", err))
+    }
+
+    fn finalize_model_load(&mut self, _signature: Option<&[u8]>) -> optee_teec::Result<()> {
+        let load = self
+            .load
+            .take()
+            .map_err(|err| bad_load_state("finalize", err))?;
+        // The TA would reload its stored model, which a dry run doesn't have
+        if load.buf.is_empty() {
+            println!("[!] No chunks pushed and no stored model in a dry run");
+            return Err(ErrorKind::ItemNotFound.into());
+        }
+        let plain = self.decrypt(&load.buf)?;
+        self.install_model(load.slot, plain)
+    }
+
+    fn storage_preflight(&mut self, _size: usize) -> optee_teec::Result<StoragePreflight> {
+        Err(ErrorKind::NotSupported.into())
+    }
+
+    fn model_status(&mut self, slot: u32) -> optee_teec::Result<ModelStatus> {
+        let slot = slot_index(slot)?;
+        let info = &self.info[slot];
+        let status = ModelStatus {
+            loaded: self.models[slot].is_some(),
+            name: info.name.clone(),
+            class_labels: info.class_labels.clone(),
+            num_classes: match info.num_classes {
+                0 => DEFAULT_NUM_CLASSES,
+                n => n,
+            },
+            stored: None,
+            staged: None,
+            license: info.license.clone().map(|license| LicenseStatus {
+                license,
+                now: now(),
+            }),
+            canaries: info.canaries,
+            normalization: info.normalization,
+            batch_hint: self.models[slot]
+                .as_ref()
+                .and_then(|model| model.batch_hint(MODEL_MEMORY_BUDGET)),
+        };
+        // Round-trip the reply like the connector does
+        let encoded = serde_json::to_vec(&status).map_err(|_| ErrorKind::Generic)?;
+        if encoded.len() > MAX_MODEL_STATUS_SIZE {
+            return Err(ErrorKind::ShortBuffer.into());
+        }
+        serde_json::from_slice(&encoded).map_err(|_| ErrorKind::BadFormat.into())
+    }
+
+    fn class_labels(&mut self, slot: u32) -> optee_teec::Result<Vec<String>> {
+        let slot = slot_index(slot)?;
+        // Round-trip through the TA's encoding, including its size limit
+        let encoded = inference::encode_class_labels(&self.info[slot].class_labels)
+            .ok_or(ErrorKind::ExcessData)?;
+        inference::decode_class_labels(&encoded).ok_or_else(|| ErrorKind::BadFormat.into())
+    }
+
+    fn set_input_flags(&mut self, flags: u32) -> optee_teec::Result<()> {
+        crate::tee::check_input_flags(flags)?;
+        self.input_flags = flags;
+        Ok(())
+    }
+
+    fn set_reject_threshold(&mut self, threshold: Option<f32>) -> optee_teec::Result<()> {
+        self.reject_below = threshold
+            .map(crate::tee::fixed_point_threshold)
+            .transpose()?;
+        Ok(())
+    }
+
+    fn infer_batch(&mut self, images: &[Image], slot: u32) -> optee_teec::Result<Vec<u8>> {
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
+        let input = crate::tee::request(images, self.input_flags, 1.0, self.reject_below)?;
+        let request = parse_request(&input)?;
+        let slot = slot_index(slot)?;
+        // Rejection needs the confidences
+        if request.threshold.is_some() {
+            let predictions = self.predictions(slot, &request)?;
+            return Ok(predictions.iter().map(|p| p.label).collect());
+        }
+        let model = self.model(slot)?;
+        let input = self.request_input(slot, &request);
+        model.predict_labels(input).ok_or(ErrorKind::Generic.into())
+    }
+
+    fn infer_predictions(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        let flags = FLAG_PREDICTIONS | FLAG_MARK_TIES | self.input_flags;
+        let input = crate::tee::request(images, flags, temperature, self.reject_below)?;
+        let request = parse_request(&input)?;
+        self.predictions(slot_index(slot)?, &request)
+    }
+
+    fn infer_batch_with_probabilities(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        let input = crate::tee::request(images, self.input_flags, temperature, self.reject_below)?;
+        let request = parse_request(&input)?;
+        let (predictions, probabilities, _) = self.outputs(slot_index(slot)?, &request)?;
+        // The connector sizes the probabilities output from `num_classes`
+        if probabilities.len() != images.len() * num_classes {
+            return Err(ErrorKind::ShortBuffer.into());
+        }
+        Ok((predictions.iter().map(|p| p.label).collect(), probabilities))
+    }
+
+    fn infer_recorded(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<RecordedBatch> {
+        let flags = FLAG_PREDICTIONS | FLAG_INPUT_HASHES | FLAG_MARK_TIES | self.input_flags;
+        let input = crate::tee::request(images, flags, temperature, self.reject_below)?;
+        let request = parse_request(&input)?;
+        let (predictions, probabilities, input_hashes) =
+            self.outputs(slot_index(slot)?, &request)?;
+        // The connector sizes the probabilities output from `num_classes`
+        if probabilities.len() != images.len() * num_classes {
+            return Err(ErrorKind::ShortBuffer.into());
+        }
+        Ok(RecordedBatch {
+            predictions,
+            probabilities,
+            input_hashes,
+        })
+    }
+
+    // Slices like the TA, timed with the host clock
+    fn infer_within_budget(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        budget: Duration,
+    ) -> optee_teec::Result<BudgetOutcome> {
+        let flags = FLAG_PREDICTIONS | FLAG_MARK_TIES | self.input_flags;
+        let budget_ms = u32::try_from(budget.as_millis()).unwrap_or(u32::MAX);
+        let input =
+            crate::tee::budgeted_request(images, flags, temperature, self.reject_below, budget_ms)?;
+        let request = parse_request(&input)?;
+        let slot = slot_index(slot)?;
+        let started = Instant::now();
+        let mut predictions = Vec::with_capacity(images.len());
+        common::run_within_budget(
+            request.images.len(),
+            TIME_BUDGET_SLICE,
+            budget.into(),
+            || started.elapsed().into(),
+            |slice| {
+                let slice = &request.images[slice];
+                let input = crate::tee::request(slice, flags, temperature, self.reject_below)?;
+                predictions.extend(self.predictions(slot, &parse_request(&input)?)?);
+                Ok::<_, optee_teec::Error>(())
+            },
+        )?;
+        Ok(if predictions.len() < images.len() {
+            BudgetOutcome::Exceeded(predictions)
+        } else {
+            BudgetOutcome::Complete(predictions)
+        })
+    }
+
+    fn infer_correlated(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        let ids = crate::tee::take_ids(&mut self.next_id, images.len());
+        let input = crate::tee::correlated_request(
+            images,
+            &ids,
+            FLAG_MARK_TIES | self.input_flags,
+            temperature,
+            self.reject_below,
+        )?;
+        let request = parse_request(&input)?;
+        let predictions = self.predictions(slot_index(slot)?, &request)?;
+        let results: Vec<CorrelatedPrediction> = request
+            .ids
+            .ok_or(ErrorKind::BadParameters)?
+            .into_iter()
+            .zip(predictions)
+            .map(|(id, prediction)| CorrelatedPrediction::new(id, prediction))
+            .collect();
+        crate::tee::verify_correlation(&ids, &results)
+    }
+
+    fn infer_ensemble(
+        &mut self,
+        images: &[Image],
+        slot_mask: u32,
+        temperature: f32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        if images.is_empty() {
+            println!("[!] No images provided for inference");
+            return Err(optee_teec::Error::from_raw_error(
+                inference::ERROR_NO_IMAGES,
+            ));
+        }
+        if self.input_flags != 0 {
+            println!("ensemble inference always applies the models' normalization");
+            return Err(ErrorKind::NotSupported.into());
+        }
+        if self.reject_below.is_some() {
+            println!("ensemble inference can't reject images, it has no threshold to carry");
+            return Err(ErrorKind::NotSupported.into());
+        }
+        // Same fixed-point round trip as the value parameter
+        let temperature = crate::tee::fixed_point_temperature(temperature)?;
+        let temperature = temperature as f32 / TEMPERATURE_SCALE as f32;
+        if slot_mask == 0 || slot_mask >> MODEL_SLOTS != 0 {
+            println!("[!] Invalid slot mask: {:#x}", slot_mask);
+            return Err(ErrorKind::BadParameters.into());
+        }
+        let mut selected = Vec::new();
+        for (slot, model) in self.models.iter().enumerate() {
+            if slot_mask & (1 << slot) == 0 {
+                continue;
+            }
+            self.check_license(slot)?;
+            match model {
+                Some(model) => selected.push(model),
+                None => {
+                    println!("[!] Slot {} is empty", slot);
+                    return Err(ErrorKind::ItemNotFound.into());
+                }
+            }
+        }
+        if selected
+            .iter()
+            .any(|model| model.num_classes() != selected[0].num_classes())
+        {
+            println!("[!] Ensemble members disagree on the class count");
+            return Err(ErrorKind::BadParameters.into());
+        }
+        // One input tensor feeds every member, so they must agree on its scaling
+        let normalization = self.info[slot_mask.trailing_zeros() as usize].normalization;
+        if (0..MODEL_SLOTS)
+            .filter(|slot| slot_mask & (1 << slot) != 0)
+            .any(|slot| self.info[slot].normalization != normalization)
+        {
+            println!("[!] Ensemble members disagree on input normalization");
+            return Err(ErrorKind::BadParameters.into());
+        }
+        let input = Model::images_to_tensors(&self.device, images, &normalization);
+        let (labels, probs) = common::predict_ensemble(&selected, input, temperature)
+            .ok_or(ErrorKind::BadParameters)?;
+        // The connector sizes the probabilities output from `num_classes`
+        if probs.len() != images.len() * num_classes {
+            println!(
+                "mismatch response, want {}, got {}",
+                images.len() * num_classes,
+                probs.len()
+            );
+            return Err(ErrorKind::ShortBuffer.into());
+        }
+        Ok((labels, probs))
+    }
+}
+
+// What the TA takes from an inference request
+struct Request<'a> {
+    images: &'a [Image],
+    temperature: f32,
+    // Correlation ids
+    ids: Option<Vec<u32>>,
+    flags: u32,
+    // Reject threshold in thousandths
+    threshold: Option<u32>,
+}
+
+/// Decodes a `Command::Infer` input the way the TA does.
+fn parse_request(input: &[u8]) -> optee_teec::Result<Request<'_>> {
+    let (header, image_bytes) = inference::split_request(input).map_err(|_| {
+        println!("[!] Malformed inference request");
+        ErrorKind::BadParameters
+    })?;
+    let images: &[Image] = wire::records(image_bytes).ok_or(ErrorKind::BadParameters)?;
+    if images.is_empty() {
+        println!("[!] No images provided for inference");
+        return Err(optee_teec::Error::from_raw_error(
+            inference::ERROR_NO_IMAGES,
+        ));
+    }
+    Ok(Request {
+        images,
+        temperature: header.map_or(1.0, |header| header.temperature()),
+        ids: header.and_then(|header| inference::correlation_ids(input, &header)),
+        flags: header.map_or(0, |header| header.flags()),
+        threshold: header.and_then(|header| inference::reject_threshold(input, &header)),
+    })
+}
+
+// SHA-256 of each image's row of the input tensor, as the TA hashes it
+fn input_hashes(input: &burn::prelude::Tensor<NdArray, 2>) -> Vec<[u8; INPUT_HASH_SIZE]> {
+    use sha2::{Digest, Sha256};
+    let values: Vec<f32> = input.to_data().iter::<f32>().collect();
+    values
+        .chunks(IMAGE_SIZE)
+        .map(|row| {
+            let bytes: Vec<u8> = row.iter().flat_map(|value| value.to_le_bytes()).collect();
+            Sha256::digest(&bytes).into()
+        })
+        .collect()
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn slot_index(value: u32) -> optee_teec::Result<usize> {
+    let slot = value as usize;
+    if slot >= MODEL_SLOTS {
+        println!("[!] Invalid model slot: {}", slot);
+        return Err(ErrorKind::BadParameters.into());
+    }
+    Ok(slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::encrypt::{encrypt_model, EncryptOptions};
+    use proto::inference::LoadPhase;
+
+    const KEY: [u8; 32] = [0x5a; 32];
+
+    fn kind<T>(result: optee_teec::Result<T>) -> Option<ErrorKind> {
+        result.err().map(|err| err.kind())
+    }
+
+    // Images that differ from each other in every pixel
+    fn images(count: usize) -> Vec<Image> {
+        (0..count)
+            .map(|i| {
+                let mut pixels = [0; IMAGE_SIZE];
+                for (j, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = (j * 7 + i * 31) as u8;
+                }
+                Image::from_luma28(&pixels)
+            })
+            .collect()
+    }
+
+    fn input(images: &[Image]) -> burn::prelude::Tensor<NdArray, 2> {
+        Model::images_to_tensors(&Default::default(), images, &Normalization::MNIST)
+    }
+
+    // Encrypts `model` with `options` and loads it into `slot` the way
+    // `provision` does; `test` names the temporary files
+    fn provision(
+        ta: &mut SimulatedTa,
+        slot: u32,
+        model: &Model,
+        options: &EncryptOptions,
+        test: &str,
+    ) -> anyhow::Result<()> {
+        let dir = std::env::temp_dir();
+        let record = dir.join(format!("sim-{}-{}.bin", test, std::process::id()));
+        let encrypted = record.with_extension("json");
+        std::fs::write(&record, model.export().unwrap()).unwrap();
+        let result = encrypt_model(&record, &encrypted, &KEY, options).and_then(|()| {
+            crate::commands::infer::load_model(ta, encrypted.to_str().unwrap(), slot)
+        });
+        let _ = std::fs::remove_file(&record);
+        let _ = std::fs::remove_file(&encrypted);
+        result
+    }
+
+    #[test]
+    fn out_of_order_loads_are_refused_like_the_ta_does() {
+        let mut ta = SimulatedTa::new([0; 32]);
+        // Nothing begun
+        assert_eq!(
+            kind(ta.push_encrypted_chunk(b"x")),
+            Some(ErrorKind::BadState)
+        );
+        assert_eq!(
+            kind(ta.finalize_model_load(None)),
+            Some(ErrorKind::BadState)
+        );
+
+        // Begun with 32 bytes announced: a second begin, an overrun and an
+        // early finalize are refused and leave the load as it was
+        ta.begin_model_load(1, 32).unwrap();
+        assert_eq!(kind(ta.begin_model_load(0, 32)), Some(ErrorKind::BadState));
+        ta.push_encrypted_chunk(&[0; 16]).unwrap();
+        assert_eq!(
+            kind(ta.push_encrypted_chunk(&[0; 17])),
+            Some(ErrorKind::BadParameters)
+        );
+        assert_eq!(
+            kind(ta.finalize_model_load(None)),
+            Some(ErrorKind::BadState)
+        );
+        assert_eq!(
+            (ta.load.phase(), ta.load.received()),
+            (LoadPhase::Receiving, 16)
+        );
+
+        // Staged: only finalize goes, and takes the load even though the
+        // bytes don't decrypt
+        ta.push_encrypted_chunk(&[0; 16]).unwrap();
+        assert_eq!(
+            kind(ta.push_encrypted_chunk(b"x")),
+            Some(ErrorKind::BadState)
+        );
+        assert_eq!(ta.load.phase(), LoadPhase::Staged);
+        assert!(ta.finalize_model_load(None).is_err());
+        assert_eq!(ta.load.phase(), LoadPhase::Idle);
+        ta.begin_model_load(0, 0).unwrap();
+    }
+
+    #[test]
+    fn ensembles_need_every_slot_in_the_mask() {
+        let mut ta = SimulatedTa::new([0; 32]);
+        assert_eq!(
+            kind(ta.infer_ensemble(&[Image::BLANK], 0b11, 1.0, DEFAULT_NUM_CLASSES)),
+            Some(ErrorKind::ItemNotFound)
+        );
+        assert_eq!(
+            kind(ta.infer_ensemble(&[Image::BLANK], 0, 1.0, DEFAULT_NUM_CLASSES)),
+            Some(ErrorKind::BadParameters)
+        );
+    }
+
+    // `provision` with `metadata` embedded through a metadata file, which
+    // goes in unchecked
+    fn provision_with_metadata(
+        ta: &mut SimulatedTa,
+        slot: u32,
+        model: &Model,
+        metadata: &common::ModelMetadata,
+    ) -> anyhow::Result<()> {
+        let test = &metadata.name;
+        let path = std::env::temp_dir().join(format!("sim-{}-{}.json", test, std::process::id()));
+        std::fs::write(&path, serde_json::to_vec(metadata).unwrap()).unwrap();
+        let options = EncryptOptions {
+            metadata_path: path.to_str(),
+            ..Default::default()
+        };
+        let result = provision(ta, slot, model, &options, test);
+        let _ = std::fs::remove_file(&path);
+        result
+    }
+
+    #[test]
+    fn mismatched_canaries_keep_the_model_out() {
+        let mut ta = SimulatedTa::new(KEY);
+        let model = Model::new_with_seed(&Default::default(), 2, DEFAULT_NUM_CLASSES);
+        let images = images(common::MAX_CANARIES);
+        let labels = model.predict_labels(input(&images)).unwrap();
+        // Canaries in a metadata file skip the check `--canary` makes, as
+        // the canaries of a record decrypted with a stale key would
+        let with_canaries = |wrong: Option<usize>, name: &str| common::ModelMetadata {
+            name: name.into(),
+            canary: images
+                .iter()
+                .zip(&labels)
+                .enumerate()
+                .map(|(i, (&image, &label))| common::Canary {
+                    label: match wrong == Some(i) {
+                        true => (label + 1) % DEFAULT_NUM_CLASSES as u8,
+                        false => label,
+                    },
+                    image,
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        provision_with_metadata(&mut ta, 0, &model, &with_canaries(None, "canaries")).unwrap();
+        assert_eq!(ta.model_status(0).unwrap().canaries, common::MAX_CANARIES);
+
+        let metadata = with_canaries(Some(3), "bad-canary");
+        assert!(provision_with_metadata(&mut ta, 1, &model, &metadata).is_err());
+        assert!(!ta.model_status(1).unwrap().loaded);
+        // The model already installed is untouched
+        assert_eq!(ta.infer_batch(&images, 0).unwrap(), labels);
+    }
+
+    #[test]
+    fn inputs_are_normalized_with_the_profile_of_the_model() {
+        let mut ta = SimulatedTa::new(KEY);
+        let model = Model::new_with_seed(&Default::default(), 4, DEFAULT_NUM_CLASSES);
+        let images = images(16);
+        let reference = |normalization| {
+            let input = Model::images_to_tensors(&Default::default(), &images, normalization);
+            model.predict(input).unwrap()
+        };
+        let (unit_labels, unit_probs) = reference(&Normalization::UNIT);
+        let (mnist_labels, _) = reference(&Normalization::MNIST);
+        // The profiles must lead somewhere apart for the test to mean anything
+        assert_ne!(unit_labels, mnist_labels);
+
+        // A model trained on plain [0, 1] inputs
+        let metadata = common::ModelMetadata {
+            name: "unit-scaled".into(),
+            normalization: Some(Normalization::UNIT),
+            ..Default::default()
+        };
+        provision_with_metadata(&mut ta, 0, &model, &metadata).unwrap();
+        let status = ta.model_status(0).unwrap();
+        assert_eq!(status.normalization, Normalization::UNIT);
+        let (labels, probs) = ta
+            .infer_batch_with_probabilities(&images, 1.0, 0, DEFAULT_NUM_CLASSES)
+            .unwrap();
+        assert_eq!(labels, unit_labels);
+        assert_eq!(probs, unit_probs);
+        assert_eq!(ta.infer_batch(&images, 0).unwrap(), unit_labels);
+
+        // Records without a profile keep the MNIST constants
+        provision(&mut ta, 1, &model, &Default::default(), "legacy-scaled").unwrap();
+        assert_eq!(
+            ta.model_status(1).unwrap().normalization,
+            Normalization::MNIST
+        );
+        assert_eq!(ta.infer_batch(&images, 1).unwrap(), mnist_labels);
+    }
+
+    #[test]
+    fn input_flags_match_the_host_side_reference() {
+        use inference::{FLAG_NO_NORMALIZE, FLAG_RAW_SCALE};
+        let mut ta = SimulatedTa::new(KEY);
+        let model = Model::new_with_seed(&Default::default(), 7, DEFAULT_NUM_CLASSES);
+        let images = images(8);
+        // Reference inference over the tensor the host builds itself
+        let reference = |mean: f32, std: f32, scale: f32| {
+            let data: Vec<f32> = images
+                .iter()
+                .flat_map(|image| image.as_bytes().iter())
+                .map(|&pixel| (pixel as f32 / scale - mean) / std)
+                .collect();
+            let input = burn::prelude::Tensor::<NdArray, 2>::from_data(
+                burn::tensor::TensorData::new(data, [images.len(), IMAGE_SIZE]),
+                &Default::default(),
+            );
+            model.predict(input).unwrap()
+        };
+
+        for (slot, profile) in [Normalization::MNIST, Normalization::UNIT]
+            .into_iter()
+            .enumerate()
+        {
+            let metadata = common::ModelMetadata {
+                name: format!("flags-{}", slot),
+                normalization: Some(profile),
+                ..Default::default()
+            };
+            provision_with_metadata(&mut ta, slot as u32, &model, &metadata).unwrap();
+            for (flags, (mean, std, scale)) in [
+                (0, (profile.mean, profile.std, profile.scale)),
+                (FLAG_RAW_SCALE, (0.0, 1.0, 255.0)),
+                (FLAG_NO_NORMALIZE, (0.0, 1.0, 1.0)),
+            ] {
+                ta.set_input_flags(flags).unwrap();
+                let outputs = ta
+                    .infer_batch_with_probabilities(&images, 1.0, slot as u32, DEFAULT_NUM_CLASSES)
+                    .unwrap();
+                assert!(
+                    outputs == reference(mean, std, scale),
+                    "profile {}, flags {:#x}",
+                    profile,
+                    flags
+                );
+            }
+        }
+        // One or the other
+        assert_eq!(
+            kind(ta.set_input_flags(FLAG_RAW_SCALE | FLAG_NO_NORMALIZE)),
+            Some(ErrorKind::BadParameters)
+        );
+    }
+
+    #[test]
+    fn models_of_any_class_count_run_end_to_end() {
+        let mut ta = SimulatedTa::new(KEY);
+        let model = Model::new_with_seed(&Default::default(), 5, 26);
+        provision(&mut ta, 0, &model, &Default::default(), "letters").unwrap();
+        let status = ta.model_status(0).unwrap();
+        assert_eq!(status.num_classes, 26);
+
+        let images = images(3);
+        let (labels, probs) = ta
+            .infer_batch_with_probabilities(&images, 1.0, 0, status.num_classes)
+            .unwrap();
+        assert_eq!(Some(labels.clone()), model.predict_labels(input(&images)));
+        assert_eq!(ta.infer_batch(&images, 0).unwrap(), labels);
+        assert_eq!(probs.len(), 3 * 26);
+        assert_eq!(inference::probabilities_size(3, 26), 3 * 26 * 4);
+        // A connector sized for the default 10 classes is refused, not cut short
+        assert_eq!(
+            kind(ta.infer_batch_with_probabilities(&images, 1.0, 0, DEFAULT_NUM_CLASSES)),
+            Some(ErrorKind::ShortBuffer)
+        );
+    }
+
+    #[test]
+    fn empty_requests_follow_the_connector_contract() {
+        let mut ta = SimulatedTa::new(KEY);
+        let model = Model::new(&Default::default());
+        provision(&mut ta, 0, &model, &Default::default(), "empty").unwrap();
+        provision(&mut ta, 1, &model, &Default::default(), "empty-1").unwrap();
+        // An empty batch is answered without reaching the TA
+        assert_eq!(ta.infer_batch(&[], 0).unwrap(), Vec::<u8>::new());
+        // Requests that do reach it are refused, in both paths
+        assert!(ta.infer_predictions(&[], 1.0, 0).is_err());
+        assert!(ta
+            .infer_batch_with_probabilities(&[], 1.0, 0, DEFAULT_NUM_CLASSES)
+            .is_err());
+        assert!(ta
+            .infer_ensemble(&[], 0b11, 1.0, DEFAULT_NUM_CLASSES)
+            .is_err());
+        // The refusals leave the models usable
+        let images = images(2);
+        assert_eq!(
+            Some(ta.infer_batch(&images, 0).unwrap()),
+            model.predict_labels(input(&images))
+        );
+    }
+
+    #[test]
+    fn batch_hints_follow_the_loaded_model() {
+        let mut ta = SimulatedTa::new(KEY);
+        assert_eq!(ta.model_status(0).unwrap().batch_hint, None);
+        let options = EncryptOptions::default();
+        let model = Model::new_with_seed(&Default::default(), 2, DEFAULT_NUM_CLASSES);
+        provision(&mut ta, 0, &model, &options, "hint-default").unwrap();
+        let hint = ta.model_status(0).unwrap().batch_hint.unwrap();
+        assert_eq!(Some(hint), model.batch_hint(MODEL_MEMORY_BUDGET));
+        assert!(hint.recommended <= hint.max / 2);
+        assert!(model.memory_estimate(hint.max) <= MODEL_MEMORY_BUDGET);
+        assert!(model.memory_estimate(hint.max + 1) > MODEL_MEMORY_BUDGET);
+        // Other slots stay without one
+        assert_eq!(ta.model_status(1).unwrap().batch_hint, None);
+
+        // Replacing the model replaces the hint
+        let wider = Model::new_with_seed(&Default::default(), 2, 47);
+        provision(&mut ta, 0, &wider, &options, "hint-wider").unwrap();
+        let wider_hint = ta.model_status(0).unwrap().batch_hint.unwrap();
+        assert_eq!(Some(wider_hint), wider.batch_hint(MODEL_MEMORY_BUDGET));
+        assert!(wider_hint.max < hint.max);
+    }
+}
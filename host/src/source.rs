@@ -0,0 +1,572 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Where inference inputs come from. Each kind of input the CLI takes (`-b`,
+// `-i`, `--idx`, `--batch-file`, an image manifest, the MNIST test set) is
+// an `ImageSource`: an indexed list of images with names and, when the
+// source has them, true labels. Commands turn their flags into a list of
+// sources and read them through `Batches`, so batching and digit alignment
+// work the same whatever the inputs are.
+//
+// Files that need decoding are decoded when their source is opened, so every
+// warning is known before anything is sent to the TA. Batch files are read
+// as the batches are.
+
+use std::borrow::Cow;
+use std::ops::Range;
+use std::path::Path;
+
+use proto::Image;
+
+use crate::cache::PreprocessCache;
+use crate::input::{self, Alignment, Format, Input};
+
+pub trait ImageSource {
+    /// Images in the source.
+    fn len(&self) -> usize;
+    /// Reads image `index`, below `len`, into `image`.
+    fn read_into(&self, index: usize, image: &mut Image) -> anyhow::Result<()>;
+    /// True label of image `index`, `None` when the source has none.
+    fn label(&self, index: usize) -> Option<u8>;
+    /// Name of image `index` in results and messages.
+    fn name(&self, index: usize) -> Cow<'_, str>;
+    /// What was off about the file image `index` came from.
+    fn warnings(&self, _index: usize) -> &[String] {
+        &[]
+    }
+}
+
+/// A raw IMAGE_SIZE byte image (`-b`).
+pub struct BinaryFile {
+    path: String,
+    input: Input,
+}
+
+impl BinaryFile {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: path.to_string(),
+            input: input::load_binary(path)?,
+        })
+    }
+}
+
+impl ImageSource for BinaryFile {
+    fn len(&self) -> usize {
+        1
+    }
+
+    fn read_into(&self, _index: usize, image: &mut Image) -> anyhow::Result<()> {
+        *image = self.input.image;
+        Ok(())
+    }
+
+    fn label(&self, _index: usize) -> Option<u8> {
+        None
+    }
+
+    fn name(&self, _index: usize) -> Cow<'_, str> {
+        Cow::Borrowed(&self.path)
+    }
+
+    fn warnings(&self, _index: usize) -> &[String] {
+        &self.input.warnings
+    }
+}
+
+/// An image file decoded to 28x28 grayscale (`-i`).
+pub struct DecodedImage {
+    path: String,
+    input: Input,
+}
+
+impl DecodedImage {
+    pub fn open(
+        path: &str,
+        format: Format,
+        cache: Option<&PreprocessCache>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: path.to_string(),
+            input: input::load_image(path, format, cache)?,
+        })
+    }
+}
+
+impl ImageSource for DecodedImage {
+    fn len(&self) -> usize {
+        1
+    }
+
+    fn read_into(&self, _index: usize, image: &mut Image) -> anyhow::Result<()> {
+        *image = self.input.image;
+        Ok(())
+    }
+
+    fn label(&self, _index: usize) -> Option<u8> {
+        None
+    }
+
+    fn name(&self, _index: usize) -> Cow<'_, str> {
+        Cow::Borrowed(&self.path)
+    }
+
+    fn warnings(&self, _index: usize) -> &[String] {
+        &self.input.warnings
+    }
+}
+
+/// Records of an IDX images file (`--idx`, `--index`), named `path[record]`.
+pub struct IdxDataset {
+    inputs: Vec<(String, Input)>,
+}
+
+impl IdxDataset {
+    pub fn open(path: &str, records: Option<Range<usize>>) -> anyhow::Result<Self> {
+        Ok(Self {
+            inputs: input::load_idx(path, records)?,
+        })
+    }
+}
+
+impl ImageSource for IdxDataset {
+    fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    fn read_into(&self, index: usize, image: &mut Image) -> anyhow::Result<()> {
+        *image = self.inputs[index].1.image;
+        Ok(())
+    }
+
+    fn label(&self, _index: usize) -> Option<u8> {
+        None
+    }
+
+    fn name(&self, index: usize) -> Cow<'_, str> {
+        Cow::Borrowed(&self.inputs[index].0)
+    }
+
+    fn warnings(&self, index: usize) -> &[String] {
+        &self.inputs[index].1.warnings
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ManifestEntry {
+    image: String,
+}
+
+/// Images listed in a JSON manifest of `{"image": <path>}` entries, paths
+/// relative to the manifest (canary manifests work as they are).
+pub struct Manifest {
+    images: Vec<DecodedImage>,
+}
+
+impl Manifest {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let entries: Vec<ManifestEntry> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let base = Path::new(path).parent().unwrap_or(Path::new("."));
+        let images = entries
+            .iter()
+            .map(|entry| {
+                let path = base.join(&entry.image).to_string_lossy().into_owned();
+                DecodedImage::open(&path, Format::Auto, None)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { images })
+    }
+}
+
+impl ImageSource for Manifest {
+    fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    fn read_into(&self, index: usize, image: &mut Image) -> anyhow::Result<()> {
+        self.images[index].read_into(0, image)
+    }
+
+    fn label(&self, _index: usize) -> Option<u8> {
+        None
+    }
+
+    fn name(&self, index: usize) -> Cow<'_, str> {
+        self.images[index].name(0)
+    }
+
+    fn warnings(&self, index: usize) -> &[String] {
+        self.images[index].warnings(0)
+    }
+}
+
+/// The labeled MNIST test set in a directory (`evaluate --data`).
+pub struct MnistTestSet {
+    dir: String,
+    images: Vec<Image>,
+    labels: Vec<u8>,
+}
+
+impl MnistTestSet {
+    pub fn open(dir: &str) -> anyhow::Result<Self> {
+        let dataset = rust_mnist::Mnist::new(&crate::commands::evaluate::mnist_data_path(dir));
+        anyhow::ensure!(
+            !dataset.test_data.is_empty(),
+            "no test images found in {}",
+            dir
+        );
+        Ok(Self {
+            dir: dir.to_string(),
+            images: dataset.test_data.iter().map(Image::from_luma28).collect(),
+            labels: dataset.test_labels,
+        })
+    }
+}
+
+impl ImageSource for MnistTestSet {
+    fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    fn read_into(&self, index: usize, image: &mut Image) -> anyhow::Result<()> {
+        *image = self.images[index];
+        Ok(())
+    }
+
+    fn label(&self, index: usize) -> Option<u8> {
+        Some(self.labels[index])
+    }
+
+    fn name(&self, index: usize) -> Cow<'_, str> {
+        Cow::Owned(format!("{}[{}]", self.dir, index))
+    }
+}
+
+/// Images in all of `sources`.
+pub fn total(sources: &[Box<dyn ImageSource>]) -> usize {
+    sources.iter().map(|source| source.len()).sum()
+}
+
+/// Names of the images of `sources`, in order.
+pub fn names(sources: &[Box<dyn ImageSource>]) -> Vec<String> {
+    sources
+        .iter()
+        .flat_map(|source| (0..source.len()).map(|index| source.name(index).into_owned()))
+        .collect()
+}
+
+/// Prints the warnings of every image of `sources`, returning how many
+/// there were.
+pub fn print_warnings(sources: &[Box<dyn ImageSource>]) -> usize {
+    let mut count = 0;
+    for source in sources {
+        for index in 0..source.len() {
+            for warning in source.warnings(index) {
+                println!("warning: {}: {}", source.name(index), warning);
+            }
+            count += source.warnings(index).len();
+        }
+    }
+    count
+}
+
+/// Images read from the sources, aligned, with their labels when every one
+/// of them has a label.
+pub struct Batch {
+    pub images: Vec<Image>,
+    pub labels: Option<Vec<u8>>,
+}
+
+/// Reads a list of sources in order, a batch at a time; batches run on from
+/// one source into the next.
+pub struct Batches<'a> {
+    sources: &'a [Box<dyn ImageSource>],
+    alignment: Alignment,
+    source: usize,
+    index: usize,
+}
+
+impl<'a> Batches<'a> {
+    pub fn new(sources: &'a [Box<dyn ImageSource>], alignment: Alignment) -> Self {
+        Self {
+            sources,
+            alignment,
+            source: 0,
+            index: 0,
+        }
+    }
+
+    /// The next `max` images at most, `None` once all were read.
+    pub fn next_batch(&mut self, max: usize) -> anyhow::Result<Option<Batch>> {
+        let mut images = Vec::with_capacity(max);
+        let mut labels = Some(Vec::with_capacity(max));
+        while images.len() < max {
+            let Some(source) = self.sources.get(self.source) else {
+                break;
+            };
+            if self.index == source.len() {
+                self.source += 1;
+                self.index = 0;
+                continue;
+            }
+            let mut image = Image::BLANK;
+            source.read_into(self.index, &mut image)?;
+            images.push(input::align_digit(&image, self.alignment));
+            labels = labels
+                .zip(source.label(self.index))
+                .map(|(mut labels, label)| {
+                    labels.push(label);
+                    labels
+                });
+            self.index += 1;
+        }
+        if images.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Batch { images, labels }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proto::IMAGE_SIZE;
+
+    // A directory under the temp directory for one test
+    fn temp_dir(test: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("source-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Dim enough not to look inverted
+    fn images(count: usize) -> Vec<Image> {
+        (0..count)
+            .map(|i| {
+                let mut pixels = [0; IMAGE_SIZE];
+                for (p, pixel) in pixels.iter_mut().enumerate() {
+                    *pixel = ((p * 7 + i * 31) % 100) as u8;
+                }
+                Image::from_luma28(&pixels)
+            })
+            .collect()
+    }
+
+    fn write_png(path: &Path, image: &Image) {
+        image::GrayImage::from_raw(28, 28, image.as_bytes().to_vec())
+            .unwrap()
+            .save(path)
+            .unwrap();
+    }
+
+    fn read_all(source: &dyn ImageSource) -> Vec<Image> {
+        (0..source.len())
+            .map(|index| {
+                let mut image = Image::BLANK;
+                source.read_into(index, &mut image).unwrap();
+                image
+            })
+            .collect()
+    }
+
+    // Images held in memory, with labels and warnings when given
+    struct Listed {
+        name: &'static str,
+        images: Vec<Image>,
+        labels: Option<Vec<u8>>,
+        warnings: Vec<String>,
+    }
+
+    impl Listed {
+        fn new(name: &'static str, images: Vec<Image>, labels: Option<Vec<u8>>) -> Box<Self> {
+            Box::new(Self {
+                name,
+                images,
+                labels,
+                warnings: Vec::new(),
+            })
+        }
+    }
+
+    impl ImageSource for Listed {
+        fn len(&self) -> usize {
+            self.images.len()
+        }
+
+        fn read_into(&self, index: usize, image: &mut Image) -> anyhow::Result<()> {
+            *image = self.images[index];
+            Ok(())
+        }
+
+        fn label(&self, index: usize) -> Option<u8> {
+            Some(self.labels.as_ref()?[index])
+        }
+
+        fn name(&self, index: usize) -> Cow<'_, str> {
+            Cow::Owned(format!("{}{}", self.name, index))
+        }
+
+        fn warnings(&self, index: usize) -> &[String] {
+            match index {
+                0 => &self.warnings,
+                _ => &[],
+            }
+        }
+    }
+
+    #[test]
+    fn single_files_are_one_image_sources() {
+        let dir = temp_dir("single");
+        let image = images(1)[0];
+        let binary = dir.join("digit.bin");
+        let mut bytes = image.as_bytes().to_vec();
+        bytes.push(b'\n');
+        std::fs::write(&binary, bytes).unwrap();
+        let png = dir.join("digit.png");
+        write_png(&png, &image);
+
+        let binary = BinaryFile::open(binary.to_str().unwrap()).unwrap();
+        let png = DecodedImage::open(png.to_str().unwrap(), Format::Auto, None).unwrap();
+        for (source, file) in [
+            (&binary as &dyn ImageSource, "digit.bin"),
+            (&png, "digit.png"),
+        ] {
+            assert_eq!(source.len(), 1);
+            assert_eq!(read_all(source), [image]);
+            assert_eq!(source.label(0), None);
+            assert_eq!(source.name(0), dir.join(file).to_str().unwrap());
+        }
+        assert_eq!(binary.warnings(0), ["stripped a trailing newline"]);
+        assert!(png.warnings(0).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn idx_records_are_named_by_index() {
+        let dir = temp_dir("idx");
+        let path = dir.join("images.idx");
+        let images = images(3);
+        let mut bytes: Vec<u8> = [0x803u32, 3, 28, 28]
+            .iter()
+            .flat_map(|v| v.to_be_bytes())
+            .collect();
+        for image in &images {
+            bytes.extend_from_slice(image.as_bytes());
+        }
+        std::fs::write(&path, bytes).unwrap();
+        let path = path.to_str().unwrap();
+
+        let all = IdxDataset::open(path, None).unwrap();
+        assert_eq!(read_all(&all), images);
+        let some = IdxDataset::open(path, Some(1..3)).unwrap();
+        assert_eq!(read_all(&some), images[1..]);
+        assert_eq!(some.name(0), format!("{}[1]", path));
+        assert_eq!(some.name(1), format!("{}[2]", path));
+        assert_eq!(some.label(0), None);
+        assert!(IdxDataset::open(path, Some(2..4)).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn manifests_resolve_images_next_to_them() {
+        let dir = temp_dir("manifest");
+        std::fs::create_dir_all(dir.join("digits")).unwrap();
+        let images = images(2);
+        write_png(&dir.join("digits/a.png"), &images[0]);
+        write_png(&dir.join("digits/b.png"), &images[1]);
+        let manifest = dir.join("manifest.json");
+        std::fs::write(
+            &manifest,
+            r#"[{"image": "digits/a.png"}, {"image": "digits/b.png"}]"#,
+        )
+        .unwrap();
+
+        let source = Manifest::open(manifest.to_str().unwrap()).unwrap();
+        assert_eq!(read_all(&source), images);
+        assert_eq!(source.name(1), dir.join("digits/b.png").to_str().unwrap());
+        assert_eq!(source.label(0), None);
+        // Every image is decoded when the manifest is opened
+        std::fs::write(&manifest, r#"[{"image": "digits/c.png"}]"#).unwrap();
+        assert!(Manifest::open(manifest.to_str().unwrap()).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn batches_run_on_across_sources() {
+        let images = images(5);
+        let sources: Vec<Box<dyn ImageSource>> = vec![
+            Listed::new("a", images[..3].to_vec(), Some(vec![1, 2, 3])),
+            Listed::new("empty", Vec::new(), None),
+            Listed::new("b", images[3..].to_vec(), Some(vec![4, 5])),
+        ];
+        assert_eq!(total(&sources), 5);
+        assert_eq!(names(&sources), ["a0", "a1", "a2", "b0", "b1"]);
+        let mut batches = Batches::new(&sources, Alignment::default());
+        let mut read = Vec::new();
+        while let Some(batch) = batches.next_batch(2).unwrap() {
+            read.push((batch.images, batch.labels.unwrap()));
+        }
+        assert_eq!(
+            read,
+            [
+                (images[..2].to_vec(), vec![1, 2]),
+                (images[2..4].to_vec(), vec![3, 4]),
+                (images[4..].to_vec(), vec![5]),
+            ]
+        );
+        assert!(batches.next_batch(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn batches_are_labeled_only_when_every_image_is() {
+        let images = images(3);
+        let sources: Vec<Box<dyn ImageSource>> = vec![
+            Listed::new("a", images[..2].to_vec(), Some(vec![1, 2])),
+            Listed::new("b", images[2..].to_vec(), None),
+        ];
+        let mut batches = Batches::new(&sources, Alignment::default());
+        assert_eq!(
+            batches.next_batch(2).unwrap().unwrap().labels,
+            Some(vec![1, 2])
+        );
+        assert_eq!(batches.next_batch(2).unwrap().unwrap().labels, None);
+        let mut batches = Batches::new(&sources, Alignment::default());
+        assert_eq!(batches.next_batch(3).unwrap().unwrap().labels, None);
+    }
+
+    #[test]
+    fn batches_are_aligned_and_warnings_counted() {
+        let images = images(2);
+        let mut source = Listed::new("a", images.clone(), None);
+        source.warnings = vec!["one".into(), "two".into()];
+        let sources: Vec<Box<dyn ImageSource>> = vec![source];
+        assert_eq!(print_warnings(&sources), 2);
+        let center = Alignment {
+            center: true,
+            deskew: false,
+        };
+        let batch = Batches::new(&sources, center)
+            .next_batch(8)
+            .unwrap()
+            .unwrap();
+        let aligned: Vec<Image> = images
+            .iter()
+            .map(|image| input::align_digit(image, center))
+            .collect();
+        assert_eq!(batch.images, aligned);
+    }
+}
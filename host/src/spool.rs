@@ -0,0 +1,403 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Spool directory bookkeeping behind `watch`: which files are waiting, and
+// committing the results of a batch of them so that a crash at any point
+// leaves each file either waiting in the spool with nothing emitted, or
+// moved to the done directory with exactly one result line in the output.
+//
+// A batch is committed through a journal in the done directory:
+//
+// 1. the results, and the length of the output before them, are written to
+//    the journal and synced;
+// 2. the results are appended to the output and synced;
+// 3. the files are renamed into the done directory, which is synced;
+// 4. the journal is emptied.
+//
+// Opening the spool finishes a batch left in the journal: the output is cut
+// back to the recorded length and the results appended again, and the files
+// still in the spool are moved. A journal that doesn't parse was torn during
+// step 1, before anything else happened, and is dropped. Renames need both
+// directories on the same file system.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+const JOURNAL_NAME: &str = ".watch-journal";
+
+/// One line of the output: the class of a spooled file, or why it has none.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileResult {
+    /// File name in the spool (and now in the done directory).
+    pub file: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f32>,
+    /// Why the file couldn't be classified; it is moved all the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl FileResult {
+    pub fn classified(file: &str, class: u8, label: String, confidence: f32) -> Self {
+        Self {
+            file: file.to_string(),
+            class: Some(class),
+            label: Some(label),
+            confidence: Some(confidence),
+            error: None,
+        }
+    }
+
+    pub fn failed(file: &str, error: &anyhow::Error) -> Self {
+        Self {
+            file: file.to_string(),
+            class: None,
+            label: None,
+            confidence: None,
+            error: Some(format!("{:#}", error)),
+        }
+    }
+}
+
+// A batch between steps 1 and 4
+#[derive(Serialize, Deserialize)]
+struct JournalEntry {
+    output_len: u64,
+    results: Vec<FileResult>,
+}
+
+pub struct Spool {
+    dir: PathBuf,
+    done_dir: PathBuf,
+    journal: PathBuf,
+    output: File,
+}
+
+impl Spool {
+    /// Opens the spool `dir`, creating `done_dir`, and appends results to
+    /// `output`. Finishes the batch a previous run left in the journal.
+    pub fn open(dir: &Path, done_dir: &Path, output: &Path) -> anyhow::Result<Self> {
+        anyhow::ensure!(dir.is_dir(), "{} is not a directory", dir.display());
+        fs::create_dir_all(done_dir)
+            .with_context(|| format!("cannot create {}", done_dir.display()))?;
+        // Absolute, as the paths of watch events are
+        let dir = fs::canonicalize(dir)?;
+        let done_dir = fs::canonicalize(done_dir)?;
+        anyhow::ensure!(
+            dir != done_dir,
+            "the done directory must differ from the spool directory"
+        );
+        let output = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(output)
+            .with_context(|| format!("cannot open {}", output.display()))?;
+        let mut spool = Self {
+            journal: done_dir.join(JOURNAL_NAME),
+            dir,
+            done_dir,
+            output,
+        };
+        spool.recover()?;
+        Ok(spool)
+    }
+
+    /// The spool directory, absolute.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Whether `path` names a file `watch` takes from the spool: directly in
+    /// it, and not hidden (writers that rename finished files into place
+    /// usually write them under a dot name first).
+    pub fn is_candidate(&self, path: &Path) -> bool {
+        path.parent() == Some(self.dir.as_path())
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| !name.starts_with('.'))
+    }
+
+    /// Files waiting in the spool, oldest first.
+    pub fn waiting(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_file() && self.is_candidate(&path) {
+                files.push((entry.metadata()?.modified()?, path));
+            }
+        }
+        files.sort();
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Emits `results` and moves their files to the done directory, as one
+    /// step as far as a crash is concerned.
+    pub fn commit(&mut self, results: Vec<FileResult>) -> anyhow::Result<()> {
+        if results.is_empty() {
+            return Ok(());
+        }
+        let entry = JournalEntry {
+            output_len: self.output.metadata()?.len(),
+            results,
+        };
+        let mut journal = File::create(&self.journal)?;
+        journal.write_all(&serde_json::to_vec(&entry)?)?;
+        journal.sync_all()?;
+        sync_dir(&self.done_dir)?;
+        self.finish(&entry)
+    }
+
+    fn recover(&mut self) -> anyhow::Result<()> {
+        let data = match fs::read(&self.journal) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if data.is_empty() {
+            return Ok(());
+        }
+        match serde_json::from_slice::<JournalEntry>(&data) {
+            Ok(entry) => {
+                println!(
+                    "Finishing a batch of {} file(s) interrupted by the last run",
+                    entry.results.len()
+                );
+                self.finish(&entry)
+            }
+            Err(_) => {
+                println!("Dropping a batch interrupted before any result was emitted");
+                self.clear_journal()
+            }
+        }
+    }
+
+    // Steps 2 to 4; repeating them after a crash gives the same outcome
+    fn finish(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        self.output.set_len(entry.output_len)?;
+        self.output.seek(SeekFrom::End(0))?;
+        let mut lines = Vec::new();
+        for result in &entry.results {
+            serde_json::to_writer(&mut lines, result)?;
+            lines.push(b'\n');
+        }
+        self.output.write_all(&lines)?;
+        self.output.sync_data()?;
+        for result in &entry.results {
+            let from = self.dir.join(&result.file);
+            match fs::rename(&from, self.done_dir.join(&result.file)) {
+                Ok(()) => {}
+                // Moved before the crash
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("cannot move {}", from.display()))
+                }
+            }
+        }
+        sync_dir(&self.dir)?;
+        sync_dir(&self.done_dir)?;
+        self.clear_journal()
+    }
+
+    fn clear_journal(&self) -> anyhow::Result<()> {
+        let journal = File::create(&self.journal)?;
+        journal.sync_all()?;
+        Ok(())
+    }
+}
+
+// Makes the renames and creations in `dir` durable
+fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A spool, done directory and output under the temp directory
+    struct Dirs {
+        root: PathBuf,
+        spool: PathBuf,
+        done: PathBuf,
+        output: PathBuf,
+    }
+
+    impl Dirs {
+        fn new(test: &str) -> Self {
+            let root = std::env::temp_dir().join(format!("spool-{}-{}", test, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            let spool = root.join("spool");
+            fs::create_dir_all(&spool).unwrap();
+            Self {
+                done: root.join("done"),
+                output: root.join("results.jsonl"),
+                spool,
+                root,
+            }
+        }
+
+        fn open(&self) -> Spool {
+            Spool::open(&self.spool, &self.done, &self.output).unwrap()
+        }
+
+        fn arrive(&self, names: &[&str]) {
+            for name in names {
+                fs::write(self.spool.join(name), name).unwrap();
+            }
+        }
+
+        fn emitted(&self) -> Vec<FileResult> {
+            fs::read_to_string(&self.output)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+
+        fn listed(&self, dir: &Path) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| name != JOURNAL_NAME)
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for Dirs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn result(file: &str) -> FileResult {
+        FileResult::classified(file, file.len() as u8, file.to_string(), 0.5)
+    }
+
+    fn names(results: &[FileResult]) -> Vec<&str> {
+        results.iter().map(|r| r.file.as_str()).collect()
+    }
+
+    #[test]
+    fn committed_files_are_emitted_once_and_moved() {
+        let dirs = Dirs::new("commit");
+        dirs.arrive(&["a.png", "b.png", ".c.png.tmp"]);
+        let mut spool = dirs.open();
+        let waiting = spool.waiting().unwrap();
+        // Hidden files are still being written
+        assert_eq!(
+            waiting,
+            [spool.dir().join("a.png"), spool.dir().join("b.png")]
+        );
+        assert!(!spool.is_candidate(&spool.dir().join(".c.png.tmp")));
+        assert!(!spool.is_candidate(&dirs.done.join("a.png")));
+
+        spool
+            .commit(vec![result("a.png"), result("b.png")])
+            .unwrap();
+        assert_eq!(dirs.emitted(), [result("a.png"), result("b.png")]);
+        assert_eq!(dirs.listed(&dirs.done), ["a.png", "b.png"]);
+        assert_eq!(dirs.listed(&dirs.spool), [".c.png.tmp"]);
+        assert!(spool.waiting().unwrap().is_empty());
+        // Errors are emitted in place of a class
+        dirs.arrive(&["d.png"]);
+        let failed = FileResult::failed("d.png", &anyhow::anyhow!("not an image"));
+        spool.commit(vec![failed.clone()]).unwrap();
+        assert_eq!(dirs.emitted()[2], failed);
+        assert_eq!(fs::read(dirs.done.join(JOURNAL_NAME)).unwrap(), b"");
+
+        // Reopening emits nothing again
+        drop(spool);
+        dirs.open();
+        assert_eq!(dirs.emitted().len(), 3);
+        assert!(Spool::open(&dirs.spool, &dirs.spool, &dirs.output).is_err());
+    }
+
+    #[test]
+    fn batches_interrupted_after_the_journal_are_finished() {
+        let dirs = Dirs::new("recover");
+        dirs.arrive(&["a.png", "b.png", "c.png", "d.png"]);
+        let mut spool = dirs.open();
+        spool.commit(vec![result("a.png")]).unwrap();
+        drop(spool);
+        let output_len = fs::metadata(&dirs.output).unwrap().len();
+
+        // A crash while committing b, c and d: journal written, part of a
+        // line emitted, b moved
+        let entry = JournalEntry {
+            output_len,
+            results: vec![result("b.png"), result("c.png"), result("d.png")],
+        };
+        fs::write(
+            dirs.done.join(JOURNAL_NAME),
+            serde_json::to_vec(&entry).unwrap(),
+        )
+        .unwrap();
+        let mut output = OpenOptions::new().append(true).open(&dirs.output).unwrap();
+        output.write_all(b"{\"file\":\"b.p").unwrap();
+        fs::rename(dirs.spool.join("b.png"), dirs.done.join("b.png")).unwrap();
+
+        let spool = dirs.open();
+        let emitted = dirs.emitted();
+        assert_eq!(names(&emitted), ["a.png", "b.png", "c.png", "d.png"]);
+        assert_eq!(emitted[1..], entry.results);
+        assert_eq!(
+            dirs.listed(&dirs.done),
+            ["a.png", "b.png", "c.png", "d.png"]
+        );
+        assert!(spool.waiting().unwrap().is_empty());
+        // Finishing it again changes nothing
+        drop(spool);
+        fs::write(
+            dirs.done.join(JOURNAL_NAME),
+            serde_json::to_vec(&entry).unwrap(),
+        )
+        .unwrap();
+        dirs.open();
+        assert_eq!(dirs.emitted(), emitted);
+    }
+
+    #[test]
+    fn torn_journals_leave_their_files_waiting() {
+        let dirs = Dirs::new("torn");
+        dirs.arrive(&["a.png", "b.png"]);
+        fs::create_dir_all(&dirs.done).unwrap();
+        fs::write(dirs.done.join(JOURNAL_NAME), b"{\"output_len\":0,\"resu").unwrap();
+        let mut spool = dirs.open();
+        assert_eq!(dirs.emitted(), []);
+        assert_eq!(spool.waiting().unwrap().len(), 2);
+        // And they are committed like any other
+        spool
+            .commit(vec![result("a.png"), result("b.png")])
+            .unwrap();
+        assert_eq!(names(&dirs.emitted()), ["a.png", "b.png"]);
+    }
+}
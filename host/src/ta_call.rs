@@ -0,0 +1,994 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Typed TA command invocations. A `TaCall` names its command once and takes
+// its parameters in order, the unset ones passed as `ParamNone`; the
+// `TaReply` it returns only reads back what the call declared as output, so
+// reading the value of an input or the size of an unused parameter doesn't
+// compile. Output sizes are checked against what the command may write
+// before any of the buffer is read.
+
+use std::fmt;
+
+use optee_teec::{
+    raw, ErrorKind, Operation, Param, ParamNone, ParamTmpRef, ParamType, ParamValue, Session,
+};
+use proto::inference::Command;
+
+/// Parameters the TA writes a value pair back to.
+pub trait ValueParam: Param {
+    fn value(&self) -> (u32, u32);
+}
+
+/// Parameters the TA writes bytes to, reporting how many.
+pub trait OutputParam: Param {
+    fn updated_size(&self) -> usize;
+}
+
+// Wraps an optee_teec parameter so the wrapper's type records its direction
+macro_rules! typed_param {
+    ($(#[$doc:meta])* $name:ident $(<$lt:lifetime>)?, $inner:ty) => {
+        $(#[$doc])*
+        pub struct $name$(<$lt>)?($inner);
+
+        impl$(<$lt>)? Param for $name$(<$lt>)? {
+            fn into_raw(&mut self) -> raw::TEEC_Parameter {
+                self.0.into_raw()
+            }
+
+            fn param_type(&self) -> ParamType {
+                self.0.param_type()
+            }
+
+            fn from_raw(raw: raw::TEEC_Parameter, param_type: ParamType) -> Self {
+                Self(<$inner>::from_raw(raw, param_type))
+            }
+        }
+    };
+}
+
+typed_param!(
+    /// Bytes sent to the TA.
+    Input<'a>,
+    ParamTmpRef<'a>
+);
+typed_param!(
+    /// Buffer the TA fills.
+    Output<'a>,
+    ParamTmpRef<'a>
+);
+typed_param!(
+    /// Value pair sent to the TA.
+    ValueIn,
+    ParamValue
+);
+typed_param!(
+    /// Value pair the TA sets.
+    ValueOut,
+    ParamValue
+);
+typed_param!(
+    /// Value pair sent to the TA, which may overwrite it.
+    ValueInout,
+    ParamValue
+);
+
+/// Buffer a TA fills with a diagnostic when the command fails (see
+/// `inference::ERROR_DETAIL_TAG`), or nothing for TAs that don't write one.
+pub struct ErrorDetail<'a>(Option<ParamTmpRef<'a>>);
+
+impl Param for ErrorDetail<'_> {
+    fn into_raw(&mut self) -> raw::TEEC_Parameter {
+        match &mut self.0 {
+            Some(param) => param.into_raw(),
+            None => ParamNone.into_raw(),
+        }
+    }
+
+    fn param_type(&self) -> ParamType {
+        self.0.as_ref().map_or(ParamType::None, Param::param_type)
+    }
+
+    // `Operation::parameters` decodes the type of every parameter but the
+    // first as None, so an absent buffer is told by its null pointer
+    fn from_raw(raw: raw::TEEC_Parameter, _param_type: ParamType) -> Self {
+        if unsafe { raw.tmpref }.buffer.is_null() {
+            Self(None)
+        } else {
+            Self(Some(ParamTmpRef::from_raw(
+                raw,
+                ParamType::MemrefTempOutput,
+            )))
+        }
+    }
+}
+
+impl OutputParam for ErrorDetail<'_> {
+    fn updated_size(&self) -> usize {
+        self.0.as_ref().map_or(0, ParamTmpRef::updated_size)
+    }
+}
+
+impl OutputParam for Output<'_> {
+    fn updated_size(&self) -> usize {
+        self.0.updated_size()
+    }
+}
+
+impl ValueParam for ValueOut {
+    fn value(&self) -> (u32, u32) {
+        (self.0.a(), self.0.b())
+    }
+}
+
+impl ValueParam for ValueInout {
+    fn value(&self) -> (u32, u32) {
+        (self.0.a(), self.0.b())
+    }
+}
+
+/// The parameters declared so far, as a tuple of up to four.
+pub trait Params {
+    type P0: Param;
+    type P1: Param;
+    type P2: Param;
+    type P3: Param;
+
+    fn into_operation(self, cmd: u32) -> Operation<Self::P0, Self::P1, Self::P2, Self::P3>;
+
+    /// Types of the four parameters. `Operation::parameters` can't be asked:
+    /// it decodes all but the first as None.
+    #[cfg(test)]
+    fn param_types(&self) -> [ParamType; 4];
+}
+
+/// Operation a call with parameters `P` is invoked with.
+pub type CallOperation<P> =
+    Operation<<P as Params>::P0, <P as Params>::P1, <P as Params>::P2, <P as Params>::P3>;
+
+impl Params for () {
+    type P0 = ParamNone;
+    type P1 = ParamNone;
+    type P2 = ParamNone;
+    type P3 = ParamNone;
+
+    fn into_operation(self, cmd: u32) -> CallOperation<Self> {
+        Operation::new(cmd, ParamNone, ParamNone, ParamNone, ParamNone)
+    }
+
+    #[cfg(test)]
+    fn param_types(&self) -> [ParamType; 4] {
+        [ParamType::None; 4]
+    }
+}
+
+impl<A: Param> Params for (A,) {
+    type P0 = A;
+    type P1 = ParamNone;
+    type P2 = ParamNone;
+    type P3 = ParamNone;
+
+    fn into_operation(self, cmd: u32) -> CallOperation<Self> {
+        Operation::new(cmd, self.0, ParamNone, ParamNone, ParamNone)
+    }
+
+    #[cfg(test)]
+    fn param_types(&self) -> [ParamType; 4] {
+        [
+            self.0.param_type(),
+            ParamType::None,
+            ParamType::None,
+            ParamType::None,
+        ]
+    }
+}
+
+impl<A: Param, B: Param> Params for (A, B) {
+    type P0 = A;
+    type P1 = B;
+    type P2 = ParamNone;
+    type P3 = ParamNone;
+
+    fn into_operation(self, cmd: u32) -> CallOperation<Self> {
+        Operation::new(cmd, self.0, self.1, ParamNone, ParamNone)
+    }
+
+    #[cfg(test)]
+    fn param_types(&self) -> [ParamType; 4] {
+        [
+            self.0.param_type(),
+            self.1.param_type(),
+            ParamType::None,
+            ParamType::None,
+        ]
+    }
+}
+
+impl<A: Param, B: Param, C: Param> Params for (A, B, C) {
+    type P0 = A;
+    type P1 = B;
+    type P2 = C;
+    type P3 = ParamNone;
+
+    fn into_operation(self, cmd: u32) -> CallOperation<Self> {
+        Operation::new(cmd, self.0, self.1, self.2, ParamNone)
+    }
+
+    #[cfg(test)]
+    fn param_types(&self) -> [ParamType; 4] {
+        [
+            self.0.param_type(),
+            self.1.param_type(),
+            self.2.param_type(),
+            ParamType::None,
+        ]
+    }
+}
+
+impl<A: Param, B: Param, C: Param, D: Param> Params for (A, B, C, D) {
+    type P0 = A;
+    type P1 = B;
+    type P2 = C;
+    type P3 = D;
+
+    fn into_operation(self, cmd: u32) -> CallOperation<Self> {
+        Operation::new(cmd, self.0, self.1, self.2, self.3)
+    }
+
+    #[cfg(test)]
+    fn param_types(&self) -> [ParamType; 4] {
+        [
+            self.0.param_type(),
+            self.1.param_type(),
+            self.2.param_type(),
+            self.3.param_type(),
+        ]
+    }
+}
+
+/// Declares the next parameter; there is no fifth.
+pub trait Append<T> {
+    type Output;
+
+    fn append(self, param: T) -> Self::Output;
+}
+
+impl<T> Append<T> for () {
+    type Output = (T,);
+
+    fn append(self, param: T) -> Self::Output {
+        (param,)
+    }
+}
+
+impl<A, T> Append<T> for (A,) {
+    type Output = (A, T);
+
+    fn append(self, param: T) -> Self::Output {
+        (self.0, param)
+    }
+}
+
+impl<A, B, T> Append<T> for (A, B) {
+    type Output = (A, B, T);
+
+    fn append(self, param: T) -> Self::Output {
+        (self.0, self.1, param)
+    }
+}
+
+impl<A, B, C, T> Append<T> for (A, B, C) {
+    type Output = (A, B, C, T);
+
+    fn append(self, param: T) -> Self::Output {
+        (self.0, self.1, self.2, param)
+    }
+}
+
+/// The parameter at index `N` of an operation.
+pub trait Slot<const N: usize>: Params {
+    type Param: Param;
+
+    fn get(op: &CallOperation<Self>) -> Self::Param;
+}
+
+impl<P: Params> Slot<0> for P {
+    type Param = P::P0;
+
+    fn get(op: &CallOperation<Self>) -> Self::Param {
+        op.parameters().0
+    }
+}
+
+impl<P: Params> Slot<1> for P {
+    type Param = P::P1;
+
+    fn get(op: &CallOperation<Self>) -> Self::Param {
+        op.parameters().1
+    }
+}
+
+impl<P: Params> Slot<2> for P {
+    type Param = P::P2;
+
+    fn get(op: &CallOperation<Self>) -> Self::Param {
+        op.parameters().2
+    }
+}
+
+impl<P: Params> Slot<3> for P {
+    type Param = P::P3;
+
+    fn get(op: &CallOperation<Self>) -> Self::Param {
+        op.parameters().3
+    }
+}
+
+/// One invocation of `cmd`, its parameters declared in order.
+pub struct TaCall<P> {
+    cmd: Command,
+    params: P,
+}
+
+impl TaCall<()> {
+    pub fn new(cmd: Command) -> Self {
+        Self { cmd, params: () }
+    }
+}
+
+impl<P> TaCall<P> {
+    pub fn command(&self) -> Command {
+        self.cmd
+    }
+
+    fn then<T>(self, param: T) -> TaCall<P::Output>
+    where
+        P: Append<T>,
+    {
+        TaCall {
+            cmd: self.cmd,
+            params: self.params.append(param),
+        }
+    }
+
+    pub fn input<'a>(self, bytes: &'a [u8]) -> TaCall<<P as Append<Input<'a>>>::Output>
+    where
+        P: Append<Input<'a>>,
+    {
+        self.then(Input(ParamTmpRef::new_input(bytes)))
+    }
+
+    pub fn output<'a>(self, buf: &'a mut [u8]) -> TaCall<<P as Append<Output<'a>>>::Output>
+    where
+        P: Append<Output<'a>>,
+    {
+        self.then(Output(ParamTmpRef::new_output(buf)))
+    }
+
+    /// A parameter the command doesn't take here.
+    pub fn none(self) -> TaCall<<P as Append<ParamNone>>::Output>
+    where
+        P: Append<ParamNone>,
+    {
+        self.then(ParamNone)
+    }
+
+    /// `buf` for the TA's error detail, or None when the TA doesn't write one.
+    pub fn error_detail<'a>(
+        self,
+        buf: Option<&'a mut [u8]>,
+    ) -> TaCall<<P as Append<ErrorDetail<'a>>>::Output>
+    where
+        P: Append<ErrorDetail<'a>>,
+    {
+        self.then(ErrorDetail(buf.map(ParamTmpRef::new_output)))
+    }
+
+    pub fn value(self, a: u32, b: u32) -> TaCall<<P as Append<ValueIn>>::Output>
+    where
+        P: Append<ValueIn>,
+    {
+        self.then(ValueIn(ParamValue::new(a, b, ParamType::ValueInput)))
+    }
+
+    pub fn value_out(self) -> TaCall<<P as Append<ValueOut>>::Output>
+    where
+        P: Append<ValueOut>,
+    {
+        self.then(ValueOut(ParamValue::new(0, 0, ParamType::ValueOutput)))
+    }
+
+    pub fn value_inout(self, a: u32, b: u32) -> TaCall<<P as Append<ValueInout>>::Output>
+    where
+        P: Append<ValueInout>,
+    {
+        self.then(ValueInout(ParamValue::new(a, b, ParamType::ValueInout)))
+    }
+}
+
+impl<P: Params> TaCall<P> {
+    /// Invokes the command on `sess`. The reply is returned with the result
+    /// since TAs report details of some failures in output values.
+    pub fn invoke(self, sess: &mut Session) -> (optee_teec::Result<()>, TaReply<P>) {
+        self.invoke_with(|cmd, op| sess.invoke_command(cmd, op))
+    }
+
+    /// Like `invoke`, with `invoke` running the operation under the command
+    /// id, for callers that time or retry the invocation.
+    pub fn invoke_with(
+        self,
+        invoke: impl FnOnce(u32, &mut CallOperation<P>) -> optee_teec::Result<()>,
+    ) -> (optee_teec::Result<()>, TaReply<P>) {
+        let cmd = self.cmd as u32;
+        let mut op = self.params.into_operation(cmd);
+        let result = invoke(cmd, &mut op);
+        (result, TaReply { cmd: self.cmd, op })
+    }
+}
+
+/// A parameter as the TA played by a test sees it in
+/// `TaCall::invoke_mocked`.
+#[cfg(test)]
+pub enum MockParam<'a> {
+    None,
+    Input(&'a [u8]),
+    /// The host's buffer, and the size reported back, its length until the
+    /// mock changes it.
+    Output {
+        buffer: &'a mut [u8],
+        size: usize,
+    },
+    Value {
+        a: u32,
+        b: u32,
+    },
+}
+
+#[cfg(test)]
+impl<P: Params> TaCall<P> {
+    /// `invoke_with` with `ta` playing the TA and the driver: it gets the
+    /// command id and each parameter, and the output sizes and values it
+    /// leaves in them are what the reply reports.
+    pub fn invoke_mocked(
+        self,
+        ta: impl FnOnce(u32, &mut [MockParam<'_>; 4]) -> optee_teec::Result<()>,
+    ) -> (optee_teec::Result<()>, TaReply<P>) {
+        let types = self.params.param_types();
+        self.invoke_with(|cmd, op| {
+            let (mut p0, mut p1, mut p2, mut p3) = op.parameters();
+            let mut raw = [p0.into_raw(), p1.into_raw(), p2.into_raw(), p3.into_raw()];
+            // The buffers are the ones the call borrowed, alive until the
+            // reply is dropped
+            let mut params = [0, 1, 2, 3].map(|i| unsafe { mock_param(&raw[i], types[i]) });
+            let result = ta(cmd, &mut params);
+            for (raw, param) in raw.iter_mut().zip(&params) {
+                match param {
+                    MockParam::Output { size, .. } => raw.tmpref.size = *size,
+                    &MockParam::Value { a, b } => raw.value = raw::TEEC_Value { a, b },
+                    MockParam::None | MockParam::Input(_) => {}
+                }
+            }
+            *op = Operation::new(
+                cmd,
+                P::P0::from_raw(raw[0], types[0]),
+                P::P1::from_raw(raw[1], types[1]),
+                P::P2::from_raw(raw[2], types[2]),
+                P::P3::from_raw(raw[3], types[3]),
+            );
+            result
+        })
+    }
+}
+
+// Safety: temporary memrefs in `raw` must point at live buffers of their
+// size, output ones not otherwise borrowed
+#[cfg(test)]
+unsafe fn mock_param<'a>(raw: &raw::TEEC_Parameter, param_type: ParamType) -> MockParam<'a> {
+    match param_type {
+        ParamType::None => MockParam::None,
+        ParamType::ValueInput | ParamType::ValueOutput | ParamType::ValueInout => {
+            MockParam::Value {
+                a: raw.value.a,
+                b: raw.value.b,
+            }
+        }
+        ParamType::MemrefTempInput => {
+            let tmpref = raw.tmpref;
+            MockParam::Input(std::slice::from_raw_parts(
+                tmpref.buffer as *const u8,
+                tmpref.size,
+            ))
+        }
+        _ => {
+            let tmpref = raw.tmpref;
+            MockParam::Output {
+                buffer: std::slice::from_raw_parts_mut(tmpref.buffer as *mut u8, tmpref.size),
+                size: tmpref.size,
+            }
+        }
+    }
+}
+
+/// What a command may write to an output parameter.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputSize {
+    /// Exactly this many bytes.
+    Exact(usize),
+    /// Up to this many bytes, usually the buffer's length.
+    AtMost(usize),
+    /// Whole records of `unit` bytes, up to `max` bytes.
+    MultipleOf { unit: usize, max: usize },
+}
+
+impl OutputSize {
+    fn allows(self, size: usize) -> bool {
+        match self {
+            OutputSize::Exact(expected) => size == expected,
+            OutputSize::AtMost(max) => size <= max,
+            OutputSize::MultipleOf { unit, max } => size <= max && size % unit == 0,
+        }
+    }
+}
+
+impl fmt::Display for OutputSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputSize::Exact(expected) => write!(f, "exactly {}", expected),
+            OutputSize::AtMost(max) => write!(f, "at most {}", max),
+            OutputSize::MultipleOf { unit, max } => {
+                write!(f, "a multiple of {} up to {}", unit, max)
+            }
+        }
+    }
+}
+
+/// An output parameter the TA reported an inconsistent size for; none of
+/// the buffer should be trusted.
+#[derive(Debug)]
+pub struct OutputSizeError {
+    pub command: Command,
+    pub param: usize,
+    pub size: usize,
+    pub expected: OutputSize,
+}
+
+impl fmt::Display for OutputSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} reported {} bytes in output parameter {}, expected {}",
+            self.command, self.size, self.param, self.expected
+        )
+    }
+}
+
+// optee_teec errors carry no message, so it is printed on the way
+impl From<OutputSizeError> for optee_teec::Error {
+    fn from(err: OutputSizeError) -> Self {
+        println!("{}", err);
+        ErrorKind::BadFormat.into()
+    }
+}
+
+/// What the TA wrote back to the parameters of a `TaCall`.
+pub struct TaReply<P: Params> {
+    cmd: Command,
+    op: CallOperation<P>,
+}
+
+impl<P: Params> TaReply<P> {
+    /// Values a and b of parameter `N`, declared as value output or inout.
+    pub fn value<const N: usize>(&self) -> (u32, u32)
+    where
+        P: Slot<N>,
+        <P as Slot<N>>::Param: ValueParam,
+    {
+        <P as Slot<N>>::get(&self.op).value()
+    }
+
+    /// Bytes the TA says it wrote to parameter `N`, declared as an output
+    /// buffer. Unchecked, for transcripts; read buffers with `checked_size`.
+    pub fn size<const N: usize>(&self) -> usize
+    where
+        P: Slot<N>,
+        <P as Slot<N>>::Param: OutputParam,
+    {
+        <P as Slot<N>>::get(&self.op).updated_size()
+    }
+
+    /// `size`, failing unless it is what `expected` allows.
+    pub fn checked_size<const N: usize>(
+        &self,
+        expected: OutputSize,
+    ) -> Result<usize, OutputSizeError>
+    where
+        P: Slot<N>,
+        <P as Slot<N>>::Param: OutputParam,
+    {
+        let size = self.size::<N>();
+        if !expected.allows(size) {
+            return Err(OutputSizeError {
+                command: self.cmd,
+                param: N,
+                size,
+                expected,
+            });
+        }
+        Ok(size)
+    }
+}
+
+/// Runs `attempt` with `output` and, when the TA answers ShortBuffer with a
+/// larger size than `output` has, grows it to that size and tries once more.
+/// `attempt` returns its result and the size the TA reported needing;
+/// `on_retry` is told the size before the retry.
+pub fn retry_short_buffer(
+    output: &mut Vec<u8>,
+    mut attempt: impl FnMut(&mut [u8]) -> (optee_teec::Result<()>, usize),
+    on_retry: impl FnOnce(usize),
+) -> optee_teec::Result<()> {
+    match attempt(output) {
+        (Err(err), required)
+            if matches!(err.kind(), ErrorKind::ShortBuffer) && required > output.len() =>
+        {
+            on_retry(required);
+            output.resize(required, 0);
+            attempt(output).0
+        }
+        (result, _) => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Invokes `call` with the driver's part played here: each (param, size)
+    // is reported as the size written to that output, as the TA would
+    fn reply_with<P: Params>(call: TaCall<P>, sizes: &[(usize, usize)]) -> TaReply<P> {
+        let (result, reply) = call.invoke_mocked(|_, params| {
+            for &(param, reported) in sizes {
+                match &mut params[param] {
+                    MockParam::Output { size, .. } => *size = reported,
+                    _ => panic!("parameter {param} isn't an output"),
+                }
+            }
+            Ok(())
+        });
+        result.unwrap();
+        reply
+    }
+
+    // Plays a TA answering Infer with `labels` bytes: ShortBuffer with the
+    // size it needs in value a of parameter 2 when the output is smaller
+    fn infer_attempt(
+        labels: usize,
+        attempts: &mut Vec<usize>,
+    ) -> impl FnMut(&mut [u8]) -> (optee_teec::Result<()>, usize) + '_ {
+        move |output| {
+            attempts.push(output.len());
+            let call = TaCall::new(Command::Infer)
+                .input(&[])
+                .output(output)
+                .value_inout(0, 0);
+            let (result, reply) = call.invoke_mocked(|_, params| {
+                let [_, MockParam::Output { buffer, size }, MockParam::Value { a, .. }, _] = params
+                else {
+                    panic!("not an Infer call");
+                };
+                if buffer.len() < labels {
+                    *a = labels as u32;
+                    return Err(ErrorKind::ShortBuffer.into());
+                }
+                buffer[..labels].fill(7);
+                *size = labels;
+                Ok(())
+            });
+            let size = reply.checked_size::<1>(OutputSize::Exact(labels));
+            let result = result.and_then(|()| size.map(drop).map_err(Into::into));
+            (result, reply.value::<2>().0 as usize)
+        }
+    }
+
+    // Types the call declares, as the driver encodes them
+    fn types<P: Params>(call: &TaCall<P>) -> [u32; 4] {
+        call.params.param_types().map(|t| t as u32)
+    }
+
+    #[test]
+    fn each_shape_declares_its_parameters_in_order() {
+        use ParamType::{
+            MemrefTempInput as In, MemrefTempOutput as Out, None as No, ValueInout as VIo,
+            ValueInput as VIn, ValueOutput as VOut,
+        };
+        let expect = |types: [ParamType; 4]| types.map(|t| t as u32);
+        let (mut a, mut b) = ([0_u8; 4], [0_u8; 4]);
+        assert_eq!(
+            types(&TaCall::new(Command::CommitModel)),
+            expect([No, No, No, No])
+        );
+        assert_eq!(
+            types(&TaCall::new(Command::GetKeyFingerprint).output(&mut a)),
+            expect([Out, No, No, No])
+        );
+        assert_eq!(
+            types(&TaCall::new(Command::SetTraceId).value_inout(1, 2)),
+            expect([VIo, No, No, No])
+        );
+        assert_eq!(
+            types(&TaCall::new(Command::ModelStatus).output(&mut a).value(0, 0)),
+            expect([Out, VIn, No, No])
+        );
+        assert_eq!(
+            types(
+                &TaCall::new(Command::StoragePreflight)
+                    .value(0, 0)
+                    .value_out()
+                    .value_out()
+            ),
+            expect([VIn, VOut, VOut, No])
+        );
+        assert_eq!(
+            types(
+                &TaCall::new(Command::Infer)
+                    .input(&[])
+                    .output(&mut a)
+                    .none()
+                    .output(&mut b)
+            ),
+            expect([In, Out, No, Out])
+        );
+        assert_eq!(
+            types(
+                &TaCall::new(Command::StageModel)
+                    .input(&[])
+                    .none()
+                    .value_inout(0, 0)
+                    .error_detail(Some(&mut a))
+            ),
+            expect([In, No, VIo, Out])
+        );
+        // No error detail buffer leaves the parameter unused
+        assert_eq!(
+            types(&TaCall::new(Command::StageModel).error_detail(None)),
+            expect([No, No, No, No])
+        );
+    }
+
+    #[test]
+    fn calls_invoke_the_command_they_were_built_for() {
+        for cmd in [Command::Infer, Command::ModelStatus, Command::SetTraceId] {
+            let call = TaCall::new(cmd).value_inout(7, 9);
+            assert_eq!(call.command(), cmd);
+            let (result, reply) = call.invoke_mocked(|id, params| {
+                assert_eq!(id, cmd as u32);
+                let MockParam::Value { a, b } = &mut params[0] else {
+                    panic!("not a value");
+                };
+                assert_eq!((*a, *b), (7, 9));
+                (*a, *b) = (*b, *a);
+                Ok(())
+            });
+            result.unwrap();
+            // Inout values come back as the TA left them
+            assert_eq!(reply.value::<0>(), (9, 7));
+        }
+    }
+
+    #[test]
+    fn mocked_tas_see_inputs_and_report_values() {
+        let call = TaCall::new(Command::StoragePreflight)
+            .value(4096, 0)
+            .value_out()
+            .value_out();
+        let (result, reply) = call.invoke_mocked(|cmd, params| {
+            assert_eq!(cmd, Command::StoragePreflight as u32);
+            let [MockParam::Value { a: needed, .. }, MockParam::Value { a: used, b: quota }, MockParam::Value { a: fits, .. }, MockParam::None] =
+                params
+            else {
+                panic!("not a StoragePreflight call");
+            };
+            (*used, *quota, *fits) = (1024, 8192, (*needed <= 8192 - 1024) as u32);
+            Ok(())
+        });
+        result.unwrap();
+        assert_eq!(reply.value::<1>(), (1024, 8192));
+        assert_eq!(reply.value::<2>(), (1, 0));
+
+        let mut output = [0_u8; 3];
+        let call = TaCall::new(Command::EncryptModel)
+            .input(b"model")
+            .output(&mut output);
+        let (result, reply) = call.invoke_mocked(|_, params| {
+            let [MockParam::Input(input), MockParam::Output { buffer, size }, ..] = params else {
+                panic!("not an EncryptModel call");
+            };
+            buffer.copy_from_slice(&input[..3]);
+            *size = 3;
+            Err(ErrorKind::Generic.into())
+        });
+        assert!(matches!(result.unwrap_err().kind(), ErrorKind::Generic));
+        assert_eq!(reply.size::<1>(), 3);
+        assert_eq!(&output, b"mod");
+    }
+
+    #[test]
+    fn exact_fit_is_not_retried() {
+        let mut output = vec![0_u8; 4];
+        let mut attempts = Vec::new();
+        let result = retry_short_buffer(&mut output, infer_attempt(4, &mut attempts), |_| {
+            panic!("retried an exact fit")
+        });
+        result.unwrap();
+        assert_eq!(attempts, [4]);
+        assert_eq!(output.len(), 4);
+    }
+
+    #[test]
+    fn one_byte_short_is_retried_with_the_required_size() {
+        let mut output = vec![0_u8; 4];
+        let mut attempts = Vec::new();
+        let mut retried = None;
+        let result = retry_short_buffer(&mut output, infer_attempt(5, &mut attempts), |n| {
+            retried = Some(n)
+        });
+        result.unwrap();
+        assert_eq!(attempts, [4, 5]);
+        assert_eq!(retried, Some(5));
+        assert_eq!(output, [7; 5]);
+    }
+
+    #[test]
+    fn zero_length_output_is_grown() {
+        let mut output = Vec::new();
+        let mut attempts = Vec::new();
+        retry_short_buffer(&mut output, infer_attempt(3, &mut attempts), |_| {}).unwrap();
+        assert_eq!(attempts, [0, 3]);
+        assert_eq!(output.len(), 3);
+    }
+
+    #[test]
+    fn short_buffer_without_a_larger_size_fails() {
+        // A TA asking for no more than it was given, or asking again after
+        // the retry, gets its error back instead of another attempt
+        let mut output = vec![0_u8; 4];
+        let mut calls = 0;
+        let result = retry_short_buffer(
+            &mut output,
+            |_| {
+                calls += 1;
+                (Err(ErrorKind::ShortBuffer.into()), 4)
+            },
+            |_| panic!("retried without a larger size"),
+        );
+        assert!(matches!(result.unwrap_err().kind(), ErrorKind::ShortBuffer));
+        assert_eq!(calls, 1);
+
+        let mut calls = 0;
+        let result = retry_short_buffer(
+            &mut output,
+            |output| {
+                calls += 1;
+                (Err(ErrorKind::ShortBuffer.into()), output.len() + 1)
+            },
+            |_| {},
+        );
+        assert!(matches!(result.unwrap_err().kind(), ErrorKind::ShortBuffer));
+        assert_eq!(calls, 2);
+        assert_eq!(output.len(), 5);
+    }
+
+    #[test]
+    fn exact_sizes() {
+        let mut buf = [0_u8; 8];
+        let expected = OutputSize::Exact(buf.len());
+        for (size, allowed) in [(8, true), (9, false), (0, false), (7, false)] {
+            let call = TaCall::new(Command::GetKeyFingerprint).output(&mut buf);
+            let reply = reply_with(call, &[(0, size)]);
+            assert_eq!(reply.size::<0>(), size);
+            assert_eq!(
+                reply.checked_size::<0>(expected).ok(),
+                allowed.then_some(size)
+            );
+        }
+    }
+
+    #[test]
+    fn bounded_sizes() {
+        let mut buf = [0_u8; 16];
+        let expected = OutputSize::AtMost(buf.len());
+        for (size, allowed) in [(0, true), (16, true), (17, false), (usize::MAX, false)] {
+            let call = TaCall::new(Command::ModelStatus).output(&mut buf);
+            let reply = reply_with(call, &[(0, size)]);
+            assert_eq!(
+                reply.checked_size::<0>(expected).ok(),
+                allowed.then_some(size)
+            );
+        }
+    }
+
+    #[test]
+    fn record_sizes() {
+        let mut buf = [0_u8; 16];
+        let expected = OutputSize::MultipleOf { unit: 4, max: 16 };
+        for (size, allowed) in [(0, true), (12, true), (16, true), (14, false), (20, false)] {
+            let call = TaCall::new(Command::Infer).input(&[]).output(&mut buf);
+            let reply = reply_with(call, &[(1, size)]);
+            assert_eq!(
+                reply.checked_size::<1>(expected).ok(),
+                allowed.then_some(size)
+            );
+        }
+    }
+
+    #[test]
+    fn errors_name_the_parameter() {
+        let mut output = [0_u8; 4];
+        let mut probs = [0_u8; 40];
+        let call = TaCall::new(Command::Infer)
+            .input(&[])
+            .output(&mut output)
+            .none()
+            .output(&mut probs);
+        let reply = reply_with(call, &[(1, 4), (3, 41)]);
+        assert_eq!(reply.checked_size::<1>(OutputSize::Exact(4)).unwrap(), 4);
+        let err = reply.checked_size::<3>(OutputSize::Exact(40)).unwrap_err();
+        assert_eq!(err.command, Command::Infer);
+        assert_eq!(err.param, 3);
+        assert_eq!(err.size, 41);
+        assert_eq!(err.expected, OutputSize::Exact(40));
+        assert_eq!(
+            err.to_string(),
+            "Infer reported 41 bytes in output parameter 3, expected exactly 40"
+        );
+        let err: optee_teec::Error = err.into();
+        assert!(matches!(err.kind(), ErrorKind::BadFormat));
+    }
+
+    #[test]
+    fn size_descriptions() {
+        assert_eq!(OutputSize::AtMost(16).to_string(), "at most 16");
+        assert_eq!(
+            OutputSize::MultipleOf { unit: 4, max: 16 }.to_string(),
+            "a multiple of 4 up to 16"
+        );
+    }
+
+    #[test]
+    fn absent_error_detail_reports_nothing() {
+        let call = TaCall::new(Command::StageModel)
+            .none()
+            .none()
+            .none()
+            .error_detail(None);
+        let reply = reply_with(call, &[]);
+        assert_eq!(reply.size::<3>(), 0);
+        assert_eq!(reply.checked_size::<3>(OutputSize::AtMost(0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn error_details_report_their_size() {
+        // Read back from the last parameter, past the ones the SDK decodes
+        let mut detail = [0_u8; 16];
+        let call = TaCall::new(Command::StageModel)
+            .value_inout(0, 0)
+            .none()
+            .none()
+            .error_detail(Some(&mut detail));
+        assert_eq!(reply_with(call, &[(3, 9)]).size::<3>(), 9);
+    }
+}
@@ -15,67 +15,2374 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use optee_teec::{Context, ErrorKind, Operation, ParamNone, ParamTmpRef, Session, Uuid};
-use proto::{inference, Image};
+use bytemuck::Zeroable;
+use optee_teec::{
+    Context, ErrorKind, Operation, Param, ParamNone, ParamTmpRef, ParamType, ParamValue, Session,
+    Uuid,
+};
+use proto::inference::{
+    self, BuildManifest, Command, CorrelatedPrediction, CrashReport, InferenceRequestHeader,
+    LoadPhase, ManagePolicy, ModelStatus, ModelUsage, ModelVersion, OutputWindow, Prediction,
+    ResidencyPolicy, SessionRole, ShadowReport, StorageObject, StorageSchema, BUDGET_EXCEEDED,
+    ERROR_DETAIL_BUFFER_SIZE, FLAG_CORRELATION, FLAG_FIXED_POINT_PROBS, FLAG_INPUT_HASHES,
+    FLAG_OUTPUT_WINDOW, FLAG_PREDICTIONS, FLAG_REJECT_BELOW, FLAG_TIME_BUDGET, INPUT_HASH_SIZE,
+};
+use proto::{fixed_point, wire, Image, IMAGE_SIZE};
+use sha2::{Digest, Sha256};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
+use crate::metrics;
+use crate::ta_call::{
+    retry_short_buffer, Input, Output, OutputSize, Params, TaCall, TaReply, ValueIn, ValueInout,
+};
+use crate::transcript::{self, Step};
+
+
+// Set once from `--ta-uuid` / ENC_MNIST_TA_UUID before any session is opened
+static TA_UUID_OVERRIDE: OnceLock<String> = OnceLock::new();
+
+/// Makes every connector talk to the inference TA instance with `uuid`
+/// instead of the compiled-in `proto::inference::UUID`.
+pub fn set_inference_ta_uuid(uuid: &str) -> anyhow::Result<()> {
+    let uuid = proto::parse_uuid(uuid).map_err(|err| anyhow::anyhow!("--ta-uuid: {}", err))?;
+    TA_UUID_OVERRIDE
+        .set(uuid.to_string())
+        .map_err(|_| anyhow::anyhow!("TA UUID already set"))
+}
+
+/// UUID of the inference TA the connectors open, as configured.
+pub fn configured_ta_uuid() -> &'static str {
+    match TA_UUID_OVERRIDE.get() {
+        Some(uuid) => uuid.as_str(),
+        None => inference::UUID,
+    }
+}
+
+fn inference_ta_uuid() -> optee_teec::Result<Uuid> {
+    let uuid = configured_ta_uuid();
+    proto::parse_uuid(uuid)
+        .ok()
+        .and_then(|uuid| Uuid::parse_str(uuid).ok())
+        .ok_or_else(|| {
+            println!("invalid TA uuid \"{}\"", uuid.trim());
+            ErrorKind::BadParameters.into()
+        })
+}
+
+// Records a TA command in the transcript; `reported` is a size the TA sent back
+fn record_invoke(
+    cmd: Command,
+    input_bytes: usize,
+    reported: Option<usize>,
+    result: &optee_teec::Result<()>,
+) {
+    transcript::record(Step::Invoke {
+        command: format!("{:?}", cmd),
+        command_id: cmd as u32,
+        input_bytes,
+        reported,
+        error: result.as_ref().err().map(|err| err.to_string()),
+    });
+}
+
+// Detail the TA gave for the last failed command that reported one, with
+// the command's return code
+static ERROR_DETAIL: Mutex<Option<(u32, String)>> = Mutex::new(None);
+
+// Keeps the error detail a failed `cmd` wrote to the first `size` bytes of
+// `output` for `attach_error_detail`; any other outcome forgets the last one
+fn note_error_detail(cmd: Command, result: &optee_teec::Result<()>, output: &[u8], size: usize) {
+    let detail = error_detail(cmd, result, output, size);
+    *ERROR_DETAIL.lock().unwrap_or_else(|err| err.into_inner()) = detail;
+}
+
+// The detail a failed `cmd` wrote to the first `size` bytes of `output`,
+// with its return code
+fn error_detail(
+    cmd: Command,
+    result: &optee_teec::Result<()>,
+    output: &[u8],
+    size: usize,
+) -> Option<(u32, String)> {
+    let err = result.as_ref().err()?;
+    let detail = inference::parse_error_detail(output.get(..size)?)?;
+    Some((err.raw_code(), format!("{:?}: {}", cmd, detail)))
+}
+
+// For the commands that don't report details, so their failures don't get
+// the detail of an earlier one
+fn forget_error_detail() {
+    ERROR_DETAIL
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take();
+}
+
+/// Adds the detail the TA wrote for the failure behind `err`, if any, as
+/// context.
+pub fn attach_error_detail(err: anyhow::Error) -> anyhow::Error {
+    let detail = ERROR_DETAIL
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .take();
+    with_error_detail(err, detail)
+}
+
+// `err` with `detail` as context when it has the detail's return code
+fn with_error_detail(err: anyhow::Error, detail: Option<(u32, String)>) -> anyhow::Error {
+    let code = err
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<optee_teec::Error>())
+        .map(optee_teec::Error::raw_code);
+    match detail {
+        Some((raw_code, detail)) if code == Some(raw_code) => {
+            err.context(format!("TA reported: {}", detail))
+        }
+        _ => err,
+    }
+}
+
+// Explains the TA-defined error of a model refused by its canary check
+fn report_canary_mismatch(result: &optee_teec::Result<()>, index: u32) {
+    if matches!(result, Err(err) if err.raw_code() == inference::ERROR_CANARY_MISMATCH) {
+        println!(
+            "canary image {} was misclassified, the model was not installed (wrong key?)",
+            index
+        );
+    }
+}
+
+// Explains the TA-defined error of an inference on an expired model
+fn report_license_expired(err: &optee_teec::Error) {
+    if err.raw_code() == inference::ERROR_LICENSE_EXPIRED {
+        println!("the model's license has expired, provision a renewed model");
+    }
+}
+
+// Explains a BadState answer to a model-load command with the phase and the
+// byte count the TA reported
+fn report_load_state(result: &optee_teec::Result<()>, phase: u32, received: u32) {
+    if !matches!(result, Err(err) if matches!(err.kind(), ErrorKind::BadState)) {
+        return;
+    }
+    match LoadPhase::try_from(phase) {
+        Ok(LoadPhase::Idle) => println!("no model load in progress, the TA lost or finished it"),
+        Ok(LoadPhase::Receiving) => println!(
+            "another model load is in progress ({} bytes received), retry once it ends",
+            received
+        ),
+        Ok(LoadPhase::Staged) => println!(
+            "a model load holding all of its {} bytes waits to be finalized",
+            received
+        ),
+        Err(_) => println!("model load out of order (TA load state {})", phase),
+    }
+}
+
+// Explains an OutOfMemory answer to a model load with the bytes the model
+// needs and, from TAs that report it, the bytes the TA has left
+fn report_out_of_memory(needed: u32, available: u32) {
+    if available == 0 {
+        println!(
+            "model needs ~{} bytes, more than the TA memory budget",
+            needed
+        );
+    } else {
+        println!(
+            "model too large for this device (needs ~{} bytes, available ~{})",
+            needed, available
+        );
+    }
+}
+
+// TEE_ERROR_SIGNATURE_INVALID, which the client API has no ErrorKind for
+const ERROR_SIGNATURE_INVALID: u32 = 0xFFFF_3072;
+
+// Explains a SignatureInvalid answer to a model load
+fn report_signature_invalid(result: &optee_teec::Result<()>) {
+    if matches!(result, Err(err) if err.raw_code() == ERROR_SIGNATURE_INVALID) {
+        println!(
+            "the TA has a model verify key and the model's signature is missing or doesn't match"
+        );
+    }
+}
+
+// Explains a session refused because a newer TA migrated the storage
+fn report_storage_downgrade(err: &optee_teec::Error) {
+    if err.raw_code() == inference::ERROR_STORAGE_DOWNGRADE {
+        println!(
+            "the TA's secure storage was written by a newer TA; reinstall that TA or wipe the storage"
+        );
+    }
+}
+
+// Explains a manage session refused by the TA's `ManagePolicy`
+fn report_manage_denied(err: &optee_teec::Error, role: SessionRole) {
+    if role == SessionRole::Manage && matches!(err.kind(), ErrorKind::AccessDenied) {
+        println!("the TA's manage policy doesn't allow this client to manage it");
+    }
+}
+
+// Parameter 3 of open_session for connectors that only manage the TA
+fn manage_role() -> ParamValue {
+    ParamValue::new(SessionRole::Manage.into(), 0, ParamType::ValueInout)
+}
+
+fn report_nothing_staged(result: &optee_teec::Result<()>) {
+    if matches!(result, Err(err) if matches!(err.kind(), ErrorKind::ItemNotFound)) {
+        println!("no model is staged");
+    }
+}
+
+/// Prints why the TA died when `err` is a TargetDead error and the TA, once
+/// restarted in a new session, has a crash report.
+pub fn report_last_crash(err: &anyhow::Error) {
+    let dead = err.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<optee_teec::Error>(),
+            Some(err) if matches!(err.kind(), ErrorKind::TargetDead)
+        )
+    });
+    if !dead {
+        return;
+    }
+    let report = Context::new().and_then(|mut ctx| {
+        let mut caller = InferenceTaConnector::new(&mut ctx, SessionRole::Infer)?;
+        if !caller.supports(inference::CAP_CRASH_REPORT) {
+            return Ok(None);
+        }
+        caller.last_crash(false)
+    });
+    match report {
+        Ok(Some(report)) => println!("the TA {}", report),
+        Ok(None) => {}
+        Err(err) => println!("could not fetch the TA crash report: {}", err),
+    }
+}
+
+/// Prints what to do about a model the TA refused with one of the
+/// `ERROR_MODEL_*` codes, or that failed to import host-side.
+pub fn report_model_error(err: &anyhow::Error) {
+    let code = err.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<optee_teec::Error>() {
+            return Some(err.raw_code());
+        }
+        #[cfg(feature = "encrypt-model")]
+        if let Some(err) = cause.downcast_ref::<common::ModelError>() {
+            return Some(err.code());
+        }
+        None
+    });
+    let guidance = match code {
+        Some(inference::ERROR_MODEL_RECORD_FORMAT) => {
+            "the model record doesn't decode: check the key it was encrypted with, or \
+             re-export it with burn 0.17"
+        }
+        Some(inference::ERROR_MODEL_SHAPE_MISMATCH) => {
+            "the model's layers have other sizes than the MLP the TA runs \
+             (784-512-256-128-N); retrain it with `train`"
+        }
+        Some(inference::ERROR_MODEL_UNSUPPORTED_ARCH) => {
+            "the record holds another model than the TA's MLP; `inspect` shows what it contains"
+        }
+        Some(inference::ERROR_MODEL_UNSUPPORTED_PRECISION) => {
+            "the record was saved at reduced precision, re-export it with FullPrecisionSettings"
+        }
+        Some(inference::ERROR_MODEL_METADATA) => {
+            "the model container or its metadata is invalid, rebuild it with `encrypt-model`"
+        }
+        Some(inference::ERROR_MODEL_IO) => "the model record could not be read",
+        _ => return,
+    };
+    println!("{}", guidance);
+}
+
+// Errors after which the session is unusable: the TA panicked or
+// tee-supplicant went away
+fn session_lost(err: &optee_teec::Error) -> bool {
+    matches!(err.kind(), ErrorKind::TargetDead | ErrorKind::Communication)
+}
+
+// Runs `send` over `link` and, when the session was lost before the reply
+// came back and `reconnect` reopened it with what the command needs, runs it
+// once more. Only for commands that are safe to repeat.
+fn retry_on_lost_session<L, T>(
+    link: &mut L,
+    cmd: Command,
+    mut send: impl FnMut(&mut L) -> optee_teec::Result<T>,
+    reconnect: impl FnOnce(&mut L, &optee_teec::Error) -> bool,
+) -> optee_teec::Result<T> {
+    match send(link) {
+        Err(err) if session_lost(&err) => {
+            if !reconnect(link, &err) {
+                return Err(err);
+            }
+            transcript::record(Step::Retry {
+                command: format!("{:?}", cmd),
+                reason: format!("session lost: {}", err),
+            });
+            send(link)
+        }
+        result => result,
+    }
+}
+
+// Invokes an inference command, timing it for the metrics
+fn invoke_timed<A: Param, B: Param, C: Param, D: Param>(
+    sess: &mut Session,
+    cmd: u32,
+    op: &mut Operation<A, B, C, D>,
+) -> optee_teec::Result<()> {
+    let start = Instant::now();
+    let result = sess.invoke_command(cmd, op);
+    metrics::observe_ta_invoke(start.elapsed());
+    result
+}
+
+// Seconds since the Unix epoch, reported to the TA for its usage counters
+fn host_time() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs().min(u32::MAX as u64) as u32)
+}
+
+/// Answer of `InferenceTaConnector::storage_preflight`.
+pub struct StoragePreflight {
+    /// Bytes held by the TA's objects.
+    pub used: usize,
+    /// Storage granted to the TA, when the build declares it.
+    pub quota: Option<usize>,
+    /// Whether the TA would attempt to store the requested bytes; always true
+    /// when the quota is unknown.
+    pub fits: bool,
+}
+
+/// `PushEncryptedChunk` sizes a TA with `CAP_CHUNK_SIZES` reports at
+/// open_session.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkSizes {
+    /// What the TA asks hosts without a configured size to use.
+    pub preferred: usize,
+    /// The largest chunk it accepts.
+    pub max: usize,
+}
+
+// What the TA answered at open_session
+#[derive(Debug, PartialEq)]
+struct Negotiated {
+    protocol_version: u32,
+    capabilities: u32,
+    chunk_sizes: Option<ChunkSizes>,
+}
+
+// Reads the TA's protocol version and capabilities (`reply`) and chunk sizes
+// (`sizes`) from the open_session parameters. TAs older than the host's
+// minimum are refused; newer ones are taken at the capabilities they report.
+fn negotiate(reply: (u32, u32), sizes: (u32, u32)) -> optee_teec::Result<Negotiated> {
+    let (protocol_version, capabilities) = reply;
+    // TAs without CAP_CHUNK_SIZES leave parameter 3 alone
+    let chunk_sizes = match sizes {
+        (preferred, max) if capabilities & inference::CAP_CHUNK_SIZES != 0 && max > 0 => {
+            Some(ChunkSizes {
+                preferred: preferred as usize,
+                max: max as usize,
+            })
+        }
+        _ => None,
+    };
+    if protocol_version < inference::MIN_TA_PROTOCOL_VERSION {
+        println!(
+            "TA speaks protocol version {}, this host needs at least {}; update the TA",
+            protocol_version,
+            inference::MIN_TA_PROTOCOL_VERSION
+        );
+        return Err(ErrorKind::NotSupported.into());
+    }
+    Ok(Negotiated {
+        protocol_version,
+        capabilities,
+        chunk_sizes,
+    })
+}
+
+/// Buffer `infer_batch` copies every batch into: request header and up to
+/// `max_batch` images, followed by the labels of that many images.
+struct SharedBatch {
+    buffer: Vec<u8>,
+    max_batch: usize,
+}
+
+impl SharedBatch {
+    fn input_capacity(&self) -> usize {
+        size_of::<InferenceRequestHeader>() + self.max_batch * IMAGE_SIZE
+    }
+}
+
+/// Printed before and after anything the simulated TA reports.
+pub const DRY_RUN_BANNER: &str = "*** DRY RUN: results come from the simulated TA, not a TEE ***";
+/// Printed before what `--encrypted` runs on the host; only dev-tools builds
+/// decrypt models.
+#[cfg(feature = "dev-tools")]
+pub const HOST_SIDE_BANNER: &str =
+    "*** HOST-SIDE: the model was decrypted and run on this host, not in a TEE ***";
+
+/// Result of `InferenceTa::infer_within_budget`: the predictions of the
+/// images the TA got through, in order.
+#[derive(Debug)]
+pub enum BudgetOutcome {
+    /// Every image of the batch.
+    Complete(Vec<Prediction>),
+    /// The budget ran out before the end of the batch; the images after
+    /// these weren't run.
+    Exceeded(Vec<Prediction>),
+}
+
+/// Result of `InferenceTa::infer_recorded`, in the order of the images.
+pub struct RecordedBatch {
+    pub predictions: Vec<Prediction>,
+    /// Row-major, `num_classes` per image
+    pub probabilities: Vec<f32>,
+    /// What the TA reported for each image's input tensor
+    pub input_hashes: Vec<[u8; INPUT_HASH_SIZE]>,
+}
+
+/// Model loading and inference commands shared by `InferenceTaConnector`
+/// and the simulated TA of `--dry-run` (`crate::sim::SimulatedTa`).
+pub trait InferenceTa {
+    fn supports(&self, capability: u32) -> bool;
+    /// Chunk sizes the TA reported, `None` when it didn't.
+    fn chunk_sizes(&self) -> Option<ChunkSizes> {
+        None
+    }
+    /// Starts loading into `slot` a payload of `size` bytes, which the TA
+    /// only finalizes once all of it is pushed; 0 when the size is unknown.
+    fn begin_model_load(&mut self, slot: u32, size: usize) -> optee_teec::Result<()>;
+    fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()>;
+    /// Installs the pushed model, sending `signature` to TAs that verify
+    /// model signatures.
+    fn finalize_model_load(&mut self, signature: Option<&[u8]>) -> optee_teec::Result<()>;
+    fn storage_preflight(&mut self, size: usize) -> optee_teec::Result<StoragePreflight>;
+    fn model_status(&mut self, slot: u32) -> optee_teec::Result<ModelStatus>;
+    /// Class label names of the model in `slot`, empty without metadata.
+    fn class_labels(&mut self, slot: u32) -> optee_teec::Result<Vec<String>>;
+    /// Sends every following inference request with `flags`, a combination
+    /// of `FLAG_RAW_SCALE` and `FLAG_NO_NORMALIZE` (0 for the model's own
+    /// normalization).
+    fn set_input_flags(&mut self, flags: u32) -> optee_teec::Result<()>;
+    /// Has every following inference request label images whose confidence
+    /// is below `threshold` (a probability) as `REJECT_LABEL`; `None` turns
+    /// rejection off.
+    fn set_reject_threshold(&mut self, threshold: Option<f32>) -> optee_teec::Result<()>;
+    /// Has the TA keep the results of up to `entries` images, 0 turning its
+    /// result cache off. Only the real TA has one.
+    fn set_result_cache(&mut self, _entries: u32) -> optee_teec::Result<()> {
+        println!("the simulated TA has no result cache");
+        Err(ErrorKind::NotSupported.into())
+    }
+    /// Fails with NotSupported when the TA was built without the Cargo
+    /// feature `feature`. The simulated TA has them all.
+    fn require_feature(&mut self, _feature: &str) -> optee_teec::Result<()> {
+        Ok(())
+    }
+    /// No labels for no images, without a request to the TA.
+    fn infer_batch(&mut self, images: &[Image], slot: u32) -> optee_teec::Result<Vec<u8>>;
+    fn infer_predictions(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>>;
+    /// `infer_batch` that also returns the probabilities (row-major,
+    /// `num_classes` per image) computed with the given temperature.
+    fn infer_batch_with_probabilities(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)>;
+    fn infer_correlated(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>>;
+    fn infer_ensemble(
+        &mut self,
+        images: &[Image],
+        slot_mask: u32,
+        temperature: f32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)>;
+    /// `infer_predictions` that also returns the probabilities and the
+    /// TA's hash of every image's input tensor, for recording a run.
+    fn infer_recorded(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<RecordedBatch>;
+    /// `infer_predictions` that stops at the end of the slice of
+    /// `TIME_BUDGET_SLICE` images during which `budget` ran out.
+    fn infer_within_budget(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        budget: Duration,
+    ) -> optee_teec::Result<BudgetOutcome>;
+}
+
+/// Idle time after which the session is pinged before it is used again.
+const KEEP_ALIVE_IDLE: Duration = Duration::from_secs(30);
+/// Output memrefs of one Infer are kept under this size, which every
+/// supported platform can map as temporary shared memory. Larger results are
+/// fetched a window of images at a time when the TA has `CAP_OUTPUT_WINDOW`.
+const MAX_OUTPUT_SIZE: usize = 1024 * 1024;
 
 pub struct InferenceTaConnector {
     sess: Session,
+    // Context of a session reopened by `reconnect`; declared after `sess` so
+    // it is dropped after it
+    reopened_ctx: Option<Context>,
+    last_used: Instant,
+    // Asked for at open_session, and again by `reconnect`
+    role: SessionRole,
+    protocol_version: u32,
+    capabilities: u32,
+    chunk_sizes: Option<ChunkSizes>,
+    shared: Option<SharedBatch>,
+    // Next id handed out by `infer_correlated`
+    next_id: u32,
+    // Added to the flags of every inference request
+    input_flags: u32,
+    // Sent with `FLAG_REJECT_BELOW` in every inference request, in thousandths
+    reject_below: Option<u32>,
+    // Per slot, dropped whenever a model is (re)installed there
+    class_labels: [Option<Vec<String>>; inference::MODEL_SLOTS],
+}
+
+impl InferenceTaConnector {
+    /// Opens a session in `role`: `SessionRole::Infer` unless the caller
+    /// loads models or changes the TA's stored state or settings.
+    pub fn new(ctx: &mut Context, role: SessionRole) -> optee_teec::Result<Self> {
+        let uuid = inference_ta_uuid()?;
+        // Open a session with minimal data and negotiate the protocol version
+        let dummy = [0u8; 1];
+        let mut op = Operation::new(
+            0,
+            ParamTmpRef::new_input(&dummy),
+            ParamValue::new(
+                inference::PROTOCOL_VERSION,
+                host_time(),
+                ParamType::ValueInput,
+            ),
+            ParamValue::new(0, 0, ParamType::ValueOutput),
+            ParamValue::new(role.into(), 0, ParamType::ValueInout),
+        );
+        let sess = ctx
+            .open_session_with_operation(uuid, &mut op)
+            .inspect_err(report_storage_downgrade)
+            .inspect_err(|err| report_manage_denied(err, role))?;
+        let (reply, sizes) = (op.parameters().2, op.parameters().3);
+        let Negotiated {
+            protocol_version,
+            capabilities,
+            chunk_sizes,
+        } = negotiate((reply.a(), reply.b()), (sizes.a(), sizes.b()))?;
+        let mut connector = Self {
+            sess,
+            reopened_ctx: None,
+            last_used: Instant::now(),
+            role,
+            protocol_version,
+            capabilities,
+            chunk_sizes,
+            shared: None,
+            next_id: 0,
+            input_flags: 0,
+            reject_below: None,
+            class_labels: Default::default(),
+        };
+        connector.send_trace_id();
+        Ok(connector)
+    }
+
+    // Passes the invocation's trace id on to TAs that can log it. Tracing is
+    // best effort: a TA that doesn't take the id only gets a warning.
+    fn send_trace_id(&mut self) {
+        let id = match transcript::trace_id() {
+            Some(id) if self.supports(inference::CAP_TRACE_ID) => id,
+            _ => return,
+        };
+        let sess = &mut self.sess;
+        if let Some(warning) = pass_trace_id(id, |call| call.invoke(sess)) {
+            println!("{}", warning);
+        }
+    }
+
+    /// Makes `infer_batch` copy its batches into one buffer sized for
+    /// `max_batch` images, allocated here and passed to every call instead of
+    /// a request built per batch, splitting larger requests into `max_batch`
+    /// sized calls. Returns false, leaving per-batch buffers in use, when the
+    /// buffer can't be allocated.
+    pub fn use_shared_memory(&mut self, max_batch: usize) -> bool {
+        let size = size_of::<InferenceRequestHeader>() + max_batch * (IMAGE_SIZE + 1);
+        let mut buffer = Vec::new();
+        match buffer.try_reserve_exact(size) {
+            Ok(()) => {
+                buffer.extend(std::iter::repeat_n(0, size));
+                self.shared = Some(SharedBatch { buffer, max_batch });
+                true
+            }
+            Err(err) => {
+                println!("Cannot allocate {} bytes of shared memory: {}", size, err);
+                false
+            }
+        }
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+
+    pub fn capabilities(&self) -> u32 {
+        self.capabilities
+    }
+
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability == capability
+    }
+
+    // `buf` as the error detail buffer of a command, for TAs that fill one
+    fn detail_buffer<'a>(&self, buf: &'a mut [u8]) -> Option<&'a mut [u8]> {
+        (self.protocol_version >= inference::ERROR_DETAIL_PROTOCOL_VERSION).then_some(buf)
+    }
+
+    pub fn chunk_sizes(&self) -> Option<ChunkSizes> {
+        self.chunk_sizes
+    }
+
+    // Fails fast instead of sending a command the TA would not understand
+    fn require(&self, capability: u32, what: &str) -> optee_teec::Result<()> {
+        if !self.supports(capability) {
+            println!("TA does not support {}", what);
+            return Err(ErrorKind::NotSupported.into());
+        }
+        Ok(())
+    }
+
+    fn require_slot(&self, slot: u32) -> optee_teec::Result<()> {
+        if slot != 0 {
+            self.require(inference::CAP_SLOTS, "model slots")?;
+        }
+        Ok(())
+    }
+
+    /// Opens a new session in place of one that died with `err` while
+    /// running `cmd`. Returns whether the models in the slots of `slot_mask`
+    /// are still usable: loaded, or for slot 0 stored, since the TA reloads
+    /// the stored model on the next inference.
+    fn reconnect(&mut self, cmd: Command, err: &optee_teec::Error, slot_mask: u32) -> bool {
+        println!("TA session lost during {:?} ({}), reconnecting", cmd, err);
+        let reopened = Context::new().and_then(|mut ctx| {
+            let fresh = Self::new(&mut ctx, self.role)?;
+            Ok((ctx, fresh))
+        });
+        let recovered = match reopened {
+            Ok((ctx, fresh)) => {
+                self.sess = fresh.sess;
+                self.reopened_ctx = Some(ctx);
+                self.last_used = Instant::now();
+                self.protocol_version = fresh.protocol_version;
+                self.capabilities = fresh.capabilities;
+                self.chunk_sizes = fresh.chunk_sizes;
+                self.class_labels = Default::default();
+                if self.shared.take().is_some() {
+                    println!("Shared buffer went with the old session, using per-batch buffers");
+                }
+                if matches!(err.kind(), ErrorKind::TargetDead)
+                    && self.supports(inference::CAP_CRASH_REPORT)
+                {
+                    if let Ok(Some(report)) = self.last_crash(false) {
+                        println!("the TA {}", report);
+                    }
+                }
+                self.models_survived(slot_mask)
+            }
+            Err(err) => {
+                println!("Cannot reopen the TA session: {}", err);
+                false
+            }
+        };
+        transcript::record(Step::Reconnect {
+            command: format!("{:?}", cmd),
+            reason: err.to_string(),
+            recovered,
+        });
+        recovered
+    }
+
+    fn models_survived(&mut self, slot_mask: u32) -> bool {
+        (0..inference::MODEL_SLOTS as u32)
+            .filter(|slot| slot_mask & 1 << slot != 0)
+            .all(|slot| match self.model_status(slot) {
+                Ok(status) if status.loaded || (slot == 0 && status.stored.is_some()) => true,
+                Ok(_) => {
+                    println!(
+                        "The model in slot {} was lost with the session, load it again",
+                        slot
+                    );
+                    false
+                }
+                Err(err) => {
+                    println!("Cannot check the model in slot {}: {}", slot, err);
+                    false
+                }
+            })
+    }
+
+    /// Pings a session idle for `KEEP_ALIVE_IDLE` or longer and reopens it
+    /// when the TA or tee-supplicant went away in the meantime, so the next
+    /// command doesn't fail on a dead session.
+    pub fn keep_alive(&mut self) -> optee_teec::Result<()> {
+        if self.last_used.elapsed() < KEEP_ALIVE_IDLE {
+            return Ok(());
+        }
+        let mut output = vec![0_u8; inference::MAX_MODEL_STATUS_SIZE];
+        let (result, _) = TaCall::new(Command::ModelStatus)
+            .output(&mut output)
+            .invoke(&mut self.sess);
+        match result {
+            Err(err) if session_lost(&err) => {
+                if !self.reconnect(Command::ModelStatus, &err, 0) {
+                    return Err(err);
+                }
+            }
+            // Only liveness matters here
+            _ => self.last_used = Instant::now(),
+        }
+        Ok(())
+    }
+
+    // Runs an inference command against the models of `slot_mask`, which is
+    // safe to repeat: when the session dies under it, it is reopened and the
+    // command retried once. Provisioning commands must not come through here.
+    fn invoke_idempotent<P: Params>(
+        &mut self,
+        slot_mask: u32,
+        call: TaCall<P>,
+    ) -> (optee_teec::Result<()>, TaReply<P>) {
+        let cmd = call.command();
+        call.invoke_with(|id, op| {
+            self.keep_alive()?;
+            let result = retry_on_lost_session(
+                self,
+                cmd,
+                |this| invoke_timed(&mut this.sess, id, op),
+                |this, err| this.reconnect(cmd, err, slot_mask),
+            );
+            self.last_used = Instant::now();
+            result
+        })
+    }
+
+    /// Starts streaming a model that will be installed into `slot` on finalize.
+    pub fn begin_model_load(&mut self, slot: u32, size: usize) -> optee_teec::Result<()> {
+        self.require_slot(slot)?;
+        // A session that died while idle is reopened before the stream starts;
+        // once chunks are pushed a lost session fails the load
+        self.keep_alive()?;
+        self.forget_class_labels(slot);
+        // Sizes past u32 aren't announced; the TA then finalizes whatever
+        // was pushed. TAs without CAP_LOAD_STATE ignore the size.
+        let size = u32::try_from(size).unwrap_or(0);
+        let (result, reply) = TaCall::new(Command::BeginModelLoad)
+            .value_inout(slot, size)
+            .invoke(&mut self.sess);
+        record_invoke(Command::BeginModelLoad, 0, None, &result);
+        let (phase, received) = reply.value::<0>();
+        report_load_state(&result, phase, received);
+        result
+    }
+
+    pub fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()> {
+        let call = TaCall::new(Command::PushEncryptedChunk).input(chunk);
+        // Older TAs refuse a parameter 1
+        let result = if self.supports(inference::CAP_LOAD_STATE) {
+            let (result, reply) = call.value_out().invoke(&mut self.sess);
+            let (phase, received) = reply.value::<1>();
+            report_load_state(&result, phase, received);
+            result
+        } else {
+            call.invoke(&mut self.sess).0
+        };
+        record_invoke(Command::PushEncryptedChunk, chunk.len(), None, &result);
+        result
+    }
+
+    pub fn finalize_model_load(&mut self, signature: Option<&[u8]>) -> optee_teec::Result<()> {
+        let signature = self.model_signature(signature);
+        let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail_buf = self.detail_buffer(&mut detail);
+        let call = TaCall::new(Command::FinalizeModelLoad).value_out();
+        let (result, (a, b), detail_size) = match signature {
+            Some(signature) => {
+                let (result, reply) = call
+                    .input(signature)
+                    .none()
+                    .error_detail(detail_buf)
+                    .invoke(&mut self.sess);
+                (result, reply.value::<0>(), reply.size::<3>())
+            }
+            None => {
+                let (result, reply) = call
+                    .none()
+                    .none()
+                    .error_detail(detail_buf)
+                    .invoke(&mut self.sess);
+                (result, reply.value::<0>(), reply.size::<3>())
+            }
+        };
+        note_error_detail(Command::FinalizeModelLoad, &result, &detail, detail_size);
+        let out_of_memory =
+            matches!(&result, Err(err) if matches!(err.kind(), ErrorKind::OutOfMemory));
+        let required = out_of_memory.then_some(a as usize);
+        record_invoke(Command::FinalizeModelLoad, 0, required, &result);
+        report_canary_mismatch(&result, b);
+        report_signature_invalid(&result);
+        report_load_state(&result, a, b);
+        if out_of_memory {
+            report_out_of_memory(a, b);
+        }
+        result
+    }
+
+    // The signature to send along a model, dropped for TAs that don't
+    // verify signatures and would refuse the extra parameter
+    fn model_signature<'a>(&self, signature: Option<&'a [u8]>) -> Option<&'a [u8]> {
+        if signature.is_some() && !self.supports(inference::CAP_MODEL_SIGNATURES) {
+            println!("the TA doesn't verify model signatures, ignoring the model's signature");
+            return None;
+        }
+        signature
+    }
+
+    /// Provisions the RSA public key (DER, SubjectPublicKeyInfo or PKCS#1)
+    /// every following model must be signed with. The key can't be replaced.
+    pub fn set_model_verify_key(&mut self, public_key: &[u8]) -> optee_teec::Result<()> {
+        self.require(inference::CAP_MODEL_SIGNATURES, "model signatures")?;
+        let (result, _) = TaCall::new(Command::SetModelVerifyKey)
+            .input(public_key)
+            .invoke(&mut self.sess);
+        record_invoke(Command::SetModelVerifyKey, public_key.len(), None, &result);
+        match &result {
+            Err(err) if matches!(err.kind(), ErrorKind::AccessConflict) => {
+                println!("a different model verify key is already provisioned")
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::BadParameters) => {
+                println!("the TA refused the key, it takes 2048 to 4096-bit RSA keys")
+            }
+            _ => {}
+        }
+        result
+    }
+
+    /// Restricts manage sessions to the clients `policy` lists; a policy
+    /// without clients lets any client manage the TA again.
+    pub fn set_manage_policy(&mut self, policy: &ManagePolicy) -> optee_teec::Result<()> {
+        let json = serde_json::to_vec(policy).map_err(|_| ErrorKind::BadParameters)?;
+        let (result, _) = TaCall::new(Command::SetManagePolicy)
+            .input(&json)
+            .invoke(&mut self.sess);
+        record_invoke(Command::SetManagePolicy, json.len(), None, &result);
+        match result {
+            Err(err) if matches!(err.kind(), ErrorKind::AccessConflict) => {
+                println!("the policy doesn't list this client, which would lock it out");
+                Err(err)
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::BadParameters) => {
+                println!("TA predates session roles or refused the policy");
+                Err(err)
+            }
+            result => result,
+        }
+    }
+
+    /// Applies the streamed payload as a layer patch onto the model in the
+    /// slot passed to `begin_model_load`.
+    pub fn patch_model(&mut self) -> optee_teec::Result<()> {
+        self.require(inference::CAP_PATCH, "layer patches")?;
+        let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail_buf = self.detail_buffer(&mut detail);
+        let call = TaCall::new(Command::PatchModel);
+        let (result, detail_size) = if self.supports(inference::CAP_LOAD_STATE) {
+            let (result, reply) = call
+                .value_out()
+                .none()
+                .none()
+                .error_detail(detail_buf)
+                .invoke(&mut self.sess);
+            let (phase, received) = reply.value::<0>();
+            report_load_state(&result, phase, received);
+            (result, reply.size::<3>())
+        } else {
+            (call.invoke(&mut self.sess).0, 0)
+        };
+        note_error_detail(Command::PatchModel, &result, &detail, detail_size);
+        record_invoke(Command::PatchModel, 0, None, &result);
+        if matches!(&result, Err(err) if err.raw_code() == ERROR_SIGNATURE_INVALID) {
+            println!("the TA has a model verify key and doesn't take unsigned patches");
+        }
+        result
+    }
+
+    /// Queries whether a model is installed in `slot`, plus its name and class
+    /// labels when the model carried a metadata block.
+    pub fn model_status(&mut self, slot: u32) -> optee_teec::Result<ModelStatus> {
+        self.require_slot(slot)?;
+        let mut output = vec![0_u8; inference::MAX_MODEL_STATUS_SIZE];
+        let size = {
+            let expected = output_size(Command::ModelStatus, 0, output.len());
+            let (result, reply) = TaCall::new(Command::ModelStatus)
+                .output(&mut output)
+                .value(slot, 0)
+                .invoke(&mut self.sess);
+            let reported = reply.size::<0>();
+            record_invoke(Command::ModelStatus, 0, Some(reported), &result);
+            result?;
+            reply.checked_size::<0>(expected)?
+        };
+        output.truncate(size);
+        let status = serde_json::from_slice(&output).map_err(|err| {
+            println!("malformed model status: {}", err);
+            ErrorKind::BadFormat
+        })?;
+        transcript::record(Step::Status {
+            slot,
+            status: &status,
+        });
+        Ok(status)
+    }
+
+    /// Class label names of the model in `slot`, empty when it carried no
+    /// metadata. Fetched once per installed model; TAs without
+    /// `CAP_CLASS_LABELS` are asked for the model status instead.
+    pub fn class_labels(&mut self, slot: u32) -> optee_teec::Result<Vec<String>> {
+        self.require_slot(slot)?;
+        if let Some(labels) = &self.class_labels[slot as usize] {
+            return Ok(labels.clone());
+        }
+        let labels = if self.supports(inference::CAP_CLASS_LABELS) {
+            let mut output = vec![0_u8; inference::MAX_CLASS_LABELS_SIZE];
+            let size = {
+                let expected = output_size(Command::GetClassLabels, 0, output.len());
+                let (result, reply) = TaCall::new(Command::GetClassLabels)
+                    .output(&mut output)
+                    .value(slot, 0)
+                    .invoke(&mut self.sess);
+                result?;
+                reply.checked_size::<0>(expected)?
+            };
+            inference::decode_class_labels(&output[..size]).ok_or_else(|| {
+                println!("malformed class labels");
+                ErrorKind::BadFormat
+            })?
+        } else {
+            self.model_status(slot)?.class_labels
+        };
+        self.class_labels[slot as usize] = Some(labels.clone());
+        Ok(labels)
+    }
+
+    fn forget_class_labels(&mut self, slot: u32) {
+        if let Some(labels) = self.class_labels.get_mut(slot as usize) {
+            *labels = None;
+        }
+    }
+
+    /// Reinstalls the model kept in the TA's secure storage into `slot`.
+    pub fn load_stored_model(&mut self, slot: u32) -> optee_teec::Result<()> {
+        self.begin_model_load(slot, 0)?;
+        self.finalize_model_load(None)
+    }
+
+    /// Reinstalls the previous stored model version into slot 0 and discards
+    /// the active one.
+    pub fn rollback_model(&mut self) -> optee_teec::Result<()> {
+        self.require(inference::CAP_HISTORY, "model rollback")?;
+        self.forget_class_labels(0);
+        let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail_buf = self.detail_buffer(&mut detail);
+        let (result, canary, detail_size) = {
+            let (result, reply) = TaCall::new(Command::RollbackModel)
+                .value_out()
+                .none()
+                .none()
+                .error_detail(detail_buf)
+                .invoke(&mut self.sess);
+            (result, reply.value::<0>().1, reply.size::<3>())
+        };
+        note_error_detail(Command::RollbackModel, &result, &detail, detail_size);
+        record_invoke(Command::RollbackModel, 0, None, &result);
+        report_canary_mismatch(&result, canary);
+        result
+    }
+
+    /// Validates the streamed model and keeps it as the staged model without
+    /// installing it. An earlier staged model is only replaced with `force`.
+    /// `signature` is sent to TAs that verify model signatures. Returns the
+    /// number of canary images the model classified correctly.
+    pub fn stage_model(
+        &mut self,
+        force: bool,
+        signature: Option<&[u8]>,
+    ) -> optee_teec::Result<usize> {
+        self.require(inference::CAP_STAGING, "model staging")?;
+        let flags = if force { inference::STAGE_FORCE } else { 0 };
+        let signature = self.model_signature(signature);
+        let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail_buf = self.detail_buffer(&mut detail);
+        let call = TaCall::new(Command::StageModel).value_inout(flags, 0);
+        let (result, (a, b), detail_size) = match signature {
+            Some(signature) => {
+                let (result, reply) = call
+                    .input(signature)
+                    .none()
+                    .error_detail(detail_buf)
+                    .invoke(&mut self.sess);
+                (result, reply.value::<0>(), reply.size::<3>())
+            }
+            None => {
+                let (result, reply) = call
+                    .none()
+                    .none()
+                    .error_detail(detail_buf)
+                    .invoke(&mut self.sess);
+                (result, reply.value::<0>(), reply.size::<3>())
+            }
+        };
+        note_error_detail(Command::StageModel, &result, &detail, detail_size);
+        record_invoke(Command::StageModel, 0, None, &result);
+        report_canary_mismatch(&result, b);
+        report_signature_invalid(&result);
+        report_load_state(&result, a, b);
+        match &result {
+            Err(err) if matches!(err.kind(), ErrorKind::AccessConflict) => {
+                println!("a model is already staged, commit or discard it, or use --force")
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::OutOfMemory) => report_out_of_memory(a, b),
+            _ => {}
+        }
+        result.map(|()| b as usize)
+    }
+
+    /// Installs the staged model into slot 0 and makes it the active stored
+    /// model.
+    pub fn commit_model(&mut self) -> optee_teec::Result<()> {
+        self.require(inference::CAP_STAGING, "model staging")?;
+        self.forget_class_labels(0);
+        let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail_buf = self.detail_buffer(&mut detail);
+        let (result, canary, detail_size) = {
+            let (result, reply) = TaCall::new(Command::CommitModel)
+                .value_out()
+                .none()
+                .none()
+                .error_detail(detail_buf)
+                .invoke(&mut self.sess);
+            (result, reply.value::<0>().1, reply.size::<3>())
+        };
+        note_error_detail(Command::CommitModel, &result, &detail, detail_size);
+        record_invoke(Command::CommitModel, 0, None, &result);
+        report_canary_mismatch(&result, canary);
+        report_nothing_staged(&result);
+        result
+    }
+
+    /// Drops the staged model.
+    pub fn discard_staged(&mut self) -> optee_teec::Result<()> {
+        self.require(inference::CAP_STAGING, "model staging")?;
+        let (result, _) = TaCall::new(Command::DiscardStaged).invoke(&mut self.sess);
+        record_invoke(Command::DiscardStaged, 0, None, &result);
+        report_nothing_staged(&result);
+        result
+    }
+
+    /// Chooses what the TA keeps in memory once its last session closes.
+    pub fn set_residency_policy(&mut self, policy: ResidencyPolicy) -> optee_teec::Result<()> {
+        self.require(inference::CAP_RESIDENCY, "residency policies")?;
+        TaCall::new(Command::SetResidencyPolicy)
+            .value(policy.into(), 0)
+            .invoke(&mut self.sess)
+            .0
+    }
+
+    /// Has the model in `shadow` run every inference on `active` too, without
+    /// affecting the responses, or turns shadowing off with `None`. Starts a
+    /// new shadow report either way.
+    pub fn set_shadow(&mut self, slots: Option<(u32, u32)>) -> optee_teec::Result<()> {
+        self.require(inference::CAP_SHADOW, "shadow slots")?;
+        let call = TaCall::new(Command::SetShadow);
+        let result = match slots {
+            Some((active, shadow)) => call.value(active, shadow).invoke(&mut self.sess).0,
+            None => call.invoke(&mut self.sess).0,
+        };
+        record_invoke(Command::SetShadow, 0, None, &result);
+        if let (Err(err), Some((_, shadow))) = (&result, slots) {
+            if matches!(err.kind(), ErrorKind::ItemNotFound) {
+                println!("no model in slot {} to shadow with", shadow);
+            }
+        }
+        result
+    }
+
+    /// How the shadow slot's labels compared with the active slot's so far;
+    /// `reset` starts a new report once read.
+    pub fn shadow_report(&mut self, reset: bool) -> optee_teec::Result<ShadowReport> {
+        self.require(inference::CAP_SHADOW, "shadow slots")?;
+        let sess = &mut self.sess;
+        read_shadow_report(reset, |call| call.invoke(sess))
+    }
+
+    /// Layout version of the TA's secure storage and its last migration.
+    pub fn storage_schema(&mut self) -> optee_teec::Result<StorageSchema> {
+        self.require(inference::CAP_STORAGE_SCHEMA, "storage schema versions")?;
+        let mut output = vec![0_u8; inference::MAX_STORAGE_SCHEMA_SIZE];
+        let size = {
+            let expected = output_size(Command::GetSchemaVersion, 0, output.len());
+            let (result, reply) = TaCall::new(Command::GetSchemaVersion)
+                .output(&mut output)
+                .invoke(&mut self.sess);
+            let reported = reply.size::<0>();
+            record_invoke(Command::GetSchemaVersion, 0, Some(reported), &result);
+            result?;
+            reply.checked_size::<0>(expected)?
+        };
+        output.truncate(size);
+        serde_json::from_slice(&output).map_err(|err| {
+            println!("malformed storage schema: {}", err);
+            ErrorKind::BadFormat.into()
+        })
+    }
+
+    /// Features and limits the TA was built with, `None` for TAs predating
+    /// `Command::GetBuildManifest`.
+    pub fn build_manifest(&mut self) -> optee_teec::Result<Option<BuildManifest>> {
+        let sess = &mut self.sess;
+        read_build_manifest(|call| call.invoke(sess))
+    }
+
+    /// Fails with NotSupported when the TA reports it was built without the
+    /// Cargo feature `feature`. TAs that can't tell are given the benefit of
+    /// the doubt.
+    pub fn require_feature(&mut self, feature: &str) -> optee_teec::Result<()> {
+        check_feature(self.build_manifest()?.as_ref(), feature)
+    }
+
+    /// Model versions kept in secure storage, newest first.
+    pub fn model_history(&mut self) -> optee_teec::Result<Vec<ModelVersion>> {
+        self.require(inference::CAP_HISTORY, "model history")?;
+        let mut output = vec![0_u8; inference::MAX_MODEL_HISTORY_SIZE];
+        let size = {
+            let expected = output_size(Command::ModelHistory, 0, output.len());
+            let (result, reply) = TaCall::new(Command::ModelHistory)
+                .output(&mut output)
+                .invoke(&mut self.sess);
+            result?;
+            reply.checked_size::<0>(expected)?
+        };
+        output.truncate(size);
+        serde_json::from_slice(&output).map_err(|err| {
+            println!("malformed model history: {}", err);
+            ErrorKind::BadFormat.into()
+        })
+    }
+
+    /// Runs the TA's known-answer tests; whether each of
+    /// `proto::test_vectors::VECTORS` passed, as far as the TA knows them.
+    pub fn run_self_test(&mut self) -> optee_teec::Result<Vec<bool>> {
+        self.require(inference::CAP_SELF_TEST, "self test")?;
+        let mut output = vec![0_u8; proto::test_vectors::VECTORS.len()];
+        let size = {
+            let expected = output_size(Command::RunSelfTest, 0, output.len());
+            let (result, reply) = TaCall::new(Command::RunSelfTest)
+                .output(&mut output)
+                .invoke(&mut self.sess);
+            result?;
+            reply.checked_size::<0>(expected)?
+        };
+        output.truncate(size);
+        Ok(output.iter().map(|&result| result != 0).collect())
+    }
+
+    /// Usage counters of the model in `slot`, since it was loaded and over
+    /// its lifetime.
+    pub fn model_usage(&mut self, slot: u32) -> optee_teec::Result<ModelUsage> {
+        self.require(inference::CAP_STATS, "usage statistics")?;
+        self.require_slot(slot)?;
+        let mut output = vec![0_u8; inference::MAX_MODEL_USAGE_SIZE];
+        let size = {
+            let expected = output_size(Command::GetPersistentStats, 0, output.len());
+            let (result, reply) = TaCall::new(Command::GetPersistentStats)
+                .output(&mut output)
+                .value(slot, 0)
+                .invoke(&mut self.sess);
+            result?;
+            reply.checked_size::<0>(expected)?
+        };
+        output.truncate(size);
+        serde_json::from_slice(&output).map_err(|err| {
+            println!("malformed usage statistics: {}", err);
+            ErrorKind::BadFormat.into()
+        })
+    }
+
+    /// Zeroes the usage counters of the model in `slot`, including the
+    /// lifetime counters kept in secure storage.
+    pub fn reset_model_usage(&mut self, slot: u32) -> optee_teec::Result<()> {
+        self.require(inference::CAP_STATS, "usage statistics")?;
+        self.require_slot(slot)?;
+        TaCall::new(Command::ResetPersistentStats)
+            .value(slot, 0)
+            .invoke(&mut self.sess)
+            .0
+    }
+
+    /// Asks the TA whether storing `size` more bytes fits its secure storage.
+    pub fn storage_preflight(&mut self, size: usize) -> optee_teec::Result<StoragePreflight> {
+        self.require(inference::CAP_STORAGE, "storage management")?;
+        let size = u32::try_from(size).map_err(|_| ErrorKind::BadParameters)?;
+        let (result, reply) = TaCall::new(Command::StoragePreflight)
+            .value(size, 0)
+            .value_out()
+            .value_out()
+            .invoke(&mut self.sess);
+        let (used, quota) = reply.value::<1>();
+        let used = used as usize;
+        record_invoke(
+            Command::StoragePreflight,
+            size as usize,
+            Some(used),
+            &result,
+        );
+        result?;
+        Ok(StoragePreflight {
+            used,
+            quota: (quota != 0).then_some(quota as usize),
+            fits: reply.value::<2>().0 != 0,
+        })
+    }
+
+    /// Lists the secure storage objects owned by the TA.
+    pub fn list_storage(&mut self) -> optee_teec::Result<Vec<StorageObject>> {
+        self.require(inference::CAP_STORAGE, "storage management")?;
+        let mut output = vec![0_u8; inference::MAX_STORAGE_LIST_SIZE];
+        let size = {
+            let expected = output_size(Command::ListStorage, 0, output.len());
+            let (result, reply) = TaCall::new(Command::ListStorage)
+                .output(&mut output)
+                .invoke(&mut self.sess);
+            result?;
+            reply.checked_size::<0>(expected)?
+        };
+        output.truncate(size);
+        serde_json::from_slice(&output).map_err(|err| {
+            println!("malformed storage list: {}", err);
+            ErrorKind::BadFormat.into()
+        })
+    }
+
+    /// Deletes one secure storage object; the TA only accepts ids under
+    /// `inference::DELETABLE_STORAGE_PREFIXES`.
+    pub fn delete_storage_object(&mut self, id: &str) -> optee_teec::Result<()> {
+        self.require(inference::CAP_STORAGE, "storage management")?;
+        let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail_buf = self.detail_buffer(&mut detail);
+        let (result, detail_size) = {
+            let (result, reply) = TaCall::new(Command::DeleteStorageObject)
+                .input(id.as_bytes())
+                .none()
+                .none()
+                .error_detail(detail_buf)
+                .invoke(&mut self.sess);
+            (result, reply.size::<3>())
+        };
+        note_error_detail(Command::DeleteStorageObject, &result, &detail, detail_size);
+        result
+    }
+
+    /// The report of the TA's last panic, `None` when it hasn't panicked
+    /// since the report was last cleared. `clear` deletes it once read.
+    pub fn last_crash(&mut self, clear: bool) -> optee_teec::Result<Option<CrashReport>> {
+        self.require(inference::CAP_CRASH_REPORT, "crash reports")?;
+        let mut output = vec![0_u8; inference::MAX_CRASH_REPORT_SIZE];
+        let flags = if clear {
+            inference::CRASH_REPORT_CLEAR
+        } else {
+            0
+        };
+        let result = {
+            let expected = output_size(Command::GetLastCrash, 0, output.len());
+            let (result, reply) = TaCall::new(Command::GetLastCrash)
+                .output(&mut output)
+                .value(flags, 0)
+                .invoke(&mut self.sess);
+            record_invoke(Command::GetLastCrash, 0, None, &result);
+            result.map(|()| reply.checked_size::<0>(expected))
+        };
+        let size = match result {
+            Ok(size) => size?,
+            Err(err) if matches!(err.kind(), ErrorKind::ItemNotFound) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        output.truncate(size);
+        serde_json::from_slice(&output).map(Some).map_err(|err| {
+            println!("malformed crash report: {}", err);
+            ErrorKind::BadFormat.into()
+        })
+    }
+
+    /// Has the TA re-encrypt the stored model for reading it back with
+    /// `read_encrypted_chunk`; returns the export's size and SHA-256.
+    pub fn begin_model_export(&mut self) -> optee_teec::Result<(usize, [u8; 32])> {
+        self.require(inference::CAP_MODEL_EXPORT, "model export")?;
+        let mut hash = [0_u8; 32];
+        let size = {
+            let expected = output_size(Command::BeginModelExport, 0, hash.len());
+            let (result, reply) = TaCall::new(Command::BeginModelExport)
+                .value_out()
+                .output(&mut hash)
+                .invoke(&mut self.sess);
+            record_invoke(Command::BeginModelExport, 0, None, &result);
+            if matches!(&result, Err(err) if matches!(err.kind(), ErrorKind::ItemNotFound)) {
+                println!("the TA has no stored model to export");
+            }
+            result?;
+            reply.checked_size::<1>(expected)?;
+            reply.value::<0>().0 as usize
+        };
+        Ok((size, hash))
+    }
+
+    /// Reads `buf.len()` bytes of the export at `offset`, at most
+    /// `MAX_EXPORT_CHUNK_SIZE`. The TA refuses empty ranges and ranges past
+    /// the end.
+    pub fn read_encrypted_chunk(
+        &mut self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> optee_teec::Result<()> {
+        let offset = u32::try_from(offset).map_err(|_| ErrorKind::BadParameters)?;
+        let len = u32::try_from(buf.len()).map_err(|_| ErrorKind::BadParameters)?;
+        let (result, reply) = TaCall::new(Command::ReadEncryptedChunk)
+            .value(offset, len)
+            .output(buf)
+            .invoke(&mut self.sess);
+        record_invoke(Command::ReadEncryptedChunk, 0, None, &result);
+        result?;
+        reply.checked_size::<1>(output_size(Command::ReadEncryptedChunk, 0, len as usize))?;
+        Ok(())
+    }
+
+    /// Drops the TA's copy of the export.
+    pub fn end_model_export(&mut self) -> optee_teec::Result<()> {
+        let (result, _) = TaCall::new(Command::EndModelExport).invoke(&mut self.sess);
+        record_invoke(Command::EndModelExport, 0, None, &result);
+        result
+    }
+
+    /// Makes the TA panic; only TAs built with the `debug-panic` feature
+    /// accept this. Others are refused with NotSupported before anything is
+    /// sent, or answer BadParameters if they predate build manifests.
+    pub fn debug_panic(&mut self) -> optee_teec::Result<()> {
+        self.require_feature(inference::FEATURE_DEBUG_PANIC)?;
+        let (result, _) = TaCall::new(Command::DebugPanic).invoke(&mut self.sess);
+        record_invoke(Command::DebugPanic, 0, None, &result);
+        result
+    }
+
+    /// Sends every following inference request with `flags`, a combination
+    /// of `FLAG_RAW_SCALE` or `FLAG_NO_NORMALIZE` (neither for the model's own
+    /// normalization), `FLAG_SKIP_SHADOW`, `FLAG_NO_CACHE` and
+    /// `FLAG_FIXED_POINT_PROBS`. No capability announces the last one: TAs
+    /// that predate it refuse the requests with `BadParameters`.
+    pub fn set_input_flags(&mut self, flags: u32) -> optee_teec::Result<()> {
+        let unscaled =
+            inference::FLAG_SKIP_SHADOW | inference::FLAG_NO_CACHE | FLAG_FIXED_POINT_PROBS;
+        if flags & !unscaled != 0 {
+            self.require(inference::CAP_INPUT_SCALING, "input scaling flags")?;
+        }
+        if flags & inference::FLAG_SKIP_SHADOW != 0 {
+            self.require(inference::CAP_SHADOW, "shadow slots")?;
+        }
+        if flags & inference::FLAG_NO_CACHE != 0 {
+            self.require(inference::CAP_RESULT_CACHE, "the result cache")?;
+        }
+        check_input_flags(flags)?;
+        self.input_flags = flags;
+        Ok(())
+    }
+
+    /// Has the TA keep the label and probabilities of the last `entries`
+    /// images it classified, for all sessions, and answer repeated images
+    /// from them; 0 turns the cache off.
+    pub fn set_result_cache(&mut self, entries: u32) -> optee_teec::Result<()> {
+        self.require(inference::CAP_RESULT_CACHE, "the result cache")?;
+        let (result, _) = TaCall::new(Command::SetResultCache)
+            .value(entries, 0)
+            .invoke(&mut self.sess);
+        record_invoke(Command::SetResultCache, 0, None, &result);
+        if matches!(&result, Err(err) if matches!(err.kind(), ErrorKind::BadParameters)) {
+            println!(
+                "the TA caches at most {} results",
+                inference::MAX_RESULT_CACHE_ENTRIES
+            );
+        }
+        result
+    }
+
+    /// Has every following inference request label images whose confidence
+    /// is below `threshold` (a probability) as `REJECT_LABEL`; `None` turns
+    /// rejection off.
+    pub fn set_reject_threshold(&mut self, threshold: Option<f32>) -> optee_teec::Result<()> {
+        if threshold.is_some() {
+            self.require(inference::CAP_REJECT_THRESHOLD, "reject thresholds")?;
+        }
+        self.reject_below = threshold.map(fixed_point_threshold).transpose()?;
+        Ok(())
+    }
+
+    /// Labels `images` with the model in `slot`. An empty batch is answered
+    /// here with no labels; the TA refuses requests without images.
+    pub fn infer_batch(&mut self, images: &[Image], slot: u32) -> optee_teec::Result<Vec<u8>> {
+        if images.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.require_slot(slot)?;
+        // The shared buffer only has room for a bare header
+        let use_shared = self.shared.is_some() && self.reject_below.is_none();
+        if use_shared {
+            self.keep_alive()?;
+        }
+        if let Some(shared) = self.shared.as_mut().filter(|_| use_shared) {
+            let sess = &mut self.sess;
+            let flags = self.input_flags;
+            match infer_batch_shared(shared, images, slot, flags, |call| {
+                call.invoke_with(|cmd, op| invoke_timed(sess, cmd, op))
+            }) {
+                // Reconnecting drops the shared buffer, the retry below goes
+                // through per-batch buffers
+                Err(err) if session_lost(&err) => {
+                    if !self.reconnect(Command::Infer, &err, 1 << slot) {
+                        return Err(err);
+                    }
+                }
+                result => {
+                    self.last_used = Instant::now();
+                    return result;
+                }
+            }
+        }
+        let input = request(images, self.input_flags, 1.0, self.reject_below)?;
+        let mut output = vec![0_u8; images.len()];
+        // The value parameter is inout so the TA can report the size it
+        // needs when the output is too small
+        let attempt = |output: &mut [u8]| {
+            let expected = output_size(Command::Infer, self.input_flags, output.len());
+            let call = TaCall::new(Command::Infer)
+                .input(&input)
+                .output(output)
+                .value_inout(0, slot);
+            let (result, reply) = self.invoke_idempotent(1 << slot, call);
+            forget_error_detail();
+            let size = reply.checked_size::<1>(expected);
+            let result = result.and_then(|()| size.map(drop).map_err(Into::into));
+            (result, reply.value::<2>().0 as usize)
+        };
+        retry_short_buffer(&mut output, attempt, |required| {
+            println!("output buffer too small, retrying with {} bytes", required);
+            transcript::record(Step::Retry {
+                command: format!("{:?}", Command::Infer),
+                reason: format!("short buffer, TA needs {} bytes", required),
+            });
+        })?;
+        Ok(output)
+    }
+
+    /// Runs inference with the model in `slot` and also returns the softmax
+    /// probabilities (row-major, `num_classes` per image, see
+    /// `ModelStatus::num_classes`) computed with the given temperature.
+    pub fn infer_batch_with_probabilities(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        self.require(inference::CAP_PROBABILITIES, "probabilities")?;
+        self.require_slot(slot)?;
+        let flags = self.input_flags;
+        let windows = self.infer_windows(images, flags, temperature, slot, num_classes, 1)?;
+        let (labels, probs): (Vec<_>, Vec<_>) = windows.into_iter().unzip();
+        let probs = decode_probabilities(&probs.concat(), flags);
+        Ok((labels.concat(), probs))
+    }
+
+    /// Runs inference with the model in `slot` and returns one `Prediction`
+    /// (label and confidence) per image, computed with the given temperature.
+    pub fn infer_predictions(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        self.require(inference::CAP_PROBABILITIES, "confidences")?;
+        self.require_slot(slot)?;
+        let flags = FLAG_PREDICTIONS | self.tie_marks() | self.input_flags;
+        let input = request(images, flags, temperature, self.reject_below)?;
+        let mut output = vec![Prediction::zeroed(); images.len()];
+        let result = {
+            let expected = output_size(Command::Infer, flags, size_of_val(output.as_slice()));
+            let call = TaCall::new(Command::Infer)
+                .input(&input)
+                .output(wire::as_bytes_mut(&mut output))
+                .value(0, slot);
+            let (result, reply) = self.invoke_idempotent(1 << slot, call);
+            if result.is_ok() {
+                reply.checked_size::<1>(expected)?;
+            }
+            result
+        };
+        forget_error_detail();
+        result?;
+        Ok(output)
+    }
+
+    /// `infer_predictions` with `FLAG_TIME_BUDGET`: the TA stops at the end of
+    /// the slice of `TIME_BUDGET_SLICE` images during which `budget` ran out
+    /// and returns what it has, so the caller can send the rest later.
+    pub fn infer_within_budget(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        budget: Duration,
+    ) -> optee_teec::Result<BudgetOutcome> {
+        self.require(inference::CAP_TIME_BUDGET, "time budgets")?;
+        self.require_slot(slot)?;
+        if images.is_empty() {
+            return Ok(BudgetOutcome::Complete(Vec::new()));
+        }
+        let flags = FLAG_PREDICTIONS | self.tie_marks() | self.input_flags;
+        let budget_ms = u32::try_from(budget.as_millis()).unwrap_or(u32::MAX);
+        let input = budgeted_request(images, flags, temperature, self.reject_below, budget_ms)?;
+        read_budget_outcome(images.len(), flags, slot, &input, |call| {
+            self.invoke_idempotent(1 << slot, call)
+        })
+    }
+
+    // `FLAG_MARK_TIES` for TAs that take it, so predictions say when their
+    // class was picked among tied ones
+    fn tie_marks(&self) -> u32 {
+        if self.supports(inference::CAP_TIE_MARKS) {
+            inference::FLAG_MARK_TIES
+        } else {
+            0
+        }
+    }
+
+    /// `infer_predictions` with a correlation id per image; fails unless
+    /// the TA's results carry the ids of the images they answer, in order.
+    pub fn infer_correlated(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        self.require(inference::CAP_CORRELATION, "correlation ids")?;
+        self.require_slot(slot)?;
+        let ids = take_ids(&mut self.next_id, images.len());
+        let input = correlated_request(
+            images,
+            &ids,
+            self.tie_marks() | self.input_flags,
+            temperature,
+            self.reject_below,
+        )?;
+        let result = infer_correlated_call(&input, &ids, slot, |call| {
+            self.invoke_idempotent(1 << slot, call)
+        });
+        forget_error_detail();
+        result
+    }
+
+    /// Runs inference with the model in `slot` and returns per image its
+    /// `Prediction`, its probabilities (`num_classes` each) and the SHA-256
+    /// the TA computed over its input tensor (`FLAG_INPUT_HASHES`).
+    pub fn infer_recorded(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<RecordedBatch> {
+        self.require(inference::CAP_INPUT_HASHES, "input hashes")?;
+        self.require_slot(slot)?;
+        let flags = FLAG_PREDICTIONS | FLAG_INPUT_HASHES | self.tie_marks() | self.input_flags;
+        let per_image = size_of::<Prediction>() + INPUT_HASH_SIZE;
+        let windows =
+            self.infer_windows(images, flags, temperature, slot, num_classes, per_image)?;
+        let mut batch = RecordedBatch {
+            predictions: Vec::with_capacity(images.len()),
+            probabilities: Vec::with_capacity(images.len() * num_classes),
+            input_hashes: Vec::with_capacity(images.len()),
+        };
+        // Each window's output holds its predictions, then its hashes
+        for (output, probs) in windows {
+            let (predictions, hashes) =
+                output.split_at(output.len() / per_image * size_of::<Prediction>());
+            let predictions = wire::records::<Prediction>(predictions).ok_or(ErrorKind::Generic)?;
+            let hashes: Vec<[u8; INPUT_HASH_SIZE]> = bytemuck::pod_collect_to_vec(hashes);
+            batch.predictions.extend_from_slice(predictions);
+            let probs = decode_probabilities(&probs, flags);
+            batch.probabilities.extend(probs);
+            batch.input_hashes.extend(hashes);
+        }
+        Ok(batch)
+    }
+
+    // Runs an Infer request with probabilities over `images`, as one call or,
+    // when its outputs (`output_per_image` bytes in p1 plus the probabilities
+    // per image) would exceed `MAX_OUTPUT_SIZE` and the TA can window them,
+    // as one call per window of the same request. The TA runs the batch once
+    // for all the windows. Returns the p1 output and the probabilities of
+    // each call, in image order.
+    fn infer_windows(
+        &mut self,
+        images: &[Image],
+        flags: u32,
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+        output_per_image: usize,
+    ) -> optee_teec::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let per_image = output_per_image + probabilities_size(flags, 1, num_classes);
+        let window_len = self
+            .supports(inference::CAP_OUTPUT_WINDOW)
+            .then(|| (MAX_OUTPUT_SIZE / per_image).max(1));
+        let reject_below = self.reject_below;
+        let invoke = |input: &[u8], count| {
+            let probs_size = probabilities_size(flags, count, num_classes);
+            self.invoke_with_probabilities(input, count * output_per_image, probs_size, slot)
+        };
+        windowed_calls(images, flags, temperature, reject_below, window_len, invoke)
+    }
+
+    // One Infer with the probabilities memref, checking the TA filled both
+    // outputs exactly
+    fn invoke_with_probabilities(
+        &mut self,
+        input: &[u8],
+        output_len: usize,
+        probs_size: usize,
+        slot: u32,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<u8>)> {
+        let mut output = vec![0_u8; output_len];
+        let mut probs = vec![0_u8; probs_size];
+        let result = {
+            let expected_output = output_size(Command::Infer, 0, output_len);
+            let expected_probs = output_size(Command::Infer, 0, probs_size);
+            let call = TaCall::new(Command::Infer)
+                .input(input)
+                .output(&mut output)
+                .value(0, slot)
+                .output(&mut probs);
+            let (result, reply) = self.invoke_idempotent(1 << slot, call);
+            if result.is_ok() {
+                reply.checked_size::<1>(expected_output)?;
+                reply.checked_size::<3>(expected_probs)?;
+            }
+            result
+        };
+        forget_error_detail();
+        result?;
+        Ok((output, probs))
+    }
+
+    /// Runs every model selected by `slot_mask` (bit N = slot N) and returns
+    /// the labels and probabilities of their averaged softmax outputs.
+    pub fn infer_ensemble(
+        &mut self,
+        images: &[Image],
+        slot_mask: u32,
+        temperature: f32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        self.require(inference::CAP_ENSEMBLE, "ensemble inference")?;
+        if self.input_flags & !FLAG_FIXED_POINT_PROBS != 0 {
+            // Ensemble requests are bare image arrays, with no flags to carry,
+            // so their probabilities always come back as f32
+            println!("ensemble inference always applies the models' normalization");
+            return Err(ErrorKind::NotSupported.into());
+        }
+        if self.reject_below.is_some() {
+            println!("ensemble inference can't reject images, it has no threshold to carry");
+            return Err(ErrorKind::NotSupported.into());
+        }
+        let temperature = fixed_point_temperature(temperature)?;
+        let probs_size = inference::probabilities_size(images.len(), num_classes);
+        let mut output = vec![0_u8; images.len()];
+        let mut probs = vec![0_u8; probs_size];
+        let result = {
+            let call = TaCall::new(Command::InferEnsemble)
+                .input(wire::as_bytes(images))
+                .output(&mut output)
+                .value(slot_mask, temperature)
+                .output(&mut probs);
+            let (result, reply) = self.invoke_idempotent(slot_mask, call);
+            if result.is_ok() {
+                reply.checked_size::<1>(output_size(Command::InferEnsemble, 0, images.len()))?;
+                reply.checked_size::<3>(output_size(Command::InferEnsemble, 0, probs_size))?;
+            }
+            result
+        };
+        forget_error_detail();
+        result?;
+        Ok((output, decode_probabilities(&probs, 0)))
+    }
 }
 
-impl InferenceTaConnector {
-    pub fn new(ctx: &mut Context) -> optee_teec::Result<Self> {
-        let uuid = Uuid::parse_str(inference::UUID).map_err(|err| {
-            println!(
-                "parse uuid \"{}\" failed due to: {:?}",
-                inference::UUID,
-                err
-            );
-            ErrorKind::BadParameters
-        })?;
-        // Open a session with minimal data
-        let dummy = [0u8; 1];
-        let mut op = Operation::new(0, ParamTmpRef::new_input(&dummy), ParamNone, ParamNone, ParamNone);
-        Ok(Self { sess: ctx.open_session_with_operation(uuid, &mut op)? })
+impl InferenceTa for InferenceTaConnector {
+    fn supports(&self, capability: u32) -> bool {
+        InferenceTaConnector::supports(self, capability)
     }
 
-    pub fn begin_model_load(&mut self) -> optee_teec::Result<()> {
-        let mut op = Operation::new(4, ParamNone, ParamNone, ParamNone, ParamNone);
-        self.sess.invoke_command(4, &mut op)?;
-        Ok(())
+    fn chunk_sizes(&self) -> Option<ChunkSizes> {
+        InferenceTaConnector::chunk_sizes(self)
     }
 
-    pub fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()> {
-        let mut op = Operation::new(5, ParamTmpRef::new_input(chunk), ParamNone, ParamNone, ParamNone);
-        self.sess.invoke_command(5, &mut op)?;
-        Ok(())
+    fn begin_model_load(&mut self, slot: u32, size: usize) -> optee_teec::Result<()> {
+        InferenceTaConnector::begin_model_load(self, slot, size)
     }
 
-    pub fn finalize_model_load(&mut self) -> optee_teec::Result<()> {
-        let mut op = Operation::new(6, ParamNone, ParamNone, ParamNone, ParamNone);
-        self.sess.invoke_command(6, &mut op)?;
-        Ok(())
+    fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()> {
+        InferenceTaConnector::push_encrypted_chunk(self, chunk)
     }
-    pub fn infer_batch(&mut self, images: &[Image]) -> optee_teec::Result<Vec<u8>> {
-        let mut output = vec![0_u8; images.len()];
+
+    fn finalize_model_load(&mut self, signature: Option<&[u8]>) -> optee_teec::Result<()> {
+        InferenceTaConnector::finalize_model_load(self, signature)
+    }
+
+    fn storage_preflight(&mut self, size: usize) -> optee_teec::Result<StoragePreflight> {
+        InferenceTaConnector::storage_preflight(self, size)
+    }
+
+    fn model_status(&mut self, slot: u32) -> optee_teec::Result<ModelStatus> {
+        InferenceTaConnector::model_status(self, slot)
+    }
+
+    fn class_labels(&mut self, slot: u32) -> optee_teec::Result<Vec<String>> {
+        InferenceTaConnector::class_labels(self, slot)
+    }
+
+    fn set_input_flags(&mut self, flags: u32) -> optee_teec::Result<()> {
+        InferenceTaConnector::set_input_flags(self, flags)
+    }
+
+    fn set_result_cache(&mut self, entries: u32) -> optee_teec::Result<()> {
+        InferenceTaConnector::set_result_cache(self, entries)
+    }
+
+    fn require_feature(&mut self, feature: &str) -> optee_teec::Result<()> {
+        InferenceTaConnector::require_feature(self, feature)
+    }
+
+    fn set_reject_threshold(&mut self, threshold: Option<f32>) -> optee_teec::Result<()> {
+        InferenceTaConnector::set_reject_threshold(self, threshold)
+    }
+
+    fn infer_batch(&mut self, images: &[Image], slot: u32) -> optee_teec::Result<Vec<u8>> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_batch(self, images, slot)
+        })
+        .inspect_err(report_license_expired)
+    }
+
+    fn infer_predictions(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_predictions(self, images, temperature, slot)
+        })
+        .inspect_err(report_license_expired)
+    }
+
+    fn infer_batch_with_probabilities(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_batch_with_probabilities(
+                self,
+                images,
+                temperature,
+                slot,
+                num_classes,
+            )
+        })
+        .inspect_err(report_license_expired)
+    }
+
+    fn infer_correlated(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_correlated(self, images, temperature, slot)
+        })
+        .inspect_err(report_license_expired)
+    }
+
+    fn infer_ensemble(
+        &mut self,
+        images: &[Image],
+        slot_mask: u32,
+        temperature: f32,
+        num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_ensemble(self, images, slot_mask, temperature, num_classes)
+        })
+        .inspect_err(report_license_expired)
+    }
+
+    fn infer_recorded(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        num_classes: usize,
+    ) -> optee_teec::Result<RecordedBatch> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_recorded(self, images, temperature, slot, num_classes)
+        })
+        .inspect_err(report_license_expired)
+    }
+
+    fn infer_within_budget(
+        &mut self,
+        images: &[Image],
+        temperature: f32,
+        slot: u32,
+        budget: Duration,
+    ) -> optee_teec::Result<BudgetOutcome> {
+        metrics::measure_request(images.len(), || {
+            InferenceTaConnector::infer_within_budget(self, images, temperature, slot, budget)
+        })
+        .inspect_err(report_license_expired)
+    }
+}
+
+pub fn fixed_point_temperature(temperature: f32) -> optee_teec::Result<u32> {
+    inference::fixed_point_temperature(temperature).map_err(|err| {
+        println!("{}", err);
+        ErrorKind::BadParameters.into()
+    })
+}
+
+pub fn fixed_point_threshold(threshold: f32) -> optee_teec::Result<u32> {
+    inference::fixed_point_threshold(threshold).map_err(|err| {
+        println!("{}", err);
+        ErrorKind::BadParameters.into()
+    })
+}
+
+/// Parameters of an Infer call with a single output: the request, the
+/// results and the slot.
+type InferParams<'a> = (Input<'a>, Output<'a>, ValueIn);
+
+/// `InferenceTaConnector::infer_batch` through the shared buffer, one call
+/// per `max_batch` images, each made by `invoke`; the last call only passes
+/// the part of the buffer its smaller batch fills.
+fn infer_batch_shared(
+    shared: &mut SharedBatch,
+    images: &[Image],
+    slot: u32,
+    flags: u32,
+    mut invoke: impl for<'a> FnMut(
+        TaCall<InferParams<'a>>,
+    ) -> (optee_teec::Result<()>, TaReply<InferParams<'a>>),
+) -> optee_teec::Result<Vec<u8>> {
+    let output_offset = shared.input_capacity();
+    let mut labels = Vec::with_capacity(images.len());
+    for batch in images.chunks(shared.max_batch) {
+        let header = request_header(batch.len(), flags, 1.0)?;
+        let header = wire::bytes_of(&header);
+        let input_size = header.len() + size_of_val(batch);
+        let (input, output) = shared.buffer.split_at_mut(output_offset);
+        input[..header.len()].copy_from_slice(header);
+        input[header.len()..input_size].copy_from_slice(wire::as_bytes(batch));
+        let output = &mut output[..batch.len()];
         let size = {
-            let mut op = Operation::new(
-                0,
-                ParamTmpRef::new_input(bytemuck::cast_slice(images)),
-                ParamTmpRef::new_output(&mut output),
-                ParamNone,
-                ParamNone,
-            );
-            self.sess.invoke_command(0, &mut op)?;
-            op.parameters().1.updated_size()
+            let call = TaCall::new(Command::Infer)
+                .input(&input[..input_size])
+                .output(output)
+                .value(0, slot);
+            let (result, reply) = invoke(call);
+            result?;
+            reply.checked_size::<1>(output_size(Command::Infer, flags, batch.len()))?
+        };
+        labels.extend_from_slice(&output[..size]);
+    }
+    Ok(labels)
+}
+
+/// Fails unless `flags` is a valid argument of `InferenceTa::set_input_flags`.
+pub fn check_input_flags(flags: u32) -> optee_teec::Result<()> {
+    let scaling = inference::FLAG_RAW_SCALE | inference::FLAG_NO_NORMALIZE;
+    let known =
+        scaling | inference::FLAG_SKIP_SHADOW | inference::FLAG_NO_CACHE | FLAG_FIXED_POINT_PROBS;
+    if flags & !known != 0 || flags & scaling == scaling {
+        println!("invalid input flags {:#x}", flags);
+        return Err(ErrorKind::BadParameters.into());
+    }
+    Ok(())
+}
+
+fn request_header(
+    batch_size: usize,
+    flags: u32,
+    temperature: f32,
+) -> optee_teec::Result<InferenceRequestHeader> {
+    InferenceRequestHeader::new(batch_size, flags, temperature).map_err(|err| {
+        println!("invalid inference request: {}", err);
+        ErrorKind::BadParameters.into()
+    })
+}
+
+// Request header, followed by the threshold (in thousandths) with
+// `FLAG_REJECT_BELOW` when `reject_below` is set and by the window with
+// `FLAG_OUTPUT_WINDOW` when `window` is
+fn request_prefix(
+    batch_size: usize,
+    flags: u32,
+    temperature: f32,
+    reject_below: Option<u32>,
+    window: Option<OutputWindow>,
+) -> optee_teec::Result<Vec<u8>> {
+    let flags = match reject_below {
+        Some(_) => flags | FLAG_REJECT_BELOW,
+        None => flags,
+    };
+    let flags = match window {
+        Some(_) => flags | FLAG_OUTPUT_WINDOW,
+        None => flags,
+    };
+    let header = request_header(batch_size, flags, temperature)?;
+    let mut prefix = wire::bytes_of(&header).to_vec();
+    if let Some(threshold) = reject_below {
+        prefix.extend_from_slice(&threshold.to_le_bytes());
+    }
+    if let Some(window) = window {
+        prefix.extend_from_slice(&window.to_bytes());
+    }
+    Ok(prefix)
+}
+
+/// Input memref of `Command::Infer`: request header followed by the images.
+/// With `reject_below` (a threshold in thousandths) the request is sent with
+/// `FLAG_REJECT_BELOW`.
+pub fn request(
+    images: &[Image],
+    flags: u32,
+    temperature: f32,
+    reject_below: Option<u32>,
+) -> optee_teec::Result<Vec<u8>> {
+    windowed_request(images, flags, temperature, reject_below, None)
+}
+
+// `request` that, with `window`, asks only for the results of the images in
+// it (`FLAG_OUTPUT_WINDOW`)
+fn windowed_request(
+    images: &[Image],
+    flags: u32,
+    temperature: f32,
+    reject_below: Option<u32>,
+    window: Option<OutputWindow>,
+) -> optee_teec::Result<Vec<u8>> {
+    let mut input = request_prefix(images.len(), flags, temperature, reject_below, window)?;
+    input.extend_from_slice(wire::as_bytes(images));
+    Ok(input)
+}
+
+// Runs `invoke` with the input and image count of each call of an Infer
+// request over `images`: one call for the whole batch, or with `window_len`,
+// one per window of at most that many images of the same request. Returns
+// the results of the calls in image order.
+fn windowed_calls<T>(
+    images: &[Image],
+    flags: u32,
+    temperature: f32,
+    reject_below: Option<u32>,
+    window_len: Option<usize>,
+    mut invoke: impl FnMut(&[u8], usize) -> optee_teec::Result<T>,
+) -> optee_teec::Result<Vec<T>> {
+    let window_len = match window_len {
+        Some(len) if len < images.len() => len,
+        _ => {
+            let input = request(images, flags, temperature, reject_below)?;
+            return Ok(vec![invoke(&input, images.len())?]);
+        }
+    };
+    let mut outputs = Vec::new();
+    for start in (0..images.len()).step_by(window_len) {
+        let count = window_len.min(images.len() - start);
+        let window = OutputWindow {
+            start: start as u32,
+            count: count as u32,
         };
+        let input = windowed_request(images, flags, temperature, reject_below, Some(window))?;
+        outputs.push(invoke(&input, count)?);
+    }
+    Ok(outputs)
+}
+
+/// `request` with `FLAG_TIME_BUDGET`, the budget in milliseconds following
+/// the threshold.
+pub fn budgeted_request(
+    images: &[Image],
+    flags: u32,
+    temperature: f32,
+    reject_below: Option<u32>,
+    budget_ms: u32,
+) -> optee_teec::Result<Vec<u8>> {
+    let flags = flags | FLAG_TIME_BUDGET;
+    let mut input = request_prefix(images.len(), flags, temperature, reject_below, None)?;
+    input.extend_from_slice(&budget_ms.to_le_bytes());
+    input.extend_from_slice(wire::as_bytes(images));
+    Ok(input)
+}
+
+/// Input memref of a correlated `Command::Infer`: request header, the ids
+/// padded to `correlation_block_size`, then the images. `flags` are sent
+/// along with `FLAG_CORRELATION`.
+pub fn correlated_request(
+    images: &[Image],
+    ids: &[u32],
+    flags: u32,
+    temperature: f32,
+    reject_below: Option<u32>,
+) -> optee_teec::Result<Vec<u8>> {
+    let prefix = request_prefix(
+        images.len(),
+        FLAG_CORRELATION | flags,
+        temperature,
+        reject_below,
+        None,
+    )?;
+    let images_offset = inference::correlation_block_size(ids.len())
+        .and_then(|size| size.checked_add(prefix.len()))
+        .ok_or(ErrorKind::BadParameters)?;
+    let mut input = Vec::with_capacity(images_offset + size_of_val(images));
+    input.extend_from_slice(&prefix);
+    for id in ids {
+        input.extend_from_slice(&id.to_le_bytes());
+    }
+    input.resize(images_offset, 0);
+    input.extend_from_slice(wire::as_bytes(images));
+    Ok(input)
+}
 
-        if output.len() != size {
-            println!("mismatch response, want {}, got {}", size, output.len());
-            return Err(ErrorKind::Generic.into());
+type BudgetParams<'a> = (Input<'a>, Output<'a>, ValueInout);
+
+/// Sends the `budgeted_request` `input` of `images` images with `flags` to
+/// `slot` through `invoke`, and reads back the predictions of the images the
+/// TA got through.
+fn read_budget_outcome(
+    images: usize,
+    flags: u32,
+    slot: u32,
+    input: &[u8],
+    invoke: impl for<'a> FnOnce(
+        TaCall<BudgetParams<'a>>,
+    ) -> (optee_teec::Result<()>, TaReply<BudgetParams<'a>>),
+) -> optee_teec::Result<BudgetOutcome> {
+    let mut output = vec![Prediction::zeroed(); images];
+    let (result, size, completed, status) = {
+        let flags = flags | FLAG_TIME_BUDGET;
+        let expected = output_size(Command::Infer, flags, size_of_val(output.as_slice()));
+        // The TA reports the images it got through in the value parameter
+        let call = TaCall::new(Command::Infer)
+            .input(input)
+            .output(wire::as_bytes_mut(&mut output))
+            .value_inout(0, slot);
+        let (result, reply) = invoke(call);
+        if result.is_ok() {
+            reply.checked_size::<1>(expected)?;
         }
-        Ok(output)
+        let (completed, status) = reply.value::<2>();
+        (result, reply.size::<1>(), completed as usize, status)
+    };
+    forget_error_detail();
+    result?;
+    let exceeded = status & BUDGET_EXCEEDED != 0;
+    if completed == 0
+        || completed > images
+        || (!exceeded && completed != images)
+        || size != completed * size_of::<Prediction>()
+    {
+        println!(
+            "mismatch response, {} of {} images in {} bytes",
+            completed, images, size
+        );
+        return Err(ErrorKind::Generic.into());
+    }
+    output.truncate(completed);
+    Ok(if exceeded {
+        BudgetOutcome::Exceeded(output)
+    } else {
+        BudgetOutcome::Complete(output)
+    })
+}
+
+type BuildManifestParams<'a> = (Output<'a>,);
+
+/// Reads the TA's `BuildManifest` with `Command::GetBuildManifest` through
+/// `invoke`, `None` when the TA predates the command.
+fn read_build_manifest(
+    invoke: impl for<'a> FnOnce(
+        TaCall<BuildManifestParams<'a>>,
+    ) -> (optee_teec::Result<()>, TaReply<BuildManifestParams<'a>>),
+) -> optee_teec::Result<Option<BuildManifest>> {
+    let mut output = vec![0_u8; inference::MAX_BUILD_MANIFEST_SIZE];
+    let size = {
+        let expected = output_size(Command::GetBuildManifest, 0, output.len());
+        let (result, reply) = invoke(TaCall::new(Command::GetBuildManifest).output(&mut output));
+        let reported = reply.size::<0>();
+        record_invoke(Command::GetBuildManifest, 0, Some(reported), &result);
+        match result {
+            Err(err) if matches!(err.kind(), ErrorKind::BadParameters) => return Ok(None),
+            result => result?,
+        }
+        reply.checked_size::<0>(expected)?
+    };
+    output.truncate(size);
+    serde_json::from_slice(&output).map(Some).map_err(|err| {
+        println!("malformed build manifest: {}", err);
+        ErrorKind::BadFormat.into()
+    })
+}
+
+/// Fails with NotSupported when `manifest` lacks the Cargo feature
+/// `feature`; a TA without a manifest is assumed to have it.
+fn check_feature(manifest: Option<&BuildManifest>, feature: &str) -> optee_teec::Result<()> {
+    match manifest {
+        Some(manifest) if !manifest.has_feature(feature) => {
+            println!("TA built without {}", feature);
+            Err(ErrorKind::NotSupported.into())
+        }
+        _ => Ok(()),
+    }
+}
+
+type ShadowReportParams<'a> = (Output<'a>, ValueIn);
+
+/// Reads the shadow report with `Command::GetShadowReport` through
+/// `invoke`, resetting it once read if `reset`.
+fn read_shadow_report(
+    reset: bool,
+    invoke: impl for<'a> FnOnce(
+        TaCall<ShadowReportParams<'a>>,
+    ) -> (optee_teec::Result<()>, TaReply<ShadowReportParams<'a>>),
+) -> optee_teec::Result<ShadowReport> {
+    let mut output = vec![0_u8; inference::MAX_SHADOW_REPORT_SIZE];
+    let flags = if reset {
+        inference::SHADOW_REPORT_RESET
+    } else {
+        0
+    };
+    let size = {
+        let expected = output_size(Command::GetShadowReport, 0, output.len());
+        let (result, reply) = invoke(
+            TaCall::new(Command::GetShadowReport)
+                .output(&mut output)
+                .value(flags, 0),
+        );
+        let reported = reply.size::<0>();
+        record_invoke(Command::GetShadowReport, 0, Some(reported), &result);
+        result?;
+        reply.checked_size::<0>(expected)?
+    };
+    output.truncate(size);
+    serde_json::from_slice(&output).map_err(|err| {
+        println!("malformed shadow report: {}", err);
+        ErrorKind::BadFormat.into()
+    })
+}
+
+/// Sends `id` with `Command::SetTraceId` through `invoke`. Returns the
+/// warning to print when the TA did not take it.
+fn pass_trace_id(
+    id: u64,
+    invoke: impl FnOnce(TaCall<(ValueInout,)>) -> (optee_teec::Result<()>, TaReply<(ValueInout,)>),
+) -> Option<String> {
+    let (low, high) = inference::split_trace_id(id);
+    let (result, reply) = invoke(TaCall::new(Command::SetTraceId).value_inout(low, high));
+    record_invoke(Command::SetTraceId, 0, None, &result);
+    let (low, high) = reply.value::<0>();
+    let echoed = inference::join_trace_id(low, high);
+    match result {
+        Err(err) => Some(format!(
+            "warning: the TA did not take the trace id: {}",
+            err
+        )),
+        Ok(()) if echoed != id => Some(format!(
+            "warning: the TA logs with trace id {} instead of {}",
+            transcript::format_trace_id(echoed),
+            transcript::format_trace_id(id)
+        )),
+        Ok(()) => None,
+    }
+}
+
+/// Sends the correlated request `input` for the images with `ids` through
+/// `invoke` and returns their predictions once the results are known to
+/// carry the same ids, in order.
+fn infer_correlated_call(
+    input: &[u8],
+    ids: &[u32],
+    slot: u32,
+    invoke: impl for<'a> FnOnce(
+        TaCall<InferParams<'a>>,
+    ) -> (optee_teec::Result<()>, TaReply<InferParams<'a>>),
+) -> optee_teec::Result<Vec<Prediction>> {
+    let mut output = vec![CorrelatedPrediction::zeroed(); ids.len()];
+    {
+        let expected = output_size(Command::Infer, 0, size_of_val(output.as_slice()));
+        let call = TaCall::new(Command::Infer)
+            .input(input)
+            .output(wire::as_bytes_mut(&mut output))
+            .value(0, slot);
+        let (result, reply) = invoke(call);
+        result?;
+        reply.checked_size::<1>(expected)?;
+    }
+    verify_correlation(ids, &output)
+}
+
+/// Hands out `count` consecutive ids starting at `next`.
+pub fn take_ids(next: &mut u32, count: usize) -> Vec<u32> {
+    (0..count)
+        .map(|_| {
+            let id = *next;
+            *next = next.wrapping_add(1);
+            id
+        })
+        .collect()
+}
+
+/// Unwraps correlated results once they are known to answer `ids`, in order.
+pub fn verify_correlation(
+    ids: &[u32],
+    results: &[CorrelatedPrediction],
+) -> optee_teec::Result<Vec<Prediction>> {
+    inference::check_correlation(ids, results).map_err(|err| {
+        println!("results don't match the request: {}", err);
+        ErrorKind::Generic
+    })?;
+    Ok(results.iter().map(|result| result.prediction).collect())
+}
+
+// Size of the probabilities output of a request sent with `flags`
+// What `cmd` may report writing to an output buffer of `len` bytes; every
+// output is checked against it before any of the buffer is read. Infer
+// requests with `flags` holding `FLAG_TIME_BUDGET` stop between images, so
+// they fill whole predictions. The match has no catch-all, so a new command
+// doesn't build until its outputs are declared here.
+fn output_size(cmd: Command, flags: u32, len: usize) -> OutputSize {
+    match cmd {
+        Command::Infer if flags & FLAG_TIME_BUDGET != 0 => OutputSize::MultipleOf {
+            unit: size_of::<Prediction>(),
+            max: len,
+        },
+        Command::Infer
+        | Command::InferEnsemble
+        | Command::ExportAesKey
+        | Command::GetKeyFingerprint
+        | Command::BeginModelExport
+        | Command::ReadEncryptedChunk => OutputSize::Exact(len),
+        // JSON documents, self-test results and error details
+        Command::EncryptModel
+        | Command::DecryptModel
+        | Command::ModelStatus
+        | Command::GetClassLabels
+        | Command::ListStorage
+        | Command::DeleteStorageObject
+        | Command::GetPersistentStats
+        | Command::ModelHistory
+        | Command::RunSelfTest
+        | Command::GetLastCrash
+        | Command::GetShadowReport
+        | Command::GetSchemaVersion
+        | Command::GetBuildManifest
+        | Command::FinalizeModelLoad
+        | Command::PatchModel
+        | Command::RollbackModel
+        | Command::StageModel
+        | Command::CommitModel => OutputSize::AtMost(len),
+        // No output buffer, so nothing can have been written
+        Command::StoreKey
+        | Command::RotateKey
+        | Command::BeginModelLoad
+        | Command::PushEncryptedChunk
+        | Command::ResetPersistentStats
+        | Command::StoragePreflight
+        | Command::RebindStorage
+        | Command::SetResidencyPolicy
+        | Command::DiscardStaged
+        | Command::DebugPanic
+        | Command::EndModelExport
+        | Command::SetTraceId
+        | Command::SetShadow
+        | Command::SetResultCache
+        | Command::SetModelVerifyKey
+        | Command::SetManagePolicy => OutputSize::Exact(0),
+    }
+}
+
+fn probabilities_size(flags: u32, batch: usize, num_classes: usize) -> usize {
+    if flags & FLAG_FIXED_POINT_PROBS != 0 {
+        inference::fixed_point_probabilities_size(batch, num_classes)
+    } else {
+        inference::probabilities_size(batch, num_classes)
+    }
+}
+
+// Probabilities of a request sent with `flags`: little-endian f32, or u16
+// in units of `PROB_SCALE` with `FLAG_FIXED_POINT_PROBS`
+fn decode_probabilities(bytes: &[u8], flags: u32) -> Vec<f32> {
+    if flags & FLAG_FIXED_POINT_PROBS != 0 {
+        let encoded: Vec<u16> = bytes
+            .chunks_exact(size_of::<u16>())
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        return fixed_point::decode_probs(&encoded);
     }
+    bytes
+        .chunks_exact(size_of::<f32>())
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
 }
 
 pub struct ModelEncryptorTaConnector {
@@ -84,14 +2391,7 @@ pub struct ModelEncryptorTaConnector {
 
 impl ModelEncryptorTaConnector {
     pub fn new(ctx: &mut Context) -> optee_teec::Result<Self> {
-        let uuid = Uuid::parse_str(inference::UUID).map_err(|err| {
-            println!(
-                "parse uuid \"{}\" failed due to: {:?}",
-                inference::UUID,
-                err
-            );
-            ErrorKind::BadParameters
-        })?;
+        let uuid = inference_ta_uuid()?;
 
         // Create a dummy session just to get encryption capability
         let dummy_data = vec![0u8; 32]; // Minimal dummy data
@@ -100,7 +2400,7 @@ impl ModelEncryptorTaConnector {
             ParamTmpRef::new_input(&dummy_data),
             ParamNone,
             ParamNone,
-            ParamNone,
+            manage_role(),
         );
 
         let sess = ctx.open_session_with_operation(uuid, &mut open_op)?;
@@ -110,15 +2410,13 @@ impl ModelEncryptorTaConnector {
     pub fn encrypt_model(&mut self, model_data: &[u8]) -> optee_teec::Result<Vec<u8>> {
         let mut encrypted_output = vec![0_u8; model_data.len() + 1024]; // Extra space for padding
         let size = {
-            let mut op = Operation::new(
-                1, // Command ID for model encryption
-                ParamTmpRef::new_input(model_data),
-                ParamTmpRef::new_output(&mut encrypted_output),
-                ParamNone,
-                ParamNone,
-            );
-            self.sess.invoke_command(1, &mut op)?;
-            op.parameters().1.updated_size()
+            let expected = output_size(Command::EncryptModel, 0, encrypted_output.len());
+            let (result, reply) = TaCall::new(Command::EncryptModel)
+                .input(model_data)
+                .output(&mut encrypted_output)
+                .invoke(&mut self.sess);
+            result?;
+            reply.checked_size::<1>(expected)?
         };
 
         encrypted_output.truncate(size);
@@ -132,14 +2430,7 @@ pub struct ModelDecryptorTaConnector {
 
 impl ModelDecryptorTaConnector {
     pub fn new(ctx: &mut Context) -> optee_teec::Result<Self> {
-        let uuid = Uuid::parse_str(inference::UUID).map_err(|err| {
-            println!(
-                "parse uuid \"{}\" failed due to: {:?}",
-                inference::UUID,
-                err
-            );
-            ErrorKind::BadParameters
-        })?;
+        let uuid = inference_ta_uuid()?;
 
         // Create a dummy session just to get decryption capability
         let dummy_data = vec![0u8; 32]; // Minimal dummy data
@@ -176,18 +2467,14 @@ impl ModelDecryptorTaConnector {
 
 pub struct KeyProvisionTaConnector {
     sess: Session,
+    // Context of a session reopened after a lost reply; declared after `sess`
+    // so it is dropped after it
+    reopened_ctx: Option<Context>,
 }
 
 impl KeyProvisionTaConnector {
     pub fn new(ctx: &mut Context) -> optee_teec::Result<Self> {
-        let uuid = Uuid::parse_str(inference::UUID).map_err(|err| {
-            println!(
-                "parse uuid \"{}\" failed due to: {:?}",
-                inference::UUID,
-                err
-            );
-            ErrorKind::BadParameters
-        })?;
+        let uuid = inference_ta_uuid()?;
 
         // Small open to create session
         let dummy_data = vec![0u8; 16];
@@ -196,22 +2483,894 @@ impl KeyProvisionTaConnector {
             ParamTmpRef::new_input(&dummy_data),
             ParamNone,
             ParamNone,
-            ParamNone,
+            manage_role(),
         );
 
-        let sess = ctx.open_session_with_operation(uuid, &mut open_op)?;
-        Ok(Self { sess })
+        let sess = ctx
+            .open_session_with_operation(uuid, &mut open_op)
+            .inspect_err(|err| report_manage_denied(err, SessionRole::Manage))?;
+        Ok(Self {
+            sess,
+            reopened_ctx: None,
+        })
     }
 
-    pub fn store_key(&mut self, key: &[u8; 32]) -> optee_teec::Result<()> {
-        let mut op = Operation::new(
-            3, // Command ID for key provision
-            ParamTmpRef::new_input(key),
-            ParamNone,
-            ParamNone,
-            ParamNone,
-        );
-        self.sess.invoke_command(3, &mut op)?;
+    /// Stores `key` with its commitment: storing the key the TA already has
+    /// succeeds without writing, a different one fails with AccessConflict
+    /// unless `force`. That makes the call safe to repeat, so a session lost
+    /// before the reply came back is reopened and the key sent again.
+    pub fn store_key(&mut self, key: &[u8; 32], force: bool) -> optee_teec::Result<()> {
+        let commitment: [u8; inference::KEY_COMMITMENT_SIZE] = Sha256::digest(key).into();
+        let flags = if force { inference::STORE_KEY_FORCE } else { 0 };
+        let result = self.retrying(Command::StoreKey, |sess| {
+            let (result, _) = TaCall::new(Command::StoreKey)
+                .input(key)
+                .input(&commitment)
+                .value(flags, 0)
+                .invoke(sess);
+            record_invoke(Command::StoreKey, key.len(), None, &result);
+            result
+        });
+        match result {
+            // TAs predating commitments can't tell whether a key is stored,
+            // and replace it, so they only get the key when forced
+            Err(err) if matches!(err.kind(), ErrorKind::BadParameters) && force => {
+                let (result, _) = TaCall::new(Command::StoreKey)
+                    .input(key)
+                    .invoke(&mut self.sess);
+                record_invoke(Command::StoreKey, key.len(), None, &result);
+                result
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::BadParameters) => {
+                println!("TA predates key commitments and would replace any stored key");
+                Err(ErrorKind::NotSupported.into())
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces the TA's key with `key`, re-encrypting the stored model
+    /// under it. The TA checks the new ciphertext before persisting anything
+    /// and keeps the old key on any failure; rotating to the key it already
+    /// has succeeds, so a lost session is reopened and the call repeated.
+    pub fn rotate_key(&mut self, key: &[u8; 32]) -> optee_teec::Result<()> {
+        let commitment: [u8; inference::KEY_COMMITMENT_SIZE] = Sha256::digest(key).into();
+        let result = self.retrying(Command::RotateKey, |sess| {
+            let (result, _) = TaCall::new(Command::RotateKey)
+                .input(key)
+                .input(&commitment)
+                .invoke(sess);
+            record_invoke(Command::RotateKey, key.len(), None, &result);
+            result
+        });
+        match result {
+            Err(err) if matches!(err.kind(), ErrorKind::BadParameters) => {
+                println!("TA predates key rotation");
+                Err(ErrorKind::NotSupported.into())
+            }
+            result => result,
+        }
+    }
+
+    /// Fingerprint of the stored key (see `inference::KEY_FINGERPRINT_SIZE`),
+    /// `None` when the TA has no key yet.
+    pub fn key_fingerprint(
+        &mut self,
+    ) -> optee_teec::Result<Option<[u8; inference::KEY_FINGERPRINT_SIZE]>> {
+        let mut fingerprint = [0_u8; inference::KEY_FINGERPRINT_SIZE];
+        {
+            let expected = output_size(Command::GetKeyFingerprint, 0, fingerprint.len());
+            let (result, reply) = TaCall::new(Command::GetKeyFingerprint)
+                .output(&mut fingerprint)
+                .invoke(&mut self.sess);
+            let reported = reply.size::<0>();
+            record_invoke(Command::GetKeyFingerprint, 0, Some(reported), &result);
+            match result {
+                Err(err) if matches!(err.kind(), ErrorKind::ItemNotFound) => return Ok(None),
+                Err(err) if matches!(err.kind(), ErrorKind::BadParameters) => {
+                    println!("TA predates key fingerprints");
+                    return Err(ErrorKind::NotSupported.into());
+                }
+                result => result?,
+            }
+            reply.checked_size::<0>(expected)?;
+        }
+        Ok(Some(fingerprint))
+    }
+
+    // Runs `send` and, when the session was lost before the reply came back,
+    // reopens it and runs `send` once more; only for idempotent commands
+    fn retrying<F>(&mut self, command: Command, mut send: F) -> optee_teec::Result<()>
+    where
+        F: FnMut(&mut Session) -> optee_teec::Result<()>,
+    {
+        retry_on_lost_session(
+            self,
+            command,
+            |this| send(&mut this.sess),
+            |this, err| {
+                println!(
+                    "TA session lost during {:?} ({}), reconnecting",
+                    command, err
+                );
+                let reopened = this.reopen();
+                if let Err(err) = &reopened {
+                    println!("Cannot reopen the TA session: {}", err);
+                }
+                transcript::record(Step::Reconnect {
+                    command: format!("{:?}", command),
+                    reason: err.to_string(),
+                    recovered: reopened.is_ok(),
+                });
+                reopened.is_ok()
+            },
+        )
+    }
+
+    fn reopen(&mut self) -> optee_teec::Result<()> {
+        let mut ctx = Context::new()?;
+        let fresh = Self::new(&mut ctx)?;
+        self.sess = fresh.sess;
+        self.reopened_ctx = Some(ctx);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ta_call::{MockParam, OutputSizeError};
+    use proto::inference::{ParamSpec, ParamUse};
+
+    const NUM_CLASSES: usize = 10;
+
+    // Plays the TA's side of a windowed Infer: the whole batch is run, and
+    // the labels and f32 probabilities of the images in the window, or of
+    // all of them without one, are returned. Checks every call carries the
+    // whole batch, so the TA can serve the windows from its cache.
+    fn answer(input: &[u8], count: usize, batch_len: usize) -> (Vec<u8>, Vec<u8>) {
+        let (header, image_bytes) = inference::split_request(input).unwrap();
+        let header = header.unwrap();
+        assert_eq!(image_bytes.len(), batch_len * IMAGE_SIZE);
+        let outputs: Vec<(u8, Vec<f32>)> = image_bytes
+            .chunks_exact(IMAGE_SIZE)
+            .map(|image| {
+                let sum: usize = image.iter().map(|&p| p as usize).sum();
+                let probs = (0..NUM_CLASSES)
+                    .map(|class| ((sum + class) % 7) as f32 / 7.0)
+                    .collect();
+                ((sum % NUM_CLASSES) as u8, probs)
+            })
+            .collect();
+        let range = match inference::output_window(input, &header) {
+            Some(window) => window.range(),
+            None => 0..batch_len,
+        };
+        assert_eq!(range.len(), count);
+        let labels = outputs[range.clone()]
+            .iter()
+            .map(|(label, _)| *label)
+            .collect();
+        let probs = outputs[range]
+            .iter()
+            .flat_map(|(_, probs)| probs.iter().flat_map(|p| p.to_le_bytes()))
+            .collect();
+        (labels, probs)
+    }
+
+    fn images(len: usize) -> Vec<Image> {
+        (0..len)
+            .map(|i| {
+                let mut image = Image::BLANK;
+                image.as_bytes_mut()[i % IMAGE_SIZE] = i as u8 + 1;
+                image.as_bytes_mut()[IMAGE_SIZE - 1] = (i * 37) as u8;
+                image
+            })
+            .collect()
+    }
+
+    // Labels and probabilities stitched from the calls, as
+    // `infer_batch_with_probabilities` does
+    fn run(images: &[Image], window_len: Option<usize>) -> (usize, Vec<u8>, Vec<f32>) {
+        let calls = windowed_calls(images, 0, 1.0, Some(500), window_len, |input, count| {
+            Ok(answer(input, count, images.len()))
+        })
+        .unwrap();
+        let count = calls.len();
+        let (labels, probs): (Vec<_>, Vec<_>) = calls.into_iter().unzip();
+        (
+            count,
+            labels.concat(),
+            decode_probabilities(&probs.concat(), 0),
+        )
+    }
+
+    #[test]
+    fn protocol_negotiation() {
+        let sizes = inference::CAP_CHUNK_SIZES;
+        // A TA of this version
+        let current = (inference::PROTOCOL_VERSION, inference::CAP_SLOTS | sizes);
+        assert_eq!(
+            negotiate(current, (4096, 65536)).unwrap(),
+            Negotiated {
+                protocol_version: inference::PROTOCOL_VERSION,
+                capabilities: inference::CAP_SLOTS | sizes,
+                chunk_sizes: Some(ChunkSizes {
+                    preferred: 4096,
+                    max: 65536,
+                }),
+            }
+        );
+        // One that predates negotiation leaves the reply zeroed
+        assert_eq!(
+            negotiate((0, 0), (0, 0)).map_err(|err| err.kind()),
+            Err(ErrorKind::NotSupported)
+        );
+        // The oldest one still supported, without chunk sizes: parameter 3
+        // is ignored even if it holds something
+        let oldest = negotiate((inference::MIN_TA_PROTOCOL_VERSION, 0), (7, 7)).unwrap();
+        assert_eq!(oldest.capabilities, 0);
+        assert_eq!(oldest.chunk_sizes, None);
+        // A newer one, with capabilities this host doesn't know
+        let newer = negotiate((inference::PROTOCOL_VERSION + 1, u32::MAX), (0, 0)).unwrap();
+        assert_eq!(newer.protocol_version, inference::PROTOCOL_VERSION + 1);
+        assert_eq!(newer.capabilities, u32::MAX);
+        assert_eq!(newer.chunk_sizes, None);
+    }
+
+    // The only test setting the process-wide override
+    #[test]
+    fn ta_uuid_overrides() {
+        assert_eq!(configured_ta_uuid(), inference::UUID);
+        assert!(set_inference_ta_uuid("not-a-uuid").is_err());
+        assert_eq!(configured_ta_uuid(), inference::UUID);
+
+        let uuid = "0a1b2c3d-4e5f-6071-8293-a4b5c6d7e8f9";
+        set_inference_ta_uuid(&format!("{}\n", uuid)).unwrap();
+        assert_eq!(configured_ta_uuid(), uuid);
+        assert!(inference_ta_uuid().is_ok());
+        // Set once, before any session is opened
+        assert!(set_inference_ta_uuid(inference::UUID).is_err());
+        assert_eq!(configured_ta_uuid(), uuid);
+    }
+
+    #[test]
+    fn stitched_windows_equal_a_single_shot_run() {
+        for len in [1, 2, 5, 8, 13] {
+            let images = images(len);
+            let (calls, labels, probs) = run(&images, None);
+            assert_eq!(calls, 1);
+            assert_eq!(labels.len(), len);
+            assert_eq!(probs.len(), len * NUM_CLASSES);
+            for window_len in 1..=len + 1 {
+                let (calls, windowed_labels, windowed_probs) = run(&images, Some(window_len));
+                assert_eq!(calls, len.div_ceil(window_len), "{len} by {window_len}");
+                assert_eq!(windowed_labels, labels, "{len} by {window_len}");
+                assert_eq!(windowed_probs, probs, "{len} by {window_len}");
+            }
+        }
+    }
+
+    #[test]
+    fn windowed_calls_stop_at_the_first_failure() {
+        let images = images(6);
+        let mut calls = 0;
+        let result = windowed_calls(&images, 0, 1.0, None, Some(2), |_, _| {
+            calls += 1;
+            if calls == 2 {
+                return Err(optee_teec::Error::new(ErrorKind::OutOfMemory));
+            }
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::OutOfMemory);
+        assert_eq!(calls, 2);
+    }
+
+    // Plays the TA's side of a plain Infer, labelling each image by its
+    // pixel sum. Returns how many images the call carried.
+    fn label_batch(cmd: u32, params: &mut [MockParam<'_>; 4]) -> usize {
+        assert_eq!(cmd, Command::Infer as u32);
+        let [MockParam::Input(input), MockParam::Output { buffer, size }, MockParam::Value { b: slot, .. }, MockParam::None] =
+            params
+        else {
+            panic!("not an Infer call");
+        };
+        assert_eq!(*slot, 1);
+        let (header, image_bytes) = inference::split_request(input).unwrap();
+        let header = header.unwrap();
+        assert_eq!(header.flags(), inference::FLAG_NO_CACHE);
+        assert_eq!(buffer.len(), header.batch_len() as usize);
+        assert_eq!(image_bytes.len(), buffer.len() * IMAGE_SIZE);
+        for (label, image) in buffer.iter_mut().zip(image_bytes.chunks_exact(IMAGE_SIZE)) {
+            *label = image.iter().map(|&p| p as usize).sum::<usize>() as u8 % 10;
+        }
+        *size = buffer.len();
+        buffer.len()
+    }
+
+    #[test]
+    fn shared_buffer_batches_equal_single_calls() {
+        let flags = inference::FLAG_NO_CACHE;
+        for len in [1, 3, 4, 7, 9] {
+            let images = images(len);
+            let input = request(&images, flags, 1.0, None).unwrap();
+            let mut single = vec![0_u8; len];
+            let call = TaCall::new(Command::Infer)
+                .input(&input)
+                .output(&mut single)
+                .value(0, 1);
+            let (result, _) = call.invoke_mocked(|cmd, params| {
+                label_batch(cmd, params);
+                Ok(())
+            });
+            result.unwrap();
+
+            for max_batch in [1, 3, 4, 16] {
+                let size = size_of::<InferenceRequestHeader>() + max_batch * (IMAGE_SIZE + 1);
+                let mut shared = SharedBatch {
+                    buffer: vec![0xaa; size],
+                    max_batch,
+                };
+                let mut batches = Vec::new();
+                let labels = infer_batch_shared(&mut shared, &images, 1, flags, |call| {
+                    call.invoke_mocked(|cmd, params| {
+                        batches.push(label_batch(cmd, params));
+                        Ok(())
+                    })
+                })
+                .unwrap();
+                assert_eq!(labels, single, "{len} by {max_batch}");
+                let mut expected = vec![max_batch; len / max_batch];
+                if len % max_batch != 0 {
+                    expected.push(len % max_batch);
+                }
+                assert_eq!(batches, expected, "{len} by {max_batch}");
+            }
+        }
+    }
+
+    #[test]
+    fn shared_buffer_batches_stop_at_the_first_failure() {
+        let images = images(7);
+        let size = size_of::<InferenceRequestHeader>() + 3 * (IMAGE_SIZE + 1);
+        let mut shared = SharedBatch {
+            buffer: vec![0; size],
+            max_batch: 3,
+        };
+        let mut calls = 0;
+        let result = infer_batch_shared(&mut shared, &images, 1, 0, |call| {
+            call.invoke_mocked(|_, _| {
+                calls += 1;
+                Err(optee_teec::Error::new(ErrorKind::Busy))
+            })
+        });
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::Busy);
+        assert_eq!(calls, 1);
+    }
+
+    // A correlated Infer of 5 images against a TA that labels every image
+    // with its index and echoes its id, after `tamper` had the results
+    fn correlated_run(
+        tamper: impl FnOnce(&mut [CorrelatedPrediction]),
+    ) -> optee_teec::Result<Vec<Prediction>> {
+        let images = images(5);
+        let ids = take_ids(&mut 40, images.len());
+        let input = correlated_request(&images, &ids, 0, 1.0, None).unwrap();
+        infer_correlated_call(&input, &ids, 1, |call| {
+            call.invoke_mocked(|cmd, params| {
+                assert_eq!(cmd, Command::Infer as u32);
+                let [MockParam::Input(input), MockParam::Output { buffer, size }, ..] = params
+                else {
+                    panic!("not an Infer call");
+                };
+                let (header, _) = inference::split_request(input).unwrap();
+                let ids = inference::correlation_ids(input, &header.unwrap()).unwrap();
+                let mut results: Vec<_> = (0..)
+                    .zip(ids)
+                    .map(|(i, id)| CorrelatedPrediction::new(id, Prediction::new(i, 0.5).unwrap()))
+                    .collect();
+                tamper(&mut results);
+                let results = wire::as_bytes(&results);
+                buffer[..results.len()].copy_from_slice(results);
+                *size = results.len();
+                Ok(())
+            })
+        })
+    }
+
+    #[test]
+    fn reordered_results_are_detected() {
+        let labels = |predictions: Vec<Prediction>| -> Vec<u8> {
+            predictions.iter().map(|p| p.label).collect()
+        };
+        assert_eq!(labels(correlated_run(|_| {}).unwrap()), [0, 1, 2, 3, 4]);
+
+        let tampered: [fn(&mut [CorrelatedPrediction]); 4] = [
+            // Shuffled, as a TA or transport mixing up images would
+            |results| results.swap(1, 3),
+            |results| results.rotate_left(1),
+            // One image answered twice, another not at all
+            |results| results[4] = results[0],
+            // An id that was never sent
+            |results| results[2] = CorrelatedPrediction::new(7, results[2].prediction),
+        ];
+        for tamper in tampered {
+            assert_eq!(
+                correlated_run(tamper).unwrap_err().kind(),
+                ErrorKind::Generic
+            );
+        }
+    }
+
+    // Every command, and Infer once more with the flags of a time budget
+    fn commands() -> Vec<(Command, u32)> {
+        let mut commands: Vec<_> = (0..)
+            .map_while(|id| Command::try_from(id).ok())
+            .map(|cmd| (cmd, 0))
+            .collect();
+        commands.push((Command::Infer, FLAG_TIME_BUDGET));
+        commands
+    }
+
+    // Indices of the buffers the TA writes for `cmd`
+    fn output_params(cmd: Command) -> Vec<usize> {
+        let output = [
+            ParamSpec::Required(ParamUse::MemrefOut),
+            ParamSpec::Optional(ParamUse::MemrefOut),
+        ];
+        (0..4)
+            .filter(|&i| output.contains(&cmd.signature()[i]))
+            .collect()
+    }
+
+    // Runs `cmd` with a `len` byte buffer in output parameter `param`, the
+    // others unset, against a TA reporting `reported` bytes written to it,
+    // and checks the size as the connector does
+    fn check_reported(
+        cmd: Command,
+        flags: u32,
+        param: usize,
+        len: usize,
+        reported: usize,
+    ) -> Result<usize, OutputSizeError> {
+        let mut buf = vec![0_u8; len];
+        let ta = |_, params: &mut [MockParam<'_>; 4]| {
+            let MockParam::Output { size, .. } = &mut params[param] else {
+                panic!("parameter {param} isn't an output");
+            };
+            *size = reported;
+            Ok(())
+        };
+        let expected = output_size(cmd, flags, len);
+        let call = TaCall::new(cmd);
+        let (result, checked) = match param {
+            0 => {
+                let (result, reply) = call.output(&mut buf).invoke_mocked(ta);
+                (result, reply.checked_size::<0>(expected))
+            }
+            1 => {
+                let (result, reply) = call.none().output(&mut buf).invoke_mocked(ta);
+                (result, reply.checked_size::<1>(expected))
+            }
+            2 => {
+                let call = call.none().none().output(&mut buf);
+                let (result, reply) = call.invoke_mocked(ta);
+                (result, reply.checked_size::<2>(expected))
+            }
+            _ => {
+                let call = call.none().none().none().output(&mut buf);
+                let (result, reply) = call.invoke_mocked(ta);
+                (result, reply.checked_size::<3>(expected))
+            }
+        };
+        result.unwrap();
+        checked
+    }
+
+    #[test]
+    fn every_output_has_a_declared_size() {
+        for (cmd, flags) in commands() {
+            let declared = output_size(cmd, flags, 64) != OutputSize::Exact(0);
+            assert_eq!(declared, !output_params(cmd).is_empty(), "{cmd:?}");
+        }
+        assert!(commands().len() > 30);
+    }
+
+    #[test]
+    fn inconsistent_sizes_are_rejected_for_every_command() {
+        let unit = size_of::<Prediction>();
+        let len = 4 * unit;
+        for (cmd, flags) in commands() {
+            let (accepted, rejected) = match output_size(cmd, flags, len) {
+                OutputSize::Exact(_) => (vec![len], vec![0, len - 1, len + 1, usize::MAX]),
+                OutputSize::AtMost(_) => (vec![0, 1, len], vec![len + 1, usize::MAX]),
+                OutputSize::MultipleOf { .. } => {
+                    (vec![0, unit, len], vec![1, unit + 1, len - 1, len + unit])
+                }
+            };
+            for param in output_params(cmd) {
+                for size in accepted.iter().copied() {
+                    let checked = check_reported(cmd, flags, param, len, size);
+                    assert_eq!(checked.ok(), Some(size), "{cmd:?} {param} {size}");
+                }
+                for size in rejected.iter().copied() {
+                    let err = check_reported(cmd, flags, param, len, size).unwrap_err();
+                    assert_eq!((err.command, err.param, err.size), (cmd, param, size));
+                }
+            }
+        }
+    }
+
+    // A session whose replies are scripted, one per command sent; a
+    // reconnect succeeds and finds the models intact when `models_survive`
+    struct ScriptedSession {
+        replies: std::collections::VecDeque<Result<usize, ErrorKind>>,
+        sent: usize,
+        reconnects: usize,
+        models_survive: bool,
+    }
+
+    impl ScriptedSession {
+        fn new(replies: &[Result<usize, ErrorKind>], models_survive: bool) -> Self {
+            Self {
+                replies: replies.iter().copied().collect(),
+                sent: 0,
+                reconnects: 0,
+                models_survive,
+            }
+        }
+
+        // Sends one inference the way `invoke_idempotent` does
+        fn infer(&mut self) -> Result<usize, ErrorKind> {
+            retry_on_lost_session(
+                self,
+                Command::Infer,
+                |link| {
+                    link.sent += 1;
+                    link.replies.pop_front().unwrap().map_err(Into::into)
+                },
+                |link, _| {
+                    link.reconnects += 1;
+                    link.models_survive
+                },
+            )
+            .map_err(|err| err.kind())
+        }
+    }
+
+    #[test]
+    fn sessions_killed_mid_stream_are_reopened_and_the_inference_retried() {
+        use ErrorKind::{Communication, TargetDead};
+        let mut session = ScriptedSession::new(
+            &[
+                Ok(0),
+                Ok(1),
+                Err(TargetDead),
+                Ok(2),
+                Ok(3),
+                Err(Communication),
+                Ok(4),
+            ],
+            true,
+        );
+        let windows: Vec<usize> = (0..5).map(|_| session.infer().unwrap()).collect();
+        assert_eq!(windows, [0, 1, 2, 3, 4]);
+        assert_eq!((session.sent, session.reconnects), (7, 2));
+    }
+
+    #[test]
+    fn inferences_are_retried_only_once() {
+        // Dying again on the retry
+        let mut session = ScriptedSession::new(
+            &[Err(ErrorKind::TargetDead), Err(ErrorKind::TargetDead)],
+            true,
+        );
+        assert_eq!(session.infer(), Err(ErrorKind::TargetDead));
+        assert_eq!((session.sent, session.reconnects), (2, 1));
+
+        // Models lost with the session, or a session that can't be reopened
+        let mut session = ScriptedSession::new(&[Err(ErrorKind::Communication)], false);
+        assert_eq!(session.infer(), Err(ErrorKind::Communication));
+        assert_eq!((session.sent, session.reconnects), (1, 1));
+
+        // Errors the session survives are the TA's answer
+        for kind in [
+            ErrorKind::Busy,
+            ErrorKind::BadParameters,
+            ErrorKind::ShortBuffer,
+        ] {
+            let mut session = ScriptedSession::new(&[Err(kind)], true);
+            assert_eq!(session.infer(), Err(kind));
+            assert_eq!((session.sent, session.reconnects), (1, 0));
+        }
+    }
+
+    #[test]
+    fn trace_ids_round_trip_through_the_ta() {
+        let id = 0x0123_4567_89ab_cdef;
+        // A TA that takes the id and echoes it
+        let mut received = None;
+        let warning = pass_trace_id(id, |call| {
+            call.invoke_mocked(|cmd, params| {
+                assert_eq!(cmd, Command::SetTraceId as u32);
+                let MockParam::Value { a, b } = params[0] else {
+                    panic!("no trace id");
+                };
+                received = Some(inference::join_trace_id(a, b));
+                Ok(())
+            })
+        });
+        assert_eq!(warning, None);
+        assert_eq!(received, Some(id));
+
+        // One that keeps logging with another id
+        let warning = pass_trace_id(id, |call| {
+            call.invoke_mocked(|_, params| {
+                params[0] = MockParam::Value { a: 7, b: 0 };
+                Ok(())
+            })
+        });
+        assert_eq!(
+            warning.unwrap(),
+            "warning: the TA logs with trace id 0000000000000007 instead of 0123456789abcdef"
+        );
+
+        // And one that refuses it
+        let warning = pass_trace_id(id, |call| {
+            call.invoke_mocked(|_, _| Err(ErrorKind::BadParameters.into()))
+        });
+        assert!(warning
+            .unwrap()
+            .starts_with("warning: the TA did not take the trace id"));
+    }
+
+    #[test]
+    fn shadow_reports_come_back_as_the_ta_kept_them() {
+        use proto::inference::{ShadowExample, ShadowReport};
+        let planted = ShadowReport {
+            slots: Some((0, 1)),
+            compared: 40,
+            disagreements: 2,
+            skipped: 8,
+            failed: 0,
+            examples: vec![
+                ShadowExample {
+                    input_hash: "ab".repeat(32),
+                    active_label: 3,
+                    shadow_label: 8,
+                },
+                ShadowExample {
+                    input_hash: "cd".repeat(32),
+                    active_label: 7,
+                    shadow_label: 1,
+                },
+            ],
+        };
+        // A TA answering `report` and checking the reset flag
+        let ta = |report: Vec<u8>, reset: bool| {
+            move |cmd, params: &mut [MockParam<'_>; 4]| {
+                assert_eq!(cmd, Command::GetShadowReport as u32);
+                let [MockParam::Output { buffer, size }, MockParam::Value { a, .. }, ..] = params
+                else {
+                    panic!("not a GetShadowReport call");
+                };
+                assert_eq!(*a == inference::SHADOW_REPORT_RESET, reset);
+                buffer[..report.len()].copy_from_slice(&report);
+                *size = report.len();
+                Ok(())
+            }
+        };
+        let encoded = serde_json::to_vec(&planted).unwrap();
+        for reset in [false, true] {
+            let report =
+                read_shadow_report(reset, |call| call.invoke_mocked(ta(encoded.clone(), reset)));
+            assert_eq!(report.unwrap(), planted);
+        }
+        let report = read_shadow_report(false, |call| {
+            call.invoke_mocked(ta(b"{\"compared\":".to_vec(), false))
+        });
+        assert_eq!(report.unwrap_err().kind(), ErrorKind::BadFormat);
+    }
+
+    #[test]
+    fn error_details_reach_the_error_shown() {
+        let detail = "cannot delete model/v3: TEE_ERROR_STORAGE_NO_SPACE (0xffff3041)";
+        // A TA failing DeleteStorageObject with `kind` and the detail, in
+        // the buffer passed for it if any
+        let ta = |kind: ErrorKind| {
+            move |cmd, params: &mut [MockParam<'_>; 4]| {
+                assert_eq!(cmd, Command::DeleteStorageObject as u32);
+                if let MockParam::Output { buffer, size } = &mut params[3] {
+                    *size = inference::write_error_detail(detail, buffer);
+                }
+                Err(kind.into())
+            }
+        };
+        // Deletes model/v3, with a detail buffer when the TA `reports`
+        // details, and shows the error the way main does
+        let delete = |reports: bool, kind| {
+            let mut detail = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+            let (result, size) = {
+                let (result, reply) = TaCall::new(Command::DeleteStorageObject)
+                    .input(b"model/v3")
+                    .none()
+                    .none()
+                    .error_detail(reports.then_some(&mut detail[..]))
+                    .invoke_mocked(ta(kind));
+                (result, reply.size::<3>())
+            };
+            let noted = error_detail(Command::DeleteStorageObject, &result, &detail, size);
+            let err = anyhow::Error::from(result.unwrap_err()).context("cannot delete model/v3");
+            format!("{:#}", with_error_detail(err, noted))
+        };
+
+        let shown = delete(true, ErrorKind::AccessConflict);
+        assert!(
+            shown.starts_with(&format!("TA reported: DeleteStorageObject: {}:", detail)),
+            "{}",
+            shown
+        );
+        assert!(shown.contains("cannot delete model/v3"), "{}", shown);
+        // TAs that predate details get no buffer and show the bare error
+        let shown = delete(false, ErrorKind::AccessConflict);
+        assert!(!shown.contains("TA reported"), "{}", shown);
+
+        // A detail is only added to the error it came with
+        let conflict = optee_teec::Error::from(ErrorKind::AccessConflict).raw_code();
+        let noted = Some((conflict, "detail".to_string()));
+        let other = anyhow::Error::from(optee_teec::Error::from(ErrorKind::OutOfMemory));
+        assert!(!format!("{:#}", with_error_detail(other, noted.clone())).contains("detail"));
+        let unrelated = anyhow::anyhow!("no such file");
+        assert!(!format!("{:#}", with_error_detail(unrelated, noted)).contains("detail"));
+        // And successful calls or untagged buffers leave none
+        let commit = |result, output: &[u8], size| {
+            error_detail(Command::CommitModel, &result, output, size).map(|(_, detail)| detail)
+        };
+        let failed = || Err(ErrorKind::Generic.into());
+        assert_eq!(commit(failed(), b"EDT:x", 5).unwrap(), "CommitModel: x");
+        assert_eq!(commit(Ok(()), b"EDT:x", 5), None);
+        assert_eq!(commit(failed(), b"EDT:x", 9), None);
+        assert_eq!(commit(failed(), &[0; 8], 8), None);
+    }
+
+    #[test]
+    fn build_manifests_decide_what_the_ta_is_asked() {
+        use proto::inference::{FEATURE_DEBUG_PANIC, FEATURE_DEFLATE, FEATURE_ENCRYPT_MODEL};
+        let manifest = |features: &[&str]| BuildManifest {
+            features: features.iter().map(|f| f.to_string()).collect(),
+            heap_size: 16 << 20,
+            model_memory_budget: 8 << 20,
+            storage_quota: 0,
+            model_history: 2,
+            max_push_chunk_size: 64 << 10,
+        };
+        // A TA answering `reply`, the serialized manifest or an error
+        let ta = |reply: optee_teec::Result<Vec<u8>>| {
+            move |cmd, params: &mut [MockParam<'_>; 4]| {
+                assert_eq!(cmd, Command::GetBuildManifest as u32);
+                let [MockParam::Output { buffer, size }, ..] = params else {
+                    panic!("not a GetBuildManifest call");
+                };
+                let reply = reply?;
+                buffer[..reply.len()].copy_from_slice(&reply);
+                *size = reply.len();
+                Ok(())
+            }
+        };
+        let read = |reply| read_build_manifest(|call| call.invoke_mocked(ta(reply)));
+
+        let full = manifest(&[FEATURE_DEFLATE, FEATURE_ENCRYPT_MODEL]);
+        let decoded = read(Ok(serde_json::to_vec(&full).unwrap())).unwrap();
+        assert_eq!(decoded.as_ref(), Some(&full));
+        check_feature(decoded.as_ref(), FEATURE_DEFLATE).unwrap();
+        // Refused up front by a TA built without the feature
+        let minimal = read(Ok(serde_json::to_vec(&manifest(&[])).unwrap())).unwrap();
+        for feature in [FEATURE_DEFLATE, FEATURE_DEBUG_PANIC] {
+            let err = check_feature(minimal.as_ref(), feature).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotSupported);
+        }
+        let err = check_feature(decoded.as_ref(), FEATURE_DEBUG_PANIC).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotSupported);
+        // TAs predating manifests are left to answer for themselves
+        let legacy = read(Err(ErrorKind::BadParameters.into())).unwrap();
+        assert_eq!(legacy, None);
+        check_feature(legacy.as_ref(), FEATURE_DEBUG_PANIC).unwrap();
+        // Other failures aren't taken for an old TA
+        let err = read(Err(ErrorKind::Busy.into())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Busy);
+        let err = read(Ok(b"{\"features\":[".to_vec())).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BadFormat);
+    }
+
+    // Plays a TA running a budgeted request `TIME_BUDGET_SLICE` images at a
+    // time, each slice taking `slice_ms` of its clock, and answering with
+    // `reply` (images completed, status, bytes written) from what it ran
+    fn budgeted_ta(
+        slice_ms: u64,
+        reply: impl FnOnce(usize, u32) -> (usize, u32, usize),
+    ) -> impl FnOnce(u32, &mut [MockParam<'_>; 4]) -> optee_teec::Result<()> {
+        move |cmd, params| {
+            assert_eq!(cmd, Command::Infer as u32);
+            let [MockParam::Input(input), MockParam::Output { buffer, size }, MockParam::Value { a, b }, _] =
+                params
+            else {
+                panic!("not a budgeted Infer call");
+            };
+            let (header, images) = inference::split_request(input).unwrap();
+            let budget = inference::time_budget(input, &header.unwrap()).unwrap();
+            let images = images.len() / IMAGE_SIZE;
+            let clock = std::cell::Cell::new(proto::inference::DurationMicros::ZERO);
+            let completed = common::run_within_budget(
+                images,
+                inference::TIME_BUDGET_SLICE,
+                proto::inference::DurationMicros::from_millis(budget as u64),
+                || clock.get(),
+                |_| {
+                    let spent = proto::inference::DurationMicros::from_millis(slice_ms);
+                    clock.set(clock.get().saturating_add(spent));
+                    Ok::<_, ()>(())
+                },
+            )
+            .unwrap();
+            let status = match completed < images {
+                true => BUDGET_EXCEEDED,
+                false => 0,
+            };
+            let (completed, status, written) = reply(completed, status);
+            for (i, prediction) in buffer.chunks_exact_mut(4).take(completed).enumerate() {
+                prediction.copy_from_slice(&[i as u8, i as u8, 0xe8, 0x03]);
+            }
+            (*a, *b, *size) = (completed as u32, status, written);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn budgeted_requests_return_the_images_run_in_time() {
+        let images = vec![Image::BLANK; 40];
+        let budget = |ms| budgeted_request(&images, FLAG_PREDICTIONS, 1.0, None, ms).unwrap();
+        let honest = |completed, status| (completed, status, completed * 4);
+        let outcome = |ms, slice_ms| {
+            read_budget_outcome(images.len(), FLAG_PREDICTIONS, 0, &budget(ms), |call| {
+                call.invoke_mocked(budgeted_ta(slice_ms, honest))
+            })
+        };
+        // 40 images at 10ms a slice: two slices fit in 15ms, and the first
+        // always runs
+        for (ms, completed) in [(15, 32), (0, 16)] {
+            let BudgetOutcome::Exceeded(predictions) = outcome(ms, 10).unwrap() else {
+                panic!("a {ms}ms budget isn't exceeded");
+            };
+            assert_eq!(predictions.len(), completed);
+            for (i, prediction) in predictions.iter().enumerate() {
+                assert_eq!(prediction.label, i as u8);
+                assert_eq!(prediction.confidence_milli(), 1000);
+            }
+        }
+        let BudgetOutcome::Complete(predictions) = outcome(1000, 10).unwrap() else {
+            panic!("a 1s budget is exceeded");
+        };
+        assert_eq!(predictions.len(), 40);
+    }
+
+    #[test]
+    fn budget_replies_that_dont_add_up_are_refused() {
+        let images = vec![Image::BLANK; 40];
+        let input = budgeted_request(&images, FLAG_PREDICTIONS, 1.0, None, 15).unwrap();
+        let refused = |reply: fn(usize, u32) -> (usize, u32, usize)| {
+            read_budget_outcome(images.len(), FLAG_PREDICTIONS, 0, &input, |call| {
+                call.invoke_mocked(budgeted_ta(10, reply))
+            })
+            .unwrap_err()
+            .kind()
+        };
+        // Nothing completed, a partial batch not flagged as such, and more
+        // images than were sent
+        assert_eq!(refused(|_, _| (0, BUDGET_EXCEEDED, 0)), ErrorKind::Generic);
+        assert_eq!(refused(|n, _| (n, 0, n * 4)), ErrorKind::Generic);
+        assert_eq!(refused(|_, s| (41, s, 160)), ErrorKind::Generic);
+        // Bytes that don't match the images completed
+        assert_eq!(refused(|n, s| (n, s, n * 4 - 4)), ErrorKind::Generic);
+        assert_eq!(refused(|n, s| (n, s, 164)), ErrorKind::BadFormat);
+    }
+}
@@ -0,0 +1,207 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Training options beyond the plain loop of `train`: random shifts and
+// rotations of the input batch, and learning rate schedules.
+
+use burn::prelude::*;
+use clap::ValueEnum;
+use proto::{IMAGE_HEIGHT, IMAGE_SIZE, IMAGE_WIDTH};
+use rand::{rngs::StdRng, Rng};
+
+/// How the learning rate changes over a training run.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LrSchedule {
+    /// The same rate throughout
+    Constant,
+    /// Halved every `--lr-step-epochs` epochs
+    Step,
+    /// Cosine decay from the rate to zero over all batches
+    Cosine,
+}
+
+impl LrSchedule {
+    pub fn name(self) -> &'static str {
+        match self {
+            LrSchedule::Constant => "constant",
+            LrSchedule::Step => "step",
+            LrSchedule::Cosine => "cosine",
+        }
+    }
+
+    /// Rate for batch `step` (counted from 0 over the whole run) of
+    /// `total` batches, in `epoch` (from 0).
+    pub fn rate(
+        self,
+        base: f64,
+        epoch: usize,
+        step_epochs: usize,
+        step: usize,
+        total: usize,
+    ) -> f64 {
+        match self {
+            LrSchedule::Constant => base,
+            LrSchedule::Step => base * 0.5_f64.powi((epoch / step_epochs.max(1)) as i32),
+            LrSchedule::Cosine => {
+                let progress = step as f64 / total.max(1) as f64;
+                base * 0.5 * (1.0 + (std::f64::consts::PI * progress).cos())
+            }
+        }
+    }
+}
+
+/// Random affine distortion of training images: a whole-pixel shift of up
+/// to `max_shift` in each direction and a rotation of up to `max_rotation`
+/// degrees around the center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Augmentation {
+    pub max_shift: usize,
+    pub max_rotation: f32,
+}
+
+impl Augmentation {
+    pub fn is_enabled(&self) -> bool {
+        self.max_shift > 0 || self.max_rotation > 0.0
+    }
+
+    /// Distorts each row of `input` (one flattened image per row, after
+    /// normalization) with its own draw from `rng`. Pixels moved in from
+    /// outside the image take the row's smallest value, the background.
+    /// Nearest-neighbour sampling, done as one gather on the batch.
+    pub fn apply<B: Backend>(&self, input: Tensor<B, 2>, rng: &mut StdRng) -> Tensor<B, 2> {
+        if !self.is_enabled() {
+            return input;
+        }
+        let [rows, _] = input.dims();
+        let device = input.device();
+        let mut indices = Vec::with_capacity(rows * IMAGE_SIZE);
+        for _ in 0..rows {
+            let shift = self.max_shift as i32;
+            let dx = rng.random_range(-shift..=shift) as f32;
+            let dy = rng.random_range(-shift..=shift) as f32;
+            let angle = rng
+                .random_range(-self.max_rotation..=self.max_rotation)
+                .to_radians();
+            let (sin, cos) = angle.sin_cos();
+            let center_x = (IMAGE_WIDTH as f32 - 1.0) / 2.0;
+            let center_y = (IMAGE_HEIGHT as f32 - 1.0) / 2.0;
+            for y in 0..IMAGE_HEIGHT {
+                for x in 0..IMAGE_WIDTH {
+                    // Source pixel that lands on (x, y): the inverse rotation
+                    // of the unshifted position
+                    let u = x as f32 - center_x - dx;
+                    let v = y as f32 - center_y - dy;
+                    let src_x = (cos * u + sin * v + center_x).round();
+                    let src_y = (-sin * u + cos * v + center_y).round();
+                    let inside = (0.0..IMAGE_WIDTH as f32).contains(&src_x)
+                        && (0.0..IMAGE_HEIGHT as f32).contains(&src_y);
+                    // Index IMAGE_SIZE is the background column appended below
+                    let index = if inside {
+                        src_y as usize * IMAGE_WIDTH + src_x as usize
+                    } else {
+                        IMAGE_SIZE
+                    };
+                    indices.push((index as i64).elem::<B::IntElem>());
+                }
+            }
+        }
+        let indices =
+            Tensor::<B, 2, Int>::from_data(TensorData::new(indices, [rows, IMAGE_SIZE]), &device);
+        let background = input.clone().min_dim(1);
+        Tensor::cat(vec![input, background], 1).gather(1, indices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::backend::NdArray;
+    use rand::SeedableRng;
+
+    type B = NdArray;
+
+    // Two images: a bright square off center, and its inverse
+    fn batch() -> Tensor<B, 2> {
+        let mut pixels = vec![0.0; 2 * IMAGE_SIZE];
+        for (i, pixel) in pixels[..IMAGE_SIZE].iter_mut().enumerate() {
+            let (x, y) = (i % IMAGE_WIDTH, i / IMAGE_WIDTH);
+            if (4..12).contains(&x) && (6..20).contains(&y) {
+                *pixel = 1.0;
+            }
+        }
+        for i in 0..IMAGE_SIZE {
+            pixels[IMAGE_SIZE + i] = 1.0 - pixels[i];
+        }
+        Tensor::from_data(
+            TensorData::new(pixels, [2, IMAGE_SIZE]),
+            &Default::default(),
+        )
+    }
+
+    fn pixels(tensor: Tensor<B, 2>) -> Vec<f32> {
+        tensor.into_data().iter::<f32>().collect()
+    }
+
+    #[test]
+    fn augmentation_moves_pixels_reproducibly() {
+        let augmentation = Augmentation {
+            max_shift: 2,
+            max_rotation: 10.0,
+        };
+        let raw = pixels(batch());
+        let augment = |seed| pixels(augmentation.apply(batch(), &mut StdRng::seed_from_u64(seed)));
+        let augmented = augment(1);
+        assert_ne!(augmented, raw);
+        assert_eq!(augment(1), augmented);
+        // Nothing but pixels of the image, and the background, comes in
+        for (row, raw) in augmented.chunks(IMAGE_SIZE).zip(raw.chunks(IMAGE_SIZE)) {
+            assert!(row.iter().all(|p| raw.contains(p)));
+        }
+        // Shifts alone keep the square whole while it fits
+        let shifted = Augmentation {
+            max_shift: 2,
+            max_rotation: 0.0,
+        };
+        let shifted = pixels(shifted.apply(batch(), &mut StdRng::seed_from_u64(1)));
+        assert_eq!(
+            shifted[..IMAGE_SIZE].iter().sum::<f32>(),
+            raw[..IMAGE_SIZE].iter().sum::<f32>()
+        );
+
+        let disabled = Augmentation {
+            max_shift: 0,
+            max_rotation: 0.0,
+        };
+        assert!(!disabled.is_enabled());
+        assert_eq!(
+            pixels(disabled.apply(batch(), &mut StdRng::seed_from_u64(1))),
+            raw
+        );
+    }
+
+    #[test]
+    fn learning_rate_schedules() {
+        let rate = |schedule: LrSchedule, epoch, step| schedule.rate(0.1, epoch, 2, step, 100);
+        assert_eq!(rate(LrSchedule::Constant, 5, 90), 0.1);
+        assert_eq!(rate(LrSchedule::Step, 1, 40), 0.1);
+        assert_eq!(rate(LrSchedule::Step, 2, 50), 0.05);
+        assert_eq!(rate(LrSchedule::Step, 5, 90), 0.025);
+        assert_eq!(rate(LrSchedule::Cosine, 0, 0), 0.1);
+        assert!((rate(LrSchedule::Cosine, 0, 50) - 0.05).abs() < 1e-12);
+        assert!(rate(LrSchedule::Cosine, 0, 100).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,276 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Structured log of the steps taken against the TA, one JSON object per line,
+// for reproducing a provisioning run or attaching to a support request.
+// Secrets only ever reach it wrapped in `Redacted`, which serializes as a
+// fingerprint, so key bytes and model data can't end up in the file.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+use proto::inference::ModelStatus;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+// Set once from `--transcript` before the subcommand runs
+static TRANSCRIPT: OnceLock<(PathBuf, Mutex<File>)> = OnceLock::new();
+// Set once from `--trace-id` (or generated) before the subcommand runs
+static TRACE_ID: OnceLock<u64> = OnceLock::new();
+
+/// Sensitive bytes. Serialized and debug-printed as the first 8 bytes of
+/// their SHA-256 in hex, the same fingerprint the TA reports for keys.
+pub struct Redacted<T>(T);
+
+impl<T: AsRef<[u8]>> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(self.0.as_ref())[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+impl<T: AsRef<[u8]>> Serialize for Redacted<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.fingerprint())
+    }
+}
+
+impl<T: AsRef<[u8]>> std::fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Redacted({})", self.fingerprint())
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step<'a> {
+    Started {
+        command: &'a str,
+        version: &'a str,
+    },
+    /// A TA command; `reported` is a size the TA sent back, if any.
+    Invoke {
+        command: String,
+        command_id: u32,
+        input_bytes: usize,
+        reported: Option<usize>,
+        error: Option<String>,
+    },
+    Retry {
+        command: String,
+        reason: String,
+    },
+    /// The session died during `command` and was reopened; `recovered` is
+    /// false when that failed or the models the command needed were lost.
+    Reconnect {
+        command: String,
+        reason: String,
+        recovered: bool,
+    },
+    Key {
+        key: Redacted<&'a [u8]>,
+    },
+    ModelFile {
+        path: &'a str,
+        bytes: usize,
+        fingerprint: Redacted<&'a [u8]>,
+    },
+    /// A model file fetched by `provision --url`, `resumes` of them with a
+    /// range request after a dropped connection.
+    #[cfg(feature = "net")]
+    Download {
+        url: &'a str,
+        bytes: u64,
+        resumes: usize,
+        verified: bool,
+    },
+    Status {
+        slot: u32,
+        status: &'a ModelStatus,
+    },
+    Finished {
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trace_id: Option<String>,
+    #[serde(flatten)]
+    step: &'a Step<'a>,
+}
+
+/// Appends every following `record` to the file at `path`.
+pub fn open(path: &Path) -> anyhow::Result<()> {
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| anyhow::anyhow!("cannot open transcript {}: {}", path.display(), err))?;
+    TRANSCRIPT
+        .set((path.to_path_buf(), Mutex::new(file)))
+        .map_err(|_| anyhow::anyhow!("transcript already open"))
+}
+
+/// Tags every following transcript line, and the TA's log lines once a
+/// connector passes it on, with `id`; 0 means no id.
+pub fn set_trace_id(id: u64) -> anyhow::Result<()> {
+    TRACE_ID
+        .set(id)
+        .map_err(|_| anyhow::anyhow!("trace id already set"))
+}
+
+/// Trace id of this invocation, `None` when it runs without one.
+pub fn trace_id() -> Option<u64> {
+    TRACE_ID.get().copied().filter(|&id| id != 0)
+}
+
+/// A trace id as the TA prints it: 16 hex digits.
+pub fn format_trace_id(id: u64) -> String {
+    format!("{:016x}", id)
+}
+
+/// Parses `--trace-id`: up to 16 hex digits, optionally prefixed with 0x.
+pub fn parse_trace_id(s: &str) -> Result<u64, String> {
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    u64::from_str_radix(digits, 16).map_err(|err| format!("invalid trace id {:?}: {}", s, err))
+}
+
+/// Path of the open transcript.
+pub fn path() -> Option<&'static Path> {
+    TRANSCRIPT.get().map(|(path, _)| path.as_path())
+}
+
+/// Writes `step` to the transcript, if one is open. A failed write is
+/// reported but doesn't fail the command it describes.
+pub fn record(step: Step) {
+    let Some((_, file)) = TRANSCRIPT.get() else {
+        return;
+    };
+    let time = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    let line = Line {
+        time,
+        trace_id: trace_id().map(format_trace_id),
+        step: &step,
+    };
+    let mut line = match serde_json::to_vec(&line) {
+        Ok(line) => line,
+        Err(err) => return println!("warning: cannot serialize transcript step: {}", err),
+    };
+    line.push(b'\n');
+    let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+    if let Err(err) = file.write_all(&line) {
+        println!("warning: cannot write transcript: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [
+        0x3c, 0x91, 0x0e, 0xa7, 0x55, 0x21, 0xd4, 0x6b, 0x88, 0x1f, 0xc2, 0x7a, 0x09, 0xe3, 0x4d,
+        0xb6, 0x70, 0x2e, 0x95, 0xfa, 0x13, 0x68, 0xcb, 0x44, 0xaf, 0x06, 0x5d, 0x92, 0xe7, 0x31,
+        0xbc, 0x80,
+    ];
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // The only test opening the process-wide transcript and setting the
+    // trace id
+    #[test]
+    fn keys_and_models_only_appear_as_fingerprints() {
+        let path = std::env::temp_dir().join(format!("transcript-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        open(&path).unwrap();
+        assert!(open(&path).is_err());
+        set_trace_id(0x00c0_ffee_0000_0042).unwrap();
+        assert!(set_trace_id(1).is_err());
+        let model = b"plaintext weights of the model, never to be logged".repeat(4);
+        record(Step::Started {
+            command: "store-key",
+            version: "test",
+        });
+        record(Step::Key {
+            key: Redacted::new(&KEY),
+        });
+        record(Step::ModelFile {
+            path: "model.json",
+            bytes: model.len(),
+            fingerprint: Redacted::new(&model),
+        });
+        record(Step::Finished { error: None });
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let lines: Vec<serde_json::Value> = transcript
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            // Other tests may be running TA commands meanwhile
+            .filter(|line: &serde_json::Value| {
+                ["started", "key", "model_file", "finished"]
+                    .contains(&line["step"].as_str().unwrap())
+            })
+            .collect();
+        assert_eq!(lines.len(), 4);
+        for line in &lines {
+            assert_eq!(line["trace_id"], "00c0ffee00000042");
+        }
+        assert_eq!(lines[1]["key"], Redacted::new(&KEY).fingerprint());
+        assert_eq!(lines[2]["fingerprint"], Redacted::new(&model).fingerprint());
+        assert_eq!(lines[2]["bytes"], model.len());
+
+        let lower = transcript.to_lowercase();
+        // Nor any 8 consecutive key bytes, nor the model's text or bytes
+        for window in KEY.windows(8) {
+            assert!(!lower.contains(&hex(window)));
+        }
+        assert!(!transcript.contains("plaintext weights"));
+        assert!(!lower.contains(&hex(&model[..8])));
+        assert!(!format!("{:?}", Redacted::new(&KEY)).contains(&hex(&KEY[..8])));
+    }
+
+    #[test]
+    fn trace_ids_parse_as_printed() {
+        for id in [1, 0x42, 0x00c0_ffee_0000_0042, u64::MAX] {
+            let printed = format_trace_id(id);
+            assert_eq!(printed.len(), 16);
+            assert_eq!(parse_trace_id(&printed), Ok(id));
+            assert_eq!(parse_trace_id(&format!("0x{:x}", id)), Ok(id));
+        }
+        assert_eq!(parse_trace_id("0"), Ok(0));
+        assert!(parse_trace_id("").is_err());
+        assert!(parse_trace_id("12345678901234567").is_err());
+        assert!(parse_trace_id("trace").is_err());
+    }
+}
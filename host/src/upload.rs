@@ -0,0 +1,512 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Streaming of encrypted payloads to the TA. Slow transports (e.g. a USB
+// network bridge to the board) can overrun tee-supplicant when chunks are
+// pushed back to back, which shows up as sporadic Communication or Busy
+// errors. The pusher then halves its chunk size and retries the same offset;
+// those errors are raised before the TA sees the chunk, and the TA only
+// appends what it receives, so the payload reassembles unchanged. After a
+// run of successes the chunk size doubles again up to the negotiated one.
+//
+// The negotiated size is `--chunk-size`, or the TA's preferred size when it
+// reports one (`CAP_CHUNK_SIZES`) and `DEFAULT_CHUNK_SIZE` otherwise, capped
+// at the largest chunk the TA accepts.
+
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use optee_teec::ErrorKind;
+
+use crate::progress::{self, Progress};
+use crate::tee::{ChunkSizes, InferenceTa};
+use crate::transcript::{self, Step};
+
+/// Chunk size used with TAs that don't report one.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+// AES block size; keeps every chunk boundary on a block boundary
+const CHUNK_ALIGN: usize = 16;
+const MIN_CHUNK_SIZE: usize = 1024;
+// Successful pushes before the chunk size is doubled again
+const RESTORE_AFTER: usize = 4;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug)]
+pub struct UploadOptions {
+    /// `None` leaves the choice to the TA.
+    pub chunk_size: Option<usize>,
+    /// Pause after every chunk.
+    pub throttle: Duration,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: None,
+            throttle: Duration::ZERO,
+        }
+    }
+}
+
+// Set once from `--chunk-size` / `--throttle-ms` before the subcommand runs
+static OPTIONS: OnceLock<UploadOptions> = OnceLock::new();
+
+pub fn set_options(options: UploadOptions) -> anyhow::Result<()> {
+    OPTIONS
+        .set(options)
+        .map_err(|_| anyhow::anyhow!("upload options already set"))
+}
+
+pub fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    let size: usize = s
+        .parse()
+        .map_err(|_| format!("invalid chunk size: {}", s))?;
+    if size < MIN_CHUNK_SIZE || size % CHUNK_ALIGN != 0 {
+        return Err(format!(
+            "chunk size must be a multiple of {} and at least {}",
+            CHUNK_ALIGN, MIN_CHUNK_SIZE
+        ));
+    }
+    Ok(size)
+}
+
+// Push chunk size for a host configured with `configured` and a TA that
+// reported `advertised`: the configured size, else the TA's preferred one,
+// never more than the TA accepts
+fn negotiate_chunk_size(configured: Option<usize>, advertised: Option<ChunkSizes>) -> usize {
+    let wanted = configured
+        .or(advertised.map(|sizes| sizes.preferred))
+        .unwrap_or(DEFAULT_CHUNK_SIZE);
+    let size = match advertised {
+        Some(sizes) => wanted.min(sizes.max),
+        None => wanted,
+    };
+    (size / CHUNK_ALIGN * CHUNK_ALIGN).max(CHUNK_ALIGN)
+}
+
+/// Pushes `payload` to the model load started with `begin_model_load`.
+pub fn push_payload(caller: &mut dyn InferenceTa, payload: &[u8]) -> anyhow::Result<()> {
+    Pusher::new(caller, Some(payload.len())).push(payload, true)?;
+    Ok(())
+}
+
+/// Pushes a payload that arrives in pieces, e.g. while it is downloaded,
+/// keeping chunk boundaries on `CHUNK_ALIGN` across pieces.
+pub struct Pusher<'a> {
+    caller: &'a mut dyn InferenceTa,
+    options: UploadOptions,
+    // Negotiated size, restored after a run of successes
+    max_chunk_size: usize,
+    chunk_size: usize,
+    successes: usize,
+    sent: usize,
+    // Only with a known total; a streamed payload reports its download
+    progress: Option<Box<dyn Progress>>,
+}
+
+impl<'a> Pusher<'a> {
+    pub fn new(caller: &'a mut dyn InferenceTa, total: Option<usize>) -> Self {
+        let options = OPTIONS.get().copied().unwrap_or_default();
+        let advertised = caller.chunk_sizes();
+        let chunk_size = negotiate_chunk_size(options.chunk_size, advertised);
+        if let (Some(configured), Some(sizes)) = (options.chunk_size, advertised) {
+            if configured > sizes.max {
+                println!(
+                    "warning: --chunk-size {} exceeds the TA's maximum, pushing {} byte chunks",
+                    configured, chunk_size
+                );
+            }
+        }
+        Self {
+            caller,
+            options,
+            max_chunk_size: chunk_size,
+            chunk_size,
+            successes: 0,
+            sent: 0,
+            progress: total.map(|total| {
+                progress::start("Pushing encrypted model", "bytes", Some(total as u64))
+            }),
+        }
+    }
+
+    /// Pushes the whole chunks at the start of `data`, or all of it when
+    /// `last`, and returns the number of bytes pushed; the caller keeps the
+    /// rest for the next call.
+    pub fn push(&mut self, data: &[u8], last: bool) -> anyhow::Result<usize> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let end = data.len().min(offset + self.chunk_size);
+            if !last && end - offset < self.chunk_size {
+                break;
+            }
+            match self.caller.push_encrypted_chunk(&data[offset..end]) {
+                Ok(()) => {
+                    if let Some(progress) = &mut self.progress {
+                        progress.tick((end - offset) as u64);
+                    }
+                    self.sent += end - offset;
+                    offset = end;
+                    self.successes += 1;
+                    if self.chunk_size < self.max_chunk_size && self.successes >= RESTORE_AFTER {
+                        self.chunk_size = self.max_chunk_size.min(self.chunk_size * 2);
+                        self.successes = 0;
+                    }
+                    if !self.options.throttle.is_zero() && (!last || offset < data.len()) {
+                        thread::sleep(self.options.throttle);
+                    }
+                }
+                Err(err)
+                    if matches!(err.kind(), ErrorKind::Communication | ErrorKind::Busy)
+                        && self.chunk_size > MIN_CHUNK_SIZE =>
+                {
+                    self.chunk_size =
+                        (self.chunk_size / 2 / CHUNK_ALIGN * CHUNK_ALIGN).max(MIN_CHUNK_SIZE);
+                    self.successes = 0;
+                    let message = format!(
+                        "Push at offset {} failed ({}), retrying with {} byte chunks",
+                        self.sent, err, self.chunk_size
+                    );
+                    match &mut self.progress {
+                        Some(progress) => progress.message(&message),
+                        None => println!("{}", message),
+                    }
+                    transcript::record(Step::Retry {
+                        command: "PushEncryptedChunk".to_string(),
+                        reason: format!(
+                            "{} at offset {}, chunk size {}",
+                            err, self.sent, self.chunk_size
+                        ),
+                    });
+                    thread::sleep(RETRY_DELAY);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if last {
+            if let Some(progress) = &mut self.progress {
+                progress.finish();
+            }
+        }
+        Ok(offset)
+    }
+}
+
+/// A TA for the tests that only takes model chunks: it fails every chunk
+/// above `threshold` bytes with `error`, as an overrun supplicant does, takes
+/// `latency` per push and logs each one. It reports `sizes` as its chunk
+/// sizes, `DEFAULT_CHUNK_SIZE` for both unless a test changes them.
+#[cfg(test)]
+pub struct MockTransport {
+    pub threshold: usize,
+    pub error: ErrorKind,
+    pub latency: Duration,
+    pub sizes: Option<ChunkSizes>,
+    pub received: Vec<u8>,
+    /// Size of each push and whether it went through.
+    pub pushes: Vec<(usize, bool)>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn new(threshold: usize, error: ErrorKind) -> Self {
+        Self {
+            threshold,
+            error,
+            latency: Duration::ZERO,
+            sizes: Some(ChunkSizes {
+                preferred: DEFAULT_CHUNK_SIZE,
+                max: DEFAULT_CHUNK_SIZE,
+            }),
+            received: Vec::new(),
+            pushes: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl InferenceTa for MockTransport {
+    fn supports(&self, _capability: u32) -> bool {
+        true
+    }
+
+    fn chunk_sizes(&self) -> Option<ChunkSizes> {
+        self.sizes
+    }
+
+    fn begin_model_load(&mut self, _slot: u32, _size: usize) -> optee_teec::Result<()> {
+        unreachable!()
+    }
+
+    fn push_encrypted_chunk(&mut self, chunk: &[u8]) -> optee_teec::Result<()> {
+        thread::sleep(self.latency);
+        let ok = chunk.len() <= self.threshold;
+        self.pushes.push((chunk.len(), ok));
+        if !ok {
+            return Err(self.error.into());
+        }
+        self.received.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn finalize_model_load(&mut self, _signature: Option<&[u8]>) -> optee_teec::Result<()> {
+        unreachable!()
+    }
+
+    fn storage_preflight(
+        &mut self,
+        _size: usize,
+    ) -> optee_teec::Result<crate::tee::StoragePreflight> {
+        unreachable!()
+    }
+
+    fn model_status(&mut self, _slot: u32) -> optee_teec::Result<proto::inference::ModelStatus> {
+        unreachable!()
+    }
+
+    fn class_labels(&mut self, _slot: u32) -> optee_teec::Result<Vec<String>> {
+        unreachable!()
+    }
+
+    fn set_input_flags(&mut self, _flags: u32) -> optee_teec::Result<()> {
+        unreachable!()
+    }
+
+    fn set_reject_threshold(&mut self, _threshold: Option<f32>) -> optee_teec::Result<()> {
+        unreachable!()
+    }
+
+    fn infer_batch(&mut self, _images: &[proto::Image], _slot: u32) -> optee_teec::Result<Vec<u8>> {
+        unreachable!()
+    }
+
+    fn infer_predictions(
+        &mut self,
+        _images: &[proto::Image],
+        _temperature: f32,
+        _slot: u32,
+    ) -> optee_teec::Result<Vec<proto::inference::Prediction>> {
+        unreachable!()
+    }
+
+    fn infer_batch_with_probabilities(
+        &mut self,
+        _images: &[proto::Image],
+        _temperature: f32,
+        _slot: u32,
+        _num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        unreachable!()
+    }
+
+    fn infer_correlated(
+        &mut self,
+        _images: &[proto::Image],
+        _temperature: f32,
+        _slot: u32,
+    ) -> optee_teec::Result<Vec<proto::inference::Prediction>> {
+        unreachable!()
+    }
+
+    fn infer_ensemble(
+        &mut self,
+        _images: &[proto::Image],
+        _slot_mask: u32,
+        _temperature: f32,
+        _num_classes: usize,
+    ) -> optee_teec::Result<(Vec<u8>, Vec<f32>)> {
+        unreachable!()
+    }
+
+    fn infer_recorded(
+        &mut self,
+        _images: &[proto::Image],
+        _temperature: f32,
+        _slot: u32,
+        _num_classes: usize,
+    ) -> optee_teec::Result<crate::tee::RecordedBatch> {
+        unreachable!()
+    }
+
+    fn infer_within_budget(
+        &mut self,
+        _images: &[proto::Image],
+        _temperature: f32,
+        _slot: u32,
+        _budget: Duration,
+    ) -> optee_teec::Result<crate::tee::BudgetOutcome> {
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    #[test]
+    fn overruns_halve_the_chunk_size_and_successes_restore_it() {
+        let payload = payload(400_000 + 48);
+        let mut transport = MockTransport::new(20_000, ErrorKind::Communication);
+        push_payload(&mut transport, &payload).unwrap();
+        assert_eq!(transport.received, payload);
+
+        let pushes = &transport.pushes;
+        // 64 KiB and 32 KiB fail, 16 KiB goes through
+        assert_eq!(pushes[..3], [(65536, false), (32768, false), (16384, true)]);
+        // Every chunk but the last on a block boundary
+        for &(size, _) in &pushes[..pushes.len() - 1] {
+            assert_eq!(size % CHUNK_ALIGN, 0);
+        }
+        // After RESTORE_AFTER successes the size doubles, and fails again
+        assert!(pushes[3..3 + RESTORE_AFTER - 1]
+            .iter()
+            .all(|&p| p == (16384, true)));
+        assert_eq!(pushes[2 + RESTORE_AFTER], (32768, false));
+    }
+
+    #[test]
+    fn busy_transports_are_retried_down_to_the_smallest_chunk() {
+        let payload = payload(8192);
+        let mut transport = MockTransport::new(MIN_CHUNK_SIZE, ErrorKind::Busy);
+        push_payload(&mut transport, &payload).unwrap();
+        assert_eq!(transport.received, payload);
+        assert!(transport
+            .pushes
+            .iter()
+            .all(|&(size, ok)| ok == (size <= MIN_CHUNK_SIZE)));
+
+        // Failing even the smallest chunk gives up
+        let mut transport = MockTransport::new(MIN_CHUNK_SIZE - 1, ErrorKind::Communication);
+        assert!(push_payload(&mut transport, &payload).is_err());
+        assert_eq!(transport.pushes.last(), Some(&(MIN_CHUNK_SIZE, false)));
+        assert!(transport.received.is_empty());
+    }
+
+    #[test]
+    fn other_errors_are_not_retried() {
+        // A TA that died mid-stream lost the load; provisioning steps are
+        // never repeated on a reopened session
+        for error in [ErrorKind::BadParameters, ErrorKind::TargetDead] {
+            let mut transport = MockTransport::new(0, error);
+            assert!(push_payload(&mut transport, &payload(100_000)).is_err());
+            assert_eq!(transport.pushes, [(65536, false)]);
+        }
+    }
+
+    #[test]
+    fn streamed_pieces_keep_whole_chunks() {
+        let payload = payload(150_000);
+        let mut transport = MockTransport::new(usize::MAX, ErrorKind::Communication);
+        let mut pusher = Pusher::new(&mut transport, None);
+        let mut pending = Vec::new();
+        for piece in payload.chunks(50_000) {
+            pending.extend_from_slice(piece);
+            let pushed = pusher.push(&pending, false).unwrap();
+            pending.drain(..pushed);
+        }
+        pusher.push(&pending, true).unwrap();
+        assert_eq!(transport.received, payload);
+        let sizes: Vec<usize> = transport.pushes.iter().map(|&(size, _)| size).collect();
+        assert_eq!(sizes, [65536, 65536, 150_000 - 2 * 65536]);
+    }
+
+    // What a pusher reported: bytes ticked, messages, whether it finished
+    #[derive(Clone, Default)]
+    struct Reported(std::rc::Rc<std::cell::RefCell<(u64, usize, bool)>>);
+
+    impl Progress for Reported {
+        fn tick(&mut self, n: u64) {
+            self.0.borrow_mut().0 += n;
+        }
+
+        fn message(&mut self, _message: &str) {
+            self.0.borrow_mut().1 += 1;
+        }
+
+        fn finish(&mut self) {
+            self.0.borrow_mut().2 = true;
+        }
+    }
+
+    #[test]
+    fn progress_counts_the_bytes_that_went_through() {
+        let payload = payload(200_000);
+        let mut transport = MockTransport::new(20_000, ErrorKind::Communication);
+        let reported = Reported::default();
+        let mut pusher = Pusher::new(&mut transport, Some(payload.len()));
+        pusher.progress = Some(Box::new(reported.clone()));
+        assert_eq!(pusher.push(&payload, true).unwrap(), payload.len());
+        let failures = transport.pushes.iter().filter(|&&(_, ok)| !ok).count();
+        assert!(failures > 0);
+        // Failed pushes are messages, not progress
+        assert_eq!(*reported.0.borrow(), (payload.len() as u64, failures, true));
+    }
+
+    #[test]
+    fn chunk_sizes() {
+        assert_eq!(parse_chunk_size("4096"), Ok(4096));
+        for size in ["1000", "4100", "512", "x"] {
+            assert!(parse_chunk_size(size).is_err(), "{}", size);
+        }
+        let sizes = ChunkSizes {
+            preferred: 8192,
+            max: 32768,
+        };
+        assert_eq!(negotiate_chunk_size(None, None), DEFAULT_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(None, Some(sizes)), 8192);
+        assert_eq!(negotiate_chunk_size(Some(16384), Some(sizes)), 16384);
+        assert_eq!(negotiate_chunk_size(Some(65536), Some(sizes)), 32768);
+        assert_eq!(negotiate_chunk_size(Some(4096), None), 4096);
+    }
+
+    #[test]
+    fn pushes_use_the_size_the_ta_negotiated() {
+        let payload: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        let push = |sizes| {
+            let mut transport = MockTransport::new(usize::MAX, ErrorKind::Busy);
+            transport.sizes = sizes;
+            push_payload(&mut transport, &payload).unwrap();
+            assert_eq!(transport.received, payload);
+            transport.pushes
+        };
+        // A TA asking for less than the host default, and taking no more
+        let small = ChunkSizes {
+            preferred: 4096,
+            max: 8192,
+        };
+        let pushes = push(Some(small));
+        assert!(pushes.iter().all(|&(size, _)| size <= 4096));
+        assert_eq!(pushes[0].0, 4096);
+        // One asking for more
+        let large = ChunkSizes {
+            preferred: 4 * DEFAULT_CHUNK_SIZE,
+            max: 16 * DEFAULT_CHUNK_SIZE,
+        };
+        let pushes = push(Some(large));
+        assert_eq!(pushes.len(), 2);
+        assert_eq!(pushes[0].0, 4 * DEFAULT_CHUNK_SIZE);
+        // And one that reports nothing
+        let pushes = push(None);
+        assert_eq!(pushes[0].0, DEFAULT_CHUNK_SIZE);
+        assert_eq!(pushes.len(), payload.len().div_ceil(DEFAULT_CHUNK_SIZE));
+    }
+}
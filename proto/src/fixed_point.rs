@@ -0,0 +1,204 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Fixed-point encodings of probabilities.
+//
+// A row of softmax probabilities travels as one u16 per class in units of
+// `1 / PROB_SCALE`. Rounding each value on its own can leave a row summing
+// to a few units more or less than `PROB_SCALE`; `encode_probs` instead
+// hands out the units by largest remainder, so every encoded row sums to
+// exactly `PROB_SCALE` while each value stays within one unit of its share.
+
+use alloc::vec::Vec;
+
+/// Units of a probability of 1 in `encode_probs` rows.
+pub const PROB_SCALE: u16 = u16::MAX;
+
+/// `value` (clamped to [0, 1]) in units of `1 / scale`, rounded half up.
+pub fn to_fixed(value: f32, scale: u32) -> u32 {
+    (value.clamp(0.0, 1.0) * scale as f32 + 0.5) as u32
+}
+
+/// Encodes a row of probabilities in units of `1 / PROB_SCALE`.
+///
+/// Negative and NaN values count as 0 and the rest are taken relative to
+/// their sum, so a row that sums to 1 only up to float error still encodes.
+/// Guarantees, for any non-empty row:
+///
+/// - the encoded values sum to exactly `PROB_SCALE`;
+/// - each value is the floor or the ceiling of its exact share
+///   `p / sum * PROB_SCALE`, i.e. decodes to within `1 / PROB_SCALE` of the
+///   normalized probability (up to the f32 rounding of the share);
+/// - a one-hot row encodes as `PROB_SCALE` and zeros, and a row with nothing
+///   positive in it as the uniform distribution.
+///
+/// Units left over after flooring go to the largest remainders, ties to the
+/// lower index. An empty row encodes as an empty one.
+pub fn encode_probs(probs: &[f32]) -> Vec<u16> {
+    if probs.is_empty() {
+        return Vec::new();
+    }
+    let weights: Vec<f64> = probs
+        .iter()
+        .map(|&p| if p > 0.0 { p as f64 } else { 0.0 })
+        .collect();
+    let total: f64 = weights.iter().sum();
+    let shares: Vec<f64> = if total > 0.0 && total.is_finite() {
+        weights
+            .iter()
+            .map(|w| w / total * PROB_SCALE as f64)
+            .collect()
+    } else {
+        let share = PROB_SCALE as f64 / probs.len() as f64;
+        weights.iter().map(|_| share).collect()
+    };
+    let mut encoded: Vec<u16> = shares.iter().map(|&share| share as u16).collect();
+    let assigned: u32 = encoded.iter().map(|&v| v as u32).sum();
+    let mut order: Vec<usize> = (0..shares.len()).collect();
+    // Stable, so equal remainders keep index order
+    order.sort_by(|&a, &b| {
+        let remainder = |i: usize| shares[i] - encoded[i] as f64;
+        remainder(b).total_cmp(&remainder(a))
+    });
+    let missing = (PROB_SCALE as u32).saturating_sub(assigned) as usize;
+    for &i in order.iter().take(missing) {
+        encoded[i] += 1;
+    }
+    encoded
+}
+
+/// Probabilities of a row encoded by `encode_probs`.
+pub fn decode_probs(encoded: &[u16]) -> Vec<f32> {
+    encoded
+        .iter()
+        .map(|&v| v as f32 / PROB_SCALE as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use proptest::prelude::*;
+
+    // Rows mixing ordinary probabilities with zeros, negatives and any f32
+    // at all (NaN, infinities, subnormals)
+    fn row() -> impl Strategy<Value = Vec<f32>> {
+        let value = prop_oneof![
+            4 => 0.0_f32..=1.0,
+            1 => Just(0.0_f32),
+            1 => -1.0_f32..0.0,
+            1 => any::<f32>(),
+        ];
+        proptest::collection::vec(value, 1..=64)
+    }
+
+    // The exact shares `encode_probs` rounds, for rows with a positive and
+    // finite total
+    fn shares(probs: &[f32]) -> Option<Vec<f64>> {
+        let weights: Vec<f64> = probs
+            .iter()
+            .map(|&p| if p > 0.0 { p as f64 } else { 0.0 })
+            .collect();
+        let total: f64 = weights.iter().sum();
+        (total > 0.0 && total.is_finite()).then(|| {
+            weights
+                .iter()
+                .map(|w| w / total * PROB_SCALE as f64)
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn rows_sum_to_the_scale(probs in row()) {
+            let encoded = encode_probs(&probs);
+            prop_assert_eq!(encoded.len(), probs.len());
+            let sum: u32 = encoded.iter().map(|&v| v as u32).sum();
+            prop_assert_eq!(sum, PROB_SCALE as u32);
+        }
+
+        #[test]
+        fn values_are_floor_or_ceiling_of_their_share(probs in row()) {
+            let encoded = encode_probs(&probs);
+            let Some(shares) = shares(&probs) else {
+                return Ok(());
+            };
+            for (&value, &share) in encoded.iter().zip(&shares) {
+                let value = value as f64;
+                prop_assert!(value == share.floor() || value == share.ceil(), "{value} for {share}");
+            }
+        }
+
+        #[test]
+        fn decoded_rows_stay_within_one_unit(probs in row()) {
+            let Some(shares) = shares(&probs) else {
+                return Ok(());
+            };
+            let decoded = decode_probs(&encode_probs(&probs));
+            for (&p, &share) in decoded.iter().zip(&shares) {
+                let exact = share / PROB_SCALE as f64;
+                prop_assert!((p as f64 - exact).abs() <= 1.0 / PROB_SCALE as f64 + 1e-6);
+            }
+        }
+
+        #[test]
+        fn one_hot_rows_encode_exactly(len in 1_usize..=64, hot in any::<proptest::sample::Index>(), p in 1e-30_f32..=1.0) {
+            let hot = hot.index(len);
+            let mut probs = vec![0.0; len];
+            probs[hot] = p;
+            let encoded = encode_probs(&probs);
+            for (i, &value) in encoded.iter().enumerate() {
+                prop_assert_eq!(value, if i == hot { PROB_SCALE } else { 0 });
+            }
+        }
+
+        #[test]
+        fn rows_without_mass_encode_uniformly(probs in proptest::collection::vec(-1.0_f32..=0.0, 1..=64)) {
+            let encoded = encode_probs(&probs);
+            let floor = PROB_SCALE / probs.len() as u16;
+            prop_assert!(encoded.iter().all(|&v| v == floor || v == floor + 1));
+            // Leftover units go to the lowest indices
+            prop_assert!(encoded.windows(2).all(|pair| pair[0] >= pair[1]));
+        }
+
+        #[test]
+        fn to_fixed_clamps_and_keeps_order(a in any::<f32>(), b in any::<f32>(), scale in 1_u32..=1 << 16) {
+            let (low, high) = if a <= b { (a, b) } else { (b, a) };
+            if !a.is_nan() && !b.is_nan() {
+                prop_assert!(to_fixed(low, scale) <= to_fixed(high, scale));
+            }
+            prop_assert!(to_fixed(a, scale) <= scale);
+        }
+    }
+
+    #[test]
+    fn empty_rows_stay_empty() {
+        assert!(encode_probs(&[]).is_empty());
+        assert!(decode_probs(&[]).is_empty());
+    }
+
+    #[test]
+    fn to_fixed_rounds_half_up() {
+        assert_eq!(to_fixed(0.0, 1000), 0);
+        assert_eq!(to_fixed(1.0, 1000), 1000);
+        assert_eq!(to_fixed(0.0005, 1000), 1);
+        assert_eq!(to_fixed(0.0004, 1000), 0);
+        assert_eq!(to_fixed(-3.0, 1000), 0);
+        assert_eq!(to_fixed(7.0, 1000), 1000);
+    }
+}
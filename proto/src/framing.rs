@@ -0,0 +1,203 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Plaintext framing of encrypted models, shared by the host encryptor and
+// the TA: `payload length (u32 LE) | payload | zero padding` up to a whole
+// number of AES blocks, since CBC runs without padding of its own.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::key_manager::AES_BLOCK_SIZE;
+
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// Framed size of a `len` byte payload, `None` when the length doesn't fit
+/// the prefix.
+pub fn framed_len(len: usize) -> Option<usize> {
+    u32::try_from(len).ok()?;
+    len.checked_add(LENGTH_PREFIX_SIZE)?
+        .checked_next_multiple_of(AES_BLOCK_SIZE)
+}
+
+/// Frames `payload` for encryption, `None` when it's too large.
+pub fn frame(payload: &[u8]) -> Option<Vec<u8>> {
+    let framed_len = framed_len(payload.len())?;
+    let mut framed = Vec::with_capacity(framed_len);
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed.resize(framed_len, 0);
+    Some(framed)
+}
+
+/// The frame of `payload` in pieces of `chunk_size` bytes (the last one
+/// shorter), for encrypting it as it is sent instead of framing a copy of
+/// the whole payload first. Concatenated they equal `frame(payload)`.
+/// `None` when the payload is too large or `chunk_size` isn't a non-zero
+/// multiple of the AES block size.
+pub fn frame_chunks(payload: &[u8], chunk_size: usize) -> Option<FrameChunks<'_>> {
+    if chunk_size == 0 || chunk_size % AES_BLOCK_SIZE != 0 {
+        return None;
+    }
+    Some(FrameChunks {
+        payload,
+        framed_len: framed_len(payload.len())?,
+        chunk_size,
+        offset: 0,
+    })
+}
+
+pub struct FrameChunks<'a> {
+    payload: &'a [u8],
+    framed_len: usize,
+    chunk_size: usize,
+    // Offset of the next piece in the frame
+    offset: usize,
+}
+
+impl FrameChunks<'_> {
+    pub fn framed_len(&self) -> usize {
+        self.framed_len
+    }
+}
+
+impl Iterator for FrameChunks<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.offset >= self.framed_len {
+            return None;
+        }
+        let end = self.framed_len.min(self.offset + self.chunk_size);
+        let mut chunk = Vec::with_capacity(end - self.offset);
+        if self.offset < LENGTH_PREFIX_SIZE {
+            let prefix = (self.payload.len() as u32).to_le_bytes();
+            chunk.extend_from_slice(&prefix[self.offset..end.min(LENGTH_PREFIX_SIZE)]);
+        }
+        // Frame offsets clamped to the payload, then made relative to it
+        let payload_end = LENGTH_PREFIX_SIZE + self.payload.len();
+        let start = self.offset.clamp(LENGTH_PREFIX_SIZE, payload_end) - LENGTH_PREFIX_SIZE;
+        let stop = end.clamp(LENGTH_PREFIX_SIZE, payload_end) - LENGTH_PREFIX_SIZE;
+        chunk.extend_from_slice(&self.payload[start..stop]);
+        chunk.resize(end - self.offset, 0);
+        self.offset = end;
+        Some(chunk)
+    }
+}
+
+/// Where the payload sits in a decrypted frame, `None` when the frame is
+/// shorter than the prefix or the declared length runs past its end.
+pub fn payload_range(framed: &[u8]) -> Option<Range<usize>> {
+    let prefix: [u8; LENGTH_PREFIX_SIZE] = framed.get(..LENGTH_PREFIX_SIZE)?.try_into().ok()?;
+    let end = LENGTH_PREFIX_SIZE.checked_add(u32::from_le_bytes(prefix) as usize)?;
+    (end <= framed.len()).then_some(LENGTH_PREFIX_SIZE..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn round_trip(payload in proptest::collection::vec(any::<u8>(), 0..=4096)) {
+            let framed = frame(&payload).unwrap();
+            prop_assert_eq!(framed.len() % AES_BLOCK_SIZE, 0);
+            prop_assert_eq!(Some(framed.len()), framed_len(payload.len()));
+            prop_assert!(framed.len() - payload.len() - LENGTH_PREFIX_SIZE < AES_BLOCK_SIZE);
+            let range = payload_range(&framed).unwrap();
+            prop_assert_eq!(&framed[range], payload.as_slice());
+        }
+
+        #[test]
+        fn chunks_match_frame(
+            payload in proptest::collection::vec(any::<u8>(), 0..=4096),
+            blocks in 1usize..=8,
+        ) {
+            let chunks = frame_chunks(&payload, blocks * AES_BLOCK_SIZE).unwrap();
+            prop_assert_eq!(Some(chunks.framed_len()), framed_len(payload.len()));
+            prop_assert_eq!(chunks.flatten().collect::<Vec<u8>>(), frame(&payload).unwrap());
+        }
+
+        #[test]
+        fn truncated_frames_are_refused(
+            payload in proptest::collection::vec(any::<u8>(), 1..=4096),
+            keep in any::<prop::sample::Index>(),
+        ) {
+            // Anything cut short of the end of the payload
+            let framed = frame(&payload).unwrap();
+            let keep = keep.index(LENGTH_PREFIX_SIZE + payload.len());
+            prop_assert_eq!(payload_range(&framed[..keep]), None);
+        }
+
+        #[test]
+        fn corrupted_frames_stay_in_bounds(
+            payload in proptest::collection::vec(any::<u8>(), 0..=4096),
+            index in any::<prop::sample::Index>(),
+            flip in 1u8..,
+        ) {
+            // A flipped prefix or padding byte either still parses to a
+            // range inside the frame, or the frame is refused
+            let mut framed = frame(&payload).unwrap();
+            let at = index.index(framed.len());
+            framed[at] ^= flip;
+            if let Some(range) = payload_range(&framed) {
+                prop_assert!(range.end <= framed.len());
+                prop_assert_eq!(range.start, LENGTH_PREFIX_SIZE);
+            }
+        }
+
+        #[test]
+        fn oversized_prefixes_are_refused(len in 0usize..4096, declared in any::<u32>()) {
+            let mut framed = vec![0; LENGTH_PREFIX_SIZE + len];
+            framed[..LENGTH_PREFIX_SIZE].copy_from_slice(&declared.to_le_bytes());
+            let range = payload_range(&framed);
+            prop_assert_eq!(range.is_some(), declared as usize <= len);
+        }
+
+        #[test]
+        fn unaligned_chunk_sizes_are_refused(chunk_size in 0usize..1024) {
+            prop_assume!(chunk_size % AES_BLOCK_SIZE != 0 || chunk_size == 0);
+            prop_assert!(frame_chunks(&[1, 2, 3], chunk_size).is_none());
+        }
+    }
+
+    #[test]
+    fn short_frames_are_refused() {
+        for len in 0..LENGTH_PREFIX_SIZE {
+            assert_eq!(payload_range(&[0; LENGTH_PREFIX_SIZE][..len]), None);
+        }
+        assert_eq!(payload_range(&[0; LENGTH_PREFIX_SIZE]), Some(4..4));
+    }
+
+    #[test]
+    fn framed_len_bounds() {
+        assert_eq!(framed_len(0), Some(AES_BLOCK_SIZE));
+        assert_eq!(
+            framed_len(AES_BLOCK_SIZE - LENGTH_PREFIX_SIZE),
+            Some(AES_BLOCK_SIZE)
+        );
+        assert_eq!(
+            framed_len(AES_BLOCK_SIZE - LENGTH_PREFIX_SIZE + 1),
+            Some(2 * AES_BLOCK_SIZE)
+        );
+        if usize::BITS > 32 {
+            assert_eq!(framed_len(u32::MAX as usize + 1), None);
+        }
+    }
+}
@@ -15,4 +15,2311 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use alloc::{string::String, vec::Vec};
+use bytemuck::{Pod, Zeroable};
+use core::{fmt, mem::size_of, ops::Range};
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde::{Deserialize, Serialize};
+
+use crate::fixed_point;
+use crate::wire::{self, Le16, Le32, WireRecord};
+use crate::{Image, IMAGE_SIZE};
+
 pub const UUID: &str = &include_str!("../../ta/inference/uuid.txt");
+
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum Command {
+    Infer = 0,
+    EncryptModel = 1,
+    DecryptModel = 2,
+    /// Stores the AES model key in memref parameter 0. When memref
+    /// parameter 1 carries its commitment (SHA-256, `KEY_COMMITMENT_SIZE`
+    /// bytes) and a key is already stored, the same key again succeeds
+    /// without writing anything and a different one fails with
+    /// AccessConflict unless value a of parameter 2 has `STORE_KEY_FORCE`,
+    /// so a lost reply can be retried safely. Without a commitment the key
+    /// is replaced; TAs predating commitments answer one with
+    /// BadParameters.
+    StoreKey = 3,
+    BeginModelLoad = 4,
+    PushEncryptedChunk = 5,
+    FinalizeModelLoad = 6,
+    ExportAesKey = 7,
+    ModelStatus = 8,
+    InferEnsemble = 9,
+    PatchModel = 10,
+    ListStorage = 11,
+    DeleteStorageObject = 12,
+    GetPersistentStats = 13,
+    ResetPersistentStats = 14,
+    StoragePreflight = 15,
+    RollbackModel = 16,
+    ModelHistory = 17,
+    RebindStorage = 18,
+    RunSelfTest = 19,
+    SetResidencyPolicy = 20,
+    GetClassLabels = 21,
+    StageModel = 22,
+    CommitModel = 23,
+    DiscardStaged = 24,
+    GetLastCrash = 25,
+    /// Panics the TA on purpose; only TAs built with `debug-panic` have it.
+    DebugPanic = 26,
+    BeginModelExport = 27,
+    ReadEncryptedChunk = 28,
+    EndModelExport = 29,
+    SetTraceId = 30,
+    SetShadow = 31,
+    GetShadowReport = 32,
+    SetResultCache = 33,
+    SetModelVerifyKey = 34,
+    GetSchemaVersion = 35,
+    /// Returns the `BuildManifest` of the TA as JSON in memref parameter 0
+    /// (at most `MAX_BUILD_MANIFEST_SIZE` bytes). TAs predating it answer
+    /// `BadParameters`.
+    GetBuildManifest = 36,
+    /// Replaces the AES model key with the one in memref parameter 0, whose
+    /// commitment is in memref parameter 1, re-encrypting the stored model
+    /// under it. The new ciphertext is decrypted again, compared with the
+    /// model and imported (running its canaries) before anything is
+    /// persisted, and the new key is only imported once the stored model
+    /// has switched to the new ciphertext; any failure before that leaves
+    /// the old key and model as they were, and a rotation interrupted after
+    /// it is finished by the next session. Earlier versions, still under
+    /// the old key, are dropped. Fails with BadState while a model is staged. Rotating to
+    /// the key already stored succeeds without doing anything, so a lost
+    /// reply can be retried. TAs predating it answer `BadParameters`.
+    RotateKey = 37,
+    /// Replaces the `ManagePolicy` with the JSON one in memref parameter 0
+    /// (at most `MAX_MANAGE_POLICY_SIZE` bytes); one without clients
+    /// removes it. A policy that wouldn't admit the calling client fails
+    /// with AccessConflict, so a host can't lock itself out. TAs predating
+    /// session roles answer `BadParameters`.
+    SetManagePolicy = 38,
+    /// Returns the fingerprint of the stored AES model key, the first
+    /// `KEY_FINGERPRINT_SIZE` bytes of its SHA-256, in memref parameter 0;
+    /// ItemNotFound when no key is stored. The key itself stays in the TA.
+    /// TAs predating it answer `BadParameters`.
+    GetKeyFingerprint = 39,
+}
+
+impl Command {
+    /// Whether the command changes keys, models, stored state or TA-wide
+    /// settings, so that only `SessionRole::Manage` sessions may run it.
+    /// `GetLastCrash` with `CRASH_REPORT_CLEAR` and `GetShadowReport` with
+    /// `SHADOW_REPORT_RESET` need it as well. `RebindStorage` checks its
+    /// caller itself.
+    pub fn needs_manage(self) -> bool {
+        matches!(
+            self,
+            Command::EncryptModel
+                | Command::DecryptModel
+                | Command::StoreKey
+                | Command::BeginModelLoad
+                | Command::PushEncryptedChunk
+                | Command::FinalizeModelLoad
+                | Command::ExportAesKey
+                | Command::PatchModel
+                | Command::DeleteStorageObject
+                | Command::ResetPersistentStats
+                | Command::RollbackModel
+                | Command::SetResidencyPolicy
+                | Command::StageModel
+                | Command::CommitModel
+                | Command::DiscardStaged
+                | Command::DebugPanic
+                | Command::BeginModelExport
+                | Command::ReadEncryptedChunk
+                | Command::EndModelExport
+                | Command::SetShadow
+                | Command::SetResultCache
+                | Command::SetModelVerifyKey
+                | Command::RotateKey
+                | Command::SetManagePolicy
+        )
+    }
+
+    /// `needs_manage` for a call whose parameter 1 carries `flags` in value
+    /// a (0 when the host passed none), counting the report queries asked to
+    /// clear what they read.
+    pub fn needs_manage_with(self, flags: u32) -> bool {
+        match self {
+            Command::GetLastCrash => flags & CRASH_REPORT_CLEAR != 0,
+            Command::GetShadowReport => flags & SHADOW_REPORT_RESET != 0,
+            _ => self.needs_manage(),
+        }
+    }
+}
+
+/// Role a session asks for in value a of parameter 3 at open_session
+/// (value inout; the TA then answers its chunk sizes in it). Commands that
+/// `Command::needs_manage` fail with AccessDenied on Infer sessions.
+/// Sessions that leave a at 0, as hosts predating roles do, get Infer.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum SessionRole {
+    #[default]
+    Infer = 1,
+    Manage = 2,
+}
+
+impl SessionRole {
+    /// Whether a session of this role may run `cmd` with `flags` (see
+    /// `Command::needs_manage_with`).
+    pub fn may_run(self, cmd: Command, flags: u32) -> bool {
+        self == SessionRole::Manage || !cmd.needs_manage_with(flags)
+    }
+}
+
+/// Type of one operation parameter as the host passed it, GlobalPlatform's
+/// `TEE_PARAM_TYPE_*`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    None,
+    ValueInput,
+    ValueOutput,
+    ValueInout,
+    MemrefInput,
+    MemrefOutput,
+    MemrefInout,
+}
+
+/// How a command uses a parameter it takes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamUse {
+    /// Read by the TA: ValueInput or ValueInout.
+    ValueIn,
+    /// Written by the TA: ValueOutput or ValueInout.
+    ValueOut,
+    ValueInout,
+    /// Read by the TA: MemrefInput or MemrefInout.
+    MemrefIn,
+    /// Written by the TA: MemrefOutput or MemrefInout.
+    MemrefOut,
+}
+
+impl ParamUse {
+    pub fn accepts(self, param_type: ParamType) -> bool {
+        use ParamType::*;
+        match self {
+            ParamUse::ValueIn => matches!(param_type, ValueInput | ValueInout),
+            ParamUse::ValueOut => matches!(param_type, ValueOutput | ValueInout),
+            ParamUse::ValueInout => matches!(param_type, ValueInout),
+            ParamUse::MemrefIn => matches!(param_type, MemrefInput | MemrefInout),
+            ParamUse::MemrefOut => matches!(param_type, MemrefOutput | MemrefInout),
+        }
+    }
+}
+
+/// What a command expects in one of its four parameters.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamSpec {
+    /// Must be None.
+    Unused,
+    Required(ParamUse),
+    /// Hosts may leave the parameter out (None) to get the default.
+    Optional(ParamUse),
+}
+
+impl ParamSpec {
+    pub fn accepts(self, param_type: ParamType) -> bool {
+        match self {
+            ParamSpec::Unused => param_type == ParamType::None,
+            ParamSpec::Required(usage) => usage.accepts(param_type),
+            ParamSpec::Optional(usage) => {
+                param_type == ParamType::None || usage.accepts(param_type)
+            }
+        }
+    }
+}
+
+impl Command {
+    /// Parameter types the command accepts, which the TA checks before its
+    /// handler touches the parameters. The match has no catch-all, so a new
+    /// command doesn't build until its signature is declared here.
+    pub fn signature(self) -> [ParamSpec; 4] {
+        use ParamSpec::*;
+        use ParamUse::*;
+        match self {
+            Command::Infer => [
+                Required(MemrefIn),
+                Required(MemrefOut),
+                Optional(ValueIn),
+                Optional(MemrefOut),
+            ],
+            Command::EncryptModel | Command::DecryptModel => {
+                [Required(MemrefIn), Required(MemrefOut), Unused, Unused]
+            }
+            // Parameter 1 is the key commitment
+            Command::StoreKey => [
+                Required(MemrefIn),
+                Optional(MemrefIn),
+                Optional(ValueIn),
+                Unused,
+            ],
+            // Parameter 1 is the new key's commitment
+            Command::RotateKey => [Required(MemrefIn), Required(MemrefIn), Unused, Unused],
+            // Parameter 3 is the error detail buffer
+            Command::DeleteStorageObject => {
+                [Required(MemrefIn), Unused, Unused, Optional(MemrefOut)]
+            }
+            Command::SetModelVerifyKey | Command::SetManagePolicy => {
+                [Required(MemrefIn), Unused, Unused, Unused]
+            }
+            Command::PushEncryptedChunk => [Required(MemrefIn), Optional(ValueOut), Unused, Unused],
+            Command::BeginModelLoad | Command::ResetPersistentStats | Command::SetShadow => {
+                [Optional(ValueIn), Unused, Unused, Unused]
+            }
+            // Parameter 1 is the model signature, 3 the error detail buffer
+            Command::StageModel => [
+                Optional(ValueIn),
+                Optional(MemrefIn),
+                Unused,
+                Optional(MemrefOut),
+            ],
+            Command::FinalizeModelLoad => [
+                Optional(ValueOut),
+                Optional(MemrefIn),
+                Unused,
+                Optional(MemrefOut),
+            ],
+            Command::RollbackModel | Command::CommitModel | Command::PatchModel => {
+                [Optional(ValueOut), Unused, Unused, Optional(MemrefOut)]
+            }
+            Command::ExportAesKey
+            | Command::ListStorage
+            | Command::ModelHistory
+            | Command::GetSchemaVersion
+            | Command::GetBuildManifest
+            | Command::GetKeyFingerprint
+            | Command::RunSelfTest => [Required(MemrefOut), Unused, Unused, Unused],
+            Command::ModelStatus
+            | Command::GetPersistentStats
+            | Command::GetLastCrash
+            | Command::GetShadowReport => [Required(MemrefOut), Optional(ValueIn), Unused, Unused],
+            Command::GetClassLabels => [Required(MemrefOut), Required(ValueIn), Unused, Unused],
+            Command::InferEnsemble => [
+                Required(MemrefIn),
+                Required(MemrefOut),
+                Required(ValueIn),
+                Optional(MemrefOut),
+            ],
+            Command::StoragePreflight => [
+                Required(ValueIn),
+                Required(ValueOut),
+                Required(ValueOut),
+                Unused,
+            ],
+            Command::SetResidencyPolicy | Command::SetResultCache => {
+                [Required(ValueIn), Unused, Unused, Unused]
+            }
+            Command::SetTraceId => [Required(ValueInout), Unused, Unused, Unused],
+            Command::BeginModelExport => [Required(ValueOut), Required(MemrefOut), Unused, Unused],
+            Command::ReadEncryptedChunk => [Required(ValueIn), Required(MemrefOut), Unused, Unused],
+            Command::RebindStorage
+            | Command::DiscardStaged
+            | Command::DebugPanic
+            | Command::EndModelExport => [Unused; 4],
+        }
+    }
+
+    /// Index of the first parameter whose type doesn't fit `signature`.
+    pub fn mistyped_param(self, types: [ParamType; 4]) -> Option<usize> {
+        self.signature()
+            .into_iter()
+            .zip(types)
+            .position(|(spec, param_type)| !spec.accepts(param_type))
+    }
+}
+
+/// Wire protocol revision, exchanged at open_session: the host passes its
+/// version (a) and its clock in seconds since the Unix epoch (b, 0 when
+/// unknown) in a value-input parameter 1, the TA answers with its version
+/// (a) and `CAP_*` bits (b) in value-output parameter 2. TAs predating the
+/// negotiation leave parameter 2 untouched, i.e. report version 0.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest TA protocol version the host still talks to.
+pub const MIN_TA_PROTOCOL_VERSION: u32 = 1;
+/// First protocol version whose TAs write error details (see
+/// `ERROR_DETAIL_TAG`) and accept a buffer for them in parameter 3 of the
+/// commands that have no output memref of their own.
+pub const ERROR_DETAIL_PROTOCOL_VERSION: u32 = 2;
+
+/// Softmax probabilities output of `Command::Infer`.
+pub const CAP_PROBABILITIES: u32 = 1 << 0;
+/// Top-k results per image.
+pub const CAP_TOP_K: u32 = 1 << 1;
+/// Encrypted inference inputs and outputs.
+pub const CAP_ENCRYPTED_IO: u32 = 1 << 2;
+/// More than one model slot.
+pub const CAP_SLOTS: u32 = 1 << 3;
+/// Decrypting pushed chunks as they arrive instead of at finalize.
+pub const CAP_STREAMING_DECRYPT: u32 = 1 << 4;
+/// `Command::InferEnsemble`.
+pub const CAP_ENSEMBLE: u32 = 1 << 5;
+/// `Command::PatchModel`.
+pub const CAP_PATCH: u32 = 1 << 6;
+/// `Command::ListStorage`, `Command::DeleteStorageObject` and
+/// `Command::StoragePreflight`.
+pub const CAP_STORAGE: u32 = 1 << 7;
+/// `Command::GetPersistentStats` and `Command::ResetPersistentStats`.
+pub const CAP_STATS: u32 = 1 << 8;
+/// `Command::RollbackModel` and `Command::ModelHistory`.
+pub const CAP_HISTORY: u32 = 1 << 9;
+/// `Command::RunSelfTest`.
+pub const CAP_SELF_TEST: u32 = 1 << 10;
+/// Infer accepts `FLAG_CORRELATION`.
+pub const CAP_CORRELATION: u32 = 1 << 11;
+/// `Command::SetResidencyPolicy` is supported.
+pub const CAP_RESIDENCY: u32 = 1 << 12;
+/// `Command::GetClassLabels`.
+pub const CAP_CLASS_LABELS: u32 = 1 << 13;
+/// `Command::StageModel`, `Command::CommitModel` and `Command::DiscardStaged`:
+/// provisioning split into an upload that is checked and persisted aside,
+/// and a separate activation.
+pub const CAP_STAGING: u32 = 1 << 14;
+/// Value a of `Command::StageModel`: replace an already staged model.
+pub const STAGE_FORCE: u32 = 1 << 0;
+/// Value a of `Command::StoreKey`: replace a different key already stored.
+pub const STORE_KEY_FORCE: u32 = 1 << 0;
+/// Size of the key commitment `Command::StoreKey` takes, a SHA-256.
+pub const KEY_COMMITMENT_SIZE: usize = 32;
+/// Size of the key fingerprint `Command::GetKeyFingerprint` returns, the
+/// start of the key's SHA-256. `StoredModelInfo::key_fingerprint` is the
+/// same bytes in hex.
+pub const KEY_FINGERPRINT_SIZE: usize = 8;
+/// Models may carry a `ModelLicense`, which the TA enforces.
+pub const CAP_LICENSE: u32 = 1 << 15;
+/// Canary images in a model's metadata are checked before it is installed.
+pub const CAP_CANARY: u32 = 1 << 16;
+/// Panics are recorded and returned by `Command::GetLastCrash`.
+pub const CAP_CRASH_REPORT: u32 = 1 << 17;
+/// Value a of `Command::GetLastCrash`: delete the report once read.
+pub const CRASH_REPORT_CLEAR: u32 = 1 << 0;
+/// Infer accepts `FLAG_RAW_SCALE` and `FLAG_NO_NORMALIZE`.
+pub const CAP_INPUT_SCALING: u32 = 1 << 18;
+/// Infer accepts `FLAG_REJECT_BELOW`.
+pub const CAP_REJECT_THRESHOLD: u32 = 1 << 19;
+/// Infer accepts `FLAG_INPUT_HASHES`.
+pub const CAP_INPUT_HASHES: u32 = 1 << 20;
+/// `Command::BeginModelExport`, `Command::ReadEncryptedChunk` and
+/// `Command::EndModelExport`: the stored model, re-encrypted under the
+/// current key with a fresh IV, read back in chunks. `BeginModelExport`
+/// answers the export's size in value a of parameter 0 and its SHA-256 in
+/// parameter 1; each `ReadEncryptedChunk` takes an offset (a) and a length
+/// (b) in parameter 0 and fills parameter 1. The export is never plaintext.
+pub const CAP_MODEL_EXPORT: u32 = 1 << 21;
+/// Longest range one `Command::ReadEncryptedChunk` returns.
+pub const MAX_EXPORT_CHUNK_SIZE: usize = 256 * 1024;
+/// `Command::SetTraceId`: a u64 trace id, split by `split_trace_id` into
+/// values a and b of the value-inout parameter 0, that the TA puts in every
+/// log line until another id is set or its last session closes. Zero clears
+/// it. The TA answers with the id it now uses, so the host can tell it was
+/// taken.
+pub const CAP_TRACE_ID: u32 = 1 << 22;
+/// Infer accepts `FLAG_OUTPUT_WINDOW`.
+pub const CAP_OUTPUT_WINDOW: u32 = 1 << 23;
+/// `open_session` fills a value-output parameter 3, when the host passes
+/// one, with the `PushEncryptedChunk` size the TA prefers (a) and the largest
+/// it accepts (b). Hosts send chunks no larger than the latter.
+pub const CAP_CHUNK_SIZES: u32 = 1 << 24;
+/// `Command::SetShadow` and `Command::GetShadowReport`: a shadow slot runs
+/// every inference of its active slot too, silently, and the TA counts where
+/// the two disagree. Responses always come from the active slot. Value a of
+/// parameter 0 of `SetShadow` is the active slot and b the shadow; leaving
+/// the parameter out turns shadowing off. Both reset the report. Infer
+/// accepts `FLAG_SKIP_SHADOW`.
+pub const CAP_SHADOW: u32 = 1 << 25;
+/// Value a of `Command::GetShadowReport`: reset the counters once read.
+pub const SHADOW_REPORT_RESET: u32 = 1 << 0;
+/// Model loads are checked against the TA's load state: `BeginModelLoad`
+/// only starts from `LoadPhase::Idle`, chunks only go to a load that is
+/// receiving, and finalize, stage and patch only take a load that received
+/// every byte it announced (value b of `BeginModelLoad`, 0 when unknown).
+/// A command sent out of order fails with BadState and leaves the load as it
+/// was; the TA reports the load's phase (a) and the bytes it received (b) in
+/// the command's value parameter: parameter 0 of `BeginModelLoad` (inout),
+/// `FinalizeModelLoad`, `StageModel` and `PatchModel`, parameter 1 of
+/// `PushEncryptedChunk`.
+pub const CAP_LOAD_STATE: u32 = 1 << 26;
+/// `Command::SetResultCache` keeps the label and probabilities of up to
+/// value a of parameter 0 images (0 turns the cache off, the default; at most
+/// `MAX_RESULT_CACHE_ENTRIES`), least recently used first out. Entries are
+/// keyed by the SHA-256 of the image bytes, the normalization and temperature
+/// they ran with and the hash of the model, and every model install or drop
+/// flushes them. Hits and misses are counted in `UsageCounters`. Infer
+/// accepts `FLAG_NO_CACHE`.
+pub const CAP_RESULT_CACHE: u32 = 1 << 27;
+/// Largest result cache `Command::SetResultCache` accepts.
+pub const MAX_RESULT_CACHE_ENTRIES: u32 = 1024;
+/// Result cache size hosts ask for when the user doesn't give one.
+pub const DEFAULT_RESULT_CACHE_ENTRIES: u32 = 64;
+/// `Command::SetModelVerifyKey` provisions an RSA public key (DER, memref
+/// parameter 0, see `key_manager::parse_rsa_public_key`) once; setting
+/// another key afterwards fails with AccessConflict. From then on
+/// `FinalizeModelLoad` and `StageModel` need an RSASSA-PKCS1-v1_5 SHA-256
+/// signature of `model_signature_header` followed by the pushed ciphertext
+/// (memref input parameter 1) and fail with SignatureInvalid without a
+/// valid one, and `PatchModel` is refused the same way. Without a key,
+/// signatures are ignored.
+pub const CAP_MODEL_SIGNATURES: u32 = 1 << 28;
+/// Labels follow the tie-break rule of `top_class`, and infer accepts
+/// `FLAG_MARK_TIES`.
+pub const CAP_TIE_MARKS: u32 = 1 << 29;
+/// `Command::GetSchemaVersion` returns the `StorageSchema` of the TA's
+/// secure storage as JSON in memref parameter 0 (at most
+/// `MAX_STORAGE_SCHEMA_SIZE` bytes).
+pub const CAP_STORAGE_SCHEMA: u32 = 1 << 30;
+/// Infer accepts `FLAG_TIME_BUDGET` and reports how far it got in the value
+/// parameter.
+pub const CAP_TIME_BUDGET: u32 = 1 << 31;
+/// Largest model signature, that of a `key_manager::MAX_RSA_BITS` key.
+pub const MAX_MODEL_SIGNATURE_SIZE: usize = crate::key_manager::MAX_RSA_BITS / 8;
+/// Size of `model_signature_header`.
+pub const MODEL_SIGNATURE_HEADER_SIZE: usize = 16;
+const MODEL_SIGNATURE_MAGIC: &[u8; 4] = b"EMNS";
+// AES-256-CBC, the only cipher of model files
+const MODEL_CIPHER_AES_256_CBC: u32 = 1;
+
+/// Leads the message a model signature covers, followed by the ciphertext
+/// (IV included) exactly as pushed: magic, cipher and ciphertext length.
+pub fn model_signature_header(ciphertext_len: usize) -> [u8; MODEL_SIGNATURE_HEADER_SIZE] {
+    let mut header = [0u8; MODEL_SIGNATURE_HEADER_SIZE];
+    header[..4].copy_from_slice(MODEL_SIGNATURE_MAGIC);
+    header[4..8].copy_from_slice(&MODEL_CIPHER_AES_256_CBC.to_le_bytes());
+    header[8..].copy_from_slice(&(ciphertext_len as u64).to_le_bytes());
+    header
+}
+
+/// TA-defined return code of an inference on a model whose license has
+/// expired (GlobalPlatform leaves 0x00000001..=0x7FFFFFFF to TAs).
+pub const ERROR_LICENSE_EXPIRED: u32 = 0x0000_4C01;
+/// TA-defined return code of a load whose model misclassified one of its
+/// canary images; the failing index is reported in value b of parameter 0,
+/// which holds the number of canaries checked after a successful load.
+pub const ERROR_CANARY_MISMATCH: u32 = 0x0000_4C02;
+/// TA-defined return codes of a model refused at load, stage, commit,
+/// rollback or patch, one per `common::ModelError` variant. TAs that predate
+/// them answer `BadFormat` or `BadParameters` instead.
+pub const ERROR_MODEL_RECORD_FORMAT: u32 = 0x0000_4C03;
+pub const ERROR_MODEL_SHAPE_MISMATCH: u32 = 0x0000_4C04;
+pub const ERROR_MODEL_UNSUPPORTED_ARCH: u32 = 0x0000_4C05;
+pub const ERROR_MODEL_UNSUPPORTED_PRECISION: u32 = 0x0000_4C06;
+pub const ERROR_MODEL_METADATA: u32 = 0x0000_4C07;
+pub const ERROR_MODEL_IO: u32 = 0x0000_4C08;
+/// TA-defined return code of an inference request without images. Hosts
+/// don't send such requests (`InferenceTaConnector::infer_batch` answers
+/// an empty batch itself); TAs that predate it answer `BadParameters`.
+pub const ERROR_NO_IMAGES: u32 = 0x0000_4C09;
+/// TA-defined return code of open_session when the secure storage holds a
+/// layout newer than `STORAGE_SCHEMA_VERSION`, written by a newer TA this
+/// one was rolled back from. The TA refuses every session rather than
+/// misread it; reinstall the newer TA or wipe the storage.
+pub const ERROR_STORAGE_DOWNGRADE: u32 = 0x0000_4C0A;
+
+/// Starts the short UTF-8 diagnostic a failing `FinalizeModelLoad`,
+/// `PatchModel`, `StageModel`, `CommitModel`, `RollbackModel` or
+/// `DeleteStorageObject` writes to the memref the host passed in parameter
+/// 3, when it has room. The tag tells it apart from an untouched buffer.
+/// Details name what failed (an object id, a platform code, the recorder's
+/// message), never data or key material.
+pub const ERROR_DETAIL_TAG: [u8; 4] = *b"EDT:";
+/// Longest error detail after the tag, in bytes; longer ones are cut at a
+/// character boundary.
+pub const MAX_ERROR_DETAIL_SIZE: usize = 256;
+/// Room for a whole error detail, tag included.
+pub const ERROR_DETAIL_BUFFER_SIZE: usize = ERROR_DETAIL_TAG.len() + MAX_ERROR_DETAIL_SIZE;
+
+/// `detail` cut to at most `max` bytes without splitting a character.
+pub fn truncate_error_detail(detail: &str, max: usize) -> &str {
+    let mut end = detail.len().min(max);
+    while !detail.is_char_boundary(end) {
+        end -= 1;
+    }
+    &detail[..end]
+}
+
+/// Writes `detail` after `ERROR_DETAIL_TAG` to `buffer`, cut to what fits
+/// and to `MAX_ERROR_DETAIL_SIZE`. Returns the bytes written, 0 when not
+/// even a character of it fits.
+pub fn write_error_detail(detail: &str, buffer: &mut [u8]) -> usize {
+    let Some(room) = buffer.len().checked_sub(ERROR_DETAIL_TAG.len()) else {
+        return 0;
+    };
+    let detail = truncate_error_detail(detail, room.min(MAX_ERROR_DETAIL_SIZE));
+    if detail.is_empty() {
+        return 0;
+    }
+    let size = ERROR_DETAIL_TAG.len() + detail.len();
+    buffer[..ERROR_DETAIL_TAG.len()].copy_from_slice(&ERROR_DETAIL_TAG);
+    buffer[ERROR_DETAIL_TAG.len()..size].copy_from_slice(detail.as_bytes());
+    size
+}
+
+/// The error detail in the `size` bytes a failed command reported for an
+/// output buffer, `None` when they don't hold one.
+pub fn parse_error_detail(bytes: &[u8]) -> Option<&str> {
+    let detail = bytes.strip_prefix(&ERROR_DETAIL_TAG[..])?;
+    if detail.is_empty() || detail.len() > MAX_ERROR_DETAIL_SIZE {
+        return None;
+    }
+    core::str::from_utf8(detail).ok()
+}
+
+/// Low (a) and high (b) halves of a trace id, as `Command::SetTraceId`
+/// carries it.
+pub fn split_trace_id(id: u64) -> (u32, u32) {
+    (id as u32, (id >> 32) as u32)
+}
+
+/// Inverse of `split_trace_id`.
+pub fn join_trace_id(low: u32, high: u32) -> u64 {
+    (high as u64) << 32 | low as u64
+}
+
+/// Bytes of an export of `size` bytes that `Command::ReadEncryptedChunk`
+/// returns for `len` bytes at `offset`; None for empty ranges, ranges longer
+/// than `MAX_EXPORT_CHUNK_SIZE` and ranges past the end.
+pub fn export_chunk(offset: usize, len: usize, size: usize) -> Option<Range<usize>> {
+    let end = offset.checked_add(len)?;
+    (len > 0 && len <= MAX_EXPORT_CHUNK_SIZE && end <= size).then_some(offset..end)
+}
+
+/// Ranges that read an export of `size` bytes: consecutive and at most
+/// `MAX_EXPORT_CHUNK_SIZE` long, so every byte is read exactly once.
+pub fn export_chunks(size: usize) -> impl Iterator<Item = Range<usize>> {
+    (0..size)
+        .step_by(MAX_EXPORT_CHUNK_SIZE)
+        .map(move |offset| offset..(offset + MAX_EXPORT_CHUNK_SIZE).min(size))
+}
+
+/// Time-boxed license embedded in a model's metadata. Being part of the
+/// encrypted container, it can't be changed without the key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelLicense {
+    /// Seconds since the Unix epoch from which the model refuses inferences.
+    pub expires: u64,
+    #[serde(default)]
+    pub licensee: String,
+}
+
+/// License of an installed model, as reported by `Command::ModelStatus`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LicenseStatus {
+    #[serde(flatten)]
+    pub license: ModelLicense,
+    /// The TA's trusted time the expiry is checked against, 0 when unknown.
+    pub now: u64,
+}
+
+impl LicenseStatus {
+    /// Seconds of validity left, 0 once expired.
+    pub fn remaining(&self) -> u64 {
+        self.license.expires.saturating_sub(self.now)
+    }
+}
+
+/// How pixel bytes become model inputs: `(pixel / scale - mean) / std`.
+/// Carried in a model's metadata so the TA, the simulated TA and host-side
+/// checks all feed the model what it was trained on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Normalization {
+    pub mean: f32,
+    pub std: f32,
+    pub scale: f32,
+}
+
+impl Normalization {
+    /// Mean and standard deviation of the MNIST training set, used by models
+    /// whose metadata names no profile.
+    pub const MNIST: Self = Self {
+        mean: 0.1307,
+        std: 0.3081,
+        scale: 255.0,
+    };
+    /// Plain [0, 1] scaling.
+    pub const UNIT: Self = Self {
+        mean: 0.0,
+        std: 1.0,
+        scale: 255.0,
+    };
+    /// Pixel values as they are.
+    pub const IDENTITY: Self = Self {
+        mean: 0.0,
+        std: 1.0,
+        scale: 1.0,
+    };
+
+    /// Whether the profile can be applied: finite, with a positive scale
+    /// and standard deviation.
+    pub fn is_valid(&self) -> bool {
+        self.mean.is_finite()
+            && self.std.is_finite()
+            && self.std > 0.0
+            && self.scale.is_finite()
+            && self.scale > 0.0
+    }
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Self::MNIST
+    }
+}
+
+impl fmt::Display for Normalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(x / {} - {}) / {}", self.scale, self.mean, self.std)
+    }
+}
+
+/// Phase of the TA's begin/push/finalize sequence, reported with a BadState
+/// answer to a model-load command (`CAP_LOAD_STATE`).
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum LoadPhase {
+    /// No load in progress.
+    Idle = 0,
+    /// Begun, waiting for more chunks.
+    Receiving = 1,
+    /// Every announced byte pushed, waiting for finalize, stage or patch.
+    Staged = 2,
+}
+
+impl fmt::Display for LoadPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Idle => "idle",
+            Self::Receiving => "receiving",
+            Self::Staged => "staged",
+        })
+    }
+}
+
+/// What the TA keeps in memory once its last session closes, chosen with
+/// `Command::SetResidencyPolicy` (value a). Partially streamed models are
+/// wiped either way.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum ResidencyPolicy {
+    /// Models stay loaded for the next client.
+    #[default]
+    KeepResident = 0,
+    /// Models are dropped; slot 0 is reloaded from secure storage by the
+    /// next inference.
+    DropOnIdle = 1,
+}
+
+/// Number of models the TA can hold at once; commands address them by index.
+pub const MODEL_SLOTS: usize = 4;
+
+/// Fixed-point scale of the softmax temperature passed to the inference
+/// command: the value parameter carries `temperature * TEMPERATURE_SCALE`.
+pub const TEMPERATURE_SCALE: u32 = 1000;
+
+/// Magic of `InferenceRequestHeader`, "EMIR" read as a little-endian u32.
+pub const REQUEST_MAGIC: u32 = u32::from_le_bytes(*b"EMIR");
+/// Return one `Prediction` per image instead of a bare label byte.
+pub const FLAG_PREDICTIONS: u32 = 1 << 0;
+/// The header is followed by one little-endian u32 id per image, zero padded
+/// to `correlation_block_size`; the TA returns one `CorrelatedPrediction`
+/// per image, echoing the ids.
+pub const FLAG_CORRELATION: u32 = 1 << 1;
+/// The images are only scaled to [0, 1] (`Normalization::UNIT`), skipping
+/// the mean/std standardization of the model's profile.
+pub const FLAG_RAW_SCALE: u32 = 1 << 2;
+/// The pixel values go to the model unchanged (`Normalization::IDENTITY`).
+pub const FLAG_NO_NORMALIZE: u32 = 1 << 3;
+/// The header is followed by a little-endian u32 confidence threshold in
+/// thousandths (at most `MAX_REJECT_THRESHOLD`, before the ids of a
+/// correlated request): images whose top softmax probability, rounded like
+/// `Prediction::confidence_milli`, is below it get `REJECT_LABEL` in the
+/// labels and predictions. Probabilities are returned as computed, so the
+/// ranking of the classes stays available.
+pub const FLAG_REJECT_BELOW: u32 = 1 << 4;
+/// The TA appends the SHA-256 of every image's row of the input tensor it
+/// ran, `INPUT_HASH_SIZE` bytes per image in order, to the labels or
+/// predictions. The rows are hashed as little-endian f32, the layout of
+/// `input_tensor_bytes`, so the host can tell whether the TA normalized an
+/// image the way it expects.
+pub const FLAG_INPUT_HASHES: u32 = 1 << 5;
+/// The header is followed (after the threshold, before the ids) by an
+/// `OutputWindow`: the TA runs the whole batch but returns the labels,
+/// predictions, input hashes and probabilities of the images in the window
+/// only. The TA keeps the outputs of the last windowed batch, so asking for
+/// the other windows of the same request doesn't run the model again.
+pub const FLAG_OUTPUT_WINDOW: u32 = 1 << 6;
+/// Don't run the shadow slot for this request, for callers on a latency
+/// budget; the shadow report counts it as skipped.
+pub const FLAG_SKIP_SHADOW: u32 = 1 << 7;
+/// Run the model even for images in the result cache, and don't cache them.
+pub const FLAG_NO_CACHE: u32 = 1 << 8;
+/// Predictions whose class `top_class` picked among tied ones are marked,
+/// see `Prediction::is_tied`. Without the flag the mark is never set, as
+/// hosts predating it would read it as part of the confidence.
+pub const FLAG_MARK_TIES: u32 = 1 << 9;
+/// The header is followed (after the threshold, before the ids) by a
+/// little-endian u32 time budget in milliseconds. The TA runs the batch
+/// `TIME_BUDGET_SLICE` images at a time and, once the budget is spent,
+/// returns the results of the images it got through instead of running the
+/// rest: all outputs then cover the first images only. The value parameter
+/// must be inout; the TA sets a to the images completed and b to
+/// `BUDGET_EXCEEDED` when it stopped early. The first slice always runs.
+/// Can't be combined with `FLAG_OUTPUT_WINDOW`, and the result cache isn't
+/// used.
+pub const FLAG_TIME_BUDGET: u32 = 1 << 10;
+/// Probabilities come back as one little-endian u16 per class and image
+/// instead of f32, each row encoded by `fixed_point::encode_probs`: half the
+/// size, and every row sums to exactly `PROB_SCALE`. TAs predating the flag
+/// refuse it as unknown.
+pub const FLAG_FIXED_POINT_PROBS: u32 = 1 << 11;
+const KNOWN_FLAGS: u32 = FLAG_PREDICTIONS
+    | FLAG_CORRELATION
+    | FLAG_RAW_SCALE
+    | FLAG_NO_NORMALIZE
+    | FLAG_REJECT_BELOW
+    | FLAG_INPUT_HASHES
+    | FLAG_OUTPUT_WINDOW
+    | FLAG_SKIP_SHADOW
+    | FLAG_NO_CACHE
+    | FLAG_MARK_TIES
+    | FLAG_TIME_BUDGET
+    | FLAG_FIXED_POINT_PROBS;
+/// Size of one `FLAG_INPUT_HASHES` hash.
+pub const INPUT_HASH_SIZE: usize = 32;
+
+/// Label of the images a `FLAG_REJECT_BELOW` request leaves unclassified.
+/// Models with more than 255 classes can't be asked for it.
+pub const REJECT_LABEL: u8 = 255;
+/// Largest `FLAG_REJECT_BELOW` threshold, rejecting everything short of
+/// certainty.
+pub const MAX_REJECT_THRESHOLD: u32 = 1000;
+const REJECT_THRESHOLD_SIZE: usize = size_of::<u32>();
+/// Images per forward pass of a `FLAG_TIME_BUDGET` request; the budget is
+/// checked between them.
+pub const TIME_BUDGET_SLICE: usize = 16;
+/// Value b of a `FLAG_TIME_BUDGET` reply when the budget ran out before the
+/// end of the batch.
+pub const BUDGET_EXCEEDED: u32 = 1 << 0;
+const TIME_BUDGET_SIZE: usize = size_of::<u32>();
+
+/// Images of a `FLAG_OUTPUT_WINDOW` request whose results are returned, as
+/// two little-endian u32.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct OutputWindow {
+    pub start: u32,
+    pub count: u32,
+}
+
+const OUTPUT_WINDOW_SIZE: usize = 2 * size_of::<u32>();
+
+impl OutputWindow {
+    /// Indices of the images in the window.
+    pub fn range(&self) -> core::ops::Range<usize> {
+        self.start as usize..self.start as usize + self.count as usize
+    }
+
+    pub fn to_bytes(&self) -> [u8; OUTPUT_WINDOW_SIZE] {
+        let mut bytes = [0; OUTPUT_WINDOW_SIZE];
+        bytes[..4].copy_from_slice(&self.start.to_le_bytes());
+        bytes[4..].copy_from_slice(&self.count.to_le_bytes());
+        bytes
+    }
+}
+
+/// Prefix of the `Command::Infer` input memref, followed by `batch_len`
+/// images (after the ids with `FLAG_CORRELATION`). Requests without it (a
+/// bare image array) take the legacy path: labels only, temperature and slot
+/// from the value parameter. All fields are little-endian u32.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct InferenceRequestHeader {
+    magic: Le32,
+    batch_len: Le32,
+    flags: Le32,
+    /// Softmax temperature in fixed point, see `TEMPERATURE_SCALE`.
+    temperature: Le32,
+}
+
+impl WireRecord for InferenceRequestHeader {}
+
+const _: () = assert!(size_of::<InferenceRequestHeader>() == 16);
+const _: () = assert!(core::mem::align_of::<InferenceRequestHeader>() == 1);
+const _: () = assert!(core::mem::offset_of!(InferenceRequestHeader, magic) == 0);
+const _: () = assert!(core::mem::offset_of!(InferenceRequestHeader, batch_len) == 4);
+const _: () = assert!(core::mem::offset_of!(InferenceRequestHeader, flags) == 8);
+const _: () = assert!(core::mem::offset_of!(InferenceRequestHeader, temperature) == 12);
+// A header-prefixed buffer can never be mistaken for a bare image array
+const _: () = assert!(size_of::<InferenceRequestHeader>() % IMAGE_SIZE != 0);
+const _: () =
+    assert!((size_of::<InferenceRequestHeader>() + REJECT_THRESHOLD_SIZE) % IMAGE_SIZE != 0);
+const _: () = assert!((size_of::<InferenceRequestHeader>() + OUTPUT_WINDOW_SIZE) % IMAGE_SIZE != 0);
+const _: () = assert!(
+    (size_of::<InferenceRequestHeader>() + REJECT_THRESHOLD_SIZE + OUTPUT_WINDOW_SIZE) % IMAGE_SIZE
+        != 0
+);
+const _: () = assert!(
+    (size_of::<InferenceRequestHeader>() + REJECT_THRESHOLD_SIZE + TIME_BUDGET_SIZE) % IMAGE_SIZE
+        != 0
+);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    InvalidHeader,
+    EmptyBatch,
+    UnknownFlags(u32),
+    /// `FLAG_RAW_SCALE` and `FLAG_NO_NORMALIZE` together.
+    ConflictingFlags,
+    InvalidTemperature,
+    /// A `FLAG_REJECT_BELOW` threshold above `MAX_REJECT_THRESHOLD`.
+    InvalidThreshold,
+    /// The image bytes don't match `batch_len`.
+    BatchMismatch,
+    /// An empty `FLAG_OUTPUT_WINDOW` window, or one reaching past the batch.
+    InvalidWindow,
+    /// `FLAG_TIME_BUDGET` and `FLAG_OUTPUT_WINDOW` together.
+    WindowedBudget,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::InvalidHeader => write!(f, "invalid request header"),
+            RequestError::EmptyBatch => write!(f, "empty batch"),
+            RequestError::UnknownFlags(flags) => write!(f, "unknown request flags {flags:#x}"),
+            RequestError::ConflictingFlags => {
+                write!(f, "raw scaling and no normalization are exclusive")
+            }
+            RequestError::InvalidTemperature => write!(f, "temperature must be positive"),
+            RequestError::InvalidThreshold => {
+                write!(f, "reject threshold must be between 0 and 1")
+            }
+            RequestError::BatchMismatch => write!(f, "image data doesn't match the batch length"),
+            RequestError::InvalidWindow => write!(f, "output window outside the batch"),
+            RequestError::WindowedBudget => {
+                write!(f, "a windowed request can't have a time budget")
+            }
+        }
+    }
+}
+
+impl InferenceRequestHeader {
+    pub fn new(batch_len: usize, flags: u32, temperature: f32) -> Result<Self, RequestError> {
+        if batch_len == 0 {
+            return Err(RequestError::EmptyBatch);
+        }
+        let batch_len = u32::try_from(batch_len).map_err(|_| RequestError::BatchMismatch)?;
+        let header = Self {
+            magic: Le32::new(REQUEST_MAGIC),
+            batch_len: Le32::new(batch_len),
+            flags: Le32::new(flags),
+            temperature: Le32::new(fixed_point_temperature(temperature)?),
+        };
+        header.validate()?;
+        Ok(header)
+    }
+
+    /// Checks a header received over the wire.
+    pub fn validate(&self) -> Result<(), RequestError> {
+        if self.magic() != REQUEST_MAGIC {
+            return Err(RequestError::InvalidHeader);
+        }
+        if self.batch_len() == 0 {
+            return Err(RequestError::EmptyBatch);
+        }
+        if self.flags() & !KNOWN_FLAGS != 0 {
+            return Err(RequestError::UnknownFlags(self.flags()));
+        }
+        if self.flags() & FLAG_RAW_SCALE != 0 && self.flags() & FLAG_NO_NORMALIZE != 0 {
+            return Err(RequestError::ConflictingFlags);
+        }
+        if self.has_time_budget() && self.has_output_window() {
+            return Err(RequestError::WindowedBudget);
+        }
+        if self.temperature.get() == 0 {
+            return Err(RequestError::InvalidTemperature);
+        }
+        Ok(())
+    }
+
+    pub fn magic(&self) -> u32 {
+        self.magic.get()
+    }
+
+    pub fn batch_len(&self) -> u32 {
+        self.batch_len.get()
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags.get()
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature.get() as f32 / TEMPERATURE_SCALE as f32
+    }
+
+    pub fn wants_predictions(&self) -> bool {
+        self.flags() & FLAG_PREDICTIONS != 0
+    }
+
+    pub fn is_correlated(&self) -> bool {
+        self.flags() & FLAG_CORRELATION != 0
+    }
+
+    pub fn has_reject_threshold(&self) -> bool {
+        self.flags() & FLAG_REJECT_BELOW != 0
+    }
+
+    pub fn wants_input_hashes(&self) -> bool {
+        self.flags() & FLAG_INPUT_HASHES != 0
+    }
+
+    pub fn skips_shadow(&self) -> bool {
+        self.flags() & FLAG_SKIP_SHADOW != 0
+    }
+
+    pub fn bypasses_cache(&self) -> bool {
+        self.flags() & FLAG_NO_CACHE != 0
+    }
+
+    pub fn marks_ties(&self) -> bool {
+        self.flags() & FLAG_MARK_TIES != 0
+    }
+
+    pub fn fixed_point_probabilities(&self) -> bool {
+        self.flags() & FLAG_FIXED_POINT_PROBS != 0
+    }
+
+    pub fn has_output_window(&self) -> bool {
+        self.flags() & FLAG_OUTPUT_WINDOW != 0
+    }
+
+    pub fn has_time_budget(&self) -> bool {
+        self.flags() & FLAG_TIME_BUDGET != 0
+    }
+
+    fn threshold_size(&self) -> usize {
+        if self.has_reject_threshold() {
+            REJECT_THRESHOLD_SIZE
+        } else {
+            0
+        }
+    }
+
+    // Bytes between the header and the ids of a correlated request
+    fn prefix_size(&self) -> usize {
+        let window_size = if self.has_output_window() {
+            OUTPUT_WINDOW_SIZE
+        } else {
+            0
+        };
+        let budget_size = if self.has_time_budget() {
+            TIME_BUDGET_SIZE
+        } else {
+            0
+        };
+        self.threshold_size() + window_size + budget_size
+    }
+
+    /// Normalization the images of this request get, given the profile of
+    /// the model they go to.
+    pub fn normalization(&self, model: &Normalization) -> Normalization {
+        input_normalization(self.flags(), model)
+    }
+}
+
+/// Normalization of images sent with the request `flags` to a model with the
+/// profile `model`.
+pub fn input_normalization(flags: u32, model: &Normalization) -> Normalization {
+    if flags & FLAG_NO_NORMALIZE != 0 {
+        Normalization::IDENTITY
+    } else if flags & FLAG_RAW_SCALE != 0 {
+        Normalization::UNIT
+    } else {
+        *model
+    }
+}
+
+/// Row of the input tensor `image` becomes under `normalization`, as
+/// little-endian f32. Applies the same f32 operations in the same order as
+/// the model code, so it matches the TA's tensor bit for bit unless the
+/// preprocessing of the two drifted apart.
+pub fn input_tensor_bytes(image: &Image, normalization: &Normalization) -> Vec<u8> {
+    image
+        .as_bytes()
+        .iter()
+        .flat_map(|&pixel| {
+            let value =
+                (pixel as f32 / normalization.scale - normalization.mean) / normalization.std;
+            value.to_le_bytes()
+        })
+        .collect()
+}
+
+/// Bytes the ids of a correlated request of `batch` images take: 4 per
+/// image, rounded up to whole images so the header still tells a request
+/// apart from a bare image array.
+pub fn correlation_block_size(batch: usize) -> Option<usize> {
+    let ids = batch.checked_mul(size_of::<u32>())?;
+    ids.div_ceil(IMAGE_SIZE).checked_mul(IMAGE_SIZE)
+}
+
+/// Converts a temperature to the fixed point used on the wire.
+pub fn fixed_point_temperature(temperature: f32) -> Result<u32, RequestError> {
+    // Rounds to nearest; `f32::round` isn't available without std
+    let scaled = temperature * TEMPERATURE_SCALE as f32 + 0.5;
+    if !scaled.is_finite() || scaled < 1.0 || scaled >= u32::MAX as f32 {
+        return Err(RequestError::InvalidTemperature);
+    }
+    Ok(scaled as u32)
+}
+
+/// Converts a reject threshold (a probability) to the fixed point used on
+/// the wire.
+pub fn fixed_point_threshold(threshold: f32) -> Result<u32, RequestError> {
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(RequestError::InvalidThreshold);
+    }
+    Ok(fixed_point::to_fixed(threshold, MAX_REJECT_THRESHOLD))
+}
+
+/// Splits an inference input buffer into its optional header and the image
+/// bytes. Buffers whose length is a whole number of images are legacy
+/// requests without a header; any other length is rejected. On success the
+/// image bytes are always a whole number of images.
+pub fn split_request(
+    bytes: &[u8],
+) -> Result<(Option<InferenceRequestHeader>, &[u8]), RequestError> {
+    const HEADER_SIZE: usize = size_of::<InferenceRequestHeader>();
+    if bytes.len() % IMAGE_SIZE == 0 {
+        return Ok((None, bytes));
+    }
+    let header: InferenceRequestHeader = wire::read(bytes).ok_or(RequestError::BatchMismatch)?;
+    header.validate()?;
+    let prefix_size = header.prefix_size();
+    if bytes.len() % IMAGE_SIZE != (HEADER_SIZE + prefix_size) % IMAGE_SIZE {
+        return Err(RequestError::BatchMismatch);
+    }
+    if reject_threshold(bytes, &header).is_some_and(|threshold| threshold > MAX_REJECT_THRESHOLD) {
+        return Err(RequestError::InvalidThreshold);
+    }
+    if output_window(bytes, &header).is_some_and(|window| {
+        window.count == 0
+            || window
+                .start
+                .checked_add(window.count)
+                .is_none_or(|end| end > header.batch_len())
+    }) {
+        return Err(RequestError::InvalidWindow);
+    }
+    let ids_size = if header.is_correlated() {
+        correlation_block_size(header.batch_len() as usize).ok_or(RequestError::BatchMismatch)?
+    } else {
+        0
+    };
+    let images = bytes
+        .get(HEADER_SIZE + prefix_size + ids_size..)
+        .ok_or(RequestError::BatchMismatch)?;
+    if Some(images.len()) != (header.batch_len() as usize).checked_mul(IMAGE_SIZE) {
+        return Err(RequestError::BatchMismatch);
+    }
+    Ok((Some(header), images))
+}
+
+/// Ids of a request `split_request` accepted with `header`; `None` unless
+/// the request is correlated.
+pub fn correlation_ids(bytes: &[u8], header: &InferenceRequestHeader) -> Option<Vec<u32>> {
+    if !header.is_correlated() {
+        return None;
+    }
+    let start = size_of::<InferenceRequestHeader>() + header.prefix_size();
+    let end = start.checked_add((header.batch_len() as usize).checked_mul(size_of::<u32>())?)?;
+    let ids = bytes.get(start..end)?;
+    Some(
+        ids.chunks_exact(size_of::<u32>())
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            .collect(),
+    )
+}
+
+/// Threshold of a request `split_request` accepted with `header`, in
+/// thousandths; `None` without `FLAG_REJECT_BELOW`.
+pub fn reject_threshold(bytes: &[u8], header: &InferenceRequestHeader) -> Option<u32> {
+    if !header.has_reject_threshold() {
+        return None;
+    }
+    let start = size_of::<InferenceRequestHeader>();
+    let threshold = bytes.get(start..start + REJECT_THRESHOLD_SIZE)?;
+    Some(u32::from_le_bytes([
+        threshold[0],
+        threshold[1],
+        threshold[2],
+        threshold[3],
+    ]))
+}
+
+/// Window of a request `split_request` accepted with `header`; `None`
+/// without `FLAG_OUTPUT_WINDOW`.
+pub fn output_window(bytes: &[u8], header: &InferenceRequestHeader) -> Option<OutputWindow> {
+    if !header.has_output_window() {
+        return None;
+    }
+    let start = size_of::<InferenceRequestHeader>() + header.threshold_size();
+    let window = bytes.get(start..start + OUTPUT_WINDOW_SIZE)?;
+    Some(OutputWindow {
+        start: u32::from_le_bytes([window[0], window[1], window[2], window[3]]),
+        count: u32::from_le_bytes([window[4], window[5], window[6], window[7]]),
+    })
+}
+
+/// Time budget of a request `split_request` accepted with `header`, in
+/// milliseconds; `None` without `FLAG_TIME_BUDGET`.
+pub fn time_budget(bytes: &[u8], header: &InferenceRequestHeader) -> Option<u32> {
+    if !header.has_time_budget() {
+        return None;
+    }
+    // A windowed request has no budget, so only the threshold comes before it
+    let start = size_of::<InferenceRequestHeader>() + header.threshold_size();
+    let budget = bytes.get(start..start + TIME_BUDGET_SIZE)?;
+    Some(u32::from_le_bytes([
+        budget[0], budget[1], budget[2], budget[3],
+    ]))
+}
+
+/// Per-image result returned when `FLAG_PREDICTIONS` is set.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct Prediction {
+    /// Most probable class, or `REJECT_LABEL` when its confidence is below
+    /// the threshold of the request.
+    pub label: u8,
+    /// Most probable class even when rejected; TAs predating
+    /// `CAP_REJECT_THRESHOLD` leave it 0.
+    pub candidate: u8,
+    /// Softmax probability of `label`, in thousandths (little-endian u16).
+    confidence_milli: Le16,
+}
+
+impl WireRecord for Prediction {}
+
+const _: () = assert!(size_of::<Prediction>() == 4);
+const _: () = assert!(core::mem::align_of::<Prediction>() == 1);
+const _: () = assert!(core::mem::offset_of!(Prediction, candidate) == 1);
+const _: () = assert!(core::mem::offset_of!(Prediction, confidence_milli) == 2);
+
+// Top bit of `confidence_milli`, which never exceeds 1000
+const PREDICTION_TIED: u16 = 1 << 15;
+
+/// Scores within this distance of the largest one count as tied with it.
+/// Probabilities closer than this are indistinguishable in a `Prediction`
+/// anyway.
+pub const ARGMAX_TIE_TOLERANCE: f32 = 1e-6;
+
+/// The class of a row of probabilities, as the TA and the host reference
+/// path pick it: among the classes within `ARGMAX_TIE_TOLERANCE` of the
+/// largest probability, the lowest index wins. Returns that index and
+/// whether another class was tied with it, `None` for an empty row. NaNs
+/// never win; a row of only NaNs answers class 0.
+pub fn top_class(probs: &[f32]) -> Option<(usize, bool)> {
+    if probs.is_empty() {
+        return None;
+    }
+    let max = probs
+        .iter()
+        .copied()
+        .filter(|p| !p.is_nan())
+        .fold(f32::NEG_INFINITY, f32::max);
+    let mut tied = probs
+        .iter()
+        .enumerate()
+        .filter(|&(_, &p)| p >= max - ARGMAX_TIE_TOLERANCE)
+        .map(|(index, _)| index);
+    let index = tied.next().unwrap_or(0);
+    Some((index, tied.next().is_some()))
+}
+
+impl Prediction {
+    /// Returns `None` unless `confidence` is a probability.
+    pub fn new(label: u8, confidence: f32) -> Option<Self> {
+        if !(0.0..=1.0).contains(&confidence) {
+            return None;
+        }
+        Some(Self {
+            label,
+            candidate: label,
+            confidence_milli: Le16::new(fixed_point::to_fixed(confidence, 1000) as u16),
+        })
+    }
+
+    /// The prediction of one image from its row of probabilities, its class
+    /// picked by `top_class` and marked as tied when `mark_ties` is set and
+    /// it was. `None` for an empty row or a class that doesn't fit in a byte.
+    pub fn from_probabilities(probs: &[f32], mark_ties: bool) -> Option<Self> {
+        let (index, tied) = top_class(probs)?;
+        let prediction = Self::new(u8::try_from(index).ok()?, probs[index].clamp(0.0, 1.0))?;
+        Some(if mark_ties && tied {
+            prediction.mark_tied()
+        } else {
+            prediction
+        })
+    }
+
+    /// Softmax probability of `label`, in thousandths.
+    pub fn confidence_milli(&self) -> u16 {
+        self.confidence_milli.get() & !PREDICTION_TIED
+    }
+
+    /// Records that `label` was picked among tied classes.
+    pub fn mark_tied(self) -> Self {
+        Self {
+            confidence_milli: Le16::new(self.confidence_milli.get() | PREDICTION_TIED),
+            ..self
+        }
+    }
+
+    /// Whether another class had the same probability as `label` (within
+    /// `ARGMAX_TIE_TOLERANCE`) and lost to it for its higher index. Only set
+    /// in answers to `FLAG_MARK_TIES` requests.
+    pub fn is_tied(&self) -> bool {
+        self.confidence_milli.get() & PREDICTION_TIED != 0
+    }
+
+    pub fn confidence(&self) -> f32 {
+        self.confidence_milli() as f32 / 1000.0
+    }
+
+    /// Replaces the label by `REJECT_LABEL` when the confidence is below
+    /// `threshold` (in thousandths); `candidate` keeps the class.
+    pub fn with_threshold(self, threshold: u32) -> Self {
+        if (self.confidence_milli() as u32) < threshold {
+            Self {
+                label: REJECT_LABEL,
+                ..self
+            }
+        } else {
+            self
+        }
+    }
+
+    /// Only meaningful for `FLAG_REJECT_BELOW` requests, which models with
+    /// a class 255 can't take.
+    pub fn is_rejected(&self) -> bool {
+        self.label == REJECT_LABEL
+    }
+}
+
+/// Per-image result of a request with `FLAG_CORRELATION`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Pod, Zeroable)]
+pub struct CorrelatedPrediction {
+    /// Id the host sent for the image (little-endian u32).
+    id: Le32,
+    pub prediction: Prediction,
+}
+
+impl WireRecord for CorrelatedPrediction {}
+
+const _: () = assert!(size_of::<CorrelatedPrediction>() == 8);
+const _: () = assert!(core::mem::align_of::<CorrelatedPrediction>() == 1);
+const _: () = assert!(core::mem::offset_of!(CorrelatedPrediction, prediction) == 4);
+
+impl CorrelatedPrediction {
+    pub fn new(id: u32, prediction: Prediction) -> Self {
+        Self {
+            id: Le32::new(id),
+            prediction,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrelationError {
+    /// A different number of results than ids sent.
+    Length { sent: usize, received: usize },
+    /// An id came back more than once.
+    Duplicate(u32),
+    /// The result at `index` carries another image's id.
+    Mismatch {
+        index: usize,
+        sent: u32,
+        received: u32,
+    },
+}
+
+impl fmt::Display for CorrelationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorrelationError::Length { sent, received } => {
+                write!(f, "sent {sent} ids, got {received} results")
+            }
+            CorrelationError::Duplicate(id) => write!(f, "id {id} returned twice"),
+            CorrelationError::Mismatch {
+                index,
+                sent,
+                received,
+            } => write!(f, "result {index} has id {received}, sent {sent}"),
+        }
+    }
+}
+
+/// Checks that `received` answers the images with the ids `sent`, in order.
+pub fn check_correlation(
+    sent: &[u32],
+    received: &[CorrelatedPrediction],
+) -> Result<(), CorrelationError> {
+    if sent.len() != received.len() {
+        return Err(CorrelationError::Length {
+            sent: sent.len(),
+            received: received.len(),
+        });
+    }
+    let mut seen = alloc::collections::BTreeSet::new();
+    for (index, (&sent, result)) in sent.iter().zip(received).enumerate() {
+        if !seen.insert(result.id()) {
+            return Err(CorrelationError::Duplicate(result.id()));
+        }
+        if result.id() != sent {
+            return Err(CorrelationError::Mismatch {
+                index,
+                sent,
+                received: result.id(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Upper bound of the serialized `ModelStatus` returned by the TA.
+pub const MAX_MODEL_STATUS_SIZE: usize = 16 * 1024;
+
+/// Upper bound of the `Command::GetClassLabels` output.
+pub const MAX_CLASS_LABELS_SIZE: usize = 4 * 1024;
+
+/// Output of `Command::GetClassLabels`: every label as a little-endian u16
+/// byte length followed by its UTF-8 bytes. `None` when the result would
+/// exceed `MAX_CLASS_LABELS_SIZE`.
+pub fn encode_class_labels(labels: &[String]) -> Option<Vec<u8>> {
+    let mut encoded = Vec::new();
+    for label in labels {
+        let len = u16::try_from(label.len()).ok()?;
+        encoded.extend_from_slice(&len.to_le_bytes());
+        encoded.extend_from_slice(label.as_bytes());
+        if encoded.len() > MAX_CLASS_LABELS_SIZE {
+            return None;
+        }
+    }
+    Some(encoded)
+}
+
+/// Inverse of `encode_class_labels`; `None` on truncated or non-UTF-8 input.
+pub fn decode_class_labels(mut bytes: &[u8]) -> Option<Vec<String>> {
+    let mut labels = Vec::new();
+    while !bytes.is_empty() {
+        let (len, rest) = bytes.split_first_chunk::<2>()?;
+        let len = u16::from_le_bytes(*len) as usize;
+        let label = rest.get(..len)?;
+        labels.push(String::from(core::str::from_utf8(label).ok()?));
+        bytes = &rest[len..];
+    }
+    Some(labels)
+}
+
+/// Size in bytes of the probabilities returned for `batch` images: one
+/// little-endian f32 per class and image.
+pub fn probabilities_size(batch: usize, num_classes: usize) -> usize {
+    batch * num_classes * core::mem::size_of::<f32>()
+}
+
+/// `probabilities_size` of a `FLAG_FIXED_POINT_PROBS` request: one
+/// little-endian u16 per class and image.
+pub fn fixed_point_probabilities_size(batch: usize, num_classes: usize) -> usize {
+    batch * num_classes * core::mem::size_of::<u16>()
+}
+
+/// Size in bytes of the `FLAG_INPUT_HASHES` hashes of `batch` images.
+pub fn input_hashes_size(batch: usize) -> usize {
+    batch * INPUT_HASH_SIZE
+}
+
+/// Reply of `Command::ModelStatus`, serialized as JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelStatus {
+    pub loaded: bool,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub class_labels: Vec<String>,
+    /// Width of the model output, i.e. probabilities per image.
+    #[serde(default = "default_num_classes")]
+    pub num_classes: usize,
+    /// Provisioning record of the model kept in secure storage (slot 0 only).
+    #[serde(default)]
+    pub stored: Option<StoredModelInfo>,
+    /// Model waiting for `Command::CommitModel` (slot 0 only).
+    #[serde(default)]
+    pub staged: Option<StagedModelInfo>,
+    /// License of the installed model, if it carries one.
+    #[serde(default)]
+    pub license: Option<LicenseStatus>,
+    /// Canary images the installed model classified correctly at install.
+    #[serde(default)]
+    pub canaries: usize,
+    /// Input normalization of the installed model; TAs that predate
+    /// profiles always apply `Normalization::MNIST`.
+    #[serde(default)]
+    pub normalization: Normalization,
+    /// Images per inference call that suit the installed model, `None`
+    /// when the slot is empty or the TA predates batch hints.
+    #[serde(default)]
+    pub batch_hint: Option<BatchHint>,
+}
+
+/// Batch sizes the TA derives from its memory budget and the installed
+/// model's footprint estimate, which the host can't see.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchHint {
+    /// Images per call that leave headroom for the allocator and buffers.
+    pub recommended: usize,
+    /// Most images per call that fit the budget next to the model.
+    pub max: usize,
+}
+
+/// A model uploaded with `Command::StageModel`: decrypted and checked, kept
+/// in secure storage, but not installed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StagedModelInfo {
+    /// Hex SHA-256 of the decrypted model container.
+    pub model_hash: String,
+    /// Hex SHA-256 of the encrypted blob.
+    pub encrypted_hash: String,
+    pub encrypted_size: u32,
+    /// Host clock (seconds since the Unix epoch) at staging, 0 when unknown.
+    pub staged: u64,
+}
+
+/// Written to secure storage when a model is provisioned, so status queries
+/// don't have to reassemble and hash the stored blob.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StoredModelInfo {
+    /// Hex SHA-256 of the decrypted model container.
+    pub model_hash: String,
+    /// Hex SHA-256 of the encrypted blob, tying this record to the stored
+    /// version it describes.
+    pub encrypted_hash: String,
+    pub plaintext_size: u32,
+    pub encrypted_size: u32,
+    /// Layer widths, see `UnifiedModel::architecture`.
+    pub arch: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub class_labels: Vec<String>,
+    /// First 8 bytes of the SHA-256 of the AES key, hex.
+    #[serde(default)]
+    pub key_fingerprint: Option<String>,
+    /// Host clock (seconds since the Unix epoch) at provisioning, 0 when
+    /// unknown.
+    pub provisioned: u64,
+}
+
+fn default_num_classes() -> usize {
+    crate::DEFAULT_NUM_CLASSES
+}
+
+impl ModelStatus {
+    /// Human readable name of `class`, falling back to the numeric label.
+    pub fn label_name(&self, class: u8) -> String {
+        match self.class_labels.get(class as usize) {
+            Some(name) => name.clone(),
+            None => alloc::format!("{class}"),
+        }
+    }
+}
+
+/// Upper bound of the serialized `CrashReport` returned by the TA.
+pub const MAX_CRASH_REPORT_SIZE: usize = 4 * 1024;
+
+/// Where and why the TA last panicked, kept in secure storage until cleared.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    /// `file:line:column` of the panic, empty when unknown.
+    pub location: String,
+    /// Panic message, truncated by the TA.
+    pub message: String,
+    /// REE clock at the panic, seconds since the Unix epoch.
+    pub time: u64,
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.location.is_empty() {
+            write!(f, "panicked: {}", self.message)
+        } else {
+            write!(f, "panicked at {}: {}", self.location, self.message)
+        }
+    }
+}
+
+/// Upper bound of the serialized `StorageObject` list returned by the TA.
+pub const MAX_STORAGE_LIST_SIZE: usize = 64 * 1024;
+
+/// Id prefixes of the secure storage objects `Command::DeleteStorageObject`
+/// may remove.
+pub const DELETABLE_STORAGE_PREFIXES: &[&str] = &["ta_model.", "ta_stats."];
+
+/// One secure storage object owned by the TA, as listed by
+/// `Command::ListStorage` (a JSON array of these).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StorageObject {
+    /// Object id; bytes that aren't valid UTF-8 are replaced.
+    pub id: String,
+    pub data_size: u32,
+    /// `TEE_DATA_FLAG_*` bits the object was created with.
+    pub flags: u32,
+}
+
+/// Whether `id` may be removed through `Command::DeleteStorageObject`.
+pub fn is_deletable_storage_id(id: &[u8]) -> bool {
+    DELETABLE_STORAGE_PREFIXES
+        .iter()
+        .any(|prefix| id.len() > prefix.len() && id.starts_with(prefix.as_bytes()))
+}
+
+/// Upper bound of the serialized `ModelUsage` returned by the TA.
+pub const MAX_MODEL_USAGE_SIZE: usize = 16 * 1024;
+
+/// Inference counters of one model.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct UsageCounters {
+    pub invocations: u64,
+    pub images: u64,
+    /// Predictions per class.
+    pub class_histogram: Vec<u64>,
+    /// Host clock (seconds since the Unix epoch) at the last inference, 0
+    /// when the host didn't report one.
+    pub last_used: u64,
+    /// Images answered from the result cache.
+    #[serde(default)]
+    pub cache_hits: u64,
+    /// Images the result cache didn't have, while it was on.
+    #[serde(default)]
+    pub cache_misses: u64,
+    /// Time the TA spent serving inference requests, failed ones included.
+    #[serde(default)]
+    pub inference_time: DurationMicros,
+}
+
+/// A duration in whole microseconds, serialized as `{ "us": n }` so the unit
+/// travels with the number. Conversions from `Duration` saturate.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct DurationMicros {
+    us: u64,
+}
+
+impl DurationMicros {
+    pub const ZERO: Self = Self { us: 0 };
+
+    pub const fn from_micros(us: u64) -> Self {
+        Self { us }
+    }
+
+    pub const fn from_millis(ms: u64) -> Self {
+        Self {
+            us: ms.saturating_mul(1000),
+        }
+    }
+
+    pub const fn as_micros(self) -> u64 {
+        self.us
+    }
+
+    /// Seconds, the unit Prometheus expects.
+    pub fn as_secs_f64(self) -> f64 {
+        self.us as f64 / 1e6
+    }
+
+    pub const fn saturating_add(self, other: Self) -> Self {
+        Self {
+            us: self.us.saturating_add(other.us),
+        }
+    }
+
+    pub const fn saturating_sub(self, other: Self) -> Self {
+        Self {
+            us: self.us.saturating_sub(other.us),
+        }
+    }
+}
+
+impl From<core::time::Duration> for DurationMicros {
+    fn from(duration: core::time::Duration) -> Self {
+        Self {
+            us: u64::try_from(duration.as_micros()).unwrap_or(u64::MAX),
+        }
+    }
+}
+
+impl From<DurationMicros> for core::time::Duration {
+    fn from(duration: DurationMicros) -> Self {
+        core::time::Duration::from_micros(duration.us)
+    }
+}
+
+impl core::ops::AddAssign for DurationMicros {
+    fn add_assign(&mut self, other: Self) {
+        *self = self.saturating_add(other);
+    }
+}
+
+/// Formats with a unit suffix, scaled to us, ms or s.
+impl fmt::Display for DurationMicros {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.us {
+            us if us < 1_000 => write!(f, "{us} us"),
+            us if us < 1_000_000 => write!(f, "{:.2} ms", us as f64 / 1e3),
+            us => write!(f, "{:.2} s", us as f64 / 1e6),
+        }
+    }
+}
+
+/// Reply of `Command::GetPersistentStats`, serialized as JSON. Lifetime
+/// counters are kept in secure storage per model hash and survive reboots
+/// and reinstalls of the same model.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ModelUsage {
+    pub since_load: UsageCounters,
+    pub lifetime: UsageCounters,
+    /// TA heap at the time of the query, `None` from TAs that don't count it.
+    #[serde(default)]
+    pub heap: Option<HeapUsage>,
+}
+
+/// Heap bytes the TA's allocator has handed out, as requested sizes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapUsage {
+    pub current: usize,
+    /// High-water mark since the TA instance started.
+    pub peak: usize,
+    /// Configured heap size (TA_DATA_SIZE).
+    pub size: usize,
+}
+
+/// Upper bound of the serialized `ModelVersion` list returned by the TA.
+pub const MAX_MODEL_HISTORY_SIZE: usize = 16 * 1024;
+
+/// One encrypted model kept in secure storage, as listed by
+/// `Command::ModelHistory` (a JSON array, newest first).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelVersion {
+    /// Hex SHA-256 of the encrypted blob.
+    pub hash: String,
+    pub size: u32,
+    /// Host clock (seconds since the Unix epoch) when the model was
+    /// provisioned, 0 when unknown.
+    pub provisioned: u64,
+    /// Whether this is the version loaded after a restart.
+    pub active: bool,
+}
+
+/// Layout version of the secure storage this build of the TA reads and
+/// writes. The first session of a TA instance migrates older layouts to it,
+/// one version at a time.
+pub const STORAGE_SCHEMA_VERSION: u32 = 2;
+/// Upper bound of the serialized `StorageSchema` returned by the TA.
+pub const MAX_STORAGE_SCHEMA_SIZE: usize = 256;
+
+/// Reply of `Command::GetSchemaVersion`, serialized as JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StorageSchema {
+    /// Layout version of the stored objects, `STORAGE_SCHEMA_VERSION` of the
+    /// TA once a session is open.
+    pub version: u32,
+    /// Version the storage had before the TA last migrated it, `None` when
+    /// it was created in its current layout.
+    pub migrated_from: Option<u32>,
+    /// Trusted time of that migration, seconds since the Unix epoch (0 when
+    /// the TA had no time yet).
+    pub migrated_at: Option<u64>,
+}
+
+/// Upper bound of the serialized `BuildManifest` returned by the TA.
+pub const MAX_BUILD_MANIFEST_SIZE: usize = 1024;
+
+/// Cargo features of the TA that hosts check a `BuildManifest` for.
+pub const FEATURE_ENCRYPT_MODEL: &str = "encrypt-model";
+pub const FEATURE_DEFLATE: &str = "deflate";
+pub const FEATURE_DEBUG_PANIC: &str = "debug-panic";
+pub const FEATURE_BUILTIN_CRYPTO: &str = "builtin-crypto";
+
+/// Reply of `Command::GetBuildManifest`, serialized as JSON: what the TA
+/// was compiled with, fixed for the lifetime of the binary.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BuildManifest {
+    /// Enabled Cargo features of the TA crate, sorted.
+    pub features: Vec<String>,
+    /// TA heap (TA_DATA_SIZE) in bytes.
+    pub heap_size: usize,
+    /// Largest estimated model footprint finalize accepts, in bytes.
+    pub model_memory_budget: usize,
+    /// Secure storage granted to the TA in bytes, 0 when unknown.
+    pub storage_quota: usize,
+    /// Earlier model versions kept for rollback.
+    pub model_history: usize,
+    /// Largest chunk `Command::PushEncryptedChunk` accepts, in bytes.
+    pub max_push_chunk_size: usize,
+}
+
+impl BuildManifest {
+    /// Whether the TA was built with the Cargo feature `feature`.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+/// Upper bound of the serialized `ManagePolicy` the TA accepts.
+pub const MAX_MANAGE_POLICY_SIZE: usize = 2 * 1024;
+/// Clients a `ManagePolicy` may list.
+pub const MAX_MANAGE_CLIENTS: usize = 16;
+
+/// Clients allowed to open `SessionRole::Manage` sessions, kept by the TA in
+/// secure storage once provisioned with `Command::SetManagePolicy`. Without
+/// one any client may.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ManagePolicy {
+    pub clients: Vec<ManageClient>,
+}
+
+/// A TEE client identity: the login method (`TEEC_LOGIN_*`, e.g. 0 for
+/// public or 0xF0000000 for another TA) and the client UUID.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManageClient {
+    pub login: u32,
+    pub uuid: String,
+}
+
+/// Upper bound of the serialized `ShadowReport` returned by the TA.
+pub const MAX_SHADOW_REPORT_SIZE: usize = 8 * 1024;
+/// Disagreeing images a `ShadowReport` keeps examples of.
+pub const MAX_SHADOW_EXAMPLES: usize = 16;
+
+/// An image on which the active and the shadow slot disagreed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowExample {
+    /// Hex SHA-256 of the image's pixels as sent.
+    pub input_hash: String,
+    pub active_label: u8,
+    pub shadow_label: u8,
+}
+
+/// Reply of `Command::GetShadowReport`, serialized as JSON: what the shadow
+/// slot answered since shadowing was set up or the report last reset.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ShadowReport {
+    /// Active and shadow slot, `None` when shadowing is off.
+    pub slots: Option<(u32, u32)>,
+    /// Images both slots ran.
+    pub compared: u64,
+    /// Images on which their labels differ.
+    pub disagreements: u64,
+    /// Images not shadowed because of `FLAG_SKIP_SHADOW`.
+    pub skipped: u64,
+    /// Images the shadow slot couldn't run (empty, expired or failing).
+    pub failed: u64,
+    /// The first `MAX_SHADOW_EXAMPLES` disagreements.
+    pub examples: Vec<ShadowExample>,
+}
+
+impl ShadowReport {
+    /// Share of the compared images the slots disagree on.
+    pub fn disagreement_rate(&self) -> f64 {
+        if self.compared == 0 {
+            0.0
+        } else {
+            self.disagreements as f64 / self.compared as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every command and whether it needs a manage session; a command added
+    // to `Command` without a row here fails `table_covers_every_command`
+    const MANAGE: &[(Command, bool)] = &[
+        (Command::Infer, false),
+        (Command::EncryptModel, true),
+        (Command::DecryptModel, true),
+        (Command::StoreKey, true),
+        (Command::BeginModelLoad, true),
+        (Command::PushEncryptedChunk, true),
+        (Command::FinalizeModelLoad, true),
+        (Command::ExportAesKey, true),
+        (Command::ModelStatus, false),
+        (Command::InferEnsemble, false),
+        (Command::PatchModel, true),
+        (Command::ListStorage, false),
+        (Command::DeleteStorageObject, true),
+        (Command::GetPersistentStats, false),
+        (Command::ResetPersistentStats, true),
+        (Command::StoragePreflight, false),
+        (Command::RollbackModel, true),
+        (Command::ModelHistory, false),
+        (Command::RebindStorage, false),
+        (Command::RunSelfTest, false),
+        (Command::SetResidencyPolicy, true),
+        (Command::GetClassLabels, false),
+        (Command::StageModel, true),
+        (Command::CommitModel, true),
+        (Command::DiscardStaged, true),
+        (Command::GetLastCrash, false),
+        (Command::DebugPanic, true),
+        (Command::BeginModelExport, true),
+        (Command::ReadEncryptedChunk, true),
+        (Command::EndModelExport, true),
+        (Command::SetTraceId, false),
+        (Command::SetShadow, true),
+        (Command::GetShadowReport, false),
+        (Command::SetResultCache, true),
+        (Command::SetModelVerifyKey, true),
+        (Command::GetSchemaVersion, false),
+        (Command::GetBuildManifest, false),
+        (Command::RotateKey, true),
+        (Command::SetManagePolicy, true),
+        (Command::GetKeyFingerprint, false),
+    ];
+
+    #[test]
+    fn table_covers_every_command() {
+        let ids: Vec<u32> = (0..=u8::MAX as u32)
+            .filter(|&id| Command::try_from(id).is_ok())
+            .collect();
+        let listed: Vec<u32> = MANAGE.iter().map(|&(cmd, _)| cmd.into()).collect();
+        assert_eq!(listed, ids);
+    }
+
+    #[test]
+    fn role_command_matrix() {
+        for &(cmd, manage) in MANAGE {
+            assert_eq!(cmd.needs_manage(), manage, "{cmd:?}");
+            assert!(SessionRole::Manage.may_run(cmd, 0), "{cmd:?}");
+            assert_eq!(SessionRole::Infer.may_run(cmd, 0), !manage, "{cmd:?}");
+        }
+    }
+
+    #[test]
+    fn clearing_report_queries_need_manage() {
+        for (cmd, clear) in [
+            (Command::GetLastCrash, CRASH_REPORT_CLEAR),
+            (Command::GetShadowReport, SHADOW_REPORT_RESET),
+        ] {
+            assert!(SessionRole::Infer.may_run(cmd, 0));
+            assert!(SessionRole::Infer.may_run(cmd, !clear));
+            assert!(!SessionRole::Infer.may_run(cmd, clear));
+            assert!(SessionRole::Manage.may_run(cmd, clear));
+        }
+        // Other commands ignore the flags
+        for &(cmd, manage) in MANAGE {
+            if !matches!(cmd, Command::GetLastCrash | Command::GetShadowReport) {
+                assert_eq!(cmd.needs_manage_with(u32::MAX), manage, "{cmd:?}");
+            }
+        }
+    }
+
+    const PARAM_TYPES: [ParamType; 7] = [
+        ParamType::None,
+        ParamType::ValueInput,
+        ParamType::ValueOutput,
+        ParamType::ValueInout,
+        ParamType::MemrefInput,
+        ParamType::MemrefOutput,
+        ParamType::MemrefInout,
+    ];
+
+    // The type a host passes for a parameter the command takes as `usage`
+    fn plain_type(usage: ParamUse) -> ParamType {
+        match usage {
+            ParamUse::ValueIn => ParamType::ValueInput,
+            ParamUse::ValueOut => ParamType::ValueOutput,
+            ParamUse::ValueInout => ParamType::ValueInout,
+            ParamUse::MemrefIn => ParamType::MemrefInput,
+            ParamUse::MemrefOut => ParamType::MemrefOutput,
+        }
+    }
+
+    // A well typed operation for `cmd`, its optional parameters passed
+    fn well_typed(cmd: Command) -> [ParamType; 4] {
+        cmd.signature().map(|spec| match spec {
+            ParamSpec::Unused => ParamType::None,
+            ParamSpec::Required(usage) | ParamSpec::Optional(usage) => plain_type(usage),
+        })
+    }
+
+    #[test]
+    fn well_typed_operations_pass() {
+        for &(cmd, _) in MANAGE {
+            assert_eq!(cmd.mistyped_param(well_typed(cmd)), None, "{cmd:?}");
+            // Optional parameters left out
+            let bare = cmd.signature().map(|spec| match spec {
+                ParamSpec::Required(usage) => plain_type(usage),
+                _ => ParamType::None,
+            });
+            assert_eq!(cmd.mistyped_param(bare), None, "{cmd:?}");
+        }
+    }
+
+    #[test]
+    fn mistyped_operations_name_the_parameter() {
+        for &(cmd, _) in MANAGE {
+            for (index, spec) in cmd.signature().into_iter().enumerate() {
+                for param_type in PARAM_TYPES {
+                    let mut types = well_typed(cmd);
+                    types[index] = param_type;
+                    let expected = (!spec.accepts(param_type)).then_some(index);
+                    assert_eq!(
+                        cmd.mistyped_param(types),
+                        expected,
+                        "{cmd:?} with {param_type:?} in parameter {index}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn values_and_memrefs_never_stand_in_for_each_other() {
+        for usage in [ParamUse::ValueIn, ParamUse::ValueOut, ParamUse::ValueInout] {
+            for param_type in [
+                ParamType::MemrefInput,
+                ParamType::MemrefOutput,
+                ParamType::MemrefInout,
+            ] {
+                assert!(!usage.accepts(param_type), "{usage:?} {param_type:?}");
+            }
+        }
+        for usage in [ParamUse::MemrefIn, ParamUse::MemrefOut] {
+            for param_type in [
+                ParamType::ValueInput,
+                ParamType::ValueOutput,
+                ParamType::ValueInout,
+            ] {
+                assert!(!usage.accepts(param_type), "{usage:?} {param_type:?}");
+            }
+        }
+        // Inout fits either direction, but an inout value needs one
+        assert!(ParamUse::MemrefIn.accepts(ParamType::MemrefInout));
+        assert!(ParamUse::MemrefOut.accepts(ParamType::MemrefInout));
+        assert!(ParamUse::ValueOut.accepts(ParamType::ValueInout));
+        assert!(!ParamUse::ValueInout.accepts(ParamType::ValueInput));
+        assert!(!ParamUse::MemrefOut.accepts(ParamType::MemrefInput));
+    }
+
+    #[test]
+    fn unused_parameters_must_be_none() {
+        assert_eq!(
+            Command::EndModelExport.mistyped_param([ParamType::None; 4]),
+            None
+        );
+        for index in 0..4 {
+            let mut types = [ParamType::None; 4];
+            types[index] = ParamType::ValueInput;
+            assert_eq!(Command::EndModelExport.mistyped_param(types), Some(index));
+        }
+        // The first offending parameter is reported
+        let types = [
+            ParamType::ValueInput,
+            ParamType::ValueInput,
+            ParamType::None,
+            ParamType::None,
+        ];
+        assert_eq!(Command::Infer.mistyped_param(types), Some(0));
+    }
+
+    #[test]
+    fn export_ranges() {
+        const MAX: usize = MAX_EXPORT_CHUNK_SIZE;
+        assert_eq!(export_chunk(0, 10, 10), Some(0..10));
+        assert_eq!(export_chunk(9, 1, 10), Some(9..10));
+        assert_eq!(export_chunk(0, MAX, MAX + 1), Some(0..MAX));
+        // Zero-length, even at the end
+        assert_eq!(export_chunk(0, 0, 10), None);
+        assert_eq!(export_chunk(10, 0, 10), None);
+        // Past the end
+        assert_eq!(export_chunk(9, 2, 10), None);
+        assert_eq!(export_chunk(10, 1, 10), None);
+        assert_eq!(export_chunk(0, 1, 0), None);
+        assert_eq!(export_chunk(usize::MAX, 1, 10), None);
+        assert_eq!(export_chunk(1, usize::MAX, 10), None);
+        // Longer than one chunk
+        assert_eq!(export_chunk(0, MAX + 1, 2 * MAX), None);
+        // Ranges may be read again
+        assert_eq!(export_chunk(2, 4, 10), Some(2..6));
+        assert_eq!(export_chunk(4, 4, 10), Some(4..8));
+    }
+
+    #[test]
+    fn export_chunks_cover_every_byte_once() {
+        const MAX: usize = MAX_EXPORT_CHUNK_SIZE;
+        assert_eq!(export_chunks(0).count(), 0);
+        for size in [1, 10, MAX - 1, MAX, MAX + 1, 3 * MAX - 1, 3 * MAX] {
+            let mut next = 0;
+            for chunk in export_chunks(size) {
+                // Consecutive, so none overlaps the one before
+                assert_eq!(chunk.start, next, "{size}");
+                assert_eq!(
+                    export_chunk(chunk.start, chunk.len(), size),
+                    Some(chunk.clone()),
+                    "{size}"
+                );
+                next = chunk.end;
+            }
+            assert_eq!(next, size);
+            assert_eq!(export_chunks(size).count(), size.div_ceil(MAX), "{size}");
+        }
+    }
+
+    // Bytes of `InferenceRequestHeader::new(2, flags, 1.0)`
+    fn header_bytes(flags: u32) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..4].copy_from_slice(&REQUEST_MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&2u32.to_le_bytes());
+        bytes[8..12].copy_from_slice(&flags.to_le_bytes());
+        bytes[12..].copy_from_slice(&TEMPERATURE_SCALE.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn request_header_wire_layout() {
+        let header = InferenceRequestHeader::new(2, FLAG_PREDICTIONS, 1.0).unwrap();
+        assert_eq!(wire::bytes_of(&header), header_bytes(FLAG_PREDICTIONS));
+        let read = wire::read::<InferenceRequestHeader>(&header_bytes(FLAG_PREDICTIONS)).unwrap();
+        assert_eq!(read.validate(), Ok(()));
+        assert_eq!(read.batch_len(), 2);
+        assert!(read.wants_predictions());
+        assert_eq!(read.temperature(), 1.0);
+    }
+
+    #[test]
+    fn malformed_headers_are_refused() {
+        let check = |bytes: [u8; 16]| {
+            wire::read::<InferenceRequestHeader>(&bytes)?
+                .validate()
+                .err()
+        };
+        let mut bytes = header_bytes(0);
+        bytes[0] ^= 1;
+        assert_eq!(check(bytes), Some(RequestError::InvalidHeader));
+        let mut bytes = header_bytes(0);
+        bytes[4..8].fill(0);
+        assert_eq!(check(bytes), Some(RequestError::EmptyBatch));
+        let unknown = 1 << (!KNOWN_FLAGS).trailing_zeros();
+        assert_eq!(
+            check(header_bytes(unknown)),
+            Some(RequestError::UnknownFlags(unknown))
+        );
+        assert_eq!(
+            check(header_bytes(FLAG_RAW_SCALE | FLAG_NO_NORMALIZE)),
+            Some(RequestError::ConflictingFlags)
+        );
+        assert_eq!(
+            check(header_bytes(FLAG_TIME_BUDGET | FLAG_OUTPUT_WINDOW)),
+            Some(RequestError::WindowedBudget)
+        );
+        let mut bytes = header_bytes(0);
+        bytes[12..].fill(0);
+        assert_eq!(check(bytes), Some(RequestError::InvalidTemperature));
+    }
+
+    #[test]
+    fn prediction_fields() {
+        let prediction = Prediction::new(3, 0.9994).unwrap();
+        assert_eq!((prediction.label, prediction.candidate), (3, 3));
+        assert_eq!(prediction.confidence_milli(), 999);
+        assert_eq!(Prediction::new(3, 1.0).unwrap().confidence_milli(), 1000);
+        assert_eq!(Prediction::new(3, 0.0).unwrap().confidence_milli(), 0);
+        assert!(Prediction::new(3, 1.01).is_none());
+        assert!(Prediction::new(3, -0.01).is_none());
+        assert!(Prediction::new(3, f32::NAN).is_none());
+        let tied = prediction.mark_tied();
+        assert!(tied.is_tied() && !prediction.is_tied());
+        assert_eq!(tied.confidence_milli(), 999);
+    }
+
+    #[test]
+    fn top_class_breaks_ties_by_lowest_index() {
+        assert_eq!(top_class(&[]), None);
+        assert_eq!(top_class(&[0.5]), Some((0, false)));
+        assert_eq!(top_class(&[0.1, 0.6, 0.3]), Some((1, false)));
+        // Exact ties, first, last and in between
+        assert_eq!(top_class(&[0.4, 0.4, 0.2]), Some((0, true)));
+        assert_eq!(top_class(&[0.2, 0.4, 0.4]), Some((1, true)));
+        assert_eq!(top_class(&[0.3, 0.1, 0.3, 0.3]), Some((0, true)));
+        assert_eq!(top_class(&[0.25; 4]), Some((0, true)));
+        // Within the tolerance of the maximum counts as tied, past it not
+        let near = 0.5 - ARGMAX_TIE_TOLERANCE / 2.0;
+        assert_eq!(top_class(&[0.0, near, 0.5]), Some((1, true)));
+        assert_eq!(top_class(&[0.0, 0.49, 0.5]), Some((2, false)));
+        // NaNs never win or tie
+        assert_eq!(top_class(&[f32::NAN, 0.2, 0.1]), Some((1, false)));
+        assert_eq!(top_class(&[0.2, f32::NAN, 0.2]), Some((0, true)));
+        assert_eq!(top_class(&[f32::NAN; 3]), Some((0, false)));
+    }
+
+    #[test]
+    fn ties_are_marked_only_on_request() {
+        let tied = [0.1, 0.45, 0.45];
+        let marked = Prediction::from_probabilities(&tied, true).unwrap();
+        assert_eq!((marked.label, marked.is_tied()), (1, true));
+        assert_eq!(marked.confidence_milli(), 450);
+        let unmarked = Prediction::from_probabilities(&tied, false).unwrap();
+        assert_eq!((unmarked.label, unmarked.is_tied()), (1, false));
+        let clear = Prediction::from_probabilities(&[0.1, 0.2, 0.7], true).unwrap();
+        assert_eq!((clear.label, clear.is_tied()), (2, false));
+        assert!(Prediction::from_probabilities(&[], true).is_none());
+        // Class indices past a byte have no prediction
+        let mut wide = [0.0; 300];
+        wide[299] = 1.0;
+        assert!(Prediction::from_probabilities(&wide, false).is_none());
+    }
+
+    #[test]
+    fn session_roles() {
+        assert_eq!(SessionRole::default(), SessionRole::Infer);
+        assert_eq!(SessionRole::try_from(1), Ok(SessionRole::Infer));
+        assert_eq!(SessionRole::try_from(2), Ok(SessionRole::Manage));
+        assert!(SessionRole::try_from(0).is_err());
+        assert!(SessionRole::try_from(3).is_err());
+    }
+
+    #[test]
+    fn trace_ids_split_into_low_and_high_halves() {
+        assert_eq!(
+            split_trace_id(0x0123_4567_89ab_cdef),
+            (0x89ab_cdef, 0x0123_4567)
+        );
+        for id in [0, 1, 0xffff_ffff, 1 << 32, u64::MAX] {
+            let (low, high) = split_trace_id(id);
+            assert_eq!(join_trace_id(low, high), id);
+        }
+    }
+
+    #[test]
+    fn error_details_round_trip_within_their_buffer() {
+        let mut buffer = [0_u8; ERROR_DETAIL_BUFFER_SIZE];
+        let detail = "object model/active: TEE_ERROR_STORAGE_NO_SPACE (0xffff3041)";
+        let size = write_error_detail(detail, &mut buffer);
+        assert_eq!(size, ERROR_DETAIL_TAG.len() + detail.len());
+        assert_eq!(parse_error_detail(&buffer[..size]), Some(detail));
+
+        // Long details are cut to the limit, and to the buffer, at a
+        // character boundary
+        let long = "é".repeat(MAX_ERROR_DETAIL_SIZE);
+        let size = write_error_detail(&long, &mut buffer);
+        let parsed = parse_error_detail(&buffer[..size]).unwrap();
+        assert_eq!(parsed.len(), MAX_ERROR_DETAIL_SIZE);
+        assert!(long.starts_with(parsed));
+        let mut small = [0_u8; 7];
+        let size = write_error_detail(&long, &mut small);
+        assert_eq!(parse_error_detail(&small[..size]), Some("é"));
+        // Nothing is written where not a character fits
+        assert_eq!(write_error_detail(&long, &mut small[..5]), 0);
+        assert_eq!(write_error_detail(detail, &mut small[..3]), 0);
+        assert_eq!(write_error_detail("", &mut buffer), 0);
+    }
+
+    #[test]
+    fn only_tagged_details_parse() {
+        assert_eq!(parse_error_detail(b"EDT:bad header"), Some("bad header"));
+        assert_eq!(parse_error_detail(b""), None);
+        assert_eq!(parse_error_detail(b"EDT:"), None);
+        // Whatever else an output buffer may hold
+        assert_eq!(parse_error_detail(b"{\"labels\":[1]}"), None);
+        assert_eq!(parse_error_detail(b"EDT:\xff\xfe"), None);
+        let mut oversized = ERROR_DETAIL_TAG.to_vec();
+        oversized.resize(ERROR_DETAIL_BUFFER_SIZE + 1, b'x');
+        assert_eq!(parse_error_detail(&oversized), None);
+    }
+
+    #[test]
+    fn build_manifests_fit_their_buffer() {
+        let manifest = BuildManifest {
+            features: [
+                FEATURE_ENCRYPT_MODEL,
+                FEATURE_DEFLATE,
+                FEATURE_DEBUG_PANIC,
+                FEATURE_BUILTIN_CRYPTO,
+            ]
+            .iter()
+            .map(|f| String::from(*f))
+            .collect(),
+            heap_size: usize::MAX,
+            model_memory_budget: usize::MAX,
+            storage_quota: usize::MAX,
+            model_history: usize::MAX,
+            max_push_chunk_size: usize::MAX,
+        };
+        let json = serde_json::to_vec(&manifest).unwrap();
+        assert!(json.len() <= MAX_BUILD_MANIFEST_SIZE);
+        let decoded: BuildManifest = serde_json::from_slice(&json).unwrap();
+        assert_eq!(decoded, manifest);
+        assert!(decoded.has_feature(FEATURE_DEFLATE));
+        assert!(!decoded.has_feature("deflat"));
+    }
+
+    #[test]
+    fn durations_carry_their_unit() {
+        use alloc::string::ToString;
+        use core::time::Duration;
+
+        let encoded = serde_json::to_string(&DurationMicros::from_micros(1500)).unwrap();
+        assert_eq!(encoded, r#"{"us":1500}"#);
+        assert_eq!(
+            serde_json::from_str::<DurationMicros>(r#"{"us":42}"#).unwrap(),
+            DurationMicros::from_micros(42)
+        );
+        // A bare number has no unit and is refused
+        assert!(serde_json::from_str::<DurationMicros>("42").is_err());
+        // Counters from TAs without timing read as no time spent
+        let counters: UsageCounters = serde_json::from_str(
+            r#"{"invocations":1,"images":2,"class_histogram":[],"last_used":0}"#,
+        )
+        .unwrap();
+        assert_eq!(counters.inference_time, DurationMicros::ZERO);
+
+        assert_eq!(DurationMicros::from_millis(3).as_micros(), 3000);
+        assert_eq!(DurationMicros::from_millis(u64::MAX).as_micros(), u64::MAX);
+        assert_eq!(DurationMicros::from_micros(2_500_000).as_secs_f64(), 2.5);
+        assert_eq!(
+            DurationMicros::from(Duration::from_nanos(1999)),
+            DurationMicros::from_micros(1)
+        );
+        assert_eq!(
+            DurationMicros::from(Duration::MAX),
+            DurationMicros::from_micros(u64::MAX)
+        );
+        assert_eq!(
+            Duration::from(DurationMicros::from_micros(7)),
+            Duration::from_micros(7)
+        );
+        let mut total = DurationMicros::from_micros(u64::MAX - 1);
+        total += DurationMicros::from_micros(5);
+        assert_eq!(total.as_micros(), u64::MAX);
+
+        for (us, printed) in [
+            (0, "0 us"),
+            (999, "999 us"),
+            (1_000, "1.00 ms"),
+            (1_500, "1.50 ms"),
+            (2_500_000, "2.50 s"),
+        ] {
+            assert_eq!(DurationMicros::from_micros(us).to_string(), printed);
+        }
+    }
+}
@@ -25,6 +25,33 @@ pub const AES_BLOCK_SIZE: usize = 16;
 pub const AES_KEY_OBJECT_ID: &[u8] = b"km.aes.default";
 pub const RSA_KEY_OBJECT_ID: &[u8] = b"km.rsa.default";
 
+// Capability bits a key manager reports in the second value of `HasAesKey`.
+// Peers that predate them leave it untouched (0). A separate query command is
+// avoided because older peers map unknown commands to `GenerateAesKey`.
+pub const KM_CAP_AES_MULTI: u32 = 1 << 0;
+// The second value of the `HasAesKey` reply also carries, in b, the input
+// size the key manager prefers per `EncryptAesChunk`/`DecryptAesChunk`
+pub const KM_CAP_CHUNK_SIZE: u32 = 1 << 1;
+// `RsaVerify` is implemented. Callers must check for it: older peers would
+// run `GenerateAesKey` instead and replace the AES key
+pub const KM_CAP_RSA_VERIFY: u32 = 1 << 2;
+
+/// Bytes per `EncryptAesChunk`/`DecryptAesChunk` for a key manager that
+/// replied `capabilities` and `advertised` to `HasAesKey`: its advertised
+/// size in whole AES blocks, or `CHUNK_SIZE` when it doesn't report one.
+pub fn chunk_size(capabilities: u32, advertised: u32) -> usize {
+    if capabilities & KM_CAP_CHUNK_SIZE == 0 {
+        return crate::CHUNK_SIZE;
+    }
+    (advertised as usize / AES_BLOCK_SIZE * AES_BLOCK_SIZE).max(AES_BLOCK_SIZE)
+}
+
+/// RSA key sizes accepted for signature verification.
+pub const MIN_RSA_BITS: usize = 2048;
+pub const MAX_RSA_BITS: usize = 4096;
+/// Upper bound of a DER-encoded RSA public key of `MAX_RSA_BITS`.
+pub const MAX_RSA_PUBLIC_KEY_SIZE: usize = 1024;
+
 #[repr(u32)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Command {
@@ -38,6 +65,15 @@ pub enum Command {
     GenerateRsaKey = 7,
     ImportRsaKey = 8,
     ExportRsaPublic = 9,
+    // Same params as the chunk commands, but the input may span the whole
+    // buffer; the IV is chained inside the key manager
+    EncryptAesMulti = 10,
+    DecryptAesMulti = 11,
+    // Checks an RSASSA-PKCS1-v1_5 SHA-256 signature: public key (DER, see
+    // `parse_rsa_public_key`) in param 0, the 32-byte digest in param 1, the
+    // signature in param 2, all memref inputs. Fails with SignatureInvalid
+    // on a mismatch
+    RsaVerify = 12,
 }
 
 impl From<u32> for Command {
@@ -53,7 +89,136 @@ impl From<u32> for Command {
             7 => Command::GenerateRsaKey,
             8 => Command::ImportRsaKey,
             9 => Command::ExportRsaPublic,
+            10 => Command::EncryptAesMulti,
+            11 => Command::DecryptAesMulti,
+            12 => Command::RsaVerify,
             _ => Command::GenerateAesKey, // default fallback, caller should guard
         }
     }
 }
+
+/// Modulus and public exponent of an RSA public key, big-endian without
+/// leading zeros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RsaPublicKey<'a> {
+    pub modulus: &'a [u8],
+    pub exponent: &'a [u8],
+}
+
+impl RsaPublicKey<'_> {
+    pub fn bits(&self) -> usize {
+        match self.modulus.first() {
+            Some(&top) => self.modulus.len() * 8 - top.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+}
+
+// rsaEncryption, 1.2.840.113549.1.1.1
+const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// Parses a DER RSA public key, either a SubjectPublicKeyInfo (`openssl
+/// rsa -pubout -outform DER`) or a bare PKCS#1 RSAPublicKey, of
+/// `MIN_RSA_BITS` to `MAX_RSA_BITS`. Trailing bytes are refused.
+pub fn parse_rsa_public_key(der: &[u8]) -> Option<RsaPublicKey<'_>> {
+    if der.len() > MAX_RSA_PUBLIC_KEY_SIZE {
+        return None;
+    }
+    let (body, rest) = der_element(der, 0x30)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    let key = match der_element(body, 0x30) {
+        // SubjectPublicKeyInfo: AlgorithmIdentifier, then the PKCS#1 key in
+        // a BIT STRING without unused bits
+        Some((algorithm, rest)) => {
+            let (oid, _params) = der_element(algorithm, 0x06)?;
+            if oid != RSA_ENCRYPTION_OID {
+                return None;
+            }
+            let (bits, rest) = der_element(rest, 0x03)?;
+            if !rest.is_empty() || bits.first() != Some(&0) {
+                return None;
+            }
+            let (body, rest) = der_element(&bits[1..], 0x30)?;
+            if !rest.is_empty() {
+                return None;
+            }
+            rsa_public_key_body(body)?
+        }
+        None => rsa_public_key_body(body)?,
+    };
+    (MIN_RSA_BITS..=MAX_RSA_BITS)
+        .contains(&key.bits())
+        .then_some(key)
+}
+
+// SEQUENCE body of a PKCS#1 RSAPublicKey: INTEGER modulus, INTEGER exponent
+fn rsa_public_key_body(body: &[u8]) -> Option<RsaPublicKey<'_>> {
+    let (modulus, rest) = der_element(body, 0x02)?;
+    let (exponent, rest) = der_element(rest, 0x02)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(RsaPublicKey {
+        modulus: der_unsigned(modulus)?,
+        exponent: der_unsigned(exponent)?,
+    })
+}
+
+// Content of the element with `tag` at the start of `der` and what follows
+// it. Only definite lengths of up to two bytes, which covers every key size
+// accepted.
+fn der_element(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, rest) = der.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&len, rest) = rest.split_first()?;
+    let (len, rest) = match len {
+        0..=0x7f => (len as usize, rest),
+        0x81 => {
+            let (&len, rest) = rest.split_first()?;
+            (len as usize, rest)
+        }
+        0x82 => {
+            let bytes = rest.get(..2)?;
+            (u16::from_be_bytes([bytes[0], bytes[1]]) as usize, &rest[2..])
+        }
+        _ => return None,
+    };
+    if len > rest.len() {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+// A positive INTEGER without its sign padding; negative ones are refused
+fn der_unsigned(int: &[u8]) -> Option<&[u8]> {
+    match int {
+        [] => None,
+        [first, ..] if first & 0x80 != 0 => None,
+        [0, rest @ ..] if !rest.is_empty() => Some(rest),
+        _ => Some(int),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CHUNK_SIZE;
+
+    #[test]
+    fn chunk_sizes_follow_the_key_manager() {
+        // Key managers that don't report one get the default
+        assert_eq!(chunk_size(0, 0), CHUNK_SIZE);
+        assert_eq!(chunk_size(KM_CAP_AES_MULTI, 4096), CHUNK_SIZE);
+        // Smaller and larger than the default
+        assert_eq!(chunk_size(KM_CAP_CHUNK_SIZE, 1024), 1024);
+        assert_eq!(chunk_size(KM_CAP_CHUNK_SIZE, 4 << 20), 4 << 20);
+        const { assert!(CHUNK_SIZE > 1024 && CHUNK_SIZE < 4 << 20) };
+        // Whole AES blocks, at least one
+        assert_eq!(chunk_size(KM_CAP_CHUNK_SIZE, 1000), 992);
+        assert_eq!(chunk_size(KM_CAP_CHUNK_SIZE, 0), AES_BLOCK_SIZE);
+    }
+}
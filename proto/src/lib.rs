@@ -16,15 +16,251 @@
 // under the License.
 
 #![no_std]
+extern crate alloc;
+
+use bytemuck::{Pod, Zeroable};
+use core::fmt::{self, Write};
+
+pub mod fixed_point;
+pub mod framing;
 pub mod inference;
 pub mod key_manager;
+#[cfg(feature = "model-file")]
+pub mod model_file;
+pub mod test_vectors;
+pub mod wire;
 
 pub const IMAGE_HEIGHT: usize = 28;
 pub const IMAGE_WIDTH: usize = 28;
 pub const IMAGE_CHANNELS: usize = 1;
 pub const IMAGE_SIZE: usize = IMAGE_HEIGHT * IMAGE_WIDTH * IMAGE_CHANNELS;
-pub const NUM_CLASSES: usize = 10;
-pub type Image = [u8; IMAGE_SIZE];
+/// Class count of models that don't say otherwise (MNIST digits).
+pub const DEFAULT_NUM_CLASSES: usize = 10;
+/// Labels travel as one byte per image, which caps the class count.
+pub const MAX_NUM_CLASSES: usize = u8::MAX as usize + 1;
+
+/// A grayscale input image, one byte per pixel in row-major order, exactly
+/// as it travels to the TA. Built from checked bytes with
+/// [`Image::from_bytes`] or from pixels with [`Image::from_luma28`]; the
+/// wire module casts buffers of them in place.
+#[repr(transparent)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Pod, Zeroable)]
+pub struct Image(pub(crate) [u8; IMAGE_SIZE]);
+
+impl Image {
+    /// All pixels black.
+    pub const BLANK: Self = Self([0; IMAGE_SIZE]);
+
+    /// The 28x28 luma pixels `pixels`, row by row.
+    pub const fn from_luma28(pixels: &[u8; IMAGE_SIZE]) -> Self {
+        Self(*pixels)
+    }
+
+    /// Copies `bytes`, which must be exactly `IMAGE_SIZE` long.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let pixels = bytes
+            .try_into()
+            .map_err(|_| ImageError::InvalidLength(bytes.len()))?;
+        Ok(Self(pixels))
+    }
+
+    pub const fn as_bytes(&self) -> &[u8; IMAGE_SIZE] {
+        &self.0
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8; IMAGE_SIZE] {
+        &mut self.0
+    }
+}
+
+/// ASCII art of the image, one line per row and denser characters for
+/// brighter pixels, for looking at inputs in a terminal.
+impl fmt::Display for Image {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SHADES: &[u8] = b" .:-=+*#%@";
+        for (y, row) in self.0.chunks_exact(IMAGE_WIDTH).enumerate() {
+            if y > 0 {
+                f.write_char('\n')?;
+            }
+            for &pixel in row {
+                let shade = SHADES[pixel as usize * SHADES.len() / 256];
+                f.write_char(shade as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why bytes were rejected by [`Image::from_bytes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// Not `IMAGE_SIZE` bytes long.
+    InvalidLength(usize),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::InvalidLength(len) => {
+                write!(f, "image must be {IMAGE_SIZE} bytes, got {len}")
+            }
+        }
+    }
+}
 
-// Chunked encryption constants
+impl core::error::Error for ImageError {}
+
+/// Key manager chunk size used with key managers that don't advertise one
+/// (`key_manager::KM_CAP_CHUNK_SIZE`).
 pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Why a TA UUID string was rejected by [`parse_uuid`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UuidError {
+    /// Not 36 characters after trimming whitespace.
+    InvalidLength(usize),
+    /// Character at this offset is not a hex digit or a misplaced hyphen.
+    InvalidCharacter(usize),
+}
+
+impl fmt::Display for UuidError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UuidError::InvalidLength(len) => {
+                write!(f, "UUID must be 36 characters, got {len}")
+            }
+            UuidError::InvalidCharacter(pos) => {
+                write!(f, "invalid character in UUID at offset {pos}")
+            }
+        }
+    }
+}
+
+/// Trims surrounding whitespace (uuid.txt files usually end with a newline)
+/// and checks the canonical 8-4-4-4-12 hex form. Returns the trimmed string,
+/// ready for the `Uuid::parse_str` of optee-teec or optee-utee.
+pub fn parse_uuid(s: &str) -> Result<&str, UuidError> {
+    let s = s.trim();
+    if s.len() != 36 {
+        return Err(UuidError::InvalidLength(s.len()));
+    }
+    for (i, c) in s.bytes().enumerate() {
+        let valid = match i {
+            8 | 13 | 18 | 23 => c == b'-',
+            _ => c.is_ascii_hexdigit(),
+        };
+        if !valid {
+            return Err(UuidError::InvalidCharacter(i));
+        }
+    }
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn uuids_are_trimmed_and_checked() {
+        let uuid = "8f6fde7b-5bd2-4f34-ae8c-d7cd1a3f6744";
+        assert_eq!(parse_uuid(uuid), Ok(uuid));
+        assert_eq!(parse_uuid(&(uuid.to_string() + "\n")), Ok(uuid));
+        assert_eq!(parse_uuid(&alloc::format!(" {uuid}\r\n")), Ok(uuid));
+        assert_eq!(parse_uuid(""), Err(UuidError::InvalidLength(0)));
+        assert_eq!(parse_uuid(&uuid[1..]), Err(UuidError::InvalidLength(35)));
+        assert_eq!(
+            parse_uuid("8f6fde7b-5bd2-4f34-ae8c-d7cd1a3f674g"),
+            Err(UuidError::InvalidCharacter(35))
+        );
+        // Hyphens only between the groups
+        assert_eq!(
+            parse_uuid("8f6fde7b5-bd2-4f34-ae8c-d7cd1a3f6744"),
+            Err(UuidError::InvalidCharacter(8))
+        );
+        assert_eq!(
+            parse_uuid("8f6fde7b-5bd2-4f34-ae8c-d7cd-a3f6744"),
+            Err(UuidError::InvalidCharacter(28))
+        );
+        // The compiled-in ones, read from files ending in a newline or not
+        assert!(parse_uuid(inference::UUID).is_ok());
+        assert!(parse_uuid(key_manager::UUID).is_ok());
+    }
+
+    #[test]
+    fn from_bytes_takes_exactly_one_image() {
+        let bytes: [u8; IMAGE_SIZE] = core::array::from_fn(|i| i as u8);
+        let image = Image::from_bytes(&bytes).unwrap();
+        assert_eq!(image.as_bytes(), &bytes);
+        assert_eq!(image, Image::from_luma28(&bytes));
+        for len in [0, 1, IMAGE_SIZE - 1, IMAGE_SIZE + 1, 2 * IMAGE_SIZE] {
+            let bytes = [0; 2 * IMAGE_SIZE];
+            assert_eq!(
+                Image::from_bytes(&bytes[..len]),
+                Err(ImageError::InvalidLength(len))
+            );
+        }
+    }
+
+    #[test]
+    fn from_luma28_keeps_pixels_in_row_order() {
+        let mut pixels = [0; IMAGE_SIZE];
+        pixels[IMAGE_WIDTH + 2] = 200;
+        let mut image = Image::from_luma28(&pixels);
+        assert_eq!(image.as_bytes()[IMAGE_WIDTH + 2], 200);
+        image.as_bytes_mut()[0] = 1;
+        assert_eq!(image.as_bytes()[0], 1);
+        assert_eq!(Image::from_luma28(&[0; IMAGE_SIZE]), Image::BLANK);
+    }
+
+    #[test]
+    fn display_snapshot() {
+        let pixels = core::array::from_fn(|i| {
+            let (y, x) = (i / IMAGE_WIDTH, i % IMAGE_WIDTH);
+            match (y, x) {
+                (0, _) => (x * 255 / 27) as u8,
+                (10..14, 12..16) => 255,
+                (20, 4..24) => 128,
+                _ => 0,
+            }
+        });
+        let expected = [
+            "   ...:::--===+++***##%%%@@@",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "            @@@@            ",
+            "            @@@@            ",
+            "            @@@@            ",
+            "            @@@@            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "    ++++++++++++++++++++    ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+            "                            ",
+        ];
+        assert_eq!(Image::from_luma28(&pixels).to_string(), expected.join("\n"));
+    }
+
+    #[test]
+    fn blank_image_is_all_spaces() {
+        let text = Image::BLANK.to_string();
+        assert_eq!(text.lines().count(), IMAGE_HEIGHT);
+        assert!(text.lines().all(|line| line == " ".repeat(IMAGE_WIDTH)));
+    }
+}
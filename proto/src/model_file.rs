@@ -0,0 +1,513 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Strict reader of the encrypted model files written by `encrypt-model` and
+// `export-model`: `{"algorithm", "encrypted_data"}`, or the chunked layout
+// `{"algorithm", "chunk_size", "total_chunks", "original_size", "chunks"}`,
+// either with an optional `"signature"`.
+// The files come from users and the network, so every field is checked and
+// nothing is sized from a declared count before the data backs it.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::Deserialize;
+
+use crate::inference::MAX_MODEL_SIGNATURE_SIZE;
+use crate::key_manager::AES_BLOCK_SIZE;
+
+/// The only cipher encrypted model files use.
+pub const ALGORITHM: &str = "AES-256-CBC";
+/// Largest ciphertext (IV included) a model file may carry.
+pub const MAX_CIPHERTEXT_SIZE: usize = 32 * 1024 * 1024;
+/// Largest model file read. Bytes are JSON numbers, which pretty-printed
+/// inside the chunked layout take up to 14 bytes each.
+pub const MAX_MODEL_FILE_SIZE: u64 = MAX_CIPHERTEXT_SIZE as u64 * 14;
+/// Most chunks a chunked file may declare.
+pub const MAX_CHUNKS: usize = 4096;
+
+/// Why an encrypted model file was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelFileError {
+    /// The file is larger than `MAX_MODEL_FILE_SIZE`.
+    FileTooLarge(u64),
+    /// Not JSON of either layout, or a field of the wrong type or unknown
+    /// name; the parser's message.
+    Malformed(String),
+    /// An `algorithm` other than `ALGORITHM`.
+    UnknownAlgorithm(String),
+    /// Neither `encrypted_data` nor the chunked fields, or fields of both.
+    AmbiguousLayout,
+    /// `total_chunks` doesn't match the chunks present.
+    ChunkCount { declared: usize, actual: usize },
+    /// More than `MAX_CHUNKS` chunks.
+    TooManyChunks(usize),
+    /// A chunk id is repeated or out of `0..total_chunks`.
+    ChunkId(usize),
+    /// A chunk's `size` doesn't match its data, or exceeds `chunk_size`.
+    ChunkSize { id: usize, size: usize, data: usize },
+    /// `original_size` isn't the sum of the chunk sizes.
+    OriginalSize { declared: usize, actual: usize },
+    /// The ciphertext is over `MAX_CIPHERTEXT_SIZE`, shorter than an IV and
+    /// one block, or not whole AES blocks.
+    CiphertextLength(usize),
+    /// A `signature` that is empty or over `MAX_MODEL_SIGNATURE_SIZE`.
+    SignatureLength(usize),
+}
+
+impl fmt::Display for ModelFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileTooLarge(size) => write!(
+                f,
+                "model file of {size} bytes, at most {MAX_MODEL_FILE_SIZE} accepted"
+            ),
+            Self::Malformed(err) => write!(f, "malformed model file: {err}"),
+            Self::UnknownAlgorithm(algorithm) => {
+                write!(
+                    f,
+                    "unsupported algorithm {algorithm:?}, expected {ALGORITHM}"
+                )
+            }
+            Self::AmbiguousLayout => {
+                f.write_str("model file needs either encrypted_data or chunks, not both or neither")
+            }
+            Self::ChunkCount { declared, actual } => {
+                write!(
+                    f,
+                    "total_chunks is {declared} but the file has {actual} chunks"
+                )
+            }
+            Self::TooManyChunks(count) => {
+                write!(f, "{count} chunks, at most {MAX_CHUNKS} accepted")
+            }
+            Self::ChunkId(id) => write!(f, "chunk id {id} is repeated or out of range"),
+            Self::ChunkSize { id, size, data } => write!(
+                f,
+                "chunk {id} declares {size} bytes but holds {data} (or exceeds chunk_size)"
+            ),
+            Self::OriginalSize { declared, actual } => write!(
+                f,
+                "original_size is {declared} but the chunks hold {actual} bytes"
+            ),
+            Self::CiphertextLength(len) => write!(
+                f,
+                "ciphertext of {len} bytes isn't an IV and whole AES blocks of at most \
+                 {MAX_CIPHERTEXT_SIZE} bytes"
+            ),
+            Self::SignatureLength(len) => write!(
+                f,
+                "signature of {len} bytes, expected 1 to {MAX_MODEL_SIGNATURE_SIZE}"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for ModelFileError {}
+
+/// Ciphertext of an encrypted model file, as parsed by [`parse`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedModel {
+    pub algorithm: String,
+    /// Chunk count and original size of a chunked file.
+    pub chunks: Option<(usize, usize)>,
+    /// IV followed by the CBC ciphertext.
+    pub ciphertext: Vec<u8>,
+    /// RSA signature of the ciphertext for TAs with a verify key, see
+    /// `inference::CAP_MODEL_SIGNATURES`.
+    pub signature: Option<Vec<u8>>,
+}
+
+// Both layouts in one struct, so a single pass with unknown fields denied
+// tells them apart without buffering the document
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawFile {
+    algorithm: String,
+    #[serde(default)]
+    encrypted_data: Option<Vec<u8>>,
+    #[serde(default)]
+    chunk_size: Option<usize>,
+    #[serde(default)]
+    total_chunks: Option<usize>,
+    #[serde(default)]
+    original_size: Option<usize>,
+    #[serde(default)]
+    chunks: Option<Vec<RawChunk>>,
+    #[serde(default)]
+    signature: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawChunk {
+    id: usize,
+    size: usize,
+    data: Vec<u8>,
+}
+
+/// Parses and checks the JSON of an encrypted model file, single or
+/// chunked. Callers reading from disk check the size against
+/// `MAX_MODEL_FILE_SIZE` first.
+pub fn parse(json: &[u8]) -> Result<EncryptedModel, ModelFileError> {
+    if json.len() as u64 > MAX_MODEL_FILE_SIZE {
+        return Err(ModelFileError::FileTooLarge(json.len() as u64));
+    }
+    let file: RawFile =
+        serde_json::from_slice(json).map_err(|err| ModelFileError::Malformed(err.to_string()))?;
+    if file.algorithm != ALGORITHM {
+        return Err(ModelFileError::UnknownAlgorithm(file.algorithm));
+    }
+    let model = match file {
+        RawFile {
+            algorithm,
+            encrypted_data: Some(ciphertext),
+            chunk_size: None,
+            total_chunks: None,
+            original_size: None,
+            chunks: None,
+            signature,
+        } => EncryptedModel {
+            algorithm,
+            chunks: None,
+            ciphertext,
+            signature,
+        },
+        RawFile {
+            algorithm,
+            encrypted_data: None,
+            chunk_size: Some(chunk_size),
+            total_chunks: Some(total_chunks),
+            original_size: Some(original_size),
+            chunks: Some(chunks),
+            signature,
+        } => EncryptedModel {
+            algorithm,
+            chunks: Some((total_chunks, original_size)),
+            ciphertext: join_chunks(chunk_size, total_chunks, original_size, chunks)?,
+            signature,
+        },
+        _ => return Err(ModelFileError::AmbiguousLayout),
+    };
+    let len = model.ciphertext.len();
+    if !(2 * AES_BLOCK_SIZE..=MAX_CIPHERTEXT_SIZE).contains(&len) || len % AES_BLOCK_SIZE != 0 {
+        return Err(ModelFileError::CiphertextLength(len));
+    }
+    if let Some(signature) = &model.signature {
+        if signature.is_empty() || signature.len() > MAX_MODEL_SIGNATURE_SIZE {
+            return Err(ModelFileError::SignatureLength(signature.len()));
+        }
+    }
+    Ok(model)
+}
+
+// Checks the chunk table and concatenates the chunks in id order
+fn join_chunks(
+    chunk_size: usize,
+    total_chunks: usize,
+    original_size: usize,
+    mut chunks: Vec<RawChunk>,
+) -> Result<Vec<u8>, ModelFileError> {
+    if chunks.len() > MAX_CHUNKS {
+        return Err(ModelFileError::TooManyChunks(chunks.len()));
+    }
+    if total_chunks != chunks.len() {
+        return Err(ModelFileError::ChunkCount {
+            declared: total_chunks,
+            actual: chunks.len(),
+        });
+    }
+    chunks.sort_by_key(|chunk| chunk.id);
+    let mut total = 0_usize;
+    for (index, chunk) in chunks.iter().enumerate() {
+        // Sorted ids that match their positions are exactly 0..n, once each
+        if chunk.id != index {
+            return Err(ModelFileError::ChunkId(chunk.id));
+        }
+        if chunk.size != chunk.data.len() || chunk.size > chunk_size {
+            return Err(ModelFileError::ChunkSize {
+                id: chunk.id,
+                size: chunk.size,
+                data: chunk.data.len(),
+            });
+        }
+        total = total.saturating_add(chunk.size);
+    }
+    if total != original_size {
+        return Err(ModelFileError::OriginalSize {
+            declared: original_size,
+            actual: total,
+        });
+    }
+    if total > MAX_CIPHERTEXT_SIZE {
+        return Err(ModelFileError::CiphertextLength(total));
+    }
+    let mut ciphertext = Vec::with_capacity(total);
+    for chunk in chunks {
+        ciphertext.extend_from_slice(&chunk.data);
+    }
+    Ok(ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::vec;
+    use proptest::prelude::*;
+
+    fn bytes(data: &[u8]) -> String {
+        format!("{data:?}")
+    }
+
+    // What `encrypt-model` writes, pretty-printed alike
+    fn single_file(ciphertext: &[u8], signature: Option<&[u8]>) -> String {
+        let signature = signature
+            .map(|signature| format!(",\n  \"signature\": {}", bytes(signature)))
+            .unwrap_or_default();
+        format!(
+            "{{\n  \"algorithm\": \"{ALGORITHM}\",\n  \"encrypted_data\": {}{signature}\n}}",
+            bytes(ciphertext)
+        )
+    }
+
+    // The chunked layout, with `ciphertext` cut every `chunk_size` bytes
+    fn chunked_file(ciphertext: &[u8], chunk_size: usize) -> String {
+        let chunks: Vec<String> = ciphertext
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(id, data)| {
+                format!(
+                    "{{\"id\": {id}, \"size\": {}, \"data\": {}}}",
+                    data.len(),
+                    bytes(data)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"algorithm\": \"{ALGORITHM}\", \"chunk_size\": {chunk_size}, \
+             \"total_chunks\": {}, \"original_size\": {}, \"chunks\": [{}]}}",
+            chunks.len(),
+            ciphertext.len(),
+            chunks.join(", ")
+        )
+    }
+
+    fn ciphertext(blocks: usize) -> Vec<u8> {
+        (0..blocks * AES_BLOCK_SIZE).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn fixtures_parse() {
+        let data = ciphertext(4);
+        let model = parse(single_file(&data, None).as_bytes()).unwrap();
+        assert_eq!(model.ciphertext, data);
+        assert_eq!(model.chunks, None);
+        assert_eq!(model.signature, None);
+
+        let model = parse(single_file(&data, Some(&[1, 2, 3])).as_bytes()).unwrap();
+        assert_eq!(model.signature, Some(vec![1, 2, 3]));
+
+        let model = parse(chunked_file(&data, 24).as_bytes()).unwrap();
+        assert_eq!(model.ciphertext, data);
+        assert_eq!(model.chunks, Some((3, data.len())));
+    }
+
+    #[test]
+    fn chunks_are_joined_in_id_order() {
+        let json = format!(
+            "{{\"algorithm\": \"{ALGORITHM}\", \"chunk_size\": 16, \"total_chunks\": 2, \
+             \"original_size\": 32, \"chunks\": [\
+             {{\"id\": 1, \"size\": 16, \"data\": {}}}, \
+             {{\"id\": 0, \"size\": 16, \"data\": {}}}]}}",
+            bytes(&[1; 16]),
+            bytes(&[0; 16])
+        );
+        let model = parse(json.as_bytes()).unwrap();
+        assert_eq!(model.ciphertext[..16], [0; 16]);
+        assert_eq!(model.ciphertext[16..], [1; 16]);
+    }
+
+    #[test]
+    fn bad_fields_are_refused() {
+        let data = ciphertext(2);
+        let single = single_file(&data, None);
+
+        let unknown = single.replacen('{', "{\"extra\": 1, ", 1);
+        assert!(matches!(
+            parse(unknown.as_bytes()),
+            Err(ModelFileError::Malformed(_))
+        ));
+        let algorithm = single.replace(ALGORITHM, "AES-128-ECB");
+        assert_eq!(
+            parse(algorithm.as_bytes()),
+            Err(ModelFileError::UnknownAlgorithm("AES-128-ECB".into()))
+        );
+        let both = single.replacen('{', "{\"chunk_size\": 16, ", 1);
+        assert_eq!(parse(both.as_bytes()), Err(ModelFileError::AmbiguousLayout));
+        let neither = format!("{{\"algorithm\": \"{ALGORITHM}\"}}");
+        assert_eq!(
+            parse(neither.as_bytes()),
+            Err(ModelFileError::AmbiguousLayout)
+        );
+        assert_eq!(
+            parse(single_file(&data[..24], None).as_bytes()),
+            Err(ModelFileError::CiphertextLength(24))
+        );
+        assert_eq!(
+            parse(single_file(&data[..16], None).as_bytes()),
+            Err(ModelFileError::CiphertextLength(16))
+        );
+        assert_eq!(
+            parse(single_file(&data, Some(&[])).as_bytes()),
+            Err(ModelFileError::SignatureLength(0))
+        );
+        let long = vec![0; MAX_MODEL_SIGNATURE_SIZE + 1];
+        assert_eq!(
+            parse(single_file(&data, Some(&long)).as_bytes()),
+            Err(ModelFileError::SignatureLength(long.len()))
+        );
+    }
+
+    #[test]
+    fn bad_chunk_tables_are_refused() {
+        let data = ciphertext(2);
+        let chunked = chunked_file(&data, 16);
+
+        let count = chunked.replace("\"total_chunks\": 2", "\"total_chunks\": 3");
+        assert_eq!(
+            parse(count.as_bytes()),
+            Err(ModelFileError::ChunkCount {
+                declared: 3,
+                actual: 2
+            })
+        );
+        let repeated = chunked.replace("\"id\": 1", "\"id\": 0");
+        assert_eq!(parse(repeated.as_bytes()), Err(ModelFileError::ChunkId(0)));
+        let out_of_range = chunked.replace("\"id\": 1", "\"id\": 2");
+        assert_eq!(
+            parse(out_of_range.as_bytes()),
+            Err(ModelFileError::ChunkId(2))
+        );
+        let size = chunked.replacen("\"size\": 16", "\"size\": 15", 1);
+        assert_eq!(
+            parse(size.as_bytes()),
+            Err(ModelFileError::ChunkSize {
+                id: 0,
+                size: 15,
+                data: 16
+            })
+        );
+        let over = chunked.replace("\"chunk_size\": 16", "\"chunk_size\": 8");
+        assert_eq!(
+            parse(over.as_bytes()),
+            Err(ModelFileError::ChunkSize {
+                id: 0,
+                size: 16,
+                data: 16
+            })
+        );
+        let original = chunked.replace("\"original_size\": 32", "\"original_size\": 48");
+        assert_eq!(
+            parse(original.as_bytes()),
+            Err(ModelFileError::OriginalSize {
+                declared: 48,
+                actual: 32
+            })
+        );
+    }
+
+    // Whatever the input, a parse either fails or hands back a ciphertext
+    // the decryptor can take
+    fn check_invariants(json: &[u8]) -> Result<(), TestCaseError> {
+        if let Ok(model) = parse(json) {
+            let len = model.ciphertext.len();
+            prop_assert!((2 * AES_BLOCK_SIZE..=MAX_CIPHERTEXT_SIZE).contains(&len));
+            prop_assert_eq!(len % AES_BLOCK_SIZE, 0);
+            prop_assert_eq!(model.algorithm, ALGORITHM);
+            if let Some((total_chunks, original_size)) = model.chunks {
+                prop_assert!(total_chunks <= MAX_CHUNKS);
+                prop_assert_eq!(original_size, len);
+            }
+            if let Some(signature) = model.signature {
+                prop_assert!(!signature.is_empty());
+                prop_assert!(signature.len() <= MAX_MODEL_SIGNATURE_SIZE);
+            }
+        }
+        Ok(())
+    }
+
+    fn fixture() -> impl Strategy<Value = String> {
+        (2usize..=6, 1usize..=64, any::<bool>()).prop_map(|(blocks, chunk_size, chunked)| {
+            let data = ciphertext(blocks);
+            if chunked {
+                chunked_file(&data, chunk_size)
+            } else {
+                single_file(&data, Some(&[0xa5; 8]))
+            }
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn chunk_tables_round_trip(blocks in 2usize..=16, chunk_size in 1usize..=300) {
+            let data = ciphertext(blocks);
+            let model = parse(chunked_file(&data, chunk_size).as_bytes()).unwrap();
+            prop_assert_eq!(model.ciphertext, data);
+        }
+
+        #[test]
+        fn mutated_fixtures_never_panic(
+            json in fixture(),
+            edits in proptest::collection::vec(
+                (any::<prop::sample::Index>(), any::<u8>()),
+                1..=4,
+            ),
+        ) {
+            let mut json = json.into_bytes();
+            for (index, byte) in edits {
+                let index = index.index(json.len());
+                json[index] = byte;
+            }
+            check_invariants(&json)?;
+        }
+
+        #[test]
+        fn mutated_digits_never_panic(
+            json in fixture(),
+            index in any::<prop::sample::Index>(),
+            digit in b'0'..=b'9',
+        ) {
+            // Digits keep the JSON well formed, so the checks past the
+            // parser see the change
+            let mut json = json.into_bytes();
+            let digits: Vec<usize> = (0..json.len()).filter(|&i| json[i].is_ascii_digit()).collect();
+            json[digits[index.index(digits.len())]] = digit;
+            check_invariants(&json)?;
+        }
+
+        #[test]
+        fn truncated_fixtures_are_refused(
+            json in fixture(),
+            keep in any::<prop::sample::Index>(),
+        ) {
+            let keep = keep.index(json.len());
+            prop_assert!(parse(&json.as_bytes()[..keep]).is_err());
+        }
+    }
+}
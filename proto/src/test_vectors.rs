@@ -0,0 +1,120 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Known-answer vectors for the encrypted model format. The host encryptor,
+// the TA decrypt path and the TEE's AES must all reproduce them; the TA runs
+// them through `Command::RunSelfTest`.
+//
+// Every format version up to `MODEL_FORMAT_VERSION` needs at least one
+// vector, which the tests below check.
+
+/// Version of the encrypted model format: AES-256-CBC without padding over
+/// the `framing` of the plaintext, stored as `IV || ciphertext`.
+pub const MODEL_FORMAT_VERSION: u32 = 1;
+
+pub struct TestVector {
+    pub name: &'static str,
+    pub format_version: u32,
+    pub key: [u8; 32],
+    pub iv: [u8; 16],
+    pub payload: &'static [u8],
+    /// Encryption of the framed payload, without the IV.
+    pub ciphertext: &'static [u8],
+}
+
+pub const VECTORS: &[TestVector] = &[
+    TestVector {
+        name: "empty payload",
+        format_version: 1,
+        key: [0x00; 32],
+        iv: [0xa5; 16],
+        payload: b"",
+        ciphertext: &[
+            0x8b, 0xe8, 0xb9, 0x1a, 0x98, 0x91, 0xd9, 0x34, 0x41, 0x85, 0x58, 0x45, 0x8a, 0xda,
+            0xe1, 0x2d,
+        ],
+    },
+    TestVector {
+        name: "one block",
+        format_version: 1,
+        key: [0x11; 32],
+        iv: [0x22; 16],
+        payload: b"twelve bytes",
+        ciphertext: &[
+            0x67, 0xb3, 0x01, 0x85, 0xa5, 0x71, 0x49, 0x74, 0xb2, 0x85, 0x9d, 0x8c, 0x50, 0x37,
+            0x18, 0xad,
+        ],
+    },
+    TestVector {
+        name: "padded",
+        format_version: 1,
+        key: [
+            0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d,
+            0x0e, 0x0f, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b,
+            0x1c, 0x1d, 0x1e, 0x1f,
+        ],
+        iv: [
+            0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa, 0xfb, 0xfc, 0xfd,
+            0xfe, 0xff,
+        ],
+        payload: b"EMNM model record",
+        ciphertext: &[
+            0x2c, 0x46, 0x61, 0xe7, 0x77, 0x11, 0x06, 0x1d, 0x8b, 0x5c, 0x0e, 0xce, 0x94, 0x95,
+            0xab, 0x3c, 0x73, 0xe9, 0xf4, 0x33, 0x94, 0x59, 0x4e, 0xcb, 0x25, 0x1e, 0xf7, 0xdf,
+            0xbb, 0x61, 0xa7, 0xe6,
+        ],
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing;
+
+    fn has_vector(version: u32) -> bool {
+        VECTORS
+            .iter()
+            .any(|vector| vector.format_version == version)
+    }
+
+    #[test]
+    fn every_format_version_has_vectors() {
+        for version in 1..=MODEL_FORMAT_VERSION {
+            assert!(
+                has_vector(version),
+                "format version {version} has no test vectors"
+            );
+        }
+    }
+
+    #[test]
+    fn vectors_are_well_formed() {
+        for vector in VECTORS {
+            assert!(
+                (1..=MODEL_FORMAT_VERSION).contains(&vector.format_version),
+                "{}",
+                vector.name
+            );
+            assert_eq!(
+                Some(vector.ciphertext.len()),
+                framing::framed_len(vector.payload.len()),
+                "{}",
+                vector.name
+            );
+        }
+    }
+}
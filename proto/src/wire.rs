@@ -0,0 +1,178 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Byte layout of the fixed-size records exchanged with the TA as raw memory
+// (request header, predictions, images). Integer fields are kept as
+// little-endian byte arrays behind accessors, so every record has alignment
+// 1, no padding and the same bytes on any host; the layout is pinned by
+// compile-time assertions next to each record. Records go to and from
+// buffers through the helpers below, never through casts of native structs.
+
+use bytemuck::{Pod, Zeroable};
+use core::fmt;
+use core::mem::size_of;
+
+use crate::{Image, IMAGE_SIZE};
+
+/// A u16 stored little-endian.
+#[repr(transparent)]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct Le16([u8; 2]);
+
+impl Le16 {
+    pub const fn new(value: u16) -> Self {
+        Self(value.to_le_bytes())
+    }
+
+    pub const fn get(self) -> u16 {
+        u16::from_le_bytes(self.0)
+    }
+}
+
+impl fmt::Debug for Le16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+/// A u32 stored little-endian.
+#[repr(transparent)]
+#[derive(Copy, Clone, Default, PartialEq, Eq, Pod, Zeroable)]
+pub struct Le32([u8; 4]);
+
+impl Le32 {
+    pub const fn new(value: u32) -> Self {
+        Self(value.to_le_bytes())
+    }
+
+    pub const fn get(self) -> u32 {
+        u32::from_le_bytes(self.0)
+    }
+}
+
+impl fmt::Debug for Le32 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+const _: () = assert!(size_of::<Le16>() == 2 && core::mem::align_of::<Le16>() == 1);
+const _: () = assert!(size_of::<Le32>() == 4 && core::mem::align_of::<Le32>() == 1);
+// Buffers of images are cast in place, so an `Image` has to be its pixels
+const _: () = assert!(size_of::<Image>() == IMAGE_SIZE && core::mem::align_of::<Image>() == 1);
+
+/// A record with a fixed wire layout: alignment 1 and explicit
+/// little-endian fields, so its bytes are the same on every host.
+pub trait WireRecord: Pod {}
+
+impl WireRecord for Image {}
+
+/// Bytes of `record` as sent.
+pub fn bytes_of<T: WireRecord>(record: &T) -> &[u8] {
+    bytemuck::bytes_of(record)
+}
+
+/// Bytes of `records` as sent, back to back.
+pub fn as_bytes<T: WireRecord>(records: &[T]) -> &[u8] {
+    bytemuck::cast_slice(records)
+}
+
+/// Output buffer view of `records`, for the TA to fill.
+pub fn as_bytes_mut<T: WireRecord>(records: &mut [T]) -> &mut [u8] {
+    bytemuck::cast_slice_mut(records)
+}
+
+/// Records held by `bytes`, without copying; `None` unless it is a whole
+/// number of them.
+pub fn records<T: WireRecord>(bytes: &[u8]) -> Option<&[T]> {
+    bytemuck::try_cast_slice(bytes).ok()
+}
+
+/// The record at the start of `bytes`, `None` when it is shorter.
+pub fn read<T: WireRecord>(bytes: &[u8]) -> Option<T> {
+    bytes
+        .get(..size_of::<T>())
+        .map(bytemuck::pod_read_unaligned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inference::{
+        CorrelatedPrediction, InferenceRequestHeader, Prediction, FLAG_PREDICTIONS, REQUEST_MAGIC,
+    };
+    use alloc::vec::Vec;
+
+    #[test]
+    fn integers_are_little_endian() {
+        assert_eq!(bytemuck::bytes_of(&Le16::new(0x1234)), [0x34, 0x12]);
+        assert_eq!(
+            bytemuck::bytes_of(&Le32::new(0x1234_5678)),
+            [0x78, 0x56, 0x34, 0x12]
+        );
+        assert_eq!(Le16::new(0xbeef).get(), 0xbeef);
+        assert_eq!(Le32::new(u32::MAX - 1).get(), u32::MAX - 1);
+    }
+
+    #[test]
+    fn request_header_layout() {
+        let header = InferenceRequestHeader::new(3, FLAG_PREDICTIONS, 1.0).unwrap();
+        let bytes = bytes_of(&header);
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(bytes[..4], REQUEST_MAGIC.to_le_bytes());
+        assert_eq!(bytes[4..8], 3u32.to_le_bytes());
+        assert_eq!(bytes[8..12], FLAG_PREDICTIONS.to_le_bytes());
+        assert_eq!(read::<InferenceRequestHeader>(bytes), Some(header));
+    }
+
+    #[test]
+    fn prediction_layout() {
+        let prediction = Prediction::new(7, 0.25).unwrap().mark_tied();
+        assert_eq!(bytes_of(&prediction), [7, 7, 0xfa, 0x80]);
+        let correlated = CorrelatedPrediction::new(0x0102_0304, prediction);
+        assert_eq!(bytes_of(&correlated), [4, 3, 2, 1, 7, 7, 0xfa, 0x80]);
+        assert_eq!(
+            read::<CorrelatedPrediction>(bytes_of(&correlated)),
+            Some(correlated)
+        );
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let predictions: Vec<Prediction> = (0..5u8)
+            .map(|label| Prediction::new(label, label as f32 / 10.0).unwrap())
+            .collect();
+        let bytes = as_bytes(&predictions).to_vec();
+        assert_eq!(records::<Prediction>(&bytes), Some(predictions.as_slice()));
+
+        let mut images = [Image::BLANK; 2];
+        as_bytes_mut(&mut images)[IMAGE_SIZE] = 9;
+        assert_eq!(images[1].as_bytes()[0], 9);
+        assert_eq!(records::<Image>(as_bytes(&images)), Some(images.as_slice()));
+    }
+
+    #[test]
+    fn partial_records_are_refused() {
+        let bytes = [0u8; 10];
+        assert_eq!(records::<Prediction>(&bytes[..6]), None);
+        assert_eq!(records::<Image>(&bytes), None);
+        assert_eq!(read::<InferenceRequestHeader>(&bytes), None);
+        assert_eq!(read::<Prediction>(&bytes[..3]), None);
+        // Unaligned starts are fine, every record has alignment 1
+        assert!(read::<CorrelatedPrediction>(&bytes[1..]).is_some());
+    }
+}
@@ -0,0 +1,408 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// AES-CBC over a model split into pieces, kept apart from the TEE cipher and
+// the key manager session so that the chunking can be checked against a
+// software cipher in tests. The TA passes one call of the key manager (or of
+// its own cipher) as the step; each step continues from the IV the previous
+// one left, so the pieces chain into the same bytes as a single call.
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+use proto::framing;
+use proto::key_manager::AES_BLOCK_SIZE;
+
+/// Why `cbc_decrypt_in_place` refused a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CbcError {
+    /// Not an IV followed by whole blocks.
+    Length,
+    /// A step wrote another number of bytes than it was given.
+    ShortStep,
+    /// The plaintext isn't a frame, e.g. under the wrong key.
+    Framing,
+}
+
+impl fmt::Display for CbcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CbcError::Length => write!(f, "ciphertext isn't an IV and whole blocks"),
+            CbcError::ShortStep => write!(f, "decryption step wrote a partial chunk"),
+            CbcError::Framing => write!(f, "decrypted model isn't framed"),
+        }
+    }
+}
+
+impl core::error::Error for CbcError {}
+
+#[cfg(feature = "optee-utee")]
+impl From<CbcError> for optee_utee::Error {
+    fn from(_: CbcError) -> Self {
+        optee_utee::ErrorKind::BadParameters.into()
+    }
+}
+
+/// Runs `step` over `input` (whole blocks) in pieces of `chunk_size` bytes,
+/// at least a block, starting from `iv`. `step` CBC-encrypts or decrypts a
+/// piece into its output, returning the bytes written, and leaves `iv` at
+/// the last ciphertext block. Returns the output and the number of steps.
+pub fn cbc_in_chunks<E>(
+    input: &[u8],
+    chunk_size: usize,
+    iv: &mut [u8; AES_BLOCK_SIZE],
+    mut step: impl FnMut(&[u8], &mut [u8], &mut [u8; AES_BLOCK_SIZE]) -> Result<usize, E>,
+) -> Result<(Vec<u8>, usize), E> {
+    let mut output = Vec::with_capacity(input.len());
+    let mut steps = 0;
+    for chunk in input.chunks(chunk_size.max(AES_BLOCK_SIZE)) {
+        let start = output.len();
+        output.resize(start + chunk.len(), 0);
+        let size = step(chunk, &mut output[start..], iv)?;
+        output.truncate(start + size);
+        steps += 1;
+    }
+    Ok((output, steps))
+}
+
+/// Decrypts `IV || ciphertext` in place with `step` (as for
+/// `cbc_in_chunks`), leaving only the framed plaintext in `data`. Each piece
+/// is decrypted into one scratch buffer of `chunk_size` bytes and copied back
+/// a block earlier, over the IV, so no ciphertext-sized copy is allocated.
+pub fn cbc_decrypt_in_place<E: From<CbcError>>(
+    data: &mut Vec<u8>,
+    chunk_size: usize,
+    step: impl FnMut(&[u8], &mut [u8], &mut [u8; AES_BLOCK_SIZE]) -> Result<usize, E>,
+) -> Result<(), E> {
+    if data.len() < AES_BLOCK_SIZE * 2 || data.len() % AES_BLOCK_SIZE != 0 {
+        return Err(CbcError::Length.into());
+    }
+    let len = data.len() - AES_BLOCK_SIZE;
+    let chunk_size = chunk_size.max(AES_BLOCK_SIZE);
+    let mut scratch = vec![0u8; chunk_size.min(len)];
+    let decrypted = decrypt_chunks(data, chunk_size, &mut scratch, step);
+    // The last decrypted chunk would otherwise linger in freed heap
+    crate::wipe(&mut scratch);
+    decrypted?;
+    data.truncate(len);
+    let payload = framing::payload_range(data).ok_or(CbcError::Framing)?;
+    let payload_len = payload.len();
+    data.copy_within(payload, 0);
+    data.truncate(payload_len);
+    Ok(())
+}
+
+// Decrypts the ciphertext after the IV of `data` a block earlier, through
+// `scratch`
+fn decrypt_chunks<E: From<CbcError>>(
+    data: &mut [u8],
+    chunk_size: usize,
+    scratch: &mut [u8],
+    mut step: impl FnMut(&[u8], &mut [u8], &mut [u8; AES_BLOCK_SIZE]) -> Result<usize, E>,
+) -> Result<(), E> {
+    let mut iv = [0u8; AES_BLOCK_SIZE];
+    iv.copy_from_slice(&data[..AES_BLOCK_SIZE]);
+    let len = data.len() - AES_BLOCK_SIZE;
+    let mut offset = 0;
+    while offset < len {
+        let end = len.min(offset + chunk_size);
+        let input = &data[AES_BLOCK_SIZE + offset..AES_BLOCK_SIZE + end];
+        let size = step(input, scratch, &mut iv)?;
+        if size != end - offset {
+            return Err(CbcError::ShortStep.into());
+        }
+        data[offset..end].copy_from_slice(&scratch[..size]);
+        offset = end;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use core::alloc::{GlobalAlloc, Layout};
+    use core::cell::Cell;
+    use std::alloc::System;
+
+    // Largest allocation made on the current thread since `Counting::reset`,
+    // to see which buffers decryption allocates
+    struct Counting;
+
+    std::thread_local! {
+        static LARGEST: Cell<usize> = const { Cell::new(0) };
+    }
+
+    impl Counting {
+        fn reset() {
+            LARGEST.with(|largest| largest.set(0));
+        }
+
+        fn largest() -> usize {
+            LARGEST.with(Cell::get)
+        }
+
+        fn record(size: usize) {
+            let _ = LARGEST.try_with(|largest| largest.set(largest.get().max(size)));
+        }
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            Self::record(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            Self::record(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    // Stand-ins for AES, a keyed byte permutation per block; CBC chaining
+    // is what is under test
+    fn encrypt_block(block: &mut [u8]) {
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = byte.rotate_left(3) ^ (0x5a + i as u8);
+        }
+    }
+
+    fn decrypt_block(block: &mut [u8]) {
+        for (i, byte) in block.iter_mut().enumerate() {
+            *byte = (*byte ^ (0x5a + i as u8)).rotate_right(3);
+        }
+    }
+
+    // One key manager call: CBC over whole blocks, IV left at the last
+    // ciphertext block
+    fn cbc(
+        encrypt: bool,
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize, CbcError> {
+        if input.len() % AES_BLOCK_SIZE != 0 {
+            return Err(CbcError::Length);
+        }
+        for (block, out) in input
+            .chunks_exact(AES_BLOCK_SIZE)
+            .zip(output.chunks_exact_mut(AES_BLOCK_SIZE))
+        {
+            out.copy_from_slice(block);
+            if encrypt {
+                out.iter_mut().zip(iv.iter()).for_each(|(o, v)| *o ^= v);
+                encrypt_block(out);
+                iv.copy_from_slice(out);
+            } else {
+                decrypt_block(out);
+                out.iter_mut().zip(iv.iter()).for_each(|(o, v)| *o ^= v);
+                iv.copy_from_slice(block);
+            }
+        }
+        Ok(input.len())
+    }
+
+    fn encrypt(
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize, CbcError> {
+        cbc(true, input, output, iv)
+    }
+
+    fn decrypt(
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize, CbcError> {
+        cbc(false, input, output, iv)
+    }
+
+    fn plaintext(blocks: usize) -> Vec<u8> {
+        (0..blocks * AES_BLOCK_SIZE)
+            .map(|i| (i * 13 % 256) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn chunked_and_single_calls_give_the_same_ciphertext() {
+        let input = plaintext(100);
+        let mut iv = [7; AES_BLOCK_SIZE];
+        // What the multi-block command does: the whole buffer in one call
+        let (single, steps) = cbc_in_chunks(&input, input.len(), &mut iv, encrypt).unwrap();
+        assert_eq!(steps, 1);
+        let last_iv = iv;
+        assert_eq!(last_iv[..], single[single.len() - AES_BLOCK_SIZE..]);
+        // The per-chunk command, with chunk sizes that do and don't divide
+        // the input, and below a block
+        for chunk_size in [1, 16, 48, 160, 1584, 1600, 4096] {
+            let mut iv = [7; AES_BLOCK_SIZE];
+            let (chunked, steps) = cbc_in_chunks(&input, chunk_size, &mut iv, encrypt).unwrap();
+            assert_eq!(chunked, single, "chunk size {chunk_size}");
+            assert_eq!(iv, last_iv);
+            assert_eq!(steps, input.len().div_ceil(chunk_size.max(AES_BLOCK_SIZE)));
+        }
+    }
+
+    #[test]
+    fn chunked_decryption_round_trips() {
+        let input = plaintext(37);
+        let mut iv = [1; AES_BLOCK_SIZE];
+        let (ciphertext, _) = cbc_in_chunks(&input, input.len(), &mut iv, encrypt).unwrap();
+        assert_ne!(ciphertext, input);
+        for chunk_size in [16, 64, 592, 1024] {
+            let mut iv = [1; AES_BLOCK_SIZE];
+            let (plain, _) = cbc_in_chunks(&ciphertext, chunk_size, &mut iv, decrypt).unwrap();
+            assert_eq!(plain, input, "chunk size {chunk_size}");
+        }
+    }
+
+    // `IV || ciphertext` of the frame of `payload`, as the key manager
+    // writes a model
+    fn encrypted_model(payload: &[u8]) -> Vec<u8> {
+        let iv = [3; AES_BLOCK_SIZE];
+        let framed = framing::frame(payload).unwrap();
+        let (ciphertext, _) =
+            cbc_in_chunks(&framed, framed.len(), &mut iv.clone(), encrypt).unwrap();
+        [&iv[..], &ciphertext].concat()
+    }
+
+    #[test]
+    fn models_decrypt_in_place() {
+        let payload: Vec<u8> = (0..300_000).map(|i| (i % 251) as u8).collect();
+        for chunk_size in [16, 4096, 65_536] {
+            let mut data = encrypted_model(&payload);
+            let buffer = data.as_ptr();
+            Counting::reset();
+            cbc_decrypt_in_place(&mut data, chunk_size, decrypt).unwrap();
+            // Only the scratch buffer, never a ciphertext-sized copy
+            assert!(
+                Counting::largest() <= chunk_size,
+                "chunk size {chunk_size}: allocated {} bytes",
+                Counting::largest()
+            );
+            assert_eq!(data.as_ptr(), buffer);
+            assert_eq!(data, payload);
+        }
+    }
+
+    #[test]
+    fn bad_models_are_refused() {
+        let model = encrypted_model(b"model");
+        let mut short = model[..AES_BLOCK_SIZE].to_vec();
+        let mut partial_block = model[..model.len() - 1].to_vec();
+        for data in [&mut short, &mut partial_block] {
+            assert_eq!(
+                cbc_decrypt_in_place(data, 4096, decrypt),
+                Err(CbcError::Length)
+            );
+        }
+        // The wrong key: the length prefix decrypts to garbage
+        let mut data = encrypted_model(b"model");
+        data[AES_BLOCK_SIZE + 3] ^= 0x80;
+        assert_eq!(
+            cbc_decrypt_in_place(&mut data, 4096, decrypt),
+            Err(CbcError::Framing)
+        );
+        let mut data = encrypted_model(&[0; 100]);
+        assert_eq!(
+            cbc_decrypt_in_place(&mut data, 32, |i, o, v| decrypt(i, o, v).map(|n| n - 16)),
+            Err(CbcError::ShortStep)
+        );
+    }
+
+    #[test]
+    fn failing_steps_stop_the_run() {
+        let mut calls = 0;
+        let result = cbc_in_chunks(&plaintext(4), 16, &mut [0; AES_BLOCK_SIZE], |_, _, _| {
+            calls += 1;
+            if calls == 2 {
+                Err("key manager gone")
+            } else {
+                Ok(AES_BLOCK_SIZE)
+            }
+        });
+        assert_eq!(result, Err("key manager gone"));
+        assert_eq!(calls, 2);
+        assert_eq!(
+            cbc_in_chunks(&[], 16, &mut [0; AES_BLOCK_SIZE], encrypt),
+            Ok((Vec::new(), 0))
+        );
+    }
+
+    // One call of an AES-256 key manager under `key`
+    fn aes_cbc(
+        key: &[u8; 32],
+        encrypt: bool,
+    ) -> impl FnMut(&[u8], &mut [u8], &mut [u8; AES_BLOCK_SIZE]) -> Result<usize, CbcError> + '_
+    {
+        use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+        let cipher = aes::Aes256::new(GenericArray::from_slice(key));
+        move |input, output, iv| {
+            if input.len() % AES_BLOCK_SIZE != 0 {
+                return Err(CbcError::Length);
+            }
+            for (block, out) in input
+                .chunks_exact(AES_BLOCK_SIZE)
+                .zip(output.chunks_exact_mut(AES_BLOCK_SIZE))
+            {
+                let out = GenericArray::from_mut_slice(out);
+                out.copy_from_slice(block);
+                if encrypt {
+                    out.iter_mut().zip(iv.iter()).for_each(|(o, v)| *o ^= v);
+                    cipher.encrypt_block(out);
+                    iv.copy_from_slice(out);
+                } else {
+                    cipher.decrypt_block(out);
+                    out.iter_mut().zip(iv.iter()).for_each(|(o, v)| *o ^= v);
+                    iv.copy_from_slice(block);
+                }
+            }
+            Ok(input.len())
+        }
+    }
+
+    #[test]
+    fn test_vectors_pass_through_both_key_managers() {
+        use proto::test_vectors::VECTORS;
+        // How each backend splits a model: the key manager TA per chunk at
+        // the smallest size it may advertise and at `CHUNK_SIZE`, or all of
+        // it with the multi-block command; the built-in one in 64 KiB pieces
+        let chunk_sizes = [AES_BLOCK_SIZE, proto::CHUNK_SIZE, usize::MAX, 64 * 1024];
+        for vector in VECTORS {
+            let framed = framing::frame(vector.payload).unwrap();
+            for chunk_size in chunk_sizes {
+                let mut iv = vector.iv;
+                let encrypt = aes_cbc(&vector.key, true);
+                let (ciphertext, _) = cbc_in_chunks(&framed, chunk_size, &mut iv, encrypt).unwrap();
+                assert_eq!(ciphertext, vector.ciphertext, "{}", vector.name);
+
+                let mut data = [&vector.iv[..], vector.ciphertext].concat();
+                let decrypt = aes_cbc(&vector.key, false);
+                cbc_decrypt_in_place(&mut data, chunk_size, decrypt).unwrap();
+                assert_eq!(data, vector.payload, "{}", vector.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,531 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Model container: an optional metadata block in front of the Burn record.
+//
+// Layout (all integers little endian):
+//   magic "EMNM" (4) | version (1) | record format (1) | codec (1) | reserved (1)
+//   | metadata_len (4) | metadata JSON | record
+//
+// Version 1 containers hold the record as is (the codec byte was reserved
+// and is ignored). Version 2 ones hold it compressed with the codec, as
+//   decompressed length (u32 LE) | compressed record
+// so TAs that predate compression refuse them instead of misreading the
+// record. The length is checked against MAX_DECOMPRESSED_RECORD_SIZE before
+// anything is allocated for it.
+//
+// The metadata may carry up to `MAX_CANARIES` canary images with their
+// expected labels; the TA refuses a model that misclassifies any of them,
+// which catches records that still parse after decryption with a wrong key.
+//
+// Plaintexts that don't start with the magic are bare `BinBytesRecorder`
+// records, so models exported before the container existed keep loading
+// unchanged.
+//
+// Layer patches (partial records replacing one submodule) use their own magic:
+//   magic "EMNP" (4) | version (1) | name_len (1) | reserved (2) | layer name | record
+
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::fmt;
+use proto::inference::{ModelLicense, Normalization};
+use proto::{Image, IMAGE_SIZE};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ModelError;
+
+pub const CONTAINER_MAGIC: &[u8; 4] = b"EMNM";
+pub const CONTAINER_VERSION: u8 = 1;
+pub const COMPRESSED_CONTAINER_VERSION: u8 = 2;
+/// Largest record a compressed container may declare.
+pub const MAX_DECOMPRESSED_RECORD_SIZE: usize = 32 * 1024 * 1024;
+const DECOMPRESSED_LEN_SIZE: usize = 4;
+pub const CONTAINER_HEADER_SIZE: usize = 12;
+pub const MAX_METADATA_SIZE: usize = 32 * 1024;
+pub const MAX_CANARIES: usize = 8;
+pub const PATCH_MAGIC: &[u8; 4] = b"EMNP";
+const PATCH_HEADER_SIZE: usize = 8;
+
+/// Describes what a model classifies and where it came from.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ModelMetadata {
+    pub name: String,
+    #[serde(default)]
+    pub dataset: Option<String>,
+    #[serde(default)]
+    pub class_labels: Vec<String>,
+    /// Output width of the model; `DEFAULT_NUM_CLASSES` when absent.
+    #[serde(default)]
+    pub num_classes: Option<usize>,
+    #[serde(default)]
+    pub training_date: Option<String>,
+    #[serde(default)]
+    pub trainer_commit: Option<String>,
+    #[serde(default)]
+    pub extra: BTreeMap<String, String>,
+    /// Expiry enforced by the TA, see `proto::inference::ModelLicense`.
+    #[serde(default)]
+    pub license: Option<ModelLicense>,
+    /// Images the model must classify as labelled before it is installed.
+    #[serde(default)]
+    pub canary: Vec<Canary>,
+    /// Input normalization the model was trained with;
+    /// `Normalization::MNIST` when absent.
+    #[serde(default)]
+    pub normalization: Option<Normalization>,
+}
+
+/// A known input and the label the model has to predict for it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Canary {
+    pub label: u8,
+    /// Hex in JSON, keeping eight of them well inside `MAX_METADATA_SIZE`.
+    #[serde(serialize_with = "image_to_hex", deserialize_with = "image_from_hex")]
+    pub image: Image,
+}
+
+fn image_to_hex<S: Serializer>(image: &Image, serializer: S) -> Result<S::Ok, S::Error> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let hex: String = image
+        .as_bytes()
+        .iter()
+        .flat_map(|&b| [DIGITS[(b >> 4) as usize], DIGITS[(b & 15) as usize]])
+        .map(char::from)
+        .collect();
+    serializer.serialize_str(&hex)
+}
+
+fn image_from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Image, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    let invalid = || serde::de::Error::custom("canary image is not IMAGE_SIZE hex bytes");
+    if hex.len() != IMAGE_SIZE * 2 {
+        return Err(invalid());
+    }
+    let mut pixels = [0u8; IMAGE_SIZE];
+    for (byte, pair) in pixels.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let pair = core::str::from_utf8(pair).map_err(|_| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid())?;
+    }
+    Ok(Image::from_luma28(&pixels))
+}
+
+/// Burn recorder that produced the record following the header.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// `BinBytesRecorder`, the format the TA has always loaded.
+    #[default]
+    Bin = 0,
+    /// `NamedMpkBytesRecorder`, named MessagePack as used by other burn projects.
+    NamedMpk = 1,
+}
+
+impl TryFrom<u8> for RecordFormat {
+    type Error = ContainerError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(RecordFormat::Bin),
+            1 => Ok(RecordFormat::NamedMpk),
+            other => Err(ContainerError::UnsupportedRecordFormat(other)),
+        }
+    }
+}
+
+impl RecordFormat {
+    /// Whether this build can import and export the format. Named MessagePack
+    /// needs burn's `std` feature and is only compiled in with `mpk`.
+    pub fn is_supported(self) -> bool {
+        match self {
+            RecordFormat::Bin => true,
+            RecordFormat::NamedMpk => cfg!(feature = "mpk"),
+        }
+    }
+}
+
+/// Compression of the record in a container.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    None = 0,
+    /// Raw DEFLATE (RFC 1951).
+    Deflate = 1,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = ContainerError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Codec::None),
+            1 => Ok(Codec::Deflate),
+            other => Err(ContainerError::UnsupportedCodec(other)),
+        }
+    }
+}
+
+impl Codec {
+    /// Whether this build can decompress the codec; the decoder is only
+    /// compiled in with `deflate`.
+    pub fn is_supported(self) -> bool {
+        match self {
+            Codec::None => true,
+            Codec::Deflate => cfg!(feature = "deflate"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerError {
+    Truncated,
+    UnsupportedVersion(u8),
+    UnsupportedRecordFormat(u8),
+    UnsupportedCodec(u8),
+    /// A compressed record declares more than `MAX_DECOMPRESSED_RECORD_SIZE`.
+    DecompressedTooLarge(usize),
+    /// The compressed record doesn't decode to its declared length.
+    InvalidCompression,
+    /// `parse_container` was given a compressed container, whose record
+    /// only `split_container` can return.
+    Compressed,
+    MetadataTooLarge(usize),
+    InvalidMetadata,
+    NotAPatch,
+    InvalidLayerName,
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::Truncated => write!(f, "model container is truncated"),
+            ContainerError::UnsupportedVersion(v) => {
+                write!(f, "unsupported model container version {}", v)
+            }
+            ContainerError::UnsupportedRecordFormat(v) => {
+                write!(f, "unsupported record format {}", v)
+            }
+            ContainerError::UnsupportedCodec(v) => write!(f, "unsupported record codec {}", v),
+            ContainerError::DecompressedTooLarge(len) => write!(
+                f,
+                "compressed record declares {} bytes, limit is {}",
+                len, MAX_DECOMPRESSED_RECORD_SIZE
+            ),
+            ContainerError::InvalidCompression => write!(f, "compressed record is corrupt"),
+            ContainerError::Compressed => write!(f, "model container is compressed"),
+            ContainerError::MetadataTooLarge(len) => write!(
+                f,
+                "model metadata is {} bytes, limit is {}",
+                len, MAX_METADATA_SIZE
+            ),
+            ContainerError::InvalidMetadata => write!(f, "model metadata is not valid JSON"),
+            ContainerError::NotAPatch => write!(f, "not a layer patch"),
+            ContainerError::InvalidLayerName => write!(f, "invalid layer name in patch"),
+        }
+    }
+}
+
+impl core::error::Error for ContainerError {}
+
+impl ModelMetadata {
+    pub fn num_classes(&self) -> usize {
+        self.num_classes.unwrap_or(proto::DEFAULT_NUM_CLASSES)
+    }
+
+    pub fn normalization(&self) -> Normalization {
+        self.normalization.unwrap_or_default()
+    }
+}
+
+/// Parses the container header of `bytes`, returning the metadata (if any),
+/// the record format and the offset at which the Burn record starts. Fails
+/// with `ContainerError::Compressed` for compressed containers.
+pub fn parse_container(
+    bytes: &[u8],
+) -> Result<(Option<ModelMetadata>, RecordFormat, usize), ModelError> {
+    let (metadata, format, codec, record_offset) = parse_header(bytes)?;
+    if codec != Codec::None {
+        return Err(ContainerError::Compressed.into());
+    }
+    Ok((metadata, format, record_offset))
+}
+
+// `parse_container` that also returns the codec of the record
+fn parse_header(
+    bytes: &[u8],
+) -> Result<(Option<ModelMetadata>, RecordFormat, Codec, usize), ModelError> {
+    if bytes.len() < CONTAINER_MAGIC.len() || &bytes[..CONTAINER_MAGIC.len()] != CONTAINER_MAGIC {
+        return Ok((None, RecordFormat::Bin, Codec::None, 0));
+    }
+    if bytes.len() < CONTAINER_HEADER_SIZE {
+        return Err(ContainerError::Truncated.into());
+    }
+    let codec = match bytes[4] {
+        CONTAINER_VERSION => Codec::None,
+        COMPRESSED_CONTAINER_VERSION => match Codec::try_from(bytes[6])? {
+            Codec::None => return Err(ContainerError::UnsupportedCodec(bytes[6]).into()),
+            codec => codec,
+        },
+        version => return Err(ContainerError::UnsupportedVersion(version).into()),
+    };
+    let format = RecordFormat::try_from(bytes[5])?;
+    let metadata_len = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+    if metadata_len > MAX_METADATA_SIZE {
+        return Err(ContainerError::MetadataTooLarge(metadata_len).into());
+    }
+    let record_offset = CONTAINER_HEADER_SIZE + metadata_len;
+    if bytes.len() < record_offset {
+        return Err(ContainerError::Truncated.into());
+    }
+    let metadata: ModelMetadata =
+        serde_json::from_slice(&bytes[CONTAINER_HEADER_SIZE..record_offset])
+            .map_err(|_| ContainerError::InvalidMetadata)?;
+    if !metadata.normalization().is_valid() {
+        return Err(ContainerError::InvalidMetadata.into());
+    }
+    Ok((Some(metadata), format, codec, record_offset))
+}
+
+/// Splits an owned container into its metadata, record format and record,
+/// reusing the allocation of `bytes` for the record unless it has to be
+/// decompressed.
+pub fn split_container(
+    mut bytes: Vec<u8>,
+) -> Result<(Option<ModelMetadata>, RecordFormat, Vec<u8>), ModelError> {
+    let (metadata, format, codec, record_offset) = parse_header(&bytes)?;
+    if codec != Codec::None {
+        let record = decompress_record(codec, &bytes[record_offset..])?;
+        return Ok((metadata, format, record));
+    }
+    bytes.drain(..record_offset);
+    Ok((metadata, format, bytes))
+}
+
+// Checks the declared length of a compressed record against the cap, then
+// decodes it
+fn decompress_record(codec: Codec, data: &[u8]) -> Result<Vec<u8>, ModelError> {
+    let prefix = data
+        .get(..DECOMPRESSED_LEN_SIZE)
+        .ok_or(ContainerError::Truncated)?;
+    let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+    if len > MAX_DECOMPRESSED_RECORD_SIZE {
+        return Err(ContainerError::DecompressedTooLarge(len).into());
+    }
+    match codec {
+        #[cfg(feature = "deflate")]
+        Codec::Deflate => crate::inflate::inflate(&data[DECOMPRESSED_LEN_SIZE..], len)
+            .map_err(|_| ContainerError::InvalidCompression.into()),
+        _ => Err(ContainerError::UnsupportedCodec(codec as u8).into()),
+    }
+}
+
+/// Prepends a metadata block to a bare Burn record written in `format`.
+pub fn encode_container(
+    metadata: &ModelMetadata,
+    format: RecordFormat,
+    record: &[u8],
+) -> Result<Vec<u8>, ModelError> {
+    let mut out = encode_header(metadata, format, Codec::None, record.len())?;
+    out.extend_from_slice(record);
+    Ok(out)
+}
+
+/// `encode_container` for a record of `record_len` bytes compressed with
+/// `codec` (not `Codec::None`) into `compressed`.
+pub fn encode_compressed_container(
+    metadata: &ModelMetadata,
+    format: RecordFormat,
+    codec: Codec,
+    record_len: usize,
+    compressed: &[u8],
+) -> Result<Vec<u8>, ModelError> {
+    if codec == Codec::None {
+        return Err(ContainerError::UnsupportedCodec(codec as u8).into());
+    }
+    if record_len > MAX_DECOMPRESSED_RECORD_SIZE {
+        return Err(ContainerError::DecompressedTooLarge(record_len).into());
+    }
+    let size = DECOMPRESSED_LEN_SIZE + compressed.len();
+    let mut out = encode_header(metadata, format, codec, size)?;
+    out.extend_from_slice(&(record_len as u32).to_le_bytes());
+    out.extend_from_slice(compressed);
+    Ok(out)
+}
+
+// Header and metadata of a container whose record takes `record_size` bytes
+fn encode_header(
+    metadata: &ModelMetadata,
+    format: RecordFormat,
+    codec: Codec,
+    record_size: usize,
+) -> Result<Vec<u8>, ModelError> {
+    let metadata = serde_json::to_vec(metadata).map_err(|_| ContainerError::InvalidMetadata)?;
+    if metadata.len() > MAX_METADATA_SIZE {
+        return Err(ContainerError::MetadataTooLarge(metadata.len()).into());
+    }
+    let version = match codec {
+        Codec::None => CONTAINER_VERSION,
+        _ => COMPRESSED_CONTAINER_VERSION,
+    };
+    let mut out = Vec::with_capacity(CONTAINER_HEADER_SIZE + metadata.len() + record_size);
+    out.extend_from_slice(CONTAINER_MAGIC);
+    out.push(version);
+    out.push(format as u8);
+    out.push(codec as u8);
+    out.push(0);
+    out.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    out.extend_from_slice(&metadata);
+    Ok(out)
+}
+
+/// Wraps the partial record of `layer` into a patch.
+pub fn encode_patch(layer: &str, record: &[u8]) -> Result<Vec<u8>, ModelError> {
+    let name_len = u8::try_from(layer.len()).map_err(|_| ContainerError::InvalidLayerName)?;
+    let mut out = Vec::with_capacity(PATCH_HEADER_SIZE + layer.len() + record.len());
+    out.extend_from_slice(PATCH_MAGIC);
+    out.push(CONTAINER_VERSION);
+    out.push(name_len);
+    out.extend_from_slice(&[0u8; 2]);
+    out.extend_from_slice(layer.as_bytes());
+    out.extend_from_slice(record);
+    Ok(out)
+}
+
+/// Splits an owned patch into the target layer name and its partial record.
+pub fn split_patch(mut bytes: Vec<u8>) -> Result<(String, Vec<u8>), ModelError> {
+    if bytes.len() < PATCH_HEADER_SIZE || &bytes[..PATCH_MAGIC.len()] != PATCH_MAGIC {
+        return Err(ContainerError::NotAPatch.into());
+    }
+    if bytes[4] != CONTAINER_VERSION {
+        return Err(ContainerError::UnsupportedVersion(bytes[4]).into());
+    }
+    let record_offset = PATCH_HEADER_SIZE + bytes[5] as usize;
+    if bytes.len() < record_offset {
+        return Err(ContainerError::Truncated.into());
+    }
+    let layer = core::str::from_utf8(&bytes[PATCH_HEADER_SIZE..record_offset])
+        .map_err(|_| ContainerError::InvalidLayerName)?
+        .into();
+    bytes.drain(..record_offset);
+    Ok((layer, bytes))
+}
+
+#[cfg(all(test, feature = "deflate"))]
+mod tests {
+    use super::*;
+    use flate2::{write::DeflateEncoder, Compression};
+
+    extern crate std;
+    use std::io::Write;
+
+    const RECORD: &[u8] = b"a record of a model, a record of a model, a record of a model";
+
+    fn compressed_container(record: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+        encoder.write_all(record).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let metadata = ModelMetadata {
+            name: "compressed".into(),
+            ..Default::default()
+        };
+        encode_compressed_container(
+            &metadata,
+            RecordFormat::Bin,
+            Codec::Deflate,
+            record.len(),
+            &compressed,
+        )
+        .unwrap()
+    }
+
+    // Offset of the declared record length
+    fn record_offset(container: &[u8]) -> usize {
+        parse_header(container).unwrap().3
+    }
+
+    fn split_error(container: Vec<u8>) -> ContainerError {
+        match split_container(container) {
+            Err(ModelError::Metadata(err)) => err,
+            other => panic!("expected a container error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compressed_records_round_trip() {
+        for level in [0, 1, 6, 9] {
+            let (metadata, format, record) =
+                split_container(compressed_container(RECORD, level)).unwrap();
+            assert_eq!(metadata.unwrap().name, "compressed");
+            assert_eq!(format, RecordFormat::Bin);
+            assert_eq!(record, RECORD);
+        }
+        assert_eq!(
+            parse_container(&compressed_container(RECORD, 9)),
+            Err(ContainerError::Compressed.into())
+        );
+    }
+
+    #[test]
+    fn huge_declared_lengths_are_refused_before_decoding() {
+        let mut container = compressed_container(RECORD, 9);
+        let offset = record_offset(&container);
+        for declared in [MAX_DECOMPRESSED_RECORD_SIZE as u32 + 1, u32::MAX] {
+            container[offset..offset + DECOMPRESSED_LEN_SIZE]
+                .copy_from_slice(&declared.to_le_bytes());
+            assert_eq!(
+                split_error(container.clone()),
+                ContainerError::DecompressedTooLarge(declared as usize)
+            );
+        }
+        // Within the cap, the stream still has to fill what was declared
+        container[offset..offset + DECOMPRESSED_LEN_SIZE]
+            .copy_from_slice(&(MAX_DECOMPRESSED_RECORD_SIZE as u32).to_le_bytes());
+        assert_eq!(split_error(container), ContainerError::InvalidCompression);
+    }
+
+    #[test]
+    fn truncated_records_are_refused() {
+        let container = compressed_container(RECORD, 9);
+        let offset = record_offset(&container);
+        for cut in offset..offset + DECOMPRESSED_LEN_SIZE {
+            assert_eq!(
+                split_error(container[..cut].to_vec()),
+                ContainerError::Truncated
+            );
+        }
+        for cut in offset + DECOMPRESSED_LEN_SIZE..container.len() - 1 {
+            assert_eq!(
+                split_error(container[..cut].to_vec()),
+                ContainerError::InvalidCompression
+            );
+        }
+    }
+
+    #[test]
+    fn garbage_records_are_refused() {
+        let container = compressed_container(RECORD, 9);
+        let stream = record_offset(&container) + DECOMPRESSED_LEN_SIZE;
+        let mut garbage = container.clone();
+        for (i, byte) in garbage[stream..].iter_mut().enumerate() {
+            *byte = (i as u8).wrapping_mul(151) | 0x07;
+        }
+        assert_eq!(split_error(garbage), ContainerError::InvalidCompression);
+        // A stored record passed off as a compressed one
+        let mut stored = container[..stream].to_vec();
+        stored.extend_from_slice(RECORD);
+        assert_eq!(split_error(stored), ContainerError::InvalidCompression);
+    }
+}
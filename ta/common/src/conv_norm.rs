@@ -1,9 +1,8 @@
 use burn::{
-    config::Config,
     module::Module,
     nn::{
         conv::{Conv2d, Conv2dConfig},
-        BatchNorm, BatchNormConfig, ReLU,
+        BatchNorm, BatchNormConfig, PaddingConfig2d, Relu,
     },
     tensor::{backend::Backend, Tensor},
 };
@@ -12,7 +11,7 @@ use burn::{
 pub struct Conv2dNormActivation<B: Backend> {
     pub conv: Conv2d<B>,
     pub norm: BatchNorm<B, 2>,
-    pub activation: ReLU,
+    pub activation: Relu,
 }
 
 impl<B: Backend> Conv2dNormActivation<B> {
@@ -23,19 +22,14 @@ impl<B: Backend> Conv2dNormActivation<B> {
     }
 }
 
-#[derive(Config, Debug)]
+#[derive(Clone, Debug)]
 pub struct Conv2dNormActivationConfig {
     pub in_channels: usize,
     pub out_channels: usize,
-    #[config(default = "3")]
     pub kernel_size: usize,
-    #[config(default = "1")]
     pub stride: usize,
-    #[config(default = "1")]
     pub padding: usize,
-    #[config(default = "1")]
     pub dilation: usize,
-    #[config(default = "1")]
     pub groups: usize,
 }
 
@@ -77,12 +71,12 @@ impl Conv2dNormActivationConfig {
                 [self.kernel_size, self.kernel_size],
             )
             .with_stride([self.stride, self.stride])
-            .with_padding([self.padding, self.padding])
+            .with_padding(PaddingConfig2d::Explicit(self.padding, self.padding))
             .with_dilation([self.dilation, self.dilation])
             .with_groups(self.groups)
             .init(device),
             norm: BatchNormConfig::new(self.out_channels).init(device),
-            activation: ReLU::new(),
+            activation: Relu::new(),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,132 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Device binding of the stored model's records. The model manifest and the
+// staged model record end with a SHA-256 over a domain string, the TEE
+// device id and the record, so a storage image copied to another board is
+// refused. The TA passes its digest and `TeeDeviceId`; tests pass others.
+
+use alloc::vec::Vec;
+
+pub const DEVICE_TAG_SIZE: usize = 32;
+const DEVICE_TAG_DOMAIN: &[u8] = b"enc_mnist-rs model manifest";
+
+/// Tag binding `body` to the device with `device_id`, `sha256` hashing the
+/// concatenation of its parts.
+pub fn device_tag<E>(
+    sha256: impl FnOnce(&[&[u8]]) -> Result<[u8; DEVICE_TAG_SIZE], E>,
+    device_id: &[u8],
+    body: &[u8],
+) -> Result<[u8; DEVICE_TAG_SIZE], E> {
+    sha256(&[DEVICE_TAG_DOMAIN, device_id, body])
+}
+
+/// Appends the tag of `record` for `device_id`, replacing any binding to
+/// another device when `record` is the body of a checked one.
+pub fn append_device_tag<E>(
+    sha256: impl FnOnce(&[&[u8]]) -> Result<[u8; DEVICE_TAG_SIZE], E>,
+    device_id: &[u8],
+    record: &mut Vec<u8>,
+) -> Result<(), E> {
+    let tag = device_tag(sha256, device_id, record)?;
+    record.extend_from_slice(&tag);
+    Ok(())
+}
+
+/// Splits a record written by `append_device_tag` into its body and whether
+/// its tag is the one of `device_id`; `None` when it is too short to hold a
+/// tag.
+pub fn check_device_tag<'a, E>(
+    sha256: impl FnOnce(&[&[u8]]) -> Result<[u8; DEVICE_TAG_SIZE], E>,
+    device_id: &[u8],
+    record: &'a [u8],
+) -> Result<Option<(&'a [u8], bool)>, E> {
+    if record.len() <= DEVICE_TAG_SIZE {
+        return Ok(None);
+    }
+    let (body, tag) = record.split_at(record.len() - DEVICE_TAG_SIZE);
+    let bound = device_tag(sha256, device_id, body)?[..] == *tag;
+    Ok(Some((body, bound)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const BOARD: &[u8] = b"ed0b6e3c-0a9a-4d7b-8d5f-2a7f9e1c4b00";
+    const OTHER_BOARD: &[u8] = b"ed0b6e3c-0a9a-4d7b-8d5f-2a7f9e1c4b01";
+
+    // Toy hash over the concatenated parts; only equality matters here
+    fn hash(parts: &[&[u8]]) -> Result<[u8; 32], ()> {
+        let mut out = [0u8; 32];
+        for (i, &byte) in parts.concat().iter().enumerate() {
+            out[i % 32] = out[i % 32].wrapping_mul(31).wrapping_add(byte);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn records_are_bound_to_their_device() {
+        let body = b"manifest body".to_vec();
+        let mut record = body.clone();
+        append_device_tag(hash, BOARD, &mut record).unwrap();
+        assert_eq!(record.len(), body.len() + DEVICE_TAG_SIZE);
+        assert_eq!(
+            check_device_tag(hash, BOARD, &record),
+            Ok(Some((&body[..], true)))
+        );
+        // The same image on another board
+        assert_eq!(
+            check_device_tag(hash, OTHER_BOARD, &record),
+            Ok(Some((&body[..], false)))
+        );
+        // A changed body no longer matches its tag
+        record[0] ^= 1;
+        assert!(!check_device_tag(hash, BOARD, &record).unwrap().unwrap().1);
+        // Nothing past the tag
+        assert_eq!(
+            check_device_tag(hash, BOARD, &[0; DEVICE_TAG_SIZE]),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn rebinding_moves_a_record_to_the_new_device() {
+        let mut record = vec![1, 2, 3];
+        append_device_tag(hash, BOARD, &mut record).unwrap();
+        let (body, bound) = check_device_tag(hash, OTHER_BOARD, &record)
+            .unwrap()
+            .unwrap();
+        assert!(!bound);
+        // What `rebind_to_device` does after an intentional migration
+        let mut rebound = body.to_vec();
+        append_device_tag(hash, OTHER_BOARD, &mut rebound).unwrap();
+        assert_eq!(
+            check_device_tag(hash, OTHER_BOARD, &rebound),
+            Ok(Some((&[1, 2, 3][..], true)))
+        );
+        assert!(!check_device_tag(hash, BOARD, &rebound).unwrap().unwrap().1);
+    }
+
+    #[test]
+    fn tags_cover_the_domain_and_device_id() {
+        let tag = device_tag(hash, BOARD, b"body").unwrap();
+        assert_eq!(tag, hash(&[DEVICE_TAG_DOMAIN, BOARD, b"body"]).unwrap());
+        assert_ne!(tag, device_tag(hash, OTHER_BOARD, b"body").unwrap());
+    }
+}
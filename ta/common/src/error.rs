@@ -0,0 +1,342 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Why a model record or container was refused. Import, export and container
+// functions return it instead of burn's `RecorderError`, so the TA can report
+// each case with its own return code (`ModelError::code`) and the host can
+// tell the user what to fix.
+
+use alloc::{string::String, vec::Vec};
+use burn::record::{BurnMetadata, RecorderError};
+use core::fmt;
+use proto::inference;
+
+use crate::ContainerError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ModelError {
+    /// The record doesn't decode: corrupted, truncated, or written by another
+    /// recorder or burn version.
+    RecordFormat(String),
+    /// A parameter of the record doesn't have the shape of the MLP.
+    ShapeMismatch {
+        parameter: String,
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    /// The record holds another module than the MLP of this crate.
+    UnsupportedArch,
+    /// The record was saved with other precision settings than
+    /// `FullPrecisionSettings`.
+    UnsupportedPrecision,
+    /// The container header or the metadata in it is invalid.
+    Metadata(ContainerError),
+    /// The recorder couldn't read or write its storage.
+    Io(String),
+}
+
+impl ModelError {
+    /// TA return code reporting this error to the host.
+    pub fn code(&self) -> u32 {
+        match self {
+            ModelError::RecordFormat(_) => inference::ERROR_MODEL_RECORD_FORMAT,
+            ModelError::ShapeMismatch { .. } => inference::ERROR_MODEL_SHAPE_MISMATCH,
+            ModelError::UnsupportedArch => inference::ERROR_MODEL_UNSUPPORTED_ARCH,
+            ModelError::UnsupportedPrecision => inference::ERROR_MODEL_UNSUPPORTED_PRECISION,
+            ModelError::Metadata(_) => inference::ERROR_MODEL_METADATA,
+            ModelError::Io(_) => inference::ERROR_MODEL_IO,
+        }
+    }
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModelError::RecordFormat(detail) => {
+                write!(f, "model record doesn't decode: {}", detail)
+            }
+            ModelError::ShapeMismatch {
+                parameter,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} has shape {:?}, expected {:?}",
+                parameter, found, expected
+            ),
+            ModelError::UnsupportedArch => {
+                write!(f, "model record is not the MNIST MLP")
+            }
+            ModelError::UnsupportedPrecision => {
+                write!(f, "model record was not saved at full precision")
+            }
+            ModelError::Metadata(err) => write!(f, "{}", err),
+            ModelError::Io(detail) => write!(f, "model record I/O failed: {}", detail),
+        }
+    }
+}
+
+impl core::error::Error for ModelError {}
+
+impl From<ContainerError> for ModelError {
+    fn from(err: ContainerError) -> Self {
+        ModelError::Metadata(err)
+    }
+}
+
+impl From<RecorderError> for ModelError {
+    fn from(err: RecorderError) -> Self {
+        match err {
+            RecorderError::FileNotFound(detail) => ModelError::Io(detail),
+            RecorderError::DeserializeError(detail) => ModelError::RecordFormat(detail),
+            RecorderError::Unknown(detail) => classify_load_failure(detail),
+        }
+    }
+}
+
+/// Classifies a record whose item didn't decode (`detail`) by how its burn
+/// metadata differs from `expected`, the loading recorder's own. With no
+/// difference, the item has another layout than the module it was loaded
+/// into.
+pub(crate) fn classify_metadata(
+    found: &BurnMetadata,
+    expected: &BurnMetadata,
+    detail: String,
+) -> ModelError {
+    if found.float != expected.float || found.int != expected.int {
+        ModelError::UnsupportedPrecision
+    } else if found.format != expected.format || found.version != expected.version {
+        ModelError::RecordFormat(detail)
+    } else {
+        ModelError::UnsupportedArch
+    }
+}
+
+// When a record doesn't decode but its burn metadata does, the recorder
+// reports "Unable to load record." followed by a line for every metadata
+// field that differs from its own, classified as by `classify_metadata`.
+fn classify_load_failure(detail: String) -> ModelError {
+    if !detail.starts_with("Unable to load record.") {
+        ModelError::RecordFormat(detail)
+    } else if detail.contains("different float type") || detail.contains("different int type") {
+        ModelError::UnsupportedPrecision
+    } else if detail.contains("different format") || detail.contains("different Burn version") {
+        ModelError::RecordFormat(detail)
+    } else {
+        ModelError::UnsupportedArch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MnistModel, PatchError, UnifiedModel};
+    use alloc::string::ToString;
+    use alloc::vec;
+    use burn::backend::NdArray;
+    use burn::prelude::*;
+    use burn::record::{BinBytesRecorder, FullPrecisionSettings, Recorder};
+
+    type B = NdArray;
+
+    // The MLP's layout with a narrower first hidden layer
+    #[derive(Module, Debug)]
+    struct NarrowModel<B: Backend> {
+        linear1: nn::Linear<B>,
+        linear2: nn::Linear<B>,
+        linear3: nn::Linear<B>,
+        output: nn::Linear<B>,
+        dropout: nn::Dropout,
+    }
+
+    // The MLP followed by one more layer
+    #[derive(Module, Debug)]
+    struct WiderModel<B: Backend> {
+        mlp: MnistModel<B>,
+        extra: nn::Linear<B>,
+    }
+
+    fn bin_record<M: Module<B>>(module: M) -> Vec<u8> {
+        BinBytesRecorder::<FullPrecisionSettings>::new()
+            .record(module.into_record(), ())
+            .unwrap()
+    }
+
+    fn record() -> Vec<u8> {
+        MnistModel::<B>::new(&Default::default()).export().unwrap()
+    }
+
+    fn import(bytes: Vec<u8>) -> ModelError {
+        MnistModel::<B>::import(&Default::default(), bytes).unwrap_err()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let _rng = crate::TEST_RNG.lock();
+        let model = UnifiedModel::<B>::new(&Default::default());
+        let imported = UnifiedModel::<B>::import(&Default::default(), model.export().unwrap());
+        assert_eq!(imported.unwrap().parameters(), model.parameters());
+    }
+
+    #[test]
+    fn truncated_records() {
+        let _rng = crate::TEST_RNG.lock();
+        let record = record();
+        for len in [0, 1, 16, record.len() / 2, record.len() - 64] {
+            let err = import(record[..len].to_vec());
+            assert!(matches!(err, ModelError::RecordFormat(_)), "{len}: {err:?}");
+        }
+    }
+
+    #[test]
+    fn corrupted_records() {
+        let _rng = crate::TEST_RNG.lock();
+        let mut mangled = record();
+        for byte in &mut mangled[..32] {
+            *byte ^= 0xff;
+        }
+        for bytes in [mangled, vec![0xa5; 4096]] {
+            let err = import(bytes);
+            assert!(matches!(err, ModelError::RecordFormat(_)), "{err:?}");
+        }
+    }
+
+    #[test]
+    fn records_of_other_modules() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let wider = WiderModel::<B> {
+            mlp: MnistModel::new(&device),
+            extra: nn::LinearConfig::new(10, 10).init(&device),
+        };
+        assert_eq!(import(bin_record(wider)), ModelError::UnsupportedArch);
+        let linear = nn::LinearConfig::new(784, 10).init::<B>(&device);
+        let err = import(bin_record(linear));
+        assert!(matches!(err, ModelError::RecordFormat(_)), "{err:?}");
+
+        let narrow = NarrowModel::<B> {
+            linear1: nn::LinearConfig::new(784, 64).init(&device),
+            linear2: nn::LinearConfig::new(64, 256).init(&device),
+            linear3: nn::LinearConfig::new(256, 128).init(&device),
+            output: nn::LinearConfig::new(128, 10).init(&device),
+            dropout: nn::DropoutConfig::new(0.0).init(),
+        };
+        let err = import(bin_record(narrow));
+        assert_eq!(
+            err,
+            ModelError::ShapeMismatch {
+                parameter: "linear1.weight".to_string(),
+                expected: vec![784, 512],
+                found: vec![784, 64],
+            }
+        );
+        assert_eq!(err.code(), inference::ERROR_MODEL_SHAPE_MISMATCH);
+    }
+
+    #[test]
+    fn corrupted_layer_patches() {
+        let _rng = crate::TEST_RNG.lock();
+        let model = UnifiedModel::<B>::new(&Default::default());
+        let patch = model.export_layer("linear2").unwrap();
+        let err = model
+            .apply_layer(
+                &Default::default(),
+                "linear2",
+                patch[..patch.len() / 2].to_vec(),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(err, PatchError::Record(ModelError::RecordFormat(_))),
+            "{err}"
+        );
+    }
+
+    fn metadata(float: &str, format: &str, version: &str) -> BurnMetadata {
+        BurnMetadata::new(
+            float.to_string(),
+            "i32".to_string(),
+            format.to_string(),
+            version.to_string(),
+            String::new(),
+        )
+    }
+
+    #[test]
+    fn metadata_classification() {
+        let expected = metadata("f32", "bin", "0.17.1");
+        let classify = |found| classify_metadata(&found, &expected, "detail".to_string());
+        assert_eq!(
+            classify(metadata("f16", "bin", "0.17.1")),
+            ModelError::UnsupportedPrecision
+        );
+        assert_eq!(
+            classify(metadata("f32", "mpk", "0.17.1")),
+            ModelError::RecordFormat("detail".to_string())
+        );
+        assert_eq!(
+            classify(metadata("f32", "bin", "0.16.0")),
+            ModelError::RecordFormat("detail".to_string())
+        );
+        assert_eq!(
+            classify(metadata("f32", "bin", "0.17.1")),
+            ModelError::UnsupportedArch
+        );
+    }
+
+    #[test]
+    fn recorder_errors() {
+        let unknown = |detail: &str| ModelError::from(RecorderError::Unknown(detail.to_string()));
+        assert_eq!(
+            unknown("Unable to load record.\nMetadata has a different float type"),
+            ModelError::UnsupportedPrecision
+        );
+        assert!(matches!(
+            unknown("Unable to load record.\nMetadata has a different Burn version"),
+            ModelError::RecordFormat(_)
+        ));
+        assert_eq!(
+            unknown("Unable to load record.\nError: ..."),
+            ModelError::UnsupportedArch
+        );
+        assert!(matches!(unknown("eof"), ModelError::RecordFormat(_)));
+        assert_eq!(
+            ModelError::from(RecorderError::FileNotFound("x".to_string())),
+            ModelError::Io("x".to_string())
+        );
+    }
+
+    #[test]
+    fn codes_are_distinct() {
+        let errors = [
+            ModelError::RecordFormat(String::new()),
+            ModelError::ShapeMismatch {
+                parameter: String::new(),
+                expected: Vec::new(),
+                found: Vec::new(),
+            },
+            ModelError::UnsupportedArch,
+            ModelError::UnsupportedPrecision,
+            ModelError::Metadata(ContainerError::Truncated),
+            ModelError::Io(String::new()),
+        ];
+        for (i, a) in errors.iter().enumerate() {
+            for b in &errors[i + 1..] {
+                assert_ne!(a.code(), b.code(), "{a:?} {b:?}");
+            }
+        }
+    }
+}
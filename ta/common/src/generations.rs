@@ -0,0 +1,619 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Generations of the stored model blob, kept apart from OP-TEE's storage so
+// interrupted writes and damaged pieces can be exercised in tests. A version
+// is written as the pieces of an unused generation and read back before the
+// manifest, a single object, is replaced to list it; the TA's
+// `secure_storage.rs` names the objects, tags the manifest for the device
+// and implements `GenerationStorage`.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::FlatManifest;
+
+/// Why a version couldn't be stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerationError {
+    /// The blob doesn't fit the manifest's 32-bit size.
+    TooLarge,
+    /// The written pieces don't read back as the blob.
+    Unreadable,
+}
+
+impl fmt::Display for GenerationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerationError::TooLarge => write!(f, "model too large to store"),
+            GenerationError::Unreadable => write!(f, "stored model doesn't read back intact"),
+        }
+    }
+}
+
+impl core::error::Error for GenerationError {}
+
+#[cfg(feature = "optee-utee")]
+impl From<GenerationError> for optee_utee::Error {
+    fn from(err: GenerationError) -> Self {
+        match err {
+            GenerationError::TooLarge => optee_utee::ErrorKind::OutOfMemory.into(),
+            GenerationError::Unreadable => optee_utee::ErrorKind::CorruptObject.into(),
+        }
+    }
+}
+
+/// One stored version of the model:
+/// generation (4) | chunk count (4) | total size (4) | provisioned (8) | SHA-256 (32).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenerationEntry {
+    pub generation: u32,
+    pub chunks: u32,
+    pub total_size: u32,
+    pub provisioned: u64,
+    pub hash: [u8; 32],
+}
+
+impl GenerationEntry {
+    pub const SIZE: usize = 20 + 32;
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.generation.to_le_bytes());
+        out.extend_from_slice(&self.chunks.to_le_bytes());
+        out.extend_from_slice(&self.total_size.to_le_bytes());
+        out.extend_from_slice(&self.provisioned.to_le_bytes());
+        out.extend_from_slice(&self.hash);
+    }
+
+    /// Reads the entry at the start of `bytes`, which must hold at least
+    /// `SIZE` bytes.
+    pub fn read(bytes: &[u8]) -> Self {
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut provisioned = [0u8; 8];
+        provisioned.copy_from_slice(&bytes[12..20]);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes[20..Self::SIZE]);
+        Self {
+            generation: word(0),
+            chunks: word(4),
+            total_size: word(8),
+            provisioned: u64::from_le_bytes(provisioned),
+            hash,
+        }
+    }
+}
+
+/// Stored versions, newest (active) first:
+/// magic (4) | entry count (4) | entries. The TA appends its device tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenerationManifest {
+    pub entries: Vec<GenerationEntry>,
+}
+
+impl GenerationManifest {
+    const HEADER_SIZE: usize = 8;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out =
+            Vec::with_capacity(Self::HEADER_SIZE + self.entries.len() * GenerationEntry::SIZE);
+        out.extend_from_slice(FlatManifest::MAGIC);
+        out.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for entry in &self.entries {
+            entry.write(&mut out);
+        }
+        out
+    }
+
+    /// `None` for anything that isn't a manifest `to_bytes` wrote, including
+    /// one listing no version.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::HEADER_SIZE || &bytes[..4] != FlatManifest::MAGIC {
+            return None;
+        }
+        let count = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let entries = &bytes[Self::HEADER_SIZE..];
+        if count == 0 || entries.len() != count * GenerationEntry::SIZE {
+            return None;
+        }
+        Some(Self {
+            entries: entries
+                .chunks_exact(GenerationEntry::SIZE)
+                .map(GenerationEntry::read)
+                .collect(),
+        })
+    }
+
+    pub fn uses(&self, generation: u32) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.generation == generation)
+    }
+}
+
+/// Storage as the generation functions see it.
+pub trait GenerationStorage {
+    type Error: From<GenerationError>;
+
+    /// Bytes per piece.
+    const CHUNK_SIZE: usize;
+    /// Versions kept besides the active one.
+    const HISTORY: usize;
+
+    /// Piece `index` of `generation`.
+    fn read_chunk(&mut self, generation: u32, index: u32) -> Result<Option<Vec<u8>>, Self::Error>;
+    /// Creates or replaces a piece.
+    fn write_chunk(&mut self, generation: u32, index: u32, data: &[u8]) -> Result<(), Self::Error>;
+    /// Returns whether the piece existed.
+    fn delete_chunk(&mut self, generation: u32, index: u32) -> Result<bool, Self::Error>;
+    fn sha256(&mut self, data: &[u8]) -> Result<[u8; 32], Self::Error>;
+    /// Replaces the manifest in a single write.
+    fn write_manifest(&mut self, manifest: &GenerationManifest) -> Result<(), Self::Error>;
+}
+
+/// Reassembles the pieces of `entry`, `None` unless they match its size and
+/// hash.
+pub fn load_generation<S: GenerationStorage>(
+    storage: &mut S,
+    entry: &GenerationEntry,
+) -> Result<Option<Vec<u8>>, S::Error> {
+    let mut bytes = Vec::with_capacity(entry.total_size as usize);
+    for index in 0..entry.chunks {
+        match storage.read_chunk(entry.generation, index)? {
+            Some(chunk) => bytes.extend_from_slice(&chunk),
+            None => return Ok(None),
+        }
+    }
+    if bytes.len() != entry.total_size as usize || storage.sha256(&bytes)? != entry.hash {
+        return Ok(None);
+    }
+    Ok(Some(bytes))
+}
+
+/// The first intact version among `entries` and its position, skipping the
+/// ones whose pieces are missing or fail their hash.
+pub fn load_first_intact<S: GenerationStorage>(
+    storage: &mut S,
+    entries: &[GenerationEntry],
+) -> Result<Option<(usize, Vec<u8>)>, S::Error> {
+    for (i, entry) in entries.iter().enumerate() {
+        if let Some(bytes) = load_generation(storage, entry)? {
+            return Ok(Some((i, bytes)));
+        }
+    }
+    Ok(None)
+}
+
+/// Removes pieces `first`, `first + 1`, ... of `generation` up to the first
+/// gap.
+pub fn delete_generation<S: GenerationStorage>(
+    storage: &mut S,
+    generation: u32,
+    first: u32,
+) -> Result<(), S::Error> {
+    let mut index = first;
+    while storage.delete_chunk(generation, index)? {
+        index += 1;
+    }
+    Ok(())
+}
+
+/// Writes `bytes` into the pieces of `generation` and checks they read back.
+/// Nothing lists the generation yet, so an interruption leaves pieces the
+/// next write into it replaces.
+pub fn write_generation<S: GenerationStorage>(
+    storage: &mut S,
+    generation: u32,
+    bytes: &[u8],
+    provisioned: u64,
+) -> Result<GenerationEntry, S::Error> {
+    let entry = GenerationEntry {
+        generation,
+        chunks: bytes.chunks(S::CHUNK_SIZE).len() as u32,
+        total_size: u32::try_from(bytes.len()).map_err(|_| GenerationError::TooLarge)?,
+        provisioned,
+        hash: storage.sha256(bytes)?,
+    };
+    for (index, chunk) in bytes.chunks(S::CHUNK_SIZE).enumerate() {
+        storage.write_chunk(generation, index as u32, chunk)?;
+    }
+    // Leftovers of an earlier, larger model in this generation
+    delete_generation(storage, generation, entry.chunks)?;
+    if load_generation(storage, &entry)?.is_none() {
+        return Err(GenerationError::Unreadable.into());
+    }
+    Ok(entry)
+}
+
+/// Replaces the manifest, then deletes the pieces of versions it no longer
+/// lists; until the manifest write completes the old one stays in effect.
+pub fn switch_manifest<S: GenerationStorage>(
+    storage: &mut S,
+    old: Option<&GenerationManifest>,
+    new: &GenerationManifest,
+) -> Result<(), S::Error> {
+    storage.write_manifest(new)?;
+    for dropped in old.iter().flat_map(|m| &m.entries) {
+        if !new.uses(dropped.generation) {
+            delete_generation(storage, dropped.generation, 0)?;
+        }
+    }
+    Ok(())
+}
+
+/// The lowest of `generations` neither listed in `manifest` nor holding
+/// `staged`.
+pub fn free_generation(
+    manifest: Option<&GenerationManifest>,
+    staged: Option<&GenerationEntry>,
+    generations: u32,
+) -> Option<u32> {
+    (0..generations)
+        .find(|&g| !manifest.is_some_and(|m| m.uses(g)) && staged.is_none_or(|s| s.generation != g))
+}
+
+/// Writes `bytes` into `generation`, a free one, and switches to a manifest
+/// listing it ahead of up to `S::HISTORY` versions of `old`; older ones are
+/// evicted. A failure at any step leaves `old` or the new manifest in
+/// effect, each listing only complete versions.
+pub fn store_version<S: GenerationStorage>(
+    storage: &mut S,
+    old: Option<&GenerationManifest>,
+    generation: u32,
+    bytes: &[u8],
+    provisioned: u64,
+) -> Result<GenerationEntry, S::Error> {
+    let entry = write_generation(storage, generation, bytes, provisioned)?;
+    let mut entries = alloc::vec![entry];
+    entries.extend(old.iter().flat_map(|m| &m.entries).take(S::HISTORY));
+    switch_manifest(storage, old, &GenerationManifest { entries })?;
+    Ok(entry)
+}
+
+/// Whether storing `requested` more bytes next to the `used` ones would be
+/// attempted. Without a known `quota` the answer is optimistic: the new
+/// version is stored before it replaces the active one, so running out of
+/// storage still leaves the active one in place.
+pub fn storage_fits(used: usize, requested: usize, quota: Option<usize>) -> bool {
+    quota.is_none_or(|quota| used.saturating_add(requested) <= quota)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::vec;
+
+    const HISTORY: usize = 2;
+    const GENERATIONS: u32 = HISTORY as u32 + 3;
+
+    #[derive(Debug, PartialEq)]
+    enum Fault {
+        PowerLost,
+        StorageFull,
+        Generation(GenerationError),
+    }
+
+    impl From<GenerationError> for Fault {
+        fn from(err: GenerationError) -> Self {
+            Fault::Generation(err)
+        }
+    }
+
+    // Toy hash; only equality matters here
+    fn hash(data: &[u8]) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        for (i, &byte) in data.iter().enumerate() {
+            out[i % 32] = out[i % 32].wrapping_mul(31).wrapping_add(byte);
+        }
+        out[31] ^= data.len() as u8;
+        out
+    }
+
+    // Pieces by generation and index plus the manifest, as a storage image
+    // would hold them
+    #[derive(Default, Clone)]
+    struct Store {
+        chunks: BTreeMap<(u32, u32), Vec<u8>>,
+        manifest: Option<Vec<u8>>,
+        // Fail every write and delete once this many went through, as a
+        // power loss would
+        steps_left: Option<usize>,
+        // Flip a bit of every piece written, as a failing flash would
+        corrupt_writes: bool,
+        // Bytes the pieces may take in all
+        capacity: Option<usize>,
+    }
+
+    impl Store {
+        fn step(&mut self) -> Result<(), Fault> {
+            if let Some(left) = self.steps_left.as_mut() {
+                *left = left.checked_sub(1).ok_or(Fault::PowerLost)?;
+            }
+            Ok(())
+        }
+
+        fn manifest(&self) -> Option<GenerationManifest> {
+            GenerationManifest::from_bytes(self.manifest.as_ref()?)
+        }
+
+        // What loading after a restart finds
+        fn loaded(&mut self) -> Option<Vec<u8>> {
+            let manifest = self.manifest()?;
+            load_first_intact(self, &manifest.entries)
+                .unwrap()
+                .map(|(_, bytes)| bytes)
+        }
+
+        fn store(&mut self, bytes: &[u8]) -> Result<GenerationEntry, Fault> {
+            let old = self.manifest();
+            let generation = free_generation(old.as_ref(), None, GENERATIONS).unwrap();
+            store_version(self, old.as_ref(), generation, bytes, 0)
+        }
+    }
+
+    impl GenerationStorage for Store {
+        type Error = Fault;
+
+        const CHUNK_SIZE: usize = 100;
+        const HISTORY: usize = HISTORY;
+
+        fn read_chunk(&mut self, generation: u32, index: u32) -> Result<Option<Vec<u8>>, Fault> {
+            Ok(self.chunks.get(&(generation, index)).cloned())
+        }
+
+        fn write_chunk(&mut self, generation: u32, index: u32, data: &[u8]) -> Result<(), Fault> {
+            self.step()?;
+            let used: usize = self.chunks.values().map(Vec::len).sum();
+            if self
+                .capacity
+                .is_some_and(|capacity| used + data.len() > capacity)
+            {
+                return Err(Fault::StorageFull);
+            }
+            let mut data = data.to_vec();
+            if self.corrupt_writes {
+                data[0] ^= 1;
+            }
+            self.chunks.insert((generation, index), data);
+            Ok(())
+        }
+
+        fn delete_chunk(&mut self, generation: u32, index: u32) -> Result<bool, Fault> {
+            self.step()?;
+            Ok(self.chunks.remove(&(generation, index)).is_some())
+        }
+
+        fn sha256(&mut self, data: &[u8]) -> Result<[u8; 32], Fault> {
+            Ok(hash(data))
+        }
+
+        fn write_manifest(&mut self, manifest: &GenerationManifest) -> Result<(), Fault> {
+            self.step()?;
+            self.manifest = Some(manifest.to_bytes());
+            Ok(())
+        }
+    }
+
+    fn model(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8 ^ seed).collect()
+    }
+
+    #[test]
+    fn stored_models_load_back() {
+        let mut store = Store::default();
+        assert_eq!(store.loaded(), None);
+        for len in [1, 99, 100, 101, 1000] {
+            store.store(&model(len, 1)).unwrap();
+            assert_eq!(store.loaded(), Some(model(len, 1)), "{len} bytes");
+        }
+        // The active version plus the history, no leftover pieces
+        let manifest = store.manifest().unwrap();
+        assert_eq!(manifest.entries.len(), HISTORY + 1);
+        let pieces: u32 = manifest.entries.iter().map(|e| e.chunks).sum();
+        assert_eq!(store.chunks.len(), pieces as usize);
+    }
+
+    #[test]
+    fn manifest_round_trips() {
+        let manifest = GenerationManifest {
+            entries: vec![
+                GenerationEntry {
+                    generation: 3,
+                    chunks: 2,
+                    total_size: 150,
+                    provisioned: 1_760_000_000,
+                    hash: [7; 32],
+                },
+                GenerationEntry {
+                    generation: 0,
+                    chunks: 1,
+                    total_size: 1,
+                    provisioned: 0,
+                    hash: [9; 32],
+                },
+            ],
+        };
+        let bytes = manifest.to_bytes();
+        assert_eq!(bytes.len(), 8 + 2 * GenerationEntry::SIZE);
+        assert_eq!(GenerationManifest::from_bytes(&bytes), Some(manifest));
+        assert_eq!(
+            GenerationManifest::from_bytes(&bytes[..bytes.len() - 1]),
+            None
+        );
+        // Nor is an empty manifest or a flat one
+        let empty = GenerationManifest { entries: vec![] }.to_bytes();
+        assert_eq!(GenerationManifest::from_bytes(&empty), None);
+        let flat = FlatManifest {
+            chunks: 1,
+            total_size: 1,
+            hash: [0; 32],
+        };
+        assert_eq!(GenerationManifest::from_bytes(&flat.to_bytes()), None);
+    }
+
+    #[test]
+    fn partial_writes_leave_the_old_model() {
+        let old = model(1000, 1);
+        let new = model(750, 2);
+        // Power lost after every number of pieces of the new model
+        for written in 0..8 {
+            let mut store = Store::default();
+            store.store(&old).unwrap();
+            let manifest = store.manifest.clone();
+            store.steps_left = Some(written);
+            assert_eq!(store.store(&new), Err(Fault::PowerLost));
+            assert_eq!(store.manifest, manifest, "{written} pieces");
+            assert_eq!(store.loaded(), Some(old.clone()));
+
+            // The next store reuses the generation over the leftovers
+            store.steps_left = None;
+            store.store(&new).unwrap();
+            assert_eq!(store.loaded(), Some(new.clone()));
+            let manifest = store.manifest().unwrap();
+            assert_eq!(
+                load_generation(&mut store, &manifest.entries[1]).unwrap(),
+                Some(old.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn every_interruption_leaves_a_complete_model() {
+        // A full history, so storing also evicts the oldest version
+        let mut base = Store::default();
+        for seed in 1..=HISTORY as u8 + 1 {
+            base.store(&model(1000, seed)).unwrap();
+        }
+        let old = model(1000, HISTORY as u8 + 1);
+        let new = model(1234, 0x40);
+        let mut steps = 0;
+        loop {
+            let mut store = base.clone();
+            store.steps_left = Some(steps);
+            let stored = store.store(&new);
+            let loaded = store.loaded();
+            match stored {
+                Ok(_) => {
+                    assert_eq!(loaded, Some(new.clone()));
+                    break;
+                }
+                Err(err) => {
+                    assert_eq!(err, Fault::PowerLost);
+                    assert!(
+                        loaded == Some(old.clone()) || loaded == Some(new.clone()),
+                        "{steps} steps"
+                    );
+                }
+            }
+            // After the restart, storing again succeeds and leaves no
+            // pieces of evicted versions behind
+            store.steps_left = None;
+            store.store(&new).unwrap();
+            assert_eq!(store.loaded(), Some(new.clone()));
+            let manifest = store.manifest().unwrap();
+            assert!(store.chunks.keys().all(|&(g, _)| manifest.uses(g)));
+            steps += 1;
+        }
+        // Pieces, the leftover check, the manifest and the eviction
+        assert!(steps > 13, "{steps} steps");
+    }
+
+    #[test]
+    fn running_out_of_storage_keeps_the_active_model() {
+        let mut store = Store::default();
+        store.store(&model(1000, 1)).unwrap();
+        // Room for the active version and half of the new one
+        store.capacity = Some(1500);
+        assert!(storage_fits(1000, 1000, None));
+        assert!(!storage_fits(1000, 1000, Some(1500)));
+        assert_eq!(store.store(&model(1000, 2)), Err(Fault::StorageFull));
+        assert_eq!(store.loaded(), Some(model(1000, 1)));
+        // With room the same store goes through over the leftovers
+        assert!(storage_fits(1000, 1000, Some(2000)));
+        store.capacity = Some(2000);
+        store.store(&model(1000, 2)).unwrap();
+        assert_eq!(store.loaded(), Some(model(1000, 2)));
+    }
+
+    #[test]
+    fn pieces_that_dont_read_back_are_refused() {
+        let mut store = Store::default();
+        store.store(&model(500, 1)).unwrap();
+        let manifest = store.manifest.clone();
+        store.corrupt_writes = true;
+        assert_eq!(
+            store.store(&model(500, 2)),
+            Err(Fault::Generation(GenerationError::Unreadable))
+        );
+        assert_eq!(store.manifest, manifest);
+        assert_eq!(store.loaded(), Some(model(500, 1)));
+    }
+
+    #[test]
+    fn damaged_versions_fall_back_to_older_ones() {
+        let mut store = Store::default();
+        for seed in 1..=3 {
+            store.store(&model(300, seed)).unwrap();
+        }
+        let manifest = store.manifest().unwrap();
+        let [active, previous, oldest] = [0, 1, 2].map(|i| manifest.entries[i].generation);
+
+        // A piece changed: the active version fails its hash
+        store.chunks.get_mut(&(active, 1)).unwrap()[5] ^= 0x80;
+        let found = load_first_intact(&mut store, &manifest.entries).unwrap();
+        assert_eq!(found, Some((1, model(300, 2))));
+
+        // A piece missing
+        store.chunks.remove(&(previous, 2));
+        let found = load_first_intact(&mut store, &manifest.entries).unwrap();
+        assert_eq!(found, Some((2, model(300, 1))));
+
+        // A piece of the wrong length
+        store.chunks.get_mut(&(oldest, 0)).unwrap().pop();
+        assert_eq!(
+            load_first_intact(&mut store, &manifest.entries).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn free_generations_skip_listed_and_staged_ones() {
+        let mut store = Store::default();
+        for seed in 1..=4 {
+            store.store(&model(10, seed)).unwrap();
+        }
+        let manifest = store.manifest().unwrap();
+        let staged = GenerationEntry {
+            generation: free_generation(Some(&manifest), None, GENERATIONS).unwrap(),
+            ..manifest.entries[0]
+        };
+        let free = free_generation(Some(&manifest), Some(&staged), GENERATIONS).unwrap();
+        assert!(!manifest.uses(free) && free != staged.generation);
+        assert!(free < GENERATIONS);
+        // Every generation in use
+        let full = GenerationManifest {
+            entries: (0..GENERATIONS)
+                .map(|generation| GenerationEntry {
+                    generation,
+                    ..staged
+                })
+                .collect(),
+        };
+        assert_eq!(free_generation(Some(&full), None, GENERATIONS), None);
+    }
+}
@@ -0,0 +1,171 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+// Heap checks of model loads, kept apart from the TA's allocator so they can
+// run against any heap in tests. A load decrypts its ciphertext in place
+// through the key manager's scratch chunk, then decodes the record into
+// tensor data and builds the model from it, each about the size of the
+// plaintext. It is refused before decrypting when that doesn't fit.
+
+use core::fmt;
+
+/// Precision `largest_allocation` finds the largest block to.
+pub const PROBE_GRANULE: usize = 4096;
+
+/// Heap a model load needs on top of its `ciphertext_len` byte ciphertext,
+/// which is already allocated.
+pub fn load_footprint(ciphertext_len: usize) -> usize {
+    proto::CHUNK_SIZE.saturating_add(ciphertext_len.saturating_mul(2))
+}
+
+/// A load that doesn't fit the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapShortfall {
+    pub needed: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for HeapShortfall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "load needs ~{} heap bytes, {} available",
+            self.needed, self.available
+        )
+    }
+}
+
+impl core::error::Error for HeapShortfall {}
+
+#[cfg(feature = "optee-utee")]
+impl From<HeapShortfall> for optee_utee::Error {
+    fn from(_: HeapShortfall) -> Self {
+        optee_utee::ErrorKind::OutOfMemory.into()
+    }
+}
+
+/// Fails when `needed` more bytes don't fit in `available`.
+pub fn check_heap(needed: usize, available: usize) -> Result<(), HeapShortfall> {
+    if needed <= available {
+        Ok(())
+    } else {
+        Err(HeapShortfall { needed, available })
+    }
+}
+
+/// Bytes left of a `size` byte heap with `allocated` bytes in use.
+pub fn heap_available(size: usize, allocated: usize) -> usize {
+    size.saturating_sub(allocated)
+}
+
+/// Largest block of at most `limit` bytes `try_alloc` manages to allocate,
+/// to within `PROBE_GRANULE` bytes, for heaps that keep no statistics.
+/// `try_alloc` frees what it allocates.
+pub fn largest_allocation(limit: usize, mut try_alloc: impl FnMut(usize) -> bool) -> usize {
+    if try_alloc(limit) {
+        return limit;
+    }
+    // `low` bytes can be allocated, `high` can't
+    let (mut low, mut high) = (0, limit);
+    while high - low > PROBE_GRANULE {
+        let mid = low + (high - low) / 2;
+        if try_alloc(mid) {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A TA heap artificially smaller than the default 16 MiB one
+    const LOW_BUDGET: usize = 2 * 1024 * 1024;
+
+    #[test]
+    fn footprint_counts_the_scratch_chunk_and_two_plaintexts() {
+        assert_eq!(load_footprint(0), proto::CHUNK_SIZE);
+        assert_eq!(load_footprint(1000), proto::CHUNK_SIZE + 2000);
+        assert_eq!(load_footprint(usize::MAX), usize::MAX);
+        assert_eq!(load_footprint(usize::MAX / 2 + 1), usize::MAX);
+    }
+
+    #[test]
+    fn available_heap_never_goes_negative() {
+        assert_eq!(heap_available(LOW_BUDGET, 0), LOW_BUDGET);
+        assert_eq!(heap_available(LOW_BUDGET, 1024), LOW_BUDGET - 1024);
+        assert_eq!(heap_available(LOW_BUDGET, LOW_BUDGET), 0);
+        assert_eq!(heap_available(LOW_BUDGET, LOW_BUDGET + 1), 0);
+    }
+
+    #[test]
+    fn loads_over_a_low_budget_fail_early_with_the_shortfall() {
+        let allocated = 256 * 1024;
+        let available = heap_available(LOW_BUDGET, allocated);
+        // Largest ciphertext that still fits, and the next byte up
+        let fits = (available - proto::CHUNK_SIZE) / 2;
+        assert_eq!(check_heap(load_footprint(fits), available), Ok(()));
+        assert_eq!(
+            check_heap(load_footprint(fits + 1), available),
+            Err(HeapShortfall {
+                needed: load_footprint(fits + 1),
+                available,
+            })
+        );
+        // The same model fits the default heap
+        let default = 16 * 1024 * 1024;
+        assert_eq!(
+            check_heap(load_footprint(fits + 1), heap_available(default, allocated)),
+            Ok(())
+        );
+        // Nothing fits once the heap is used up
+        assert_eq!(
+            check_heap(load_footprint(0), heap_available(LOW_BUDGET, LOW_BUDGET)),
+            Err(HeapShortfall {
+                needed: proto::CHUNK_SIZE,
+                available: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn probing_finds_the_largest_block_within_a_granule() {
+        let frees = [0, 1, PROBE_GRANULE, 100_000, LOW_BUDGET - 1, LOW_BUDGET];
+        for free in frees.into_iter().chain([usize::MAX]) {
+            let mut probes = 0;
+            let found = largest_allocation(LOW_BUDGET, |size| {
+                probes += 1;
+                size <= free
+            });
+            let expected = free.min(LOW_BUDGET);
+            assert!(found <= expected, "{free}: found {found}");
+            assert!(expected - found <= PROBE_GRANULE, "{free}: found {found}");
+            // One probe of the whole heap, then a bisection down to a granule
+            assert!(probes <= 1 + 10, "{free}: {probes} probes");
+        }
+    }
+
+    #[test]
+    fn probing_never_asks_for_more_than_the_limit() {
+        largest_allocation(LOW_BUDGET, |size| {
+            assert!(size <= LOW_BUDGET);
+            size < 12345
+        });
+    }
+}
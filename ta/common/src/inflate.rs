@@ -0,0 +1,461 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// DEFLATE (RFC 1951) decoder for compressed model containers, small enough
+// for the TA: canonical Huffman codes are decoded bit by bit, as in zlib's
+// puff.c, rather than through lookup tables. The output never grows past
+// the length the container declares, so a crafted stream can't allocate
+// more than the caller already accepted.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+const MAX_BITS: usize = 15;
+const MAX_LITERAL_CODES: usize = 288;
+const MAX_DISTANCE_CODES: usize = 30;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order in which a dynamic block lists the code length code lengths
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    /// The stream ends before its last block does.
+    Truncated,
+    /// Reserved block type, or a stored block whose length check fails.
+    InvalidBlock,
+    /// Over-subscribed code lengths or a bit sequence no code matches.
+    InvalidCode,
+    /// A back-reference before the start of the output.
+    InvalidDistance,
+    /// The stream decodes to more than the declared length.
+    TooLong,
+    /// The stream decodes to less than the declared length.
+    TooShort,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            InflateError::Truncated => "truncated stream",
+            InflateError::InvalidBlock => "invalid block",
+            InflateError::InvalidCode => "invalid Huffman code",
+            InflateError::InvalidDistance => "back-reference out of range",
+            InflateError::TooLong => "longer than declared",
+            InflateError::TooShort => "shorter than declared",
+        };
+        write!(f, "deflate: {}", reason)
+    }
+}
+
+/// Decodes the raw DEFLATE stream `input`, which must produce exactly
+/// `len` bytes; `len` is allocated up front, so callers bound it first.
+pub fn inflate(input: &[u8], len: usize) -> Result<Vec<u8>, InflateError> {
+    let mut bits = Bits::new(input);
+    let mut out = Vec::with_capacity(len);
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(&mut bits, &mut out, len)?,
+            1 => {
+                let (literals, distances) = fixed_codes();
+                codes(&mut bits, &mut out, len, &literals, &distances)?
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(&mut bits)?;
+                codes(&mut bits, &mut out, len, &literals, &distances)?
+            }
+            _ => return Err(InflateError::InvalidBlock),
+        }
+        if last {
+            break;
+        }
+    }
+    if out.len() != len {
+        return Err(InflateError::TooShort);
+    }
+    Ok(out)
+}
+
+// LSB-first bit reader
+struct Bits<'a> {
+    input: &'a [u8],
+    pos: usize,
+    buf: u64,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            buf: 0,
+            count: 0,
+        }
+    }
+
+    fn take(&mut self, n: u32) -> Result<u32, InflateError> {
+        while self.count < n {
+            let byte = *self.input.get(self.pos).ok_or(InflateError::Truncated)?;
+            self.buf |= (byte as u64) << self.count;
+            self.count += 8;
+            self.pos += 1;
+        }
+        let value = (self.buf & ((1 << n) - 1)) as u32;
+        self.buf >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Drops the rest of the current byte
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buf >>= partial;
+        self.count -= partial;
+    }
+}
+
+// Canonical Huffman code: how many codes of each length, and the symbols
+// ordered by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    // Incomplete codes are accepted (a distance code may have a single
+    // symbol); decoding an unused code fails instead
+    fn new(lengths: &[u8]) -> Result<Self, InflateError> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(InflateError::InvalidCode);
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = alloc::vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, InflateError> {
+        // First code of the current length, and its index in `symbols`
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::InvalidCode)
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, len: usize) -> Result<(), InflateError> {
+    bits.align();
+    let size = bits.take(16)?;
+    if bits.take(16)? != !size & 0xffff {
+        return Err(InflateError::InvalidBlock);
+    }
+    if out.len() + size as usize > len {
+        return Err(InflateError::TooLong);
+    }
+    for _ in 0..size {
+        out.push(bits.take(8)? as u8);
+    }
+    Ok(())
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; MAX_LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    // Both are complete, so building them can't fail
+    let literals = Huffman::new(&lengths).unwrap_or_else(|_| unreachable!());
+    let distances = Huffman::new(&[5; MAX_DISTANCE_CODES]).unwrap_or_else(|_| unreachable!());
+    (literals, distances)
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let length_count = bits.take(4)? as usize + 4;
+    if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+        return Err(InflateError::InvalidCode);
+    }
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..length_count] {
+        code_lengths[index] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = [0u8; MAX_LITERAL_CODES + MAX_DISTANCE_CODES];
+    let total = literal_count + distance_count;
+    let mut i = 0;
+    while i < total {
+        let symbol = code_lengths.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i].last().ok_or(InflateError::InvalidCode)?;
+                (previous, 3 + bits.take(2)? as usize)
+            }
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if i + repeat > total {
+            return Err(InflateError::InvalidCode);
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[END_OF_BLOCK as usize] == 0 {
+        return Err(InflateError::InvalidCode);
+    }
+    let literals = Huffman::new(&lengths[..literal_count])?;
+    let distances = Huffman::new(&lengths[literal_count..total])?;
+    Ok((literals, distances))
+}
+
+fn codes(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    len: usize,
+    literals: &Huffman,
+    distances: &Huffman,
+) -> Result<(), InflateError> {
+    loop {
+        let symbol = literals.decode(bits)?;
+        if symbol < END_OF_BLOCK {
+            if out.len() >= len {
+                return Err(InflateError::TooLong);
+            }
+            out.push(symbol as u8);
+            continue;
+        }
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        let symbol = (symbol - END_OF_BLOCK - 1) as usize;
+        if symbol >= LENGTH_BASE.len() {
+            return Err(InflateError::InvalidCode);
+        }
+        let length =
+            LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol] as u32)? as usize;
+        let symbol = distances.decode(bits)? as usize;
+        if symbol >= DISTANCE_BASE.len() {
+            return Err(InflateError::InvalidCode);
+        }
+        let distance =
+            DISTANCE_BASE[symbol] as usize + bits.take(DISTANCE_EXTRA[symbol] as u32)? as usize;
+        if distance > out.len() {
+            return Err(InflateError::InvalidDistance);
+        }
+        if out.len() + length > len {
+            return Err(InflateError::TooLong);
+        }
+        // Byte by byte: the copy may overlap what it appends
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use flate2::{write::DeflateEncoder, Compression};
+
+    extern crate std;
+    use std::io::Write;
+
+    fn deflate(data: &[u8], level: u32) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Inputs that make the encoder pick stored, fixed and dynamic blocks,
+    // long back-references included
+    fn samples() -> Vec<Vec<u8>> {
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..40_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let floats: Vec<u8> = (0..10_000)
+            .flat_map(|i| ((i % 97) as f32 * 0.01).to_le_bytes())
+            .collect();
+        // Repeats further back than the 32 KiB window
+        let mut repeated = noise.clone();
+        repeated.extend_from_slice(&noise);
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"a model record, a model record, a model record".to_vec(),
+            vec![0; 50_000],
+            noise,
+            floats,
+            repeated,
+        ]
+    }
+
+    #[test]
+    fn round_trips_flate2_at_every_level() {
+        for data in samples() {
+            for level in 0..=9 {
+                let compressed = deflate(&data, level);
+                assert_eq!(
+                    inflate(&compressed, data.len()).as_deref(),
+                    Ok(&data[..]),
+                    "{} bytes at level {}",
+                    data.len(),
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn output_must_have_the_declared_length() {
+        let data = b"a model record, a model record, a model record";
+        for level in [0, 1, 9] {
+            let compressed = deflate(data, level);
+            assert_eq!(
+                inflate(&compressed, data.len() - 1),
+                Err(InflateError::TooLong)
+            );
+            assert_eq!(
+                inflate(&compressed, data.len() + 1),
+                Err(InflateError::TooShort)
+            );
+        }
+        // A declared length far past what the stream holds is only reached
+        // by decoding it
+        assert_eq!(
+            inflate(&deflate(b"tiny", 9), 1 << 20),
+            Err(InflateError::TooShort)
+        );
+    }
+
+    #[test]
+    fn truncated_streams_fail() {
+        for data in samples().into_iter().filter(|data| !data.is_empty()) {
+            for level in [0, 1, 9] {
+                let compressed = deflate(&data, level);
+                // Every cut near the ends, where the block headers and the
+                // end-of-block code are, and a spread in between
+                let len = compressed.len();
+                let step = (len / 30).max(1);
+                let cuts = (0..len.min(16))
+                    .chain((0..len).step_by(step))
+                    .chain(len.saturating_sub(16)..len);
+                for cut in cuts.filter(|&cut| cut < len - 1) {
+                    assert!(inflate(&compressed[..cut], data.len()).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn garbage_fails_without_panicking() {
+        // Final block of the reserved type 3
+        assert_eq!(inflate(&[0x07], 0), Err(InflateError::InvalidBlock));
+        // Stored block whose length check doesn't match
+        assert_eq!(
+            inflate(&[0x01, 0x05, 0x00, 0x00, 0x00], 5),
+            Err(InflateError::InvalidBlock)
+        );
+        // Fixed block starting with a back-reference: length code 257,
+        // distance code 0
+        assert_eq!(
+            inflate(&[0x03, 0x02, 0x00], 3),
+            Err(InflateError::InvalidDistance)
+        );
+        assert_eq!(inflate(&[], 0), Err(InflateError::Truncated));
+        let mut state = 1_u32;
+        for len in [1, 2, 16, 300, 4096] {
+            for _ in 0..200 {
+                let input: Vec<u8> = (0..len)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                        (state >> 16) as u8
+                    })
+                    .collect();
+                let _ = inflate(&input, 1024);
+            }
+        }
+    }
+
+    #[test]
+    fn flipped_bits_never_decode_past_the_declared_length() {
+        let data = b"a model record, a model record, a model record".repeat(10);
+        let compressed = deflate(&data, 9);
+        for byte in 0..compressed.len() {
+            for bit in 0..8 {
+                let mut corrupt = compressed.clone();
+                corrupt[byte] ^= 1 << bit;
+                if let Ok(out) = inflate(&corrupt, data.len()) {
+                    assert_eq!(out.len(), data.len());
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,8 @@
 use burn::{
-    config::Config,
     module::Module,
     nn::{
         conv::{Conv2d, Conv2dConfig},
-        BatchNorm, BatchNormConfig, ReLU6,
+        BatchNorm, BatchNormConfig,
     },
     tensor::{backend::Backend, Tensor},
 };
@@ -50,7 +49,7 @@ impl<B: Backend> ConvNorm<B> {
     }
 }
 
-#[derive(Config, Debug)]
+#[derive(Clone, Debug)]
 pub struct InvertedResidualConfig {
     pub inp: usize,
     pub oup: usize,
@@ -0,0 +1,204 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// What a StoreKey request carrying a key commitment does, decided from the
+// hashes alone so the TA's key manager and crypto stay out of it. Storing
+// the key the TA already has succeeds without writing, so a host retrying
+// after a lost reply gets the same answer as the first attempt; a different
+// key is only replaced when the request forces it.
+
+use core::fmt;
+
+pub type KeyHash = [u8; 32];
+
+/// What to do with the key of a StoreKey request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStoreAction {
+    /// No key is stored yet: import it.
+    Import,
+    /// The stored key is the one sent: nothing to write.
+    AlreadyStored,
+    /// A different key is stored and the request forces its replacement.
+    Replace,
+}
+
+/// Why a StoreKey request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStoreError {
+    /// The commitment isn't the hash of the key sent with it.
+    CommitmentMismatch,
+    /// A different key is stored and the request doesn't force.
+    Conflict,
+}
+
+impl fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyStoreError::CommitmentMismatch => write!(f, "key commitment doesn't match the key"),
+            KeyStoreError::Conflict => write!(f, "a different key is already stored"),
+        }
+    }
+}
+
+impl core::error::Error for KeyStoreError {}
+
+#[cfg(feature = "optee-utee")]
+impl From<KeyStoreError> for optee_utee::Error {
+    fn from(err: KeyStoreError) -> Self {
+        match err {
+            KeyStoreError::CommitmentMismatch => optee_utee::ErrorKind::BadParameters.into(),
+            KeyStoreError::Conflict => optee_utee::ErrorKind::AccessConflict.into(),
+        }
+    }
+}
+
+/// Decides a StoreKey request from the hash of the key sent, its
+/// `commitment` and the hash of the stored key, if any.
+pub fn store_key_action(
+    key_hash: &KeyHash,
+    commitment: &KeyHash,
+    stored_hash: Option<&KeyHash>,
+    force: bool,
+) -> Result<KeyStoreAction, KeyStoreError> {
+    if !constant_time_eq(key_hash, commitment) {
+        return Err(KeyStoreError::CommitmentMismatch);
+    }
+    match stored_hash {
+        None => Ok(KeyStoreAction::Import),
+        Some(stored) if constant_time_eq(stored, commitment) => Ok(KeyStoreAction::AlreadyStored),
+        Some(_) if force => Ok(KeyStoreAction::Replace),
+        Some(_) => Err(KeyStoreError::Conflict),
+    }
+}
+
+/// Compares without an early exit, so the time taken doesn't tell how many
+/// leading bytes matched.
+pub fn constant_time_eq(a: &KeyHash, b: &KeyHash) -> bool {
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The TA's side of StoreKey: the hash of the stored key, and how many
+    // times a key was written or replaced (what the TA traces)
+    #[derive(Default)]
+    struct Ta {
+        stored: Option<KeyHash>,
+        writes: usize,
+        replaced: usize,
+    }
+
+    impl Ta {
+        fn store(&mut self, key: KeyHash, force: bool) -> Result<KeyStoreAction, KeyStoreError> {
+            self.store_committed(key, key, force)
+        }
+
+        fn store_committed(
+            &mut self,
+            key: KeyHash,
+            commitment: KeyHash,
+            force: bool,
+        ) -> Result<KeyStoreAction, KeyStoreError> {
+            let action = store_key_action(&key, &commitment, self.stored.as_ref(), force)?;
+            match action {
+                KeyStoreAction::Import => self.writes += 1,
+                KeyStoreAction::Replace => {
+                    self.writes += 1;
+                    self.replaced += 1;
+                }
+                KeyStoreAction::AlreadyStored => {}
+            }
+            if action != KeyStoreAction::AlreadyStored {
+                self.stored = Some(key);
+            }
+            Ok(action)
+        }
+    }
+
+    const KEY: KeyHash = [1; 32];
+    const OTHER: KeyHash = [2; 32];
+
+    #[test]
+    fn lost_reply_retry_succeeds_without_writing() {
+        let mut ta = Ta::default();
+        // The first attempt stores the key but its reply never arrives
+        assert_eq!(ta.store(KEY, false), Ok(KeyStoreAction::Import));
+        assert_eq!(ta.store(KEY, false), Ok(KeyStoreAction::AlreadyStored));
+        assert_eq!(ta.stored, Some(KEY));
+        assert_eq!(ta.writes, 1);
+    }
+
+    #[test]
+    fn different_key_conflicts() {
+        let mut ta = Ta::default();
+        ta.store(KEY, false).unwrap();
+        assert_eq!(ta.store(OTHER, false), Err(KeyStoreError::Conflict));
+        assert_eq!(ta.stored, Some(KEY));
+        assert_eq!(ta.writes, 1);
+        // Differing in the last byte only is still a different key
+        let mut last = KEY;
+        last[31] ^= 1;
+        assert_eq!(ta.store(last, false), Err(KeyStoreError::Conflict));
+    }
+
+    #[test]
+    fn forced_overwrite() {
+        let mut ta = Ta::default();
+        // Forcing changes nothing while there's nothing to replace
+        assert_eq!(ta.store(KEY, true), Ok(KeyStoreAction::Import));
+        assert_eq!(ta.store(KEY, true), Ok(KeyStoreAction::AlreadyStored));
+        assert_eq!(ta.replaced, 0);
+
+        assert_eq!(ta.store(OTHER, true), Ok(KeyStoreAction::Replace));
+        assert_eq!(ta.stored, Some(OTHER));
+        assert_eq!(ta.replaced, 1);
+        // A forced request retried after a lost reply finds its key stored
+        assert_eq!(ta.store(OTHER, true), Ok(KeyStoreAction::AlreadyStored));
+        assert_eq!((ta.writes, ta.replaced), (2, 1));
+    }
+
+    #[test]
+    fn commitments_must_match_the_key() {
+        let mut ta = Ta::default();
+        assert_eq!(
+            ta.store_committed(KEY, OTHER, false),
+            Err(KeyStoreError::CommitmentMismatch)
+        );
+        ta.store(KEY, false).unwrap();
+        // Checked before the stored key, even when forced
+        for force in [false, true] {
+            assert_eq!(
+                ta.store_committed(OTHER, KEY, force),
+                Err(KeyStoreError::CommitmentMismatch)
+            );
+        }
+        assert_eq!(ta.stored, Some(KEY));
+        assert_eq!(ta.writes, 1);
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(&KEY, &KEY));
+        for i in [0, 15, 31] {
+            let mut other = KEY;
+            other[i] ^= 0x80;
+            assert!(!constant_time_eq(&KEY, &other));
+        }
+    }
+}
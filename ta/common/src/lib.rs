@@ -18,8 +18,63 @@
 #![no_std]
 extern crate alloc;
 
+mod cbc;
+mod container;
+mod device_tag;
+mod error;
+mod generations;
+mod heap;
+#[cfg(feature = "deflate")]
+mod inflate;
+mod key_store;
+mod load_state;
+mod migration;
 mod model;
+mod residency;
+mod result_cache;
+mod rotation;
+mod shadow;
+mod signature;
+mod slots;
+mod time_budget;
+mod trusted_clock;
 mod utils;
 
+pub use cbc::*;
+pub use container::*;
+pub use device_tag::*;
+pub use error::*;
+pub use generations::*;
+pub use heap::*;
+#[cfg(feature = "deflate")]
+pub use inflate::*;
+pub use key_store::*;
+pub use load_state::*;
+pub use migration::*;
 pub use model::*;
+pub use residency::*;
+pub use result_cache::*;
+pub use rotation::*;
+pub use shadow::*;
+pub use signature::*;
+pub use slots::*;
+pub use time_budget::*;
+pub use trusted_clock::*;
 pub use utils::*;
+
+// The backend RNG is global: tests that draw from it, by building a model
+// and using its parameters, hold this so that seeded models come out the same
+#[cfg(test)]
+static TEST_RNG: spin::Mutex<()> = spin::Mutex::new(());
+
+// Convolutional building blocks no model uses yet; the TA leaves them out to
+// stay under the TA size limit
+#[cfg(feature = "conv-models")]
+mod conv_norm;
+#[cfg(feature = "conv-models")]
+mod inverted_residual;
+
+#[cfg(feature = "conv-models")]
+pub use conv_norm::*;
+#[cfg(feature = "conv-models")]
+pub use inverted_residual::*;
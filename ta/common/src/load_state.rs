@@ -0,0 +1,399 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+// Where the begin/push/finalize sequence of a model load stands, shared by
+// the TA and the host's dry-run TA so both refuse the same sequences. Every
+// load command is checked against the state; one sent out of order fails
+// and leaves the load as it was.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use proto::inference::LoadPhase;
+
+pub enum LoadState {
+    Idle,
+    /// Begun and taking chunks. `expected` is the size the host announced,
+    /// 0 when it didn't, in which case the load may be taken at any point.
+    Receiving {
+        slot: usize,
+        expected: usize,
+        txid: u32,
+        buf: Vec<u8>,
+    },
+    /// Every announced byte is in; only taking the load may follow.
+    Staged {
+        slot: usize,
+        txid: u32,
+        buf: Vec<u8>,
+    },
+}
+
+/// Why a load command was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The command doesn't fit the load's phase; answered with BadState and
+    /// the phase and bytes received (`CAP_LOAD_STATE`).
+    OutOfOrder { phase: LoadPhase, received: usize },
+    /// A chunk that would take the load past the size announced at begin.
+    Overrun {
+        expected: usize,
+        received: usize,
+        chunk: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::OutOfOrder { phase, received } => write!(
+                f,
+                "out of order, the model load is {} ({} bytes received)",
+                phase, received
+            ),
+            LoadError::Overrun {
+                expected,
+                received,
+                chunk,
+            } => write!(
+                f,
+                "chunk of {} bytes overruns the {} announced, {} already received",
+                chunk, expected, received
+            ),
+        }
+    }
+}
+
+impl core::error::Error for LoadError {}
+
+#[cfg(feature = "optee-utee")]
+impl From<LoadError> for optee_utee::Error {
+    fn from(err: LoadError) -> Self {
+        match err {
+            LoadError::OutOfOrder { .. } => optee_utee::ErrorKind::BadState.into(),
+            LoadError::Overrun { .. } => optee_utee::ErrorKind::BadParameters.into(),
+        }
+    }
+}
+
+/// A load taken off the state by [`LoadState::take`].
+pub struct TakenLoad {
+    pub slot: usize,
+    pub txid: u32,
+    pub buf: Vec<u8>,
+}
+
+impl LoadState {
+    pub fn phase(&self) -> LoadPhase {
+        match self {
+            Self::Idle => LoadPhase::Idle,
+            Self::Receiving { .. } => LoadPhase::Receiving,
+            Self::Staged { .. } => LoadPhase::Staged,
+        }
+    }
+
+    /// Bytes pushed so far.
+    pub fn received(&self) -> usize {
+        match self {
+            Self::Idle => 0,
+            Self::Receiving { buf, .. } | Self::Staged { buf, .. } => buf.len(),
+        }
+    }
+
+    fn out_of_order(&self) -> LoadError {
+        LoadError::OutOfOrder {
+            phase: self.phase(),
+            received: self.received(),
+        }
+    }
+
+    /// Starts a load of `expected` bytes (0 when unknown) into `slot`. Only
+    /// an idle load may begin: one in flight belongs to whoever began it.
+    pub fn begin(&mut self, slot: usize, expected: usize, txid: u32) -> Result<(), LoadError> {
+        if !matches!(self, Self::Idle) {
+            return Err(self.out_of_order());
+        }
+        *self = Self::Receiving {
+            slot,
+            expected,
+            txid,
+            buf: Vec::new(),
+        };
+        Ok(())
+    }
+
+    /// Appends `chunk` to a receiving load, staging it once it holds every
+    /// announced byte.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<(), LoadError> {
+        let Self::Receiving { expected, buf, .. } = self else {
+            return Err(self.out_of_order());
+        };
+        let expected = *expected;
+        if expected != 0 && buf.len() + chunk.len() > expected {
+            return Err(LoadError::Overrun {
+                expected,
+                received: buf.len(),
+                chunk: chunk.len(),
+            });
+        }
+        buf.extend_from_slice(chunk);
+        if expected != 0 && buf.len() == expected {
+            if let Self::Receiving {
+                slot, txid, buf, ..
+            } = core::mem::replace(self, Self::Idle)
+            {
+                *self = Self::Staged { slot, txid, buf };
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a staged load, or a receiving one that announced no size, and
+    /// leaves the state idle.
+    pub fn take(&mut self) -> Result<TakenLoad, LoadError> {
+        match core::mem::replace(self, Self::Idle) {
+            Self::Staged { slot, txid, buf }
+            | Self::Receiving {
+                slot,
+                expected: 0,
+                txid,
+                buf,
+            } => Ok(TakenLoad { slot, txid, buf }),
+            state => {
+                *self = state;
+                Err(self.out_of_order())
+            }
+        }
+    }
+
+    /// Drops whatever load is in flight, handing back its buffer for the
+    /// caller to wipe.
+    pub fn abandon(&mut self) -> Option<Vec<u8>> {
+        match core::mem::replace(self, Self::Idle) {
+            Self::Receiving { buf, .. } | Self::Staged { buf, .. } => Some(buf),
+            Self::Idle => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every state a load can be in: idle, receiving without and with an
+    // announced size, and staged
+    fn states() -> [LoadState; 4] {
+        let mut unsized_load = LoadState::Idle;
+        unsized_load.begin(1, 0, 7).unwrap();
+        unsized_load.push(b"abc").unwrap();
+        let mut partial = LoadState::Idle;
+        partial.begin(2, 8, 8).unwrap();
+        partial.push(b"abc").unwrap();
+        let mut staged = LoadState::Idle;
+        staged.begin(3, 8, 9).unwrap();
+        staged.push(b"abcdefgh").unwrap();
+        [LoadState::Idle, unsized_load, partial, staged]
+    }
+
+    // The state a failed command must leave behind untouched
+    fn same(a: &LoadState, b: &LoadState) -> bool {
+        match (a, b) {
+            (LoadState::Idle, LoadState::Idle) => true,
+            (
+                LoadState::Receiving {
+                    slot,
+                    expected,
+                    txid,
+                    buf,
+                },
+                LoadState::Receiving {
+                    slot: slot2,
+                    expected: expected2,
+                    txid: txid2,
+                    buf: buf2,
+                },
+            ) => slot == slot2 && expected == expected2 && txid == txid2 && buf == buf2,
+            (
+                LoadState::Staged { slot, txid, buf },
+                LoadState::Staged {
+                    slot: slot2,
+                    txid: txid2,
+                    buf: buf2,
+                },
+            ) => slot == slot2 && txid == txid2 && buf == buf2,
+            _ => false,
+        }
+    }
+
+    fn out_of_order(state: &LoadState) -> LoadError {
+        LoadError::OutOfOrder {
+            phase: state.phase(),
+            received: state.received(),
+        }
+    }
+
+    #[test]
+    fn states_report_their_phase() {
+        let phases = states().map(|state| (state.phase(), state.received()));
+        assert_eq!(
+            phases,
+            [
+                (LoadPhase::Idle, 0),
+                (LoadPhase::Receiving, 3),
+                (LoadPhase::Receiving, 3),
+                (LoadPhase::Staged, 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn begin_only_starts_from_idle() {
+        for (i, mut state) in states().into_iter().enumerate() {
+            let before = states().into_iter().nth(i).unwrap();
+            let result = state.begin(0, 16, 42);
+            if i == 0 {
+                assert_eq!(result, Ok(()));
+                assert!(matches!(
+                    state,
+                    LoadState::Receiving {
+                        slot: 0,
+                        expected: 16,
+                        txid: 42,
+                        ref buf
+                    } if buf.is_empty()
+                ));
+            } else {
+                assert_eq!(result, Err(out_of_order(&before)));
+                assert!(same(&state, &before));
+            }
+        }
+    }
+
+    #[test]
+    fn push_only_appends_to_receiving_loads() {
+        for (i, mut state) in states().into_iter().enumerate() {
+            let before = states().into_iter().nth(i).unwrap();
+            let result = state.push(b"de");
+            match before {
+                LoadState::Receiving { .. } => {
+                    assert_eq!(result, Ok(()));
+                    assert_eq!(state.phase(), LoadPhase::Receiving);
+                    assert_eq!(state.received(), 5);
+                }
+                _ => {
+                    assert_eq!(result, Err(out_of_order(&before)));
+                    assert!(same(&state, &before));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pushing_the_announced_size_stages_the_load() {
+        let [_, mut unsized_load, mut partial, _] = states();
+        partial.push(b"defgh").unwrap();
+        assert!(matches!(
+            partial,
+            LoadState::Staged { slot: 2, txid: 8, ref buf } if buf == b"abcdefgh"
+        ));
+        // Without an announced size nothing stages the load
+        unsized_load.push(b"defgh").unwrap();
+        assert_eq!(unsized_load.phase(), LoadPhase::Receiving);
+    }
+
+    #[test]
+    fn chunks_may_not_overrun_the_announced_size() {
+        let [_, _, mut partial, _] = states();
+        let before = states().into_iter().nth(2).unwrap();
+        assert_eq!(
+            partial.push(b"defghi"),
+            Err(LoadError::Overrun {
+                expected: 8,
+                received: 3,
+                chunk: 6
+            })
+        );
+        assert!(same(&partial, &before));
+    }
+
+    #[test]
+    fn take_needs_every_announced_byte() {
+        for (i, mut state) in states().into_iter().enumerate() {
+            let before = states().into_iter().nth(i).unwrap();
+            match (i, state.take()) {
+                (1, Ok(load)) => {
+                    assert_eq!((load.slot, load.txid), (1, 7));
+                    assert_eq!(load.buf, b"abc");
+                    assert_eq!(state.phase(), LoadPhase::Idle);
+                }
+                (3, Ok(load)) => {
+                    assert_eq!((load.slot, load.txid), (3, 9));
+                    assert_eq!(load.buf, b"abcdefgh");
+                    assert_eq!(state.phase(), LoadPhase::Idle);
+                }
+                (0 | 2, Err(err)) => {
+                    assert_eq!(err, out_of_order(&before));
+                    assert!(same(&state, &before));
+                }
+                (i, result) => panic!("state {} taken: {:?}", i, result.is_ok()),
+            }
+        }
+    }
+
+    #[test]
+    fn abandon_returns_the_buffer_and_idles() {
+        let buffers = states().map(|mut state| {
+            let buf = state.abandon();
+            assert_eq!(state.phase(), LoadPhase::Idle);
+            buf
+        });
+        assert_eq!(
+            buffers,
+            [
+                None,
+                Some(b"abc".to_vec()),
+                Some(b"abc".to_vec()),
+                Some(b"abcdefgh".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sequences() {
+        let mut state = LoadState::Idle;
+        // Push and take before begin
+        assert!(state.push(b"x").is_err());
+        assert!(state.take().is_err());
+        // A full load, then the same again: the second take finds it idle
+        state.begin(0, 4, 1).unwrap();
+        state.push(b"ab").unwrap();
+        assert!(state.take().is_err());
+        assert!(state.begin(0, 4, 2).is_err());
+        state.push(b"cd").unwrap();
+        assert!(state.push(b"e").is_err());
+        assert_eq!(state.take().unwrap().buf, b"abcd");
+        assert_eq!(
+            state.take().err(),
+            Some(LoadError::OutOfOrder {
+                phase: LoadPhase::Idle,
+                received: 0
+            })
+        );
+        // An empty unsized load can be taken straight away
+        state.begin(1, 0, 3).unwrap();
+        assert!(state.take().unwrap().buf.is_empty());
+    }
+}
@@ -0,0 +1,423 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Secure storage layout migrations, kept apart from OP-TEE's storage so they
+// can be driven from fixtures in tests. The TA's `schema.rs` decides which
+// steps run and records the version in `ta_schema` (see `SchemaRecord`);
+// the steps themselves go through the storage traits below.
+
+use alloc::vec::Vec;
+use proto::inference::StorageSchema;
+
+/// Serialized `ta_schema` record:
+/// magic (4) | version (4) | migrated from (4, 0 = never) | migrated at (8).
+pub struct SchemaRecord;
+
+impl SchemaRecord {
+    pub const SIZE: usize = 20;
+    const MAGIC: &'static [u8; 4] = b"EMSV";
+
+    pub fn to_bytes(schema: &StorageSchema) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[..4].copy_from_slice(Self::MAGIC);
+        out[4..8].copy_from_slice(&schema.version.to_le_bytes());
+        out[8..12].copy_from_slice(&schema.migrated_from.unwrap_or(0).to_le_bytes());
+        out[12..].copy_from_slice(&schema.migrated_at.unwrap_or(0).to_le_bytes());
+        out
+    }
+
+    /// `None` for anything that isn't a record `to_bytes` wrote.
+    pub fn from_bytes(bytes: &[u8]) -> Option<StorageSchema> {
+        if bytes.len() != Self::SIZE || &bytes[..4] != Self::MAGIC {
+            return None;
+        }
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut migrated_at = [0u8; 8];
+        migrated_at.copy_from_slice(&bytes[12..]);
+        let migrated_from = word(8);
+        Some(StorageSchema {
+            version: word(4),
+            migrated_from: (migrated_from != 0).then_some(migrated_from),
+            migrated_at: (migrated_from != 0).then_some(u64::from_le_bytes(migrated_at)),
+        })
+    }
+}
+
+/// Manifest of the flat layout of the first releases, a single model
+/// stored as `ta_model.<n>` pieces:
+/// magic (4) | chunk count (4) | total size (4) | SHA-256 (32).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlatManifest {
+    pub chunks: u32,
+    pub total_size: u32,
+    pub hash: [u8; 32],
+}
+
+impl FlatManifest {
+    pub const SIZE: usize = 12 + 32;
+    /// Shared with the generation manifests, which are longer.
+    pub const MAGIC: &'static [u8; 4] = b"EMSM";
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[..4].copy_from_slice(Self::MAGIC);
+        out[4..8].copy_from_slice(&self.chunks.to_le_bytes());
+        out[8..12].copy_from_slice(&self.total_size.to_le_bytes());
+        out[12..].copy_from_slice(&self.hash);
+        out
+    }
+
+    /// `None` unless `bytes` is a flat manifest, e.g. for the manifest of a
+    /// layout that already has generations.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE || &bytes[..4] != Self::MAGIC {
+            return None;
+        }
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&bytes[12..]);
+        Some(Self {
+            chunks: word(4),
+            total_size: word(8),
+            hash,
+        })
+    }
+}
+
+/// Storage as `migrate_flat_layout` sees it.
+pub trait FlatStorage {
+    type Error;
+
+    /// The `ta_model.manifest` object, whatever its layout.
+    fn read_manifest(&mut self) -> Result<Option<Vec<u8>>, Self::Error>;
+    /// Flat piece `ta_model.<index>`.
+    fn read_flat_chunk(&mut self, index: u32) -> Result<Option<Vec<u8>>, Self::Error>;
+    /// Returns whether the piece existed.
+    fn delete_flat_chunk(&mut self, index: u32) -> Result<bool, Self::Error>;
+    fn sha256(&mut self, data: &[u8]) -> Result<[u8; 32], Self::Error>;
+    /// Writes `model` as generation 0 and replaces the manifest with one
+    /// listing only it, in a single write.
+    fn store_generation_zero(&mut self, model: &[u8]) -> Result<(), Self::Error>;
+    /// Deletes the manifest and the model info.
+    fn discard_model(&mut self) -> Result<(), Self::Error>;
+}
+
+/// What `migrate_flat_layout` found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlatMigration {
+    /// No flat model, at most leftover pieces that were deleted.
+    Nothing,
+    /// The flat model is now generation 0.
+    Moved,
+    /// The flat model didn't match its manifest and was deleted.
+    Dropped,
+}
+
+/// Moves a model stored in the flat layout into generation 0, then deletes
+/// the flat pieces. A flat model that doesn't match its manifest is dropped,
+/// like the first releases did on load, with all of its pieces. Safe to
+/// repeat after an interruption: the manifest is replaced in one write, and
+/// leftover flat pieces are removed whatever layout the manifest is in.
+pub fn migrate_flat_layout<S: FlatStorage>(storage: &mut S) -> Result<FlatMigration, S::Error> {
+    let mut found = FlatMigration::Nothing;
+    // Pieces the manifest lists are deleted even past a missing one
+    let mut end = 0;
+    let flat = storage
+        .read_manifest()?
+        .and_then(|bytes| FlatManifest::from_bytes(&bytes));
+    if let Some(manifest) = flat {
+        end = manifest.chunks;
+        let mut model = Vec::with_capacity(manifest.total_size as usize);
+        for index in 0..manifest.chunks {
+            match storage.read_flat_chunk(index)? {
+                Some(chunk) => model.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        if model.len() == manifest.total_size as usize && storage.sha256(&model)? == manifest.hash {
+            storage.store_generation_zero(&model)?;
+            found = FlatMigration::Moved;
+        } else {
+            found = FlatMigration::Dropped;
+        }
+    }
+    while storage.read_flat_chunk(end)?.is_some() {
+        end += 1;
+    }
+    // Last first, so an interrupted run leaves pieces `0..n` for the next
+    // one to find
+    for index in (0..end).rev() {
+        storage.delete_flat_chunk(index)?;
+    }
+    // Only now, as the manifest is what lists pieces past a missing one
+    if found == FlatMigration::Dropped {
+        storage.discard_model()?;
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+
+    const MANIFEST: &str = "ta_model.manifest";
+    const INFO: &str = "ta_model.info";
+    const GENERATION_ZERO: &str = "ta_model.0.0";
+
+    // Toy hash; only equality matters here
+    fn hash(data: &[u8]) -> [u8; 32] {
+        let mut out = [0u8; 32];
+        for (i, &byte) in data.iter().enumerate() {
+            out[i % 32] = out[i % 32].wrapping_mul(31).wrapping_add(byte);
+        }
+        out[31] ^= data.len() as u8;
+        out
+    }
+
+    // Object store by id, as a storage image would hold it
+    #[derive(Default, Debug, PartialEq)]
+    struct Store {
+        objects: BTreeMap<String, Vec<u8>>,
+        // Fail the write of generation 0, as a power loss would
+        fail_store: bool,
+        // Fail deleting pieces once this many were deleted
+        deletes_left: Option<usize>,
+    }
+
+    impl FlatStorage for Store {
+        type Error = ();
+
+        fn read_manifest(&mut self) -> Result<Option<Vec<u8>>, ()> {
+            Ok(self.objects.get(MANIFEST).cloned())
+        }
+
+        fn read_flat_chunk(&mut self, index: u32) -> Result<Option<Vec<u8>>, ()> {
+            Ok(self.objects.get(&format!("ta_model.{index}")).cloned())
+        }
+
+        fn delete_flat_chunk(&mut self, index: u32) -> Result<bool, ()> {
+            if let Some(left) = self.deletes_left.as_mut() {
+                *left = left.checked_sub(1).ok_or(())?;
+            }
+            Ok(self.objects.remove(&format!("ta_model.{index}")).is_some())
+        }
+
+        fn sha256(&mut self, data: &[u8]) -> Result<[u8; 32], ()> {
+            Ok(hash(data))
+        }
+
+        fn store_generation_zero(&mut self, model: &[u8]) -> Result<(), ()> {
+            if self.fail_store {
+                return Err(());
+            }
+            self.objects.insert(GENERATION_ZERO.into(), model.to_vec());
+            // Any manifest longer than the flat one
+            self.objects
+                .insert(MANIFEST.into(), vec![0xee; FlatManifest::SIZE + 8]);
+            Ok(())
+        }
+
+        fn discard_model(&mut self) -> Result<(), ()> {
+            self.objects.remove(MANIFEST);
+            self.objects.remove(INFO);
+            Ok(())
+        }
+    }
+
+    // Storage written by the first releases: `model` in pieces of `chunk`
+    // bytes under a flat manifest, plus the model info
+    fn flat_fixture(model: &[u8], chunk: usize) -> Store {
+        let mut store = Store::default();
+        let pieces: Vec<&[u8]> = model.chunks(chunk).collect();
+        for (index, piece) in pieces.iter().enumerate() {
+            store
+                .objects
+                .insert(format!("ta_model.{index}"), piece.to_vec());
+        }
+        let manifest = FlatManifest {
+            chunks: pieces.len() as u32,
+            total_size: model.len() as u32,
+            hash: hash(model),
+        };
+        store
+            .objects
+            .insert(MANIFEST.into(), manifest.to_bytes().to_vec());
+        store.objects.insert(INFO.into(), b"{}".to_vec());
+        store
+    }
+
+    fn model() -> Vec<u8> {
+        (0..1000).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn flat_pieces(store: &Store) -> usize {
+        store
+            .objects
+            .keys()
+            .filter(|id| {
+                id.strip_prefix("ta_model.")
+                    .is_some_and(|n| n.parse::<u32>().is_ok())
+            })
+            .count()
+    }
+
+    #[test]
+    fn flat_model_moves_to_generation_zero() {
+        for chunk in [1, 64, 999, 1000, 4096] {
+            let mut store = flat_fixture(&model(), chunk);
+            assert_eq!(
+                migrate_flat_layout(&mut store),
+                Ok(FlatMigration::Moved),
+                "chunk {chunk}"
+            );
+            assert_eq!(store.objects[GENERATION_ZERO], model());
+            assert!(FlatManifest::from_bytes(&store.objects[MANIFEST]).is_none());
+            assert_eq!(flat_pieces(&store), 0);
+            assert!(store.objects.contains_key(INFO));
+        }
+    }
+
+    #[test]
+    fn migration_is_safe_to_repeat() {
+        let mut store = flat_fixture(&model(), 100);
+        assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Moved));
+        let migrated = store.objects.clone();
+        assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Nothing));
+        assert_eq!(store.objects, migrated);
+    }
+
+    #[test]
+    fn interrupted_migration_resumes() {
+        // Power lost before generation 0 was written: the flat model is intact
+        let mut store = flat_fixture(&model(), 100);
+        store.fail_store = true;
+        assert!(migrate_flat_layout(&mut store).is_err());
+        assert_eq!(store.objects, flat_fixture(&model(), 100).objects);
+        store.fail_store = false;
+        assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Moved));
+        assert_eq!(store.objects[GENERATION_ZERO], model());
+
+        // Power lost after the manifest switched, with flat pieces left over
+        let mut store = flat_fixture(&model(), 100);
+        store.store_generation_zero(&model()).unwrap();
+        assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Nothing));
+        assert_eq!(store.objects[GENERATION_ZERO], model());
+        assert_eq!(flat_pieces(&store), 0);
+
+        // Power lost while deleting the flat pieces, of a moved or a
+        // dropped model
+        for damage in [false, true] {
+            for deletes in 0..10 {
+                let mut store = flat_fixture(&model(), 100);
+                if damage {
+                    store.objects.remove("ta_model.3");
+                }
+                store.deletes_left = Some(deletes);
+                assert!(migrate_flat_layout(&mut store).is_err());
+                store.deletes_left = None;
+                migrate_flat_layout(&mut store).unwrap();
+                assert_eq!(flat_pieces(&store), 0, "{deletes} deletes");
+                assert_eq!(store.objects.contains_key(GENERATION_ZERO), !damage);
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_flat_models_are_dropped() {
+        let damaged: [fn(&mut Store); 4] = [
+            // A piece missing
+            |store| {
+                store.objects.remove("ta_model.3");
+            },
+            // A piece changed
+            |store| {
+                store.objects.get_mut("ta_model.0").unwrap()[0] ^= 1;
+            },
+            // The manifest claims another size
+            |store| {
+                let bytes = store.objects.get_mut(MANIFEST).unwrap();
+                bytes[8] ^= 1;
+            },
+            // The manifest lists fewer pieces
+            |store| {
+                let bytes = store.objects.get_mut(MANIFEST).unwrap();
+                bytes[4] -= 1;
+            },
+        ];
+        for damage in damaged {
+            let mut store = flat_fixture(&model(), 100);
+            damage(&mut store);
+            assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Dropped));
+            assert!(store.objects.is_empty(), "{:?}", store.objects.keys());
+        }
+    }
+
+    #[test]
+    fn empty_and_current_storage_is_left_alone() {
+        let mut store = Store::default();
+        assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Nothing));
+        assert!(store.objects.is_empty());
+
+        let mut store = Store::default();
+        store.store_generation_zero(&model()).unwrap();
+        store.objects.insert(INFO.into(), b"{}".to_vec());
+        let current = store.objects.clone();
+        assert_eq!(migrate_flat_layout(&mut store), Ok(FlatMigration::Nothing));
+        assert_eq!(store.objects, current);
+    }
+
+    #[test]
+    fn schema_records_round_trip() {
+        for schema in [
+            StorageSchema {
+                version: 2,
+                migrated_from: None,
+                migrated_at: None,
+            },
+            StorageSchema {
+                version: 2,
+                migrated_from: Some(1),
+                migrated_at: Some(1_760_000_000),
+            },
+        ] {
+            let bytes = SchemaRecord::to_bytes(&schema);
+            assert_eq!(SchemaRecord::from_bytes(&bytes), Some(schema));
+        }
+    }
+
+    #[test]
+    fn schema_record_fixture() {
+        let bytes = [
+            b'E', b'M', b'S', b'V', 2, 0, 0, 0, 1, 0, 0, 0, 0x00, 0x2b, 0xe8, 0x68, 0, 0, 0, 0,
+        ];
+        let schema = SchemaRecord::from_bytes(&bytes).unwrap();
+        assert_eq!(schema.version, 2);
+        assert_eq!(schema.migrated_from, Some(1));
+        assert_eq!(schema.migrated_at, Some(0x68e8_2b00));
+        assert_eq!(SchemaRecord::to_bytes(&schema), bytes);
+        assert!(SchemaRecord::from_bytes(&bytes[..19]).is_none());
+        let mut wrong_magic = bytes;
+        wrong_magic[0] = b'X';
+        assert!(SchemaRecord::from_bytes(&wrong_magic).is_none());
+    }
+}
@@ -15,13 +15,40 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use alloc::vec::Vec;
+use alloc::{format, string::String, vec::Vec};
 use burn::{
     prelude::*,
-    record::{FullPrecisionSettings, Recorder, RecorderError},
+    record::{BurnMetadata, BurnRecord, BurnRecordNoItem, FullPrecisionSettings, Record, Recorder},
     tensor::{backend::Backend, Tensor, TensorData},
 };
-use proto::{Image, IMAGE_SIZE, NUM_CLASSES};
+use proto::inference::{self, BatchHint, Normalization};
+use proto::{Image, DEFAULT_NUM_CLASSES, IMAGE_SIZE};
+
+use crate::{classify_metadata, Canary, ModelError, RecordFormat};
+
+/// Widths of the hidden layers between the input image and the class logits.
+const HIDDEN_SIZES: [usize; 3] = [512, 256, 128];
+
+/// Submodules that can be exported and patched individually.
+pub const LAYER_NAMES: [&str; 4] = ["linear1", "linear2", "linear3", "output"];
+
+/// Why a layer patch was refused; the patched model is left untouched.
+#[derive(Debug)]
+pub enum PatchError {
+    UnknownLayer,
+    ShapeMismatch,
+    Record(ModelError),
+}
+
+impl core::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PatchError::UnknownLayer => write!(f, "unknown layer"),
+            PatchError::ShapeMismatch => write!(f, "patch shapes don't match the layer"),
+            PatchError::Record(err) => write!(f, "invalid patch record: {}", err),
+        }
+    }
+}
 
 /// Enhanced multi-layer neural network model for MNIST classification
 #[derive(Module, Debug)]
@@ -35,15 +62,71 @@ pub struct MnistModel<B: Backend> {
 
 impl<B: Backend> MnistModel<B> {
     pub fn new(device: &B::Device) -> Self {
+        Self::new_with_classes(device, DEFAULT_NUM_CLASSES)
+    }
+
+    /// Builds a model whose output layer has `num_classes` logits.
+    pub fn new_with_classes(device: &B::Device, num_classes: usize) -> Self {
         Self {
-            linear1: nn::LinearConfig::new(IMAGE_SIZE, 512).init(device),
-            linear2: nn::LinearConfig::new(512, 256).init(device),
-            linear3: nn::LinearConfig::new(256, 128).init(device),
-            output: nn::LinearConfig::new(128, NUM_CLASSES).init(device),
+            linear1: nn::LinearConfig::new(IMAGE_SIZE, HIDDEN_SIZES[0]).init(device),
+            linear2: nn::LinearConfig::new(HIDDEN_SIZES[0], HIDDEN_SIZES[1]).init(device),
+            linear3: nn::LinearConfig::new(HIDDEN_SIZES[1], HIDDEN_SIZES[2]).init(device),
+            output: nn::LinearConfig::new(HIDDEN_SIZES[2], num_classes).init(device),
             dropout: nn::DropoutConfig::new(0.5).init(),
         }
     }
 
+    /// Like [`Self::new_with_classes`], but seeds the backend RNG first so that
+    /// models built with the same seed have bit-identical parameters.
+    pub fn new_with_seed(device: &B::Device, seed: u64, num_classes: usize) -> Self {
+        B::seed(seed);
+        let model = Self::new_with_classes(device, num_classes);
+        // Parameters are drawn on first use, from wherever the RNG is by
+        // then; draw them now
+        model.parameters();
+        model
+    }
+
+    fn layer(&self, name: &str) -> Option<&nn::Linear<B>> {
+        match name {
+            "linear1" => Some(&self.linear1),
+            "linear2" => Some(&self.linear2),
+            "linear3" => Some(&self.linear3),
+            "output" => Some(&self.output),
+            _ => None,
+        }
+    }
+
+    fn layer_mut(&mut self, name: &str) -> Option<&mut nn::Linear<B>> {
+        match name {
+            "linear1" => Some(&mut self.linear1),
+            "linear2" => Some(&mut self.linear2),
+            "linear3" => Some(&mut self.linear3),
+            "output" => Some(&mut self.output),
+            _ => None,
+        }
+    }
+
+    /// Weight and bias of every layer, named after their record fields
+    /// (`linear1.weight`, `linear1.bias`, ...).
+    pub fn parameters(&self) -> Vec<(String, TensorData)> {
+        let mut params = Vec::new();
+        for (name, layer) in LAYER_NAMES.iter().filter_map(|n| Some((n, self.layer(n)?))) {
+            params.push((format!("{}.weight", name), layer.weight.val().into_data()));
+            if let Some(bias) = &layer.bias {
+                params.push((format!("{}.bias", name), bias.val().into_data()));
+            }
+        }
+        params
+    }
+
+    /// Width of the output layer. Records carry their own tensor shapes, so
+    /// this reflects the imported model rather than the template it was
+    /// loaded into.
+    pub fn num_classes(&self) -> usize {
+        self.output.weight.val().dims()[1]
+    }
+
     pub fn forward(&self, input: Tensor<B, 2>) -> Tensor<B, 2> {
         let x = self.linear1.forward(input);
         let x = burn::tensor::activation::relu(x);
@@ -60,20 +143,124 @@ impl<B: Backend> MnistModel<B> {
         self.output.forward(x)
     }
 
-    pub fn export(&self) -> Result<Vec<u8>, RecorderError> {
+    pub fn export(&self) -> Result<Vec<u8>, ModelError> {
         let recorder = burn::record::BinBytesRecorder::<FullPrecisionSettings>::new();
-        recorder.record(self.clone().into_record(), ())
+        Ok(recorder.record(self.clone().into_record(), ())?)
     }
 
-    pub fn import(device: &B::Device, record: Vec<u8>) -> Result<Self, RecorderError> {
-        let recorder = burn::record::BinBytesRecorder::<FullPrecisionSettings>::new();
-        let record = recorder.load(record, device)?;
+    /// Decodes `record` and loads it into a fresh module. The byte buffer is
+    /// consumed by the recorder and freed before the parameters are installed;
+    /// the template's parameters are lazily initialized, so they are never
+    /// materialized alongside the decoded ones.
+    pub fn import(device: &B::Device, record: Vec<u8>) -> Result<Self, ModelError> {
+        let record = load_bin_record(record, device)?;
+
+        let m = Self::new(device).load_record(record);
+        m.check_shapes()?;
+        Ok(m)
+    }
+
+    /// Imports a record written from a plain `MnistModel` by the recorder
+    /// selected by `format`. Bin records decode the same as those of
+    /// [`UnifiedModel`]; named ones lack its `mnist` field.
+    pub fn import_as(
+        device: &B::Device,
+        bytes: Vec<u8>,
+        format: RecordFormat,
+    ) -> Result<Self, ModelError> {
+        match format {
+            RecordFormat::Bin => Self::import(device, bytes),
+            #[cfg(feature = "mpk")]
+            RecordFormat::NamedMpk => {
+                let recorder = burn::record::NamedMpkBytesRecorder::<FullPrecisionSettings>::new();
+                let record = recorder.load(bytes, device)?;
+                let m = Self::new(device).load_record(record);
+                m.check_shapes()?;
+                Ok(m)
+            }
+            #[cfg(not(feature = "mpk"))]
+            RecordFormat::NamedMpk => Err(mpk_unsupported()),
+        }
+    }
+
+    // Records carry their own tensor shapes and loading doesn't compare them
+    // with the template, so an imported model is checked against
+    // `HIDDEN_SIZES`; only the output width is up to the record.
+    fn check_shapes(&self) -> Result<(), ModelError> {
+        let widths = [
+            IMAGE_SIZE,
+            HIDDEN_SIZES[0],
+            HIDDEN_SIZES[1],
+            HIDDEN_SIZES[2],
+            self.num_classes(),
+        ];
+        for (i, name) in LAYER_NAMES.iter().enumerate() {
+            let layer = self.layer(name).expect("LAYER_NAMES are layers");
+            let (d_input, d_output) = (widths[i], widths[i + 1]);
+            let weight = layer.weight.val().dims();
+            check_shape(name, "weight", &weight, &[d_input, d_output])?;
+            if let Some(bias) = &layer.bias {
+                check_shape(name, "bias", &bias.val().dims(), &[d_output])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Loads a `BinBytesRecorder` record the way the recorder does. burn 0.17's
+// recorder unwraps bincode's result, so a corrupted or truncated record would
+// abort the TA; here it is an error. Records must end with the item, which
+// the recorder doesn't check. `bytes` is freed before the record is turned
+// into parameters.
+fn load_bin_record<B: Backend, R: Record<B>>(
+    bytes: Vec<u8>,
+    device: &B::Device,
+) -> Result<R, ModelError> {
+    type Item<B, R> = <R as Record<B>>::Item<FullPrecisionSettings>;
+    let config = bincode::config::standard();
+    let decoded = bincode::serde::decode_from_slice::<BurnRecord<Item<B, R>, B>, _>(&bytes, config);
+    let (detail, ended_early) = match decoded {
+        Ok((record, read)) if read == bytes.len() => {
+            drop(bytes);
+            return Ok(R::from_item(record.item, device));
+        }
+        // The module's fields decoded from the start of a larger one
+        Ok(_) => return Err(ModelError::UnsupportedArch),
+        Err(err) => (format!("{}", err), ended_early(&err)),
+    };
+    let metadata = bincode::serde::decode_from_slice::<BurnRecordNoItem, _>(&bytes, config);
+    Err(match metadata {
+        // As with burn's recorders, metadata that decodes tells why the item
+        // didn't, unless the item was cut short
+        Ok((record, _)) if !ended_early => {
+            classify_metadata(&record.metadata, &bin_metadata::<B>(), detail)
+        }
+        _ => ModelError::RecordFormat(detail),
+    })
+}
 
-        let m = Self::new(device);
-        Ok(m.load_record(record))
+// Whether decoding ran out of bytes. burn's tensor deserializer passes
+// bincode's error on as a message.
+fn ended_early(err: &bincode::error::DecodeError) -> bool {
+    use bincode::error::DecodeError;
+    match err {
+        DecodeError::UnexpectedEnd { .. } => true,
+        DecodeError::OtherString(message) => message.starts_with("UnexpectedEnd"),
+        _ => false,
     }
 }
 
+// Metadata `BinBytesRecorder` writes with the settings records are loaded with
+fn bin_metadata<B: Backend>() -> BurnMetadata {
+    let recorder = burn::record::BinBytesRecorder::<FullPrecisionSettings>::new();
+    let bytes = Recorder::<B>::record(&recorder, (), ()).expect("() records");
+    let config = bincode::config::standard();
+    bincode::serde::decode_from_slice::<BurnRecordNoItem, _>(&bytes, config)
+        .expect("records start with their metadata")
+        .0
+        .metadata
+}
+
 // A wrapper to be compatible with records exported from a unified model
 // where MNIST submodel is stored under a `mnist` field.
 #[derive(Module, Debug)]
@@ -83,64 +270,328 @@ pub struct UnifiedModel<B: Backend> {
 
 impl<B: Backend> UnifiedModel<B> {
     pub fn new(device: &B::Device) -> Self {
-        Self { mnist: MnistModel::new(device) }
+        Self {
+            mnist: MnistModel::new(device),
+        }
+    }
+
+    pub fn new_with_seed(device: &B::Device, seed: u64, num_classes: usize) -> Self {
+        Self {
+            mnist: MnistModel::new_with_seed(device, seed, num_classes),
+        }
     }
 
     pub fn forward(&self, input: Tensor<B, 2>) -> Tensor<B, 2> {
         self.mnist.forward(input)
     }
 
-    pub fn export(&self) -> Result<Vec<u8>, RecorderError> {
+    pub fn num_classes(&self) -> usize {
+        self.mnist.num_classes()
+    }
+
+    /// [`MnistModel::parameters`] under the `mnist.` prefix of the wrapper.
+    pub fn parameters(&self) -> Vec<(String, TensorData)> {
+        self.mnist
+            .parameters()
+            .into_iter()
+            .map(|(name, data)| (format!("mnist.{}", name), data))
+            .collect()
+    }
+
+    /// Layer widths from input to output, e.g. `mlp-784-512-256-128-10`.
+    pub fn architecture(&self) -> String {
+        format!(
+            "mlp-{}-{}-{}-{}-{}",
+            IMAGE_SIZE,
+            HIDDEN_SIZES[0],
+            HIDDEN_SIZES[1],
+            HIDDEN_SIZES[2],
+            self.num_classes()
+        )
+    }
+
+    /// Number of scalar parameters, counted by burn's module visitor.
+    pub fn num_params(&self) -> usize {
+        Module::num_params(self)
+    }
+
+    /// Rough RAM needed to hold the model and run a batch of `batch_size`
+    /// images: parameters plus one activation tensor per layer, all stored as
+    /// the backend float element.
+    pub fn memory_estimate(&self, batch_size: usize) -> usize {
+        let activations = IMAGE_SIZE + HIDDEN_SIZES.iter().sum::<usize>() + self.num_classes();
+        (self.num_params() + batch_size * activations) * core::mem::size_of::<B::FloatElem>()
+    }
+
+    /// Images per inference call that fit in `budget` bytes next to the
+    /// model, per `memory_estimate`: the most, and half of that rounded down
+    /// to a power of two as the recommendation. `None` when not even one
+    /// image fits.
+    pub fn batch_hint(&self, budget: usize) -> Option<BatchHint> {
+        let fixed = self.memory_estimate(0);
+        let per_image = self.memory_estimate(1) - fixed;
+        let max = budget.checked_sub(fixed)? / per_image;
+        if max == 0 {
+            return None;
+        }
+        Some(BatchHint {
+            recommended: 1 << (max / 2).max(1).ilog2(),
+            max,
+        })
+    }
+
+    /// Runs the batch through the model and returns `(labels, probabilities)`,
+    /// the probabilities row-major with `num_classes` per image. Softmax is
+    /// applied over dim 1 of the whole batch at once, and the labels picked
+    /// with [`top_labels`]. `None` if a class index doesn't fit in a byte.
+    pub fn predict(&self, input: Tensor<B, 2>) -> Option<(Vec<u8>, Vec<f32>)> {
+        self.predict_with_temperature(input, 1.0)
+    }
+
+    /// Like [`Self::predict`], with the logits divided by `temperature` before
+    /// the softmax. A temperature of 1.0 leaves the logits untouched.
+    pub fn predict_with_temperature(
+        &self,
+        input: Tensor<B, 2>,
+        temperature: f32,
+    ) -> Option<(Vec<u8>, Vec<f32>)> {
+        let logits = self.forward(input);
+        let logits = if temperature == 1.0 {
+            logits
+        } else {
+            logits / temperature
+        };
+        let probs: Vec<f32> = burn::tensor::activation::softmax(logits, 1)
+            .into_data()
+            .iter::<f32>()
+            .collect();
+        let labels = top_labels(&probs, self.num_classes())?;
+        Some((labels, probs))
+    }
+
+    /// Same as [`Self::predict`] but only returns the predicted class per
+    /// image. The softmax still runs: ties are broken over the probabilities,
+    /// so labels-only requests answer the same classes as the others.
+    pub fn predict_labels(&self, input: Tensor<B, 2>) -> Option<Vec<u8>> {
+        self.predict(input).map(|(labels, _)| labels)
+    }
+
+    pub fn export(&self) -> Result<Vec<u8>, ModelError> {
         let recorder = burn::record::BinBytesRecorder::<FullPrecisionSettings>::new();
-        recorder.record(self.clone().into_record(), ())
+        Ok(recorder.record(self.clone().into_record(), ())?)
     }
 
-    pub fn import(device: &B::Device, bytes: Vec<u8>) -> Result<Self, RecorderError> {
+    pub fn import(device: &B::Device, bytes: Vec<u8>) -> Result<Self, ModelError> {
+        // The wrapper has a single field and bincode doesn't encode field
+        // names, so records exported from `UnifiedModel` and from a plain
+        // `MnistModel` decode identically. One load is enough, which avoids
+        // keeping a copy of `bytes` around for a fallback attempt.
+        let mnist = MnistModel::import(device, bytes)?;
+        Ok(Self { mnist })
+    }
+
+    /// Exports only the parameters of the layer called `name` (see
+    /// [`LAYER_NAMES`]) as a `BinBytesRecorder` partial record.
+    pub fn export_layer(&self, name: &str) -> Result<Vec<u8>, PatchError> {
+        let layer = self.mnist.layer(name).ok_or(PatchError::UnknownLayer)?;
         let recorder = burn::record::BinBytesRecorder::<FullPrecisionSettings>::new();
-        match recorder.load(bytes.clone(), device) {
-            Ok(record) => {
-                let m = Self::new(device);
-                Ok(m.load_record(record))
+        recorder
+            .record(layer.clone().into_record(), ())
+            .map_err(|err| PatchError::Record(err.into()))
+    }
+
+    /// Returns a copy of the model with the layer called `name` replaced by
+    /// the partial record in `bytes`. The weight and bias shapes must match
+    /// the current layer.
+    pub fn apply_layer(
+        &self,
+        device: &B::Device,
+        name: &str,
+        bytes: Vec<u8>,
+    ) -> Result<Self, PatchError> {
+        let record = load_bin_record(bytes, device).map_err(PatchError::Record)?;
+
+        let mut patched = self.clone();
+        let layer = patched
+            .mnist
+            .layer_mut(name)
+            .ok_or(PatchError::UnknownLayer)?;
+        let updated = layer.clone().load_record(record);
+        let bias_dims = |l: &nn::Linear<B>| l.bias.as_ref().map(|b| b.val().dims());
+        if updated.weight.val().dims() != layer.weight.val().dims()
+            || bias_dims(&updated) != bias_dims(layer)
+        {
+            return Err(PatchError::ShapeMismatch);
+        }
+        *layer = updated;
+        Ok(patched)
+    }
+
+    /// Exports the model with the recorder selected by `format`.
+    pub fn export_as(&self, format: RecordFormat) -> Result<Vec<u8>, ModelError> {
+        match format {
+            RecordFormat::Bin => self.export(),
+            #[cfg(feature = "mpk")]
+            RecordFormat::NamedMpk => {
+                let recorder = burn::record::NamedMpkBytesRecorder::<FullPrecisionSettings>::new();
+                Ok(recorder.record(self.clone().into_record(), ())?)
             }
-            Err(_) => {
-                // Fallback: try loading as plain MnistModel then wrap
-                let mnist = MnistModel::import(device, bytes)?;
-                Ok(Self { mnist })
+            #[cfg(not(feature = "mpk"))]
+            RecordFormat::NamedMpk => Err(mpk_unsupported()),
+        }
+    }
+
+    /// Imports a record produced by the recorder selected by `format`. Named
+    /// records keep the `mnist` field name, so they must come from `UnifiedModel`.
+    pub fn import_as(
+        device: &B::Device,
+        bytes: Vec<u8>,
+        format: RecordFormat,
+    ) -> Result<Self, ModelError> {
+        match format {
+            RecordFormat::Bin => Self::import(device, bytes),
+            #[cfg(feature = "mpk")]
+            RecordFormat::NamedMpk => {
+                let recorder = burn::record::NamedMpkBytesRecorder::<FullPrecisionSettings>::new();
+                let record = recorder.load(bytes, device)?;
+                let model = Self::new(device).load_record(record);
+                model.mnist.check_shapes()?;
+                Ok(model)
             }
+            #[cfg(not(feature = "mpk"))]
+            RecordFormat::NamedMpk => Err(mpk_unsupported()),
         }
     }
 
-    pub fn image_to_tensor(device: &B::Device, image: &Image) -> Tensor<B, 2> {
-        MnistModel::<B>::image_to_tensor(device, image)
+    pub fn image_to_tensor(
+        device: &B::Device,
+        image: &Image,
+        normalization: &Normalization,
+    ) -> Tensor<B, 2> {
+        MnistModel::<B>::image_to_tensor(device, image, normalization)
     }
 
-    pub fn images_to_tensors(device: &B::Device, images: &[Image]) -> Tensor<B, 2> {
-        MnistModel::<B>::images_to_tensors(device, images)
+    pub fn images_to_tensors(
+        device: &B::Device,
+        images: &[Image],
+        normalization: &Normalization,
+    ) -> Tensor<B, 2> {
+        MnistModel::<B>::images_to_tensors(device, images, normalization)
     }
 
     pub fn labels_to_tensors(device: &B::Device, labels: &[u8]) -> Tensor<B, 1, Int> {
         MnistModel::<B>::labels_to_tensors(device, labels)
     }
+
+    /// Index of the first of `canaries` the model doesn't classify as
+    /// labelled, run before a model is installed. A model whose classes
+    /// don't fit in a byte fails at the first one.
+    pub fn failing_canary(
+        &self,
+        device: &B::Device,
+        canaries: &[Canary],
+        normalization: &Normalization,
+    ) -> Option<usize> {
+        if canaries.is_empty() {
+            return None;
+        }
+        let images: Vec<Image> = canaries.iter().map(|canary| canary.image).collect();
+        let input = Self::images_to_tensors(device, &images, normalization);
+        match self.predict_labels(input) {
+            Some(labels) => canaries
+                .iter()
+                .zip(labels)
+                .position(|(canary, label)| canary.label != label),
+            None => Some(0),
+        }
+    }
+}
+
+fn check_shape(
+    layer: &str,
+    param: &str,
+    found: &[usize],
+    expected: &[usize],
+) -> Result<(), ModelError> {
+    if found == expected {
+        return Ok(());
+    }
+    Err(ModelError::ShapeMismatch {
+        parameter: format!("{}.{}", layer, param),
+        expected: expected.to_vec(),
+        found: found.to_vec(),
+    })
+}
+
+#[cfg(not(feature = "mpk"))]
+fn mpk_unsupported() -> ModelError {
+    ModelError::RecordFormat(String::from(
+        "named MessagePack records need the `mpk` feature",
+    ))
 }
 
 // Keep existing name `Model` for compatibility with TA/host code.
 pub type Model<B> = UnifiedModel<B>;
 
+/// Class of every row of `probs` (`num_classes` per image) under
+/// `inference::top_class`, the tie-break rule the TA and the host share.
+/// `None` without classes or if a class index doesn't fit in a byte.
+pub fn top_labels(probs: &[f32], num_classes: usize) -> Option<Vec<u8>> {
+    if num_classes == 0 {
+        return None;
+    }
+    probs
+        .chunks_exact(num_classes)
+        .map(|row| u8::try_from(inference::top_class(row)?.0).ok())
+        .collect()
+}
+
+/// Runs the batch through every model, averages their softmax outputs and
+/// returns `(labels, averaged probabilities)` like [`UnifiedModel::predict`].
+/// Returns `None` for an empty model list, members disagreeing on the class
+/// count or a class index that doesn't fit in a byte.
+pub fn predict_ensemble<B: Backend>(
+    models: &[&Model<B>],
+    input: Tensor<B, 2>,
+    temperature: f32,
+) -> Option<(Vec<u8>, Vec<f32>)> {
+    let (first, rest) = models.split_first()?;
+    let (_, mut sum) = first.predict_with_temperature(input.clone(), temperature)?;
+    for model in rest {
+        let (_, probs) = model.predict_with_temperature(input.clone(), temperature)?;
+        if probs.len() != sum.len() {
+            return None;
+        }
+        sum.iter_mut().zip(probs).for_each(|(total, p)| *total += p);
+    }
+    let probs: Vec<f32> = sum.into_iter().map(|p| p / models.len() as f32).collect();
+    let labels = top_labels(&probs, first.num_classes())?;
+    Some((labels, probs))
+}
+
 impl<B: Backend> MnistModel<B> {
     // Originally inspired by the burn/examples/mnist-inference-web package.
-    pub fn image_to_tensor(device: &B::Device, image: &Image) -> Tensor<B, 2> {
-        let tensor = TensorData::from(image.as_slice()).convert::<B::FloatElem>();
+    pub fn image_to_tensor(
+        device: &B::Device,
+        image: &Image,
+        normalization: &Normalization,
+    ) -> Tensor<B, 2> {
+        let tensor = TensorData::from(image.as_bytes().as_slice()).convert::<B::FloatElem>();
         let tensor = Tensor::<B, 1>::from_data(tensor, device);
         let tensor = tensor.reshape([1, IMAGE_SIZE]);
 
-        // Normalize input: [0,1] then standardize with mean/std from PyTorch MNIST
-        ((tensor / 255) - 0.1307) / 0.3081
+        // Scale to [0,1] (for scale 255), then standardize
+        ((tensor / normalization.scale) - normalization.mean) / normalization.std
     }
 
-    pub fn images_to_tensors(device: &B::Device, images: &[Image]) -> Tensor<B, 2> {
+    pub fn images_to_tensors(
+        device: &B::Device,
+        images: &[Image],
+        normalization: &Normalization,
+    ) -> Tensor<B, 2> {
         let tensors = images
             .iter()
-            .map(|v| Self::image_to_tensor(device, v))
+            .map(|v| Self::image_to_tensor(device, v, normalization))
             .collect();
         Tensor::cat(tensors, 0)
     }
@@ -155,3 +606,359 @@ impl<B: Backend> MnistModel<B> {
         Tensor::cat(targets, 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_CANARIES;
+    use alloc::vec;
+    use burn::backend::NdArray;
+    use proto::inference::Prediction;
+
+    type B = NdArray;
+
+    // `count` images of pseudo-random pixels, the same for the same `seed`
+    fn images(count: usize, seed: u32) -> Vec<Image> {
+        let mut state = seed.wrapping_mul(2654435761) | 1;
+        (0..count)
+            .map(|_| {
+                let mut pixels = [0; IMAGE_SIZE];
+                for pixel in pixels.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    *pixel = state as u8;
+                }
+                Image::from_luma28(&pixels)
+            })
+            .collect()
+    }
+
+    fn input(images: &[Image]) -> Tensor<B, 2> {
+        Model::<B>::images_to_tensors(&Default::default(), images, &Normalization::MNIST)
+    }
+
+    #[test]
+    fn seeded_models_are_reproducible() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let parameters =
+            |seed| Model::<B>::new_with_seed(&device, seed, DEFAULT_NUM_CLASSES).parameters();
+        let first = parameters(3);
+        // Draws between building and using a model don't change it
+        let model = Model::<B>::new_with_seed(&device, 3, DEFAULT_NUM_CLASSES);
+        Tensor::<B, 2>::random([64, 64], burn::tensor::Distribution::Default, &device);
+        assert!(model.parameters() == first);
+        assert!(parameters(3) == first);
+        assert!(parameters(4) != first);
+        // Records also hold parameter ids, which no seed fixes; their weights
+        // are the seeded ones
+        let imported = Model::<B>::import(&device, model.export().unwrap()).unwrap();
+        assert!(imported.parameters() == first);
+    }
+
+    #[test]
+    fn default_model_size() {
+        let _rng = crate::TEST_RNG.lock();
+        let model = Model::<B>::new(&Default::default());
+        // 784-512-256-128-10 with biases; a change here is an architecture
+        // change, which breaks every provisioned record
+        assert_eq!(model.num_params(), 567_434);
+        assert_eq!(model.architecture(), "mlp-784-512-256-128-10");
+        let per_image = (IMAGE_SIZE + 512 + 256 + 128 + 10) * 4;
+        assert_eq!(model.memory_estimate(0), 567_434 * 4);
+        assert_eq!(model.memory_estimate(64), 567_434 * 4 + 64 * per_image);
+        let wider = Model::<B>::new_with_seed(&Default::default(), 0, 47);
+        assert_eq!(wider.num_params(), 567_434 + 37 * (128 + 1));
+    }
+
+    #[test]
+    fn batch_hints_fit_the_budget_next_to_the_model() {
+        let _rng = crate::TEST_RNG.lock();
+        let model = Model::<B>::new(&Default::default());
+        let (fixed, per_image) = (model.memory_estimate(0), model.memory_estimate(1));
+        let per_image = per_image - fixed;
+        let hint = |budget| model.batch_hint(budget);
+        // 100 images and change: half of that, rounded down to a power of two
+        let hint100 = hint(fixed + 100 * per_image + per_image / 2).unwrap();
+        assert_eq!((hint100.max, hint100.recommended), (100, 32));
+        assert!(model.memory_estimate(hint100.max) <= fixed + 100 * per_image + per_image / 2);
+        assert_eq!(hint(fixed + 64 * per_image).unwrap().recommended, 32);
+        // A single image still gets a hint, no image none
+        assert_eq!(
+            hint(fixed + per_image),
+            Some(BatchHint {
+                recommended: 1,
+                max: 1
+            })
+        );
+        assert_eq!(hint(fixed + per_image - 1), None);
+        assert_eq!(hint(fixed / 2), None);
+        // A bigger model leaves room for fewer images in the same budget
+        let wider = Model::<B>::new_with_seed(&Default::default(), 0, 47);
+        let budget = 12 * 1024 * 1024;
+        assert!(wider.batch_hint(budget).unwrap().max < hint(budget).unwrap().max);
+    }
+
+    #[test]
+    fn ensembles_average_their_members() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let first = Model::<B>::new_with_seed(&device, 1, DEFAULT_NUM_CLASSES);
+        let second = Model::<B>::new_with_seed(&device, 2, DEFAULT_NUM_CLASSES);
+        let images = images(32, 2);
+        let (labels1, probs1) = first.predict(input(&images)).unwrap();
+        let (labels2, probs2) = second.predict(input(&images)).unwrap();
+        // The members must disagree somewhere for the test to mean anything
+        assert_ne!(labels1, labels2);
+
+        let (labels, probs) = predict_ensemble(&[&first, &second], input(&images), 1.0).unwrap();
+        for ((p, p1), p2) in probs.iter().zip(&probs1).zip(&probs2) {
+            assert!((p - (p1 + p2) / 2.0).abs() < 1e-6);
+        }
+        assert_eq!(labels, top_labels(&probs, DEFAULT_NUM_CLASSES).unwrap());
+        assert!(probs != probs1 && probs != probs2);
+        // Where the members disagree, the average can't pick the class of the
+        // less confident one
+        for (i, (&l1, &l2)) in labels1.iter().zip(&labels2).enumerate() {
+            let row =
+                |probs: &[f32]| probs[i * DEFAULT_NUM_CLASSES..][..DEFAULT_NUM_CLASSES].to_vec();
+            let (row1, row2) = (row(&probs1), row(&probs2));
+            if l1 != l2
+                && row1[l1 as usize] - row1[l2 as usize] > row2[l2 as usize] - row2[l1 as usize]
+            {
+                assert_ne!(labels[i], l2);
+            }
+        }
+
+        let (alone, _) = predict_ensemble(&[&first], input(&images), 1.0).unwrap();
+        assert_eq!(alone, labels1);
+        assert!(predict_ensemble::<B>(&[], input(&images), 1.0).is_none());
+        let other = Model::<B>::new_with_seed(&device, 3, 12);
+        assert!(predict_ensemble(&[&first, &other], input(&images), 1.0).is_none());
+    }
+
+    #[test]
+    fn record_formats_load_to_the_same_predictions() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let model = Model::<B>::new_with_seed(&device, 6, DEFAULT_NUM_CLASSES);
+        let images = images(4, 6);
+        let expected = model.predict(input(&images)).unwrap();
+        for format in [RecordFormat::Bin, RecordFormat::NamedMpk] {
+            if !format.is_supported() {
+                assert!(model.export_as(format).is_err());
+                continue;
+            }
+            let record = model.export_as(format).unwrap();
+            let container = crate::encode_container(&Default::default(), format, &record);
+            let (_, found, record) = crate::split_container(container.unwrap()).unwrap();
+            assert_eq!(found, format);
+            let imported = Model::<B>::import_as(&device, record, format).unwrap();
+            assert_eq!(imported.predict(input(&images)).unwrap(), expected);
+        }
+
+        // The format byte is the only difference, and unknown ones are refused
+        let container = |format| crate::encode_container(&Default::default(), format, b"r");
+        let mut bin = container(RecordFormat::Bin).unwrap();
+        let mpk = container(RecordFormat::NamedMpk).unwrap();
+        let at = (0..bin.len())
+            .filter(|&i| bin[i] != mpk[i])
+            .collect::<Vec<_>>();
+        assert_eq!(at.len(), 1);
+        bin[at[0]] = 7;
+        assert!(matches!(
+            crate::split_container(bin),
+            Err(ModelError::Metadata(
+                crate::ContainerError::UnsupportedRecordFormat(7)
+            ))
+        ));
+    }
+
+    #[test]
+    fn batched_predictions_match_the_per_row_loop() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let model = Model::<B>::new_with_seed(&device, 1, DEFAULT_NUM_CLASSES);
+        let images = images(9, 1);
+        let (labels, probs) = model.predict(input(&images)).unwrap();
+        assert_eq!(model.predict_labels(input(&images)).unwrap(), labels);
+        assert_eq!(probs.len(), images.len() * DEFAULT_NUM_CLASSES);
+
+        // What the TA used to do: softmax and argmax one row at a time
+        let logits = model.forward(input(&images));
+        let per_row: Vec<u8> = logits
+            .iter_dim(0)
+            .map(|row| {
+                let probs = burn::tensor::activation::softmax(row, 1);
+                probs.argmax(1).into_scalar().elem::<i64>() as u8
+            })
+            .collect();
+        assert_eq!(labels, per_row);
+        for row in probs.chunks_exact(DEFAULT_NUM_CLASSES) {
+            assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn labels_only_requests_keep_their_labels() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let model = Model::<B>::new_with_seed(&device, 5, DEFAULT_NUM_CLASSES);
+        for count in [1, 17, 64] {
+            let images = images(count, 5);
+            // The per-image softmax and argmax the TA used to run
+            let per_row: Vec<u8> = model
+                .forward(input(&images))
+                .iter_dim(0)
+                .map(|row| {
+                    let probs = burn::tensor::activation::softmax(row, 1);
+                    probs.argmax(1).into_scalar().elem::<i64>() as u8
+                })
+                .collect();
+            assert_eq!(model.predict_labels(input(&images)).unwrap(), per_row);
+            let (labels, _) = model.predict_with_temperature(input(&images), 0.5).unwrap();
+            assert_eq!(labels, per_row);
+        }
+    }
+
+    #[test]
+    fn mismatched_canaries_refuse_the_model() {
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        let model = Model::<B>::new_with_seed(&device, 8, DEFAULT_NUM_CLASSES);
+        let images = images(MAX_CANARIES, 8);
+        let labels = model.predict_labels(input(&images)).unwrap();
+        let mut canaries: Vec<Canary> = images
+            .iter()
+            .zip(&labels)
+            .map(|(&image, &label)| Canary { label, image })
+            .collect();
+        let check = |model: &Model<B>, canaries: &[Canary]| {
+            model.failing_canary(&device, canaries, &Normalization::MNIST)
+        };
+        assert_eq!(check(&model, &canaries), None);
+        assert_eq!(check(&model, &[]), None);
+
+        // A wrong label names its canary
+        canaries[5].label = (labels[5] + 1) % DEFAULT_NUM_CLASSES as u8;
+        assert_eq!(check(&model, &canaries), Some(5));
+        canaries[2].label = (labels[2] + 3) % DEFAULT_NUM_CLASSES as u8;
+        assert_eq!(check(&model, &canaries), Some(2));
+        canaries[2].label = labels[2];
+        canaries[5].label = labels[5];
+
+        // A record that parses but holds other weights, as one opened with a
+        // stale key can, misses the first canary it disagrees on
+        let stale = Model::<B>::new_with_seed(&device, 9, DEFAULT_NUM_CLASSES);
+        let stale_labels = stale.predict_labels(input(&images)).unwrap();
+        let first = labels.iter().zip(&stale_labels).position(|(a, b)| a != b);
+        assert!(first.is_some());
+        assert_eq!(check(&stale, &canaries), first);
+    }
+
+    #[test]
+    fn inputs_follow_the_normalization_profile() {
+        let mut pixels = [0; IMAGE_SIZE];
+        pixels[..4].copy_from_slice(&[0, 51, 255, 128]);
+        let image = Image::from_luma28(&pixels);
+        let values = |normalization: &Normalization| -> Vec<f32> {
+            Model::<B>::images_to_tensors(&Default::default(), &[image, image], normalization)
+                .into_data()
+                .iter::<f32>()
+                .collect()
+        };
+        for normalization in [
+            Normalization::MNIST,
+            Normalization::UNIT,
+            Normalization::IDENTITY,
+            Normalization {
+                mean: 0.2860,
+                std: 0.3530,
+                scale: 255.0,
+            },
+        ] {
+            let values = values(&normalization);
+            assert_eq!(values.len(), 2 * IMAGE_SIZE);
+            for (i, &pixel) in pixels.iter().chain(&pixels).enumerate() {
+                let expected =
+                    (pixel as f32 / normalization.scale - normalization.mean) / normalization.std;
+                assert!(
+                    (values[i] - expected).abs() < 1e-6,
+                    "{}: {}",
+                    normalization,
+                    i
+                );
+            }
+        }
+        assert_eq!(
+            values(&Normalization::UNIT)[..4],
+            [0.0, 0.2, 1.0, 128.0 / 255.0]
+        );
+        assert_eq!(
+            values(&Normalization::IDENTITY)[..4],
+            [0.0, 51.0, 255.0, 128.0]
+        );
+        // Records without a profile get the MNIST constants
+        assert_eq!(Normalization::default(), Normalization::MNIST);
+        assert_eq!(
+            crate::ModelMetadata::default().normalization(),
+            Normalization::MNIST
+        );
+    }
+
+    #[test]
+    fn labels_past_a_byte_are_refused() {
+        let classes = 300;
+        let mut probs = vec![0.0; 2 * classes];
+        probs[5] = 1.0;
+        probs[classes + 255] = 1.0;
+        assert_eq!(top_labels(&probs, classes), Some(vec![5, 255]));
+        probs[classes + 256] = 2.0;
+        assert_eq!(top_labels(&probs, classes), None);
+    }
+
+    #[test]
+    fn tied_logits_get_the_same_class_on_both_paths() {
+        // Rows of logits with exact ties, softmaxed the way `predict` does
+        let logits = [
+            [1.0, 3.0, 3.0, 0.0],
+            [2.0, 2.0, 2.0, 2.0],
+            [5.0, -1.0, 5.0, -1.0],
+            [0.0, 0.0, 0.0, 7.0],
+            [-3.0, 4.0, 1.0, 4.0],
+        ];
+        let data: Vec<f32> = logits.iter().flatten().copied().collect();
+        let tensor = Tensor::<NdArray, 2>::from_data(
+            TensorData::new(data, [logits.len(), 4]),
+            &Default::default(),
+        );
+        let probs: Vec<f32> = burn::tensor::activation::softmax(tensor, 1)
+            .into_data()
+            .iter::<f32>()
+            .collect();
+
+        // The batch labels of the model and ensemble paths, and the per-image
+        // predictions the TA and the dry run answer
+        let labels = top_labels(&probs, 4).unwrap();
+        let predictions: Vec<Prediction> = probs
+            .chunks_exact(4)
+            .map(|row| Prediction::from_probabilities(row, true).unwrap())
+            .collect();
+        assert_eq!(labels, vec![1, 0, 0, 3, 1]);
+        assert_eq!(
+            predictions.iter().map(|p| p.label).collect::<Vec<_>>(),
+            labels
+        );
+        assert_eq!(
+            predictions.iter().map(|p| p.is_tied()).collect::<Vec<_>>(),
+            vec![true, true, true, false, true]
+        );
+    }
+
+    #[test]
+    fn top_labels_needs_classes() {
+        assert_eq!(top_labels(&[0.5, 0.5], 0), None);
+        assert_eq!(top_labels(&[], 10), Some(vec![]));
+    }
+}
@@ -0,0 +1,184 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Open session count of a TA instance and what happens to the sensitive
+// buffers when the last one closes (see `ResidencyPolicy`). The policy lives
+// as long as the TA instance; it is not persisted.
+
+use proto::inference::ResidencyPolicy;
+use spin::Mutex;
+
+use crate::{wipe_vec, LoadState, ModelSlots};
+
+pub struct Residency {
+    sessions: Mutex<usize>,
+    policy: Mutex<ResidencyPolicy>,
+}
+
+impl Residency {
+    pub const fn new() -> Self {
+        Self {
+            sessions: Mutex::new(0),
+            policy: Mutex::new(ResidencyPolicy::KeepResident),
+        }
+    }
+
+    pub fn session_opened(&self) {
+        *self.sessions.lock() += 1;
+    }
+
+    /// Counts a session out; returns the policy to release memory under
+    /// when no other session remains.
+    pub fn session_closed(&self) -> Option<ResidencyPolicy> {
+        let mut sessions = self.sessions.lock();
+        *sessions = sessions.saturating_sub(1);
+        (*sessions == 0).then(|| self.policy())
+    }
+
+    pub fn policy(&self) -> ResidencyPolicy {
+        *self.policy.lock()
+    }
+
+    pub fn set_policy(&self, policy: ResidencyPolicy) {
+        *self.policy.lock() = policy;
+    }
+}
+
+impl Default for Residency {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wipes the half-streamed model of `pending` and, under `DropOnIdle`,
+/// drops the installed models, running `paired` under the slot lock as
+/// `ModelSlots::clear` does. Returns whether the models were dropped.
+pub fn release_models<M>(
+    policy: ResidencyPolicy,
+    pending: &mut LoadState,
+    models: &ModelSlots<M>,
+    paired: impl FnOnce(),
+) -> bool {
+    if let Some(mut buf) = pending.abandon() {
+        wipe_vec(&mut buf);
+    }
+    if policy != ResidencyPolicy::DropOnIdle {
+        return false;
+    }
+    drop(models.clear(paired));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use proto::inference::LoadPhase;
+
+    // Counts its drops, standing in for a model's weights
+    struct Model(&'static AtomicUsize);
+
+    impl Drop for Model {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // A TA with a model in slots 0 and 2 and another half pushed
+    fn loaded(dropped: &'static AtomicUsize) -> (LoadState, ModelSlots<Model>) {
+        let models = ModelSlots::new();
+        models.install(0, Model(dropped), || {});
+        models.install(2, Model(dropped), || {});
+        let mut pending = LoadState::Idle;
+        pending.begin(1, 64, 1).unwrap();
+        pending.push(&[0x5a; 32]).unwrap();
+        (pending, models)
+    }
+
+    // What the TA's close_session does
+    fn close(residency: &Residency, pending: &mut LoadState, models: &ModelSlots<Model>) {
+        if let Some(policy) = residency.session_closed() {
+            release_models(policy, pending, models, || {});
+        }
+    }
+
+    #[test]
+    fn the_last_session_closing_drops_models_on_idle() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        let (mut pending, models) = loaded(&DROPPED);
+        let residency = Residency::new();
+        residency.set_policy(ResidencyPolicy::DropOnIdle);
+        residency.session_opened();
+        residency.session_opened();
+
+        // Another client is still connected
+        close(&residency, &mut pending, &models);
+        assert_eq!(pending.phase(), LoadPhase::Receiving);
+        assert!(models.is_loaded(0) && models.is_loaded(2));
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+
+        close(&residency, &mut pending, &models);
+        assert_eq!(pending.phase(), LoadPhase::Idle);
+        assert!((0..4).all(|slot| !models.is_loaded(slot)));
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 2);
+        // A stray close doesn't wrap the count
+        assert_eq!(
+            residency.session_closed(),
+            Some(ResidencyPolicy::DropOnIdle)
+        );
+    }
+
+    #[test]
+    fn resident_models_outlive_their_sessions() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        let (mut pending, models) = loaded(&DROPPED);
+        let residency = Residency::default();
+        assert_eq!(residency.policy(), ResidencyPolicy::KeepResident);
+        residency.session_opened();
+        close(&residency, &mut pending, &models);
+        // The half-streamed model goes either way
+        assert_eq!(pending.phase(), LoadPhase::Idle);
+        assert!(models.is_loaded(0) && models.is_loaded(2));
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+
+        // TA destroy releases under DropOnIdle whatever the policy
+        let mut paired = false;
+        assert!(release_models(
+            ResidencyPolicy::DropOnIdle,
+            &mut pending,
+            &models,
+            || paired = true
+        ));
+        assert!(paired && !models.is_loaded(0));
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn wiped_buffers_are_zeroed_and_freed() {
+        let mut bytes = [0xa5; 48];
+        crate::wipe(&mut bytes[8..]);
+        assert!(bytes[..8].iter().all(|&b| b == 0xa5));
+        assert!(bytes[8..].iter().all(|&b| b == 0));
+
+        let mut buf = vec![0xa5; 100];
+        buf.truncate(10);
+        wipe_vec(&mut buf);
+        assert!(buf.is_empty());
+        assert_eq!(buf.capacity(), 0);
+    }
+}
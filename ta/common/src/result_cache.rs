@@ -0,0 +1,305 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Per-image inference results (`Command::SetResultCache`), for hosts that
+// classify the same images over and over. Entries are keyed by a digest of
+// `result_key_prefix` and the image, which the TA computes; the cache is
+// small, so lookups are a linear scan with the most recently used entry
+// first.
+
+use alloc::collections::VecDeque;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use proto::inference::Normalization;
+use spin::Mutex;
+
+struct Entry {
+    key: [u8; 32],
+    label: u8,
+    probs: Vec<f32>,
+}
+
+/// Up to `capacity` cached results, none while the capacity is 0.
+pub struct ResultCache {
+    capacity: usize,
+    entries: VecDeque<Entry>,
+}
+
+impl ResultCache {
+    pub const fn new() -> Self {
+        Self {
+            capacity: 0,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Keeps up to `capacity` results from now on, 0 turning the cache off.
+    /// Cached results are dropped either way.
+    pub fn configure(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.entries.clear();
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The label and probabilities cached under `key`, which becomes the
+    /// most recently used entry.
+    pub fn get(&mut self, key: &[u8; 32]) -> Option<(u8, Vec<f32>)> {
+        let index = self.entries.iter().position(|entry| entry.key == *key)?;
+        let entry = self.entries.remove(index)?;
+        let result = (entry.label, entry.probs.clone());
+        self.entries.push_front(entry);
+        Some(result)
+    }
+
+    /// Caches a result, replacing the one under the same key or evicting
+    /// the least recently used one when full.
+    pub fn put(&mut self, key: [u8; 32], label: u8, probs: &[f32]) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.retain(|entry| entry.key != key);
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_back();
+        }
+        self.entries.push_front(Entry {
+            key,
+            label,
+            probs: probs.to_vec(),
+        });
+    }
+
+    /// Drops the cached results, keeping the configured size. Called
+    /// whenever a model is installed or dropped.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the key of an image's result covers besides its pixels: the hash
+/// of the model, how the image was normalized and the temperature.
+pub fn result_key_prefix(
+    model_hash: &[u8; 32],
+    normalization: &Normalization,
+    temperature: f32,
+) -> [u8; 48] {
+    let mut prefix = [0u8; 48];
+    prefix[..32].copy_from_slice(model_hash);
+    let values = [
+        normalization.mean,
+        normalization.std,
+        normalization.scale,
+        temperature,
+    ];
+    for (chunk, value) in prefix[32..].chunks_exact_mut(4).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    prefix
+}
+
+/// Labels and probabilities of a batch answered through the cache.
+pub struct CachedOutputs {
+    pub labels: Vec<u8>,
+    pub probs: Vec<f32>,
+    /// Images answered from the cache.
+    pub hits: usize,
+}
+
+/// Answers the images with `keys` from `cache` where it can and runs the
+/// model on the others only, whose results are cached in turn. `run` gets
+/// the indices of the misses and returns their labels and probabilities.
+/// The model runs without the lock.
+pub fn run_cached<E>(
+    cache: &Mutex<ResultCache>,
+    keys: &[[u8; 32]],
+    num_classes: usize,
+    run: impl FnOnce(&[usize]) -> Result<(Vec<u8>, Vec<f32>), E>,
+) -> Result<CachedOutputs, E> {
+    let mut labels = vec![0u8; keys.len()];
+    let mut probs = vec![0f32; keys.len() * num_classes];
+    let mut misses = Vec::new();
+    for (i, key) in keys.iter().enumerate() {
+        match cache.lock().get(key) {
+            Some((label, cached)) if cached.len() == num_classes => {
+                labels[i] = label;
+                probs[i * num_classes..(i + 1) * num_classes].copy_from_slice(&cached);
+            }
+            _ => misses.push(i),
+        }
+    }
+    if !misses.is_empty() {
+        let (miss_labels, miss_probs) = run(&misses)?;
+        let mut cache = cache.lock();
+        for (j, &i) in misses.iter().enumerate() {
+            let row = &miss_probs[j * num_classes..(j + 1) * num_classes];
+            labels[i] = miss_labels[j];
+            probs[i * num_classes..(i + 1) * num_classes].copy_from_slice(row);
+            cache.put(keys[i], miss_labels[j], row);
+        }
+    }
+    Ok(CachedOutputs {
+        labels,
+        probs,
+        hits: keys.len() - misses.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLASSES: usize = 3;
+
+    type Outputs = Result<(Vec<u8>, Vec<f32>), ()>;
+
+    // Stands in for the TA's SHA-256 of the prefix and the image: the
+    // prefix's model hash byte, temperature byte and the image number
+    fn key(prefix: &[u8; 48], image: u8) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key[..3].copy_from_slice(&[prefix[0], prefix[47], image]);
+        key
+    }
+
+    // A model labelling image `i` with `i % CLASSES`, logging what it runs
+    fn model<'a>(
+        keys: &'a [[u8; 32]],
+        ran: &'a mut Vec<u8>,
+    ) -> impl FnOnce(&[usize]) -> Outputs + 'a {
+        move |misses| {
+            let mut probs = Vec::new();
+            let labels = misses
+                .iter()
+                .map(|&i| {
+                    let image = keys[i][2];
+                    ran.push(image);
+                    let label = image % CLASSES as u8;
+                    probs.extend((0..CLASSES).map(|c| (c == label as usize) as u8 as f32));
+                    label
+                })
+                .collect();
+            Ok((labels, probs))
+        }
+    }
+
+    fn cache(capacity: usize) -> Mutex<ResultCache> {
+        let mut cache = ResultCache::new();
+        cache.configure(capacity);
+        Mutex::new(cache)
+    }
+
+    #[test]
+    fn identical_inputs_hit_the_cache() {
+        let cache = cache(8);
+        let prefix = result_key_prefix(&[1; 32], &Normalization::MNIST, 1.0);
+        let keys: Vec<_> = [4, 5, 4].iter().map(|&image| key(&prefix, image)).collect();
+        let mut ran = Vec::new();
+        let first = run_cached(&cache, &keys, CLASSES, model(&keys, &mut ran)).unwrap();
+        // Duplicates within a batch are only cached once it has run
+        assert_eq!((first.hits, &ran[..]), (0, &[4, 5, 4][..]));
+        assert_eq!(first.labels, [1, 2, 1]);
+        assert_eq!(cache.lock().len(), 2);
+
+        let mut ran = Vec::new();
+        let again = run_cached(&cache, &keys, CLASSES, model(&keys, &mut ran)).unwrap();
+        assert_eq!((again.hits, ran.len()), (3, 0));
+        assert_eq!((again.labels, again.probs), (first.labels, first.probs));
+
+        // Only the new image of a mixed batch runs, in its place
+        let keys = [key(&prefix, 5), key(&prefix, 6)];
+        let mut ran = Vec::new();
+        let mixed = run_cached(&cache, &keys, CLASSES, model(&keys, &mut ran)).unwrap();
+        assert_eq!((mixed.hits, &ran[..]), (1, &[6][..]));
+        assert_eq!(mixed.labels, [2, 0]);
+        assert_eq!(&mixed.probs[CLASSES..], [1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn model_swaps_invalidate_the_cache() {
+        let cache = cache(8);
+        let prefix = result_key_prefix(&[1; 32], &Normalization::MNIST, 1.0);
+        let keys = [key(&prefix, 7)];
+        run_cached(&cache, &keys, CLASSES, model(&keys, &mut Vec::new())).unwrap();
+        // Installing or dropping a model clears it
+        cache.lock().clear();
+        let mut ran = Vec::new();
+        let outputs = run_cached(&cache, &keys, CLASSES, model(&keys, &mut ran)).unwrap();
+        assert_eq!((outputs.hits, &ran[..]), (0, &[7][..]));
+        // And a result of another model, normalization or temperature is
+        // never taken for this one
+        let others = [
+            result_key_prefix(&[2; 32], &Normalization::MNIST, 1.0),
+            result_key_prefix(&[1; 32], &Normalization::UNIT, 1.0),
+            result_key_prefix(&[1; 32], &Normalization::MNIST, 2.0),
+        ];
+        for other in others {
+            assert_ne!(other, prefix);
+        }
+        let keys = [key(&others[0], 7)];
+        let mut ran = Vec::new();
+        let outputs = run_cached(&cache, &keys, CLASSES, model(&keys, &mut ran)).unwrap();
+        assert_eq!((outputs.hits, ran.len()), (0, 1));
+    }
+
+    #[test]
+    fn least_recently_used_results_are_evicted() {
+        let mut cache = ResultCache::new();
+        assert!(!cache.enabled());
+        cache.put([1; 32], 1, &[1.0]);
+        assert!(cache.is_empty());
+
+        cache.configure(2);
+        cache.put([1; 32], 1, &[1.0]);
+        cache.put([2; 32], 2, &[2.0]);
+        // Reading 1 makes 2 the oldest
+        assert_eq!(cache.get(&[1; 32]), Some((1, vec![1.0])));
+        cache.put([3; 32], 3, &[3.0]);
+        assert_eq!(cache.get(&[2; 32]), None);
+        assert_eq!(cache.get(&[1; 32]), Some((1, vec![1.0])));
+        assert_eq!(cache.get(&[3; 32]), Some((3, vec![3.0])));
+        // Resizing drops what was cached, turning it off too
+        cache.configure(4);
+        assert!(cache.is_empty());
+        cache.configure(0);
+        assert!(!cache.enabled());
+    }
+
+    #[test]
+    fn failed_runs_cache_nothing() {
+        let cache = cache(4);
+        let keys = [[9; 32]];
+        let result = run_cached(&cache, &keys, CLASSES, |_| Err("out of memory"));
+        assert_eq!(result.err(), Some("out of memory"));
+        assert!(cache.lock().is_empty());
+    }
+}
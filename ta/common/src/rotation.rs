@@ -0,0 +1,526 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Order of the steps of a key rotation, kept apart from the TA's key manager
+// and storage so every failure point can be exercised in tests. The stored
+// model is re-encrypted under the new key and checked, written next to the
+// old version, and switched to; only then does the key manager's key
+// change. A journal naming the new key and ciphertext, written just before
+// the switch, lets `recover` finish a rotation interrupted between the
+// switch and the key change. At any other point the old key and the old
+// ciphertext are still in place.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+pub type RotationKey = [u8; 32];
+pub type RotationHash = [u8; 32];
+
+/// Why a rotation was abandoned before the switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RotationError {
+    /// The ciphertext written isn't the one that was verified.
+    Mismatch,
+}
+
+impl fmt::Display for RotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RotationError::Mismatch => write!(f, "written model differs from the verified one"),
+        }
+    }
+}
+
+impl core::error::Error for RotationError {}
+
+#[cfg(feature = "optee-utee")]
+impl From<RotationError> for optee_utee::Error {
+    fn from(err: RotationError) -> Self {
+        match err {
+            RotationError::Mismatch => optee_utee::ErrorKind::CorruptObject.into(),
+        }
+    }
+}
+
+/// Points of a rotation at which `RotationSteps::after` sees the new
+/// ciphertext.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Encrypted under the new key, not checked yet.
+    Encrypted,
+    /// Checked to decrypt to the model, not written yet.
+    Verified,
+}
+
+/// What a rotation persists before switching to the new ciphertext: the
+/// new key and the hash of the ciphertext encrypted under it. Holds key
+/// material, so it only goes to secure storage.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RotationJournal {
+    pub key: RotationKey,
+    pub hash: RotationHash,
+}
+
+impl RotationJournal {
+    pub const SIZE: usize = 64;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[..32].copy_from_slice(&self.key);
+        bytes[32..].copy_from_slice(&self.hash);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        let mut journal = Self {
+            key: [0; 32],
+            hash: [0; 32],
+        };
+        journal.key.copy_from_slice(&bytes[..32]);
+        journal.hash.copy_from_slice(&bytes[32..]);
+        Some(journal)
+    }
+}
+
+impl fmt::Debug for RotationJournal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RotationJournal")
+            .field("hash", &self.hash)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Key manager and storage operations a rotation is made of.
+pub trait RotationSteps {
+    type Error: From<RotationError>;
+
+    /// `plain` encrypted under `key` in the stored format, without touching
+    /// the key manager's key.
+    fn encrypt(&mut self, key: &RotationKey, plain: &[u8]) -> Result<Vec<u8>, Self::Error>;
+
+    /// Checks that `ciphertext` decrypts under `key` to the model `plain`
+    /// and that the result imports; returns the SHA-256 of `ciphertext`.
+    fn verify(
+        &mut self,
+        key: &RotationKey,
+        ciphertext: &[u8],
+        plain: &[u8],
+    ) -> Result<RotationHash, Self::Error>;
+
+    /// Writes `ciphertext` next to the stored model without switching to it;
+    /// returns the SHA-256 of what reads back.
+    fn write(&mut self, ciphertext: &[u8]) -> Result<RotationHash, Self::Error>;
+
+    /// Throws away what `write` wrote.
+    fn discard_written(&mut self) -> Result<(), Self::Error>;
+
+    /// Makes what `write` wrote the stored model, in one atomic step.
+    fn switch(&mut self) -> Result<(), Self::Error>;
+
+    /// SHA-256 of the stored ciphertext, `None` when no model is stored.
+    fn stored_hash(&mut self) -> Result<Option<RotationHash>, Self::Error>;
+
+    /// Replaces the key manager's key with `key`, deleting the old one.
+    fn import_key(&mut self, key: &RotationKey) -> Result<(), Self::Error>;
+
+    fn write_journal(&mut self, journal: &RotationJournal) -> Result<(), Self::Error>;
+
+    fn read_journal(&mut self) -> Result<Option<RotationJournal>, Self::Error>;
+
+    fn delete_journal(&mut self) -> Result<(), Self::Error>;
+
+    /// Test hook, called with the new ciphertext after each `Stage`.
+    fn after(&mut self, _stage: Stage, _ciphertext: &mut Vec<u8>) {}
+}
+
+/// How `recover` found an earlier rotation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// No rotation was interrupted.
+    Clean,
+    /// One was interrupted before its switch; the old key and model stay.
+    RolledBack,
+    /// One was interrupted after its switch; its key is now imported.
+    Completed,
+}
+
+/// Re-encrypts `plain`, the stored model decrypted under the current key,
+/// under `key` and makes `key` the key manager's key. Without a stored
+/// model only the key is replaced. Returns the SHA-256 of the new stored
+/// ciphertext.
+///
+/// Any failure before the switch leaves the old key and ciphertext in
+/// place. A failure after it leaves the journal, so `recover` (or retrying
+/// with the same key) completes the rotation.
+pub fn rotate<S: RotationSteps>(
+    steps: &mut S,
+    key: &RotationKey,
+    plain: Option<&[u8]>,
+) -> Result<Option<RotationHash>, S::Error> {
+    let Some(plain) = plain else {
+        steps.import_key(key)?;
+        return Ok(None);
+    };
+    let mut ciphertext = steps.encrypt(key, plain)?;
+    steps.after(Stage::Encrypted, &mut ciphertext);
+    let verified = steps.verify(key, &ciphertext, plain)?;
+    steps.after(Stage::Verified, &mut ciphertext);
+    if steps.write(&ciphertext)? != verified {
+        steps.discard_written()?;
+        return Err(RotationError::Mismatch.into());
+    }
+    let journal = RotationJournal {
+        key: *key,
+        hash: verified,
+    };
+    if let Err(err) = steps.write_journal(&journal) {
+        steps.discard_written()?;
+        return Err(err);
+    }
+    if let Err(err) = steps.switch() {
+        // The switch either happened or not; the journal tells which
+        return match recover(steps)? {
+            Recovery::Completed => Ok(Some(verified)),
+            _ => Err(err),
+        };
+    }
+    steps.import_key(key)?;
+    steps.delete_journal()?;
+    Ok(Some(verified))
+}
+
+/// Finishes or rolls back a rotation that was interrupted after writing its
+/// journal, going by whether the stored ciphertext is the journal's.
+pub fn recover<S: RotationSteps>(steps: &mut S) -> Result<Recovery, S::Error> {
+    let Some(journal) = steps.read_journal()? else {
+        return Ok(Recovery::Clean);
+    };
+    let recovery = if steps.stored_hash()? == Some(journal.hash) {
+        steps.import_key(&journal.key)?;
+        Recovery::Completed
+    } else {
+        Recovery::RolledBack
+    };
+    steps.delete_journal()?;
+    Ok(recovery)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD_KEY: RotationKey = [0x11; 32];
+    const NEW_KEY: RotationKey = [0x22; 32];
+    const MODEL: &[u8] = b"a model record that is long enough to span the key";
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum Step {
+        Encrypt,
+        Verify,
+        Write,
+        DiscardWritten,
+        Switch,
+        StoredHash,
+        ImportKey,
+        WriteJournal,
+        ReadJournal,
+        DeleteJournal,
+    }
+
+    const STEPS: [Step; 10] = [
+        Step::Encrypt,
+        Step::Verify,
+        Step::Write,
+        Step::DiscardWritten,
+        Step::Switch,
+        Step::StoredHash,
+        Step::ImportKey,
+        Step::WriteJournal,
+        Step::ReadJournal,
+        Step::DeleteJournal,
+    ];
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum FakeError {
+        Injected(Step),
+        Crashed,
+        WrongModel,
+        Rotation(RotationError),
+    }
+
+    impl From<RotationError> for FakeError {
+        fn from(err: RotationError) -> Self {
+            FakeError::Rotation(err)
+        }
+    }
+
+    // A key manager holding one key and a store holding one ciphertext, a
+    // written but unswitched one and a journal. A failure injected at a step
+    // either fails that step alone or, as a crash, every step from there on.
+    struct Fake {
+        key: RotationKey,
+        stored: Vec<u8>,
+        written: Option<Vec<u8>>,
+        journal: Option<RotationJournal>,
+        fail_at: Option<Step>,
+        crash: bool,
+        crashed: bool,
+        corrupt_at: Option<Stage>,
+    }
+
+    // XOR with the key; enough to tell which key a ciphertext is under
+    fn cipher(key: &RotationKey, data: &[u8]) -> Vec<u8> {
+        data.iter()
+            .zip(key.iter().cycle())
+            .enumerate()
+            .map(|(i, (byte, k))| byte ^ k ^ i as u8)
+            .collect()
+    }
+
+    fn hash(data: &[u8]) -> RotationHash {
+        let mut hash = [0u8; 32];
+        for (i, byte) in data.iter().enumerate() {
+            let slot = &mut hash[i % 32];
+            *slot = slot.wrapping_mul(31).wrapping_add(*byte);
+        }
+        hash[0] ^= data.len() as u8;
+        hash
+    }
+
+    impl Fake {
+        fn new() -> Self {
+            Self {
+                key: OLD_KEY,
+                stored: cipher(&OLD_KEY, MODEL),
+                written: None,
+                journal: None,
+                fail_at: None,
+                crash: false,
+                crashed: false,
+                corrupt_at: None,
+            }
+        }
+
+        fn failing(step: Step, crash: bool) -> Self {
+            Self {
+                fail_at: Some(step),
+                crash,
+                ..Self::new()
+            }
+        }
+
+        fn enter(&mut self, step: Step) -> Result<(), FakeError> {
+            if self.crashed {
+                return Err(FakeError::Crashed);
+            }
+            if self.fail_at == Some(step) {
+                self.crashed = self.crash;
+                return Err(FakeError::Injected(step));
+            }
+            Ok(())
+        }
+
+        // A restart: the injected failure is gone, storage and key stay
+        fn restart(&mut self) {
+            self.fail_at = None;
+            self.crashed = false;
+            self.written = None;
+        }
+
+        fn model(&self) -> Vec<u8> {
+            cipher(&self.key, &self.stored)
+        }
+    }
+
+    impl RotationSteps for Fake {
+        type Error = FakeError;
+
+        fn encrypt(&mut self, key: &RotationKey, plain: &[u8]) -> Result<Vec<u8>, FakeError> {
+            self.enter(Step::Encrypt)?;
+            Ok(cipher(key, plain))
+        }
+
+        fn verify(
+            &mut self,
+            key: &RotationKey,
+            ciphertext: &[u8],
+            plain: &[u8],
+        ) -> Result<RotationHash, FakeError> {
+            self.enter(Step::Verify)?;
+            if cipher(key, ciphertext) != plain {
+                return Err(FakeError::WrongModel);
+            }
+            Ok(hash(ciphertext))
+        }
+
+        fn write(&mut self, ciphertext: &[u8]) -> Result<RotationHash, FakeError> {
+            self.enter(Step::Write)?;
+            self.written = Some(ciphertext.to_vec());
+            Ok(hash(ciphertext))
+        }
+
+        fn discard_written(&mut self) -> Result<(), FakeError> {
+            self.enter(Step::DiscardWritten)?;
+            self.written = None;
+            Ok(())
+        }
+
+        fn switch(&mut self) -> Result<(), FakeError> {
+            self.enter(Step::Switch)?;
+            self.stored = self.written.take().expect("switch without a write");
+            Ok(())
+        }
+
+        fn stored_hash(&mut self) -> Result<Option<RotationHash>, FakeError> {
+            self.enter(Step::StoredHash)?;
+            Ok(Some(hash(&self.stored)))
+        }
+
+        fn import_key(&mut self, key: &RotationKey) -> Result<(), FakeError> {
+            self.enter(Step::ImportKey)?;
+            self.key = *key;
+            Ok(())
+        }
+
+        fn write_journal(&mut self, journal: &RotationJournal) -> Result<(), FakeError> {
+            self.enter(Step::WriteJournal)?;
+            self.journal = Some(journal.clone());
+            Ok(())
+        }
+
+        fn read_journal(&mut self) -> Result<Option<RotationJournal>, FakeError> {
+            self.enter(Step::ReadJournal)?;
+            Ok(self.journal.clone())
+        }
+
+        fn delete_journal(&mut self) -> Result<(), FakeError> {
+            self.enter(Step::DeleteJournal)?;
+            self.journal = None;
+            Ok(())
+        }
+
+        fn after(&mut self, stage: Stage, ciphertext: &mut Vec<u8>) {
+            if self.corrupt_at == Some(stage) {
+                ciphertext[0] ^= 0x80;
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_switches_model_and_key() {
+        let mut fake = Fake::new();
+        let hash = rotate(&mut fake, &NEW_KEY, Some(MODEL)).unwrap();
+        assert_eq!(fake.key, NEW_KEY);
+        assert_eq!(fake.model(), MODEL);
+        assert_eq!(hash, Some(super::tests::hash(&fake.stored)));
+        assert_eq!(fake.journal, None);
+        assert_eq!(recover(&mut fake), Ok(Recovery::Clean));
+    }
+
+    #[test]
+    fn rotation_without_a_model_only_replaces_the_key() {
+        let mut fake = Fake::new();
+        assert_eq!(rotate(&mut fake, &NEW_KEY, None), Ok(None));
+        assert_eq!(fake.key, NEW_KEY);
+    }
+
+    #[test]
+    fn corruption_keeps_old_key_and_model() {
+        for stage in [Stage::Encrypted, Stage::Verified] {
+            let mut fake = Fake::new();
+            let old = fake.stored.clone();
+            fake.corrupt_at = Some(stage);
+            assert!(rotate(&mut fake, &NEW_KEY, Some(MODEL)).is_err(), "{:?}", stage);
+            assert_eq!(fake.key, OLD_KEY, "{:?}", stage);
+            assert_eq!(fake.stored, old, "{:?}", stage);
+            assert_eq!(fake.written, None, "{:?}", stage);
+            assert_eq!(fake.journal, None, "{:?}", stage);
+        }
+        let mut fake = Fake::new();
+        fake.corrupt_at = Some(Stage::Verified);
+        assert_eq!(
+            rotate(&mut fake, &NEW_KEY, Some(MODEL)),
+            Err(FakeError::Rotation(RotationError::Mismatch))
+        );
+    }
+
+    // Wherever a rotation fails or crashes, the model stays readable: either
+    // the old key with the old ciphertext or, once the switch happened, the
+    // new key with the new one after recovery.
+    #[test]
+    fn every_failure_point_keeps_the_model_readable() {
+        for step in STEPS {
+            for crash in [false, true] {
+                let mut fake = Fake::failing(step, crash);
+                let old = fake.stored.clone();
+                let result = rotate(&mut fake, &NEW_KEY, Some(MODEL));
+                fake.restart();
+                recover(&mut fake).unwrap();
+                let case = (step, crash, &result);
+                assert_eq!(fake.model(), MODEL, "{:?}", case);
+                assert_eq!(fake.journal, None, "{:?}", case);
+                if fake.key == OLD_KEY {
+                    assert_eq!(fake.stored, old, "{:?}", case);
+                    assert!(result.is_err(), "{:?}", case);
+                } else {
+                    assert_eq!(fake.key, NEW_KEY, "{:?}", case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interrupted_switch_is_completed_by_recovery() {
+        // Crashed between the switch and the key change
+        let mut fake = Fake::failing(Step::ImportKey, true);
+        assert!(rotate(&mut fake, &NEW_KEY, Some(MODEL)).is_err());
+        assert_eq!(fake.key, OLD_KEY);
+        assert!(fake.journal.is_some());
+        fake.restart();
+        assert_eq!(recover(&mut fake), Ok(Recovery::Completed));
+        assert_eq!(fake.key, NEW_KEY);
+        assert_eq!(fake.model(), MODEL);
+    }
+
+    #[test]
+    fn interrupted_write_is_rolled_back_by_recovery() {
+        // Crashed after the journal, before the switch
+        let mut fake = Fake::failing(Step::Switch, true);
+        let old = fake.stored.clone();
+        assert!(rotate(&mut fake, &NEW_KEY, Some(MODEL)).is_err());
+        fake.restart();
+        assert_eq!(recover(&mut fake), Ok(Recovery::RolledBack));
+        assert_eq!(fake.key, OLD_KEY);
+        assert_eq!(fake.stored, old);
+    }
+
+    #[test]
+    fn journal_round_trip() {
+        let journal = RotationJournal {
+            key: NEW_KEY,
+            hash: [7; 32],
+        };
+        assert_eq!(
+            RotationJournal::from_bytes(&journal.to_bytes()),
+            Some(journal)
+        );
+        assert_eq!(RotationJournal::from_bytes(&[0; 63]), None);
+    }
+}
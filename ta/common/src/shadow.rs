@@ -0,0 +1,282 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Shadow evaluation of a candidate model (`Command::SetShadow`): inferences on
+// the active slot also run the shadow slot, whose labels are only compared
+// with the active ones. Nothing here may fail an inference; the active
+// slot's response is sent whatever the shadow does. The TA passes the model
+// run and the input hash; tests pass stand-ins.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use proto::inference::{ShadowExample, ShadowReport, MAX_SHADOW_EXAMPLES};
+use proto::Image;
+use spin::Mutex;
+
+/// The shadow configuration and what the shadow slot answered so far.
+pub struct ShadowLog {
+    report: ShadowReport,
+}
+
+impl ShadowLog {
+    pub const fn new() -> Self {
+        Self {
+            report: ShadowReport {
+                slots: None,
+                compared: 0,
+                disagreements: 0,
+                skipped: 0,
+                failed: 0,
+                examples: Vec::new(),
+            },
+        }
+    }
+
+    /// Makes `shadow` the shadow of `active`, or turns shadowing off, and
+    /// starts a new report.
+    pub fn configure(&mut self, slots: Option<(usize, usize)>) {
+        self.report = ShadowReport {
+            slots: slots.map(|(active, shadow)| (active as u32, shadow as u32)),
+            ..Default::default()
+        };
+    }
+
+    /// Slot shadowing `active`, if any.
+    pub fn shadow_of(&self, active: usize) -> Option<usize> {
+        match self.report.slots {
+            Some((slot, shadow)) if slot as usize == active => Some(shadow as usize),
+            _ => None,
+        }
+    }
+
+    /// Compares the labels of both slots for `images`, keeping the first
+    /// disagreements under the hash `input_hash` gives their pixels.
+    pub fn record(
+        &mut self,
+        images: &[Image],
+        active: &[u8],
+        shadow: &[u8],
+        mut input_hash: impl FnMut(&[u8]) -> Option<String>,
+    ) {
+        let report = &mut self.report;
+        report.compared += active.len() as u64;
+        for ((image, &active_label), &shadow_label) in images.iter().zip(active).zip(shadow) {
+            if active_label == shadow_label {
+                continue;
+            }
+            report.disagreements += 1;
+            if report.examples.len() >= MAX_SHADOW_EXAMPLES {
+                continue;
+            }
+            if let Some(input_hash) = input_hash(image.as_bytes()) {
+                report.examples.push(ShadowExample {
+                    input_hash,
+                    active_label,
+                    shadow_label,
+                });
+            }
+        }
+    }
+
+    /// The report so far; `reset` starts a new one with the same slots.
+    pub fn report(&mut self, reset: bool) -> ShadowReport {
+        let current = self.report.clone();
+        if reset {
+            self.configure(
+                current
+                    .slots
+                    .map(|(active, shadow)| (active as usize, shadow as usize)),
+            );
+        }
+        current
+    }
+}
+
+impl Default for ShadowLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs the shadow of `slot`, if it has one, on the `images` the active
+/// model gave `labels`, and records where the two disagree. `skip` is the
+/// request's `FLAG_SKIP_SHADOW`; `run` runs the shadow model, `None` when it
+/// can't. The model runs without the lock. Returns the shadow's labels.
+pub fn run_shadow(
+    log: &Mutex<ShadowLog>,
+    slot: usize,
+    images: &[Image],
+    labels: &[u8],
+    skip: bool,
+    run: impl FnOnce(usize) -> Option<Vec<u8>>,
+    input_hash: impl FnMut(&[u8]) -> Option<String>,
+) -> Option<Vec<u8>> {
+    let shadow = log.lock().shadow_of(slot)?;
+    if skip {
+        log.lock().report.skipped += images.len() as u64;
+        return None;
+    }
+    match run(shadow) {
+        Some(shadow_labels) if shadow_labels.len() == labels.len() => {
+            log.lock()
+                .record(images, labels, &shadow_labels, input_hash);
+            Some(shadow_labels)
+        }
+        _ => {
+            log.lock().report.failed += images.len() as u64;
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::vec;
+
+    fn images(count: usize) -> Vec<Image> {
+        (0..count)
+            .map(|i| {
+                let mut image = Image::BLANK;
+                image.as_bytes_mut()[0] = i as u8;
+                image
+            })
+            .collect()
+    }
+
+    // Stands in for the SHA-256 of the TA: the first pixel
+    fn first_pixel(pixels: &[u8]) -> Option<String> {
+        Some(format!("{:02x}", pixels[0]))
+    }
+
+    fn shadowing(active: usize, shadow: usize) -> Mutex<ShadowLog> {
+        let mut log = ShadowLog::new();
+        log.configure(Some((active, shadow)));
+        Mutex::new(log)
+    }
+
+    #[test]
+    fn planted_disagreements_are_reported() {
+        let log = shadowing(0, 1);
+        let images = images(6);
+        let active = [1, 2, 3, 4, 5, 6];
+        // The candidate differs on images 1 and 4
+        let planted = vec![1, 7, 3, 4, 9, 6];
+        let mut runs = Vec::new();
+        let shadow = run_shadow(
+            &log,
+            0,
+            &images,
+            &active,
+            false,
+            |slot| {
+                runs.push(slot);
+                Some(planted.clone())
+            },
+            first_pixel,
+        );
+        assert_eq!(runs, [1]);
+        assert_eq!(shadow.as_deref(), Some(&planted[..]));
+        let report = log.lock().report(false);
+        assert_eq!(report.slots, Some((0, 1)));
+        assert_eq!((report.compared, report.disagreements), (6, 2));
+        assert_eq!(
+            report.examples,
+            [
+                ShadowExample {
+                    input_hash: "01".into(),
+                    active_label: 2,
+                    shadow_label: 7,
+                },
+                ShadowExample {
+                    input_hash: "04".into(),
+                    active_label: 5,
+                    shadow_label: 9,
+                },
+            ]
+        );
+        assert_eq!(report.disagreement_rate(), 2.0 / 6.0);
+    }
+
+    #[test]
+    fn only_the_active_slot_is_shadowed() {
+        let log = shadowing(0, 1);
+        for slot in [1, 2] {
+            let run = |_| panic!("slot {slot} has no shadow");
+            assert_eq!(
+                run_shadow(&log, slot, &images(2), &[0, 0], false, run, first_pixel),
+                None
+            );
+        }
+        // Nor anything once it is turned off
+        log.lock().configure(None);
+        let run = |_| panic!("shadowing is off");
+        assert_eq!(
+            run_shadow(&log, 0, &images(2), &[0, 0], false, run, first_pixel),
+            None
+        );
+        assert_eq!(log.lock().report(false), ShadowReport::default());
+    }
+
+    #[test]
+    fn skipped_and_failed_runs_are_counted() {
+        let log = shadowing(2, 0);
+        let run = |_| panic!("skipped requests don't run the shadow");
+        assert_eq!(
+            run_shadow(&log, 2, &images(3), &[1, 1, 1], true, run, first_pixel),
+            None
+        );
+        // An empty or expired shadow, and one answering for fewer images
+        for labels in [None, Some(vec![1])] {
+            let shadow = run_shadow(&log, 2, &images(2), &[1, 1], false, |_| labels, first_pixel);
+            assert_eq!(shadow, None);
+        }
+        let report = log.lock().report(false);
+        assert_eq!((report.skipped, report.failed), (3, 4));
+        assert_eq!((report.compared, report.disagreements), (0, 0));
+    }
+
+    #[test]
+    fn examples_are_capped_and_reports_reset() {
+        let log = shadowing(0, 1);
+        let count = MAX_SHADOW_EXAMPLES + 4;
+        let active = vec![0; count];
+        run_shadow(
+            &log,
+            0,
+            &images(count),
+            &active,
+            false,
+            |_| Some(vec![1; count]),
+            first_pixel,
+        );
+        let report = log.lock().report(true);
+        assert_eq!(report.disagreements, count as u64);
+        assert_eq!(report.examples.len(), MAX_SHADOW_EXAMPLES);
+        // A new report for the same slots
+        let report = log.lock().report(false);
+        assert_eq!(report.slots, Some((0, 1)));
+        assert_eq!(report.disagreements, 0);
+        assert!(report.examples.is_empty());
+        // Images that can't be hashed are counted without an example
+        log.lock().record(&images(1), &[0], &[1], |_| None);
+        let report = log.lock().report(false);
+        assert_eq!((report.disagreements, report.examples.len()), (1, 0));
+    }
+}
@@ -0,0 +1,143 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+// Which pushed models pass the provenance check, kept apart from the TA's
+// key manager so the policy can be exercised with any verifier in tests.
+// Once a verify key is provisioned every model needs a signature the key
+// accepts; without one, models load unsigned as before.
+
+use core::fmt;
+
+use proto::inference::MAX_MODEL_SIGNATURE_SIZE;
+
+/// Why a pushed model's signature was refused, `E` being the verifier's
+/// error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError<E> {
+    /// A verify key is provisioned but the model carries no signature.
+    Unsigned,
+    /// The signature is empty or longer than any supported key's.
+    BadLength(usize),
+    /// The verifier refused the signature or failed.
+    Check(E),
+}
+
+impl<E: fmt::Display> fmt::Display for SignatureError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Unsigned => {
+                write!(f, "unsigned model, but a verify key is provisioned")
+            }
+            SignatureError::BadLength(len) => write!(f, "model signature of {} bytes", len),
+            SignatureError::Check(err) => write!(f, "model signature check failed: {}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> core::error::Error for SignatureError<E> {}
+
+#[cfg(feature = "optee-utee")]
+impl From<SignatureError<optee_utee::Error>> for optee_utee::Error {
+    fn from(err: SignatureError<optee_utee::Error>) -> Self {
+        match err {
+            SignatureError::Unsigned | SignatureError::BadLength(_) => {
+                optee_utee::ErrorKind::SignatureInvalid.into()
+            }
+            SignatureError::Check(err) => err,
+        }
+    }
+}
+
+/// Checks a pushed model's `signature` against the provisioned verify `key`
+/// with `verify(key, signature)`, which checks it over the model. Passes
+/// anything while no key is provisioned.
+pub fn check_model_signature<E>(
+    key: Option<&[u8]>,
+    signature: Option<&[u8]>,
+    verify: impl FnOnce(&[u8], &[u8]) -> Result<(), E>,
+) -> Result<(), SignatureError<E>> {
+    let Some(key) = key else {
+        return Ok(());
+    };
+    let signature = signature.ok_or(SignatureError::Unsigned)?;
+    if signature.is_empty() || signature.len() > MAX_MODEL_SIGNATURE_SIZE {
+        return Err(SignatureError::BadLength(signature.len()));
+    }
+    verify(key, signature).map_err(SignatureError::Check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"verify key";
+    const SIGNATURE: &[u8] = b"signature by the verify key";
+
+    // Accepts SIGNATURE under KEY only
+    fn verify(key: &[u8], signature: &[u8]) -> Result<(), &'static str> {
+        assert_eq!(key, KEY);
+        if signature == SIGNATURE {
+            Ok(())
+        } else {
+            Err("bad signature")
+        }
+    }
+
+    #[test]
+    fn models_load_unchecked_without_a_key() {
+        let unreachable = |_: &[u8], _: &[u8]| -> Result<(), ()> { panic!("verified") };
+        assert_eq!(check_model_signature(None, None, unreachable), Ok(()));
+        assert_eq!(
+            check_model_signature(None, Some(b"junk"), unreachable),
+            Ok(())
+        );
+        assert_eq!(check_model_signature(None, Some(b""), unreachable), Ok(()));
+    }
+
+    #[test]
+    fn unsigned_models_are_refused_once_a_key_is_provisioned() {
+        assert_eq!(
+            check_model_signature(Some(KEY), None, verify),
+            Err(SignatureError::Unsigned)
+        );
+    }
+
+    #[test]
+    fn signatures_of_impossible_lengths_never_reach_the_verifier() {
+        let too_long = [0u8; MAX_MODEL_SIGNATURE_SIZE + 1];
+        for signature in [&[][..], &too_long[..]] {
+            let result =
+                check_model_signature(Some(KEY), Some(signature), |_, _| -> Result<(), ()> {
+                    panic!("verified")
+                });
+            assert_eq!(result, Err(SignatureError::BadLength(signature.len())));
+        }
+    }
+
+    #[test]
+    fn only_the_keys_signature_passes() {
+        assert_eq!(
+            check_model_signature(Some(KEY), Some(SIGNATURE), verify),
+            Ok(())
+        );
+        let mut tampered = SIGNATURE.to_vec();
+        tampered[0] ^= 1;
+        assert_eq!(
+            check_model_signature(Some(KEY), Some(&tampered), verify),
+            Err(SignatureError::Check("bad signature"))
+        );
+    }
+}
@@ -0,0 +1,321 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// The installed models, one per slot. Handles are `Arc`s: an inference
+// clones one and runs its forward pass without the lock, and a replaced
+// model is freed by whoever drops its last handle, never under the lock.
+// The lock is only held to clone, swap or compare handles. State kept beside
+// the models, under its own lock taken after this one, is updated or read
+// in the closures the methods run under the lock, so it always belongs to
+// the same install as the handle.
+
+use alloc::sync::Arc;
+use proto::inference::MODEL_SLOTS;
+use spin::Mutex;
+
+pub struct ModelSlots<M> {
+    slots: Mutex<[Option<Arc<M>>; MODEL_SLOTS]>,
+}
+
+/// `ModelSlots::replace` found another model in the slot than the one the
+/// replacement was made from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotChanged;
+
+impl<M> ModelSlots<M> {
+    pub const fn new() -> Self {
+        Self {
+            slots: Mutex::new([const { None }; MODEL_SLOTS]),
+        }
+    }
+
+    /// Handle on the model in `slot`.
+    pub fn get(&self, slot: usize) -> Option<Arc<M>> {
+        self.slots.lock()[slot].clone()
+    }
+
+    /// `get`, with `paired` read under the lock.
+    pub fn get_with<R>(&self, slot: usize, paired: impl FnOnce() -> R) -> (Option<Arc<M>>, R) {
+        let slots = self.slots.lock();
+        (slots[slot].clone(), paired())
+    }
+
+    pub fn is_loaded(&self, slot: usize) -> bool {
+        self.slots.lock()[slot].is_some()
+    }
+
+    /// Installs `model` into `slot`, running `paired` under the lock, and
+    /// returns the model it replaced.
+    pub fn install(&self, slot: usize, model: M, paired: impl FnOnce()) -> Option<Arc<M>> {
+        let model = Arc::new(model);
+        let mut slots = self.slots.lock();
+        paired();
+        slots[slot].replace(model)
+    }
+
+    /// Installs `model`, made from `current` without the lock, unless the
+    /// slot holds another model by now. Returns `current`'s handle in the
+    /// slot.
+    pub fn replace(
+        &self,
+        slot: usize,
+        current: &Arc<M>,
+        model: M,
+    ) -> Result<Option<Arc<M>>, SlotChanged> {
+        let model = Arc::new(model);
+        let mut slots = self.slots.lock();
+        if !slots[slot]
+            .as_ref()
+            .is_some_and(|installed| Arc::ptr_eq(installed, current))
+        {
+            return Err(SlotChanged);
+        }
+        Ok(slots[slot].replace(model))
+    }
+
+    /// Empties `slot`, running `paired` under the lock, and returns the
+    /// model it held.
+    pub fn remove(&self, slot: usize, paired: impl FnOnce()) -> Option<Arc<M>> {
+        let mut slots = self.slots.lock();
+        paired();
+        slots[slot].take()
+    }
+
+    /// Empties every slot, running `paired` under the lock, and returns the
+    /// models they held.
+    pub fn clear(&self, paired: impl FnOnce()) -> [Option<Arc<M>>; MODEL_SLOTS] {
+        let mut slots = self.slots.lock();
+        paired();
+        core::mem::replace(&mut *slots, [const { None }; MODEL_SLOTS])
+    }
+}
+
+impl<M> Default for ModelSlots<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+
+    // A model whose every weight is its id, so a torn or freed one shows
+    struct Model {
+        id: usize,
+        weights: Vec<usize>,
+        dropped: &'static AtomicUsize,
+    }
+
+    impl Model {
+        fn new(id: usize, dropped: &'static AtomicUsize) -> Self {
+            Self {
+                id,
+                weights: vec![id; 1024],
+                dropped,
+            }
+        }
+
+        // Stands in for a forward pass, run without the slot lock
+        fn forward(&self) -> usize {
+            self.weights.iter().filter(|&&w| w == self.id).count()
+        }
+    }
+
+    impl Drop for Model {
+        fn drop(&mut self) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn install_get_and_remove() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        let slots = ModelSlots::new();
+        assert!(!slots.is_loaded(0));
+        assert!(slots.install(0, Model::new(1, &DROPPED), || {}).is_none());
+        let handle = slots.get(0).unwrap();
+        // The replaced model lives on in the handle taken before
+        let replaced = slots.install(0, Model::new(2, &DROPPED), || {}).unwrap();
+        assert!(Arc::ptr_eq(&replaced, &handle));
+        drop(replaced);
+        assert_eq!(handle.forward(), 1024);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 0);
+        drop(handle);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+
+        slots.install(1, Model::new(3, &DROPPED), || {});
+        assert_eq!(slots.remove(0, || {}).unwrap().id, 2);
+        assert!(!slots.is_loaded(0) && slots.is_loaded(1));
+        let cleared = slots.clear(|| {});
+        assert_eq!(cleared.iter().flatten().count(), 1);
+        drop(cleared);
+        assert_eq!(DROPPED.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn replace_refuses_a_changed_slot() {
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        let slots = ModelSlots::new();
+        slots.install(0, Model::new(1, &DROPPED), || {});
+        let current = slots.get(0).unwrap();
+        slots.install(0, Model::new(2, &DROPPED), || {});
+        assert_eq!(
+            slots.replace(0, &current, Model::new(3, &DROPPED)).err(),
+            Some(SlotChanged)
+        );
+        assert_eq!(slots.get(0).unwrap().id, 2);
+
+        let current = slots.get(0).unwrap();
+        let replaced = slots.replace(0, &current, Model::new(4, &DROPPED));
+        assert!(Arc::ptr_eq(&replaced.unwrap().unwrap(), &current));
+        assert_eq!(slots.get(0).unwrap().id, 4);
+        // An emptied slot isn't refilled either
+        slots.remove(0, || {});
+        let err = slots.replace(0, &current, Model::new(5, &DROPPED));
+        assert_eq!(err.err(), Some(SlotChanged));
+        assert!(!slots.is_loaded(0));
+    }
+
+    // Inferences, installs and patches on one slot from several threads:
+    // every inference gets a whole model paired with its own info, installs
+    // and patches all finish, and every replaced model is freed
+    #[test]
+    fn concurrent_installs_and_inferences() {
+        const INSTALLS: usize = 200;
+        const READERS: usize = 4;
+        static DROPPED: AtomicUsize = AtomicUsize::new(0);
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        static SLOTS: ModelSlots<Model> = ModelSlots::new();
+        static INFO: Mutex<[usize; MODEL_SLOTS]> = Mutex::new([0; MODEL_SLOTS]);
+        static DONE: AtomicBool = AtomicBool::new(false);
+
+        let new_model = |id| {
+            CREATED.fetch_add(1, Ordering::Relaxed);
+            Model::new(id, &DROPPED)
+        };
+        SLOTS.install(0, new_model(1), || INFO.lock()[0] = 1);
+
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                thread::spawn(|| {
+                    let mut inferences = 0;
+                    while !DONE.load(Ordering::Relaxed) || inferences < 100 {
+                        let (model, info) = SLOTS.get_with(0, || INFO.lock()[0]);
+                        let model = model.expect("slot 0 stays loaded");
+                        assert_eq!(model.id, info);
+                        assert_eq!(model.forward(), model.weights.len());
+                        inferences += 1;
+                    }
+                    inferences
+                })
+            })
+            .collect();
+        let patcher = thread::spawn(move || {
+            while !DONE.load(Ordering::Relaxed) {
+                let current = SLOTS.get(0).unwrap();
+                let patch = new_model(current.id);
+                if let Ok(replaced) = SLOTS.replace(0, &current, patch) {
+                    assert!(Arc::ptr_eq(&replaced.unwrap(), &current));
+                }
+            }
+        });
+        for id in 2..=INSTALLS {
+            let replaced = SLOTS.install(0, new_model(id), || INFO.lock()[0] = id);
+            assert!(replaced.is_some());
+        }
+        DONE.store(true, Ordering::Relaxed);
+        for reader in readers {
+            assert!(reader.join().unwrap() >= 100);
+        }
+        patcher.join().unwrap();
+
+        assert_eq!(SLOTS.get(0).unwrap().id, INSTALLS);
+        assert_eq!(INFO.lock()[0], INSTALLS);
+        // Only the installed model is still alive
+        assert_eq!(
+            DROPPED.load(Ordering::Relaxed),
+            CREATED.load(Ordering::Relaxed) - 1
+        );
+    }
+
+    // The TA's own model type, which isn't `Sync`, so commands reach it one
+    // at a time as OP-TEE delivers them: installs and patches land between
+    // an inference taking its handle and running its forward pass, which
+    // still sees the model it took, paired with that model's info
+    #[test]
+    fn interleaved_provisioning_and_inference_with_the_real_model() {
+        use crate::Model;
+        use burn::backend::NdArray;
+        use burn::tensor::Tensor;
+        use proto::IMAGE_SIZE;
+
+        type B = NdArray;
+        let _rng = crate::TEST_RNG.lock();
+        let device = Default::default();
+        // Parameters are initialized lazily, so models are made from records
+        // to get the same weights every time
+        let records: Vec<_> = (0..4)
+            .map(|seed| {
+                Model::<B>::new_with_seed(&device, seed, 10)
+                    .export()
+                    .unwrap()
+            })
+            .collect();
+        let model =
+            |seed: u64| Model::<B>::import(&device, records[seed as usize].clone()).unwrap();
+        let input = || Tensor::<B, 2>::ones([2, IMAGE_SIZE], &device);
+        let expected: Vec<_> = (0..4)
+            .map(|seed| model(seed).forward(input()).into_data())
+            .collect();
+
+        let slots = ModelSlots::new();
+        let info = Mutex::new([None; MODEL_SLOTS]);
+        slots.install(0, model(0), || info.lock()[0] = Some(0));
+        let mut held = Vec::new();
+        for round in 0..8u64 {
+            let seed = round % 4;
+            let (handle, paired) = slots.get_with(0, || info.lock()[0]);
+            held.push((handle.unwrap(), paired.unwrap()));
+            let next = (round + 1) % 4;
+            if round % 2 == 0 {
+                slots.install(0, model(next), || info.lock()[0] = Some(next));
+            } else {
+                let current = slots.get(0).unwrap();
+                let patched = slots.replace(0, &current, model(next)).unwrap();
+                assert!(Arc::ptr_eq(&patched.unwrap(), &current));
+                info.lock()[0] = Some(next);
+            }
+            let (handle, paired) = held.last().unwrap();
+            assert_eq!(*paired, seed);
+            assert_eq!(handle.forward(input()).into_data(), expected[seed as usize]);
+        }
+        // Every replaced model is only kept alive by the handles held here
+        for (handle, _) in held {
+            assert_eq!(Arc::strong_count(&handle), 1);
+        }
+        let (handle, paired) = slots.get_with(0, || info.lock()[0]);
+        assert_eq!(paired, Some(0));
+        assert_eq!(handle.unwrap().forward(input()).into_data(), expected[0]);
+    }
+}
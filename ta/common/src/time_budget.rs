@@ -0,0 +1,129 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Slicing of a `FLAG_TIME_BUDGET` batch, shared by the TA and the simulated
+// TA: the batch runs `TIME_BUDGET_SLICE` images at a time, and the budget is
+// checked against the clock each passes in after every slice.
+
+use core::ops::Range;
+
+use proto::inference::DurationMicros;
+
+/// Runs the `images` of a batch through `run`, `slice` at a time, until the
+/// slice during which `budget` ran out by `now`. The first slice always
+/// runs. Returns the images run, all of them unless the budget ran out
+/// first.
+pub fn run_within_budget<E>(
+    images: usize,
+    slice: usize,
+    budget: DurationMicros,
+    mut now: impl FnMut() -> DurationMicros,
+    mut run: impl FnMut(Range<usize>) -> Result<(), E>,
+) -> Result<usize, E> {
+    let started = now();
+    let mut completed = 0;
+    while completed < images {
+        let end = images.min(completed + slice);
+        run(completed..end)?;
+        completed = end;
+        if now().saturating_sub(started) >= budget {
+            break;
+        }
+    }
+    Ok(completed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use core::cell::Cell;
+
+    const SLICE: usize = 16;
+
+    // Runs `images` with every slice taking `per_slice` of a clock that
+    // only moves while a slice runs, logging the slices
+    fn run(images: usize, budget_ms: u64, per_slice: u64) -> (usize, Vec<Range<usize>>) {
+        let clock = Cell::new(DurationMicros::from_millis(1000));
+        let spent = DurationMicros::from_millis(per_slice);
+        let mut slices = Vec::new();
+        let completed = run_within_budget(
+            images,
+            SLICE,
+            DurationMicros::from_millis(budget_ms),
+            || clock.get(),
+            |range| {
+                clock.set(clock.get().saturating_add(spent));
+                slices.push(range);
+                Ok::<_, ()>(())
+            },
+        )
+        .unwrap();
+        (completed, slices)
+    }
+
+    #[test]
+    fn partial_results_cover_the_slices_run_in_time() {
+        // 128 images at 10ms a slice: the budget is spent during the fifth
+        let (completed, slices) = run(128, 45, 10);
+        assert_eq!(completed, 80);
+        assert_eq!(slices, [0..16, 16..32, 32..48, 48..64, 64..80]);
+        // Spent exactly at the end of a slice stops there too
+        assert_eq!(run(128, 50, 10).0, 80);
+        assert_eq!(run(128, 51, 10).0, 96);
+    }
+
+    #[test]
+    fn batches_in_time_complete() {
+        let (completed, slices) = run(40, 1000, 10);
+        assert_eq!(completed, 40);
+        // The last slice is short
+        assert_eq!(slices, [0..16, 16..32, 32..40]);
+        // Running out during the last slice still completes the batch
+        assert_eq!(run(40, 25, 10).0, 40);
+        assert_eq!(run(0, 0, 10), (0, Vec::new()));
+    }
+
+    #[test]
+    fn the_first_slice_always_runs() {
+        for (images, per_slice, first) in [(128, 10, 0..16), (128, 0, 0..16), (5, 10, 0..5)] {
+            let (completed, slices) = run(images, 0, per_slice);
+            assert_eq!(completed, first.end);
+            assert_eq!(slices, Vec::from([first]));
+        }
+    }
+
+    #[test]
+    fn failing_slices_fail_the_batch() {
+        let mut slices = 0;
+        let result = run_within_budget(
+            64,
+            SLICE,
+            DurationMicros::from_millis(1000),
+            || DurationMicros::ZERO,
+            |range| {
+                slices += 1;
+                match range.start {
+                    32 => Err("out of memory"),
+                    _ => Ok(()),
+                }
+            },
+        );
+        assert_eq!(result, Err("out of memory"));
+        assert_eq!(slices, 3);
+    }
+}
@@ -0,0 +1,182 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Trusted time the TA checks model licenses against. It starts from the host
+// clock, never runs backwards, and in between host reports follows the TEE
+// system time. The TA keeps the latest value in secure storage and passes
+// system times in milliseconds; tests pass their own.
+
+/// Furthest one host report moves a known trusted time forward, in seconds.
+pub const MAX_HOST_TIME_STEP: u64 = 7 * 24 * 60 * 60;
+
+/// Host reports this far behind the trusted time are worth a mention.
+const BEHIND_SLACK: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrustedClock {
+    // Trusted time (seconds since the Unix epoch) at system time `since`
+    at: u64,
+    since: u64,
+}
+
+/// What a host clock report did to the trusted time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HostTime {
+    Taken,
+    /// The host was `ahead` seconds ahead; the time moved by `step` only.
+    Capped {
+        ahead: u64,
+        step: u64,
+    },
+    /// The host clock is behind the trusted time and was ignored.
+    Behind,
+}
+
+impl TrustedClock {
+    /// Clock resuming from `stored`, the time an earlier session wrote back
+    /// (0 when none did), at system time `millis`.
+    pub const fn resume(stored: u64, millis: u64) -> Self {
+        Self {
+            at: stored,
+            since: millis,
+        }
+    }
+
+    /// Seconds since the Unix epoch, 0 when no time was ever reported.
+    pub fn now(&self, millis: u64) -> u64 {
+        if self.at == 0 {
+            return 0;
+        }
+        self.at + millis.saturating_sub(self.since) / 1000
+    }
+
+    /// Moves the trusted time forward towards the host clock `secs`, if it
+    /// is ahead, by at most `MAX_HOST_TIME_STEP` unless no time was known
+    /// yet.
+    pub fn observe_host_time(&mut self, secs: u64, millis: u64) -> HostTime {
+        let now = self.now(millis);
+        if secs <= now {
+            return match secs + BEHIND_SLACK < now {
+                true => HostTime::Behind,
+                false => HostTime::Taken,
+            };
+        }
+        // Nothing to compare the first report with
+        let step = match now {
+            0 => secs,
+            _ => (secs - now).min(MAX_HOST_TIME_STEP),
+        };
+        self.at = now + step;
+        self.since = millis;
+        match now + step < secs {
+            true => HostTime::Capped {
+                ahead: secs - now,
+                step,
+            },
+            false => HostTime::Taken,
+        }
+    }
+}
+
+/// Whether a model whose license `expires` (`ModelLicense::expires`) may no
+/// longer be used at trusted time `now`; the expiry second itself is past it.
+pub fn license_expired(expires: u64, now: u64) -> bool {
+    now >= expires
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `encrypt-model --expires 2026-01-01`
+    const EXPIRES: u64 = 1_767_225_600;
+    const DAY: u64 = 24 * 60 * 60;
+
+    #[test]
+    fn licenses_expire_at_their_second() {
+        let mut clock = TrustedClock::resume(0, 5_000);
+        assert_eq!(clock.observe_host_time(EXPIRES - 2, 5_000), HostTime::Taken);
+        assert!(!license_expired(EXPIRES, clock.now(5_000)));
+        assert!(!license_expired(EXPIRES, clock.now(6_999)));
+        // The system time carries the clock over the expiry
+        assert_eq!(clock.now(7_000), EXPIRES);
+        assert!(license_expired(EXPIRES, clock.now(7_000)));
+        assert!(license_expired(EXPIRES, clock.now(60_000)));
+    }
+
+    #[test]
+    fn setting_the_host_clock_back_revives_nothing() {
+        let mut clock = TrustedClock::resume(0, 0);
+        clock.observe_host_time(EXPIRES + 10, 0);
+        assert!(license_expired(EXPIRES, clock.now(0)));
+
+        // Within this session
+        assert_eq!(
+            clock.observe_host_time(EXPIRES - DAY, 1_000),
+            HostTime::Behind
+        );
+        assert_eq!(clock.now(1_000), EXPIRES + 11);
+        assert!(license_expired(EXPIRES, clock.now(1_000)));
+
+        // In a later one, resumed from what was stored, with the system
+        // time starting over
+        let stored = clock.now(2_000);
+        let mut clock = TrustedClock::resume(stored, 0);
+        assert_eq!(clock.observe_host_time(EXPIRES - DAY, 0), HostTime::Behind);
+        assert_eq!(clock.now(0), stored);
+        assert!(license_expired(EXPIRES, clock.now(0)));
+
+        // Slightly slow host clocks are not worth a warning
+        assert_eq!(clock.observe_host_time(stored - 30, 0), HostTime::Taken);
+        assert_eq!(clock.now(0), stored);
+    }
+
+    #[test]
+    fn far_future_reports_move_the_time_a_step_at_a_time() {
+        let mut clock = TrustedClock::resume(EXPIRES - 30 * DAY, 0);
+        let future = EXPIRES + 365 * DAY;
+        assert_eq!(
+            clock.observe_host_time(future, 0),
+            HostTime::Capped {
+                ahead: 395 * DAY,
+                step: MAX_HOST_TIME_STEP,
+            }
+        );
+        assert_eq!(clock.now(0), EXPIRES - 23 * DAY);
+        assert!(!license_expired(EXPIRES, clock.now(0)));
+
+        // A device that was switched off catches up over a few sessions
+        let now = EXPIRES - DAY;
+        let mut reports = 0;
+        while clock.now(0) < now {
+            clock.observe_host_time(now, 0);
+            reports += 1;
+        }
+        assert_eq!(reports, 4);
+        assert_eq!(clock.now(0), now);
+        assert!(!license_expired(EXPIRES, now));
+    }
+
+    #[test]
+    fn the_first_report_is_taken_as_is() {
+        let mut clock = TrustedClock::resume(0, 123_456);
+        assert_eq!(clock.now(999_999), 0);
+        assert_eq!(clock.observe_host_time(EXPIRES, 200_000), HostTime::Taken);
+        assert_eq!(clock.now(200_000), EXPIRES);
+        assert_eq!(clock.now(260_500), EXPIRES + 60);
+    }
+}
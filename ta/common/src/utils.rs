@@ -15,23 +15,120 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use alloc::vec::Vec;
+use core::sync::atomic::{compiler_fence, Ordering};
+
 #[cfg(feature = "optee-utee")]
-use optee_utee::{trace_println, ErrorKind, Parameter, Result};
+use optee_utee::{trace_println, ErrorKind, ParamType, Parameter};
+
+/// Why `copy_to_output` refused to write a result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputError {
+    /// The parameter is not an output (or inout) memref.
+    NotOutput,
+    /// The buffer is smaller than the result, which needs `required` bytes.
+    ShortBuffer { required: usize },
+}
+
+#[cfg(feature = "optee-utee")]
+impl From<OutputError> for optee_utee::Error {
+    fn from(err: OutputError) -> Self {
+        match err {
+            OutputError::NotOutput => ErrorKind::BadParameters.into(),
+            OutputError::ShortBuffer { .. } => ErrorKind::ShortBuffer.into(),
+        }
+    }
+}
 
 #[cfg(feature = "optee-utee")]
-pub fn copy_to_output(param: &mut Parameter, data: &[u8]) -> Result<()> {
-    let mut output = unsafe { param.as_memref()? };
+pub fn copy_to_output(param: &mut Parameter, data: &[u8]) -> Result<(), OutputError> {
+    // An input memref would take the result without the host ever seeing it
+    if !matches!(
+        param.param_type,
+        ParamType::MemrefOutput | ParamType::MemrefInout
+    ) {
+        trace_println!(
+            "expect an output memref, got type {}",
+            param.param_type as u32
+        );
+        return Err(OutputError::NotOutput);
+    }
+    let mut output = unsafe { param.as_memref() }.map_err(|_| OutputError::NotOutput)?;
 
     let buffer = output.buffer();
-    if buffer.len() < data.len() {
+    let written = fill_output(buffer, data).inspect_err(|_| {
         trace_println!(
             "expect output buffer size {}, got size {} instead",
             data.len(),
             buffer.len()
+        )
+    })?;
+    output.set_updated_size(written);
+    Ok(())
+}
+
+/// Copies `data` to the start of `buffer`, returning the size to report, or
+/// leaves `buffer` untouched when `data` doesn't fit.
+pub fn fill_output(buffer: &mut [u8], data: &[u8]) -> Result<usize, OutputError> {
+    let Some(prefix) = buffer.get_mut(..data.len()) else {
+        return Err(OutputError::ShortBuffer {
+            required: data.len(),
+        });
+    };
+    prefix.copy_from_slice(data);
+    Ok(data.len())
+}
+
+/// Overwrites `bytes` with zeros in a way the compiler can't elide.
+pub fn wipe(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { core::ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Wipes the whole allocation of `buf`, spare capacity included, and frees it.
+pub fn wipe_vec(buf: &mut Vec<u8>) {
+    let capacity = buf.capacity();
+    buf.resize(capacity, 0);
+    wipe(buf);
+    *buf = Vec::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_that_fit_exactly() {
+        let mut buffer = [0_u8; 4];
+        assert_eq!(fill_output(&mut buffer, &[1, 2, 3, 4]), Ok(4));
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        // Room to spare is left as it was
+        let mut buffer = [9_u8; 6];
+        assert_eq!(fill_output(&mut buffer, &[1, 2, 3, 4]), Ok(4));
+        assert_eq!(buffer, [1, 2, 3, 4, 9, 9]);
+    }
+
+    #[test]
+    fn one_byte_short_reports_the_required_size() {
+        let mut buffer = [9_u8; 3];
+        assert_eq!(
+            fill_output(&mut buffer, &[1, 2, 3, 4]),
+            Err(OutputError::ShortBuffer { required: 4 })
         );
-        return Err(ErrorKind::ShortBuffer.into());
+        assert_eq!(buffer, [9; 3]);
+    }
+
+    #[test]
+    fn zero_length_buffers() {
+        assert_eq!(fill_output(&mut [], &[]), Ok(0));
+        assert_eq!(
+            fill_output(&mut [], &[1]),
+            Err(OutputError::ShortBuffer { required: 1 })
+        );
+        let mut buffer = [9_u8; 2];
+        assert_eq!(fill_output(&mut buffer, &[]), Ok(0));
+        assert_eq!(buffer, [9; 2]);
     }
-    buffer[..data.len()].copy_from_slice(data);
-    output.set_updated_size(data.len());
-    Ok(())
 }
@@ -16,10 +16,105 @@
 // under the License.
 
 use optee_utee_build::{Error, RustEdition, TaConfig};
+use std::{env, fs, path::PathBuf};
+
+// Models whose estimated footprint exceeds this are refused at finalize;
+// override with TA_MODEL_MEMORY_BUDGET (bytes) at build time.
+const DEFAULT_MODEL_MEMORY_BUDGET: usize = 12 * 1024 * 1024;
+
+// TA heap (TA_DATA_SIZE), which finalize checks a load against before
+// decrypting; override with TA_HEAP_SIZE (bytes) at build time.
+const DEFAULT_HEAP_SIZE: u32 = 16 * 1024 * 1024;
+
+// Secure storage OP-TEE grants this TA, in bytes; the platform has no API to
+// query it. 0 means unknown, in which case storing is always attempted.
+const DEFAULT_STORAGE_QUOTA: usize = 0;
+
+// Earlier model versions kept in secure storage for rollback; override with
+// TA_MODEL_HISTORY at build time.
+const DEFAULT_MODEL_HISTORY: usize = 2;
+
+// Both TA UUIDs default to the uuid.txt files compiled into proto; set
+// INFERENCE_TA_UUID / KEY_MANAGER_TA_UUID to build another instance.
+fn uuid_from_env(var: &str, default: &'static str) -> String {
+    println!("cargo:rerun-if-env-changed={}", var);
+    let value = env::var(var).unwrap_or_else(|_| default.to_string());
+    match proto::parse_uuid(&value) {
+        Ok(uuid) => uuid.to_string(),
+        Err(err) => panic!("{}: {}", var, err),
+    }
+}
+
+// Enabled Cargo features as `FEATURES`, for the build manifest. Cargo passes
+// them as CARGO_FEATURE_<NAME> with `-` turned into `_`; every feature of
+// this crate is lowercase kebab-case, so that is undone here.
+fn features_source() -> String {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .filter(|name| name != "DEFAULT")
+        .map(|name| name.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+    format!("const FEATURES: &[&str] = &{:?};\n", features)
+}
 
 fn main() -> Result<(), Error> {
-    let config = TaConfig::new_default_with_cargo_env(proto::inference::UUID)?
-        .ta_data_size(16 * 1024 * 1024) // Increase heap for model import
+    println!("cargo:rerun-if-env-changed=TA_MODEL_MEMORY_BUDGET");
+    let budget = env::var("TA_MODEL_MEMORY_BUDGET")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MODEL_MEMORY_BUDGET);
+    println!("cargo:rerun-if-env-changed=TA_HEAP_SIZE");
+    let heap_size = env::var("TA_HEAP_SIZE")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_HEAP_SIZE);
+    println!("cargo:rerun-if-env-changed=TA_STORAGE_QUOTA");
+    let quota = env::var("TA_STORAGE_QUOTA")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_STORAGE_QUOTA);
+    println!("cargo:rerun-if-env-changed=TA_MODEL_HISTORY");
+    let history = env::var("TA_MODEL_HISTORY")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MODEL_HISTORY);
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(
+        out_dir.join("memory_budget.rs"),
+        format!("const MODEL_MEMORY_BUDGET: usize = {};\n", budget),
+    )
+    .unwrap();
+    fs::write(
+        out_dir.join("heap_size.rs"),
+        format!("const HEAP_SIZE: usize = {};\n", heap_size),
+    )
+    .unwrap();
+    fs::write(
+        out_dir.join("storage_quota.rs"),
+        format!("const STORAGE_QUOTA: usize = {};\n", quota),
+    )
+    .unwrap();
+    fs::write(
+        out_dir.join("model_history.rs"),
+        format!("const MODEL_HISTORY: usize = {};\n", history),
+    )
+    .unwrap();
+    fs::write(out_dir.join("build_features.rs"), features_source()).unwrap();
+
+    let ta_uuid = uuid_from_env("INFERENCE_TA_UUID", proto::inference::UUID);
+    let key_manager_uuid = uuid_from_env("KEY_MANAGER_TA_UUID", proto::key_manager::UUID);
+    fs::write(
+        out_dir.join("key_manager_uuid.rs"),
+        format!(
+            "const KEY_MANAGER_TA_UUID: &str = {:?};\n",
+            key_manager_uuid
+        ),
+    )
+    .unwrap();
+
+    let config = TaConfig::new_default_with_cargo_env(&ta_uuid)?
+        .ta_data_size(heap_size) // Increase heap for model import
         .ta_stack_size(8 * 1024 * 1024) // More stack for recorder/load
         .ta_framework_stack_size(1 * 1024 * 1024);
     optee_utee_build::build(RustEdition::Before2024, config)
@@ -0,0 +1,41 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// What this TA was compiled with, for `Command::GetBuildManifest`: the Cargo
+// features and the limits build.rs was configured with. Hosts check it to
+// refuse early what this build can't do instead of decoding a bare
+// NotSupported or BadParameters.
+
+use alloc::string::ToString;
+use proto::inference::BuildManifest;
+
+use crate::{MAX_PUSH_CHUNK_SIZE, MODEL_MEMORY_BUDGET, STORAGE_QUOTA};
+
+include!(concat!(env!("OUT_DIR"), "/build_features.rs"));
+include!(concat!(env!("OUT_DIR"), "/heap_size.rs"));
+include!(concat!(env!("OUT_DIR"), "/model_history.rs"));
+
+pub fn manifest() -> BuildManifest {
+    BuildManifest {
+        features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        heap_size: HEAP_SIZE,
+        model_memory_budget: MODEL_MEMORY_BUDGET,
+        storage_quota: STORAGE_QUOTA,
+        model_history: MODEL_HISTORY,
+        max_push_chunk_size: MAX_PUSH_CHUNK_SIZE as usize,
+    }
+}
@@ -0,0 +1,156 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Key manager operations done inside the inference TA (`builtin-crypto`
+// feature), for OP-TEE images that ship without the key manager TA. The key
+// lives in this TA's own secure storage and AES-256-CBC runs on the TEE
+// cipher, so models encrypted for either build decrypt with the other given
+// the same key; only where the key is kept differs.
+
+use alloc::{vec, vec::Vec};
+
+use optee_utee::{
+    AlgorithmId, Asymmetric, AttributeId, AttributeMemref, ErrorKind, OperationMode, Random,
+    Result, TransientObject, TransientObjectType,
+};
+use proto::key_manager::{parse_rsa_public_key, AES_BLOCK_SIZE, AES_KEY_SIZE};
+
+use crate::key_manager::{tee_aes_cbc, KeyManager};
+use crate::residency::wipe;
+use crate::secure_storage::{read_object, write_object};
+
+const KEY_OBJECT_ID: &[u8] = b"ta_aes_key";
+// No IPC to bound it; one scratch buffer of this size during decryption
+const DECRYPT_CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct BuiltinKeyManager {
+    // Loaded from storage on first use
+    key: Option<[u8; AES_KEY_SIZE]>,
+}
+
+impl BuiltinKeyManager {
+    fn key(&mut self) -> Result<&[u8; AES_KEY_SIZE]> {
+        if self.key.is_none() {
+            let mut stored = read_object(KEY_OBJECT_ID)?.ok_or(ErrorKind::ItemNotFound)?;
+            let key = <[u8; AES_KEY_SIZE]>::try_from(stored.as_slice()).ok();
+            wipe(&mut stored);
+            self.key = Some(key.ok_or(ErrorKind::CorruptObject)?);
+        }
+        self.key
+            .as_ref()
+            .ok_or_else(|| ErrorKind::ItemNotFound.into())
+    }
+
+    fn store_key(&mut self, key: &[u8; AES_KEY_SIZE]) -> Result<()> {
+        write_object(KEY_OBJECT_ID, key)?;
+        if let Some(old) = self.key.as_mut() {
+            wipe(old);
+        }
+        self.key = Some(*key);
+        Ok(())
+    }
+
+    fn cbc(
+        &mut self,
+        mode: OperationMode,
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize> {
+        tee_aes_cbc(self.key()?, mode, input, output, iv)
+    }
+}
+
+impl Drop for BuiltinKeyManager {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.as_mut() {
+            wipe(key);
+        }
+    }
+}
+
+impl KeyManager for BuiltinKeyManager {
+    fn open() -> Result<Self> {
+        Ok(Self { key: None })
+    }
+
+    fn has_aes_key(&mut self) -> Result<bool> {
+        match self.key() {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::ItemNotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn generate_aes_key(&mut self) -> Result<()> {
+        let mut key = [0u8; AES_KEY_SIZE];
+        Random::generate(&mut key);
+        let stored = self.store_key(&key);
+        wipe(&mut key);
+        stored
+    }
+
+    fn import_aes_key(&mut self, key: &[u8; AES_KEY_SIZE]) -> Result<()> {
+        self.store_key(key)
+    }
+
+    fn export_aes_key(&mut self) -> Result<[u8; AES_KEY_SIZE]> {
+        self.key().copied()
+    }
+
+    fn generate_iv(&mut self) -> Result<[u8; AES_BLOCK_SIZE]> {
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        Random::generate(&mut iv);
+        Ok(iv)
+    }
+
+    fn chunk_size(&self) -> usize {
+        DECRYPT_CHUNK_SIZE
+    }
+
+    fn encrypt_blocks(&mut self, input: &[u8], iv: &mut [u8; AES_BLOCK_SIZE]) -> Result<Vec<u8>> {
+        let mut output = vec![0u8; input.len()];
+        let size = self.cbc(OperationMode::Encrypt, input, &mut output, iv)?;
+        output.truncate(size);
+        Ok(output)
+    }
+
+    fn decrypt_blocks(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize> {
+        self.cbc(OperationMode::Decrypt, input, output, iv)
+    }
+
+    fn rsa_verify(&mut self, public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> Result<()> {
+        let key = parse_rsa_public_key(public_key).ok_or(ErrorKind::BadParameters)?;
+        let bits = key.bits();
+        let mut object = TransientObject::allocate(TransientObjectType::RsaPublicKey, bits)?;
+        let modulus = AttributeMemref::from_ref(AttributeId::RsaModulus, key.modulus);
+        let exponent = AttributeMemref::from_ref(AttributeId::RsaPublicExponent, key.exponent);
+        object.populate(&[modulus.into(), exponent.into()])?;
+        let rsa = Asymmetric::allocate(
+            AlgorithmId::RsassaPkcs1V15Sha256,
+            OperationMode::Verify,
+            bits,
+        )?;
+        rsa.set_key(&object)?;
+        rsa.verify_digest(&[], digest, signature)
+    }
+}
@@ -0,0 +1,112 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Crash reporting. A panic aborts the TA and the host only sees
+// TEEC_ERROR_TARGET_DEAD, so the panic handler first writes where and why the
+// TA panicked to secure storage (`ta_last_crash`); the next session returns it
+// through Command::GetLastCrash. The report is bounded by
+// `MAX_CRASH_REPORT_SIZE` and only the latest panic is kept.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use optee_utee::Time;
+use proto::inference::{CrashReport, MAX_CRASH_REPORT_SIZE};
+
+use crate::secure_storage;
+
+// Small enough that the JSON stays within MAX_CRASH_REPORT_SIZE even if
+// every byte has to be escaped as \u00XX
+const MAX_MESSAGE_SIZE: usize = 512;
+const MAX_LOCATION_SIZE: usize = 128;
+const _: () = assert!((MAX_MESSAGE_SIZE + MAX_LOCATION_SIZE) * 6 + 128 <= MAX_CRASH_REPORT_SIZE);
+
+// Set by the first panic; a panic while recording it skips straight to the
+// abort
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// Formats into a String, dropping whatever doesn't fit in `limit` bytes
+struct Truncating {
+    buf: String,
+    limit: usize,
+}
+
+impl Truncating {
+    fn new(limit: usize) -> Self {
+        Self {
+            buf: String::new(),
+            limit,
+        }
+    }
+}
+
+impl Write for Truncating {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.buf.len() + c.len_utf8() > self.limit {
+                return Err(fmt::Error);
+            }
+            self.buf.push(c);
+        }
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    if !PANICKING.swap(true, Ordering::SeqCst) {
+        record(info);
+    }
+    unsafe { optee_utee_sys::TEE_Panic(0) };
+    loop {}
+}
+
+fn record(info: &PanicInfo) {
+    let mut message = Truncating::new(MAX_MESSAGE_SIZE);
+    // A message cut short is still worth keeping
+    let _ = write!(message, "{}", info.message());
+    let mut location = Truncating::new(MAX_LOCATION_SIZE);
+    if let Some(at) = info.location() {
+        let _ = write!(location, "{}:{}:{}", at.file(), at.line(), at.column());
+    }
+    let mut time = Time::new();
+    time.ree_time();
+    let report = CrashReport {
+        location: location.buf,
+        message: message.buf,
+        time: time.seconds as u64,
+    };
+    trace_println!("[!] TA panicked: {}", report);
+    let stored = serde_json::to_vec(&report)
+        .map_err(|_| ())
+        .and_then(|bytes| secure_storage::store_crash(&bytes).map_err(|_| ()));
+    if stored.is_err() {
+        trace_println!("[!] Failed to store the crash report");
+    }
+}
+
+/// The last stored crash report, deleting it afterwards when `clear` is set.
+pub fn last_crash(clear: bool) -> optee_utee::Result<Option<Vec<u8>>> {
+    let report = secure_storage::load_crash()?;
+    if clear && report.is_some() {
+        secure_storage::delete_crash()?;
+    }
+    Ok(report)
+}
@@ -0,0 +1,91 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+// Short diagnostics for failures the return code alone doesn't explain:
+// the recorder's message for a refused model, the object and platform code
+// of a failed storage operation. Code that fails records one with `set`;
+// `invoke_command` then moves it into the context of the session that ran
+// the command (`take`) and, when the command failed, writes it to the
+// buffer the host passed for it (see `param_types::detail_param`). Details
+// say what failed, never the data involved, and are cleared before every
+// command.
+//
+// `set` runs deep in the storage and import helpers, which don't see the
+// session, so the detail is recorded in `RUNNING` while its command runs.
+// OP-TEE doesn't run the entry points of a TA instance concurrently (the TA
+// doesn't set TA_FLAG_CONCURRENT), and the slot is cleared before each
+// command and emptied into the session after it, so a session never gets
+// another one's detail.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use optee_utee::Parameter;
+use proto::inference::{truncate_error_detail, write_error_detail, MAX_ERROR_DETAIL_SIZE};
+use spin::Mutex;
+
+static RUNNING: Mutex<Option<String>> = Mutex::new(None);
+
+/// The detail of a session's last command.
+#[derive(Default)]
+pub struct Detail(Option<String>);
+
+impl Detail {
+    /// Writes the detail, if any, to `param`, cut to what its buffer holds
+    /// after the tag.
+    pub fn write(&mut self, param: &mut Parameter) {
+        let Some(message) = self.0.take() else {
+            return;
+        };
+        let Ok(mut memref) = (unsafe { param.as_memref() }) else {
+            return;
+        };
+        let size = write_error_detail(&message, memref.buffer());
+        if size > 0 {
+            memref.set_updated_size(size);
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+/// Clears what an earlier command left, before the next one runs.
+pub fn clear() {
+    RUNNING.lock().take();
+}
+
+/// Records the detail of the running command's failure. The first one
+/// recorded is kept, as it is closest to the cause.
+pub fn set(args: fmt::Arguments) {
+    let mut detail = RUNNING.lock();
+    if detail.is_some() {
+        return;
+    }
+    let mut message = String::new();
+    if message.write_fmt(args).is_err() {
+        return;
+    }
+    let kept = truncate_error_detail(&message, MAX_ERROR_DETAIL_SIZE).len();
+    message.truncate(kept);
+    *detail = Some(message);
+}
+
+/// Hands the detail the command that just ran recorded to its session.
+pub fn take() -> Detail {
+    Detail(RUNNING.lock().take())
+}
@@ -0,0 +1,89 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Heap usage logging around model load, backed by the malloc statistics of
+// OP-TEE's libutils. Those are only compiled in with CFG_WITH_STATS=y, so the
+// helpers are no-ops unless the `heap-stats` feature is enabled. The
+// `heap-accounting` feature reads the same statistics.
+
+#[cfg(any(feature = "heap-stats", feature = "heap-accounting"))]
+mod malloc {
+    const ALLOCATOR_DESC_LENGTH: usize = 32;
+
+    // Mirrors `struct pta_stats_alloc` from OP-TEE
+    #[repr(C)]
+    #[derive(Default)]
+    pub struct MallocStats {
+        desc: [u8; ALLOCATOR_DESC_LENGTH],
+        pub allocated: u32,
+        pub max_allocated: u32,
+        pub size: u32,
+        num_alloc_fail: u32,
+        biggest_alloc_fail: u32,
+        biggest_alloc_fail_used: u32,
+    }
+
+    extern "C" {
+        fn malloc_get_stats(stats: *mut MallocStats);
+        fn malloc_reset_stats();
+    }
+
+    /// Current statistics of the TA heap.
+    pub fn stats() -> MallocStats {
+        let mut stats = MallocStats::default();
+        unsafe { malloc_get_stats(&mut stats) };
+        stats
+    }
+
+    pub fn reset_stats() {
+        unsafe { malloc_reset_stats() }
+    }
+}
+
+#[cfg(feature = "heap-accounting")]
+pub use malloc::stats;
+
+#[cfg(feature = "heap-stats")]
+mod imp {
+    use super::malloc;
+
+    /// Restarts high-water-mark tracking from the current usage.
+    pub fn reset() {
+        malloc::reset_stats()
+    }
+
+    /// Logs current and peak heap usage after `stage`.
+    pub fn log(stage: &str) {
+        let stats = malloc::stats();
+        trace_println!(
+            "[+] Heap after {}: {} bytes in use, high-water mark {} of {} bytes",
+            stage,
+            stats.allocated,
+            stats.max_allocated,
+            stats.size
+        );
+    }
+}
+
+#[cfg(not(feature = "heap-stats"))]
+mod imp {
+    pub fn reset() {}
+
+    pub fn log(_stage: &str) {}
+}
+
+pub use imp::*;
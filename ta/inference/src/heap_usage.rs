@@ -0,0 +1,75 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Heap available to a model load, which finalize and stage check the load
+// against before decrypting (see `common::check_heap`). With the
+// `heap-accounting` feature it is what the malloc statistics of OP-TEE's
+// libutils leave of the heap, and the stats command reports current and peak
+// usage from them; those need OP-TEE built with CFG_WITH_STATS=y. Without it
+// the largest block the heap can still allocate is probed.
+
+use proto::inference::HeapUsage;
+
+#[cfg(feature = "heap-accounting")]
+mod imp {
+    use super::HeapUsage;
+    use crate::heap_stats;
+
+    /// Heap bytes not in use.
+    pub fn available() -> usize {
+        let stats = heap_stats::stats();
+        common::heap_available(stats.size as usize, stats.allocated as usize)
+    }
+
+    /// Heap usage for the stats command. The peak restarts with each load
+    /// when `heap-stats` is enabled too.
+    pub fn usage() -> Option<HeapUsage> {
+        let stats = heap_stats::stats();
+        Some(HeapUsage {
+            current: stats.allocated as usize,
+            peak: stats.max_allocated as usize,
+            size: stats.size as usize,
+        })
+    }
+}
+
+#[cfg(not(feature = "heap-accounting"))]
+mod imp {
+    use super::HeapUsage;
+    use alloc::vec::Vec;
+
+    include!(concat!(env!("OUT_DIR"), "/heap_size.rs"));
+
+    /// Largest block the heap can still allocate, up to the configured heap
+    /// size. A load's buffers are each about the size of the model, so this
+    /// is what bounds it, rather than the free bytes in total.
+    pub fn available() -> usize {
+        common::largest_allocation(HEAP_SIZE, |size| {
+            let mut probe = Vec::<u8>::new();
+            let allocated = probe.try_reserve_exact(size).is_ok();
+            // Keeps the allocation from being optimized out
+            core::hint::black_box(&mut probe);
+            allocated
+        })
+    }
+
+    pub fn usage() -> Option<HeapUsage> {
+        None
+    }
+}
+
+pub use imp::*;
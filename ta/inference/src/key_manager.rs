@@ -1,30 +1,56 @@
 use alloc::{vec, vec::Vec};
-use core::cmp;
 
 use optee_utee::{
-    ErrorKind, ParamIndex, Result, TaSession, TaSessionBuilder, TeeParams, Uuid,
+    AlgorithmId, AttributeId, AttributeMemref, Cipher, ErrorKind, OperationMode, Random, Result,
+    TransientObject, TransientObjectType,
 };
-use proto::key_manager::{self, Command, AES_BLOCK_SIZE, AES_KEY_SIZE};
+#[cfg(not(feature = "builtin-crypto"))]
+use optee_utee::{ParamIndex, TaSession, TaSessionBuilder, TeeParams, Uuid};
+use proto::framing;
+#[cfg(not(feature = "builtin-crypto"))]
+use proto::key_manager::{self, Command, KM_CAP_AES_MULTI, KM_CAP_RSA_VERIFY};
+use proto::key_manager::{AES_BLOCK_SIZE, AES_KEY_SIZE};
+#[cfg(not(feature = "builtin-crypto"))]
 use proto::CHUNK_SIZE;
 
-fn with_client<F, R>(f: F) -> Result<R>
-where
-    F: FnOnce(&mut KeyManagerClient) -> Result<R>,
-{
-    let mut client = KeyManagerClient::new()?;
-    f(&mut client)
-}
+/// Key operations behind model encryption. The default build delegates them
+/// to the key manager TA (`KeyManagerClient`); with the `builtin-crypto`
+/// feature the inference TA does them itself (`BuiltinKeyManager`), for
+/// images that ship without the key manager. Framing and the in-place
+/// decryption are shared, so both produce the same model format.
+pub trait KeyManager: Sized {
+    fn open() -> Result<Self>;
 
-struct KeyManagerClient {
-    session: TaSession,
-}
+    fn has_aes_key(&mut self) -> Result<bool>;
 
-impl KeyManagerClient {
-    fn new() -> Result<Self> {
-        let uuid = Uuid::parse_str(key_manager::UUID.trim())?;
-        let session = TaSessionBuilder::new(uuid).build()?;
-        Ok(Self { session })
-    }
+    fn generate_aes_key(&mut self) -> Result<()>;
+
+    fn import_aes_key(&mut self, key: &[u8; AES_KEY_SIZE]) -> Result<()>;
+
+    fn export_aes_key(&mut self) -> Result<[u8; AES_KEY_SIZE]>;
+
+    fn generate_iv(&mut self) -> Result<[u8; AES_BLOCK_SIZE]>;
+
+    /// Bytes `decrypt_blocks` takes at a time, whole AES blocks.
+    fn chunk_size(&self) -> usize;
+
+    /// CBC-encrypts `input` (whole blocks) starting from `iv`, which is left
+    /// at the last ciphertext block.
+    fn encrypt_blocks(&mut self, input: &[u8], iv: &mut [u8; AES_BLOCK_SIZE]) -> Result<Vec<u8>>;
+
+    /// CBC-decrypts one chunk of at most `chunk_size` bytes into `output`,
+    /// returning the bytes written and leaving `iv` at the last ciphertext
+    /// block.
+    fn decrypt_blocks(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize>;
+
+    /// Checks an RSASSA-PKCS1-v1_5 signature of the SHA-256 `digest` with
+    /// `public_key` (DER), failing with SignatureInvalid on a mismatch.
+    fn rsa_verify(&mut self, public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> Result<()>;
 
     fn ensure_aes_key(&mut self) -> Result<()> {
         if !self.has_aes_key()? {
@@ -40,20 +66,182 @@ impl KeyManagerClient {
         Ok(())
     }
 
+    fn encrypt_data(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.ensure_aes_key()?;
+        let framed = framing::frame(data).ok_or(ErrorKind::BadParameters)?;
+
+        let mut iv = self.generate_iv()?;
+        let mut result = Vec::with_capacity(AES_BLOCK_SIZE + framed.len());
+        result.extend_from_slice(&iv);
+        result.extend_from_slice(&self.encrypt_blocks(&framed, &mut iv)?);
+        Ok(result)
+    }
+
+    /// Decrypts `IV || ciphertext` in place, leaving only the framed
+    /// plaintext in `data`. Each chunk is decrypted into one scratch buffer and
+    /// copied back a block earlier (over the IV), so no ciphertext-sized copy
+    /// is ever allocated. This is also why decryption stays per chunk even
+    /// when the key manager could do it in a single call.
+    fn decrypt_in_place(&mut self, data: &mut Vec<u8>) -> Result<()> {
+        self.require_aes_key()?;
+        let chunk_size = self.chunk_size();
+        common::cbc_decrypt_in_place(data, chunk_size, |input, output, iv| {
+            self.decrypt_blocks(input, output, iv)
+        })
+    }
+}
+
+/// AES-256-CBC without padding under `key` on the TEE cipher, over whole
+/// blocks, leaving `iv` at the last ciphertext block.
+pub fn tee_aes_cbc(
+    key: &[u8; AES_KEY_SIZE],
+    mode: OperationMode,
+    input: &[u8],
+    output: &mut [u8],
+    iv: &mut [u8; AES_BLOCK_SIZE],
+) -> Result<usize> {
+    if input.is_empty() || input.len() % AES_BLOCK_SIZE != 0 || output.len() < input.len() {
+        return Err(ErrorKind::BadParameters.into());
+    }
+    let key_bits = AES_KEY_SIZE * 8;
+    // The next IV is the last ciphertext block, the input when decrypting
+    let decrypting = matches!(mode, OperationMode::Decrypt);
+    let cipher = Cipher::allocate(AlgorithmId::AesCbcNopad, mode, key_bits)?;
+    let mut object = TransientObject::allocate(TransientObjectType::Aes, key_bits)?;
+    let secret = AttributeMemref::from_ref(AttributeId::SecretValue, key);
+    object.populate(&[secret.into()])?;
+    cipher.set_key(&object)?;
+    cipher.init(iv);
+    let size = cipher.do_final(input, &mut output[..input.len()])?;
+    let ciphertext = if decrypting { input } else { &output[..size] };
+    iv.copy_from_slice(&ciphertext[ciphertext.len() - AES_BLOCK_SIZE..]);
+    Ok(size)
+}
+
+// A key held only in memory, to encrypt and check a model under a key before
+// it replaces the stored one. Only the cipher operations are available.
+struct TransientKey([u8; AES_KEY_SIZE]);
+
+// Bytes per decryption call; there is no IPC to bound it
+const TRANSIENT_CHUNK_SIZE: usize = 64 * 1024;
+
+impl Drop for TransientKey {
+    fn drop(&mut self) {
+        crate::residency::wipe(&mut self.0);
+    }
+}
+
+impl KeyManager for TransientKey {
+    fn open() -> Result<Self> {
+        Err(ErrorKind::NotSupported.into())
+    }
+
+    fn has_aes_key(&mut self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn generate_aes_key(&mut self) -> Result<()> {
+        Err(ErrorKind::NotSupported.into())
+    }
+
+    fn import_aes_key(&mut self, _key: &[u8; AES_KEY_SIZE]) -> Result<()> {
+        Err(ErrorKind::NotSupported.into())
+    }
+
+    fn export_aes_key(&mut self) -> Result<[u8; AES_KEY_SIZE]> {
+        Err(ErrorKind::NotSupported.into())
+    }
+
+    fn generate_iv(&mut self) -> Result<[u8; AES_BLOCK_SIZE]> {
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        Random::generate(&mut iv);
+        Ok(iv)
+    }
+
+    fn chunk_size(&self) -> usize {
+        TRANSIENT_CHUNK_SIZE
+    }
+
+    fn encrypt_blocks(&mut self, input: &[u8], iv: &mut [u8; AES_BLOCK_SIZE]) -> Result<Vec<u8>> {
+        let mut output = vec![0u8; input.len()];
+        let size = tee_aes_cbc(&self.0, OperationMode::Encrypt, input, &mut output, iv)?;
+        output.truncate(size);
+        Ok(output)
+    }
+
+    fn decrypt_blocks(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        iv: &mut [u8; AES_BLOCK_SIZE],
+    ) -> Result<usize> {
+        tee_aes_cbc(&self.0, OperationMode::Decrypt, input, output, iv)
+    }
+
+    fn rsa_verify(
+        &mut self,
+        _public_key: &[u8],
+        _digest: &[u8; 32],
+        _signature: &[u8],
+    ) -> Result<()> {
+        Err(ErrorKind::NotSupported.into())
+    }
+}
+
+#[cfg(not(feature = "builtin-crypto"))]
+type Backend = KeyManagerClient;
+#[cfg(feature = "builtin-crypto")]
+type Backend = crate::builtin_crypto::BuiltinKeyManager;
+
+fn with_client<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce(&mut Backend) -> Result<R>,
+{
+    let mut client = Backend::open()?;
+    f(&mut client)
+}
+
+// Key manager TA to open sessions with; KEY_MANAGER_TA_UUID at build time
+// overrides `proto::key_manager::UUID`, see build.rs
+#[cfg(not(feature = "builtin-crypto"))]
+include!(concat!(env!("OUT_DIR"), "/key_manager_uuid.rs"));
+
+#[cfg(not(feature = "builtin-crypto"))]
+struct KeyManagerClient {
+    session: TaSession,
+    // `KM_CAP_*` bits, learned from the `HasAesKey` reply
+    capabilities: u32,
+    // Bytes per chunk command, whole AES blocks: what the key manager
+    // advertises with `KM_CAP_CHUNK_SIZE`, else `CHUNK_SIZE`
+    chunk_size: usize,
+}
+
+#[cfg(not(feature = "builtin-crypto"))]
+impl KeyManager for KeyManagerClient {
+    fn open() -> Result<Self> {
+        let uuid = Uuid::parse_str(KEY_MANAGER_TA_UUID)?;
+        let session = TaSessionBuilder::new(uuid).build()?;
+        Ok(Self {
+            session,
+            capabilities: 0,
+            chunk_size: CHUNK_SIZE,
+        })
+    }
+
     fn generate_aes_key(&mut self) -> Result<()> {
         let mut params = TeeParams::new();
         self.session
             .invoke_command(Command::GenerateAesKey as u32, &mut params)
     }
 
-    pub fn import_aes_key(&mut self, key: &[u8; AES_KEY_SIZE]) -> Result<()> {
-        let key_buf = *key;
-        let mut params = TeeParams::new().with_memref_in(ParamIndex::Arg0, &key_buf);
+    fn import_aes_key(&mut self, key: &[u8; AES_KEY_SIZE]) -> Result<()> {
+        // Passed by reference so no further copy of the key is left behind
+        let mut params = TeeParams::new().with_memref_in(ParamIndex::Arg0, key);
         self.session
             .invoke_command(Command::ImportAesKey as u32, &mut params)
     }
 
-    pub fn export_aes_key(&mut self) -> Result<[u8; AES_KEY_SIZE]> {
+    fn export_aes_key(&mut self) -> Result<[u8; AES_KEY_SIZE]> {
         let mut buffer = [0u8; AES_KEY_SIZE];
         let mut params = TeeParams::new().with_memref_out(ParamIndex::Arg0, &mut buffer);
         self.session
@@ -68,115 +256,102 @@ impl KeyManagerClient {
     }
 
     fn has_aes_key(&mut self) -> Result<bool> {
-        let mut params = TeeParams::new().with_value_out(ParamIndex::Arg0, 0, 0);
-        self.session
-            .invoke_command(Command::HasAesKey as u32, &mut params)?;
+        let mut params = TeeParams::new()
+            .with_value_out(ParamIndex::Arg0, 0, 0)
+            .with_value_out(ParamIndex::Arg1, 0, 0);
+        match self
+            .session
+            .invoke_command(Command::HasAesKey as u32, &mut params)
+        {
+            Ok(()) => {}
+            // A peer checking param types strictly rejects the capability slot
+            Err(err) if err.kind() == ErrorKind::BadParameters => {
+                params = TeeParams::new().with_value_out(ParamIndex::Arg0, 0, 0);
+                self.session
+                    .invoke_command(Command::HasAesKey as u32, &mut params)?;
+            }
+            Err(err) => return Err(err),
+        }
         let (a, _) = params[ParamIndex::Arg0]
             .output_value()
             .ok_or(ErrorKind::BadParameters)?;
+        let (capabilities, chunk_size) = params[ParamIndex::Arg1].output_value().unwrap_or((0, 0));
+        self.capabilities = capabilities;
+        self.chunk_size = key_manager::chunk_size(capabilities, chunk_size);
         Ok(a != 0)
     }
 
-    pub fn encrypt_data(&mut self, data: &[u8]) -> Result<Vec<u8>> {
-        self.ensure_aes_key()?;
-        let block_size = AES_BLOCK_SIZE;
-
-        let mut data_with_len = Vec::with_capacity(4 + data.len());
-        data_with_len.extend_from_slice(&(data.len() as u32).to_le_bytes());
-        data_with_len.extend_from_slice(data);
-
-        let padded_len = ((data_with_len.len() + block_size - 1) / block_size) * block_size;
-        data_with_len.resize(padded_len, 0);
-
-        let mut iv = self.generate_iv()?;
-        let mut result = Vec::with_capacity(block_size + padded_len);
-        result.extend_from_slice(&iv);
-
-        let chunk_size = cmp::max(CHUNK_SIZE, block_size);
-        let mut offset = 0;
-        while offset < data_with_len.len() {
-            let end = cmp::min(offset + chunk_size, data_with_len.len());
-            let chunk = &data_with_len[offset..end];
-            let mut encrypted_chunk = vec![0u8; chunk.len()];
-            let size = self.encrypt_chunk(chunk, &mut encrypted_chunk, &mut iv)?;
-            result.extend_from_slice(&encrypted_chunk[..size]);
-            offset = end;
-        }
-        Ok(result)
+    fn chunk_size(&self) -> usize {
+        self.chunk_size
     }
 
-    pub fn decrypt_data(&mut self, encrypted: &[u8]) -> Result<Vec<u8>> {
-        self.require_aes_key()?;
-        if encrypted.len() < AES_BLOCK_SIZE * 2 {
-            return Err(ErrorKind::BadParameters.into());
-        }
-        let mut iv = [0u8; AES_BLOCK_SIZE];
-        iv.copy_from_slice(&encrypted[..AES_BLOCK_SIZE]);
-        let ciphertext = &encrypted[AES_BLOCK_SIZE..];
-        if ciphertext.len() % AES_BLOCK_SIZE != 0 {
-            return Err(ErrorKind::BadParameters.into());
-        }
-
-        let chunk_size = cmp::max(CHUNK_SIZE, AES_BLOCK_SIZE);
-        let mut offset = 0;
-        let mut decrypted = Vec::with_capacity(ciphertext.len());
-        while offset < ciphertext.len() {
-            let end = cmp::min(offset + chunk_size, ciphertext.len());
-            let chunk = &ciphertext[offset..end];
-            let mut plain_chunk = vec![0u8; chunk.len()];
-            let size = self.decrypt_chunk(chunk, &mut plain_chunk, &mut iv)?;
-            decrypted.extend_from_slice(&plain_chunk[..size]);
-            offset = end;
-        }
-        if decrypted.len() < 4 {
-            return Err(ErrorKind::BadParameters.into());
-        }
-        let original_len = u32::from_le_bytes([
-            decrypted[0],
-            decrypted[1],
-            decrypted[2],
-            decrypted[3],
-        ]) as usize;
-        if original_len + 4 > decrypted.len() {
-            return Err(ErrorKind::BadParameters.into());
-        }
-        Ok(decrypted[4..4 + original_len].to_vec())
+    // One invocation covers the whole buffer when the key manager supports
+    // the multi-block command; otherwise it goes `chunk_size` at a time. Both
+    // paths produce the same bytes, only the number of calls differs.
+    fn encrypt_blocks(&mut self, input: &[u8], iv: &mut [u8; AES_BLOCK_SIZE]) -> Result<Vec<u8>> {
+        let (command, chunk_size) = if self.capabilities & KM_CAP_AES_MULTI != 0 {
+            (Command::EncryptAesMulti, input.len())
+        } else {
+            (Command::EncryptAesChunk, self.chunk_size)
+        };
+        let (output, invocations) =
+            common::cbc_in_chunks(input, chunk_size, iv, |chunk, out, iv| {
+                self.cbc_chunk(command, chunk, out, iv)
+            })?;
+        debug_println!(
+            "[+] {:?}: {} bytes in {} key manager invocation(s)",
+            command,
+            input.len(),
+            invocations
+        );
+        Ok(output)
     }
 
-    fn encrypt_chunk(
+    fn decrypt_blocks(
         &mut self,
         input: &[u8],
         output: &mut [u8],
         iv: &mut [u8; AES_BLOCK_SIZE],
     ) -> Result<usize> {
-        if input.len() % AES_BLOCK_SIZE != 0 {
-            return Err(ErrorKind::BadParameters.into());
+        self.cbc_chunk(Command::DecryptAesChunk, input, output, iv)
+    }
+
+    fn rsa_verify(&mut self, public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> Result<()> {
+        // Key managers without the command would generate a new AES key
+        // instead, so its capability bit is checked on every call
+        self.has_aes_key()?;
+        if self.capabilities & KM_CAP_RSA_VERIFY == 0 {
+            trace_println!("[!] The key manager can't verify RSA signatures");
+            return Err(ErrorKind::NotSupported.into());
         }
-        let mut iv_param = *iv;
         let mut params = TeeParams::new()
-            .with_memref_in(ParamIndex::Arg0, input)
-            .with_memref_out(ParamIndex::Arg1, &mut output[..input.len()])
-            .with_memref_inout(ParamIndex::Arg2, &mut iv_param);
+            .with_memref_in(ParamIndex::Arg0, public_key)
+            .with_memref_in(ParamIndex::Arg1, digest)
+            .with_memref_in(ParamIndex::Arg2, signature);
         self.session
-            .invoke_command(Command::EncryptAesChunk as u32, &mut params)?;
-        let written = params[ParamIndex::Arg1]
-            .written_slice()
-            .ok_or(ErrorKind::BadParameters)?
-            .len();
-        let iv_slice = params[ParamIndex::Arg2]
+            .invoke_command(Command::RsaVerify as u32, &mut params)
+    }
+
+    fn generate_iv(&mut self) -> Result<[u8; AES_BLOCK_SIZE]> {
+        let mut buffer = [0u8; AES_BLOCK_SIZE];
+        let mut params = TeeParams::new().with_memref_inout(ParamIndex::Arg0, &mut buffer);
+        self.session
+            .invoke_command(Command::GenerateRandom as u32, &mut params)?;
+        let written = params[ParamIndex::Arg0]
             .written_slice()
             .ok_or(ErrorKind::BadParameters)?;
-        if iv_slice.len() != AES_BLOCK_SIZE {
+        if written.len() != AES_BLOCK_SIZE {
             return Err(ErrorKind::BadParameters.into());
         }
-        let mut next_iv = [0u8; AES_BLOCK_SIZE];
-        next_iv.copy_from_slice(iv_slice);
-        iv.copy_from_slice(&next_iv);
-        Ok(written)
+        Ok(buffer)
     }
+}
 
-    fn decrypt_chunk(
+#[cfg(not(feature = "builtin-crypto"))]
+impl KeyManagerClient {
+    fn cbc_chunk(
         &mut self,
+        command: Command,
         input: &[u8],
         output: &mut [u8],
         iv: &mut [u8; AES_BLOCK_SIZE],
@@ -189,8 +364,7 @@ impl KeyManagerClient {
             .with_memref_in(ParamIndex::Arg0, input)
             .with_memref_out(ParamIndex::Arg1, &mut output[..input.len()])
             .with_memref_inout(ParamIndex::Arg2, &mut iv_param);
-        self.session
-            .invoke_command(Command::DecryptAesChunk as u32, &mut params)?;
+        self.session.invoke_command(command as u32, &mut params)?;
         let written = params[ParamIndex::Arg1]
             .written_slice()
             .ok_or(ErrorKind::BadParameters)?
@@ -206,20 +380,6 @@ impl KeyManagerClient {
         iv.copy_from_slice(&next_iv);
         Ok(written)
     }
-
-    fn generate_iv(&mut self) -> Result<[u8; AES_BLOCK_SIZE]> {
-        let mut buffer = [0u8; AES_BLOCK_SIZE];
-        let mut params = TeeParams::new().with_memref_inout(ParamIndex::Arg0, &mut buffer);
-        self.session
-            .invoke_command(Command::GenerateRandom as u32, &mut params)?;
-        let written = params[ParamIndex::Arg0]
-            .written_slice()
-            .ok_or(ErrorKind::BadParameters)?;
-        if written.len() != AES_BLOCK_SIZE {
-            return Err(ErrorKind::BadParameters.into());
-        }
-        Ok(buffer)
-    }
 }
 
 pub fn ensure_aes_key() -> Result<()> {
@@ -230,6 +390,10 @@ pub fn require_aes_key() -> Result<()> {
     with_client(|client| client.require_aes_key())
 }
 
+pub fn has_aes_key() -> Result<bool> {
+    with_client(|client| client.has_aes_key())
+}
+
 pub fn import_aes_key(key: &[u8; AES_KEY_SIZE]) -> Result<()> {
     with_client(|client| client.import_aes_key(key))
 }
@@ -242,6 +406,21 @@ pub fn encrypt_model_data(data: &[u8]) -> Result<Vec<u8>> {
     with_client(|client| client.encrypt_data(data))
 }
 
-pub fn decrypt_model_data(data: &[u8]) -> Result<Vec<u8>> {
-    with_client(|client| client.decrypt_data(data))
+pub fn decrypt_model_in_place(data: &mut Vec<u8>) -> Result<()> {
+    with_client(|client| client.decrypt_in_place(data))
+}
+
+/// [`encrypt_model_data`] under `key` instead of the stored key, which is
+/// left alone.
+pub fn encrypt_model_data_with(key: &[u8; AES_KEY_SIZE], data: &[u8]) -> Result<Vec<u8>> {
+    TransientKey(*key).encrypt_data(data)
+}
+
+/// [`decrypt_model_in_place`] under `key` instead of the stored key.
+pub fn decrypt_model_in_place_with(key: &[u8; AES_KEY_SIZE], data: &mut Vec<u8>) -> Result<()> {
+    TransientKey(*key).decrypt_in_place(data)
+}
+
+pub fn rsa_verify(public_key: &[u8], digest: &[u8; 32], signature: &[u8]) -> Result<()> {
+    with_client(|client| client.rsa_verify(public_key, digest, signature))
 }
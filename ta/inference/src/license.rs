@@ -0,0 +1,161 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Model licenses. A model whose metadata carries a `ModelLicense` answers no
+// inferences once the trusted time reaches its expiry.
+//
+// The TA has no wall clock worth trusting, so the trusted time
+// (`common::TrustedClock`) starts from the host clock reported at
+// open_session but never runs backwards: the latest time seen is kept in
+// secure storage (`ta_clock`) and, between reports, the TEE system time
+// (monotonic while the TA is loaded) advances it. Setting the host clock back
+// therefore doesn't revive an expired model.
+//
+// Once a time was stored, one host report moves the trusted time forward by
+// at most `MAX_HOST_TIME_STEP`; a report further ahead advances it by that
+// much and the rest waits for later sessions. A host clock set to a far
+// future date, which the trusted time could never come back from, only
+// creeps forward by a week per session, and a device that was switched off
+// for a while catches up within a few sessions.
+
+use common::{license_expired, HostTime, TrustedClock};
+use optee_utee::{Error, Result, Time};
+use proto::inference::{LicenseStatus, ModelLicense, ERROR_LICENSE_EXPIRED, MODEL_SLOTS};
+use spin::Mutex;
+
+use crate::secure_storage;
+
+struct Clock {
+    trusted: TrustedClock,
+    // Last value written to secure storage
+    stored: u64,
+}
+
+impl Clock {
+    fn now(&self) -> u64 {
+        self.trusted.now(system_millis())
+    }
+
+    fn persist(&mut self) {
+        let now = self.now();
+        if now <= self.stored {
+            return;
+        }
+        match secure_storage::store_clock(now) {
+            Ok(()) => self.stored = now,
+            Err(err) => trace_println!("[!] Failed to store the trusted time: {:?}", err),
+        }
+    }
+}
+
+static CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+static LICENSES: Mutex<[Option<ModelLicense>; MODEL_SLOTS]> =
+    Mutex::new([const { None }; MODEL_SLOTS]);
+
+fn system_millis() -> u64 {
+    let mut time = Time::new();
+    time.system_time();
+    time.seconds as u64 * 1000 + time.millis as u64
+}
+
+// The clock, resumed from the time stored by an earlier session on first use
+fn clock(clock: &mut Option<Clock>) -> &mut Clock {
+    clock.get_or_insert_with(|| {
+        let stored = secure_storage::load_clock()
+            .unwrap_or_else(|err| {
+                trace_println!("[!] Failed to read the trusted time: {:?}", err);
+                None
+            })
+            .unwrap_or(0);
+        Clock {
+            trusted: TrustedClock::resume(stored, system_millis()),
+            stored,
+        }
+    })
+}
+
+/// Moves the trusted time forward towards the host clock `secs`, see
+/// `TrustedClock::observe_host_time`.
+pub fn observe_host_time(secs: u64) {
+    let mut guard = CLOCK.lock();
+    let clock = clock(&mut guard);
+    match clock.trusted.observe_host_time(secs, system_millis()) {
+        HostTime::Taken => {}
+        HostTime::Capped { ahead, step } => trace_println!(
+            "[!] Host clock is {} s ahead of the trusted time, advancing it by {} s",
+            ahead,
+            step
+        ),
+        HostTime::Behind => {
+            debug_println!("[+] Host clock is behind the trusted time, ignoring it")
+        }
+    }
+    clock.persist();
+}
+
+/// Writes the trusted time back, so the time spent in this session counts.
+pub fn flush() {
+    if let Some(clock) = CLOCK.lock().as_mut() {
+        clock.persist();
+    }
+}
+
+/// Seconds since the Unix epoch, 0 when no time was ever reported.
+pub fn trusted_time() -> u64 {
+    clock(&mut CLOCK.lock()).now()
+}
+
+/// Remembers the license of the model just installed in `slot`.
+pub fn install(slot: usize, license: Option<ModelLicense>) {
+    if let Some(license) = &license {
+        debug_println!(
+            "[+] Slot {} model licensed to \"{}\" until {}",
+            slot,
+            license.licensee,
+            license.expires
+        );
+    }
+    LICENSES.lock()[slot] = license;
+}
+
+/// Fails with `ERROR_LICENSE_EXPIRED` when the model in `slot` may no
+/// longer be used.
+pub fn check(slot: usize) -> Result<()> {
+    let expires = match &LICENSES.lock()[slot] {
+        Some(license) => license.expires,
+        None => return Ok(()),
+    };
+    let now = trusted_time();
+    if license_expired(expires, now) {
+        trace_println!(
+            "[!] License of slot {} expired at {} (now {})",
+            slot,
+            expires,
+            now
+        );
+        return Err(Error::from_raw_error(ERROR_LICENSE_EXPIRED));
+    }
+    Ok(())
+}
+
+pub fn status(slot: usize) -> Option<LicenseStatus> {
+    let license = LICENSES.lock()[slot].clone()?;
+    Some(LicenseStatus {
+        license,
+        now: trusted_time(),
+    })
+}
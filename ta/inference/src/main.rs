@@ -19,75 +19,339 @@
 #![no_main]
 extern crate alloc;
 
-use burn::{
-    backend::{ndarray::NdArrayDevice, NdArray},
-    tensor::cast::ToElement,
-};
+use burn::backend::{ndarray::NdArrayDevice, NdArray};
+use burn::tensor::Tensor;
+
+// Error logging, prefixed with the host's trace id (`Command::SetTraceId`)
+// when one is set. Used instead of `optee_utee::trace_println!` throughout.
+macro_rules! trace_println {
+    ($fmt:literal $($arg:tt)*) => {
+        match crate::trace_id::get() {
+            0 => optee_utee::trace_println!($fmt $($arg)*),
+            id => optee_utee::trace_println!(concat!("[{:016x}] ", $fmt), id $($arg)*),
+        }
+    };
+}
 
+// Progress logging. Without the `verbose-logs` feature the calls still
+// type-check but are compiled out, so their format strings stay out of the
+// TA binary; `trace_println!` remains for errors.
+macro_rules! debug_println {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "verbose-logs") {
+            trace_println!($($arg)*);
+        }
+    };
+}
 
+mod build_manifest;
+#[cfg(feature = "builtin-crypto")]
+mod builtin_crypto;
+mod crash;
+mod error_detail;
+mod heap_stats;
+mod heap_usage;
 mod key_manager;
+mod license;
+mod model_signature;
+mod output_cache;
+mod param_types;
+mod residency;
+mod result_cache;
+mod schema;
+mod secure_storage;
+mod self_test;
+mod session_role;
+mod shadow;
+mod stats;
+mod ta_local;
+mod trace_id;
 
+use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
-use alloc::string::ToString;
+use alloc::string::{String, ToString};
 use key_manager::{
-    decrypt_model_data, encrypt_model_data, ensure_aes_key, export_aes_key, import_aes_key,
+    decrypt_model_in_place, decrypt_model_in_place_with, encrypt_model_data,
+    encrypt_model_data_with, ensure_aes_key, export_aes_key, has_aes_key, import_aes_key,
     require_aes_key,
 };
+use output_cache::BatchOutputs;
+use ta_local::TaLocal;
 
 
 
-use common::{copy_to_output, Model};
-use optee_utee::{
-    ta_close_session, ta_create, ta_destroy, ta_invoke_command, ta_open_session, trace_println,
+use common::{
+    constant_time_eq, copy_to_output, load_footprint, predict_ensemble, split_container,
+    split_patch, store_key_action, ContainerError, KeyStoreAction, LoadError, LoadState,
+    Model, ModelError, ModelMetadata, ModelSlots, OutputError, PatchError, Recovery, RotationHash,
+    RotationJournal, RotationKey, RotationSteps, TakenLoad, MAX_CANARIES,
+};
+use optee_utee::{ta_close_session, ta_create, ta_destroy, ta_invoke_command, ta_open_session};
+use optee_utee::{property::{ClientIdentity, PropertyKey}, Error, ErrorKind, LoginType, ParamType, Parameter, Parameters, Result};
+use proto::inference::{
+    correlation_ids, encode_class_labels, export_chunk, is_deletable_storage_id, join_trace_id,
+    output_window, reject_threshold, split_request, split_trace_id, time_budget, Command,
+    CorrelatedPrediction, DurationMicros, InferenceRequestHeader, ModelStatus, ModelUsage,
+    Normalization, Prediction, ResidencyPolicy, SessionRole, StoredModelInfo, BUDGET_EXCEEDED,
+    CAP_CANARY, CAP_CHUNK_SIZES, CAP_CLASS_LABELS, CAP_CORRELATION, CAP_CRASH_REPORT, CAP_ENSEMBLE,
+    CAP_HISTORY, CAP_INPUT_HASHES, CAP_INPUT_SCALING, CAP_LICENSE, CAP_LOAD_STATE,
+    CAP_MODEL_EXPORT, CAP_MODEL_SIGNATURES, CAP_OUTPUT_WINDOW, CAP_PATCH, CAP_PROBABILITIES,
+    CAP_REJECT_THRESHOLD, CAP_RESIDENCY, CAP_RESULT_CACHE, CAP_SELF_TEST, CAP_SHADOW, CAP_SLOTS,
+    CAP_STAGING, CAP_STATS, CAP_STORAGE, CAP_STORAGE_SCHEMA, CAP_TIE_MARKS, CAP_TIME_BUDGET,
+    CAP_TRACE_ID, CRASH_REPORT_CLEAR, ERROR_CANARY_MISMATCH, ERROR_MODEL_SHAPE_MISMATCH,
+    ERROR_NO_IMAGES, INPUT_HASH_SIZE, KEY_COMMITMENT_SIZE, KEY_FINGERPRINT_SIZE,
+    MAX_CLASS_LABELS_SIZE, MAX_RESULT_CACHE_ENTRIES, MODEL_SLOTS, PROTOCOL_VERSION, REJECT_LABEL,
+    SHADOW_REPORT_RESET, STAGE_FORCE, STORE_KEY_FORCE, TEMPERATURE_SCALE, TIME_BUDGET_SLICE,
 };
-use optee_utee::{property::{ClientIdentity, PropertyKey}, ErrorKind, LoginType, Parameters, Result};
-use proto::Image;
+use proto::{fixed_point, wire, Image, DEFAULT_NUM_CLASSES, IMAGE_SIZE, MAX_NUM_CLASSES};
 use spin::Mutex;
 
 type NoStdModel = Model<NdArray>;
 const DEVICE: NdArrayDevice = NdArrayDevice::Cpu;
 const SECURE_UPDATE_TA_UUID: &str = "00000073-6563-7572-655f-757064617465";
-static MODEL: Mutex<Option<NoStdModel>> = Mutex::new(Option::None);
-static MODEL_BUF: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+// Sessions can be used from several host threads and these are spinlocks, so
+// none of them is held across a decrypt, import or forward pass: inferences
+// clone the handle of the model they use, and installs swap in a model that
+// was imported without the lock. A replaced model is freed once the last
+// inference using it finishes. Models aren't `Sync`, hence `TaLocal`.
+static MODELS: TaLocal<ModelSlots<NoStdModel>> = TaLocal::new(ModelSlots::new());
+// Checks the real model type against what the statics holding it need
+const _: fn() = || {
+    fn in_static<T: Sync>() {}
+    in_static::<TaLocal<ModelSlots<NoStdModel>>>();
+    in_static::<TaLocal<Mutex<Option<alloc::sync::Weak<NoStdModel>>>>>();
+};
+// Lock order: MODELS before MODEL_INFO
+static MODEL_INFO: Mutex<[ModelInfo; MODEL_SLOTS]> =
+    Mutex::new([ModelInfo::EMPTY; MODEL_SLOTS]);
+static PENDING_LOAD: Mutex<PendingLoad> = Mutex::new(PendingLoad::EMPTY);
+// Re-encrypted stored model read back between BeginModelExport and
+// EndModelExport
+static PENDING_EXPORT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+// Why loading the stored model on first inference failed, if it did
+static LAZY_LOAD_FAILURE: Mutex<Option<ErrorKind>> = Mutex::new(None);
+// Features reported to the host at open_session
+const CAPABILITIES: u32 = CAP_PROBABILITIES
+    | CAP_SLOTS
+    | CAP_ENSEMBLE
+    | CAP_PATCH
+    | CAP_STORAGE
+    | CAP_STATS
+    | CAP_HISTORY
+    | CAP_SELF_TEST
+    | CAP_CORRELATION
+    | CAP_RESIDENCY
+    | CAP_CLASS_LABELS
+    | CAP_STAGING
+    | CAP_LICENSE
+    | CAP_CANARY
+    | CAP_CRASH_REPORT
+    | CAP_INPUT_SCALING
+    | CAP_REJECT_THRESHOLD
+    | CAP_INPUT_HASHES
+    | CAP_MODEL_EXPORT
+    | CAP_TRACE_ID
+    | CAP_OUTPUT_WINDOW
+    | CAP_CHUNK_SIZES
+    | CAP_SHADOW
+    | CAP_LOAD_STATE
+    | CAP_RESULT_CACHE
+    | CAP_MODEL_SIGNATURES
+    | CAP_TIE_MARKS
+    | CAP_STORAGE_SCHEMA
+    | CAP_TIME_BUDGET;
+// PushEncryptedChunk sizes reported at open_session: the one the host is
+// asked to use, and the most one push may carry
+const PREFERRED_PUSH_CHUNK_SIZE: u32 = 64 * 1024;
+const MAX_PUSH_CHUNK_SIZE: u32 = 1024 * 1024;
+// Batch size assumed when estimating a model's memory footprint at finalize
+const ESTIMATE_BATCH_SIZE: usize = 64;
+
+/// Name and class labels of the installed model, taken from its metadata block,
+/// plus the output width of the model itself.
+struct ModelInfo {
+    name: Option<String>,
+    class_labels: Vec<String>,
+    num_classes: usize,
+    // Canary images checked at install
+    canaries: usize,
+    normalization: Normalization,
+}
+
+impl ModelInfo {
+    const EMPTY: Self = Self {
+        name: None,
+        class_labels: Vec::new(),
+        num_classes: DEFAULT_NUM_CLASSES,
+        canaries: 0,
+        normalization: Normalization::MNIST,
+    };
+}
+
+/// The model load in progress and the id the next one is logged under. Only
+/// locked to check a command against the state, append a chunk or take the
+/// buffer.
+struct PendingLoad {
+    state: LoadState,
+    next_txid: u32,
+}
+
+impl PendingLoad {
+    const EMPTY: Self = Self {
+        state: LoadState::Idle,
+        next_txid: 1,
+    };
+}
 
 #[ta_create]
 fn create() -> Result<()> {
-    trace_println!("[+] TA create");
+    debug_println!("[+] TA create");
     Ok(())
 }
 
+// Session context: the role the session opened with and the error detail of
+// its last command
+#[derive(Default)]
+struct Session {
+    role: SessionRole,
+    detail: error_detail::Detail,
+}
+
 #[ta_open_session]
-fn open_session(params: &mut Parameters) -> Result<()> {
+fn open_session(params: &mut Parameters, session: &mut Session) -> Result<()> {
     let mut p0 = unsafe { params.0.as_memref()? };
     let size = p0.buffer().len();
-    trace_println!("[+] Open session; initial buffer size: {} bytes (ignored)", size);
+    debug_println!("[+] Open session; initial buffer size: {} bytes (ignored)", size);
+    // Read before the chunk sizes overwrite it
+    let requested = unsafe { params.3.as_value() }.map_or(0, |value| value.a());
+    session.role = session_role::open(requested)?;
+    // Negotiating hosts pass their protocol version in p1 and read ours from p2
+    if let Ok(host) = unsafe { params.1.as_value() } {
+        debug_println!("[+] Host protocol version: {}", host.a());
+        stats::set_host_time(host.b());
+        license::observe_host_time(host.b() as u64);
+        let mut reply = unsafe { params.2.as_value()? };
+        reply.set_a(PROTOCOL_VERSION);
+        reply.set_b(CAPABILITIES);
+        if let Ok(mut sizes) = unsafe { params.3.as_value() } {
+            sizes.set_a(PREFERRED_PUSH_CHUNK_SIZE);
+            sizes.set_b(MAX_PUSH_CHUNK_SIZE);
+        }
+    }
+    // Before anything reads the stored model in a layout it doesn't know
+    schema::ensure_current()?;
+    // A model switched to a new key before the key itself was imported
+    // doesn't decrypt until the rotation is finished
+    if let Err(err) = recover_key_rotation() {
+        trace_println!("[!] Recovering the interrupted key rotation failed: {:?}", err);
+    }
+    residency::session_opened();
     Ok(())
 }
 
 #[ta_close_session]
-fn close_session() {
-    trace_println!("[+] TA close session");
+fn close_session(_session: &mut Session) {
+    debug_println!("[+] TA close session");
+    stats::flush();
+    license::flush();
+    if let Some(policy) = residency::session_closed() {
+        release_memory(policy);
+        trace_id::set(0);
+    }
 }
 
 #[ta_destroy]
 fn destroy() {
-    trace_println!("[+] TA destroy");
+    debug_println!("[+] TA destroy");
+    release_memory(ResidencyPolicy::DropOnIdle);
+}
+
+// Wipes a half-streamed model and, unless the policy keeps them resident,
+// drops the installed models. The AES key never outlives a key manager call
+// in this TA, so there is no key material to clear here.
+fn release_memory(policy: ResidencyPolicy) {
+    PENDING_EXPORT.lock().take();
+    let dropped = common::release_models(policy, &mut PENDING_LOAD.lock().state, &MODELS, || {
+        *MODEL_INFO.lock() = [ModelInfo::EMPTY; MODEL_SLOTS]
+    });
+    if dropped {
+        debug_println!("[+] Dropped resident models");
+        LAZY_LOAD_FAILURE.lock().take();
+        output_cache::clear();
+        result_cache::clear();
+    }
 }
 
 #[ta_invoke_command]
-fn invoke_command(cmd_id: u32, params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] TA invoke command, cmd_id: {}", cmd_id);
+fn invoke_command(session: &mut Session, cmd_id: u32, params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] TA invoke command, cmd_id: {}", cmd_id);
+    error_detail::clear();
+    let result = run_command(session.role, cmd_id, params);
+    session.detail = error_detail::take();
+    if let (Err(_), Ok(cmd)) = (&result, Command::try_from(cmd_id)) {
+        match param_types::detail_param(cmd, params) {
+            Some(param) => session.detail.write(param),
+            // Nowhere to put it without clobbering an output
+            None => {
+                if let Some(detail) = session.detail.as_str() {
+                    trace_println!("[!] {:?}: {}", cmd, detail);
+                }
+            }
+        }
+    }
+    result
+}
+
+fn run_command(role: SessionRole, cmd_id: u32, params: &mut Parameters) -> Result<()> {
+    if let Ok(cmd) = Command::try_from(cmd_id) {
+        param_types::check(cmd, params)?;
+        session_role::check(role, cmd, params)?;
+    }
     
-    match cmd_id {
-        0 => invoke_inference(params),
+    match Command::try_from(cmd_id) {
+        Ok(Command::Infer) => invoke_inference(params),
         #[cfg(feature = "encrypt-model")]
-        1 => invoke_encrypt_model(params),
-        // 2 => invoke_decrypt_model(params),
-        3 => invoke_store_key(params),
-        4 => invoke_begin_model_load(params),
-        5 => invoke_push_encrypted_chunk(params),
-        6 => invoke_finalize_model_load(params),
-        // 7 => invoke_export_aes_key(params),
+        Ok(Command::EncryptModel) => invoke_encrypt_model(params),
+        // Ok(Command::DecryptModel) => invoke_decrypt_model(params),
+        Ok(Command::StoreKey) => invoke_store_key(params),
+        Ok(Command::BeginModelLoad) => invoke_begin_model_load(params),
+        Ok(Command::PushEncryptedChunk) => invoke_push_encrypted_chunk(params),
+        Ok(Command::FinalizeModelLoad) => invoke_finalize_model_load(params),
+        // Ok(Command::ExportAesKey) => invoke_export_aes_key(params),
+        Ok(Command::ModelStatus) => invoke_model_status(params),
+        Ok(Command::InferEnsemble) => invoke_inference_ensemble(params),
+        Ok(Command::PatchModel) => invoke_patch_model(params),
+        Ok(Command::ListStorage) => invoke_list_storage(params),
+        Ok(Command::DeleteStorageObject) => invoke_delete_storage_object(params),
+        Ok(Command::GetPersistentStats) => invoke_get_persistent_stats(params),
+        Ok(Command::ResetPersistentStats) => invoke_reset_persistent_stats(params),
+        Ok(Command::StoragePreflight) => invoke_storage_preflight(params),
+        Ok(Command::RollbackModel) => invoke_rollback_model(params),
+        Ok(Command::ModelHistory) => invoke_model_history(params),
+        Ok(Command::RebindStorage) => invoke_rebind_storage(params),
+        Ok(Command::RunSelfTest) => invoke_run_self_test(params),
+        Ok(Command::SetResidencyPolicy) => invoke_set_residency_policy(params),
+        Ok(Command::GetClassLabels) => invoke_get_class_labels(params),
+        Ok(Command::StageModel) => invoke_stage_model(params),
+        Ok(Command::CommitModel) => invoke_commit_model(params),
+        Ok(Command::DiscardStaged) => invoke_discard_staged(params),
+        Ok(Command::GetLastCrash) => invoke_get_last_crash(params),
+        Ok(Command::BeginModelExport) => invoke_begin_model_export(params),
+        Ok(Command::ReadEncryptedChunk) => invoke_read_encrypted_chunk(params),
+        Ok(Command::EndModelExport) => invoke_end_model_export(params),
+        Ok(Command::SetTraceId) => invoke_set_trace_id(params),
+        Ok(Command::SetShadow) => invoke_set_shadow(params),
+        Ok(Command::GetShadowReport) => invoke_get_shadow_report(params),
+        Ok(Command::SetResultCache) => invoke_set_result_cache(params),
+        Ok(Command::SetModelVerifyKey) => invoke_set_model_verify_key(params),
+        Ok(Command::GetSchemaVersion) => invoke_get_schema_version(params),
+        Ok(Command::GetBuildManifest) => invoke_get_build_manifest(params),
+        Ok(Command::RotateKey) => invoke_rotate_key(params),
+        Ok(Command::SetManagePolicy) => invoke_set_manage_policy(params),
+        Ok(Command::GetKeyFingerprint) => invoke_get_key_fingerprint(params),
+        #[cfg(feature = "debug-panic")]
+        Ok(Command::DebugPanic) => panic!("panic requested by Command::DebugPanic"),
         _ => {
             trace_println!("[!] Unknown command ID: {}", cmd_id);
             Err(ErrorKind::BadParameters.into())
@@ -96,64 +360,566 @@ fn invoke_command(cmd_id: u32, params: &mut Parameters) -> Result<()> {
 }
 
 fn invoke_inference(params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] Processing inference request");
+    debug_println!("[+] Processing inference request");
+    // After a restart the stored model is loaded by the first inference on slot 0
+    if unsafe { params.2.as_value() }.map_or(0, |value| value.b()) == 0 {
+        ensure_stored_model_loaded()?;
+    }
     
-    trace_println!("[+] Getting input parameters...");
+    debug_println!("[+] Getting input parameters...");
     let mut p0 = unsafe { params.0.as_memref()? };
-    trace_println!("[+] Input buffer size: {} bytes", p0.buffer().len());
+    debug_println!("[+] Input buffer size: {} bytes", p0.buffer().len());
     
-    trace_println!("[+] Converting to images...");
-    let images: &[Image] = bytemuck::cast_slice(p0.buffer());
-    trace_println!("[+] Number of images: {}", images.len());
+    debug_println!("[+] Converting to images...");
+    let request = p0.buffer();
+    let (header, image_bytes) = split_request(request).map_err(|err| {
+        trace_println!("[!] Malformed inference request");
+        error_detail::set(format_args!("malformed inference request: {}", err));
+        ErrorKind::BadParameters
+    })?;
+    // Echoed with the predictions of correlated requests
+    let ids = header.and_then(|header| correlation_ids(request, &header));
+    let threshold = header.and_then(|header| reject_threshold(request, &header));
+    let window = header.and_then(|header| output_window(request, &header));
+    let budget = header.and_then(|header| time_budget(request, &header));
+    let ids = match window {
+        Some(window) => ids.map(|ids| ids[window.range()].to_vec()),
+        None => ids,
+    };
+    let images: &[Image] = wire::records(image_bytes).ok_or(ErrorKind::BadParameters)?;
+    debug_println!("[+] Number of images: {}", images.len());
     
     if images.is_empty() {
         trace_println!("[!] No images provided for inference");
-        return Err(ErrorKind::BadParameters.into());
+        return Err(Error::from_raw_error(ERROR_NO_IMAGES));
     }
     
-    trace_println!("[+] Converting images to tensors...");
-    trace_println!("[+] Image data validation - first image: {:?}", &images[0][0..8]);
-    let input = NoStdModel::images_to_tensors(&DEVICE, images);
-    trace_println!("[+] Tensor conversion completed");
-
-    trace_println!("[+] Getting model from lock...");
-    let model_guard = MODEL.lock();
-    let model = model_guard.as_ref().ok_or(ErrorKind::CorruptObject)?;
-    trace_println!("[+] Model retrieved successfully");
-    
-    trace_println!("[+] Running forward pass...");
-    let output = model.forward(input);
-    trace_println!("[+] Forward pass completed");
-    
-    trace_println!("[+] Processing output...");
-    let result: alloc::vec::Vec<u8> = output
-        .iter_dim(0)
-        .map(|v| {
-            let data = burn::tensor::activation::softmax(v, 1);
-            data.argmax(1).into_scalar().to_u8()
-        })
-        .collect();
-    trace_println!("[+] Output processing completed, result size: {}", result.len());
+    // Optional value parameter: a = temperature in fixed point (see
+    // `TEMPERATURE_SCALE`, legacy requests only; the header carries it
+    // otherwise), b = model slot
+    let value = unsafe { params.2.as_value() }
+        .ok()
+        .map(|value| (value.a(), value.b()));
+    let slot = match value {
+        Some((_, slot)) => slot_index(slot)?,
+        None => 0,
+    };
+    let _timer = stats::InferenceTimer::start(slot);
+    let temperature = match (&header, value) {
+        (Some(header), _) => header.temperature(),
+        (None, Some((temperature, _))) => parse_temperature(temperature)?,
+        (None, None) => 1.0,
+    };
+    let want_predictions =
+        ids.is_some() || header.is_some_and(|header| header.wants_predictions());
+    let mark_ties = header.is_some_and(|header| header.marks_ties());
+    let fixed_point = header.is_some_and(|header| header.fixed_point_probabilities());
+    // Probabilities are only computed when the host passes an output memref for them
+    let want_probabilities = matches!(
+        params.3.param_type,
+        ParamType::MemrefOutput | ParamType::MemrefInout
+    );
+
+    license::check(slot)?;
+    debug_println!("[+] Getting model from lock...");
+    let (model, profile) = installed_model(slot).ok_or_else(|| {
+        error_detail::set(format_args!("no model installed in slot {}", slot));
+        ErrorKind::CorruptObject
+    })?;
+    debug_println!("[+] Model retrieved successfully");
+
+    // The reject label would be ambiguous for a model that has a class 255
+    if threshold.is_some() && model.num_classes() > REJECT_LABEL as usize {
+        trace_println!(
+            "[!] Reject threshold on a model with {} classes",
+            model.num_classes()
+        );
+        error_detail::set(format_args!(
+            "reject threshold on a model with {} classes, at most {}",
+            model.num_classes(),
+            REJECT_LABEL
+        ));
+        return Err(ErrorKind::BadParameters.into());
+    }
 
-    trace_println!("[+] Copying to output...");
-    copy_to_output(&mut params.1, &result)
+    debug_println!(
+        "[+] Image data validation - first image: {:?}",
+        &images[0].as_bytes()[0..8]
+    );
+    // Request flags may override the model's profile for pre-scaled inputs
+    let normalization = header.map_or(profile, |header| header.normalization(&profile));
+    let wants_hashes = header.is_some_and(|header| header.wants_input_hashes());
+    // Input hashes aren't cached, windowed batches have their own cache, and
+    // a budgeted batch may stop before the images the cache would answer
+    let use_cache = !wants_hashes
+        && window.is_none()
+        && budget.is_none()
+        && !header.is_some_and(|header| header.bypasses_cache())
+        && result_cache::enabled();
+    let outputs = match (header, window) {
+        (Some(header), Some(window)) => {
+            let key = output_cache::key(&header, image_bytes)?;
+            let outputs = match output_cache::get(&model, &key) {
+                Some(outputs) => {
+                    debug_println!("[+] Batch outputs cached");
+                    outputs
+                }
+                None => {
+                    let outputs =
+                        run_batch(&model, images, &normalization, temperature, wants_hashes)?;
+                    stats::record(slot, &outputs.labels);
+                    run_shadow(slot, images, &outputs.labels, Some(header));
+                    let outputs = Arc::new(outputs);
+                    output_cache::put(&model, key, outputs.clone());
+                    outputs
+                }
+            };
+            debug_println!("[+] Returning images {:?}", window.range());
+            outputs.window(window.range(), model.num_classes())
+        }
+        _ if use_cache => {
+            let outputs = run_cached(slot, &model, images, &normalization, temperature)?;
+            run_shadow(slot, images, &outputs.labels, header);
+            outputs
+        }
+        _ if !want_probabilities
+            && !want_predictions
+            && threshold.is_none()
+            && budget.is_none() =>
+        {
+            debug_println!("[+] Converting images to tensors...");
+            let input = NoStdModel::images_to_tensors(&DEVICE, images, &normalization);
+            // Appended to the labels
+            let hashes = if wants_hashes {
+                input_hashes(&input)?
+            } else {
+                Vec::new()
+            };
+            debug_println!("[+] Running forward pass...");
+            let result = model.predict_labels(input).ok_or(ErrorKind::Generic)?;
+            debug_println!("[+] Output processing completed, result size: {}", result.len());
+            stats::record(slot, &result);
+            run_shadow(slot, images, &result, header);
+
+            debug_println!("[+] Copying to output...");
+            return copy_inference_output(&mut params.1, &mut params.2, &result, &hashes);
+        }
+        _ => {
+            let outputs = match budget {
+                Some(budget) => run_budgeted(
+                    &model,
+                    images,
+                    &normalization,
+                    temperature,
+                    wants_hashes,
+                    DurationMicros::from_millis(budget as u64),
+                )?,
+                None => run_batch(&model, images, &normalization, temperature, wants_hashes)?,
+            };
+            stats::record(slot, &outputs.labels);
+            run_shadow(
+                slot,
+                &images[..outputs.labels.len()],
+                &outputs.labels,
+                header,
+            );
+            outputs
+        }
+    };
+    let completed = outputs.labels.len();
+    // Images a budgeted batch didn't get to have no results
+    let ids = ids.map(|mut ids| {
+        ids.truncate(completed);
+        ids
+    });
+    let BatchOutputs {
+        labels,
+        probs,
+        hashes,
+    } = outputs;
+
+    debug_println!("[+] Copying to output...");
+    if want_predictions || threshold.is_some() {
+        // Picks the same classes as `labels`, with the tie marks
+        let predictions = probs
+            .chunks_exact(model.num_classes())
+            .map(|row| {
+                let prediction = Prediction::from_probabilities(row, mark_ties)?;
+                Some(threshold.map_or(prediction, |threshold| prediction.with_threshold(threshold)))
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(ErrorKind::Generic)?;
+        match ids {
+            Some(ids) => {
+                let records: Vec<CorrelatedPrediction> = ids
+                    .into_iter()
+                    .zip(predictions)
+                    .map(|(id, prediction)| CorrelatedPrediction::new(id, prediction))
+                    .collect();
+                copy_inference_output(
+                    &mut params.1,
+                    &mut params.2,
+                    wire::as_bytes(&records),
+                    &hashes,
+                )?;
+            }
+            None if want_predictions => copy_inference_output(
+                &mut params.1,
+                &mut params.2,
+                wire::as_bytes(&predictions),
+                &hashes,
+            )?,
+            None => {
+                let labels: Vec<u8> = predictions.iter().map(|p| p.label).collect();
+                copy_inference_output(&mut params.1, &mut params.2, &labels, &hashes)?;
+            }
+        }
+    } else {
+        copy_inference_output(&mut params.1, &mut params.2, &labels, &hashes)?;
+    }
+    if want_probabilities && fixed_point {
+        let bytes = fixed_point_probabilities_to_bytes(&probs, model.num_classes());
+        copy_to_output(&mut params.3, &bytes)?;
+    } else if want_probabilities {
+        copy_to_output(&mut params.3, &probabilities_to_bytes(&probs))?;
+    }
+    if budget.is_some() {
+        let mut value = unsafe { params.2.as_value()? };
+        value.set_a(completed as u32);
+        value.set_b(if completed < images.len() {
+            BUDGET_EXCEEDED
+        } else {
+            0
+        });
+    }
+    Ok(())
+}
+
+fn invoke_inference_ensemble(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Processing ensemble inference request");
+    let mut p0 = unsafe { params.0.as_memref()? };
+    let images: &[Image] = wire::records(p0.buffer()).ok_or_else(|| {
+        trace_println!("[!] Input is not a whole number of images");
+        ErrorKind::BadParameters
+    })?;
+    if images.is_empty() {
+        trace_println!("[!] No images provided for inference");
+        return Err(Error::from_raw_error(ERROR_NO_IMAGES));
+    }
+    let (mask, temperature) = {
+        let value = unsafe { params.2.as_value()? };
+        (value.a(), parse_temperature(value.b())?)
+    };
+    if mask == 0 || mask >> MODEL_SLOTS != 0 {
+        trace_println!("[!] Invalid slot mask: {:#x}", mask);
+        return Err(ErrorKind::BadParameters.into());
+    }
+    if mask & 1 != 0 {
+        ensure_stored_model_loaded()?;
+    }
+    for slot in (0..MODEL_SLOTS).filter(|slot| mask & (1 << slot) != 0) {
+        license::check(slot)?;
+    }
+    let mut selected = Vec::new();
+    let mut profiles = Vec::new();
+    for slot in (0..MODEL_SLOTS).filter(|slot| mask & (1 << slot) != 0) {
+        match installed_model(slot) {
+            Some((model, profile)) => {
+                selected.push(model);
+                profiles.push(profile);
+            }
+            None => {
+                trace_println!("[!] Slot {} is empty", slot);
+                error_detail::set(format_args!("no model installed in slot {}", slot));
+                return Err(ErrorKind::ItemNotFound.into());
+            }
+        }
+    }
+    // One input tensor feeds every member, so they must agree on its scaling
+    if profiles.iter().any(|profile| *profile != profiles[0]) {
+        trace_println!("[!] Ensemble members disagree on input normalization");
+        return Err(ErrorKind::BadParameters.into());
+    }
+    let input = NoStdModel::images_to_tensors(&DEVICE, images, &profiles[0]);
+    let selected: Vec<&NoStdModel> = selected.iter().map(|model| model.as_ref()).collect();
+    if selected
+        .iter()
+        .any(|model| model.num_classes() != selected[0].num_classes())
+    {
+        trace_println!("[!] Ensemble members disagree on the class count");
+        return Err(ErrorKind::BadParameters.into());
+    }
+    debug_println!("[+] Averaging {} models", selected.len());
+    let (labels, probs) =
+        predict_ensemble(&selected, input, temperature).ok_or(ErrorKind::BadParameters)?;
+    // Every member served the request
+    for slot in (0..MODEL_SLOTS).filter(|slot| mask & (1 << slot) != 0) {
+        stats::record(slot, &labels);
+    }
+    copy_to_output(&mut params.1, &labels)?;
+    if matches!(
+        params.3.param_type,
+        ParamType::MemrefOutput | ParamType::MemrefInout
+    ) {
+        copy_to_output(&mut params.3, &probabilities_to_bytes(&probs))?;
+    }
+    Ok(())
+}
+
+// Runs the forward pass with probabilities over a whole batch, with the input
+// hashes (appended to the labels or predictions) when asked for
+fn run_batch(
+    model: &NoStdModel,
+    images: &[Image],
+    normalization: &Normalization,
+    temperature: f32,
+    wants_hashes: bool,
+) -> Result<BatchOutputs> {
+    debug_println!("[+] Converting images to tensors...");
+    let input = NoStdModel::images_to_tensors(&DEVICE, images, normalization);
+    debug_println!("[+] Tensor conversion completed");
+    let hashes = if wants_hashes {
+        input_hashes(&input)?
+    } else {
+        Vec::new()
+    };
+    debug_println!("[+] Computing probabilities, temperature: {}", temperature);
+    let (labels, probs) = model
+        .predict_with_temperature(input, temperature)
+        .ok_or(ErrorKind::Generic)?;
+    debug_println!("[+] Output processing completed, result size: {}", labels.len());
+    Ok(BatchOutputs {
+        labels,
+        probs,
+        hashes,
+    })
+}
+
+// `run_batch` a `TIME_BUDGET_SLICE` of the images at a time, stopping at the
+// end of the slice during which `budget` ran out. Softmax is per image, so
+// the outputs are those of the first images of one whole-batch run.
+fn run_budgeted(
+    model: &NoStdModel,
+    images: &[Image],
+    normalization: &Normalization,
+    temperature: f32,
+    wants_hashes: bool,
+    budget: DurationMicros,
+) -> Result<BatchOutputs> {
+    let mut outputs = BatchOutputs {
+        labels: Vec::with_capacity(images.len()),
+        probs: Vec::with_capacity(images.len() * model.num_classes()),
+        hashes: Vec::new(),
+    };
+    common::run_within_budget(
+        images.len(),
+        TIME_BUDGET_SLICE,
+        budget,
+        stats::system_time,
+        |slice| {
+            let part = run_batch(model, &images[slice], normalization, temperature, wants_hashes)?;
+            outputs.labels.extend_from_slice(&part.labels);
+            outputs.probs.extend_from_slice(&part.probs);
+            outputs.hashes.extend_from_slice(&part.hashes);
+            Ok::<_, Error>(())
+        },
+    )?;
+    if outputs.labels.len() < images.len() {
+        debug_println!(
+            "[+] Time budget spent after {} of {} images",
+            outputs.labels.len(),
+            images.len()
+        );
+    }
+    Ok(outputs)
+}
+
+// `run_batch` without input hashes, taking the results of the images in the
+// result cache from it and running the model on the others only, whose
+// results are cached in turn. Counts the inference and the cache hits.
+fn run_cached(
+    slot: usize,
+    model: &NoStdModel,
+    images: &[Image],
+    normalization: &Normalization,
+    temperature: f32,
+) -> Result<BatchOutputs> {
+    let model_hash = stats::model_hash(slot).ok_or(ErrorKind::CorruptObject)?;
+    let num_classes = model.num_classes();
+    let keys = images
+        .iter()
+        .map(|image| result_cache::key(&model_hash, normalization, temperature, image))
+        .collect::<Result<Vec<_>>>()?;
+    let outputs = result_cache::run(&keys, num_classes, |misses| {
+        let batch: Vec<Image> = misses.iter().map(|&i| images[i]).collect();
+        let outputs = run_batch(model, &batch, normalization, temperature, false)?;
+        Ok((outputs.labels, outputs.probs))
+    })?;
+    let misses = images.len() - outputs.hits;
+    debug_println!(
+        "[+] Result cache: {} hits, {} misses",
+        outputs.hits,
+        misses
+    );
+    stats::record(slot, &outputs.labels);
+    stats::record_cache(slot, outputs.hits, misses);
+    Ok(BatchOutputs {
+        labels: outputs.labels,
+        probs: outputs.probs,
+        hashes: Vec::new(),
+    })
+}
+
+// Runs the shadow of `slot`, if it has one, on the images the active model
+// just labelled and records where the two disagree. Never fails the request:
+// a shadow that can't run is only counted.
+fn run_shadow(
+    slot: usize,
+    images: &[Image],
+    labels: &[u8],
+    header: Option<InferenceRequestHeader>,
+) {
+    let skip = header.is_some_and(|header| header.skips_shadow());
+    shadow::run(slot, images, labels, skip, |shadow| {
+        let installed = license::check(shadow)
+            .ok()
+            .and_then(|()| installed_model(shadow));
+        let shadow_labels = installed.and_then(|(model, profile)| {
+            let normalization = header.map_or(profile, |header| header.normalization(&profile));
+            let input = NoStdModel::images_to_tensors(&DEVICE, images, &normalization);
+            model.predict_labels(input)
+        });
+        if shadow_labels.is_none() {
+            trace_println!("[!] Shadow slot {} failed to run", shadow);
+        }
+        shadow_labels
+    });
+}
+
+// Writes an Infer result, followed by the input hashes if any, to `output`.
+// When it doesn't fit and the host passed the value parameter as inout, the
+// size needed is reported in its `a` so the host can retry with a larger
+// buffer.
+fn copy_inference_output(
+    output: &mut Parameter,
+    value: &mut Parameter,
+    data: &[u8],
+    hashes: &[u8],
+) -> Result<()> {
+    let joined;
+    let data = if hashes.is_empty() {
+        data
+    } else {
+        joined = [data, hashes].concat();
+        &joined
+    };
+    copy_to_output(output, data).map_err(|err| {
+        if let OutputError::ShortBuffer { required } = err {
+            if matches!(value.param_type, ParamType::ValueInout) {
+                if let Ok(mut value) = unsafe { value.as_value() } {
+                    value.set_a(required.min(u32::MAX as usize) as u32);
+                }
+            }
+        }
+        err.into()
+    })
+}
+
+// SHA-256 of each image's row of the input tensor, as little-endian f32
+// (`FLAG_INPUT_HASHES`)
+fn input_hashes(input: &Tensor<NdArray, 2>) -> Result<Vec<u8>> {
+    let values: Vec<f32> = input.to_data().iter::<f32>().collect();
+    let mut hashes = Vec::with_capacity(values.len() / IMAGE_SIZE * INPUT_HASH_SIZE);
+    for row in values.chunks(IMAGE_SIZE) {
+        let bytes: Vec<u8> = row.iter().flat_map(|value| value.to_le_bytes()).collect();
+        hashes.extend_from_slice(&secure_storage::sha256(&bytes)?);
+    }
+    Ok(hashes)
+}
+
+// Temperatures travel as `temperature * TEMPERATURE_SCALE` and must be positive
+fn parse_temperature(value: u32) -> Result<f32> {
+    if value == 0 {
+        trace_println!("[!] Temperature must be positive");
+        return Err(ErrorKind::BadParameters.into());
+    }
+    Ok(value as f32 / TEMPERATURE_SCALE as f32)
+}
+
+// Handle on the model installed in `slot` and its input normalization, read
+// under one lock so a concurrent install can't pair a model with another's
+// profile
+fn installed_model(slot: usize) -> Option<(Arc<NoStdModel>, Normalization)> {
+    let (model, normalization) = MODELS.get_with(slot, || MODEL_INFO.lock()[slot].normalization);
+    Some((model?, normalization))
+}
+
+// Answers a load command the load state refused. Out of order, it reports
+// the load's phase (a) and the bytes it received (b) in the value parameter
+// `param`, when the host passed one, and fails with BadState.
+fn bad_load_state(cmd: Command, param: &mut Parameter, err: LoadError) -> Error {
+    trace_println!("[!] {:?}: {}", cmd, err);
+    if let LoadError::OutOfOrder { phase, received } = err {
+        if let Ok(mut value) = unsafe { param.as_value() } {
+            value.set_a(phase.into());
+            value.set_b(received as u32);
+        }
+    }
+    err.into()
+}
+
+// Takes the streamed model, with its slot, out of a load that received every
+// byte it announced and leaves the pipeline idle. `param` is where `cmd`
+// reports the state when the load isn't complete.
+fn take_pending_load(cmd: Command, param: &mut Parameter) -> Result<(usize, Vec<u8>)> {
+    let TakenLoad { slot, txid, buf } = PENDING_LOAD
+        .lock()
+        .state
+        .take()
+        .map_err(|err| bad_load_state(cmd, param, err))?;
+    debug_println!("[+] Model load {} taken: {} bytes", txid, buf.len());
+    Ok((slot, buf))
+}
+
+fn slot_index(value: u32) -> Result<usize> {
+    let slot = value as usize;
+    if slot >= MODEL_SLOTS {
+        trace_println!("[!] Invalid model slot: {}", slot);
+        return Err(ErrorKind::BadParameters.into());
+    }
+    Ok(slot)
+}
+
+// Probabilities are returned as little-endian f32, one per class and image
+fn probabilities_to_bytes(probs: &[f32]) -> Vec<u8> {
+    probs.iter().flat_map(|p| p.to_le_bytes()).collect()
+}
+
+// With FLAG_FIXED_POINT_PROBS each image's row is encoded on its own so that
+// it sums to exactly PROB_SCALE
+fn fixed_point_probabilities_to_bytes(probs: &[f32], num_classes: usize) -> Vec<u8> {
+    probs
+        .chunks_exact(num_classes)
+        .flat_map(fixed_point::encode_probs)
+        .flat_map(|p| p.to_le_bytes())
+        .collect()
 }
 
 #[cfg(feature = "encrypt-model")]
 fn invoke_encrypt_model(params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] Processing model encryption request");
+    debug_println!("[+] Processing model encryption request");
     
     let mut p0 = unsafe { params.0.as_memref()? };
     let mut p1 = unsafe { params.1.as_memref()? };
     
     let model_data = p0.buffer();
-    trace_println!("[+] Received model data: {} bytes", model_data.len());
+    debug_println!("[+] Received model data: {} bytes", model_data.len());
     
     ensure_aes_key()?;
 
-    trace_println!("[+] Encrypting model with TA AES key...");
+    debug_println!("[+] Encrypting model with TA AES key...");
     let encrypted_model = encrypt_model_data(model_data)?;
-    trace_println!("[+] Model encrypted, size: {} bytes", encrypted_model.len());
+    debug_println!("[+] Model encrypted, size: {} bytes", encrypted_model.len());
     
     if p1.buffer().len() < encrypted_model.len() {
         trace_println!("[!] Output buffer too small: {} < {}", p1.buffer().len(), encrypted_model.len());
@@ -163,13 +929,13 @@ fn invoke_encrypt_model(params: &mut Parameters) -> Result<()> {
     p1.buffer()[..encrypted_model.len()].copy_from_slice(&encrypted_model);
     p1.set_updated_size(encrypted_model.len());
     
-    trace_println!("[+] Encrypted model returned to host");
+    debug_println!("[+] Encrypted model returned to host");
     Ok(())
 }
 
 
 fn invoke_store_key(params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] Processing key provision request");
+    debug_println!("[+] Processing key provision request");
     let mut p0 = unsafe { params.0.as_memref()? };
     let key_buf = p0.buffer();
     if key_buf.len() != 32 {
@@ -178,11 +944,230 @@ fn invoke_store_key(params: &mut Parameters) -> Result<()> {
     }
     let mut key = [0u8; 32];
     key.copy_from_slice(key_buf);
-    import_aes_key(&key)?;
-    trace_println!("[+] Secret key stored in key manager");
+    let stored = store_key(&key, params);
+    residency::wipe(&mut key);
+    stored
+}
+
+// Imports `key` unless the request carries a commitment and a key is already
+// stored: the same key is then left as it is, so a host retrying after a lost
+// reply succeeds, and a different one is only replaced when forced
+fn store_key(key: &[u8; 32], params: &mut Parameters) -> Result<()> {
+    let commitment = match unsafe { params.1.as_memref() } {
+        Ok(mut p1) => {
+            let buf = p1.buffer();
+            if buf.len() != KEY_COMMITMENT_SIZE {
+                trace_println!("[!] Invalid key commitment size: {}", buf.len());
+                return Err(ErrorKind::BadParameters.into());
+            }
+            let mut commitment = [0u8; KEY_COMMITMENT_SIZE];
+            commitment.copy_from_slice(buf);
+            Some(commitment)
+        }
+        Err(_) => None,
+    };
+    let force = match unsafe { params.2.as_value() } {
+        Ok(value) => value.a() & STORE_KEY_FORCE != 0,
+        Err(_) => false,
+    };
+    if let Some(commitment) = commitment {
+        let key_hash = secure_storage::sha256(key)?;
+        let stored_hash = if has_aes_key()? {
+            let mut current = export_aes_key()?;
+            let hashed = secure_storage::sha256(&current);
+            residency::wipe(&mut current);
+            Some(hashed?)
+        } else {
+            None
+        };
+        let action = store_key_action(&key_hash, &commitment, stored_hash.as_ref(), force)
+            .inspect_err(|err| trace_println!("[!] {}", err))?;
+        match action {
+            KeyStoreAction::AlreadyStored => {
+                debug_println!("[+] Secret key already stored");
+                return Ok(());
+            }
+            KeyStoreAction::Replace => trace_println!("[!] Replacing the stored key as forced"),
+            KeyStoreAction::Import => {}
+        }
+    }
+    import_aes_key(key)?;
+    debug_println!("[+] Secret key stored in key manager");
+    Ok(())
+}
+
+fn invoke_rotate_key(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Processing key rotation request");
+    let mut key = [0u8; 32];
+    let mut commitment = [0u8; KEY_COMMITMENT_SIZE];
+    {
+        let mut p0 = unsafe { params.0.as_memref()? };
+        let mut p1 = unsafe { params.1.as_memref()? };
+        if p0.buffer().len() != key.len() || p1.buffer().len() != KEY_COMMITMENT_SIZE {
+            trace_println!("[!] Invalid key or commitment size");
+            return Err(ErrorKind::BadParameters.into());
+        }
+        key.copy_from_slice(p0.buffer());
+        commitment.copy_from_slice(p1.buffer());
+    }
+    let rotated = rotate_key(&key, &commitment);
+    residency::wipe(&mut key);
+    rotated
+}
+
+// Re-encrypts the stored model under `key` and switches to it once the new
+// ciphertext decrypts to the same model and that model imports and passes
+// its canaries; only then does the key manager's key change (see
+// `common::rotate`). A failure before the switch leaves the old key and
+// model in place.
+fn rotate_key(key: &[u8; 32], commitment: &[u8; KEY_COMMITMENT_SIZE]) -> Result<()> {
+    if !constant_time_eq(&secure_storage::sha256(key)?, commitment) {
+        trace_println!("[!] Key commitment doesn't match the key");
+        return Err(ErrorKind::BadParameters.into());
+    }
+    // A retry after an interruption finds the rotation finished here
+    recover_key_rotation()?;
+    require_aes_key()?;
+    let mut old_key = export_aes_key()?;
+    let already_rotated = constant_time_eq(&secure_storage::sha256(&old_key)?, commitment);
+    residency::wipe(&mut old_key);
+    if already_rotated {
+        debug_println!("[+] Key already rotated");
+        return Ok(());
+    }
+    if secure_storage::staged_model_info()?.is_some() {
+        trace_println!("[!] A model is staged, commit or discard it before rotating the key");
+        return Err(ErrorKind::BadState.into());
+    }
+    let mut plain = match secure_storage::load_model_bytes()? {
+        Some(mut model) => {
+            decrypt_model_in_place(&mut model)?;
+            Some(model)
+        }
+        None => None,
+    };
+    let info = secure_storage::load_model_info().ok().flatten();
+    let mut steps = TaRotation::default();
+    let result = common::rotate(&mut steps, key, plain.as_deref());
+    if let Some(plain) = plain.as_mut() {
+        residency::wipe_vec(plain);
+    }
+    let hash = result.inspect_err(|err| trace_println!("[!] Key rotation failed: {:?}", err))?;
+    if let (Some(hash), Some(mut info)) = (hash, info) {
+        info.encrypted_hash = secure_storage::hex(&hash);
+        info.encrypted_size = steps.written_size as u32;
+        info.key_fingerprint = secure_storage::sha256(key)
+            .ok()
+            .map(|hash| secure_storage::hex(&hash[..KEY_FINGERPRINT_SIZE]));
+        if let Err(err) = secure_storage::store_model_info(&info) {
+            trace_println!("[!] Failed to store model info: {:?}", err);
+        }
+    }
+    debug_println!("[+] Secret key rotated");
     Ok(())
 }
 
+fn recover_key_rotation() -> Result<()> {
+    match common::recover(&mut TaRotation::default())? {
+        Recovery::Clean => {}
+        Recovery::RolledBack => {
+            trace_println!("[!] Dropped a key rotation interrupted before its switch")
+        }
+        Recovery::Completed => {
+            trace_println!("[!] Finished a key rotation interrupted after its switch")
+        }
+    }
+    Ok(())
+}
+
+// Rotation steps on the key manager and secure storage
+#[derive(Default)]
+struct TaRotation {
+    written: Option<secure_storage::Rekeyed>,
+    written_size: usize,
+}
+
+impl RotationSteps for TaRotation {
+    type Error = Error;
+
+    fn encrypt(&mut self, key: &RotationKey, plain: &[u8]) -> Result<Vec<u8>> {
+        encrypt_model_data_with(key, plain)
+    }
+
+    // Imports the copy the new key opened, running its canaries
+    fn verify(
+        &mut self,
+        key: &RotationKey,
+        ciphertext: &[u8],
+        plain: &[u8],
+    ) -> Result<RotationHash> {
+        let mut check = ciphertext.to_vec();
+        decrypt_model_in_place_with(key, &mut check)?;
+        if secure_storage::sha256(&check)? != secure_storage::sha256(plain)? {
+            residency::wipe_vec(&mut check);
+            trace_println!("[!] Re-encrypted model doesn't decrypt to the stored one");
+            return Err(ErrorKind::CorruptObject.into());
+        }
+        import_model(None, check)?;
+        secure_storage::sha256(ciphertext)
+    }
+
+    fn write(&mut self, ciphertext: &[u8]) -> Result<RotationHash> {
+        let rekeyed = secure_storage::write_rekeyed(ciphertext)?;
+        let hash = rekeyed.hash();
+        self.written = Some(rekeyed);
+        self.written_size = ciphertext.len();
+        Ok(hash)
+    }
+
+    fn discard_written(&mut self) -> Result<()> {
+        match self.written.take() {
+            Some(rekeyed) => secure_storage::discard_rekeyed(rekeyed),
+            None => Ok(()),
+        }
+    }
+
+    fn switch(&mut self) -> Result<()> {
+        let rekeyed = self.written.take().ok_or(ErrorKind::BadState)?;
+        secure_storage::switch_rekeyed(rekeyed)
+    }
+
+    fn stored_hash(&mut self) -> Result<Option<RotationHash>> {
+        secure_storage::active_model_hash()
+    }
+
+    fn import_key(&mut self, key: &RotationKey) -> Result<()> {
+        import_aes_key(key)
+    }
+
+    fn write_journal(&mut self, journal: &RotationJournal) -> Result<()> {
+        let mut bytes = journal.to_bytes();
+        let stored = secure_storage::store_rotation_journal(&bytes);
+        residency::wipe(&mut bytes);
+        stored
+    }
+
+    fn read_journal(&mut self) -> Result<Option<RotationJournal>> {
+        let Some(mut bytes) = secure_storage::load_rotation_journal()? else {
+            return Ok(None);
+        };
+        let journal = RotationJournal::from_bytes(&bytes);
+        residency::wipe_vec(&mut bytes);
+        if journal.is_none() {
+            // Nothing to finish it with; the stored model is whichever
+            // version the manifest names
+            trace_println!("[!] Malformed key rotation journal, deleting it");
+            secure_storage::delete_rotation_journal()?;
+        }
+        Ok(journal)
+    }
+
+    fn delete_journal(&mut self) -> Result<()> {
+        secure_storage::delete_rotation_journal()?;
+        Ok(())
+    }
+}
+
 fn ensure_secure_update_caller() -> Result<()> {
     let identity = ClientIdentity.get()?;
     if identity.login_type() != LoginType::TrustedApp {
@@ -195,7 +1180,7 @@ fn ensure_secure_update_caller() -> Result<()> {
 }
 
 fn invoke_export_aes_key(params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] Export AES key request received");
+    debug_println!("[+] Export AES key request received");
     ensure_secure_update_caller()?;
     let key = export_aes_key()?;
     let mut p0 = unsafe { params.0.as_memref()? };
@@ -212,49 +1197,842 @@ fn invoke_export_aes_key(params: &mut Parameters) -> Result<()> {
     Ok(())
 }
 
-fn invoke_begin_model_load(_params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] Begin model load");
+fn invoke_begin_model_load(params: &mut Parameters) -> Result<()> {
+    let (slot, expected) = match unsafe { params.0.as_value() } {
+        Ok(value) => (slot_index(value.a())?, value.b() as usize),
+        Err(_) => (0, 0),
+    };
+    debug_println!("[+] Begin model load into slot {}", slot);
     require_aes_key()?;
-    let mut buf = MODEL_BUF.lock();
-    buf.clear();
+    let mut pending = PENDING_LOAD.lock();
+    // A load in flight belongs to whoever began it; it is only dropped when
+    // taken or when the last session closes
+    let txid = pending.next_txid;
+    if let Err(err) = pending.state.begin(slot, expected, txid) {
+        return Err(bad_load_state(Command::BeginModelLoad, &mut params.0, err));
+    }
+    pending.next_txid = txid.wrapping_add(1);
+    drop(pending);
+    debug_println!("[+] Model load {} expects {} bytes", txid, expected);
+    // Provisioning gives the stored model another chance to load lazily
+    LAZY_LOAD_FAILURE.lock().take();
     Ok(())
 }
 
 fn invoke_push_encrypted_chunk(params: &mut Parameters) -> Result<()> {
     let mut p0 = unsafe { params.0.as_memref()? };
     let enc = p0.buffer();
-    if enc.is_empty() { return Ok(()); }
-    let mut buf = MODEL_BUF.lock();
-    let before = buf.len();
+    if enc.len() > MAX_PUSH_CHUNK_SIZE as usize {
+        trace_println!(
+            "[!] Chunk of {} bytes, at most {} accepted",
+            enc.len(),
+            MAX_PUSH_CHUNK_SIZE
+        );
+        return Err(ErrorKind::BadParameters.into());
+    }
+    let mut pending = PENDING_LOAD.lock();
+    let before = pending.state.received();
     // Append encrypted bytes as-is; decrypt once at finalize
-    buf.extend_from_slice(enc);
-    trace_println!("[+] Encrypted chunk appended: {} -> {}", before, buf.len());
+    if let Err(err) = pending.state.push(enc) {
+        return Err(bad_load_state(
+            Command::PushEncryptedChunk,
+            &mut params.1,
+            err,
+        ));
+    }
+    debug_println!(
+        "[+] Encrypted chunk appended: {} -> {}",
+        before,
+        pending.state.received()
+    );
     Ok(())
 }
 
-fn invoke_finalize_model_load(_params: &mut Parameters) -> Result<()> {
-    trace_println!("[+] Finalize model load");
+fn invoke_finalize_model_load(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Finalize model load");
+    heap_stats::reset();
     // Decrypt full encrypted buffer once
     require_aes_key()?;
-    let encrypted = {
-        let mut buf = MODEL_BUF.lock();
-        core::mem::take(&mut *buf)
-    };
-    trace_println!("[+] Decrypting accumulated encrypted model: {} bytes", encrypted.len());
-    let plain = decrypt_model_data(&encrypted)?;
-    trace_println!("[+] Decrypted model size: {} bytes", plain.len());
-    trace_println!("[+] Importing model with {} bytes...", plain.len());
-    let imported_model = match Model::import(&DEVICE, plain) {
+    let (slot, mut model) = take_pending_load(Command::FinalizeModelLoad, &mut params.0)?;
+    // Finalizing without pushing anything reloads the model kept in secure storage
+    if model.is_empty() {
+        debug_println!("[+] No chunks pushed, loading the stored model");
+        return load_stored_model(Some(params), slot);
+    }
+    if let Err(err) = check_heap(&mut params.0, load_footprint(model.len())) {
+        residency::wipe_vec(&mut model);
+        return Err(err);
+    }
+    let signature = model_signature::signature_param(&mut params.1);
+    if let Err(err) = model_signature::verify(&model, signature.as_deref()) {
+        residency::wipe_vec(&mut model);
+        return Err(err);
+    }
+    // The primary slot's model survives restarts. The ciphertext is stored
+    // before the buffer is decrypted in place, so running out of storage
+    // leaves the current model in place; a model that then fails to decrypt
+    // or install is dropped from storage again.
+    let provisioned = stats::host_time();
+    let stored = if slot == 0 {
+        match secure_storage::store_model_bytes(&model, provisioned) {
+            Ok(hash) => Some((hash, model.len())),
+            Err(err) => {
+                trace_println!("[!] Failed to store model: {:?}", err);
+                return Err(err);
+            }
+        }
+    } else {
+        None
+    };
+    debug_println!("[+] Decrypting accumulated encrypted model: {} bytes", model.len());
+    let installed = decrypt_model_in_place(&mut model).and_then(|()| {
+        debug_println!("[+] Decrypted model size: {} bytes", model.len());
+        heap_stats::log("decrypt");
+        // Usage counters follow the model contents, not a particular encryption
+        let model_hash = secure_storage::sha256(&model)?;
+        let plaintext_size = model.len();
+        install_model(Some(params), slot, model, model_hash)?;
+        Ok((model_hash, plaintext_size))
+    });
+    let (model_hash, plaintext_size) = match installed {
+        Ok(v) => v,
+        Err(err) => {
+            if stored.is_some() {
+                if let Err(err) = secure_storage::discard_active() {
+                    trace_println!("[!] Failed to drop stored model: {:?}", err);
+                }
+            }
+            return Err(err);
+        }
+    };
+    if let Some((encrypted_hash, encrypted_size)) = stored {
+        store_model_info(
+            encrypted_hash,
+            encrypted_size,
+            model_hash,
+            plaintext_size,
+            provisioned,
+        );
+    }
+    Ok(())
+}
+
+// Fails a load early with OutOfMemory, instead of dying mid-import, when
+// `needed` more heap bytes than are in use now don't fit the TA heap. The
+// bytes needed (a) and available (b) go to `reply` when the host passed a
+// value parameter.
+fn check_heap(reply: &mut Parameter, needed: usize) -> Result<()> {
+    common::check_heap(needed, heap_usage::available()).map_err(|shortfall| {
+        trace_println!("[!] {}", shortfall);
+        if let Ok(mut value) = unsafe { reply.as_value() } {
+            value.set_a(shortfall.needed.min(u32::MAX as usize) as u32);
+            value.set_b(shortfall.available.min(u32::MAX as usize) as u32);
+        }
+        shortfall.into()
+    })
+}
+
+// Records what the stored slot-0 model contains for status queries. The
+// record is only a cache, so failing to write it doesn't fail the load.
+fn store_model_info(
+    encrypted_hash: [u8; 32],
+    encrypted_size: usize,
+    model_hash: [u8; 32],
+    plaintext_size: usize,
+    provisioned: u64,
+) {
+    let arch = match MODELS.get(0) {
+        Some(model) => model.architecture(),
+        None => return,
+    };
+    let key_fingerprint = export_aes_key()
+        .and_then(|key| secure_storage::sha256(&key))
+        .ok()
+        .map(|hash| secure_storage::hex(&hash[..KEY_FINGERPRINT_SIZE]));
+    let info = {
+        let model_info = &MODEL_INFO.lock()[0];
+        StoredModelInfo {
+            model_hash: secure_storage::hex(&model_hash),
+            encrypted_hash: secure_storage::hex(&encrypted_hash),
+            plaintext_size: plaintext_size as u32,
+            encrypted_size: encrypted_size as u32,
+            arch,
+            name: model_info.name.clone(),
+            class_labels: model_info.class_labels.clone(),
+            key_fingerprint,
+            provisioned,
+        }
+    };
+    if let Err(err) = secure_storage::store_model_info(&info) {
+        trace_println!("[!] Failed to store model info: {:?}", err);
+    }
+}
+
+// Validates the pushed model and keeps it in secure storage as the staged
+// model, leaving the installed and active ones alone until it is committed
+fn invoke_stage_model(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Stage model");
+    require_aes_key()?;
+    let force = match unsafe { params.0.as_value() } {
+        Ok(value) => value.a() & STAGE_FORCE != 0,
+        Err(_) => false,
+    };
+    let (slot, mut model) = take_pending_load(Command::StageModel, &mut params.0)?;
+    if slot != 0 {
+        trace_println!("[!] Only the primary slot's model can be staged");
+        residency::wipe_vec(&mut model);
+        return Err(ErrorKind::BadParameters.into());
+    }
+    if model.is_empty() {
+        trace_println!("[!] No model pushed to stage");
+        return Err(ErrorKind::BadParameters.into());
+    }
+    // Staging decrypts a copy, the ciphertext is stored as it was pushed
+    let footprint = model.len() + load_footprint(model.len());
+    if let Err(err) = check_heap(&mut params.0, footprint) {
+        residency::wipe_vec(&mut model);
+        return Err(err);
+    }
+    let signature = model_signature::signature_param(&mut params.1);
+    if let Err(err) = model_signature::verify(&model, signature.as_deref()) {
+        residency::wipe_vec(&mut model);
+        return Err(err);
+    }
+    if !force && secure_storage::staged_model_info()?.is_some() {
+        trace_println!("[!] A model is already staged");
+        return Err(ErrorKind::AccessConflict.into());
+    }
+    let mut plain = model.clone();
+    decrypt_model_in_place(&mut plain)?;
+    let model_hash = secure_storage::sha256(&plain)?;
+    // Checked the way install would, then dropped again
+    import_model(Some(params), plain)?;
+    secure_storage::stage_model_bytes(&model, &model_hash, stats::host_time())?;
+    debug_println!("[+] Model staged: {} bytes", model.len());
+    Ok(())
+}
+
+// Installs the staged model into slot 0 and makes it the active stored one
+fn invoke_commit_model(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Commit staged model");
+    require_aes_key()?;
+    let staged = secure_storage::staged_model_info()?.ok_or_else(|| {
+        trace_println!("[!] No model staged");
+        ErrorKind::ItemNotFound
+    })?;
+    let mut model = secure_storage::staged_model_bytes()?.ok_or(ErrorKind::ItemNotFound)?;
+    decrypt_model_in_place(&mut model)?;
+    let model_hash = secure_storage::sha256(&model)?;
+    let plaintext_size = model.len();
+    install_model(Some(params), 0, model, model_hash)?;
+    let (encrypted_hash, encrypted_size) = secure_storage::commit_staged()?;
+    LAZY_LOAD_FAILURE.lock().take();
+    store_model_info(
+        encrypted_hash,
+        encrypted_size,
+        model_hash,
+        plaintext_size,
+        staged.staged,
+    );
+    Ok(())
+}
+
+fn invoke_discard_staged(_params: &mut Parameters) -> Result<()> {
+    if !secure_storage::discard_staged()? {
+        trace_println!("[!] No model staged");
+        return Err(ErrorKind::ItemNotFound.into());
+    }
+    debug_println!("[+] Staged model discarded");
+    Ok(())
+}
+
+// Re-encrypts the stored model for the host to read back. The stored
+// ciphertext isn't handed out as it is: decrypting it first proves the
+// current key opens it, and the export gets a fresh IV.
+fn invoke_begin_model_export(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Begin model export");
+    require_aes_key()?;
+    let mut model = secure_storage::load_model_bytes()?.ok_or_else(|| {
+        trace_println!("[!] No stored model to export");
+        ErrorKind::ItemNotFound
+    })?;
+    decrypt_model_in_place(&mut model)?;
+    let encrypted = encrypt_model_data(&model);
+    residency::wipe_vec(&mut model);
+    let encrypted = encrypted?;
+    let hash = secure_storage::sha256(&encrypted)?;
+    unsafe { params.0.as_value()? }.set_a(encrypted.len() as u32);
+    copy_to_output(&mut params.1, &hash)?;
+    debug_println!("[+] Model export ready: {} bytes", encrypted.len());
+    *PENDING_EXPORT.lock() = Some(encrypted);
+    Ok(())
+}
+
+fn invoke_read_encrypted_chunk(params: &mut Parameters) -> Result<()> {
+    let range = unsafe { params.0.as_value()? };
+    let (offset, len) = (range.a() as usize, range.b() as usize);
+    let export = PENDING_EXPORT.lock();
+    let export = export.as_ref().ok_or_else(|| {
+        trace_println!("[!] No model export in progress");
+        ErrorKind::BadState
+    })?;
+    let chunk = export_chunk(offset, len, export.len()).ok_or_else(|| {
+        trace_println!(
+            "[!] Invalid export range: {} bytes at {} of {}",
+            len,
+            offset,
+            export.len()
+        );
+        ErrorKind::BadParameters
+    })?;
+    Ok(copy_to_output(&mut params.1, &export[chunk])?)
+}
+
+fn invoke_end_model_export(_params: &mut Parameters) -> Result<()> {
+    if PENDING_EXPORT.lock().take().is_some() {
+        debug_println!("[+] Model export finished");
+    }
+    Ok(())
+}
+
+// Decrypts the model kept in secure storage and installs it into `slot`
+fn load_stored_model(params: Option<&mut Parameters>, slot: usize) -> Result<()> {
+    require_aes_key()?;
+    let mut model = secure_storage::load_model_bytes()?.ok_or(ErrorKind::ItemNotFound)?;
+    debug_println!("[+] Decrypting stored model: {} bytes", model.len());
+    decrypt_model_in_place(&mut model)?;
+    let model_hash = secure_storage::sha256(&model)?;
+    install_model(params, slot, model, model_hash)
+}
+
+// Installs the stored model into slot 0 on the first inference after a
+// restart. The lock is held for the whole load, so concurrent inferences wait
+// for it instead of loading again, and a failure is remembered until the
+// next provisioning so later inferences fail fast.
+fn ensure_stored_model_loaded() -> Result<()> {
+    let mut failure = LAZY_LOAD_FAILURE.lock();
+    if MODELS.is_loaded(0) {
+        return Ok(());
+    }
+    if let Some(kind) = *failure {
+        error_detail::set(format_args!(
+            "loading the stored model failed earlier ({:?}); load or roll back a model",
+            kind
+        ));
+        return Err(kind.into());
+    }
+    if !secure_storage::model_bytes_exist()? {
+        return Ok(());
+    }
+    debug_println!("[+] Slot 0 is empty, loading the stored model");
+    let start = stats::system_time();
+    if let Err(err) = load_stored_model(None, 0) {
+        trace_println!("[!] Loading the stored model failed: {:?}", err);
+        error_detail::set(format_args!(
+            "loading the stored model failed: {:?}",
+            err.kind()
+        ));
+        *failure = Some(err.kind());
+        return Err(err);
+    }
+    let elapsed = stats::system_time().saturating_sub(start);
+    debug_println!("[+] Stored model loaded in {}", elapsed);
+    Ok(())
+}
+
+// Imports a decrypted model container and checks it against the class count,
+// memory budget and canary images
+fn import_model(
+    params: Option<&mut Parameters>,
+    plain: Vec<u8>,
+) -> Result<(NoStdModel, Option<ModelMetadata>, usize)> {
+    // Where the estimate or failing canary go back to the host, if it asked
+    let mut reply = params.and_then(|params| unsafe { params.0.as_value() }.ok());
+    let (metadata, format, record) = match split_container(plain) {
+        Ok(v) => v,
+        Err(ModelError::Metadata(ContainerError::UnsupportedCodec(codec))) => {
+            trace_println!("[!] Record codec {} not compiled into this TA", codec);
+            error_detail::set(format_args!(
+                "record codec {} not compiled into this TA",
+                codec
+            ));
+            return Err(ErrorKind::NotSupported.into());
+        }
+        Err(err) => {
+            trace_println!("[!] Invalid model container: {}", err);
+            return Err(model_error(err));
+        }
+    };
+    if !format.is_supported() {
+        trace_println!("[!] Record format {:?} not compiled into this TA", format);
+        error_detail::set(format_args!(
+            "record format {:?} not compiled into this TA",
+            format
+        ));
+        return Err(ErrorKind::NotSupported.into());
+    }
+    debug_println!("[+] Importing {:?} model with {} bytes...", format, record.len());
+    let imported_model = match Model::import_as(&DEVICE, record, format) {
+        Ok(m) => m,
+        Err(err) => {
+            trace_println!("[!] Model import failed: {}", err);
+            return Err(model_error(err));
+        }
+    };
+    heap_stats::log("import");
+    let num_classes = imported_model.num_classes();
+    let declared = metadata.as_ref().map_or(num_classes, |m| m.num_classes());
+    if num_classes == 0 || num_classes > MAX_NUM_CLASSES || num_classes != declared {
+        trace_println!(
+            "[!] Unsupported class count: model {}, metadata {}",
+            num_classes,
+            declared
+        );
+        error_detail::set(format_args!(
+            "unsupported class count: model {}, metadata {}",
+            num_classes, declared
+        ));
+        return Err(ErrorKind::BadFormat.into());
+    }
+    let num_params = imported_model.num_params();
+    let estimate = imported_model.memory_estimate(ESTIMATE_BATCH_SIZE);
+    debug_println!(
+        "[+] Model parameters: {}, estimated memory: {} bytes (budget {})",
+        num_params,
+        estimate,
+        MODEL_MEMORY_BUDGET
+    );
+    if estimate > MODEL_MEMORY_BUDGET {
+        trace_println!("[!] Model exceeds the memory budget");
+        error_detail::set(format_args!(
+            "model needs ~{} bytes, the TA budget is {}",
+            estimate, MODEL_MEMORY_BUDGET
+        ));
+        if let Some(p0) = reply.as_mut() {
+            p0.set_a(estimate.min(u32::MAX as usize) as u32);
+            p0.set_b(MODEL_MEMORY_BUDGET.min(u32::MAX as usize) as u32);
+        }
+        return Err(ErrorKind::OutOfMemory.into());
+    }
+    let canaries = metadata.as_ref().map_or(&[][..], |m| &m.canary[..]);
+    if canaries.len() > MAX_CANARIES {
+        trace_println!(
+            "[!] {} canary images, at most {}",
+            canaries.len(),
+            MAX_CANARIES
+        );
+        return Err(ErrorKind::BadFormat.into());
+    }
+    let normalization = metadata
+        .as_ref()
+        .map(ModelMetadata::normalization)
+        .unwrap_or_default();
+    if let Some(index) = imported_model.failing_canary(&DEVICE, canaries, &normalization) {
+        trace_println!("[!] Canary {} misclassified, refusing the model", index);
+        if let Some(p0) = reply.as_mut() {
+            p0.set_b(index as u32);
+        }
+        return Err(Error::from_raw_error(ERROR_CANARY_MISMATCH));
+    }
+    if !canaries.is_empty() {
+        debug_println!(
+            "[+] {} canary images classified as expected",
+            canaries.len()
+        );
+    }
+    if let Some(p0) = reply.as_mut() {
+        p0.set_b(canaries.len() as u32);
+    }
+    Ok((imported_model, metadata, num_classes))
+}
+
+// Reports a refused model with the return code of its error, which the host
+// explains, and its message as the error detail
+fn model_error(err: ModelError) -> Error {
+    error_detail::set(format_args!("{}", err));
+    Error::from_raw_error(err.code())
+}
+
+// Imports a decrypted model container and installs it into `slot`
+fn install_model(
+    params: Option<&mut Parameters>,
+    slot: usize,
+    plain: Vec<u8>,
+    model_hash: [u8; 32],
+) -> Result<()> {
+    let (imported_model, metadata, num_classes) = import_model(params, plain)?;
+    let license = metadata.as_ref().and_then(|m| m.license.clone());
+    let info = match metadata {
+        Some(metadata) => {
+            debug_println!("[+] Model metadata: {}", metadata.name);
+            ModelInfo {
+                normalization: metadata.normalization(),
+                name: Some(metadata.name),
+                class_labels: metadata.class_labels,
+                num_classes,
+                canaries: metadata.canary.len(),
+            }
+        }
+        None => ModelInfo {
+            num_classes,
+            ..ModelInfo::EMPTY
+        },
+    };
+    // Only the swap happens under the lock; the replaced model is released
+    // after it, or by the last inference still using it
+    let replaced = MODELS.install(slot, imported_model, || MODEL_INFO.lock()[slot] = info);
+    drop(replaced);
+    result_cache::clear();
+    license::install(slot, license);
+    stats::install(slot, model_hash, num_classes);
+    debug_println!("[+] Model loaded and installed into slot {}", slot);
+    heap_stats::log("finalize");
+    Ok(())
+}
+
+// Reinstalls the previous stored version into slot 0 and makes it the active
+// one; the version rolled back from is discarded
+fn invoke_rollback_model(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Rollback model");
+    require_aes_key()?;
+    let mut model = secure_storage::previous_model_bytes()?.ok_or(ErrorKind::ItemNotFound)?;
+    let (encrypted_hash, encrypted_size) = (secure_storage::sha256(&model)?, model.len());
+    decrypt_model_in_place(&mut model)?;
+    let model_hash = secure_storage::sha256(&model)?;
+    let plaintext_size = model.len();
+    install_model(Some(params), 0, model, model_hash)?;
+    secure_storage::commit_rollback()?;
+    LAZY_LOAD_FAILURE.lock().take();
+    let provisioned = secure_storage::model_history()?
+        .first()
+        .map_or(0, |version| version.provisioned);
+    store_model_info(
+        encrypted_hash,
+        encrypted_size,
+        model_hash,
+        plaintext_size,
+        provisioned,
+    );
+    debug_println!("[+] Rolled back to the previous model");
+    Ok(())
+}
+
+fn invoke_model_history(params: &mut Parameters) -> Result<()> {
+    let history = secure_storage::model_history()?;
+    let encoded = serde_json::to_vec(&history).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+// Migration escape hatch: adopts a stored model copied from another device.
+// Only the secure update TA may do this, after it has re-provisioned the key.
+fn invoke_rebind_storage(_params: &mut Parameters) -> Result<()> {
+    ensure_secure_update_caller()?;
+    if !secure_storage::rebind_to_device()? {
+        return Err(ErrorKind::ItemNotFound.into());
+    }
+    debug_println!("[+] Stored model re-bound to this device");
+    Ok(())
+}
+
+// One byte per known-answer vector, 1 when it passed
+fn invoke_run_self_test(params: &mut Parameters) -> Result<()> {
+    let results: Vec<u8> = self_test::run().into_iter().map(u8::from).collect();
+    Ok(copy_to_output(&mut params.0, &results)?)
+}
+
+fn invoke_set_residency_policy(params: &mut Parameters) -> Result<()> {
+    let value = unsafe { params.0.as_value()? };
+    let policy = ResidencyPolicy::try_from(value.a()).map_err(|_| {
+        trace_println!("[!] Unknown residency policy: {}", value.a());
+        ErrorKind::BadParameters
+    })?;
+    debug_println!("[+] Residency policy: {:?}", policy);
+    residency::set_policy(policy);
+    Ok(())
+}
+
+// Value a = entries the result cache keeps, 0 turning it off
+fn invoke_set_result_cache(params: &mut Parameters) -> Result<()> {
+    let entries = unsafe { params.0.as_value()? }.a();
+    if entries > MAX_RESULT_CACHE_ENTRIES {
+        trace_println!("[!] Result cache of {} entries is too large", entries);
+        return Err(ErrorKind::BadParameters.into());
+    }
+    debug_println!("[+] Result cache entries: {}", entries);
+    result_cache::configure(entries as usize);
+    Ok(())
+}
+
+fn invoke_set_model_verify_key(params: &mut Parameters) -> Result<()> {
+    let mut p0 = unsafe { params.0.as_memref()? };
+    model_signature::set_verify_key(p0.buffer())?;
+    debug_println!("[+] Model verify key provisioned");
+    Ok(())
+}
+
+// Value a = active slot, b = shadow slot; without the parameter shadowing
+// is turned off
+fn invoke_set_shadow(params: &mut Parameters) -> Result<()> {
+    let slots = match unsafe { params.0.as_value() } {
+        Ok(value) => {
+            let (active, shadow) = (slot_index(value.a())?, slot_index(value.b())?);
+            if active == shadow {
+                trace_println!("[!] Slot {} can't shadow itself", active);
+                return Err(ErrorKind::BadParameters.into());
+            }
+            if installed_model(shadow).is_none() {
+                trace_println!("[!] Shadow slot {} is empty", shadow);
+                return Err(ErrorKind::ItemNotFound.into());
+            }
+            Some((active, shadow))
+        }
+        Err(_) => None,
+    };
+    debug_println!("[+] Shadow slots: {:?}", slots);
+    shadow::configure(slots);
+    Ok(())
+}
+
+fn invoke_get_shadow_report(params: &mut Parameters) -> Result<()> {
+    let reset = match unsafe { params.1.as_value() } {
+        Ok(value) => value.a() & SHADOW_REPORT_RESET != 0,
+        Err(_) => false,
+    };
+    let report = shadow::report(reset);
+    let encoded = serde_json::to_vec(&report).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+fn invoke_get_schema_version(params: &mut Parameters) -> Result<()> {
+    let encoded = serde_json::to_vec(&schema::status()?).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+fn invoke_set_manage_policy(params: &mut Parameters) -> Result<()> {
+    let mut p0 = unsafe { params.0.as_memref()? };
+    session_role::set_policy(p0.buffer())
+}
+
+// Only the hash prefix leaves the TA; the exported key is wiped right after
+// hashing it
+fn invoke_get_key_fingerprint(params: &mut Parameters) -> Result<()> {
+    if !has_aes_key()? {
+        return Err(ErrorKind::ItemNotFound.into());
+    }
+    let mut key = export_aes_key()?;
+    let hash = secure_storage::sha256(&key);
+    residency::wipe(&mut key);
+    let hash = hash?;
+    let fingerprint = &hash[..KEY_FINGERPRINT_SIZE];
+    Ok(copy_to_output(&mut params.0, fingerprint)?)
+}
+
+fn invoke_get_build_manifest(params: &mut Parameters) -> Result<()> {
+    let encoded =
+        serde_json::to_vec(&build_manifest::manifest()).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+// The id tags every following log line, including those of other sessions,
+// and is echoed back so the host knows this TA took it
+fn invoke_set_trace_id(params: &mut Parameters) -> Result<()> {
+    let mut value = unsafe { params.0.as_value()? };
+    let id = join_trace_id(value.a(), value.b());
+    trace_id::set(id);
+    debug_println!("[+] Trace id set");
+    let (low, high) = split_trace_id(trace_id::get());
+    value.set_a(low);
+    value.set_b(high);
+    Ok(())
+}
+
+// Finishes a begin/push sequence whose payload is a layer patch: replaces one
+// layer of the model already installed in the load slot.
+fn invoke_patch_model(params: &mut Parameters) -> Result<()> {
+    debug_println!("[+] Patch model");
+    require_aes_key()?;
+    let (slot, mut plain) = take_pending_load(Command::PatchModel, &mut params.0)?;
+    // Patches carry no signature, so they can't be trusted once models need one
+    if model_signature::is_provisioned()? {
+        trace_println!("[!] Layer patches are refused while a verify key is provisioned");
+        residency::wipe_vec(&mut plain);
+        return Err(ErrorKind::SignatureInvalid.into());
+    }
+    decrypt_model_in_place(&mut plain)?;
+    let (layer, record) = match split_patch(plain) {
+        Ok(v) => v,
+        Err(err) => {
+            trace_println!("[!] Invalid layer patch: {}", err);
+            return Err(model_error(err));
+        }
+    };
+    let model = MODELS.get(slot).ok_or(ErrorKind::ItemNotFound)?;
+    // The installed model is only replaced once the patch fully applies
+    let patched = match model.apply_layer(&DEVICE, &layer, record) {
         Ok(m) => m,
-        Err(_err) => {
-            trace_println!("[!] Model import failed");
+        Err(PatchError::UnknownLayer) => {
+            trace_println!("[!] Unknown layer: {}", layer.as_str());
             return Err(ErrorKind::BadParameters.into());
         }
+        Err(PatchError::ShapeMismatch) => {
+            trace_println!("[!] Patch for {} does not fit the model", layer.as_str());
+            return Err(Error::from_raw_error(ERROR_MODEL_SHAPE_MISMATCH));
+        }
+        Err(PatchError::Record(err)) => {
+            trace_println!("[!] Invalid record for {}: {}", layer.as_str(), err);
+            return Err(model_error(err));
+        }
+    };
+    // The patch was applied without the lock; don't undo an install that
+    // happened meanwhile
+    if MODELS.replace(slot, &model, patched).is_err() {
+        trace_println!("[!] Slot {} was replaced while patching", slot);
+        return Err(ErrorKind::AccessConflict.into());
+    }
+    // The patched model keeps the hash its results were cached under
+    result_cache::clear();
+    debug_println!("[+] Patched layer {} in slot {}", layer.as_str(), slot);
+    Ok(())
+}
+
+fn invoke_get_class_labels(params: &mut Parameters) -> Result<()> {
+    let slot = slot_index(unsafe { params.1.as_value()? }.a())?;
+    let encoded = encode_class_labels(&MODEL_INFO.lock()[slot].class_labels).ok_or_else(|| {
+        trace_println!(
+            "[!] Class labels of slot {} exceed {} bytes",
+            slot,
+            MAX_CLASS_LABELS_SIZE
+        );
+        ErrorKind::ExcessData
+    })?;
+    debug_println!("[+] Class labels of slot {}: {} bytes", slot, encoded.len());
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+fn invoke_get_last_crash(params: &mut Parameters) -> Result<()> {
+    let clear = match unsafe { params.1.as_value() } {
+        Ok(value) => value.a() & CRASH_REPORT_CLEAR != 0,
+        Err(_) => false,
+    };
+    let report = crash::last_crash(clear)?.ok_or(ErrorKind::ItemNotFound)?;
+    debug_println!("[+] Returning crash report (cleared: {})", clear);
+    Ok(copy_to_output(&mut params.0, &report)?)
+}
+
+fn invoke_model_status(params: &mut Parameters) -> Result<()> {
+    let slot = match unsafe { params.1.as_value() } {
+        Ok(value) => slot_index(value.a())?,
+        Err(_) => 0,
+    };
+    debug_println!("[+] Model status request for slot {}", slot);
+    let model = MODELS.get(slot);
+    let status = {
+        let info = &MODEL_INFO.lock()[slot];
+        ModelStatus {
+            loaded: model.is_some(),
+            name: info.name.clone(),
+            class_labels: info.class_labels.clone(),
+            num_classes: info.num_classes,
+            stored: None,
+            staged: None,
+            license: license::status(slot),
+            canaries: info.canaries,
+            normalization: info.normalization,
+            batch_hint: model.and_then(|model| model.batch_hint(MODEL_MEMORY_BUDGET)),
+        }
     };
-    let mut model = MODEL.lock();
-    model.replace(imported_model);
-    trace_println!("[+] Model loaded and installed");
+    let status = match slot {
+        0 => ModelStatus {
+            stored: secure_storage::load_model_info().unwrap_or_else(|err| {
+                trace_println!("[!] Failed to read model info: {:?}", err);
+                None
+            }),
+            staged: secure_storage::staged_model_info().unwrap_or_else(|err| {
+                trace_println!("[!] Failed to read staged model: {:?}", err);
+                None
+            }),
+            ..status
+        },
+        _ => status,
+    };
+    let encoded = serde_json::to_vec(&status).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+fn invoke_list_storage(params: &mut Parameters) -> Result<()> {
+    let objects = secure_storage::list_objects()?;
+    debug_println!("[+] Listing {} storage objects", objects.len());
+    let encoded = serde_json::to_vec(&objects).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+fn invoke_delete_storage_object(params: &mut Parameters) -> Result<()> {
+    let mut p0 = unsafe { params.0.as_memref()? };
+    let id = p0.buffer();
+    if !is_deletable_storage_id(id) {
+        trace_println!("[!] Refusing to delete storage object outside the allowed prefixes");
+        return Err(ErrorKind::AccessDenied.into());
+    }
+    if !secure_storage::delete_storage_object(id)? {
+        return Err(ErrorKind::ItemNotFound.into());
+    }
+    debug_println!("[+] Deleted storage object");
+    // Any model piece belongs to the stored slot-0 model, which is gone now;
+    // drop the in-memory copy as well
+    if id.starts_with(b"ta_model.") {
+        MODELS.remove(0, || MODEL_INFO.lock()[0] = ModelInfo::EMPTY);
+        debug_println!("[+] Slot 0 unloaded");
+    }
+    Ok(())
+}
+
+fn invoke_get_persistent_stats(params: &mut Parameters) -> Result<()> {
+    let slot = match unsafe { params.1.as_value() } {
+        Ok(value) => slot_index(value.a())?,
+        Err(_) => 0,
+    };
+    let usage = ModelUsage {
+        heap: heap_usage::usage(),
+        ..stats::usage(slot).ok_or(ErrorKind::ItemNotFound)?
+    };
+    let encoded = serde_json::to_vec(&usage).map_err(|_| ErrorKind::Generic)?;
+    Ok(copy_to_output(&mut params.0, &encoded)?)
+}
+
+fn invoke_reset_persistent_stats(params: &mut Parameters) -> Result<()> {
+    let slot = match unsafe { params.0.as_value() } {
+        Ok(value) => slot_index(value.a())?,
+        Err(_) => 0,
+    };
+    if !stats::reset(slot)? {
+        return Err(ErrorKind::ItemNotFound.into());
+    }
+    debug_println!("[+] Usage counters of slot {} reset", slot);
+    Ok(())
+}
+
+// p0: value input, a = bytes to store; p1: value output, a = bytes in use,
+// b = storage quota (0 when unknown); p2: value output, a = 1 when storing
+// would be attempted
+fn invoke_storage_preflight(params: &mut Parameters) -> Result<()> {
+    let requested = unsafe { params.0.as_value()? }.a() as usize;
+    let used = secure_storage::used_bytes()?;
+    // The active and previous models stay until the new one is in place
+    let quota = core::num::NonZeroUsize::new(STORAGE_QUOTA).map(|quota| quota.get());
+    let fits = common::storage_fits(used, requested, quota);
+    debug_println!(
+        "[+] Storage preflight: {} bytes requested, {} in use, fits: {}",
+        requested,
+        used,
+        fits
+    );
+    let mut usage = unsafe { params.1.as_value()? };
+    usage.set_a(used.min(u32::MAX as usize) as u32);
+    usage.set_b(STORAGE_QUOTA.min(u32::MAX as usize) as u32);
+    unsafe { params.2.as_value()? }.set_a(fits as u32);
     Ok(())
 }
 
+include!(concat!(env!("OUT_DIR"), "/memory_budget.rs"));
+include!(concat!(env!("OUT_DIR"), "/storage_quota.rs"));
 include!(concat!(env!("OUT_DIR"), "/user_ta_header.rs"));
@@ -0,0 +1,84 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Provenance checks of pushed models. Once an RSA verify key is provisioned
+// (`Command::SetModelVerifyKey`), finalize and stage only take models that
+// carry a signature of `model_signature_header` and their ciphertext by the
+// matching private key, checked through the key manager's `RsaVerify`; this
+// holds whoever knows the AES key. The key is written once, so a session
+// can't swap it for its own. Without a key, models load unsigned as before.
+
+use alloc::vec::Vec;
+
+use common::check_model_signature;
+use optee_utee::{AlgorithmId, Digest, ErrorKind, Parameter, Result};
+use proto::inference::model_signature_header;
+use proto::key_manager::parse_rsa_public_key;
+
+use crate::key_manager;
+use crate::secure_storage::{read_object, write_object};
+
+const VERIFY_KEY_OBJECT_ID: &[u8] = b"ta_verify_key";
+
+/// Provisions `der` as the verify key. Setting the key already provisioned
+/// again succeeds; any other key fails with AccessConflict.
+pub fn set_verify_key(der: &[u8]) -> Result<()> {
+    if parse_rsa_public_key(der).is_none() {
+        trace_println!("[!] Verify key is not a supported RSA public key");
+        return Err(ErrorKind::BadParameters.into());
+    }
+    match read_object(VERIFY_KEY_OBJECT_ID)? {
+        Some(current) if current == der => Ok(()),
+        Some(_) => {
+            trace_println!("[!] A different verify key is already provisioned");
+            Err(ErrorKind::AccessConflict.into())
+        }
+        None => write_object(VERIFY_KEY_OBJECT_ID, der),
+    }
+}
+
+/// Whether models need a signature.
+pub fn is_provisioned() -> Result<bool> {
+    Ok(read_object(VERIFY_KEY_OBJECT_ID)?.is_some())
+}
+
+/// Checks `signature` of the pushed `ciphertext` against the verify key,
+/// failing with SignatureInvalid when it is missing or wrong. Passes
+/// anything while no key is provisioned.
+pub fn verify(ciphertext: &[u8], signature: Option<&[u8]>) -> Result<()> {
+    let key = read_object(VERIFY_KEY_OBJECT_ID)?;
+    if key.is_none() && signature.is_some() {
+        debug_println!("[+] No verify key provisioned, signature ignored");
+    }
+    check_model_signature(key.as_deref(), signature, |key, signature| {
+        let digest = Digest::allocate(AlgorithmId::Sha256)?;
+        digest.update(&model_signature_header(ciphertext.len()));
+        let mut hash = [0u8; 32];
+        digest.do_final(ciphertext, &mut hash)?;
+        key_manager::rsa_verify(key, &hash, signature)
+    })
+    .map_err(|err| {
+        trace_println!("[!] {}", err);
+        err.into()
+    })
+}
+
+/// Copies the signature memref the host passed in `param`, if any.
+pub fn signature_param(param: &mut Parameter) -> Option<Vec<u8>> {
+    let mut memref = unsafe { param.as_memref() }.ok()?;
+    Some(memref.buffer().to_vec())
+}
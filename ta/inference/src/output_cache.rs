@@ -0,0 +1,114 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Outputs of the last `FLAG_OUTPUT_WINDOW` batch. A host whose results don't
+// fit one shared memory buffer sends the same request once per window; the
+// first runs the model and the others are answered from here. One batch is
+// kept for all sessions, keyed by a hash of the request header and images and
+// by the model that ran it, so new input or a reinstalled slot misses. It is
+// dropped with the resident models, and batches larger than
+// `MAX_CACHED_SIZE` are not kept at all: each window then runs the batch
+// again.
+
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::ops::Range;
+use optee_utee::{AlgorithmId, Digest, Result};
+use proto::inference::{InferenceRequestHeader, INPUT_HASH_SIZE};
+use proto::wire;
+use spin::Mutex;
+
+use crate::ta_local::TaLocal;
+use crate::NoStdModel;
+
+// Leaves most of the TA heap to the model and the forward pass
+const MAX_CACHED_SIZE: usize = 2 * 1024 * 1024;
+
+/// Labels, probabilities (`num_classes` per image) and, when asked for,
+/// input hashes of a batch.
+pub struct BatchOutputs {
+    pub labels: Vec<u8>,
+    pub probs: Vec<f32>,
+    pub hashes: Vec<u8>,
+}
+
+impl BatchOutputs {
+    fn size(&self) -> usize {
+        self.labels.len() + self.probs.len() * size_of::<f32>() + self.hashes.len()
+    }
+
+    /// Outputs of the images in `range`, which must lie in the batch.
+    pub fn window(&self, range: Range<usize>, num_classes: usize) -> BatchOutputs {
+        let hashes = if self.hashes.is_empty() {
+            Vec::new()
+        } else {
+            self.hashes[range.start * INPUT_HASH_SIZE..range.end * INPUT_HASH_SIZE].to_vec()
+        };
+        BatchOutputs {
+            labels: self.labels[range.clone()].to_vec(),
+            probs: self.probs[range.start * num_classes..range.end * num_classes].to_vec(),
+            hashes,
+        }
+    }
+}
+
+struct Entry {
+    key: [u8; 32],
+    // Weak so a cached batch doesn't keep a replaced model resident, and a
+    // new model can't be mistaken for the old one at the same address
+    model: Weak<NoStdModel>,
+    outputs: Arc<BatchOutputs>,
+}
+
+// `Entry` holds a model handle, which isn't `Sync`
+static CACHE: TaLocal<Mutex<Option<Entry>>> = TaLocal::new(Mutex::new(None));
+
+/// Identifies a batch. The output window follows the header, so the windows
+/// of one request share a key.
+pub fn key(header: &InferenceRequestHeader, images: &[u8]) -> Result<[u8; 32]> {
+    let digest = Digest::allocate(AlgorithmId::Sha256)?;
+    digest.update(wire::bytes_of(header));
+    let mut key = [0u8; 32];
+    digest.do_final(images, &mut key)?;
+    Ok(key)
+}
+
+pub fn get(model: &Arc<NoStdModel>, key: &[u8; 32]) -> Option<Arc<BatchOutputs>> {
+    let cache = CACHE.lock();
+    let entry = cache.as_ref()?;
+    if entry.key != *key || !Weak::ptr_eq(&entry.model, &Arc::downgrade(model)) {
+        return None;
+    }
+    Some(entry.outputs.clone())
+}
+
+/// Replaces the cached batch, or just drops it when `outputs` is too large
+/// to keep.
+pub fn put(model: &Arc<NoStdModel>, key: [u8; 32], outputs: Arc<BatchOutputs>) {
+    let entry = (outputs.size() <= MAX_CACHED_SIZE).then(|| Entry {
+        key,
+        model: Arc::downgrade(model),
+        outputs,
+    });
+    let replaced = core::mem::replace(&mut *CACHE.lock(), entry);
+    drop(replaced);
+}
+
+pub fn clear() {
+    let replaced = CACHE.lock().take();
+    drop(replaced);
+}
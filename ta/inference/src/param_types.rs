@@ -0,0 +1,86 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Checks the parameter types the host passed against the signature each
+// command declares in `Command::signature`, before its handler touches the
+// parameters.
+
+use optee_utee::{ErrorKind, ParamType, Parameter, Parameters, Result};
+use proto::inference::{self, Command, ParamUse};
+
+fn wire_type(param_type: &ParamType) -> inference::ParamType {
+    match param_type {
+        ParamType::None => inference::ParamType::None,
+        ParamType::ValueInput => inference::ParamType::ValueInput,
+        ParamType::ValueOutput => inference::ParamType::ValueOutput,
+        ParamType::ValueInout => inference::ParamType::ValueInout,
+        ParamType::MemrefInput => inference::ParamType::MemrefInput,
+        ParamType::MemrefOutput => inference::ParamType::MemrefOutput,
+        ParamType::MemrefInout => inference::ParamType::MemrefInout,
+    }
+}
+
+fn name(param_type: &ParamType) -> &'static str {
+    match param_type {
+        ParamType::None => "none",
+        ParamType::ValueInput => "value input",
+        ParamType::ValueOutput => "value output",
+        ParamType::ValueInout => "value inout",
+        ParamType::MemrefInput => "memref input",
+        ParamType::MemrefOutput => "memref output",
+        ParamType::MemrefInout => "memref inout",
+    }
+}
+
+/// Fails with BadParameters, logging the index of the first offending
+/// parameter, unless the types the host passed match the signature of `cmd`.
+pub fn check(cmd: Command, params: &Parameters) -> Result<()> {
+    let types = [
+        &params.0.param_type,
+        &params.1.param_type,
+        &params.2.param_type,
+        &params.3.param_type,
+    ];
+    if let Some(index) = cmd.mistyped_param(types.map(wire_type)) {
+        trace_println!(
+            "[!] {:?}: unexpected {} in parameter {}",
+            cmd,
+            name(types[index]),
+            index
+        );
+        return Err(ErrorKind::BadParameters.into());
+    }
+    Ok(())
+}
+
+/// The parameter a failing `cmd` writes its error detail to: parameter 3,
+/// for the commands that keep it for their detail and have no output memref
+/// of their own, when the host passed a buffer there. Inference results and
+/// the sizes reported with them are never overwritten.
+pub fn detail_param(cmd: Command, params: &mut Parameters) -> Option<&mut Parameter> {
+    let reports_detail = matches!(
+        cmd,
+        Command::FinalizeModelLoad
+            | Command::PatchModel
+            | Command::StageModel
+            | Command::CommitModel
+            | Command::RollbackModel
+            | Command::DeleteStorageObject
+    );
+    let param = &mut params.3;
+    (reports_detail && ParamUse::MemrefOut.accepts(wire_type(&param.param_type))).then_some(param)
+}
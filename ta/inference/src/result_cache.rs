@@ -0,0 +1,71 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// The TA's `common::ResultCache`. Off until the host sizes it with
+// `Command::SetResultCache`; the size and the entries are shared by all
+// sessions, and are flushed whenever a model is installed or dropped.
+
+use alloc::vec::Vec;
+use common::{CachedOutputs, ResultCache};
+use optee_utee::{AlgorithmId, Digest, Result};
+use proto::inference::Normalization;
+use proto::Image;
+use spin::Mutex;
+
+static CACHE: Mutex<ResultCache> = Mutex::new(ResultCache::new());
+
+/// Keeps up to `capacity` results from now on, 0 turning the cache off.
+/// Cached results are dropped either way.
+pub fn configure(capacity: usize) {
+    CACHE.lock().configure(capacity);
+}
+
+pub fn enabled() -> bool {
+    CACHE.lock().enabled()
+}
+
+/// Identifies the result of `image` run through the model with `model_hash`.
+pub fn key(
+    model_hash: &[u8; 32],
+    normalization: &Normalization,
+    temperature: f32,
+    image: &Image,
+) -> Result<[u8; 32]> {
+    let digest = Digest::allocate(AlgorithmId::Sha256)?;
+    digest.update(&common::result_key_prefix(
+        model_hash,
+        normalization,
+        temperature,
+    ));
+    let mut key = [0u8; 32];
+    digest.do_final(image.as_bytes(), &mut key)?;
+    Ok(key)
+}
+
+/// `common::run_cached` over the TA's cache.
+pub fn run(
+    keys: &[[u8; 32]],
+    num_classes: usize,
+    run: impl FnOnce(&[usize]) -> Result<(Vec<u8>, Vec<f32>)>,
+) -> Result<CachedOutputs> {
+    common::run_cached(&CACHE, keys, num_classes, run)
+}
+
+/// Drops the cached results, keeping the configured size.
+pub fn clear() {
+    CACHE.lock().clear();
+}
@@ -0,0 +1,134 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Layout version of the TA's secure storage.
+//
+// `ta_schema` holds the version the stored objects are laid out in, the
+// version they were last migrated from and the trusted time of that
+// migration. The first session of a TA instance checks it before anything
+// else touches storage:
+//
+// - storage without the record predates it and is version 1, unless it
+//   holds no model at all, in which case there is nothing to migrate;
+// - an older version is migrated one step at a time through `MIGRATIONS`,
+//   the record being rewritten after every step so an interrupted upgrade
+//   resumes where it stopped (each step is safe to repeat);
+// - a newer version was written by a TA this one was rolled back from, and
+//   every session is refused with `ERROR_STORAGE_DOWNGRADE` rather than
+//   misreading it.
+//
+// Versions:
+// 1. Anything written before the record existed. The model may still be in
+//    the flat layout of the first releases.
+// 2. Models in generations under a device-tagged manifest
+//    (`secure_storage.rs`).
+//
+// The record format and the migration steps live in `common::migration`,
+// where they are tested against storage fixtures.
+
+use common::{FlatMigration, SchemaRecord};
+use optee_utee::{Error, ErrorKind, Result};
+use proto::inference::{StorageSchema, ERROR_STORAGE_DOWNGRADE, STORAGE_SCHEMA_VERSION};
+use spin::Mutex;
+
+use crate::{license, secure_storage};
+
+// Step `i` migrates version `i + 1` to `i + 2`
+const MIGRATIONS: [fn() -> Result<()>; STORAGE_SCHEMA_VERSION as usize - 1] = [flat_to_generations];
+
+// Checked record of this TA instance, `None` until the first session
+static STATE: Mutex<Option<StorageSchema>> = Mutex::new(None);
+
+fn flat_to_generations() -> Result<()> {
+    match secure_storage::migrate_flat_layout()? {
+        FlatMigration::Nothing => {}
+        FlatMigration::Moved => trace_println!("[+] Moved the stored model out of the flat layout"),
+        FlatMigration::Dropped => {
+            trace_println!("[!] Flat stored model doesn't match its manifest, discarded it")
+        }
+    }
+    Ok(())
+}
+
+// The stored record, or what storage without one is taken for, and whether
+// there was a record
+fn stored() -> Result<(StorageSchema, bool)> {
+    if let Some(bytes) = secure_storage::load_schema()? {
+        return SchemaRecord::from_bytes(&bytes)
+            .map(|schema| (schema, true))
+            .ok_or_else(|| {
+                trace_println!("[!] Malformed storage schema record");
+                ErrorKind::CorruptObject.into()
+            });
+    }
+    let version = if secure_storage::holds_model_objects()? {
+        1
+    } else {
+        STORAGE_SCHEMA_VERSION
+    };
+    let schema = StorageSchema {
+        version,
+        migrated_from: None,
+        migrated_at: None,
+    };
+    Ok((schema, false))
+}
+
+/// Brings the storage to `STORAGE_SCHEMA_VERSION` on the first call,
+/// migrating older layouts. Fails with `ERROR_STORAGE_DOWNGRADE` when the
+/// storage is newer than this TA.
+pub fn ensure_current() -> Result<()> {
+    let mut state = STATE.lock();
+    if state.is_some() {
+        return Ok(());
+    }
+    let (mut schema, recorded) = stored()?;
+    if schema.version > STORAGE_SCHEMA_VERSION {
+        trace_println!(
+            "[!] Storage schema {} is newer than this TA's ({}), refusing it",
+            schema.version,
+            STORAGE_SCHEMA_VERSION
+        );
+        return Err(Error::from_raw_error(ERROR_STORAGE_DOWNGRADE));
+    }
+    if !recorded && schema.version == STORAGE_SCHEMA_VERSION {
+        // Nothing stored yet, so it starts out in the current layout
+        secure_storage::store_schema(&SchemaRecord::to_bytes(&schema))?;
+    }
+    while schema.version < STORAGE_SCHEMA_VERSION {
+        let from = schema.version;
+        MIGRATIONS[from as usize - 1]()?;
+        schema = StorageSchema {
+            version: from + 1,
+            migrated_from: Some(from),
+            migrated_at: Some(license::trusted_time()),
+        };
+        secure_storage::store_schema(&SchemaRecord::to_bytes(&schema))?;
+        trace_println!("[+] Migrated storage schema {} to {}", from, from + 1);
+    }
+    *state = Some(schema);
+    Ok(())
+}
+
+/// The storage schema checked by `ensure_current`.
+pub fn status() -> Result<StorageSchema> {
+    ensure_current()?;
+    STATE
+        .lock()
+        .clone()
+        .ok_or_else(|| ErrorKind::BadState.into())
+}
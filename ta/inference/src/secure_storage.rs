@@ -0,0 +1,837 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Persistence of the encrypted model blob in OP-TEE secure storage.
+//
+// Secure storage objects are size limited well below our models, so a blob is
+// split into `ta_model.<generation>.<n>` pieces. The `ta_model.manifest`
+// object lists the stored versions newest first, each with generation, chunk
+// count, total size, SHA-256 and provisioning time: the active one plus up to
+// `MODEL_HISTORY` older ones to roll back to. Storing a model writes an unused
+// generation, reads it back to check the hash, and only then replaces the
+// manifest, which is a single atomic object write. A crash at any point
+// therefore leaves the old or the new complete model loadable, and loading
+// falls back to older versions when the active one fails its hash.
+//
+// The manifest ends with a tag over its contents and the TEE device id, so a
+// storage image copied to another board is refused instead of loaded; an
+// intentional migration re-tags it through `rebind_to_device`.
+//
+// A staged model (see `Command::StageModel`) is written the same way into a
+// generation of its own, recorded by `ta_model.staged` (its entry, the hash
+// of the decrypted model and a device tag) instead of the manifest. Commit
+// moves that entry to the front of the manifest.
+//
+// `ta_model.info` describes the active version (hashes, sizes, architecture,
+// labels, key fingerprint) so status queries don't reassemble the blob. It is
+// written after the model is installed and names the encrypted hash of the
+// version it belongs to; a record that doesn't match the active version is
+// ignored.
+//
+// Lifetime usage counters live next to it in one `ta_stats.<model hash>`
+// object per model, and `ta_clock` holds the latest trusted time licenses
+// were checked against (see `license.rs`).
+//
+// `ta_key_rotation` exists only while a key rotation switches the stored
+// model to its new key (see `common::rotate`).
+//
+// `ta_schema` records the layout version of all of the above (see
+// `schema.rs`). The first releases stored a single version as flat
+// `ta_model.<n>` pieces under a manifest of just chunk count, size and hash;
+// `migrate_flat_layout` moves such a model into generation 0.
+
+use alloc::string::{String, ToString};
+use alloc::{format, vec, vec::Vec};
+
+use common::{
+    append_device_tag, check_device_tag, FlatMigration, FlatStorage, GenerationEntry as Entry,
+    GenerationManifest as Manifest, GenerationStorage,
+};
+use optee_utee::property::{PropertyKey, TeeDeviceId};
+use optee_utee::{
+    AlgorithmId, DataFlag, Digest, Error, ErrorKind, GenericObject, ObjectStorageConstants,
+    PersistentObject, Result,
+};
+use proto::inference::{ModelVersion, StagedModelInfo, StorageObject, StoredModelInfo};
+
+use crate::error_detail;
+
+const MODEL_OBJECT_PREFIX: &str = "ta_model";
+const MANIFEST_OBJECT_ID: &[u8] = b"ta_model.manifest";
+const INFO_OBJECT_ID: &[u8] = b"ta_model.info";
+const STAGED_OBJECT_ID: &[u8] = b"ta_model.staged";
+const STATS_OBJECT_PREFIX: &str = "ta_stats";
+const CLOCK_OBJECT_ID: &[u8] = b"ta_clock";
+const CRASH_OBJECT_ID: &[u8] = b"ta_last_crash";
+const SCHEMA_OBJECT_ID: &[u8] = b"ta_schema";
+const MANAGE_POLICY_OBJECT_ID: &[u8] = b"ta_manage_policy";
+const ROTATION_OBJECT_ID: &[u8] = b"ta_key_rotation";
+// Hash bytes spelled out in a stats object id
+const STATS_ID_HASH_BYTES: usize = 8;
+const STORAGE_CHUNK_SIZE: usize = 32 * 1024;
+const SHA256_SIZE: usize = 32;
+// Stored versions, plus a staged one, plus the one being written
+const GENERATIONS: u32 = MODEL_HISTORY as u32 + 3;
+// TEE_OBJECT_ID_MAX_LEN
+const MAX_OBJECT_ID_SIZE: usize = 64;
+
+fn chunk_object_id(generation: u32, index: u32) -> Vec<u8> {
+    format!("{}.{}.{}", MODEL_OBJECT_PREFIX, generation, index).into_bytes()
+}
+
+fn flat_chunk_object_id(index: u32) -> Vec<u8> {
+    format!("{}.{}", MODEL_OBJECT_PREFIX, index).into_bytes()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sha256(data: &[u8]) -> Result<[u8; SHA256_SIZE]> {
+    let digest = Digest::allocate(AlgorithmId::Sha256)?;
+    let mut hash = [0u8; SHA256_SIZE];
+    digest.do_final(data, &mut hash)?;
+    Ok(hash)
+}
+
+// Creating with the data as initial content replaces an existing object
+// atomically
+pub fn write_object(id: &[u8], data: &[u8]) -> Result<()> {
+    let flags = DataFlag::ACCESS_READ
+        | DataFlag::ACCESS_WRITE
+        | DataFlag::ACCESS_WRITE_META
+        | DataFlag::OVERWRITE;
+    PersistentObject::create(ObjectStorageConstants::Private, id, flags, None, data)
+        .inspect_err(|err| record_failure("writing", id, err))?;
+    Ok(())
+}
+
+/// Reads a whole object, `None` when it doesn't exist.
+pub fn read_object(id: &[u8]) -> Result<Option<Vec<u8>>> {
+    let object = match PersistentObject::open(
+        ObjectStorageConstants::Private,
+        id,
+        DataFlag::ACCESS_READ | DataFlag::SHARE_READ,
+    ) {
+        Ok(object) => object,
+        Err(err) if err.kind() == ErrorKind::ItemNotFound => return Ok(None),
+        Err(err) => {
+            record_failure("opening", id, &err);
+            return Err(err);
+        }
+    };
+    let mut data = vec![0u8; object.info()?.data_size()];
+    let read = object
+        .read(&mut data)
+        .inspect_err(|err| record_failure("reading", id, err))? as usize;
+    data.truncate(read);
+    Ok(Some(data))
+}
+
+/// Deletes an object, returning whether it existed.
+fn delete_object(id: &[u8]) -> Result<bool> {
+    match PersistentObject::open(
+        ObjectStorageConstants::Private,
+        id,
+        DataFlag::ACCESS_READ | DataFlag::ACCESS_WRITE_META,
+    ) {
+        Ok(object) => {
+            object
+                .close_and_delete()
+                .inspect_err(|err| record_failure("deleting", id, err))?;
+            Ok(true)
+        }
+        Err(err) if err.kind() == ErrorKind::ItemNotFound => Ok(false),
+        Err(err) => {
+            record_failure("opening", id, &err);
+            Err(err)
+        }
+    }
+}
+
+// The object and platform return code of a failed storage operation, as the
+// error detail of the command
+fn record_failure(action: &str, id: &[u8], err: &Error) {
+    error_detail::set(format_args!(
+        "{} {} failed: {:?} ({:#010x})",
+        action,
+        String::from_utf8_lossy(id),
+        err.kind(),
+        err.raw_code()
+    ));
+}
+
+// Removes pieces `first`, `first + 1`, ... of `generation` up to the first gap
+fn delete_chunks_from(generation: u32, first: u32) -> Result<()> {
+    common::delete_generation(&mut Generations, generation, first)
+}
+
+// SHA-256 over the concatenation of `parts`
+fn sha256_parts(parts: &[&[u8]]) -> Result<[u8; SHA256_SIZE]> {
+    let digest = Digest::allocate(AlgorithmId::Sha256)?;
+    for part in parts {
+        digest.update(part);
+    }
+    let mut hash = [0u8; SHA256_SIZE];
+    digest.do_final(&[], &mut hash)?;
+    Ok(hash)
+}
+
+// What records are bound to (see `common::device_tag`)
+fn device_id() -> Result<String> {
+    Ok(TeeDeviceId.get()?.to_string())
+}
+
+fn write_manifest(manifest: &Manifest) -> Result<()> {
+    let mut bytes = manifest.to_bytes();
+    append_device_tag(sha256_parts, device_id()?.as_bytes(), &mut bytes)?;
+    write_object(MANIFEST_OBJECT_ID, &bytes)
+}
+
+// The manifest and whether its tag matches this device
+fn read_manifest_unchecked() -> Result<Option<(Manifest, bool)>> {
+    let bytes = match read_object(MANIFEST_OBJECT_ID)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    let checked = check_device_tag(sha256_parts, device_id()?.as_bytes(), &bytes)?;
+    match checked.and_then(|(body, bound)| Some((Manifest::from_bytes(body)?, bound))) {
+        Some(found) => Ok(Some(found)),
+        None => {
+            trace_println!("[!] Malformed model manifest, ignoring stored model");
+            Ok(None)
+        }
+    }
+}
+
+fn read_manifest() -> Result<Option<Manifest>> {
+    match read_manifest_unchecked()? {
+        Some((manifest, true)) => Ok(Some(manifest)),
+        Some((_, false)) => {
+            trace_println!("[!] Stored model is bound to another device");
+            Err(ErrorKind::AccessDenied.into())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Re-tags the stored model for this device after an intentional migration,
+/// returning whether there was a model to re-tag.
+pub fn rebind_to_device() -> Result<bool> {
+    match read_manifest_unchecked()? {
+        Some((manifest, _)) => {
+            write_manifest(&manifest)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Reassembles the pieces of `entry`, `None` unless they match its size and
+/// hash.
+fn load_entry(entry: &Entry) -> Result<Option<Vec<u8>>> {
+    common::load_generation(&mut Generations, entry)
+}
+
+// Replaces the manifest, then deletes the pieces of versions it no longer
+// lists; until the manifest write completes the old one stays in effect
+fn switch_manifest(old: Option<Manifest>, new: &Manifest) -> Result<()> {
+    common::switch_manifest(&mut Generations, old.as_ref(), new)
+}
+
+// A generation neither listed in `manifest` nor holding the staged model
+fn free_generation(manifest: Option<&Manifest>, staged: Option<&Entry>) -> Result<u32> {
+    common::free_generation(manifest, staged, GENERATIONS)
+        .ok_or_else(|| ErrorKind::CorruptObject.into())
+}
+
+/// Persists `bytes` as the active model provisioned at `provisioned` (host
+/// clock), keeping up to `MODEL_HISTORY` earlier versions. Returns the
+/// SHA-256 of `bytes`.
+pub fn store_model_bytes(bytes: &[u8], provisioned: u64) -> Result<[u8; SHA256_SIZE]> {
+    let old = read_manifest()?;
+    let staged = read_staged()?.map(|(entry, _)| entry);
+    let generation = free_generation(old.as_ref(), staged.as_ref())?;
+    // The oldest versions beyond the history length are evicted
+    let entry = common::store_version(
+        &mut Generations,
+        old.as_ref(),
+        generation,
+        bytes,
+        provisioned,
+    )?;
+    debug_println!(
+        "[+] Stored model: {} bytes in {} objects (generation {})",
+        bytes.len(),
+        entry.chunks,
+        generation
+    );
+    Ok(entry.hash)
+}
+
+/// The active version encrypted under a new key, written by
+/// `write_rekeyed` but not switched to yet.
+pub struct Rekeyed(Entry);
+
+impl Rekeyed {
+    /// SHA-256 of the written bytes, which read back intact.
+    pub fn hash(&self) -> [u8; SHA256_SIZE] {
+        self.0.hash
+    }
+}
+
+/// Writes `bytes`, the active version encrypted under a new key, into a
+/// free generation with the active version's provisioning time. The stored
+/// model doesn't change until `switch_rekeyed`; pieces left by an
+/// interruption before it are overwritten when the generation is reused.
+pub fn write_rekeyed(bytes: &[u8]) -> Result<Rekeyed> {
+    let old = read_manifest()?.ok_or(ErrorKind::ItemNotFound)?;
+    let staged = read_staged()?.map(|(entry, _)| entry);
+    let generation = free_generation(Some(&old), staged.as_ref())?;
+    Ok(Rekeyed(write_entry(
+        generation,
+        bytes,
+        old.entries[0].provisioned,
+    )?))
+}
+
+/// Deletes what `write_rekeyed` wrote.
+pub fn discard_rekeyed(rekeyed: Rekeyed) -> Result<()> {
+    delete_chunks_from(rekeyed.0.generation, 0)
+}
+
+/// Replaces the stored model with `rekeyed`. The earlier versions are still
+/// encrypted under the old key, so they are dropped. Once the new manifest
+/// is written the replacement has happened: failing to delete the old
+/// pieces after it is only logged.
+pub fn switch_rekeyed(rekeyed: Rekeyed) -> Result<()> {
+    let old = read_manifest()?.ok_or(ErrorKind::ItemNotFound)?;
+    let entry = rekeyed.0;
+    write_manifest(&Manifest {
+        entries: vec![entry],
+    })?;
+    for dropped in &old.entries {
+        if let Err(err) = delete_chunks_from(dropped.generation, 0) {
+            trace_println!(
+                "[!] Failed to delete generation {}: {:?}",
+                dropped.generation,
+                err
+            );
+        }
+    }
+    debug_println!(
+        "[+] Re-keyed model: {} bytes in {} objects (generation {})",
+        entry.total_size,
+        entry.chunks,
+        entry.generation
+    );
+    Ok(())
+}
+
+/// SHA-256 of the active version's ciphertext, `None` when no model is
+/// stored.
+pub fn active_model_hash() -> Result<Option<[u8; SHA256_SIZE]>> {
+    Ok(read_manifest()?.map(|manifest| manifest.entries[0].hash))
+}
+
+// Writes `bytes` into the pieces of `generation` and checks they read back
+fn write_entry(generation: u32, bytes: &[u8], provisioned: u64) -> Result<Entry> {
+    common::write_generation(&mut Generations, generation, bytes, provisioned)
+}
+
+// The staged entry and the hash of its decrypted model
+fn read_staged() -> Result<Option<(Entry, [u8; SHA256_SIZE])>> {
+    let bytes = match read_object(STAGED_OBJECT_ID)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    if bytes.len() != Entry::SIZE + 2 * SHA256_SIZE {
+        trace_println!("[!] Malformed staged model record, ignoring it");
+        return Ok(None);
+    }
+    let Some((body, true)) = check_device_tag(sha256_parts, device_id()?.as_bytes(), &bytes)?
+    else {
+        trace_println!("[!] Staged model is bound to another device");
+        return Err(ErrorKind::AccessDenied.into());
+    };
+    let mut model_hash = [0u8; SHA256_SIZE];
+    model_hash.copy_from_slice(&body[Entry::SIZE..]);
+    Ok(Some((Entry::read(body), model_hash)))
+}
+
+/// Persists `bytes` as the staged model, replacing any staged before. The
+/// active model and its history are left alone. Returns the SHA-256 of
+/// `bytes`.
+pub fn stage_model_bytes(
+    bytes: &[u8],
+    model_hash: &[u8; SHA256_SIZE],
+    staged_at: u64,
+) -> Result<[u8; SHA256_SIZE]> {
+    let manifest = read_manifest()?;
+    let previous = read_staged()?.map(|(entry, _)| entry);
+    let generation = free_generation(manifest.as_ref(), previous.as_ref())?;
+    let entry = write_entry(generation, bytes, staged_at)?;
+    let mut record = Vec::with_capacity(Entry::SIZE + 2 * SHA256_SIZE);
+    entry.write(&mut record);
+    record.extend_from_slice(model_hash);
+    append_device_tag(sha256_parts, device_id()?.as_bytes(), &mut record)?;
+    write_object(STAGED_OBJECT_ID, &record)?;
+    if let Some(previous) = previous {
+        delete_chunks_from(previous.generation, 0)?;
+    }
+    debug_println!(
+        "[+] Staged model: {} bytes in {} objects (generation {})",
+        bytes.len(),
+        entry.chunks,
+        generation
+    );
+    Ok(entry.hash)
+}
+
+/// What is staged, `None` when nothing is.
+pub fn staged_model_info() -> Result<Option<StagedModelInfo>> {
+    Ok(read_staged()?.map(|(entry, model_hash)| StagedModelInfo {
+        model_hash: hex(&model_hash),
+        encrypted_hash: hex(&entry.hash),
+        encrypted_size: entry.total_size,
+        staged: entry.provisioned,
+    }))
+}
+
+/// Reassembles the staged model, `None` when nothing is staged.
+pub fn staged_model_bytes() -> Result<Option<Vec<u8>>> {
+    match read_staged()? {
+        Some((entry, _)) => load_entry(&entry)?
+            .ok_or_else(|| {
+                trace_println!("[!] Staged model is damaged");
+                ErrorKind::CorruptObject.into()
+            })
+            .map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Makes the staged model the active version, keeping up to
+/// `MODEL_HISTORY` earlier ones. Returns the staged entry's encrypted hash
+/// and size.
+pub fn commit_staged() -> Result<([u8; SHA256_SIZE], usize)> {
+    let (entry, _) = read_staged()?.ok_or(ErrorKind::ItemNotFound)?;
+    let old = read_manifest()?;
+    let mut entries = vec![entry];
+    entries.extend(old.iter().flat_map(|m| &m.entries).take(MODEL_HISTORY));
+    switch_manifest(old, &Manifest { entries })?;
+    // Once listed in the manifest the pieces belong to the active version
+    delete_object(STAGED_OBJECT_ID)?;
+    debug_println!(
+        "[+] Committed staged model (generation {})",
+        entry.generation
+    );
+    Ok((entry.hash, entry.total_size as usize))
+}
+
+/// Throws the staged model away, returning whether there was one.
+pub fn discard_staged() -> Result<bool> {
+    let staged = read_staged()?;
+    // Record first, so an interrupted discard never points at missing pieces
+    if !delete_object(STAGED_OBJECT_ID)? {
+        return Ok(false);
+    }
+    if let Some((entry, _)) = staged {
+        delete_chunks_from(entry.generation, 0)?;
+    }
+    Ok(true)
+}
+
+// First intact version among `entries`
+fn load_first_intact(entries: &[Entry]) -> Result<Option<Vec<u8>>> {
+    let found = common::load_first_intact(&mut Generations, entries)?;
+    let damaged = found.as_ref().map_or(entries.len(), |(i, _)| *i);
+    if damaged > 0 {
+        trace_println!("[!] {} stored model version(s) are damaged", damaged);
+    }
+    Ok(found.map(|(_, bytes)| bytes))
+}
+
+/// Whether a model is stored, without reading it back.
+pub fn model_bytes_exist() -> Result<bool> {
+    Ok(read_manifest()?.is_some())
+}
+
+/// Reassembles the stored model, `None` when no complete model is stored.
+/// Falls back to older versions when the active one fails its hash.
+pub fn load_model_bytes() -> Result<Option<Vec<u8>>> {
+    match read_manifest()? {
+        Some(manifest) => load_first_intact(&manifest.entries),
+        None => Ok(None),
+    }
+}
+
+/// Records what the active version contains. `info.encrypted_hash` must be
+/// the hash returned by `store_model_bytes`.
+pub fn store_model_info(info: &StoredModelInfo) -> Result<()> {
+    let bytes = serde_json::to_vec(info).map_err(|_| ErrorKind::Generic)?;
+    write_object(INFO_OBJECT_ID, &bytes)
+}
+
+/// The record of the active version, `None` when there is no stored model or
+/// the record belongs to another version.
+pub fn load_model_info() -> Result<Option<StoredModelInfo>> {
+    let active = match read_manifest()? {
+        Some(manifest) => manifest.entries[0],
+        None => return Ok(None),
+    };
+    let info = read_object(INFO_OBJECT_ID)?
+        .and_then(|bytes| serde_json::from_slice::<StoredModelInfo>(&bytes).ok());
+    Ok(info.filter(|info| info.encrypted_hash == hex(&active.hash)))
+}
+
+/// Reassembles the newest intact version before the active one, the target
+/// of a rollback; `None` when there is none.
+pub fn previous_model_bytes() -> Result<Option<Vec<u8>>> {
+    match read_manifest()? {
+        Some(manifest) => load_first_intact(&manifest.entries[1..]),
+        None => Ok(None),
+    }
+}
+
+/// Drops the active version once the one before it has been installed, so
+/// the latter is loaded after a restart.
+pub fn commit_rollback() -> Result<()> {
+    let old = read_manifest()?.ok_or(ErrorKind::ItemNotFound)?;
+    if old.entries.len() < 2 {
+        return Err(ErrorKind::ItemNotFound.into());
+    }
+    discard_active()
+}
+
+/// Drops the active version, making the one before it active again; with
+/// no earlier version the stored model is removed altogether. Versions
+/// evicted when the active one was stored are not brought back.
+pub fn discard_active() -> Result<()> {
+    let old = read_manifest()?.ok_or(ErrorKind::ItemNotFound)?;
+    if old.entries.len() < 2 {
+        return delete_model_bytes();
+    }
+    let manifest = Manifest {
+        entries: old.entries[1..].to_vec(),
+    };
+    delete_object(INFO_OBJECT_ID)?;
+    switch_manifest(Some(old), &manifest)
+}
+
+/// Stored versions, newest (active) first.
+pub fn model_history() -> Result<Vec<ModelVersion>> {
+    let entries = read_manifest()?.map(|m| m.entries).unwrap_or_default();
+    Ok(entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| ModelVersion {
+            hash: hex(&entry.hash),
+            size: entry.total_size,
+            provisioned: entry.provisioned,
+            active: i == 0,
+        })
+        .collect())
+}
+
+/// Removes the stored model. The manifest goes first so an interrupted
+/// delete never leaves a manifest pointing at missing pieces.
+pub fn delete_model_bytes() -> Result<()> {
+    delete_object(MANIFEST_OBJECT_ID)?;
+    delete_object(INFO_OBJECT_ID)?;
+    delete_object(STAGED_OBJECT_ID)?;
+    for generation in 0..GENERATIONS {
+        delete_chunks_from(generation, 0)?;
+    }
+    Ok(())
+}
+
+fn stats_object_id(model_hash: &[u8; SHA256_SIZE]) -> Vec<u8> {
+    format!(
+        "{}.{}",
+        STATS_OBJECT_PREFIX,
+        hex(&model_hash[..STATS_ID_HASH_BYTES])
+    )
+    .into_bytes()
+}
+
+/// Persists the lifetime usage counters of the model with `model_hash`.
+pub fn store_stats(model_hash: &[u8; SHA256_SIZE], bytes: &[u8]) -> Result<()> {
+    write_object(&stats_object_id(model_hash), bytes)
+}
+
+/// Reads the counters stored by `store_stats`, `None` when there are none.
+pub fn load_stats(model_hash: &[u8; SHA256_SIZE]) -> Result<Option<Vec<u8>>> {
+    read_object(&stats_object_id(model_hash))
+}
+
+pub fn delete_stats(model_hash: &[u8; SHA256_SIZE]) -> Result<()> {
+    delete_object(&stats_object_id(model_hash))?;
+    Ok(())
+}
+
+/// Persists the trusted time licenses are checked against.
+pub fn store_clock(secs: u64) -> Result<()> {
+    write_object(CLOCK_OBJECT_ID, &secs.to_le_bytes())
+}
+
+/// The time stored by `store_clock`, `None` when there is none.
+pub fn load_clock() -> Result<Option<u64>> {
+    Ok(read_object(CLOCK_OBJECT_ID)?
+        .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_slice()).ok())
+        .map(u64::from_le_bytes))
+}
+
+/// Records the TA's last panic, replacing the previous report.
+pub fn store_crash(report: &[u8]) -> Result<()> {
+    write_object(CRASH_OBJECT_ID, report)
+}
+
+/// The report stored by `store_crash`, `None` when there is none.
+pub fn load_crash() -> Result<Option<Vec<u8>>> {
+    read_object(CRASH_OBJECT_ID)
+}
+
+/// Deletes the crash report, returning whether there was one.
+pub fn delete_crash() -> Result<bool> {
+    delete_object(CRASH_OBJECT_ID)
+}
+
+/// Persists the storage schema record, replacing the previous one.
+pub fn store_schema(bytes: &[u8]) -> Result<()> {
+    write_object(SCHEMA_OBJECT_ID, bytes)
+}
+
+/// The record stored by `store_schema`, `None` when there is none.
+pub fn load_schema() -> Result<Option<Vec<u8>>> {
+    read_object(SCHEMA_OBJECT_ID)
+}
+
+/// Persists the `ManagePolicy`, replacing the previous one.
+pub fn store_manage_policy(bytes: &[u8]) -> Result<()> {
+    write_object(MANAGE_POLICY_OBJECT_ID, bytes)
+}
+
+/// The policy stored by `store_manage_policy`, `None` when there is none.
+pub fn load_manage_policy() -> Result<Option<Vec<u8>>> {
+    read_object(MANAGE_POLICY_OBJECT_ID)
+}
+
+/// Deletes the manage policy, returning whether there was one.
+pub fn delete_manage_policy() -> Result<bool> {
+    delete_object(MANAGE_POLICY_OBJECT_ID)
+}
+
+/// Persists the journal of a key rotation about to switch models. It holds
+/// the new key, like the key manager's own storage does.
+pub fn store_rotation_journal(bytes: &[u8]) -> Result<()> {
+    write_object(ROTATION_OBJECT_ID, bytes)
+}
+
+/// The journal stored by `store_rotation_journal`, `None` when there is
+/// none.
+pub fn load_rotation_journal() -> Result<Option<Vec<u8>>> {
+    read_object(ROTATION_OBJECT_ID)
+}
+
+/// Deletes the rotation journal, returning whether there was one.
+pub fn delete_rotation_journal() -> Result<bool> {
+    delete_object(ROTATION_OBJECT_ID)
+}
+
+/// Whether a model is stored in either layout, regardless of its device
+/// binding or integrity.
+pub fn holds_model_objects() -> Result<bool> {
+    Ok(read_object(MANIFEST_OBJECT_ID)?.is_some()
+        || read_object(&flat_chunk_object_id(0))?.is_some())
+}
+
+/// Moves a model stored in the flat layout into generation 0 under a
+/// manifest tagged for this device, then deletes the flat pieces (see
+/// `common::migrate_flat_layout`).
+pub fn migrate_flat_layout() -> Result<FlatMigration> {
+    common::migrate_flat_layout(&mut FlatLayout)
+}
+
+// The pieces of the generations and the manifest, tagged for this device
+struct Generations;
+
+impl GenerationStorage for Generations {
+    type Error = Error;
+
+    const CHUNK_SIZE: usize = STORAGE_CHUNK_SIZE;
+    const HISTORY: usize = MODEL_HISTORY;
+
+    fn read_chunk(&mut self, generation: u32, index: u32) -> Result<Option<Vec<u8>>> {
+        read_object(&chunk_object_id(generation, index))
+    }
+
+    fn write_chunk(&mut self, generation: u32, index: u32, data: &[u8]) -> Result<()> {
+        write_object(&chunk_object_id(generation, index), data)
+    }
+
+    fn delete_chunk(&mut self, generation: u32, index: u32) -> Result<bool> {
+        delete_object(&chunk_object_id(generation, index))
+    }
+
+    fn sha256(&mut self, data: &[u8]) -> Result<[u8; SHA256_SIZE]> {
+        sha256(data)
+    }
+
+    fn write_manifest(&mut self, manifest: &Manifest) -> Result<()> {
+        write_manifest(manifest)
+    }
+}
+
+struct FlatLayout;
+
+impl FlatStorage for FlatLayout {
+    type Error = Error;
+
+    fn read_manifest(&mut self) -> Result<Option<Vec<u8>>> {
+        read_object(MANIFEST_OBJECT_ID)
+    }
+
+    fn read_flat_chunk(&mut self, index: u32) -> Result<Option<Vec<u8>>> {
+        read_object(&flat_chunk_object_id(index))
+    }
+
+    fn delete_flat_chunk(&mut self, index: u32) -> Result<bool> {
+        delete_object(&flat_chunk_object_id(index))
+    }
+
+    fn sha256(&mut self, data: &[u8]) -> Result<[u8; SHA256_SIZE]> {
+        sha256(data)
+    }
+
+    fn store_generation_zero(&mut self, model: &[u8]) -> Result<()> {
+        // Provisioned before times were recorded
+        let entry = write_entry(0, model, 0)?;
+        write_manifest(&Manifest {
+            entries: vec![entry],
+        })
+    }
+
+    fn discard_model(&mut self) -> Result<()> {
+        delete_object(MANIFEST_OBJECT_ID)?;
+        delete_object(INFO_OBJECT_ID)?;
+        Ok(())
+    }
+}
+
+/// Deletes a single object for storage maintenance, returning whether it
+/// existed. Deleting the manifest removes the whole stored model, since its
+/// pieces would be unreachable otherwise.
+pub fn delete_storage_object(id: &[u8]) -> Result<bool> {
+    if id == MANIFEST_OBJECT_ID {
+        let existed = read_object(MANIFEST_OBJECT_ID)?.is_some();
+        delete_model_bytes()?;
+        return Ok(existed);
+    }
+    if id == STAGED_OBJECT_ID {
+        return discard_staged();
+    }
+    delete_object(id)
+}
+
+/// Bytes held by all of the TA's objects.
+pub fn used_bytes() -> Result<usize> {
+    Ok(list_objects()?
+        .iter()
+        .map(|object| object.data_size as usize)
+        .sum())
+}
+
+/// Lists every object in the TA's private storage.
+pub fn list_objects() -> Result<Vec<StorageObject>> {
+    let mut objects = Vec::new();
+    let Some(mut enumerator) = ObjectEnumerator::start()? else {
+        // Nothing stored yet
+        return Ok(objects);
+    };
+    let mut id = [0u8; MAX_OBJECT_ID_SIZE];
+    while let Some((id_len, info)) = enumerator.next(&mut id)? {
+        objects.push(StorageObject {
+            id: String::from_utf8_lossy(&id[..id_len]).into_owned(),
+            data_size: info.dataSize as u32,
+            flags: info.handleFlags,
+        });
+    }
+    Ok(objects)
+}
+
+// Persistent object enumerator of the raw API; `ObjectEnumHandle` keeps the
+// handle flags of the objects it lists to itself
+struct ObjectEnumerator(optee_utee_sys::TEE_ObjectEnumHandle);
+
+impl ObjectEnumerator {
+    // None when the TA's storage is empty
+    fn start() -> Result<Option<Self>> {
+        let mut handle = core::ptr::null_mut();
+        raw_result(unsafe { optee_utee_sys::TEE_AllocatePersistentObjectEnumerator(&mut handle) })?;
+        let enumerator = Self(handle);
+        let started = unsafe {
+            optee_utee_sys::TEE_StartPersistentObjectEnumerator(
+                enumerator.0,
+                ObjectStorageConstants::Private as u32,
+            )
+        };
+        match raw_result(started) {
+            Ok(()) => Ok(Some(enumerator)),
+            Err(err) if err.kind() == ErrorKind::ItemNotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Length of the id written to `id` and info of the next object, None
+    // past the last one
+    fn next(
+        &mut self,
+        id: &mut [u8; MAX_OBJECT_ID_SIZE],
+    ) -> Result<Option<(usize, optee_utee_sys::TEE_ObjectInfo)>> {
+        let mut info: optee_utee_sys::TEE_ObjectInfo = unsafe { core::mem::zeroed() };
+        let mut id_len = id.len();
+        let next = unsafe {
+            optee_utee_sys::TEE_GetNextPersistentObject(
+                self.0,
+                &mut info,
+                id.as_mut_ptr() as _,
+                &mut id_len,
+            )
+        };
+        match raw_result(next) {
+            Ok(()) => Ok(Some((id_len.min(id.len()), info))),
+            Err(err) if err.kind() == ErrorKind::ItemNotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Drop for ObjectEnumerator {
+    fn drop(&mut self) {
+        unsafe { optee_utee_sys::TEE_FreePersistentObjectEnumerator(self.0) }
+    }
+}
+
+fn raw_result(code: u32) -> Result<()> {
+    match code {
+        optee_utee_sys::TEE_SUCCESS => Ok(()),
+        code => Err(Error::from_raw_error(code)),
+    }
+}
+
+include!(concat!(env!("OUT_DIR"), "/model_history.rs"));
@@ -0,0 +1,69 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Known-answer test of model decryption: runs `proto::test_vectors` through
+// the TEE's AES-256-CBC and the shared plaintext framing, both ways. The key
+// manager only ever uses its provisioned key, so the vectors go to the TEE
+// cipher directly instead of through it.
+
+use alloc::{vec, vec::Vec};
+
+use optee_utee::{
+    AlgorithmId, AttributeId, AttributeMemref, Cipher, ErrorKind, OperationMode, Result,
+    TransientObject, TransientObjectType,
+};
+use proto::framing;
+use proto::test_vectors::{TestVector, VECTORS};
+
+fn cbc(mode: OperationMode, vector: &TestVector, input: &[u8]) -> Result<Vec<u8>> {
+    let key_bits = vector.key.len() * 8;
+    let cipher = Cipher::allocate(AlgorithmId::AesCbcNopad, mode, key_bits)?;
+    let mut key = TransientObject::allocate(TransientObjectType::Aes, key_bits)?;
+    let secret = AttributeMemref::from_ref(AttributeId::SecretValue, &vector.key);
+    key.populate(&[secret.into()])?;
+    cipher.set_key(&key)?;
+    cipher.init(&vector.iv);
+    let mut output = vec![0u8; input.len()];
+    let size = cipher.do_final(input, &mut output)?;
+    output.truncate(size);
+    Ok(output)
+}
+
+fn check(vector: &TestVector) -> Result<bool> {
+    let framed = framing::frame(vector.payload).ok_or(ErrorKind::BadParameters)?;
+    let encrypted = cbc(OperationMode::Encrypt, vector, &framed)?;
+    let decrypted = cbc(OperationMode::Decrypt, vector, vector.ciphertext)?;
+    let payload = framing::payload_range(&decrypted).map(|range| &decrypted[range]);
+    Ok(encrypted == vector.ciphertext && payload == Some(vector.payload))
+}
+
+/// Whether each vector passed, in `VECTORS` order.
+pub fn run() -> Vec<bool> {
+    VECTORS
+        .iter()
+        .map(|vector| match check(vector) {
+            Ok(pass) => {
+                debug_println!("[+] Self test \"{}\": {}", vector.name, pass);
+                pass
+            }
+            Err(err) => {
+                trace_println!("[!] Self test \"{}\" failed: {:?}", vector.name, err);
+                false
+            }
+        })
+        .collect()
+}
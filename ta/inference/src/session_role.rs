@@ -0,0 +1,119 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Session roles. A session declares at open_session whether it only runs
+// inferences (Infer) or also manages the TA (Manage): keys, models, stored
+// state and TA-wide settings. The role is kept in the session context and
+// checked before every command, so a client that only needs answers can't
+// change anything. A `ManagePolicy` in secure storage restricts Manage to
+// the client identities it lists.
+
+use alloc::string::ToString;
+use optee_utee::property::{ClientIdentity, PropertyKey};
+use optee_utee::{ErrorKind, Parameters, Result};
+use proto::inference::{
+    Command, ManagePolicy, SessionRole, MAX_MANAGE_CLIENTS, MAX_MANAGE_POLICY_SIZE,
+};
+
+use crate::secure_storage;
+
+/// The role of a session asking for `requested` (value a of parameter 3,
+/// 0 when it didn't say). Manage fails with AccessDenied for clients the
+/// stored policy doesn't list.
+pub fn open(requested: u32) -> Result<SessionRole> {
+    let role = match requested {
+        0 => SessionRole::Infer,
+        raw => SessionRole::try_from(raw).map_err(|_| {
+            trace_println!("[!] Unknown session role {}", raw);
+            ErrorKind::BadParameters
+        })?,
+    };
+    if role == SessionRole::Manage {
+        if let Some(policy) = load_policy()? {
+            if !admits_caller(&policy)? {
+                trace_println!("[!] Client not allowed to manage the TA");
+                return Err(ErrorKind::AccessDenied.into());
+            }
+        }
+    }
+    debug_println!("[+] Session role: {:?}", role);
+    Ok(role)
+}
+
+/// Fails with AccessDenied when an Infer session runs a command that needs
+/// Manage.
+pub fn check(role: SessionRole, cmd: Command, params: &mut Parameters) -> Result<()> {
+    let flags = unsafe { params.1.as_value() }.map_or(0, |value| value.a());
+    if role.may_run(cmd, flags) {
+        return Ok(());
+    }
+    trace_println!("[!] {:?} needs a manage session", cmd);
+    Err(ErrorKind::AccessDenied.into())
+}
+
+/// Replaces the policy with the JSON `bytes`; one listing no clients
+/// removes it. The calling client must be among those listed.
+pub fn set_policy(bytes: &[u8]) -> Result<()> {
+    if bytes.len() > MAX_MANAGE_POLICY_SIZE {
+        trace_println!("[!] Manage policy too large: {} bytes", bytes.len());
+        return Err(ErrorKind::BadParameters.into());
+    }
+    let policy: ManagePolicy = serde_json::from_slice(bytes).map_err(|_| {
+        trace_println!("[!] Malformed manage policy");
+        ErrorKind::BadParameters
+    })?;
+    if policy.clients.is_empty() {
+        secure_storage::delete_manage_policy()?;
+        debug_println!("[+] Manage policy removed");
+        return Ok(());
+    }
+    if policy.clients.len() > MAX_MANAGE_CLIENTS {
+        trace_println!(
+            "[!] {} manage clients, at most {}",
+            policy.clients.len(),
+            MAX_MANAGE_CLIENTS
+        );
+        return Err(ErrorKind::BadParameters.into());
+    }
+    if !admits_caller(&policy)? {
+        trace_println!("[!] Manage policy would lock out the calling client");
+        return Err(ErrorKind::AccessConflict.into());
+    }
+    secure_storage::store_manage_policy(bytes)?;
+    debug_println!("[+] Manage policy set: {} client(s)", policy.clients.len());
+    Ok(())
+}
+
+// A stored policy that doesn't parse admits nobody rather than everybody
+fn load_policy() -> Result<Option<ManagePolicy>> {
+    Ok(secure_storage::load_manage_policy()?.map(|bytes| {
+        serde_json::from_slice(&bytes).unwrap_or_else(|_| {
+            trace_println!("[!] Stored manage policy is malformed");
+            ManagePolicy::default()
+        })
+    }))
+}
+
+fn admits_caller(policy: &ManagePolicy) -> Result<bool> {
+    let identity = ClientIdentity.get()?;
+    let login = identity.login_type() as u32;
+    let uuid = identity.uuid().to_string();
+    Ok(policy
+        .clients
+        .iter()
+        .any(|client| client.login == login && client.uuid.eq_ignore_ascii_case(&uuid)))
+}
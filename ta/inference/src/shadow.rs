@@ -0,0 +1,69 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Shadow evaluation of a candidate model (`Command::SetShadow`), see
+// `common::ShadowLog`. The counters and the first disagreeing images live
+// in memory until read with reset, shadowing is reconfigured or the TA
+// unloads.
+
+use alloc::vec::Vec;
+
+use common::ShadowLog;
+use proto::inference::ShadowReport;
+use proto::Image;
+use spin::Mutex;
+
+use crate::secure_storage;
+
+static LOG: Mutex<ShadowLog> = Mutex::new(ShadowLog::new());
+
+/// Makes `shadow` the shadow of `active`, or turns shadowing off, and starts
+/// a new report.
+pub fn configure(slots: Option<(usize, usize)>) {
+    LOG.lock().configure(slots);
+}
+
+/// Runs the shadow of `slot` with `run` as `common::run_shadow` does,
+/// identifying disagreeing images by their SHA-256.
+pub fn run(
+    slot: usize,
+    images: &[Image],
+    labels: &[u8],
+    skip: bool,
+    run: impl FnOnce(usize) -> Option<Vec<u8>>,
+) {
+    common::run_shadow(
+        &LOG,
+        slot,
+        images,
+        labels,
+        skip,
+        run,
+        |pixels| match secure_storage::sha256(pixels) {
+            Ok(hash) => Some(secure_storage::hex(&hash)),
+            Err(err) => {
+                trace_println!("[!] Failed to hash shadow example: {:?}", err);
+                None
+            }
+        },
+    );
+}
+
+/// The report so far; `reset` starts a new one with the same slots.
+pub fn report(reset: bool) -> ShadowReport {
+    LOG.lock().report(reset)
+}
@@ -0,0 +1,213 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Usage counters of the installed models. Counters since the model was loaded
+// live in memory only; lifetime counters are reloaded from secure storage
+// when a model with the same hash is installed, and written back every
+// `PERSIST_INTERVAL` inferences and at close_session. Storage failures are
+// only logged so they never fail an inference.
+
+use alloc::vec;
+
+use optee_utee::{Result, Time};
+use proto::inference::{DurationMicros, ModelUsage, UsageCounters, MODEL_SLOTS};
+use spin::Mutex;
+
+use crate::secure_storage;
+
+const PERSIST_INTERVAL: u32 = 100;
+
+struct SlotStats {
+    model_hash: [u8; 32],
+    usage: ModelUsage,
+    // Inferences not yet written to secure storage
+    unsaved: u32,
+}
+
+static STATS: Mutex<[Option<SlotStats>; MODEL_SLOTS]> = Mutex::new([const { None }; MODEL_SLOTS]);
+// Host clock reported at open_session, 0 when unknown
+static HOST_TIME: Mutex<u64> = Mutex::new(0);
+
+pub fn set_host_time(secs: u32) {
+    *HOST_TIME.lock() = secs as u64;
+}
+
+/// Host clock in seconds since the Unix epoch, 0 when unknown.
+pub fn host_time() -> u64 {
+    *HOST_TIME.lock()
+}
+
+/// TA system time, for measuring durations (millisecond resolution).
+pub fn system_time() -> DurationMicros {
+    let mut now = Time::new();
+    now.system_time();
+    DurationMicros::from_millis(now.seconds as u64 * 1000 + now.millis as u64)
+}
+
+/// Adds the time until it is dropped to the inference time of a slot, so
+/// requests that fail part way count too.
+pub struct InferenceTimer {
+    slot: usize,
+    started: DurationMicros,
+}
+
+impl InferenceTimer {
+    pub fn start(slot: usize) -> Self {
+        Self {
+            slot,
+            started: system_time(),
+        }
+    }
+}
+
+impl Drop for InferenceTimer {
+    fn drop(&mut self) {
+        let elapsed = system_time().saturating_sub(self.started);
+        let mut all = STATS.lock();
+        let Some(stats) = all[self.slot].as_mut() else {
+            return;
+        };
+        for counters in [&mut stats.usage.since_load, &mut stats.usage.lifetime] {
+            counters.inference_time += elapsed;
+        }
+    }
+}
+
+fn counters(num_classes: usize) -> UsageCounters {
+    UsageCounters {
+        class_histogram: vec![0; num_classes],
+        ..Default::default()
+    }
+}
+
+fn persist(stats: &mut SlotStats) {
+    let encoded = match serde_json::to_vec(&stats.usage.lifetime) {
+        Ok(encoded) => encoded,
+        Err(_err) => return,
+    };
+    match secure_storage::store_stats(&stats.model_hash, &encoded) {
+        Ok(()) => stats.unsaved = 0,
+        Err(err) => trace_println!("[!] Failed to store usage counters: {:?}", err),
+    }
+}
+
+/// Starts counting for the model with `model_hash` just installed in `slot`.
+pub fn install(slot: usize, model_hash: [u8; 32], num_classes: usize) {
+    let mut all = STATS.lock();
+    if let Some(previous) = all[slot].as_mut() {
+        if previous.unsaved > 0 {
+            persist(previous);
+        }
+    }
+    let lifetime = match secure_storage::load_stats(&model_hash) {
+        Ok(Some(bytes)) => serde_json::from_slice::<UsageCounters>(&bytes).ok(),
+        Ok(None) => None,
+        Err(err) => {
+            trace_println!("[!] Failed to load usage counters: {:?}", err);
+            None
+        }
+    };
+    let mut lifetime = lifetime.unwrap_or_else(|| counters(num_classes));
+    lifetime.class_histogram.resize(num_classes, 0);
+    debug_println!(
+        "[+] Slot {} lifetime usage: {} invocations",
+        slot,
+        lifetime.invocations
+    );
+    all[slot] = Some(SlotStats {
+        model_hash,
+        usage: ModelUsage {
+            since_load: counters(num_classes),
+            lifetime,
+            heap: None,
+        },
+        unsaved: 0,
+    });
+}
+
+/// Counts one inference of the model in `slot` that predicted `labels`.
+pub fn record(slot: usize, labels: &[u8]) {
+    let now = host_time();
+    let mut all = STATS.lock();
+    let Some(stats) = all[slot].as_mut() else {
+        return;
+    };
+    for counters in [&mut stats.usage.since_load, &mut stats.usage.lifetime] {
+        counters.invocations += 1;
+        counters.images += labels.len() as u64;
+        for &label in labels {
+            if let Some(count) = counters.class_histogram.get_mut(label as usize) {
+                *count += 1;
+            }
+        }
+        if now != 0 {
+            counters.last_used = now;
+        }
+    }
+    stats.unsaved += 1;
+    if stats.unsaved >= PERSIST_INTERVAL {
+        persist(stats);
+    }
+}
+
+/// Counts how many images of the last inference of `slot` the result cache
+/// answered (`hits`) and didn't (`misses`).
+pub fn record_cache(slot: usize, hits: usize, misses: usize) {
+    let mut all = STATS.lock();
+    let Some(stats) = all[slot].as_mut() else {
+        return;
+    };
+    for counters in [&mut stats.usage.since_load, &mut stats.usage.lifetime] {
+        counters.cache_hits += hits as u64;
+        counters.cache_misses += misses as u64;
+    }
+}
+
+/// Hash of the model installed in `slot`.
+pub fn model_hash(slot: usize) -> Option<[u8; 32]> {
+    STATS.lock()[slot].as_ref().map(|stats| stats.model_hash)
+}
+
+/// Writes back every slot with unsaved counters.
+pub fn flush() {
+    for stats in STATS.lock().iter_mut().flatten() {
+        if stats.unsaved > 0 {
+            persist(stats);
+        }
+    }
+}
+
+pub fn usage(slot: usize) -> Option<ModelUsage> {
+    STATS.lock()[slot].as_ref().map(|stats| stats.usage.clone())
+}
+
+/// Zeroes both counter sets of `slot` and drops the stored lifetime counters.
+pub fn reset(slot: usize) -> Result<bool> {
+    let mut all = STATS.lock();
+    let Some(stats) = all[slot].as_mut() else {
+        return Ok(false);
+    };
+    let num_classes = stats.usage.lifetime.class_histogram.len();
+    stats.usage = ModelUsage {
+        since_load: counters(num_classes),
+        lifetime: counters(num_classes),
+        heap: None,
+    };
+    stats.unsaved = 0;
+    secure_storage::delete_stats(&stats.model_hash)?;
+    Ok(true)
+}
@@ -0,0 +1,44 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Statics holding state that isn't `Sync`, such as burn models: their
+// parameters keep lazy-initialization cells that must not be shared between
+// threads.
+
+use core::ops::Deref;
+
+/// Makes a value usable from a `static` in this TA.
+pub struct TaLocal<T>(T);
+
+// SAFETY: OP-TEE runs at most one entry point of a TA instance at a time; a
+// command from another session or host thread waits until the current one
+// returns. The value is never reached from two threads at once.
+unsafe impl<T> Sync for TaLocal<T> {}
+
+impl<T> TaLocal<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Deref for TaLocal<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
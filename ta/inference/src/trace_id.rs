@@ -0,0 +1,34 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+// Trace id set by the host with `Command::SetTraceId`, put in every log line
+// so they can be matched with the host's transcript. The TA keeps one id for
+// all its sessions; the last one set wins and it is cleared when the last
+// session closes.
+
+use spin::Mutex;
+
+static TRACE_ID: Mutex<u64> = Mutex::new(0);
+
+/// Current trace id, 0 when none is set.
+pub fn get() -> u64 {
+    *TRACE_ID.lock()
+}
+
+pub fn set(id: u64) {
+    *TRACE_ID.lock() = id;
+}